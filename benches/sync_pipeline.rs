@@ -0,0 +1,167 @@
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::stream;
+use rand::RngCore;
+use syncit::bench::*;
+use tempfile::TempDir;
+use tokio::fs::File;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+
+const FILE_SIZE: usize = 16 * 1024 * 1024;
+
+fn random_data(len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut data);
+
+    data
+}
+
+fn hash_file_block_sizes(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let data = random_data(FILE_SIZE);
+
+    let mut group = c.benchmark_group("hash_file");
+    group.throughput(Throughput::Bytes(FILE_SIZE as _));
+    for block_size in [128 * 1024, 1024 * 1024, 4 * 1024 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(block_size),
+            &block_size,
+            |b, &block_size| {
+                b.to_async(&runtime).iter(|| async {
+                    hash_file_with_block_size(Cursor::new(&data), block_size)
+                        .await
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn sqlite_update_file(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let (mut index_file, index) = runtime.block_on(async {
+        let index = create_sqlite_index(&dir.path().join("index.db"))
+            .await
+            .unwrap();
+        let (hash_sum, block_chain) = hash_file(Cursor::new(random_data(FILE_SIZE)))
+            .await
+            .unwrap();
+        let index_file = IndexFile {
+            filename: OsString::from("test.txt"),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum,
+                block_chain: Some(block_chain),
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::now(),
+            update_by: Uuid::new_v4().to_string(),
+        };
+
+        let mut guard = index.begin().await.unwrap();
+        guard.create_file(&index_file).await.unwrap();
+        guard.commit().await.unwrap();
+
+        (index_file, index)
+    });
+
+    c.bench_function("sqlite_update_file", |b| {
+        b.to_async(&runtime).iter(|| {
+            index_file.detail.gen += 1;
+            let index_file = index_file.clone();
+            let index = &index;
+
+            async move {
+                let mut guard = index.begin().await.unwrap();
+                guard.update_file(&index_file).await.unwrap();
+                guard.commit().await.unwrap();
+            }
+        })
+    });
+}
+
+fn compare_large_block_chains(c: &mut Criterion) {
+    let dir_id = Uuid::new_v4();
+    let local_blocks = (0..100_000u64)
+        .map(|i| Block {
+            offset: i * 4096,
+            len: 4096,
+            hash_sum: [(i % 256) as u8; 32],
+            zero: false,
+        })
+        .collect::<Vec<_>>();
+    // a block is inserted at the start
+    let mut remote_blocks = vec![Block {
+        offset: 0,
+        len: 4096,
+        hash_sum: [255; 32],
+        zero: false,
+    }];
+    remote_blocks.extend(local_blocks.iter().map(|block| Block {
+        offset: block.offset + 4096,
+        ..block.clone()
+    }));
+
+    c.bench_function("compare_blocks", |b| {
+        b.iter(|| compare_blocks(dir_id, Path::new("test.txt"), &remote_blocks, &local_blocks))
+    });
+}
+
+fn sync_file_write_path(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let path = dir.path().join("test.txt");
+    let block = Bytes::from(random_data(1024 * 1024));
+    let options = SyncOptions::default();
+
+    let mut group = c.benchmark_group("sync_file");
+    group.throughput(Throughput::Bytes(FILE_SIZE as _));
+    group.bench_function("write", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let file = File::create(&path).await.unwrap();
+            let blocks = (0..FILE_SIZE / block.len()).map(|i| {
+                Ok::<_, io::Error>(Some(DownloadBlock {
+                    offset: (i * block.len()) as _,
+                    data: block.clone(),
+                }))
+            });
+
+            sync_file(
+                path.as_os_str(),
+                // the default paranoia level doesn't verify the whole file
+                &[0; 32],
+                &file,
+                None,
+                stream::iter(blocks),
+                &options,
+                &SyncTimings::default(),
+            )
+            .await
+            .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    hash_file_block_sizes,
+    sqlite_update_file,
+    compare_large_block_chains,
+    sync_file_write_path
+);
+criterion_main!(benches);
@@ -1,12 +1,17 @@
 use std::error::Error;
 
-use prost_build::Config;
-
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut config = Config::new();
-    config.bytes(["."]);
+    #[cfg(feature = "grpc")]
+    {
+        let mut config = prost_build::Config::new();
+        config.bytes(["."]);
 
-    tonic_build::configure().compile_with_config(config, &["proto/protocol.proto"], &["proto"])?;
+        tonic_build::configure().compile_with_config(
+            config,
+            &["proto/protocol.proto"],
+            &["proto"],
+        )?;
+    }
 
     Ok(())
 }
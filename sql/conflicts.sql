@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS conflicts
+(
+    conflict_filename TEXT    NOT NULL PRIMARY KEY,
+    filename          TEXT    NOT NULL,
+    hash_sum          TEXT    NOT NULL,
+    time              INTEGER NOT NULL
+);
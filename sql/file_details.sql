@@ -1,4 +1,4 @@
-CREATE TABLE file_details
+CREATE TABLE IF NOT EXISTS file_details
 (
     filename    TEXT    NOT NULL,
     gen         INTEGER NOT NULL,
@@ -7,4 +7,4 @@ CREATE TABLE file_details
     deleted     BLOB    NOT NULL
 );
 
-CREATE INDEX idx_filename_gen ON file_details (filename, gen);
+CREATE INDEX IF NOT EXISTS idx_filename_gen ON file_details (filename, gen);
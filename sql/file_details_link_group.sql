@@ -0,0 +1 @@
+ALTER TABLE file_details ADD COLUMN link_group TEXT;
@@ -0,0 +1 @@
+ALTER TABLE file_details ADD COLUMN version TEXT NOT NULL DEFAULT '{}';
@@ -1,4 +1,4 @@
-CREATE TABLE index_files
+CREATE TABLE IF NOT EXISTS index_files
 (
     filename    TEXT    NOT NULL,
     kind        TEXT    NOT NULL,
@@ -6,4 +6,4 @@ CREATE TABLE index_files
     update_time INTEGER NOT NULL,
     update_by   TEXT    NOT NULL
 );
-CREATE INDEX idx_filename ON index_files (filename);
+CREATE INDEX IF NOT EXISTS idx_filename ON index_files (filename);
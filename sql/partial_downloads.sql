@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS partial_downloads
+(
+    filename        TEXT NOT NULL PRIMARY KEY,
+    hash_sum        TEXT NOT NULL,
+    temp_name       TEXT NOT NULL,
+    written_offsets TEXT NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS peer_addresses
+(
+    peer_id      TEXT    NOT NULL,
+    addr         TEXT    NOT NULL,
+    kind         TEXT    NOT NULL,
+    last_success INTEGER,
+    failures     INTEGER NOT NULL,
+    PRIMARY KEY (peer_id, addr)
+);
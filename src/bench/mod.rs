@@ -0,0 +1,59 @@
+//! the internal items used by the benchmarks and the benchsync binary, it is not a stable api
+
+use std::error::Error;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::pin::Pin;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::{Stream, TryStreamExt};
+
+pub use crate::ext::hash::{hash_file, hash_file_with_block_size};
+pub use crate::index::sqlite_index::SqliteIndex;
+pub use crate::index::{Block, BlockChain, FileDetail, FileKind, Index, IndexFile, IndexGuard};
+pub use crate::sync_control::options::SyncOptions;
+pub use crate::sync_control::rumors_event_handler::{
+    blocks_to_download_block_requests, compare_blocks, sync_file, BlocksDiff, CopyBlock,
+    LocalBlocks, RumorsEventHandler,
+};
+pub use crate::sync_control::sync_all_handler::SyncAllHandler;
+pub use crate::sync_control::timing::SyncTimings;
+pub use crate::sync_control::SendRumors;
+pub use crate::transfer::grpc::client::GrpcClient;
+pub use crate::transfer::grpc::server::GrpcServerBuilder;
+pub use crate::transfer::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+
+/// create a sqlite index with the index tables at the path
+pub async fn create_sqlite_index(db_path: &Path) -> Result<SqliteIndex> {
+    Ok(SqliteIndex::open(db_path).await?)
+}
+
+/// adapt a transfer whose error is not an io error and whose block stream is not Unpin, such as
+/// the grpc client, to the rumors event handler
+#[derive(Debug)]
+pub struct IoTransfer<D>(pub D);
+
+#[async_trait]
+impl<D> DownloadTransfer for IoTransfer<D>
+where
+    D: DownloadTransfer + Send + Sync,
+    D::Error: Send + Sync + 'static,
+{
+    type Error = io::Error;
+    type BlockStream<'a> = Pin<Box<dyn Stream<Item = Result<Option<DownloadBlock>, Self::Error>> + 'a>> where Self: 'a;
+
+    async fn download<'a>(
+        &'a self,
+        block_offset: &'a [DownloadBlockRequest],
+    ) -> Result<Self::BlockStream<'a>, Self::Error> {
+        let stream = self.0.download(block_offset).await.map_err(to_io_err)?;
+
+        Ok(Box::pin(stream.map_err(to_io_err)))
+    }
+}
+
+fn to_io_err<E: Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(ErrorKind::Other, err)
+}
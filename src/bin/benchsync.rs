@@ -0,0 +1,114 @@
+//! generate a corpus in node a, then sync it to node b with the grpc transfer in the same
+//! process, and report the throughput
+//!
+//! usage: benchsync [files] [file size in bytes]
+
+use std::env;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+use syncit::bench::{
+    create_sqlite_index, GrpcClient, GrpcServerBuilder, IoTransfer, RumorsEventHandler, SendRumors,
+    SyncAllHandler,
+};
+use tokio::fs;
+use tonic::transport::Endpoint;
+use uuid::Uuid;
+
+const DEFAULT_FILES: usize = 64;
+const DEFAULT_FILE_SIZE: usize = 4 * 1024 * 1024;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let files = match args.next() {
+        None => DEFAULT_FILES,
+        Some(files) => files.parse().context("invalid files")?,
+    };
+    let file_size = match args.next() {
+        None => DEFAULT_FILE_SIZE,
+        Some(file_size) => file_size.parse().context("invalid file size")?,
+    };
+
+    let work_dir = env::temp_dir().join(format!("benchsync-{}", Uuid::new_v4()));
+    let result = run(&work_dir, files, file_size).await;
+
+    let _ = fs::remove_dir_all(&work_dir).await;
+
+    result
+}
+
+async fn run(work_dir: &Path, files: usize, file_size: usize) -> Result<()> {
+    let dir_id = Uuid::new_v4();
+    let (user_a, user_b) = (Uuid::new_v4(), Uuid::new_v4());
+    let (dir_a, dir_b) = (work_dir.join("a"), work_dir.join("b"));
+    fs::create_dir_all(&dir_a).await?;
+    fs::create_dir_all(&dir_b).await?;
+
+    let mut data = vec![0; file_size];
+    for i in 0..files {
+        rand::thread_rng().fill_bytes(&mut data);
+        fs::write(dir_a.join(format!("file-{i}")), &data).await?;
+    }
+
+    let index_a = create_sqlite_index(&work_dir.join("a.db")).await?;
+    let index_b = create_sqlite_index(&work_dir.join("b.db")).await?;
+
+    let (sender, receiver) = flume::unbounded::<SendRumors>();
+
+    let start = Instant::now();
+    SyncAllHandler::new(&user_a, &dir_id, &dir_a, &index_a, sender.into_sink())
+        .handle_sync_all_event()
+        .await?;
+    let index_elapsed = start.elapsed();
+
+    let rumors = receiver
+        .drain()
+        .flat_map(|send_rumors| send_rumors.rumors)
+        .collect::<Vec<_>>();
+
+    let addr = free_addr()?;
+    let router = GrpcServerBuilder::new().add_dir(dir_id, dir_a).build();
+    tokio::spawn(router.serve(addr));
+
+    let channel = Endpoint::from_shared(format!("http://{addr}"))?.connect_lazy();
+    let client = IoTransfer(GrpcClient::new(channel));
+
+    // node b forwards the new rumors to the other nodes, there is none here
+    let (forward_sender, _forward_receiver) = flume::unbounded();
+
+    let start = Instant::now();
+    RumorsEventHandler::new(
+        user_b,
+        dir_id,
+        &dir_b,
+        &index_b,
+        &client,
+        forward_sender.into_sink(),
+    )
+    .handle_rumors_event(user_a, rumors)
+    .await?;
+    let sync_elapsed = start.elapsed();
+
+    let total = (files * file_size) as f64 / 1024.0 / 1024.0;
+    println!("corpus: {files} files, {total:.1} MiB");
+    println!(
+        "index node a: {index_elapsed:?}, {:.1} MiB/s",
+        total / index_elapsed.as_secs_f64()
+    );
+    println!(
+        "sync to node b: {sync_elapsed:?}, {:.1} MiB/s",
+        total / sync_elapsed.as_secs_f64()
+    );
+
+    Ok(())
+}
+
+fn free_addr() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+
+    Ok(listener.local_addr()?)
+}
@@ -0,0 +1,20 @@
+use std::fmt::Debug;
+use std::time::SystemTime;
+
+use mockall::automock;
+
+/// the time source of the handlers, tests can use a mock clock to control the update time and
+/// the conflict file name
+#[automock]
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
@@ -0,0 +1,271 @@
+//! the sync events for the external consumers, such as the GUIs and the bots. The event types
+//! are decoupled from the internal index types, the ids are hyphenated uuids, the filenames are
+//! lossy utf-8 strings and the times are RFC 3339 strings, so their json form stays stable when
+//! the internal types change. The new fields are only added with the serde defaults
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::index::address::PeerAddress;
+use crate::index::IndexFile;
+use crate::journal::{ConflictRecord, Journal, NoopJournal, OperationSource};
+use crate::sync_control::timing::FileTiming;
+
+/// the events kept for the slow subscribers, the older ones are skipped by them
+pub const DEFAULT_EVENTS_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SyncEvent {
+    FileSynced(FileSynced),
+    ConflictCreated(ConflictCreated),
+    PeerConnected(PeerConnected),
+    ScanCompleted(ScanCompleted),
+}
+
+/// where the change of the synced file is made
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOrigin {
+    /// the change of the local file is indexed and sent to the peers
+    Local,
+    /// the change of the peer is applied to the local file
+    Remote,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileSynced {
+    pub time: String,
+    pub dir_id: String,
+    pub filename: String,
+    pub origin: ChangeOrigin,
+    /// file, symlink or special
+    pub kind: String,
+    pub gen: u32,
+    pub deleted: bool,
+    /// the hex sha256 sum of the content
+    pub hash_sum: String,
+    /// the user who made the change
+    pub update_by: String,
+}
+
+/// the conflict of two peers creating the same filename independently
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ConflictCreated {
+    pub time: String,
+    pub dir_id: String,
+    pub filename: String,
+    /// where the content of the conflict side is kept, None on the peer whose file is kept
+    pub conflict_filename: Option<String>,
+    pub kept_update_by: String,
+    pub conflict_update_by: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PeerConnected {
+    pub time: String,
+    pub peer_id: String,
+    pub addr: String,
+}
+
+/// the whole sync dir is scanned
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ScanCompleted {
+    pub time: String,
+    pub dir_id: String,
+    pub duration_ms: u64,
+}
+
+/// publish the sync events to the subscribers, the clones share the subscribers. The events are
+/// dropped when there is no subscriber, and a slow subscriber skips the oldest events
+#[derive(Debug, Clone)]
+pub struct SyncEvents {
+    sender: broadcast::Sender<SyncEvent>,
+}
+
+impl Default for SyncEvents {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENTS_CAPACITY)
+    }
+}
+
+impl SyncEvents {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity.max(1)).0,
+        }
+    }
+
+    /// receive the events published after subscribing
+    pub fn subscribe(&self) -> broadcast::Receiver<SyncEvent> {
+        self.sender.subscribe()
+    }
+
+    pub(crate) fn publish(&self, event: SyncEvent) {
+        // no subscriber is not an error
+        let _ = self.sender.send(event);
+    }
+
+    pub(crate) fn file_synced(
+        &self,
+        dir_id: Uuid,
+        source: OperationSource,
+        index_file: &IndexFile,
+    ) {
+        let origin = match source {
+            OperationSource::Watch | OperationSource::SyncAll => ChangeOrigin::Local,
+            OperationSource::Rumor => ChangeOrigin::Remote,
+        };
+
+        self.publish(SyncEvent::FileSynced(FileSynced {
+            time: format_time(index_file.update_time),
+            dir_id: dir_id.as_hyphenated().to_string(),
+            filename: index_file.filename.to_string_lossy().into_owned(),
+            origin,
+            kind: index_file.kind.to_string().to_lowercase(),
+            gen: index_file.detail.gen,
+            deleted: index_file.detail.deleted,
+            hash_sum: hex::encode(index_file.detail.hash_sum),
+            update_by: index_file.update_by.clone(),
+        }));
+    }
+
+    pub(crate) fn conflict_created(&self, dir_id: Uuid, conflict: &ConflictRecord) {
+        self.publish(SyncEvent::ConflictCreated(ConflictCreated {
+            time: format_time(conflict.time),
+            dir_id: dir_id.as_hyphenated().to_string(),
+            filename: conflict.filename.to_string_lossy().into_owned(),
+            conflict_filename: conflict
+                .conflict_filename
+                .as_ref()
+                .map(|filename| filename.to_string_lossy().into_owned()),
+            kept_update_by: conflict.kept.update_by.clone(),
+            conflict_update_by: conflict.conflict.update_by.clone(),
+        }));
+    }
+
+    pub(crate) fn peer_connected(&self, address: &PeerAddress, now: SystemTime) {
+        self.publish(SyncEvent::PeerConnected(PeerConnected {
+            time: format_time(now),
+            peer_id: address.peer_id.as_hyphenated().to_string(),
+            addr: address.addr.clone(),
+        }));
+    }
+
+    pub(crate) fn scan_completed(&self, dir_id: Uuid, now: SystemTime, elapsed: Duration) {
+        self.publish(SyncEvent::ScanCompleted(ScanCompleted {
+            time: format_time(now),
+            dir_id: dir_id.as_hyphenated().to_string(),
+            duration_ms: elapsed.as_millis() as _,
+        }));
+    }
+}
+
+/// record the applied operations into the journal and publish them as the sync events
+#[derive(Debug, Clone)]
+pub(crate) struct EventJournal {
+    pub(crate) journal: Arc<dyn Journal>,
+    pub(crate) events: SyncEvents,
+}
+
+impl Default for EventJournal {
+    fn default() -> Self {
+        Self {
+            journal: Arc::new(NoopJournal),
+            events: Default::default(),
+        }
+    }
+}
+
+impl Journal for EventJournal {
+    fn record(&self, dir_id: Uuid, source: OperationSource, index_file: &IndexFile) {
+        self.journal.record(dir_id, source, index_file);
+        self.events.file_synced(dir_id, source, index_file);
+    }
+
+    fn record_conflict(&self, dir_id: Uuid, conflict: &ConflictRecord) {
+        self.journal.record_conflict(dir_id, conflict);
+        self.events.conflict_created(dir_id, conflict);
+    }
+
+    fn record_timing(&self, dir_id: Uuid, timing: &FileTiming) {
+        self.journal.record_timing(dir_id, timing);
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use super::*;
+    use crate::index::{FileDetail, FileKind};
+
+    #[tokio::test]
+    async fn publish_journal_records() {
+        let events = SyncEvents::default();
+        let mut subscriber = events.subscribe();
+        let journal = EventJournal {
+            journal: Arc::new(NoopJournal),
+            events: events.clone(),
+        };
+
+        let dir_id = Uuid::new_v4();
+        let index_file = IndexFile {
+            filename: OsString::from("test.txt"),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum: [1; 32],
+                block_chain: None,
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH,
+            update_by: "peer".to_string(),
+        };
+        journal.record(dir_id, OperationSource::Rumor, &index_file);
+
+        let event = subscriber.recv().await.unwrap();
+        assert_eq!(
+            event,
+            SyncEvent::FileSynced(FileSynced {
+                time: "1970-01-01T00:00:00.000Z".to_string(),
+                dir_id: dir_id.as_hyphenated().to_string(),
+                filename: "test.txt".to_string(),
+                origin: ChangeOrigin::Remote,
+                kind: "file".to_string(),
+                gen: 1,
+                deleted: false,
+                hash_sum: hex::encode([1; 32]),
+                update_by: "peer".to_string(),
+            })
+        );
+
+        // the json form is tagged by the event type
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "file_synced");
+        assert_eq!(json["origin"], "remote");
+        assert_eq!(serde_json::from_value::<SyncEvent>(json).unwrap(), event);
+
+        events.scan_completed(dir_id, SystemTime::UNIX_EPOCH, Duration::from_millis(20));
+        assert!(matches!(
+            subscriber.recv().await.unwrap(),
+            SyncEvent::ScanCompleted(ScanCompleted {
+                duration_ms: 20,
+                ..
+            })
+        ));
+    }
+}
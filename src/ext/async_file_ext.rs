@@ -6,7 +6,8 @@ use std::os::unix::io::{AsRawFd, FromRawFd};
 use async_trait::async_trait;
 use bytes::{Buf, Bytes, BytesMut};
 use tokio::fs::File;
-use tokio::task;
+
+use crate::ext::runtime;
 
 #[async_trait]
 pub trait AsyncFileExt {
@@ -25,13 +26,12 @@ impl AsyncFileExt for File {
 
         let mut bytes_mut = BytesMut::zeroed(buf.len());
 
-        let (mut bytes_mut, n) = task::spawn_blocking(move || {
+        let (mut bytes_mut, n) = runtime::spawn_blocking(move || {
             let n = std_file.read_at(&mut bytes_mut, offset)?;
 
             Ok::<_, Error>((bytes_mut, n))
         })
-        .await
-        .unwrap()?;
+        .await?;
 
         bytes_mut.copy_to_slice(&mut buf[..n]);
 
@@ -46,9 +46,7 @@ impl AsyncFileExt for File {
 
         let data = Bytes::copy_from_slice(data);
 
-        let n = task::spawn_blocking(move || std_file.write_at(&data, offset))
-            .await
-            .unwrap()?;
+        let n = runtime::spawn_blocking(move || std_file.write_at(&data, offset)).await?;
 
         Ok(n as _)
     }
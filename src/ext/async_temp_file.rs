@@ -1,15 +1,16 @@
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 
-use rand::distributions::Alphanumeric;
-use rand::Rng;
 use tokio::fs::{File, OpenOptions};
 use tokio::{fs, io};
 
+use crate::ext::runtime;
+
 #[derive(Debug)]
 pub struct AsyncTempFile {
     path: PathBuf,
     file: Option<File>,
+    keep: bool,
 }
 
 impl Deref for AsyncTempFile {
@@ -27,12 +28,7 @@ impl DerefMut for AsyncTempFile {
 }
 
 impl AsyncTempFile {
-    pub async fn create(dir: &Path) -> io::Result<Self> {
-        let filename = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(10)
-            .map(char::from)
-            .collect::<String>();
+    pub async fn create(dir: &Path, filename: &str) -> io::Result<Self> {
         let path = dir.join(filename);
 
         let file = OpenOptions::new()
@@ -45,6 +41,24 @@ impl AsyncTempFile {
         Ok(Self {
             path,
             file: Some(file),
+            keep: false,
+        })
+    }
+
+    /// open the temp file which is kept by the interrupted download
+    pub async fn open(dir: &Path, filename: &str) -> io::Result<Self> {
+        let path = dir.join(filename);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .await?;
+
+        Ok(Self {
+            path,
+            file: Some(file),
+            keep: false,
         })
     }
 
@@ -55,16 +69,30 @@ impl AsyncTempFile {
     pub fn close(&mut self) {
         self.file.take();
     }
+
+    /// don't remove the file when dropped, so the download can be resumed
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
 }
 
 impl Drop for AsyncTempFile {
     fn drop(&mut self) {
         let path = self.path.clone();
         let file = self.file.take();
+        if self.keep {
+            return;
+        }
 
-        tokio::spawn(async move {
-            drop(file);
-            let _ = fs::remove_file(path).await;
-        });
+        let fallback_path = path.clone();
+        runtime::spawn_detached(
+            async move {
+                drop(file);
+                let _ = fs::remove_file(path).await;
+            },
+            move || {
+                let _ = std::fs::remove_file(fallback_path);
+            },
+        );
     }
 }
@@ -1,10 +1,14 @@
 use std::io;
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, RawFd};
 
 use async_trait::async_trait;
-use nix::fcntl;
 use tokio::fs::File;
-use tokio::task;
+
+use crate::ext::runtime;
+
+/// the buffer size of the portable copy
+#[cfg(feature = "portable")]
+const COPY_BUF_SIZE: usize = 64 * 1024;
 
 #[async_trait]
 pub trait AsyncFileCopy {
@@ -28,33 +32,85 @@ impl AsyncFileCopy for File {
     ) -> io::Result<u64> {
         let self_fd = self.as_raw_fd();
         let target_fd = target.as_raw_fd();
-        let mut offset_in = offset_in as i64;
-        let mut offset_out = offset_out as i64;
 
-        let remaining = task::spawn_blocking(move || {
-            let mut remaing = size;
+        let remaining = runtime::spawn_blocking(move || {
+            copy_range(self_fd, offset_in, target_fd, offset_out, size)
+        })
+        .await?;
+
+        Ok(size - remaining)
+    }
+}
 
-            while remaing > 0 {
-                let n = fcntl::copy_file_range(
-                    self_fd,
-                    Some(&mut offset_in),
-                    target_fd,
-                    Some(&mut offset_out),
-                    remaing as _,
-                )?;
+/// copy the range in the kernel, return the remaining size when the source file ends
+#[cfg(not(feature = "portable"))]
+fn copy_range(
+    fd_in: RawFd,
+    offset_in: u64,
+    fd_out: RawFd,
+    offset_out: u64,
+    size: u64,
+) -> io::Result<u64> {
+    use nix::fcntl;
 
-                if n == 0 {
-                    return Ok::<_, io::Error>(remaing);
-                }
+    let mut offset_in = offset_in as i64;
+    let mut offset_out = offset_out as i64;
+    let mut remaing = size;
 
-                remaing -= n as u64;
-            }
+    while remaing > 0 {
+        let n = fcntl::copy_file_range(
+            fd_in,
+            Some(&mut offset_in),
+            fd_out,
+            Some(&mut offset_out),
+            remaing as _,
+        )?;
 
-            Ok(0)
-        })
-        .await
-        .unwrap()?;
+        if n == 0 {
+            return Ok(remaing);
+        }
 
-        Ok(size - remaining)
+        remaing -= n as u64;
     }
+
+    Ok(0)
+}
+
+/// copy the range by reading and writing a buffer, for the targets whose libc has no
+/// `copy_file_range`, return the remaining size when the source file ends
+#[cfg(feature = "portable")]
+fn copy_range(
+    fd_in: RawFd,
+    mut offset_in: u64,
+    fd_out: RawFd,
+    mut offset_out: u64,
+    size: u64,
+) -> io::Result<u64> {
+    use std::fs::File as StdFile;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::FromRawFd;
+
+    // Safety: the fds are owned by the files which outlive the copy, and they are not closed here
+    let file_in = unsafe { ManuallyDrop::new(StdFile::from_raw_fd(fd_in)) };
+    let file_out = unsafe { ManuallyDrop::new(StdFile::from_raw_fd(fd_out)) };
+
+    let mut buf = vec![0; COPY_BUF_SIZE.min(size as usize)];
+    let mut remaing = size;
+
+    while remaing > 0 {
+        let len = buf.len().min(remaing as usize);
+        let n = file_in.read_at(&mut buf[..len], offset_in)?;
+        if n == 0 {
+            return Ok(remaing);
+        }
+
+        file_out.write_all_at(&buf[..n], offset_out)?;
+
+        offset_in += n as u64;
+        offset_out += n as u64;
+        remaing -= n as u64;
+    }
+
+    Ok(0)
 }
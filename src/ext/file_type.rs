@@ -0,0 +1,130 @@
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+use tokio::fs;
+
+use crate::ext::SymlinkPolicy;
+use crate::index::FileKind;
+
+/// when return true, the path is a fifo, socket or device, opening or reading it may block
+/// forever, the symlink is followed. A not found path is not special
+pub async fn is_special_file(path: &Path) -> io::Result<bool> {
+    let file_type = match fs::metadata(path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+        Ok(metadata) => metadata.file_type(),
+    };
+
+    Ok(file_type.is_fifo()
+        || file_type.is_socket()
+        || file_type.is_block_device()
+        || file_type.is_char_device())
+}
+
+/// when return true, the path is a dir, the symlink is followed. A not found path is not a dir
+pub async fn is_dir(path: &Path) -> io::Result<bool> {
+    match fs::metadata(path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+        Ok(metadata) => Ok(metadata.is_dir()),
+    }
+}
+
+/// the kind of the file which is recorded in the index without content, a special file or a
+/// symlink when the symlink policy is store. When return None, the file content can be synced
+pub async fn unsyncable_kind(
+    path: &Path,
+    symlink_policy: SymlinkPolicy,
+) -> io::Result<Option<FileKind>> {
+    if symlink_policy == SymlinkPolicy::Store {
+        match fs::symlink_metadata(path).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+            Ok(metadata) if metadata.is_symlink() => return Ok(Some(FileKind::Symlink)),
+            Ok(_) => {}
+        }
+    }
+
+    if is_special_file(path).await? {
+        return Ok(Some(FileKind::Special));
+    }
+
+    Ok(None)
+}
+
+/// the dev and inode of a file which has more than one hard link, the names with the same id
+/// share the content. When return None, the file has only one name
+pub async fn hard_link_id(path: &Path) -> io::Result<Option<(u64, u64)>> {
+    let metadata = fs::metadata(path).await?;
+    if metadata.nlink() <= 1 {
+        return Ok(None);
+    }
+
+    Ok(Some((metadata.dev(), metadata.ino())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn special() {
+        assert!(is_special_file(Path::new("/dev/null")).await.unwrap());
+        assert!(!is_special_file(Path::new("/")).await.unwrap());
+        assert!(!is_special_file(Path::new("/not-exist")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unsyncable() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("test.txt");
+        let link_path = dir.path().join("link.txt");
+        fs::write(&path, b"test").await.unwrap();
+        fs::symlink(&path, &link_path).await.unwrap();
+
+        assert_eq!(
+            unsyncable_kind(&path, SymlinkPolicy::Store).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            unsyncable_kind(&link_path, SymlinkPolicy::Store)
+                .await
+                .unwrap(),
+            Some(FileKind::Symlink)
+        );
+        assert_eq!(
+            unsyncable_kind(&link_path, SymlinkPolicy::Follow)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            unsyncable_kind(Path::new("/dev/null"), SymlinkPolicy::Follow)
+                .await
+                .unwrap(),
+            Some(FileKind::Special)
+        );
+    }
+
+    #[tokio::test]
+    async fn hard_link() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("test.txt");
+        let link_path = dir.path().join("link.txt");
+        fs::write(&path, b"test").await.unwrap();
+
+        assert!(hard_link_id(&path).await.unwrap().is_none());
+
+        fs::hard_link(&path, &link_path).await.unwrap();
+
+        let id = hard_link_id(&path).await.unwrap();
+        assert!(id.is_some());
+        assert_eq!(id, hard_link_id(&link_path).await.unwrap());
+    }
+}
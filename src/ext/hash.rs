@@ -3,52 +3,246 @@ use std::io;
 use bytes::BytesMut;
 use sha2::{Digest, Sha256};
 use tap::TapFallible;
+use thiserror::Error;
+use tokio::fs::File;
 use tokio::io::{AsyncRead, AsyncReadExt};
-use tracing::error;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
+use crate::ext::{sparse, AsyncFileExt};
 use crate::index::{Block, BlockChain, Sha256sum, BLOCK_SIZE};
 
-pub async fn hash_file<R: AsyncRead + Unpin>(
-    mut reader: R,
+pub async fn hash_file<R: AsyncRead + Unpin>(reader: R) -> anyhow::Result<(Sha256sum, BlockChain)> {
+    hash_file_with_block_size(reader, BLOCK_SIZE).await
+}
+
+/// hash the file with the given block size, the last block is always shorter than the block size,
+/// so a file whose size is a multiple of the block size, or an empty file, has a trailing empty
+/// block
+pub async fn hash_file_with_block_size<R: AsyncRead + Unpin>(
+    reader: R,
+    block_size: usize,
 ) -> anyhow::Result<(Sha256sum, BlockChain)> {
-    let mut hasher = Sha256::new();
-    let mut block_hasher = Sha256::new();
+    hash_file_with_progress(reader, block_size, &CancellationToken::new(), |_| {}).await
+}
 
-    let mut buf = BytesMut::zeroed(BLOCK_SIZE);
-    let mut offset = 0;
-    let mut blocks = vec![];
+/// the hashing is stopped by the cancellation token
+#[derive(Debug, Error)]
+#[error("hashing is canceled")]
+pub struct HashCanceled;
+
+/// like [`hash_file_with_block_size`], the token is checked before reading every block, and the
+/// progress is called with the hashed bytes after every block. When the token is canceled,
+/// [`HashCanceled`] is returned
+pub async fn hash_file_with_progress<R, P>(
+    mut reader: R,
+    block_size: usize,
+    cancel: &CancellationToken,
+    mut progress: P,
+) -> anyhow::Result<(Sha256sum, BlockChain)>
+where
+    R: AsyncRead + Unpin,
+    P: FnMut(u64),
+{
+    let mut blocks_hasher = BlocksHasher::default();
+    let mut buf = BytesMut::zeroed(block_size);
     loop {
+        if cancel.is_cancelled() {
+            warn!(hashed_bytes = blocks_hasher.offset, "hashing is canceled");
+
+            return Err(HashCanceled.into());
+        }
+
         let n = read_fill(&mut reader, &mut buf)
             .await
             .tap_err(|err| error!(%err, "read file block failed"))?;
 
-        hasher.update(&buf);
+        if n == 0 {
+            break;
+        }
+
+        blocks_hasher.push(&buf[..n]);
+        progress(blocks_hasher.offset);
+
+        if n < buf.len() {
+            break;
+        }
+    }
+
+    Ok(blocks_hasher.finish(block_size))
+}
+
+/// like [`hash_file_with_progress`], but the holes of the sparse file are not read, the blocks
+/// inside the holes are the zero blocks
+pub async fn hash_sparse_file_with_progress<P>(
+    file: File,
+    block_size: usize,
+    cancel: &CancellationToken,
+    mut progress: P,
+) -> anyhow::Result<(Sha256sum, BlockChain)>
+where
+    P: FnMut(u64),
+{
+    let file_len = file
+        .metadata()
+        .await
+        .tap_err(|err| error!(%err, "get file metadata failed"))?
+        .len();
+    let data_ranges = sparse::data_ranges(&file)
+        .await
+        .tap_err(|err| error!(%err, "find file data ranges failed"))?;
+    let data_len = data_ranges
+        .iter()
+        .map(|range| range.end - range.start)
+        .sum::<u64>();
+    if data_len >= file_len {
+        return hash_file_with_progress(file, block_size, cancel, progress).await;
+    }
+
+    let mut blocks_hasher = BlocksHasher::default();
+    let zeros = BytesMut::zeroed(block_size);
+    let mut buf = BytesMut::zeroed(block_size);
+    while blocks_hasher.offset < file_len {
+        if cancel.is_cancelled() {
+            warn!(hashed_bytes = blocks_hasher.offset, "hashing is canceled");
 
-        block_hasher.update(&buf);
-        let block_hash_sum = block_hasher.finalize_reset();
+            return Err(HashCanceled.into());
+        }
+
+        let offset = blocks_hasher.offset;
+        let len = (file_len - offset).min(block_size as _) as usize;
+        if sparse::is_hole(&data_ranges, offset..offset + len as u64) {
+            blocks_hasher.push_zeros(&zeros[..len]);
+        } else {
+            let n = read_fill_at(&file, &mut buf[..len], offset)
+                .await
+                .tap_err(|err| error!(%err, offset, "read file block failed"))?;
+            blocks_hasher.push(&buf[..n]);
+
+            // the file is truncated when hashing
+            if n < len {
+                break;
+            }
+        }
+
+        progress(blocks_hasher.offset);
+    }
+
+    info!(
+        file_len,
+        data_len,
+        blocks = blocks_hasher.blocks.len(),
+        "hash sparse file done"
+    );
 
-        blocks.push(Block {
-            offset,
-            len: n as _,
-            hash_sum: block_hash_sum.into(),
+    Ok(blocks_hasher.finish(block_size))
+}
+
+/// build the block chain and the hash sum of the whole file from the blocks in order
+#[derive(Default)]
+struct BlocksHasher {
+    hasher: Sha256,
+    block_hasher: Sha256,
+    offset: u64,
+    blocks: Vec<Block>,
+    /// the hash sum of the zero block of the len, most zero blocks have the same len
+    zero_hash_sum: Option<(u64, Sha256sum)>,
+}
+
+impl BlocksHasher {
+    fn push(&mut self, data: &[u8]) {
+        if !data.is_empty() && data.iter().all(|byte| *byte == 0) {
+            self.push_zeros(data);
+
+            return;
+        }
+
+        self.block_hasher.update(data);
+        let hash_sum = self.block_hasher.finalize_reset().into();
+        self.push_block(data, hash_sum, false);
+    }
+
+    fn push_zeros(&mut self, zeros: &[u8]) {
+        let len = zeros.len() as u64;
+        let hash_sum = match self.zero_hash_sum {
+            Some((zero_len, hash_sum)) if zero_len == len => hash_sum,
+            _ => {
+                let hash_sum = Sha256::digest(zeros).into();
+                self.zero_hash_sum = Some((len, hash_sum));
+
+                hash_sum
+            }
+        };
+
+        self.push_block(zeros, hash_sum, true);
+    }
+
+    fn push_block(&mut self, data: &[u8], hash_sum: Sha256sum, zero: bool) {
+        self.hasher.update(data);
+        self.blocks.push(Block {
+            offset: self.offset,
+            len: data.len() as _,
+            hash_sum,
+            zero,
         });
 
-        offset += n as u64;
+        self.offset += data.len() as u64;
+    }
 
-        if n < buf.len() {
+    /// the trailing empty block is pushed when the last block is full or there is no block
+    fn finish(mut self, block_size: usize) -> (Sha256sum, BlockChain) {
+        if self
+            .blocks
+            .last()
+            .map_or(true, |block| block.len == block_size as u64)
+        {
+            self.push(&[]);
+        }
+
+        (
+            self.hasher.finalize().into(),
+            BlockChain {
+                block_size: block_size as _,
+                blocks: self.blocks,
+            },
+        )
+    }
+}
+
+/// the sha256 sum of the whole file, the block chain isn't built
+pub async fn file_hash_sum<R: AsyncRead + Unpin>(mut reader: R) -> io::Result<Sha256sum> {
+    let mut hasher = Sha256::new();
+    let mut buf = BytesMut::zeroed(BLOCK_SIZE);
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .await
+            .tap_err(|err| error!(%err, "read file failed"))?;
+        if n == 0 {
             break;
         }
+
+        hasher.update(&buf[..n]);
     }
 
-    let hash_sum = hasher.finalize();
+    Ok(hasher.finalize().into())
+}
 
-    Ok((
-        hash_sum.into(),
-        BlockChain {
-            block_size: BLOCK_SIZE as _,
-            blocks,
-        },
-    ))
+async fn read_fill_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<usize> {
+    let mut sum = 0;
+    while !buf.is_empty() {
+        let n = file.read_at(buf, offset).await? as usize;
+        if n == 0 {
+            return Ok(sum);
+        }
+
+        sum += n;
+        offset += n as u64;
+
+        buf = &mut buf[n..];
+    }
+
+    Ok(sum)
 }
 
 async fn read_fill<R: AsyncRead + Unpin>(reader: &mut R, mut buf: &mut [u8]) -> io::Result<usize> {
@@ -66,3 +260,158 @@ async fn read_fill<R: AsyncRead + Unpin>(reader: &mut R, mut buf: &mut [u8]) ->
 
     Ok(sum)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Cursor;
+
+    use tempfile::TempDir;
+    use tokio::fs;
+    use tokio::fs::OpenOptions;
+
+    use super::*;
+    use crate::index::{BlockSizePolicy, SYNCTHING_MAX_BLOCK_SIZE, SYNCTHING_MIN_BLOCK_SIZE};
+
+    #[tokio::test]
+    async fn hash_multiple_blocks() {
+        let data = b"testtesttest";
+        let (hash_sum, block_chain) = hash_file_with_block_size(Cursor::new(data), 8)
+            .await
+            .unwrap();
+
+        assert_eq!(hash_sum, <[u8; 32]>::from(Sha256::digest(data)));
+        assert_eq!(block_chain.block_size, 8);
+        assert_eq!(
+            block_chain.blocks,
+            vec![
+                Block {
+                    offset: 0,
+                    len: 8,
+                    hash_sum: Sha256::digest(&data[..8]).into(),
+                    zero: false,
+                },
+                Block {
+                    offset: 8,
+                    len: 4,
+                    hash_sum: Sha256::digest(&data[8..]).into(),
+                    zero: false,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hash_block_size_multiple() {
+        let (_, block_chain) = hash_file_with_block_size(Cursor::new(b"testtest"), 4)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            block_chain
+                .blocks
+                .iter()
+                .map(|block| block.len)
+                .collect::<Vec<_>>(),
+            [4, 4, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn hash_with_progress() {
+        let mut hashed = vec![];
+        hash_file_with_progress(
+            Cursor::new(b"testtesttest"),
+            8,
+            &CancellationToken::new(),
+            |hashed_bytes| hashed.push(hashed_bytes),
+        )
+        .await
+        .unwrap();
+        assert_eq!(hashed, [8, 12]);
+
+        let cancel = CancellationToken::new();
+        let err = hash_file_with_progress(Cursor::new(b"testtesttest"), 8, &cancel, |_| {
+            cancel.cancel()
+        })
+        .await
+        .unwrap_err();
+        assert!(err.is::<HashCanceled>());
+    }
+
+    #[tokio::test]
+    async fn hash_sparse_file() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("sparse");
+        let block_size = 64 * 1024;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .await
+            .unwrap();
+        file.set_len(3 * block_size as u64).await.unwrap();
+        file.write_at(b"test", block_size as _).await.unwrap();
+
+        let mut hashed = vec![];
+        let sparse_hashed = hash_sparse_file_with_progress(
+            file,
+            block_size,
+            &CancellationToken::new(),
+            |hashed_bytes| hashed.push(hashed_bytes),
+        )
+        .await
+        .unwrap();
+        assert_eq!(hashed.last(), Some(&(3 * block_size as u64)));
+
+        // the holes read as zeros
+        let hashed = hash_file_with_block_size(fs::File::open(&path).await.unwrap(), block_size)
+            .await
+            .unwrap();
+        assert_eq!(sparse_hashed, hashed);
+        assert_eq!(
+            hashed
+                .1
+                .blocks
+                .iter()
+                .map(|block| block.zero)
+                .collect::<Vec<_>>(),
+            [true, false, true, false]
+        );
+    }
+
+    #[tokio::test]
+    async fn hash_empty_file() {
+        let (hash_sum, block_chain) = hash_file(Cursor::new(b"")).await.unwrap();
+
+        assert_eq!(hash_sum, <[u8; 32]>::from(Sha256::digest(b"")));
+        assert_eq!(
+            block_chain.blocks,
+            vec![Block {
+                offset: 0,
+                len: 0,
+                hash_sum: Sha256::digest(b"").into(),
+                zero: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn syncthing_block_size() {
+        let policy = BlockSizePolicy::Syncthing;
+
+        assert_eq!(policy.block_size(0), SYNCTHING_MIN_BLOCK_SIZE);
+        assert_eq!(
+            policy.block_size(2000 * SYNCTHING_MIN_BLOCK_SIZE as u64 - 1),
+            SYNCTHING_MIN_BLOCK_SIZE
+        );
+        assert_eq!(
+            policy.block_size(2000 * SYNCTHING_MIN_BLOCK_SIZE as u64),
+            2 * SYNCTHING_MIN_BLOCK_SIZE
+        );
+        assert_eq!(policy.block_size(u64::MAX), SYNCTHING_MAX_BLOCK_SIZE);
+        assert_eq!(BlockSizePolicy::Fixed.block_size(u64::MAX), BLOCK_SIZE);
+        assert_eq!(BlockSizePolicy::Custom(4).block_size(u64::MAX), 4);
+    }
+}
@@ -0,0 +1,94 @@
+use std::io;
+#[cfg(not(feature = "portable"))]
+use std::mem;
+#[cfg(not(feature = "portable"))]
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+#[cfg(not(feature = "portable"))]
+use nix::libc::{c_int, c_long};
+#[cfg(not(feature = "portable"))]
+use nix::{ioctl_read_bad, ioctl_write_ptr_bad, request_code_read, request_code_write};
+#[cfg(not(feature = "portable"))]
+use tracing::info;
+
+#[cfg(not(feature = "portable"))]
+use crate::ext::{log_path, runtime};
+
+/// the inode flags of `chattr(1)`, see `linux/fs.h`
+#[cfg(not(feature = "portable"))]
+const FS_IMMUTABLE_FL: c_int = 0x10;
+#[cfg(not(feature = "portable"))]
+const FS_NODUMP_FL: c_int = 0x40;
+
+// the ioctls are declared with long but the kernel reads and writes an int
+#[cfg(not(feature = "portable"))]
+ioctl_read_bad!(
+    fs_ioc_getflags,
+    request_code_read!(b'f', 1, mem::size_of::<c_long>()),
+    c_int
+);
+#[cfg(not(feature = "portable"))]
+ioctl_write_ptr_bad!(
+    fs_ioc_setflags,
+    request_code_write!(b'f', 2, mem::size_of::<c_long>()),
+    c_int
+);
+
+/// the inode flags managed for the synced files, the other flags of the file are kept
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct InodeFlags {
+    /// the file can't be modified, deleted or renamed, setting or clearing it requires
+    /// `CAP_LINUX_IMMUTABLE`
+    pub immutable: bool,
+
+    /// the file is skipped by `dump(8)`
+    pub no_dump: bool,
+}
+
+/// set or clear the managed inode flags of the file, the file system may not support them, such
+/// as tmpfs, then the error is returned
+#[cfg(not(feature = "portable"))]
+pub async fn set_inode_flags(path: impl AsRef<Path>, flags: InodeFlags) -> io::Result<()> {
+    let path = path.as_ref().to_path_buf();
+
+    runtime::spawn_blocking(move || {
+        let file = std::fs::File::open(&path)?;
+
+        let mut old_flags = 0;
+        // Safety: the fd is valid and the flags is a valid pointer
+        unsafe { fs_ioc_getflags(file.as_raw_fd(), &mut old_flags) }?;
+
+        let mut new_flags = old_flags & !(FS_IMMUTABLE_FL | FS_NODUMP_FL);
+        if flags.immutable {
+            new_flags |= FS_IMMUTABLE_FL;
+        }
+        if flags.no_dump {
+            new_flags |= FS_NODUMP_FL;
+        }
+
+        if new_flags != old_flags {
+            // Safety: the fd is valid and the flags is a valid pointer
+            unsafe { fs_ioc_setflags(file.as_raw_fd(), &new_flags) }?;
+
+            info!(path = ?log_path(&path), ?flags, "set inode flags done");
+        }
+
+        Ok(())
+    })
+    .await
+}
+
+/// the portable build has no ioctl, setting the flags is unsupported, and clearing them is done
+/// because they are never set by sync
+#[cfg(feature = "portable")]
+pub async fn set_inode_flags(_path: impl AsRef<Path>, flags: InodeFlags) -> io::Result<()> {
+    if flags == InodeFlags::default() {
+        return Ok(());
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "inode flags are not supported by the portable build",
+    ))
+}
@@ -0,0 +1,164 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use sha2::{Digest, Sha256};
+
+static LOG_PRIVACY: AtomicU8 = AtomicU8::new(LogPrivacy::Full as _);
+
+/// how the paths and filenames are written into the logs
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[repr(u8)]
+pub enum LogPrivacy {
+    /// log the full path
+    #[default]
+    Full,
+
+    /// only log the last component of the path, the directory structure is hidden
+    Truncate,
+
+    /// log a short hash of the path, the same path always has the same hash, so log lines of one
+    /// file can still be correlated
+    Hash,
+}
+
+/// set the log privacy mode of the whole process, the controller sets it by the
+/// [`SyncOptions::log_privacy`](crate::sync_control::options::SyncOptions::log_privacy)
+pub fn set_log_privacy(privacy: LogPrivacy) {
+    LOG_PRIVACY.store(privacy as _, Ordering::Relaxed);
+}
+
+pub fn log_privacy() -> LogPrivacy {
+    match LOG_PRIVACY.load(Ordering::Relaxed) {
+        n if n == LogPrivacy::Truncate as u8 => LogPrivacy::Truncate,
+        n if n == LogPrivacy::Hash as u8 => LogPrivacy::Hash,
+        _ => LogPrivacy::Full,
+    }
+}
+
+/// wrap a path or filename which will be logged, it respects the log privacy mode
+pub fn log_path<P: AsRef<Path> + ?Sized>(path: &P) -> LogPath<'_> {
+    LogPath(path.as_ref())
+}
+
+#[derive(Copy, Clone)]
+pub struct LogPath<'a>(&'a Path);
+
+impl LogPath<'_> {
+    fn hash(&self) -> String {
+        let hash_sum = Sha256::digest(self.0.as_os_str().as_bytes());
+
+        format!("<{}>", hex::encode(&hash_sum[..8]))
+    }
+
+    fn truncate(&self) -> &Path {
+        self.0.file_name().map(Path::new).unwrap_or(self.0)
+    }
+}
+
+impl Debug for LogPath<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match log_privacy() {
+            LogPrivacy::Full => Debug::fmt(self.0, f),
+            LogPrivacy::Truncate => Debug::fmt(self.truncate(), f),
+            LogPrivacy::Hash => f.write_str(&self.hash()),
+        }
+    }
+}
+
+impl Display for LogPath<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match log_privacy() {
+            LogPrivacy::Full => Display::fmt(&self.0.display(), f),
+            LogPrivacy::Truncate => Display::fmt(&self.truncate().display(), f),
+            LogPrivacy::Hash => f.write_str(&self.hash()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{info, subscriber, Event, Metadata, Subscriber};
+
+    use super::*;
+
+    /// collect the path fields of the events
+    #[derive(Default)]
+    struct PathFields(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for PathFields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            if field.name() == "path" {
+                self.0.lock().unwrap().push(format!("{value:?}"));
+            }
+        }
+    }
+
+    struct CollectSubscriber(Arc<Mutex<Vec<String>>>);
+
+    impl Subscriber for CollectSubscriber {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _span: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            event.record(&mut PathFields(self.0.clone()));
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn format() {
+        let path = log_path("dir/sub/test.txt");
+
+        assert_eq!(path.to_string(), "dir/sub/test.txt");
+        assert_eq!(path.truncate(), Path::new("test.txt"));
+
+        let hash = path.hash();
+        assert_eq!(hash.len(), 18);
+        assert_eq!(hash, log_path("dir/sub/test.txt").hash());
+        assert_ne!(hash, log_path("dir/sub/test2.txt").hash());
+
+        // the privacy mode is process wide, so it is only changed in this test
+        let fields = Arc::new(Mutex::new(vec![]));
+        subscriber::with_default(CollectSubscriber(fields.clone()), || {
+            set_log_privacy(LogPrivacy::Hash);
+            info!(path = %path, "display path");
+            info!(path = ?path, "debug path");
+
+            set_log_privacy(LogPrivacy::Truncate);
+            info!(path = %path, "display path");
+            info!(path = ?path, "debug path");
+
+            set_log_privacy(LogPrivacy::Full);
+            info!(path = %path, "display path");
+        });
+
+        assert_eq!(
+            *fields.lock().unwrap(),
+            [
+                hash.clone(),
+                hash,
+                "test.txt".to_string(),
+                "\"test.txt\"".to_string(),
+                "dir/sub/test.txt".to_string(),
+            ]
+        );
+    }
+}
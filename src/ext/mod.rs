@@ -1,9 +1,23 @@
 pub use async_file_ext::AsyncFileExt;
 pub use async_temp_file::AsyncTempFile;
 pub use file_copy::AsyncFileCopy;
+pub use file_type::{hard_link_id, is_dir, unsyncable_kind};
+pub use hash::file_hash_sum;
+#[cfg(test)]
 pub use hash::hash_file;
+pub use inode_flags::{set_inode_flags, InodeFlags};
+pub use log_path::{log_path, set_log_privacy, LogPrivacy};
+pub use open::open_read;
+pub use walk_dir::{walk_dir_sorted, SymlinkPolicy, WalkLimitError, WalkLimits};
 
 mod async_file_ext;
 mod async_temp_file;
 mod file_copy;
-mod hash;
+mod file_type;
+pub mod hash;
+mod inode_flags;
+mod log_path;
+mod open;
+pub mod runtime;
+pub mod sparse;
+mod walk_dir;
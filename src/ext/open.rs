@@ -0,0 +1,77 @@
+use std::io;
+use std::path::Path;
+
+use tokio::fs::File;
+
+/// open the file to read, when no_atime is true, the access time of the file is not updated by
+/// the reads, so hashing and serving the blocks don't confuse the backup tools. Only the owner of
+/// the file or a privileged process can open it with `O_NOATIME`, otherwise the file is opened as
+/// usual. With the portable feature, the access time is always updated
+#[cfg(not(feature = "portable"))]
+pub async fn open_read(path: impl AsRef<Path>, no_atime: bool) -> io::Result<File> {
+    use nix::errno::Errno;
+    use nix::fcntl::OFlag;
+    use tokio::fs::OpenOptions;
+    use tracing::debug;
+
+    use crate::ext::log_path;
+
+    let path = path.as_ref();
+    if !no_atime {
+        return File::open(path).await;
+    }
+
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NOATIME.bits())
+        .open(path)
+        .await
+    {
+        Err(err) if err.raw_os_error() == Some(Errno::EPERM as _) => {
+            debug!(path = ?log_path(path), "open file with no atime is not permitted, fallback");
+
+            File::open(path).await
+        }
+
+        result => result,
+    }
+}
+
+#[cfg(feature = "portable")]
+pub async fn open_read(path: impl AsRef<Path>, _no_atime: bool) -> io::Result<File> {
+    File::open(path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tempfile::TempDir;
+    use tokio::fs;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn read_with_no_atime() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("test.txt");
+        fs::write(&path, b"test").await.unwrap();
+
+        for no_atime in [true, false] {
+            let mut buf = String::new();
+            open_read(&path, no_atime)
+                .await
+                .unwrap()
+                .read_to_string(&mut buf)
+                .await
+                .unwrap();
+            assert_eq!(buf, "test");
+        }
+
+        let err = open_read(dir.path().join("not_exist"), true)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
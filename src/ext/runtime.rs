@@ -0,0 +1,64 @@
+use std::future::Future;
+
+use tokio::runtime::Handle;
+
+/// run the blocking closure on the tokio blocking pool when it is called inside a tokio runtime,
+/// otherwise run it in place, so the blocking io helpers don't panic in other executors
+pub async fn spawn_blocking<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match Handle::try_current() {
+        Err(_) => f(),
+        Ok(handle) => handle.spawn_blocking(f).await.unwrap(),
+    }
+}
+
+/// spawn the future in the background when it is called inside a tokio runtime, otherwise the
+/// fallback is run in place. It is used by the cleanups in [`Drop`], which can't wait for a
+/// future
+pub fn spawn_detached<Fut, F>(fut: Fut, fallback: F)
+where
+    Fut: Future<Output = ()> + Send + 'static,
+    F: FnOnce(),
+{
+    match Handle::try_current() {
+        Err(_) => fallback(),
+        Ok(handle) => {
+            handle.spawn(fut);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn without_runtime() {
+        assert_eq!(spawn_blocking(|| 1).now_or_never(), Some(1));
+
+        let called = Cell::new(false);
+        spawn_detached(async {}, || called.set(true));
+        assert!(called.get());
+    }
+
+    #[tokio::test]
+    async fn with_runtime() {
+        assert_eq!(spawn_blocking(|| 1).await, 1);
+
+        let (sender, receiver) = flume::bounded(1);
+        spawn_detached(
+            async move {
+                sender.send_async(()).await.unwrap();
+            },
+            || unreachable!(),
+        );
+        receiver.recv_async().await.unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use std::io;
+use std::ops::Range;
+use std::os::fd::{AsRawFd, RawFd};
+
+use tokio::fs::File;
+
+use crate::ext::runtime;
+
+/// the buffer size of writing zeros when the hole can't be punched
+const ZEROS_BUF_SIZE: usize = 64 * 1024;
+
+/// the data ranges of the file found by `SEEK_DATA` and `SEEK_HOLE`, the gaps between them are
+/// the holes of the sparse file, which read as zeros. The whole file is data when the file system
+/// doesn't support finding the holes
+pub async fn data_ranges(file: &File) -> io::Result<Vec<Range<u64>>> {
+    let fd = file.as_raw_fd();
+    let len = file.metadata().await?.len();
+
+    runtime::spawn_blocking(move || find_data_ranges(fd, len)).await
+}
+
+/// the range is inside the holes between the data ranges
+pub fn is_hole(data_ranges: &[Range<u64>], range: Range<u64>) -> bool {
+    !data_ranges
+        .iter()
+        .any(|data_range| data_range.start < range.end && range.start < data_range.end)
+}
+
+/// deallocate the range of the file, it reads as zeros and the file size is kept. When the file
+/// system can't punch the hole, the zeros are written instead
+pub async fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+
+    runtime::spawn_blocking(move || punch_range(fd, offset, len)).await
+}
+
+#[cfg(not(feature = "portable"))]
+fn find_data_ranges(fd: RawFd, len: u64) -> io::Result<Vec<Range<u64>>> {
+    use nix::errno::Errno;
+    use nix::unistd::{lseek, Whence};
+
+    // the seeks move the offset of the file, restore it after finding
+    let position = lseek(fd, 0, Whence::SeekCur)?;
+    let result = (|| {
+        let mut data_ranges = vec![];
+        let mut offset = 0;
+        while offset < len {
+            let start = match lseek(fd, offset as _, Whence::SeekData) {
+                // the rest of the file is a hole
+                Err(Errno::ENXIO) => break,
+                // the file system can't find the holes
+                Err(Errno::EINVAL) => {
+                    data_ranges.push(offset..len);
+
+                    break;
+                }
+                Err(err) => return Err(err),
+                Ok(start) => start as u64,
+            };
+            let end = (lseek(fd, start as _, Whence::SeekHole)? as u64).min(len);
+
+            data_ranges.push(start..end);
+            offset = end;
+        }
+
+        Ok(data_ranges)
+    })();
+    lseek(fd, position, Whence::SeekSet)?;
+
+    Ok(result?)
+}
+
+/// finding the holes requires the libc specific seeks, the whole file is data
+#[cfg(feature = "portable")]
+fn find_data_ranges(_fd: RawFd, len: u64) -> io::Result<Vec<Range<u64>>> {
+    Ok((len > 0).then_some(0..len).into_iter().collect())
+}
+
+#[cfg(not(feature = "portable"))]
+fn punch_range(fd: RawFd, offset: u64, len: u64) -> io::Result<()> {
+    use nix::errno::Errno;
+    use nix::fcntl::{fallocate, FallocateFlags};
+
+    match fallocate(
+        fd,
+        FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        offset as _,
+        len as _,
+    ) {
+        Err(Errno::EOPNOTSUPP) => write_zeros(fd, offset, len),
+        result => Ok(result.map(|_| ())?),
+    }
+}
+
+#[cfg(feature = "portable")]
+fn punch_range(fd: RawFd, offset: u64, len: u64) -> io::Result<()> {
+    write_zeros(fd, offset, len)
+}
+
+/// write the zeros inside the file, the file size is kept
+fn write_zeros(fd: RawFd, offset: u64, len: u64) -> io::Result<()> {
+    use std::fs::File as StdFile;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::FromRawFd;
+
+    // Safety: the fd is owned by the file which outlives the writing, and it is not closed here
+    let file = unsafe { ManuallyDrop::new(StdFile::from_raw_fd(fd)) };
+
+    let end = (offset + len).min(file.metadata()?.len());
+    let zeros = vec![0; ZEROS_BUF_SIZE];
+    let mut offset = offset;
+    while offset < end {
+        let n = (end - offset).min(ZEROS_BUF_SIZE as u64);
+        file.write_all_at(&zeros[..n as usize], offset)?;
+
+        offset += n;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tempfile::TempDir;
+    use tokio::fs::OpenOptions;
+
+    use super::*;
+    use crate::ext::AsyncFileExt;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[tokio::test]
+    async fn find_and_punch_holes() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.path().join("sparse"))
+            .await
+            .unwrap();
+        file.set_len(3 * MIB).await.unwrap();
+        file.write_at(&[1; 4096], MIB).await.unwrap();
+
+        // the file systems without the hole support report the whole file as data
+        let data_ranges = data_ranges(&file).await.unwrap();
+        assert!(!is_hole(&data_ranges, MIB..MIB + 4096));
+        assert!(data_ranges.iter().all(|range| range.end <= 3 * MIB));
+
+        punch_hole(&file, MIB, 4096).await.unwrap();
+        let mut buf = [1; 4096];
+        file.read_at(&mut buf, MIB).await.unwrap();
+        assert_eq!(buf, [0; 4096]);
+        assert_eq!(file.metadata().await.unwrap().len(), 3 * MIB);
+
+        assert!(is_hole(&[0..10, 20..30], 10..20));
+        assert!(!is_hole(&[0..10, 20..30], 5..15));
+    }
+}
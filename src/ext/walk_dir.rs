@@ -0,0 +1,384 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use futures_util::Stream;
+use tap::TapFallible;
+use thiserror::Error;
+use tokio::fs;
+use tracing::{error, info, warn};
+
+use crate::ext::log_path;
+
+/// how the symlinks are handled when walking the dir
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum SymlinkPolicy {
+    /// follow the symlinks which point into the dir, a symlink to a file is listed by its own
+    /// name, a symlink to a dir is walked as a sub dir unless the dir has been walked
+    #[default]
+    Follow,
+
+    /// don't follow the symlinks, every symlink is listed by its own name, the caller stores the
+    /// symlink itself
+    Store,
+}
+
+/// default max depth of the sub dirs
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// default max number of the files in a walked dir
+pub const DEFAULT_MAX_ENTRIES: usize = 1_000_000;
+
+/// the guards of a walk, so a pathological tree fails the walk instead of exhausting the memory,
+/// 0 means no limit
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WalkLimits {
+    /// max depth of the sub dirs, the files in the walked dir are at depth 0
+    pub max_depth: usize,
+
+    /// max number of the files
+    pub max_entries: usize,
+}
+
+impl Default for WalkLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WalkLimitError {
+    #[error("dir depth exceeds the limit {0}")]
+    Depth(usize),
+
+    #[error("dir files exceed the limit {0}")]
+    Entries(usize),
+}
+
+impl WalkLimitError {
+    /// when return true, the walk failed because the dir exceeds the walk limits
+    pub fn is_walk_limit_error(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|err| err.is::<WalkLimitError>())
+    }
+}
+
+impl From<WalkLimitError> for io::Error {
+    fn from(err: WalkLimitError) -> Self {
+        io::Error::new(ErrorKind::Other, err)
+    }
+}
+
+/// a listed entry of a walking dir
+#[derive(Debug)]
+struct WalkEntry {
+    /// the name of a dir has a trailing `/`, so the files of the dir are sorted between the
+    /// entries around it, the same as the whole relative paths are sorted
+    sort_key: OsString,
+    relative_path: PathBuf,
+    /// the dev and inode of the dir, None means a file
+    dir_id: Option<(u64, u64)>,
+}
+
+/// walk the dir recursively and stream the relative filenames of the files in it, the dirs
+/// themselves are not returned. The filenames are sorted by bytes, the same order as the index
+/// files, so they can be sorted merged with the index files stream. Only the sorted entries of the
+/// dirs on the walking path are kept, so a huge dir won't keep all filenames in memory.
+///
+/// The walk fails with a [`WalkLimitError`] when the dir exceeds the limits.
+///
+/// Every dir is walked once, it is identified by dev and inode, so a symlink loop can't make the
+/// walk endless. A followed symlink which points into the dir is listed by its own name when it
+/// points to a file, a symlinked dir is skipped because the dir is walked by its real name, and
+/// the followed symlinks which point outside the dir are skipped
+pub fn walk_dir_sorted(
+    dir: &Path,
+    symlink_policy: SymlinkPolicy,
+    limits: WalkLimits,
+) -> impl Stream<Item = io::Result<OsString>> {
+    let dir = dir.to_path_buf();
+
+    async_stream::try_stream! {
+        let root = fs::canonicalize(&dir)
+            .await
+            .tap_err(|err| error!(%err, dir = ?log_path(&dir), "canonicalize dir failed"))?;
+        let root_metadata = fs::metadata(&root)
+            .await
+            .tap_err(|err| error!(%err, dir = ?log_path(&dir), "get dir metadata failed"))?;
+
+        let mut visited = HashSet::from([(root_metadata.dev(), root_metadata.ino())]);
+        // the entries of the dirs on the walking path, they are sorted in the reverse order, so
+        // the next entry is popped from the end
+        let root_entries = read_dir_sorted(&root, PathBuf::new(), symlink_policy).await?;
+        let mut walking = vec![root_entries];
+        let mut files = 0;
+
+        while let Some(entries) = walking.last_mut() {
+            let entry = match entries.pop() {
+                None => {
+                    walking.pop();
+
+                    continue;
+                }
+
+                Some(entry) => entry,
+            };
+
+            match entry.dir_id {
+                None => {
+                    if limits.max_entries > 0 && files >= limits.max_entries {
+                        error!(dir = ?log_path(&dir), max_entries = limits.max_entries, "dir files exceed the limit");
+
+                        Err(WalkLimitError::Entries(limits.max_entries))?;
+                    }
+
+                    files += 1;
+
+                    yield entry.relative_path.into_os_string();
+                }
+
+                Some(dir_id) => {
+                    if !visited.insert(dir_id) {
+                        info!(dir = ?log_path(&entry.relative_path), "dir has been walked, skip");
+
+                        continue;
+                    }
+
+                    if limits.max_depth > 0 && entry.relative_path.components().count() > limits.max_depth {
+                        error!(dir = ?log_path(&entry.relative_path), max_depth = limits.max_depth, "dir depth exceeds the limit");
+
+                        Err(WalkLimitError::Depth(limits.max_depth))?;
+                    }
+
+                    walking.push(read_dir_sorted(&root, entry.relative_path, symlink_policy).await?);
+                }
+            }
+        }
+
+        info!(dir = ?log_path(&dir), files, "walk dir done");
+    }
+}
+
+/// list the entries of a dir in the reverse sorted order
+async fn read_dir_sorted(
+    root: &Path,
+    relative_dir: PathBuf,
+    symlink_policy: SymlinkPolicy,
+) -> io::Result<Vec<WalkEntry>> {
+    let mut read_dir = fs::read_dir(root.join(&relative_dir))
+        .await
+        .tap_err(|err| error!(%err, dir = ?log_path(&relative_dir), "read dir failed"))?;
+    let mut entries = vec![];
+
+    while let Some(entry) = read_dir
+        .next_entry()
+        .await
+        .tap_err(|err| error!(%err, dir = ?log_path(&relative_dir), "read dir entry failed"))?
+    {
+        let relative_path = relative_dir.join(entry.file_name());
+        let file_type = entry
+            .file_type()
+            .await
+            .tap_err(|err| error!(%err, "get entry file type failed"))?;
+
+        if file_type.is_dir() {
+            let metadata = entry
+                .metadata()
+                .await
+                .tap_err(|err| error!(%err, "get entry metadata failed"))?;
+            let mut sort_key = entry.file_name();
+            sort_key.push("/");
+
+            entries.push(WalkEntry {
+                sort_key,
+                relative_path,
+                dir_id: Some((metadata.dev(), metadata.ino())),
+            });
+
+            continue;
+        }
+
+        if file_type.is_symlink() && symlink_policy == SymlinkPolicy::Follow {
+            let target = match fs::canonicalize(entry.path()).await {
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    warn!(path = ?log_path(&relative_path), "dangling symlink, skip");
+
+                    continue;
+                }
+
+                Err(err) => {
+                    error!(%err, path = ?log_path(&relative_path), "canonicalize symlink failed");
+
+                    return Err(err);
+                }
+
+                Ok(target) => target,
+            };
+
+            if !target.starts_with(root) {
+                warn!(path = ?log_path(&relative_path), "symlink points outside the dir, skip");
+
+                continue;
+            }
+
+            let metadata = fs::metadata(&target)
+                .await
+                .tap_err(|err| error!(%err, path = ?log_path(&relative_path), "get symlink target metadata failed"))?;
+            if metadata.is_dir() {
+                info!(path = ?log_path(&relative_path), "symlink dir is walked by its real name, skip");
+
+                continue;
+            }
+        }
+
+        entries.push(WalkEntry {
+            sort_key: entry.file_name(),
+            relative_path,
+            dir_id: None,
+        });
+    }
+
+    entries.sort_unstable_by(|a, b| b.sort_key.cmp(&a.sort_key));
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::symlink;
+
+    use futures_util::TryStreamExt;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sorted() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        fs::write(dir.path().join("b.txt"), b"b").await.unwrap();
+        fs::write(dir.path().join("a.txt"), b"a").await.unwrap();
+        fs::write(dir.path().join("C.txt"), b"c").await.unwrap();
+        fs::create_dir(dir.path().join("a")).await.unwrap();
+        fs::write(dir.path().join("a/d.txt"), b"d").await.unwrap();
+        // around the dir by the bytes order, '-' < '.' < '/' < '0'
+        fs::write(dir.path().join("a-b.txt"), b"a").await.unwrap();
+        fs::write(dir.path().join("a0.txt"), b"a").await.unwrap();
+        fs::create_dir(dir.path().join("empty")).await.unwrap();
+
+        let filenames = walk_dir_sorted(dir.path(), SymlinkPolicy::Follow, Default::default())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            filenames,
+            ["C.txt", "a-b.txt", "a.txt", "a/d.txt", "a0.txt", "b.txt"].map(OsString::from)
+        );
+    }
+
+    #[tokio::test]
+    async fn symlink_loop_and_escape() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let outside_dir = TempDir::new_in(env::temp_dir()).unwrap();
+        fs::write(outside_dir.path().join("outside.txt"), b"outside")
+            .await
+            .unwrap();
+
+        fs::create_dir(dir.path().join("sub")).await.unwrap();
+        fs::write(dir.path().join("sub/test.txt"), b"test")
+            .await
+            .unwrap();
+        symlink("..", dir.path().join("sub/loop")).unwrap();
+        symlink("test.txt", dir.path().join("sub/link.txt")).unwrap();
+        symlink(outside_dir.path(), dir.path().join("outside")).unwrap();
+        symlink(
+            outside_dir.path().join("outside.txt"),
+            dir.path().join("outside.txt"),
+        )
+        .unwrap();
+
+        let filenames = walk_dir_sorted(dir.path(), SymlinkPolicy::Follow, Default::default())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            filenames,
+            ["sub/link.txt", "sub/test.txt"].map(OsString::from)
+        );
+
+        let filenames = walk_dir_sorted(dir.path(), SymlinkPolicy::Store, Default::default())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            filenames,
+            [
+                "outside",
+                "outside.txt",
+                "sub/link.txt",
+                "sub/loop",
+                "sub/test.txt"
+            ]
+            .map(OsString::from)
+        );
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).await.unwrap();
+        fs::write(dir.path().join("a/b/test.txt"), b"test")
+            .await
+            .unwrap();
+        fs::write(dir.path().join("test.txt"), b"test")
+            .await
+            .unwrap();
+
+        let err = walk_dir_sorted(
+            dir.path(),
+            SymlinkPolicy::Follow,
+            WalkLimits {
+                max_depth: 1,
+                max_entries: 0,
+            },
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap_err();
+        assert!(WalkLimitError::is_walk_limit_error(&err));
+
+        let err = walk_dir_sorted(
+            dir.path(),
+            SymlinkPolicy::Follow,
+            WalkLimits {
+                max_depth: 0,
+                max_entries: 1,
+            },
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap_err();
+        assert!(WalkLimitError::is_walk_limit_error(&err));
+
+        let filenames = walk_dir_sorted(
+            dir.path(),
+            SymlinkPolicy::Follow,
+            WalkLimits {
+                max_depth: 2,
+                max_entries: 2,
+            },
+        )
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+        assert_eq!(filenames, ["a/b/test.txt", "test.txt"].map(OsString::from));
+    }
+}
@@ -0,0 +1,156 @@
+use std::ffi::OsStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt};
+
+use super::{FaultError, FaultPlan};
+use crate::index::{BlockChain, Index, IndexFile, IndexGuard};
+
+#[derive(Debug)]
+pub struct FaultyIndex<I> {
+    inner: I,
+    plan: Arc<FaultPlan>,
+}
+
+impl<I> FaultyIndex<I> {
+    pub fn new(inner: I, plan: Arc<FaultPlan>) -> Self {
+        Self { inner, plan }
+    }
+}
+
+#[async_trait]
+impl<I> Index for FaultyIndex<I>
+where
+    I: Index + Send + Sync,
+    I::Error: Send + Sync + 'static,
+    I::Guard: Send,
+{
+    type Error = FaultError<I::Error>;
+    type IndexStream<'a> = impl Stream<Item = Result<IndexFile, Self::Error>> where Self: 'a;
+    type Guard = FaultyIndexGuard<I::Guard>;
+
+    async fn list_all_files<'a>(&'a self) -> Result<Self::IndexStream<'a>, Self::Error> {
+        let corrupt = self.plan.next_op("list_all_files").await?;
+        let stream = self
+            .inner
+            .list_all_files()
+            .await
+            .map_err(FaultError::Inner)?;
+
+        Ok(stream.map(move |index_file| {
+            index_file
+                .map(|index_file| corrupt_index_file(index_file, corrupt))
+                .map_err(FaultError::Inner)
+        }))
+    }
+
+    async fn get_file(&self, filename: &OsStr) -> Result<Option<IndexFile>, Self::Error> {
+        let corrupt = self.plan.next_op("get_file").await?;
+        let index_file = self
+            .inner
+            .get_file(filename)
+            .await
+            .map_err(FaultError::Inner)?;
+
+        Ok(index_file.map(|index_file| corrupt_index_file(index_file, corrupt)))
+    }
+
+    async fn begin(&self) -> Result<Self::Guard, Self::Error> {
+        self.plan.next_op("begin").await?;
+        let guard = self.inner.begin().await.map_err(FaultError::Inner)?;
+
+        Ok(FaultyIndexGuard {
+            inner: guard,
+            plan: self.plan.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct FaultyIndexGuard<G> {
+    inner: G,
+    plan: Arc<FaultPlan>,
+}
+
+#[async_trait]
+impl<G> IndexGuard for FaultyIndexGuard<G>
+where
+    G: IndexGuard + Send,
+    G::Error: Send + Sync + 'static,
+{
+    type Error = FaultError<G::Error>;
+    type IndexStream<'a> = impl Stream<Item = Result<IndexFile, Self::Error>> where Self: 'a;
+
+    async fn list_all_files<'a>(&'a mut self) -> Result<Self::IndexStream<'a>, Self::Error> {
+        let corrupt = self.plan.next_op("guard list_all_files").await?;
+        let stream = self
+            .inner
+            .list_all_files()
+            .await
+            .map_err(FaultError::Inner)?;
+
+        Ok(stream.map(move |index_file| {
+            index_file
+                .map(|index_file| corrupt_index_file(index_file, corrupt))
+                .map_err(FaultError::Inner)
+        }))
+    }
+
+    async fn create_file(&mut self, file: &IndexFile) -> Result<(), Self::Error> {
+        self.plan.next_op("create_file").await?;
+
+        self.inner
+            .create_file(file)
+            .await
+            .map_err(FaultError::Inner)
+    }
+
+    async fn get_file(&mut self, filename: &OsStr) -> Result<Option<IndexFile>, Self::Error> {
+        let corrupt = self.plan.next_op("guard get_file").await?;
+        let index_file = self
+            .inner
+            .get_file(filename)
+            .await
+            .map_err(FaultError::Inner)?;
+
+        Ok(index_file.map(|index_file| corrupt_index_file(index_file, corrupt)))
+    }
+
+    async fn update_file(&mut self, file: &IndexFile) -> Result<(), Self::Error> {
+        self.plan.next_op("update_file").await?;
+
+        self.inner
+            .update_file(file)
+            .await
+            .map_err(FaultError::Inner)
+    }
+
+    async fn get_block_chain(
+        &mut self,
+        filename: &OsStr,
+        gen: u32,
+    ) -> Result<Option<BlockChain>, Self::Error> {
+        self.plan.next_op("get_block_chain").await?;
+
+        self.inner
+            .get_block_chain(filename, gen)
+            .await
+            .map_err(FaultError::Inner)
+    }
+
+    async fn commit(self) -> Result<(), Self::Error> {
+        self.plan.next_op("commit").await?;
+
+        self.inner.commit().await.map_err(FaultError::Inner)
+    }
+}
+
+/// flip the hash sum, so the index file doesn't match the file content any more
+fn corrupt_index_file(mut index_file: IndexFile, corrupt: bool) -> IndexFile {
+    if corrupt {
+        index_file.detail.hash_sum[0] ^= 0xff;
+    }
+
+    index_file
+}
@@ -0,0 +1,202 @@
+//! fault injection wrappers for the simulation harness, they can be scripted to fail, delay or
+//! corrupt the nth operation
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::time;
+use tracing::warn;
+
+pub mod index;
+pub mod transfer;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Fault {
+    /// the operation returns [`FaultError::Injected`] without calling the inner one
+    Fail,
+
+    /// delay the operation
+    Delay(Duration),
+
+    /// the operation returns corrupt data, it is ignored by the operations which don't return
+    /// data
+    Corrupt,
+}
+
+#[derive(Debug, Error)]
+pub enum FaultError<E: Error + 'static> {
+    #[error("injected fault")]
+    Injected,
+
+    #[error(transparent)]
+    Inner(E),
+}
+
+/// the fault script, operations of all wrappers sharing the plan are counted together, the first
+/// operation is 1
+#[derive(Debug, Default)]
+pub struct FaultPlan {
+    ops: AtomicU64,
+    faults: Mutex<HashMap<u64, Fault>>,
+}
+
+impl FaultPlan {
+    pub fn inject(&self, nth: u64, fault: Fault) -> &Self {
+        self.faults.lock().unwrap().insert(nth, fault);
+
+        self
+    }
+
+    /// how many operations have been called
+    pub fn ops(&self) -> u64 {
+        self.ops.load(Ordering::Relaxed)
+    }
+
+    /// count a new operation, return Err when it should fail, true when it should return corrupt
+    /// data
+    async fn next_op<E: Error + 'static>(&self, op: &'static str) -> Result<bool, FaultError<E>> {
+        let nth = self.ops.fetch_add(1, Ordering::Relaxed) + 1;
+        let fault = self.faults.lock().unwrap().remove(&nth);
+
+        match fault {
+            None => Ok(false),
+            Some(Fault::Fail) => {
+                warn!(nth, op, "inject fail fault");
+
+                Err(FaultError::Injected)
+            }
+            Some(Fault::Delay(delay)) => {
+                warn!(nth, op, ?delay, "inject delay fault");
+
+                time::sleep(delay).await;
+
+                Ok(false)
+            }
+            Some(Fault::Corrupt) => {
+                warn!(nth, op, "inject corrupt fault");
+
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::io;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use bytes::Bytes;
+    use futures_util::{stream, TryStreamExt};
+    use uuid::Uuid;
+
+    use super::index::FaultyIndex;
+    use super::transfer::FaultyTransfer;
+    use super::*;
+    use crate::index::{FileDetail, FileKind, Index, IndexFile, MockIndex};
+    use crate::transfer::{DownloadBlock, DownloadTransfer, MockDownloadTransfer};
+
+    #[tokio::test]
+    async fn transfer_faults() {
+        let mut download_transfer = MockDownloadTransfer::new();
+        download_transfer.expect_download().returning(|_| {
+            Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+                offset: 0,
+                data: Bytes::from_static(b"test"),
+            }))])))
+        });
+
+        let plan = Arc::new(FaultPlan::default());
+        plan.inject(1, Fault::Fail)
+            .inject(2, Fault::Corrupt)
+            .inject(3, Fault::Delay(Duration::from_millis(10)));
+        let transfer = FaultyTransfer::new(download_transfer, plan.clone());
+
+        assert!(matches!(
+            transfer.download(&[]).await,
+            Err(FaultError::Injected)
+        ));
+
+        let blocks = transfer
+            .download(&[])
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_ne!(
+            blocks[0].as_ref().unwrap().data,
+            Bytes::from_static(b"test")
+        );
+
+        for _ in 0..2 {
+            let blocks = transfer
+                .download(&[])
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            assert_eq!(
+                blocks[0].as_ref().unwrap().data,
+                Bytes::from_static(b"test")
+            );
+        }
+
+        assert_eq!(plan.ops(), 4);
+    }
+
+    #[tokio::test]
+    async fn index_faults() {
+        let index_file = IndexFile {
+            filename: OsString::from("test.txt"),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum: [0; 32],
+                block_chain: None,
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH,
+            update_by: Uuid::new_v4().to_string(),
+        };
+
+        let mut index = MockIndex::new();
+        {
+            let index_file = index_file.clone();
+
+            index
+                .expect_get_file()
+                .returning(move |_| Ok::<_, io::Error>(Some(index_file.clone())));
+        }
+
+        let plan = Arc::new(FaultPlan::default());
+        plan.inject(2, Fault::Corrupt).inject(3, Fault::Fail);
+        let index = FaultyIndex::new(index, plan);
+
+        let filename = OsString::from("test.txt");
+        assert_eq!(
+            index.get_file(&filename).await.unwrap(),
+            Some(index_file.clone())
+        );
+        assert_ne!(
+            index.get_file(&filename).await.unwrap(),
+            Some(index_file.clone())
+        );
+        assert!(matches!(
+            index.get_file(&filename).await,
+            Err(FaultError::Injected)
+        ));
+        assert_eq!(index.get_file(&filename).await.unwrap(), Some(index_file));
+    }
+}
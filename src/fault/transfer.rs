@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures_util::{Stream, StreamExt};
+
+use super::{FaultError, FaultPlan};
+use crate::transfer::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+
+#[derive(Debug)]
+pub struct FaultyTransfer<D> {
+    inner: D,
+    plan: Arc<FaultPlan>,
+}
+
+impl<D> FaultyTransfer<D> {
+    pub fn new(inner: D, plan: Arc<FaultPlan>) -> Self {
+        Self { inner, plan }
+    }
+}
+
+#[async_trait]
+impl<D> DownloadTransfer for FaultyTransfer<D>
+where
+    D: DownloadTransfer + Send + Sync,
+    D::Error: Send + Sync + 'static,
+{
+    type Error = FaultError<D::Error>;
+    type BlockStream<'a> = impl Stream<Item = Result<Option<DownloadBlock>, Self::Error>> where Self: 'a;
+
+    async fn download<'a>(
+        &'a self,
+        block_offset: &'a [DownloadBlockRequest],
+    ) -> Result<Self::BlockStream<'a>, Self::Error> {
+        let corrupt = self.plan.next_op("download").await?;
+        let stream = self
+            .inner
+            .download(block_offset)
+            .await
+            .map_err(FaultError::Inner)?;
+
+        Ok(stream.map(move |block| {
+            block
+                .map(|block| block.map(|block| corrupt_block(block, corrupt)))
+                .map_err(FaultError::Inner)
+        }))
+    }
+}
+
+/// flip the first byte of the block data
+fn corrupt_block(block: DownloadBlock, corrupt: bool) -> DownloadBlock {
+    if !corrupt || block.data.is_empty() {
+        return block;
+    }
+
+    let mut data = BytesMut::from(&block.data[..]);
+    data[0] ^= 0xff;
+
+    DownloadBlock {
+        offset: block.offset,
+        data: data.freeze(),
+    }
+}
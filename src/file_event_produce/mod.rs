@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::error::Error;
 use std::ffi::OsString;
 
@@ -25,6 +27,40 @@ pub enum WatchEvent {
     },
 }
 
+/// coalesce the burst of events per path, the later add or modify events of a path are dropped
+/// when the path already has an add or modify event, and a delete event drops them, because the
+/// file is read when the event is handled, not when the event happens. A rename may move the
+/// whole dir, so the events before it are never coalesced with the events after it
+pub fn coalesce_watch_events(watch_events: Vec<WatchEvent>) -> Vec<WatchEvent> {
+    let mut coalesced = Vec::with_capacity(watch_events.len());
+    // the index of the kept add or modify event of the path
+    let mut changed = HashMap::new();
+
+    for event in watch_events {
+        match &event {
+            WatchEvent::Add { name } | WatchEvent::Modify { name } => {
+                if changed.contains_key(name) {
+                    continue;
+                }
+
+                changed.insert(name.clone(), coalesced.len());
+            }
+
+            WatchEvent::Delete { name } => {
+                if let Some(index) = changed.remove(name) {
+                    coalesced[index] = None;
+                }
+            }
+
+            WatchEvent::Rename { .. } => changed.clear(),
+        }
+
+        coalesced.push(Some(event));
+    }
+
+    coalesced.into_iter().flatten().collect()
+}
+
 #[async_trait]
 pub trait WatchControl {
     type Error: Error;
@@ -33,3 +69,63 @@ pub trait WatchControl {
 
     async fn resume_watch(&mut self) -> Result<(), Self::Error>;
 }
+
+/// the watch control of the headless peer which has no watcher
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoWatch;
+
+#[async_trait]
+impl WatchControl for NoWatch {
+    type Error = Infallible;
+
+    async fn pause_watch(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn resume_watch(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(name: &str) -> WatchEvent {
+        WatchEvent::Add { name: name.into() }
+    }
+
+    fn modify(name: &str) -> WatchEvent {
+        WatchEvent::Modify { name: name.into() }
+    }
+
+    fn delete(name: &str) -> WatchEvent {
+        WatchEvent::Delete { name: name.into() }
+    }
+
+    #[test]
+    fn coalesce() {
+        let rename = WatchEvent::Rename {
+            old_name: "dir".into(),
+            new_name: "new_dir".into(),
+        };
+
+        let watch_events = vec![
+            add("a"),
+            modify("b"),
+            modify("a"),
+            modify("a"),
+            modify("b"),
+            add("c"),
+            modify("c"),
+            delete("c"),
+            rename.clone(),
+            modify("a"),
+        ];
+
+        assert_eq!(
+            coalesce_watch_events(watch_events),
+            [add("a"), modify("b"), delete("c"), rename, modify("a")]
+        );
+    }
+}
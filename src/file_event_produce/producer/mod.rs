@@ -1,26 +1,36 @@
 use std::collections::HashMap;
+use std::future;
 use std::io::{self, ErrorKind as IoErrorKind};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use flume::Receiver;
 use futures_util::task::noop_waker_ref;
-use futures_util::{Sink, SinkExt, TryStreamExt};
+use futures_util::{Sink, SinkExt, StreamExt, TryStreamExt};
 use notify::event::{CreateKind, ModifyKind, RemoveKind, RenameMode};
 use notify::{
     ErrorKind, Event as NotifyEvent, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
 };
+use poll::{PollState, Poller};
 use tap::TapFallible;
-use tracing::{error, warn};
+use tokio::select;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tracing::{error, info, warn};
 
-use crate::file_event_produce::{WatchControl, WatchEvent};
+use crate::ext::log_path;
+use crate::file_event_produce::{coalesce_watch_events, WatchControl, WatchEvent};
 use crate::sync_control::event::Event;
 
 pub struct Producer<Si> {
     dir: PathBuf,
     receiver: Receiver<Result<NotifyEvent, notify::Error>>,
     sync_control_event_sender: Si,
+    debounce: Duration,
+    poller: Poller,
+    poll_interval: Duration,
 }
 
 impl<Si> Producer<Si> {
@@ -34,16 +44,41 @@ impl<Si> Producer<Si> {
                 }
             })
             .map_err(notify_err_to_io_err)?;
+        let poll_state = Arc::new(Mutex::new(PollState::default()));
 
         Ok((
             Self {
                 dir: dir.clone(),
                 receiver,
                 sync_control_event_sender,
+                debounce: Duration::ZERO,
+                poller: Poller::new(dir.clone(), poll_state.clone()),
+                poll_interval: Duration::ZERO,
+            },
+            Controller {
+                dir,
+                dir_watcher,
+                poll_state,
             },
-            Controller { dir, dir_watcher },
         ))
     }
+
+    /// after receiving an event, wait the window and collect the following events, so a burst
+    /// of events, such as copying a dir into the sync dir, is sent as one coalesced batch
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+
+        self
+    }
+
+    /// poll the modified times of the files besides the notify events, such as the sync dir is
+    /// a NFS or SMB mount, the changes made on the server side are not notified. The interval
+    /// should be long, every poll reads the whole dir, zero means disabled
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+
+        self
+    }
 }
 
 impl<Si> Producer<Si>
@@ -53,12 +88,46 @@ where
 {
     pub async fn run(&mut self) -> io::Result<()> {
         let mut receiver_stream = self.receiver.stream();
+        let mut poll_interval = (!self.poll_interval.is_zero()).then(|| {
+            let mut poll_interval = time::interval(self.poll_interval);
+            poll_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            poll_interval
+        });
+
+        loop {
+            let event = select! {
+                event = receiver_stream.try_next() => event.map_err(|err| {
+                    error!(%err, "receive event from watcher failed");
+
+                    notify_err_to_io_err(err)
+                })?,
+
+                _ = tick(&mut poll_interval) => {
+                    // a failed poll, such as the network filesystem is unavailable for a while,
+                    // is retried by the next tick
+                    let watch_events = self
+                        .poller
+                        .poll()
+                        .await
+                        .tap_err(|err| warn!(%err, dir = ?log_path(&self.dir), "poll dir failed"))
+                        .unwrap_or_default();
+                    if !watch_events.is_empty() {
+                        Self::send_watch_events(
+                            &mut self.sync_control_event_sender,
+                            watch_events,
+                        )
+                        .await?;
+                    }
 
-        while let Some(event) = receiver_stream.try_next().await.map_err(|err| {
-            error!(%err, "receive event from watcher failed");
+                    continue;
+                }
+            };
+            let event = match event {
+                None => break,
+                Some(event) => event,
+            };
 
-            notify_err_to_io_err(err)
-        })? {
             let mut events = vec![event];
             // try to collect more events but without await
             loop {
@@ -70,7 +139,7 @@ where
                         notify_err_to_io_err(err)
                     })? {
                     Poll::Ready(None) => {
-                        error!(dir = ?self.dir, "watcher is stopped unexpectedly");
+                        error!(dir = ?log_path(&self.dir), "watcher is stopped unexpectedly");
 
                         return Err(io::Error::new(
                             IoErrorKind::Other,
@@ -84,10 +153,30 @@ where
                 }
             }
 
+            if !self.debounce.is_zero() {
+                let deadline = Instant::now() + self.debounce;
+                while let Ok(event) = time::timeout_at(deadline, receiver_stream.next()).await {
+                    match event {
+                        // the stopped watcher is reported by the next receiving
+                        None => break,
+                        Some(event) => events.push(event.map_err(|err| {
+                            error!(%err, "receive event from watcher failed");
+
+                            notify_err_to_io_err(err)
+                        })?),
+                    }
+                }
+
+                info!(
+                    count = events.len(),
+                    "collect events in debounce window done"
+                );
+            }
+
             Self::handle_events(&mut self.sync_control_event_sender, events).await?;
         }
 
-        warn!(dir = ?self.dir, "dir watcher is stopped");
+        warn!(dir = ?log_path(&self.dir), "dir watcher is stopped");
 
         Err(io::Error::new(
             IoErrorKind::Other,
@@ -110,9 +199,17 @@ where
         }
 
         Self::compose_rename_events(rename_events, &mut all_watch_events);
+        let all_watch_events = coalesce_watch_events(all_watch_events);
+
+        Self::send_watch_events(sync_control_event_sender, all_watch_events).await
+    }
 
+    async fn send_watch_events(
+        sync_control_event_sender: &mut Si,
+        watch_events: Vec<WatchEvent>,
+    ) -> io::Result<()> {
         sync_control_event_sender
-            .send(Event::Watch(all_watch_events))
+            .send(Event::Watch(watch_events))
             .await
             .map_err(Into::into)
             .tap_err(|err| error!(%err, "send watch events to sync control failed"))?;
@@ -164,12 +261,12 @@ where
                     ModifyKind::Name(rename_mode) => {
                         return match rename_mode {
                             RenameMode::Any | RenameMode::Other => {
-                                warn!(?rename_mode, paths = ?event.paths, "unhandled rename event, ignore");
+                                warn!(?rename_mode, paths = ?event.paths.iter().map(log_path).collect::<Vec<_>>(), "unhandled rename event, ignore");
 
                                 None
                             }
                             RenameMode::To | RenameMode::From => {
-                                match event.paths.get(0) {
+                                match event.paths.first() {
                                     None => {
                                         warn!(?event, "no paths event, ignore");
 
@@ -184,7 +281,7 @@ where
                             }
                             RenameMode::Both => {
                                 if event.paths.len() != 2 {
-                                    warn!(?event, "rename event doesn't have 2 path, ignore");
+                                    warn!(paths = ?event.paths.iter().map(log_path).collect::<Vec<_>>(), "rename event doesn't have 2 path, ignore");
 
                                     return None;
                                 }
@@ -259,6 +356,7 @@ where
 pub struct Controller {
     dir: PathBuf,
     dir_watcher: RecommendedWatcher,
+    poll_state: Arc<Mutex<PollState>>,
 }
 
 #[async_trait]
@@ -266,6 +364,8 @@ impl WatchControl for Controller {
     type Error = io::Error;
 
     async fn pause_watch(&mut self) -> Result<(), Self::Error> {
+        self.poll_state.lock().unwrap().pause();
+
         self.dir_watcher
             .unwatch(&self.dir)
             .map_err(notify_err_to_io_err)
@@ -274,7 +374,21 @@ impl WatchControl for Controller {
     async fn resume_watch(&mut self) -> Result<(), Self::Error> {
         self.dir_watcher
             .watch(&self.dir, RecursiveMode::NonRecursive)
-            .map_err(notify_err_to_io_err)
+            .map_err(notify_err_to_io_err)?;
+
+        self.poll_state.lock().unwrap().resume();
+
+        Ok(())
+    }
+}
+
+/// wait the next poll tick, it never completes when the polling is disabled
+async fn tick(poll_interval: &mut Option<Interval>) {
+    match poll_interval {
+        None => future::pending().await,
+        Some(poll_interval) => {
+            poll_interval.tick().await;
+        }
     }
 }
 
@@ -286,6 +400,8 @@ fn notify_err_to_io_err(err: notify::Error) -> io::Error {
     }
 }
 
+mod poll;
+
 #[cfg(test)]
 mod tests {
     use std::env;
@@ -338,6 +454,94 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_debounce() {
+        let temp_dir = tempfile::tempdir_in(env::temp_dir()).unwrap();
+        let temp_dir_path = temp_dir.path();
+        let (sender, receiver) = flume::unbounded();
+        let sender = sender
+            .into_sink()
+            .sink_map_err(|err| io::Error::new(IoErrorKind::Other, err));
+        let (producer, mut controller) =
+            Producer::new(temp_dir_path.to_path_buf(), sender).unwrap();
+        let mut producer = producer.with_debounce(Duration::from_millis(500));
+
+        controller.resume_watch().await.unwrap();
+        tokio::spawn(async move { producer.run().await });
+
+        let file_path = temp_dir_path.join("test.txt");
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&file_path)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            file.write_all(b"test").await.unwrap();
+            file.flush().await.unwrap();
+        }
+
+        let event = receiver.recv_async().await.unwrap();
+
+        controller.pause_watch().await.unwrap();
+
+        let watch_events = match event {
+            Event::Watch(watch_events) => watch_events,
+            _ => {
+                panic!("wrong event type")
+            }
+        };
+
+        assert_eq!(
+            watch_events,
+            [WatchEvent::Add {
+                name: file_path.into_os_string()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_poll() {
+        let temp_dir = tempfile::tempdir_in(env::temp_dir()).unwrap();
+        let temp_dir_path = temp_dir.path();
+        let (sender, receiver) = flume::unbounded();
+        let sender = sender
+            .into_sink()
+            .sink_map_err(|err| io::Error::new(IoErrorKind::Other, err));
+        let (producer, mut controller) =
+            Producer::new(temp_dir_path.to_path_buf(), sender).unwrap();
+        let mut producer = producer.with_poll_interval(Duration::from_millis(200));
+
+        controller.resume_watch().await.unwrap();
+        // the change is made by another client of the network filesystem, it isn't notified
+        controller.dir_watcher.unwatch(temp_dir_path).unwrap();
+        tokio::spawn(async move { producer.run().await });
+
+        // wait the first poll taking the snapshot
+        time::sleep(Duration::from_millis(100)).await;
+        let file_path = temp_dir_path.join("test.txt");
+        fs::write(&file_path, b"test").await.unwrap();
+
+        let event = receiver.recv_async().await.unwrap();
+
+        // the dir is unwatched already
+        controller.poll_state.lock().unwrap().pause();
+
+        let watch_events = match event {
+            Event::Watch(watch_events) => watch_events,
+            _ => {
+                panic!("wrong event type")
+            }
+        };
+
+        assert_eq!(
+            watch_events,
+            [WatchEvent::Add {
+                name: file_path.into_os_string()
+            }]
+        );
+    }
+
     #[tokio::test]
     async fn test_modify() {
         let temp_dir = tempfile::tempdir_in(env::temp_dir()).unwrap();
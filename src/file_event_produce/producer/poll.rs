@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tap::TapFallible;
+use tokio::fs;
+use tracing::{error, info};
+
+use crate::ext::log_path;
+use crate::file_event_produce::WatchEvent;
+
+/// the modified time and the size of the files
+type Snapshot = HashMap<OsString, (SystemTime, u64)>;
+
+/// the poll state shared with the [`Controller`](super::Controller), the files are not polled when
+/// the watch is paused, and the changes made when the watch is paused are never reported, same as
+/// the notify events
+#[derive(Debug)]
+pub(super) struct PollState {
+    paused: bool,
+    snapshot: Option<Snapshot>,
+}
+
+impl Default for PollState {
+    fn default() -> Self {
+        // the watch starts by resume_watch
+        Self {
+            paused: true,
+            snapshot: None,
+        }
+    }
+}
+
+impl PollState {
+    pub(super) fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// the next poll takes the new snapshot
+    pub(super) fn resume(&mut self) {
+        self.paused = false;
+        self.snapshot = None;
+    }
+}
+
+/// poll the modified times of the files in the dir, the network filesystems, such as NFS and
+/// SMB, don't notify the changes made by the other clients of the server
+#[derive(Debug)]
+pub(super) struct Poller {
+    dir: PathBuf,
+    state: Arc<Mutex<PollState>>,
+}
+
+impl Poller {
+    pub(super) fn new(dir: PathBuf, state: Arc<Mutex<PollState>>) -> Self {
+        Self { dir, state }
+    }
+
+    /// compare the files with the last snapshot, the first poll after resuming only takes the
+    /// snapshot
+    pub(super) async fn poll(&self) -> io::Result<Vec<WatchEvent>> {
+        if self.state.lock().unwrap().paused {
+            return Ok(vec![]);
+        }
+
+        let snapshot = self.scan().await?;
+
+        let mut state = self.state.lock().unwrap();
+        // paused when scanning
+        if state.paused {
+            return Ok(vec![]);
+        }
+
+        let watch_events = match state.snapshot.replace(snapshot) {
+            None => vec![],
+            Some(old_snapshot) => {
+                diff_snapshots(&self.dir, &old_snapshot, state.snapshot.as_ref().unwrap())
+            }
+        };
+
+        info!(count = watch_events.len(), "poll dir done");
+
+        Ok(watch_events)
+    }
+
+    async fn scan(&self) -> io::Result<Snapshot> {
+        let mut snapshot = HashMap::new();
+        let mut entries = fs::read_dir(&self.dir)
+            .await
+            .tap_err(|err| error!(%err, dir = ?log_path(&self.dir), "read dir failed"))?;
+        while let Some(entry) = entries.next_entry().await? {
+            // the file may be deleted after listing
+            let metadata = match entry.metadata().await {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                result => result?,
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+
+            snapshot.insert(entry.file_name(), (metadata.modified()?, metadata.len()));
+        }
+
+        Ok(snapshot)
+    }
+}
+
+/// the names of the events are the paths of the files, same as the notify events
+fn diff_snapshots(dir: &Path, old: &Snapshot, new: &Snapshot) -> Vec<WatchEvent> {
+    let mut names = old.keys().chain(new.keys()).collect::<Vec<_>>();
+    names.sort_unstable();
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let path = dir.join(name).into_os_string();
+            match (old.get(name), new.get(name)) {
+                (None, Some(_)) => Some(WatchEvent::Add { name: path }),
+                (Some(_), None) => Some(WatchEvent::Delete { name: path }),
+                (Some(old_state), Some(new_state)) if old_state != new_state => {
+                    Some(WatchEvent::Modify { name: path })
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn diff() {
+        let time = SystemTime::UNIX_EPOCH;
+        let old = Snapshot::from([
+            ("a".into(), (time, 1)),
+            ("b".into(), (time, 1)),
+            ("c".into(), (time, 1)),
+        ]);
+        let new = Snapshot::from([
+            ("a".into(), (time, 1)),
+            ("b".into(), (time + Duration::from_secs(1), 1)),
+            ("d".into(), (time, 1)),
+        ]);
+
+        assert_eq!(
+            diff_snapshots(Path::new("/dir"), &old, &new),
+            [
+                WatchEvent::Modify {
+                    name: "/dir/b".into()
+                },
+                WatchEvent::Delete {
+                    name: "/dir/c".into()
+                },
+                WatchEvent::Add {
+                    name: "/dir/d".into()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+use mockall::automock;
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use uuid::{Builder, Uuid};
+
+/// the namespace of the dir ids derived from external identifiers, don't change it, or the
+/// derived dir ids will change
+const DIR_ID_NAMESPACE: Uuid = Uuid::from_u128(0x5f3a_8c1e_7d2b_4e60_9a4f_1c6b_3e8d_2a71);
+
+const TEMP_NAME_LEN: usize = 10;
+
+/// derive a stable dir id from an external identifier, the same identifier always gets the same
+/// dir id
+pub fn derive_dir_id(external_id: &str) -> Uuid {
+    Uuid::new_v5(&DIR_ID_NAMESPACE, external_id.as_bytes())
+}
+
+/// the source of the random ids and temp file names, the simulation harness can use a seeded one
+/// to get reproducible runs
+#[automock]
+pub trait IdSource: Debug + Send + Sync {
+    fn new_id(&self) -> Uuid;
+
+    fn temp_name(&self) -> String;
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RandomIdSource;
+
+impl IdSource for RandomIdSource {
+    fn new_id(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+
+    fn temp_name(&self) -> String {
+        random_name(&mut rand::thread_rng())
+    }
+}
+
+/// generate the same ids and temp file names with the same seed
+#[derive(Debug)]
+pub struct SeededIdSource {
+    rng: Mutex<StdRng>,
+}
+
+impl SeededIdSource {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl IdSource for SeededIdSource {
+    fn new_id(&self) -> Uuid {
+        let mut bytes = [0; 16];
+        self.rng.lock().unwrap().fill_bytes(&mut bytes);
+
+        Builder::from_random_bytes(bytes).into_uuid()
+    }
+
+    fn temp_name(&self) -> String {
+        random_name(&mut *self.rng.lock().unwrap())
+    }
+}
+
+fn random_name<R: Rng>(rng: &mut R) -> String {
+    rng.sample_iter(&Alphanumeric)
+        .take(TEMP_NAME_LEN)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded() {
+        let source = SeededIdSource::new(1);
+        let other = SeededIdSource::new(1);
+
+        assert_eq!(source.new_id(), other.new_id());
+        assert_eq!(source.temp_name(), other.temp_name());
+        assert_ne!(source.new_id(), SeededIdSource::new(2).new_id());
+        assert_eq!(source.temp_name().len(), TEMP_NAME_LEN);
+    }
+
+    #[test]
+    fn derive() {
+        assert_eq!(derive_dir_id("photos"), derive_dir_id("photos"));
+        assert_ne!(derive_dir_id("photos"), derive_dir_id("music"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::fmt::Debug;
+use std::io;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use mockall::automock;
+use uuid::Uuid;
+
+/// where the address of a peer is reachable, the order is the connection preference when the
+/// addresses have the same failures
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum AddressKind {
+    Lan,
+    Wan,
+    Relay,
+}
+
+impl AddressKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AddressKind::Lan => "lan",
+            AddressKind::Wan => "wan",
+            AddressKind::Relay => "relay",
+        }
+    }
+}
+
+impl FromStr for AddressKind {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lan" => Ok(AddressKind::Lan),
+            "wan" => Ok(AddressKind::Wan),
+            "relay" => Ok(AddressKind::Relay),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid address kind: {s}"),
+            )),
+        }
+    }
+}
+
+/// a candidate address of a peer, such as `http://192.168.1.2:8080`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PeerAddress {
+    pub peer_id: Uuid,
+    pub addr: String,
+    pub kind: AddressKind,
+    /// the last time the address was connected
+    pub last_success: Option<SystemTime>,
+    /// the consecutive connect failures, reset by a success
+    pub failures: u32,
+}
+
+impl PeerAddress {
+    pub fn new(peer_id: Uuid, addr: impl Into<String>, kind: AddressKind) -> Self {
+        Self {
+            peer_id,
+            addr: addr.into(),
+            kind,
+            last_success: None,
+            failures: 0,
+        }
+    }
+}
+
+#[automock]
+#[async_trait]
+pub trait AddressStore: Debug + Send + Sync {
+    /// load the addresses of all peers
+    async fn load_addresses(&self) -> io::Result<Vec<PeerAddress>>;
+
+    /// save the address, the previous state of the same peer address is replaced
+    async fn save_address(&self, address: &PeerAddress) -> io::Result<()>;
+
+    async fn remove_address(&self, peer_id: Uuid, addr: &str) -> io::Result<()>;
+}
+
+/// never persist the addresses, the address book is lost after restarting
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoopAddressStore;
+
+#[async_trait]
+impl AddressStore for NoopAddressStore {
+    async fn load_addresses(&self) -> io::Result<Vec<PeerAddress>> {
+        Ok(vec![])
+    }
+
+    async fn save_address(&self, _address: &PeerAddress) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn remove_address(&self, _peer_id: Uuid, _addr: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt::Debug;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use mockall::automock;
+
+use super::Sha256sum;
+
+/// the extension of the conflict copies, the copies are never synced
+pub const CONFLICT_EXTENSION: &str = "conflict";
+
+/// a conflict copy in the sync dir which is not resolved
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConflictEntry {
+    /// the conflict copy, it holds the local content which lost the conflict
+    pub conflict_filename: OsString,
+    /// the synced file which the conflict copy is made of
+    pub filename: OsString,
+    /// the hash sum of the conflict copy content
+    pub hash_sum: Sha256sum,
+    pub time: SystemTime,
+}
+
+#[automock]
+#[async_trait]
+pub trait ConflictStore: Debug + Send + Sync {
+    /// the conflicts are sorted by the conflict filename
+    async fn list_conflicts(&self) -> io::Result<Vec<ConflictEntry>>;
+
+    async fn get_conflict(&self, conflict_filename: &OsStr) -> io::Result<Option<ConflictEntry>>;
+
+    async fn add_conflict(&self, conflict: &ConflictEntry) -> io::Result<()>;
+
+    async fn remove_conflict(&self, conflict_filename: &OsStr) -> io::Result<()>;
+}
+
+/// never track the conflicts, the conflict copies are left in the sync dir
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoopConflictStore;
+
+#[async_trait]
+impl ConflictStore for NoopConflictStore {
+    async fn list_conflicts(&self) -> io::Result<Vec<ConflictEntry>> {
+        Ok(vec![])
+    }
+
+    async fn get_conflict(&self, _conflict_filename: &OsStr) -> io::Result<Option<ConflictEntry>> {
+        Ok(None)
+    }
+
+    async fn add_conflict(&self, _conflict: &ConflictEntry) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn remove_conflict(&self, _conflict_filename: &OsStr) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// the conflict copies are named as `<filename>.<time>.conflict`
+pub fn is_conflict_file(filename: impl AsRef<Path>) -> bool {
+    filename
+        .as_ref()
+        .extension()
+        .is_some_and(|extension| extension == CONFLICT_EXTENSION)
+}
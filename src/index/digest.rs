@@ -0,0 +1,204 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+use futures_util::{future, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tap::TapFallible;
+use tracing::{error, info};
+
+use super::{Index, IndexFile, Sha256sum};
+
+/// the default buckets count of the index digest, the bucket hashes of a digest are 8 KiB
+pub const DEFAULT_DIGEST_BUCKETS: usize = 256;
+
+/// the bucketed hash summary of the whole index, the files are put into the buckets by the hash
+/// of their filenames, so two peers find the diverged filenames in a few round trips: compare
+/// the roots, then the bucket hashes, then only exchange the entries of the diverged buckets.
+/// The entry of a file is hashed by its filename, gen, hash sum, deleted flag and version, like
+/// the anti entropy file digest
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct IndexDigest {
+    root: Sha256sum,
+    buckets: Vec<Sha256sum>,
+}
+
+impl IndexDigest {
+    /// build the digest of the files, the files must be sorted by filename
+    pub fn new<'a>(files: impl IntoIterator<Item = &'a IndexFile>, buckets: usize) -> Self {
+        let buckets = buckets.max(1);
+        let mut hashers = vec![Sha256::new(); buckets];
+        for index_file in files {
+            hash_entry(
+                &mut hashers[bucket_of(&index_file.filename, buckets)],
+                index_file,
+            );
+        }
+
+        let buckets = hashers
+            .into_iter()
+            .map(|hasher| hasher.finalize().into())
+            .collect::<Vec<Sha256sum>>();
+        let mut root_hasher = Sha256::new();
+        for bucket in &buckets {
+            root_hasher.update(bucket);
+        }
+
+        Self {
+            root: root_hasher.finalize().into(),
+            buckets,
+        }
+    }
+
+    /// the digests of the same index entries have the same root
+    pub fn root(&self) -> Sha256sum {
+        self.root
+    }
+
+    pub fn buckets(&self) -> &[Sha256sum] {
+        &self.buckets
+    }
+
+    /// the buckets whose files diverge from the other digest, the digests of different buckets
+    /// counts can't be compared, so all buckets diverge
+    pub fn diverged_buckets(&self, other: &Self) -> Vec<usize> {
+        if self.root == other.root {
+            return vec![];
+        }
+
+        if self.buckets.len() != other.buckets.len() {
+            return (0..self.buckets.len()).collect();
+        }
+
+        self.buckets
+            .iter()
+            .zip(&other.buckets)
+            .enumerate()
+            .filter(|(_, (bucket, other_bucket))| bucket != other_bucket)
+            .map(|(bucket_id, _)| bucket_id)
+            .collect()
+    }
+}
+
+/// the bucket of the file, it only depends on the filename and the buckets count
+pub fn bucket_of(filename: &OsStr, buckets: usize) -> usize {
+    let hash_sum = Sha256::digest(filename.as_bytes());
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&hash_sum[..8]);
+
+    (u64::from_be_bytes(prefix) % buckets.max(1) as u64) as _
+}
+
+/// calculate the digest of the whole index
+pub async fn index_digest<I: Index>(index: &I, buckets: usize) -> Result<IndexDigest, I::Error> {
+    let index_files = index
+        .list_all_files()
+        .await
+        .tap_err(|err| error!(%err, "list all index files failed"))?
+        .try_collect::<Vec<_>>()
+        .await
+        .tap_err(|err| error!(%err, "collect all index files failed"))?;
+
+    let digest = IndexDigest::new(&index_files, buckets);
+
+    info!(
+        files = index_files.len(),
+        buckets, "calculate index digest done"
+    );
+
+    Ok(digest)
+}
+
+/// get the files of the buckets, such as the diverged buckets, the files are sorted by filename
+pub async fn bucket_files<I: Index>(
+    index: &I,
+    buckets: usize,
+    bucket_ids: &[usize],
+) -> Result<Vec<IndexFile>, I::Error> {
+    let index_files = index
+        .list_all_files()
+        .await
+        .tap_err(|err| error!(%err, "list all index files failed"))?
+        .try_filter(|index_file| {
+            let bucket_id = bucket_of(&index_file.filename, buckets);
+
+            future::ready(bucket_ids.contains(&bucket_id))
+        })
+        .try_collect::<Vec<_>>()
+        .await
+        .tap_err(|err| error!(%err, "collect bucket index files failed"))?;
+
+    info!(
+        files = index_files.len(),
+        ?bucket_ids,
+        "get bucket index files done"
+    );
+
+    Ok(index_files)
+}
+
+fn hash_entry(hasher: &mut Sha256, index_file: &IndexFile) {
+    let filename = index_file.filename.as_bytes();
+    hasher.update((filename.len() as u64).to_be_bytes());
+    hasher.update(filename);
+    hasher.update(index_file.detail.gen.to_be_bytes());
+    hasher.update(index_file.detail.hash_sum);
+    hasher.update([index_file.detail.deleted as u8]);
+
+    hasher.update((index_file.detail.version.iter().count() as u64).to_be_bytes());
+    for (user_id, count) in index_file.detail.version.iter() {
+        hasher.update((user_id.len() as u64).to_be_bytes());
+        hasher.update(user_id.as_bytes());
+        hasher.update(count.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use super::*;
+    use crate::index::memory_index::MemoryIndex;
+    use crate::index::test_util::index_file;
+    use crate::index::IndexGuard;
+
+    #[tokio::test]
+    async fn find_diverged_files() {
+        let local = MemoryIndex::new();
+        let remote = MemoryIndex::new();
+        let files = (0..100)
+            .map(|i| index_file(&format!("{i:03}.txt"), 1))
+            .collect::<Vec<_>>();
+        for (index, changed) in [(&local, "042.txt"), (&remote, "077.txt")] {
+            let mut index_guard = index.begin().await.unwrap();
+            for file in &files {
+                let file = match file.filename == changed {
+                    true => index_file(changed, 2),
+                    false => file.clone(),
+                };
+                index_guard.create_file(&file).await.unwrap();
+            }
+            index_guard.commit().await.unwrap();
+        }
+
+        let local_digest = index_digest(&local, 16).await.unwrap();
+        let remote_digest = index_digest(&remote, 16).await.unwrap();
+        assert_ne!(local_digest.root(), remote_digest.root());
+
+        let diverged = local_digest.diverged_buckets(&remote_digest);
+        assert!(!diverged.is_empty() && diverged.len() <= 2);
+        let diverged_files = bucket_files(&local, 16, &diverged)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|index_file| index_file.filename)
+            .collect::<Vec<_>>();
+        assert!(diverged_files.contains(&OsString::from("042.txt")));
+        assert!(diverged_files.contains(&OsString::from("077.txt")));
+        assert!(diverged_files.len() < files.len());
+
+        // the digests of different buckets counts are not comparable
+        let other_digest = index_digest(&remote, 8).await.unwrap();
+        assert_eq!(local_digest.diverged_buckets(&other_digest).len(), 16);
+        assert!(local_digest.diverged_buckets(&local_digest).is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures_util::stream;
+use tracing::{error, info};
+
+use super::{BlockChain, FileDetail, Index, IndexFile, IndexGuard};
+use crate::ext::log_path;
+
+type FileStream = stream::Iter<std::vec::IntoIter<Result<IndexFile, io::Error>>>;
+
+/// the index kept in memory, for the tests and the ephemeral one-shot sync sessions, the clones
+/// share the files. Like the [`SqliteIndex`], the previous details are got without their block
+/// chains
+///
+/// [`SqliteIndex`]: super::sqlite_index::SqliteIndex
+#[derive(Debug, Clone, Default)]
+pub struct MemoryIndex {
+    files: Arc<Mutex<BTreeMap<OsString, IndexFile>>>,
+}
+
+impl MemoryIndex {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// the committed files count
+    pub fn len(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.lock().unwrap().is_empty()
+    }
+}
+
+#[async_trait]
+impl Index for MemoryIndex {
+    type Error = io::Error;
+    type IndexStream<'a> = FileStream;
+    type Guard = MemoryIndexGuard;
+
+    async fn list_all_files<'a>(&'a self) -> Result<Self::IndexStream<'a>, Self::Error> {
+        let files = self
+            .files
+            .lock()
+            .unwrap()
+            .values()
+            .map(|file| Ok(without_previous_block_chains(file)))
+            .collect::<Vec<_>>();
+
+        Ok(stream::iter(files))
+    }
+
+    async fn get_file(&self, filename: &OsStr) -> Result<Option<IndexFile>, Self::Error> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .get(filename)
+            .map(without_previous_block_chains))
+    }
+
+    async fn begin(&self) -> Result<Self::Guard, Self::Error> {
+        Ok(MemoryIndexGuard {
+            files: self.files.clone(),
+            changes: Default::default(),
+        })
+    }
+}
+
+/// the changes are kept in the guard until the commit, dropping the guard without committing
+/// rolls them back. The guard reads the files committed by other guards, the changes of a guard
+/// are applied at once when it is committed
+#[derive(Debug)]
+pub struct MemoryIndexGuard {
+    files: Arc<Mutex<BTreeMap<OsString, IndexFile>>>,
+    changes: BTreeMap<OsString, IndexFile>,
+}
+
+impl MemoryIndexGuard {
+    /// the file with its stored block chains
+    fn stored_file(&self, filename: &OsStr) -> Option<IndexFile> {
+        match self.changes.get(filename) {
+            Some(file) => Some(file.clone()),
+            None => self.files.lock().unwrap().get(filename).cloned(),
+        }
+    }
+}
+
+#[async_trait]
+impl IndexGuard for MemoryIndexGuard {
+    type Error = io::Error;
+    type IndexStream<'a> = FileStream;
+
+    async fn list_all_files<'a>(&'a mut self) -> Result<Self::IndexStream<'a>, Self::Error> {
+        let mut files = self.files.lock().unwrap().clone();
+        files.extend(
+            self.changes
+                .iter()
+                .map(|(filename, file)| (filename.clone(), file.clone())),
+        );
+
+        Ok(stream::iter(
+            files
+                .values()
+                .map(|file| Ok(without_previous_block_chains(file)))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    async fn create_file(&mut self, file: &IndexFile) -> Result<(), Self::Error> {
+        if self.stored_file(&file.filename).is_some() {
+            error!(filename = ?log_path(&file.filename), "file index already exists");
+
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{:?} index already exists", file.filename),
+            ));
+        }
+
+        self.changes.insert(file.filename.clone(), file.clone());
+
+        info!(filename = ?log_path(&file.filename), "create file index done");
+
+        Ok(())
+    }
+
+    async fn get_file(&mut self, filename: &OsStr) -> Result<Option<IndexFile>, Self::Error> {
+        Ok(self
+            .stored_file(filename)
+            .as_ref()
+            .map(without_previous_block_chains))
+    }
+
+    async fn update_file(&mut self, file: &IndexFile) -> Result<(), Self::Error> {
+        let mut file = file.clone();
+
+        // the previous details are got without block chains, keep their stored block chains
+        if let Some(stored) = self.stored_file(&file.filename) {
+            for detail in file
+                .previous_details
+                .iter_mut()
+                .filter(|detail| detail.block_chain.is_none())
+            {
+                detail.block_chain = stored
+                    .previous_details
+                    .iter()
+                    .find(|stored| stored.gen == detail.gen && stored.hash_sum == detail.hash_sum)
+                    .and_then(|stored| stored.block_chain.clone());
+            }
+        }
+
+        info!(filename = ?log_path(&file.filename), "update file index done");
+
+        self.changes.insert(file.filename.clone(), file);
+
+        Ok(())
+    }
+
+    async fn get_block_chain(
+        &mut self,
+        filename: &OsStr,
+        gen: u32,
+    ) -> Result<Option<BlockChain>, Self::Error> {
+        Ok(self.stored_file(filename).and_then(|file| {
+            [file.detail]
+                .into_iter()
+                .chain(file.previous_details)
+                .find(|detail| detail.gen == gen)
+                .and_then(|detail| detail.block_chain)
+        }))
+    }
+
+    async fn commit(self) -> Result<(), Self::Error> {
+        let changes = self.changes.len();
+        self.files.lock().unwrap().extend(self.changes);
+
+        info!(changes, "commit memory index done");
+
+        Ok(())
+    }
+}
+
+fn without_previous_block_chains(file: &IndexFile) -> IndexFile {
+    IndexFile {
+        previous_details: file
+            .previous_details
+            .iter()
+            .map(|detail| FileDetail {
+                block_chain: None,
+                ..detail.clone()
+            })
+            .collect(),
+        ..file.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+    use crate::index::test_util::index_file_with_history;
+
+    #[tokio::test]
+    async fn commit_and_rollback() {
+        let index = MemoryIndex::new();
+
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard
+            .create_file(&index_file_with_history("b.txt", 1))
+            .await
+            .unwrap();
+        index_guard
+            .create_file(&index_file_with_history("a.txt", 1))
+            .await
+            .unwrap();
+        let err = index_guard
+            .create_file(&index_file_with_history("a.txt", 1))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        // the changes are not seen before the commit
+        assert!(index.get_file(OsStr::new("a.txt")).await.unwrap().is_none());
+        index_guard.commit().await.unwrap();
+
+        let files = index
+            .list_all_files()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            files,
+            [
+                index_file_with_history("a.txt", 1),
+                index_file_with_history("b.txt", 1)
+            ]
+        );
+
+        // dropping the guard without commit rolls back
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard
+            .update_file(&index_file_with_history("a.txt", 2))
+            .await
+            .unwrap();
+        assert_eq!(
+            index_guard
+                .get_file(OsStr::new("a.txt"))
+                .await
+                .unwrap()
+                .unwrap()
+                .detail
+                .gen,
+            2
+        );
+        drop(index_guard);
+        assert_eq!(
+            index.get_file(OsStr::new("a.txt")).await.unwrap(),
+            Some(index_file_with_history("a.txt", 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_previous_block_chains() {
+        let index = MemoryIndex::new();
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard
+            .create_file(&index_file_with_history("a.txt", 2))
+            .await
+            .unwrap();
+        index_guard.commit().await.unwrap();
+
+        // the got previous details have no block chains
+        let mut file = index.get_file(OsStr::new("a.txt")).await.unwrap().unwrap();
+        assert!(file.previous_details[0].block_chain.is_none());
+
+        let mut index_guard = index.begin().await.unwrap();
+        file.detail.gen = 3;
+        index_guard.update_file(&file).await.unwrap();
+        assert_eq!(
+            index_guard
+                .get_block_chain(OsStr::new("a.txt"), 1)
+                .await
+                .unwrap(),
+            index_file_with_history("a.txt", 2).previous_details[0].block_chain
+        );
+        index_guard.commit().await.unwrap();
+        assert_eq!(index.len(), 1);
+    }
+}
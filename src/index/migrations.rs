@@ -0,0 +1,138 @@
+use sqlx::pool::PoolConnection;
+use sqlx::{Executor, Sqlite};
+use tap::TapFallible;
+use tracing::{error, info};
+
+use super::sqlite_index::Error;
+
+/// a schema change of the sqlite index, the migrations are applied in the version order
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Migration {
+    /// the schema version after the migration is applied
+    pub version: u32,
+    pub sql: &'static str,
+}
+
+/// the embedded migrations, a new migration must be appended with the next version and never
+/// changed after it is released. The first migration creates the tables of the databases created
+/// before the versioning if they don't exist, the later ones only add tables and columns, so the
+/// old tables are upgraded by them
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        sql: concat!(
+            include_str!("../../sql/index_files.sql"),
+            include_str!("../../sql/file_details.sql")
+        ),
+    },
+    Migration {
+        version: 2,
+        sql: include_str!("../../sql/partial_downloads.sql"),
+    },
+    Migration {
+        version: 3,
+        sql: include_str!("../../sql/peer_addresses.sql"),
+    },
+    Migration {
+        version: 4,
+        sql: include_str!("../../sql/conflicts.sql"),
+    },
+    Migration {
+        version: 5,
+        sql: include_str!("../../sql/file_details_version.sql"),
+    },
+    Migration {
+        version: 6,
+        sql: include_str!("../../sql/file_details_metadata.sql"),
+    },
+    Migration {
+        version: 7,
+        sql: include_str!("../../sql/file_details_link_group.sql"),
+    },
+];
+
+/// the schema version of the database after all migrations are applied
+pub const SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+const CREATE_SCHEMA_VERSION: &str =
+    "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)";
+
+/// apply the pending migrations in one immediate transaction, so the processes opening the same
+/// database don't migrate it twice, return the schema version before the migrations
+pub async fn migrate(
+    conn: &mut PoolConnection<Sqlite>,
+    migrations: &[Migration],
+) -> Result<u32, Error> {
+    conn.execute("BEGIN IMMEDIATE")
+        .await
+        .tap_err(|err| error!(%err, "begin migration transaction failed"))?;
+
+    match migrate_in_transaction(conn, migrations).await {
+        Err(err) => {
+            let _ = conn.execute("ROLLBACK").await;
+
+            Err(err)
+        }
+
+        Ok(old_version) => {
+            conn.execute("COMMIT")
+                .await
+                .tap_err(|err| error!(%err, "commit migration transaction failed"))?;
+
+            Ok(old_version)
+        }
+    }
+}
+
+async fn migrate_in_transaction(
+    conn: &mut PoolConnection<Sqlite>,
+    migrations: &[Migration],
+) -> Result<u32, Error> {
+    conn.execute(CREATE_SCHEMA_VERSION)
+        .await
+        .tap_err(|err| error!(%err, "create schema version table failed"))?;
+
+    let old_version = sqlx::query_scalar::<_, i64>("SELECT version FROM schema_version")
+        .fetch_optional(&mut *conn)
+        .await
+        .tap_err(|err| error!(%err, "get schema version failed"))?;
+    let old_version = old_version.unwrap_or(0) as u32;
+
+    let latest_version = migrations.last().map_or(0, |migration| migration.version);
+    if old_version > latest_version {
+        error!(
+            old_version,
+            latest_version, "database schema is newer than the supported schema"
+        );
+
+        return Err(Error::UnsupportedSchema(old_version));
+    }
+
+    let mut version = old_version;
+    for migration in migrations
+        .iter()
+        .filter(|migration| migration.version > old_version)
+    {
+        conn.execute(migration.sql)
+            .await
+            .tap_err(|err| error!(%err, version = migration.version, "apply migration failed"))?;
+        version = migration.version;
+
+        info!(version, "apply migration done");
+    }
+
+    if version != old_version {
+        sqlx::query("DELETE FROM schema_version")
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("INSERT INTO schema_version (version) VALUES (?)")
+            .bind(version as i64)
+            .execute(&mut *conn)
+            .await
+            .tap_err(|err| error!(%err, version, "update schema version failed"))?;
+
+        info!(old_version, version, "migrate database schema done");
+    }
+
+    Ok(old_version)
+}
@@ -1,22 +1,81 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Debug, Display, Formatter};
+use std::fs::Metadata;
 use std::io;
 use std::ops::DerefMut;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 use std::pin::Pin;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use futures_util::Stream;
 use mockall::automock;
 use serde::{Deserialize, Serialize};
 
-mod sqlite_index;
+pub mod address;
+pub mod conflicts;
+pub mod digest;
+pub mod memory_index;
+mod migrations;
+pub mod replica;
+pub mod resume;
+pub mod sqlite_index;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod usage;
 
 // 4MiB
 pub const BLOCK_SIZE: usize = 4 * 1024 * 1024;
 
+// 128KiB
+pub const SYNCTHING_MIN_BLOCK_SIZE: usize = 128 * 1024;
+
+// 16MiB
+pub const SYNCTHING_MAX_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+const SYNCTHING_DESIRED_PER_FILE_BLOCKS: u64 = 2000;
+
+/// decide which block size is used to split a file
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum BlockSizePolicy {
+    /// always use [`BLOCK_SIZE`]
+    #[default]
+    Fixed,
+
+    /// the syncthing variable block size, the smallest power of 2 between 128KiB and 16MiB which
+    /// splits the file into less than 2000 blocks. Only the block size follows syncthing, the
+    /// block chain still has the trailing empty block which syncthing doesn't have, and there is
+    /// no BEP protocol transfer using it
+    Syncthing,
+
+    /// always use the configured block size
+    Custom(usize),
+}
+
+impl BlockSizePolicy {
+    pub fn block_size(&self, file_size: u64) -> usize {
+        match self {
+            BlockSizePolicy::Fixed => BLOCK_SIZE,
+            BlockSizePolicy::Custom(block_size) => (*block_size).max(1),
+            BlockSizePolicy::Syncthing => {
+                let mut block_size = SYNCTHING_MIN_BLOCK_SIZE;
+                while block_size < SYNCTHING_MAX_BLOCK_SIZE
+                    && file_size >= SYNCTHING_DESIRED_PER_FILE_BLOCKS * block_size as u64
+                {
+                    block_size *= 2;
+                }
+
+                block_size
+            }
+        }
+    }
+}
+
 pub type Sha256sum = [u8; 32];
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -24,6 +83,14 @@ pub struct Block {
     pub offset: u64,
     pub len: u64,
     pub hash_sum: Sha256sum,
+    /// the block is all zeros, such as a hole of the sparse file, it is never transferred, and
+    /// the receiver punches a hole for it. The blocks recorded by the old peers are not zero
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub zero: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -36,6 +103,8 @@ pub struct BlockChain {
 pub enum FileKind {
     File,
     Symlink,
+    /// fifo, socket or device, it is recorded in the index but can't be synced
+    Special,
 }
 
 impl Display for FileKind {
@@ -51,17 +120,152 @@ impl FromStr for FileKind {
         match s {
             "File" => Ok(FileKind::File),
             "Symlink" => Ok(FileKind::Symlink),
+            "Special" => Ok(FileKind::Special),
             s => Err(format!("invalid file kind '{s}'")),
         }
     }
 }
 
+/// the file metadata which is restored with the content on the other peers
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// the permission bits, such as 0o755
+    pub mode: u32,
+    pub mtime: SystemTime,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FileMetadata {
+    pub async fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::from(&tokio::fs::metadata(path).await?))
+    }
+}
+
+impl From<&Metadata> for FileMetadata {
+    fn from(metadata: &Metadata) -> Self {
+        let mtime = if metadata.mtime() >= 0 {
+            SystemTime::UNIX_EPOCH
+                + Duration::new(metadata.mtime() as _, metadata.mtime_nsec() as _)
+        } else {
+            SystemTime::UNIX_EPOCH
+        };
+
+        Self {
+            mode: metadata.mode() & 0o7777,
+            mtime,
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+        }
+    }
+}
+
+/// the changes of a file made by each peer, keyed by the user id. Unlike the gen, it tells
+/// whether a version descends from another one or both are changed concurrently, even when more
+/// than two peers change the file. The versions are partially ordered, `partial_cmp` returns
+/// None when they are concurrent
+#[derive(Debug, Clone, Eq, PartialEq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, u32>);
+
+impl VersionVector {
+    /// the version of the file created by the user
+    pub fn initial(user_id: &str) -> Self {
+        Self(BTreeMap::from([(user_id.to_string(), 1)]))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// the changes made by the user
+    pub fn get(&self, user_id: &str) -> u32 {
+        self.0.get(user_id).copied().unwrap_or(0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.0
+            .iter()
+            .map(|(user_id, count)| (user_id.as_str(), *count))
+    }
+
+    /// the version after the user changes the file
+    pub fn incremented(&self, user_id: &str) -> Self {
+        let mut version = self.clone();
+        *version.0.entry(user_id.to_string()).or_default() += 1;
+
+        version
+    }
+
+    /// the version which descends from both versions
+    pub fn merged(&self, other: &Self) -> Self {
+        let mut version = self.clone();
+        for (user_id, count) in other.iter() {
+            let merged_count = version.0.entry(user_id.to_string()).or_default();
+            *merged_count = (*merged_count).max(count);
+        }
+
+        version
+    }
+}
+
+impl PartialOrd for VersionVector {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for user_id in self.0.keys().chain(other.0.keys()) {
+            match (ordering, self.get(user_id).cmp(&other.get(user_id))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, user_ordering) => ordering = user_ordering,
+                (ordering, user_ordering) if ordering != user_ordering => return None,
+                _ => {}
+            }
+        }
+
+        Some(ordering)
+    }
+}
+
+impl FromIterator<(String, u32)> for VersionVector {
+    fn from_iter<T: IntoIterator<Item = (String, u32)>>(iter: T) -> Self {
+        Self(iter.into_iter().filter(|(_, count)| *count > 0).collect())
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct FileDetail {
     pub gen: u32,
     pub hash_sum: Sha256sum,
     pub block_chain: Option<BlockChain>,
     pub deleted: bool,
+    /// None when the file is deleted or the detail is recorded by an old peer
+    pub metadata: Option<FileMetadata>,
+    /// empty when the detail is recorded by an old peer
+    pub version: VersionVector,
+    /// the first filename of the hard linked names which share the file, the peers recreate the
+    /// names of a group as hard links, None when the file isn't hard linked
+    pub link_group: Option<OsString>,
+}
+
+impl FileDetail {
+    /// order the details by their versions, when return None, they are changed concurrently. The
+    /// details recorded by the old peers have no version, they are ordered by the gen
+    pub fn version_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.version.is_empty() || other.version.is_empty() {
+            return Some(self.gen.cmp(&other.gen));
+        }
+
+        self.version.partial_cmp(&other.version)
+    }
+
+    /// when return true, both details have the same file content, the gen and the block chain
+    /// are not compared
+    pub fn same_content(&self, other: &Self) -> bool {
+        if self.deleted || other.deleted {
+            return self.deleted == other.deleted;
+        }
+
+        self.hash_sum == other.hash_sum
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -69,6 +273,8 @@ pub struct IndexFile {
     pub filename: OsString,
     pub kind: FileKind,
     pub detail: FileDetail,
+    /// the index may return the previous details without their block chains, they are loaded by
+    /// [`IndexGuard::get_block_chain`] when needed
     pub previous_details: Vec<FileDetail>,
     pub update_time: SystemTime,
     pub update_by: String,
@@ -83,6 +289,8 @@ pub trait Index {
         Self: 'a;
     type Guard: IndexGuard<Error = Self::Error>;
 
+    /// the files are sorted by filename, the listing shouldn't block the writers, so the files
+    /// changed when listing may be listed in either state
     async fn list_all_files<'a>(&'a self) -> Result<Self::IndexStream<'a>, Self::Error>;
 
     async fn get_file(&self, filename: &OsStr) -> Result<Option<IndexFile>, Self::Error>;
@@ -98,6 +306,7 @@ pub trait IndexGuard {
     where
         Self: 'a;
 
+    /// the files are sorted by filename
     async fn list_all_files<'a>(&'a mut self) -> Result<Self::IndexStream<'a>, Self::Error>;
 
     async fn create_file(&mut self, file: &IndexFile) -> Result<(), Self::Error>;
@@ -106,6 +315,40 @@ pub trait IndexGuard {
 
     async fn update_file(&mut self, file: &IndexFile) -> Result<(), Self::Error>;
 
+    /// get the block chain of the detail of the gen, the got files may only contain the block
+    /// chain of the current detail. When a got file is updated, its previous details without
+    /// block chains keep their stored block chains
+    async fn get_block_chain(
+        &mut self,
+        filename: &OsStr,
+        gen: u32,
+    ) -> Result<Option<BlockChain>, Self::Error>;
+
+    /// create the files at once, such as the first scan of a large dir, the index may insert
+    /// them by fewer statements
+    async fn create_files(&mut self, files: &[IndexFile]) -> Result<(), Self::Error>
+    where
+        Self: Send,
+    {
+        for file in files {
+            self.create_file(file).await?;
+        }
+
+        Ok(())
+    }
+
+    /// the batch version of [`IndexGuard::update_file`]
+    async fn update_files(&mut self, files: &[IndexFile]) -> Result<(), Self::Error>
+    where
+        Self: Send,
+    {
+        for file in files {
+            self.update_file(file).await?;
+        }
+
+        Ok(())
+    }
+
     async fn commit(self) -> Result<(), Self::Error>;
 }
 
@@ -134,8 +377,73 @@ where
         self.deref_mut().update_file(file).await
     }
 
+    async fn get_block_chain(
+        &mut self,
+        filename: &OsStr,
+        gen: u32,
+    ) -> Result<Option<BlockChain>, Self::Error> {
+        self.deref_mut().get_block_chain(filename, gen).await
+    }
+
+    async fn create_files(&mut self, files: &[IndexFile]) -> Result<(), Self::Error> {
+        self.deref_mut().create_files(files).await
+    }
+
+    async fn update_files(&mut self, files: &[IndexFile]) -> Result<(), Self::Error> {
+        self.deref_mut().update_files(files).await
+    }
+
     async fn commit(mut self) -> Result<(), Self::Error> {
         let this = *self;
         this.commit().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_vector_order() {
+        let created = VersionVector::initial("a");
+        let changed_by_a = created.incremented("a");
+        let changed_by_b = created.incremented("b");
+
+        assert_eq!(changed_by_a.get("a"), 2);
+        assert!(created < changed_by_a);
+        assert!(created < changed_by_b);
+        assert_eq!(changed_by_a.partial_cmp(&changed_by_b), None);
+
+        let merged = changed_by_a.merged(&changed_by_b);
+        assert_eq!(merged.iter().collect::<Vec<_>>(), [("a", 2), ("b", 1)]);
+        assert!(changed_by_a < merged);
+        assert!(changed_by_b < merged);
+        assert_eq!(merged.partial_cmp(&merged.clone()), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn version_cmp_without_version() {
+        let detail = FileDetail {
+            gen: 2,
+            hash_sum: [0; 32],
+            block_chain: None,
+            deleted: true,
+            metadata: None,
+            version: VersionVector::initial("a").incremented("b"),
+            link_group: None,
+        };
+        let old_peer_detail = FileDetail {
+            gen: 3,
+            version: Default::default(),
+            ..detail.clone()
+        };
+        let concurrent_detail = FileDetail {
+            gen: 3,
+            version: VersionVector::initial("a").incremented("c"),
+            ..detail.clone()
+        };
+
+        assert_eq!(detail.version_cmp(&old_peer_detail), Some(Ordering::Less));
+        assert_eq!(detail.version_cmp(&concurrent_detail), None);
+    }
+}
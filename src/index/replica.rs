@@ -0,0 +1,317 @@
+use std::ffi::OsStr;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use flume::{Receiver, Sender};
+use futures_util::TryStreamExt;
+use tap::TapFallible;
+use tracing::{error, info, instrument, warn};
+
+use super::{BlockChain, Index, IndexFile, IndexGuard};
+use crate::ext::log_path;
+
+/// send the committed changes of the primary index to the [`Replicator`], the changes of a
+/// transaction are sent as a batch after the commit, the rolled back changes are never sent
+#[derive(Debug)]
+pub struct ReplicatedIndex<I> {
+    inner: I,
+    sender: Sender<Vec<IndexFile>>,
+}
+
+impl<I> ReplicatedIndex<I> {
+    /// the sender should be unbounded, a slow replica never blocks the commit
+    pub fn new(inner: I, sender: Sender<Vec<IndexFile>>) -> Self {
+        Self { inner, sender }
+    }
+}
+
+#[async_trait]
+impl<I> Index for ReplicatedIndex<I>
+where
+    I: Index + Send + Sync,
+    I::Error: Send + Sync + 'static,
+    I::Guard: Send,
+{
+    type Error = I::Error;
+    type IndexStream<'a> = I::IndexStream<'a> where Self: 'a;
+    type Guard = ReplicatedIndexGuard<I::Guard>;
+
+    async fn list_all_files<'a>(&'a self) -> Result<Self::IndexStream<'a>, Self::Error> {
+        self.inner.list_all_files().await
+    }
+
+    async fn get_file(&self, filename: &OsStr) -> Result<Option<IndexFile>, Self::Error> {
+        self.inner.get_file(filename).await
+    }
+
+    async fn begin(&self) -> Result<Self::Guard, Self::Error> {
+        let guard = self.inner.begin().await?;
+
+        Ok(ReplicatedIndexGuard {
+            inner: guard,
+            changes: vec![],
+            sender: self.sender.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplicatedIndexGuard<G> {
+    inner: G,
+    changes: Vec<IndexFile>,
+    sender: Sender<Vec<IndexFile>>,
+}
+
+#[async_trait]
+impl<G> IndexGuard for ReplicatedIndexGuard<G>
+where
+    G: IndexGuard + Send,
+    G::Error: Send + Sync + 'static,
+{
+    type Error = G::Error;
+    type IndexStream<'a> = G::IndexStream<'a> where Self: 'a;
+
+    async fn list_all_files<'a>(&'a mut self) -> Result<Self::IndexStream<'a>, Self::Error> {
+        self.inner.list_all_files().await
+    }
+
+    async fn create_file(&mut self, file: &IndexFile) -> Result<(), Self::Error> {
+        self.inner.create_file(file).await?;
+        self.changes.push(file.clone());
+
+        Ok(())
+    }
+
+    async fn get_file(&mut self, filename: &OsStr) -> Result<Option<IndexFile>, Self::Error> {
+        self.inner.get_file(filename).await
+    }
+
+    async fn update_file(&mut self, file: &IndexFile) -> Result<(), Self::Error> {
+        self.inner.update_file(file).await?;
+        self.changes.push(file.clone());
+
+        Ok(())
+    }
+
+    async fn get_block_chain(
+        &mut self,
+        filename: &OsStr,
+        gen: u32,
+    ) -> Result<Option<BlockChain>, Self::Error> {
+        self.inner.get_block_chain(filename, gen).await
+    }
+
+    async fn create_files(&mut self, files: &[IndexFile]) -> Result<(), Self::Error> {
+        self.inner.create_files(files).await?;
+        self.changes.extend_from_slice(files);
+
+        Ok(())
+    }
+
+    async fn update_files(&mut self, files: &[IndexFile]) -> Result<(), Self::Error> {
+        self.inner.update_files(files).await?;
+        self.changes.extend_from_slice(files);
+
+        Ok(())
+    }
+
+    async fn commit(self) -> Result<(), Self::Error> {
+        self.inner.commit().await?;
+
+        if !self.changes.is_empty() && self.sender.send(self.changes).is_err() {
+            warn!("replicator is stopped, the committed changes are not replicated");
+        }
+
+        Ok(())
+    }
+}
+
+/// the replication statistics, a failed batch makes the replica stale until it is seeded again
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ReplicaStatus {
+    pub applied_batches: u64,
+    pub applied_files: u64,
+    pub failed_batches: u64,
+}
+
+/// apply the committed changes of the primary index to the replica, such as another sqlite
+/// file, so the node can switch to the replica when the primary index is lost, and the reporting
+/// can read the replica without locking the primary
+#[derive(Debug)]
+pub struct Replicator<R> {
+    replica: R,
+    status: Arc<Mutex<ReplicaStatus>>,
+}
+
+impl<R> Replicator<R>
+where
+    R: Index + Send + Sync,
+    R::Error: Send + Sync + 'static,
+    R::Guard: Send,
+{
+    pub fn new(replica: R) -> Self {
+        Self {
+            replica,
+            status: Default::default(),
+        }
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        *self.status.lock().unwrap()
+    }
+
+    /// copy all files of the primary index to the replica, it should be called before [`run`],
+    /// and again after a batch failed
+    ///
+    /// [`run`]: Replicator::run
+    #[instrument(skip(self, primary))]
+    pub async fn seed<I>(&self, primary: &I) -> anyhow::Result<()>
+    where
+        I: Index + Sync,
+        I::Error: Send + Sync + 'static,
+    {
+        let mut stream = pin!(primary.list_all_files().await?);
+        let mut files = vec![];
+        while let Some(file) = stream.try_next().await? {
+            files.push(file);
+        }
+
+        self.apply(&files).await?;
+
+        info!(files = files.len(), "seed replica done");
+
+        Ok(())
+    }
+
+    /// apply the batches until all [`ReplicatedIndex`] and their guards are dropped
+    pub async fn run(&self, receiver: Receiver<Vec<IndexFile>>) {
+        while let Ok(files) = receiver.recv_async().await {
+            let result = self.apply(&files).await;
+
+            let mut status = self.status.lock().unwrap();
+            match result {
+                Err(err) => {
+                    error!(%err, "apply replica batch failed, the replica needs seeding again");
+
+                    status.failed_batches += 1;
+                }
+
+                Ok(_) => {
+                    status.applied_batches += 1;
+                    status.applied_files += files.len() as u64;
+                }
+            }
+        }
+
+        info!("all replicated indexes are dropped, stop replicating");
+    }
+
+    async fn apply(&self, files: &[IndexFile]) -> Result<(), R::Error> {
+        let mut guard = self.replica.begin().await?;
+        for file in files {
+            let result = match guard.get_file(&file.filename).await? {
+                None => guard.create_file(file).await,
+                Some(_) => guard.update_file(file).await,
+            };
+
+            result.tap_err(
+                |err| error!(%err, filename = %log_path(&file.filename), "replicate file failed"),
+            )?;
+        }
+
+        guard.commit().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsString;
+
+    use sqlx::{Executor, SqlitePool};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::index::sqlite_index::SqliteIndex;
+    use crate::index::test_util::index_file;
+
+    async fn create_index(dir: &TempDir, name: &str) -> SqliteIndex {
+        let url = format!("sqlite://{}?mode=rwc", dir.path().join(name).display());
+
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(include_str!("../../sql/index_files.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_version.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_metadata.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_link_group.sql"))
+            .await
+            .unwrap();
+
+        SqliteIndex::new(&url).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn replicate_committed_changes() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let (sender, receiver) = flume::unbounded();
+        let primary = ReplicatedIndex::new(create_index(&dir, "primary.db").await, sender);
+        let replicator = Replicator::new(create_index(&dir, "replica.db").await);
+
+        let mut guard = primary.inner.begin().await.unwrap();
+        guard.create_file(&index_file("old.txt", 1)).await.unwrap();
+        guard.commit().await.unwrap();
+        replicator.seed(&primary).await.unwrap();
+
+        let mut guard = primary.begin().await.unwrap();
+        guard.create_file(&index_file("new.txt", 1)).await.unwrap();
+        guard.update_file(&index_file("old.txt", 2)).await.unwrap();
+        guard.commit().await.unwrap();
+
+        // the rolled back changes are not replicated
+        let mut guard = primary.begin().await.unwrap();
+        guard
+            .create_file(&index_file("rollback.txt", 1))
+            .await
+            .unwrap();
+        drop(guard);
+
+        drop(primary);
+        replicator.run(receiver).await;
+        assert_eq!(
+            replicator.status(),
+            ReplicaStatus {
+                applied_batches: 1,
+                applied_files: 2,
+                failed_batches: 0,
+            }
+        );
+
+        let files = replicator
+            .replica
+            .list_all_files()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| (file.filename.clone(), file.detail.gen))
+                .collect::<Vec<_>>(),
+            [
+                (OsString::from("new.txt"), 1),
+                (OsString::from("old.txt"), 2)
+            ]
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Debug;
+use std::io;
+
+use async_trait::async_trait;
+use mockall::automock;
+
+use super::Sha256sum;
+
+/// the state of an interrupted download, the temp file keeps the written blocks, so the next
+/// download of the same content only requests the missing blocks
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PartialDownload {
+    pub filename: OsString,
+    /// the hash sum of the downloading content, the state is stale when the remote file changes
+    pub hash_sum: Sha256sum,
+    /// the temp file name in the sync dir
+    pub temp_name: String,
+    /// the offsets of the blocks which have been written into the temp file, they are verified
+    /// by the block hash sums before resuming
+    pub written_offsets: BTreeSet<u64>,
+}
+
+#[automock]
+#[async_trait]
+pub trait ResumeStore: Debug + Send + Sync {
+    async fn load_partial(&self, filename: &OsStr) -> io::Result<Option<PartialDownload>>;
+
+    /// save the state, the previous state of the file is replaced
+    async fn save_partial(&self, partial: &PartialDownload) -> io::Result<()>;
+
+    async fn remove_partial(&self, filename: &OsStr) -> io::Result<()>;
+}
+
+/// never resume the downloads, the temp file of an interrupted download is removed
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoopResumeStore;
+
+#[async_trait]
+impl ResumeStore for NoopResumeStore {
+    async fn load_partial(&self, _filename: &OsStr) -> io::Result<Option<PartialDownload>> {
+        Ok(None)
+    }
+
+    async fn save_partial(&self, _partial: &PartialDownload) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn remove_partial(&self, _filename: &OsStr) -> io::Result<()> {
+        Ok(())
+    }
+}
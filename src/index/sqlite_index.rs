@@ -1,24 +1,213 @@
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::io::ErrorKind;
+use std::path::Path;
 use std::pin::Pin;
-use std::time::{Duration, SystemTime};
-use std::{error, io};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use std::{error, io, slice};
 
 use async_trait::async_trait;
-use futures_util::{Stream, TryStreamExt};
+use futures_util::Stream;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::{FromRow, QueryBuilder, Sqlite, SqlitePool, Transaction};
 use tap::TapFallible;
 use thiserror::Error;
-use tracing::{error, info, instrument};
-
-use super::{BlockChain, FileDetail, FileKind, Index, IndexFile, IndexGuard};
+use tokio::time;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use super::address::{AddressStore, PeerAddress};
+use super::conflicts::{ConflictEntry, ConflictStore};
+pub use super::migrations::SCHEMA_VERSION;
+use super::migrations::{self, MIGRATIONS};
+use super::resume::{PartialDownload, ResumeStore};
+use super::{
+    BlockChain, FileDetail, FileKind, FileMetadata, Index, IndexFile, IndexGuard, VersionVector,
+};
+use crate::ext::log_path;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("sql error: {0}")]
-    SqlError(#[from] sqlx::Error),
+    Sql(#[from] sqlx::Error),
     #[error("other error: {0}")]
-    Custom(Box<dyn error::Error + Send + 'static>),
+    Custom(Box<dyn error::Error + Send + Sync + 'static>),
+    #[error("schema version {0} is newer than the supported schema version {SCHEMA_VERSION}")]
+    UnsupportedSchema(u32),
+}
+
+impl Error {
+    /// when return true, the database is busy or locked by the other transactions, such as two
+    /// transactions upgrading to write at the same time, the transaction can be run again
+    pub fn is_busy(&self) -> bool {
+        let Error::Sql(err) = self else {
+            return false;
+        };
+
+        err.as_database_error()
+            .and_then(|err| err.code())
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED))
+    }
+}
+
+pub const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const DEFAULT_MAX_BUSY_RETRIES: usize = 3;
+
+/// the sqlx default, a shorter one suits the low power devices
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const BUSY_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// the max rows of a multi rows insert, a file details row binds 7 variables, so a statement
+/// stays under the 999 variables limit of the old sqlite
+const MAX_ROWS_PER_INSERT: usize = 128;
+
+/// the files of a page when listing all files
+const LIST_PAGE_SIZE: usize = 256;
+
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+const SQLITE_BUSY_SNAPSHOT: i32 = SQLITE_BUSY | (2 << 8);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SqliteIndexOptions {
+    /// how long a statement waits for the lock of the database before it returns SQLITE_BUSY
+    pub busy_timeout: Duration,
+
+    /// how many times a statement is retried when the database is still busy after the busy
+    /// timeout, 0 means never retry
+    pub max_busy_retries: usize,
+
+    /// close the connections of the pool which are idle for the duration, None means they are
+    /// kept open
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for SqliteIndexOptions {
+    fn default() -> Self {
+        Self {
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            max_busy_retries: DEFAULT_MAX_BUSY_RETRIES,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+}
+
+/// the lock contention counters of the index, they are shared by the index and its guards
+#[derive(Debug, Default)]
+pub struct SqliteIndexMetrics {
+    busy_retries: AtomicU64,
+    busy_timeouts: AtomicU64,
+    deadlocks: AtomicU64,
+}
+
+impl SqliteIndexMetrics {
+    /// the retried statements which returned SQLITE_BUSY or SQLITE_LOCKED
+    pub fn busy_retries(&self) -> u64 {
+        self.busy_retries.load(Ordering::Relaxed)
+    }
+
+    /// the statements which were still busy after all retries
+    pub fn busy_timeouts(&self) -> u64 {
+        self.busy_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// the statements which returned SQLITE_BUSY without waiting the busy timeout, sqlite does
+    /// that when waiting would deadlock, so the whole transaction must be restarted
+    pub fn deadlocks(&self) -> u64 {
+        self.deadlocks.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum BusyError {
+    Busy,
+    Deadlock,
+}
+
+impl BusyError {
+    fn classify(err: &sqlx::Error, elapsed: Duration, busy_timeout: Duration) -> Option<Self> {
+        let code = err.as_database_error()?.code()?.parse::<i32>().ok()?;
+
+        match code & 0xff {
+            SQLITE_LOCKED => Some(BusyError::Busy),
+            SQLITE_BUSY if code == SQLITE_BUSY_SNAPSHOT || elapsed < busy_timeout => {
+                Some(BusyError::Deadlock)
+            }
+            SQLITE_BUSY => Some(BusyError::Busy),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BusyRetry {
+    options: SqliteIndexOptions,
+    metrics: Arc<SqliteIndexMetrics>,
+}
+
+impl BusyRetry {
+    /// when return true, the failed statement should be run again, the statement is only retried
+    /// when sqlite waited the busy timeout, retrying a deadlocked statement can't succeed
+    async fn should_retry(
+        &self,
+        err: &sqlx::Error,
+        elapsed: Duration,
+        attempt: &mut usize,
+    ) -> bool {
+        match BusyError::classify(err, elapsed, self.options.busy_timeout) {
+            None => false,
+
+            Some(BusyError::Deadlock) => {
+                self.metrics.deadlocks.fetch_add(1, Ordering::Relaxed);
+
+                error!(%err, ?elapsed, "transaction deadlock detected");
+
+                false
+            }
+
+            Some(BusyError::Busy) if *attempt < self.options.max_busy_retries => {
+                *attempt += 1;
+                self.metrics.busy_retries.fetch_add(1, Ordering::Relaxed);
+
+                warn!(%err, attempt = *attempt, "database is busy, retry");
+
+                time::sleep(BUSY_RETRY_BACKOFF * *attempt as u32).await;
+
+                true
+            }
+
+            Some(BusyError::Busy) => {
+                self.metrics.busy_timeouts.fetch_add(1, Ordering::Relaxed);
+
+                error!(%err, attempts = *attempt, "database is still busy after retries");
+
+                false
+            }
+        }
+    }
+}
+
+/// await the statement, run it again when the database is busy, the statement expression is
+/// evaluated for every attempt because running a statement consumes it
+macro_rules! retry_busy {
+    ($retry:expr, $statement:expr) => {{
+        let mut attempt = 0;
+        loop {
+            let start = Instant::now();
+            match $statement.await {
+                Err(err)
+                    if $retry
+                        .should_retry(&err, start.elapsed(), &mut attempt)
+                        .await => {}
+                result => break result,
+            }
+        }
+    }};
 }
 
 #[derive(Debug, FromRow)]
@@ -37,20 +226,146 @@ struct DbFileDetail {
     hash_sum: String,
     block_chain: Option<String>,
     deleted: bool,
+    metadata: Option<String>,
+    /// the json of the version vector
+    version: String,
+    link_group: Option<String>,
+}
+
+/// the stored block chain of a previous detail
+#[derive(Debug, FromRow)]
+struct DbBlockChain {
+    filename: String,
+    gen: i64,
+    hash_sum: String,
+    block_chain: String,
+}
+
+#[derive(Debug, FromRow)]
+struct DbPartialDownload {
+    hash_sum: String,
+    temp_name: String,
+    written_offsets: String,
+}
+
+#[derive(Debug, FromRow)]
+struct DbConflict {
+    conflict_filename: String,
+    filename: String,
+    hash_sum: String,
+    time: i64,
+}
+
+impl TryFrom<DbConflict> for ConflictEntry {
+    type Error = io::Error;
+
+    fn try_from(db_conflict: DbConflict) -> Result<Self, Self::Error> {
+        let hash_sum = hex::decode(&db_conflict.hash_sum)
+            .ok()
+            .and_then(|hash_sum| hash_sum.try_into().ok())
+            .ok_or_else(|| {
+                error!(hash_sum = %db_conflict.hash_sum, "hash sum invalid");
+
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid hash sum: {}", db_conflict.hash_sum),
+                )
+            })?;
+
+        Ok(ConflictEntry {
+            conflict_filename: OsString::from(db_conflict.conflict_filename),
+            filename: OsString::from(db_conflict.filename),
+            hash_sum,
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(db_conflict.time as _),
+        })
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct DbPeerAddress {
+    peer_id: String,
+    addr: String,
+    kind: String,
+    last_success: Option<i64>,
+    failures: i64,
 }
 
 #[derive(Debug)]
 pub struct SqliteIndex {
     db_poll: SqlitePool,
+    retry: BusyRetry,
 }
 
 impl SqliteIndex {
+    /// connect the database whose tables already exist, see [`SqliteIndex::open`] to create or
+    /// upgrade the database
     pub async fn new(db_path: &str) -> Result<Self, Error> {
-        let pool = SqlitePool::connect(db_path)
+        Self::new_with_options(db_path, Default::default()).await
+    }
+
+    pub async fn new_with_options(
+        db_path: &str,
+        options: SqliteIndexOptions,
+    ) -> Result<Self, Error> {
+        let connect_options = SqliteConnectOptions::from_str(db_path)
+            .tap_err(|err| error!(%err, "parse sqlite url failed"))?;
+
+        Self::connect(connect_options, options).await
+    }
+
+    /// open the database file, it is created when it doesn't exist, and its schema is upgraded
+    /// to [`SCHEMA_VERSION`] by the embedded migrations
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_with_options(path, Default::default()).await
+    }
+
+    pub async fn open_with_options(
+        path: impl AsRef<Path>,
+        options: SqliteIndexOptions,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let connect_options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let index = Self::connect(connect_options, options).await?;
+
+        let mut conn = index
+            .db_poll
+            .acquire()
+            .await
+            .tap_err(|err| error!(%err, "acquire sqlite connection failed"))?;
+        let old_version = migrations::migrate(&mut conn, MIGRATIONS).await?;
+
+        info!(path = ?log_path(path), old_version, "open sqlite index done");
+
+        drop(conn);
+
+        Ok(index)
+    }
+
+    async fn connect(
+        connect_options: SqliteConnectOptions,
+        options: SqliteIndexOptions,
+    ) -> Result<Self, Error> {
+        let connect_options = connect_options.busy_timeout(options.busy_timeout);
+
+        let pool = SqlitePoolOptions::new()
+            .idle_timeout(options.idle_timeout)
+            .connect_with(connect_options)
             .await
             .tap_err(|err| error!(%err, "connect sqlite failed"))?;
 
-        Ok(Self { db_poll: pool })
+        Ok(Self {
+            db_poll: pool,
+            retry: BusyRetry {
+                options,
+                metrics: Default::default(),
+            },
+        })
+    }
+
+    pub fn metrics(&self) -> &SqliteIndexMetrics {
+        &self.retry.metrics
     }
 }
 
@@ -64,13 +379,25 @@ impl Index for SqliteIndex {
     #[instrument]
     async fn list_all_files(&self) -> Result<Self::IndexStream<'_>, Self::Error> {
         let stream = async_stream::try_stream! {
-            let mut index_guard = self.begin().await?;
-
-            info!("create index guard done");
+            let mut after = None;
+            loop {
+                // every page is read by its own transaction, so the reader doesn't block the
+                // writers between the pages
+                let mut index_guard = self.begin().await?;
+                let page = index_guard.list_files_page(after.as_deref(), LIST_PAGE_SIZE).await?;
+                drop(index_guard);
+
+                info!(files = page.len(), "list index files page done");
+
+                let last_page = page.len() < LIST_PAGE_SIZE;
+                after = page.last().map(|file| file.filename.to_string_lossy().to_string());
+                for file in page {
+                    yield file
+                }
 
-            let mut stream = index_guard.list_all_files().await?;
-            while let Some(file) = stream.try_next().await? {
-                yield file
+                if last_page {
+                    break;
+                }
             }
         };
 
@@ -78,7 +405,7 @@ impl Index for SqliteIndex {
     }
 
     #[inline]
-    #[instrument]
+    #[instrument(skip(filename), fields(filename = %log_path(filename)))]
     async fn get_file(&self, filename: &OsStr) -> Result<Option<IndexFile>, Self::Error> {
         let mut index_guard = self.begin().await?;
 
@@ -90,46 +417,310 @@ impl Index for SqliteIndex {
     #[inline]
     #[instrument]
     async fn begin(&self) -> Result<Self::Guard, Self::Error> {
-        let transaction = self
-            .db_poll
-            .begin()
-            .await
+        let transaction = retry_busy!(self.retry, self.db_poll.begin())
             .tap_err(|err| error!(%err, "create a transaction failed"))?;
 
         info!("create transaction done");
 
-        Ok(SqliteIndexGuard { transaction })
+        Ok(SqliteIndexGuard {
+            transaction,
+            retry: self.retry.clone(),
+        })
     }
 }
 
+/// the states of the interrupted downloads are kept in the partial_downloads table, they are not
+/// part of the index transaction, so a state survives the rolled back rumor
+#[async_trait]
+impl ResumeStore for SqliteIndex {
+    #[instrument(skip(filename), fields(filename = %log_path(filename)))]
+    async fn load_partial(&self, filename: &OsStr) -> io::Result<Option<PartialDownload>> {
+        let db_partial: Option<DbPartialDownload> = retry_busy!(
+            self.retry,
+            sqlx::query_as("SELECT * FROM partial_downloads WHERE filename=?")
+                .bind(filename.to_string_lossy())
+                .fetch_optional(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "select partial download failed"))?;
+
+        let db_partial = match db_partial {
+            None => return Ok(None),
+            Some(db_partial) => db_partial,
+        };
+
+        let hash_sum = hex::decode(&db_partial.hash_sum)
+            .ok()
+            .and_then(|hash_sum| hash_sum.try_into().ok())
+            .ok_or_else(|| {
+                error!(hash_sum = %db_partial.hash_sum, "hash sum invalid");
+
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid hash sum: {}", db_partial.hash_sum),
+                )
+            })?;
+
+        let written_offsets = serde_json::from_str(&db_partial.written_offsets)
+            .map_err(io::Error::from)
+            .tap_err(|err| error!(%err, "unmarshal written offsets failed"))?;
+
+        Ok(Some(PartialDownload {
+            filename: filename.to_owned(),
+            hash_sum,
+            temp_name: db_partial.temp_name,
+            written_offsets,
+        }))
+    }
+
+    #[instrument(skip(partial), fields(filename = %log_path(&partial.filename)))]
+    async fn save_partial(&self, partial: &PartialDownload) -> io::Result<()> {
+        let written_offsets = serde_json::to_string(&partial.written_offsets)?;
+
+        retry_busy!(
+            self.retry,
+            sqlx::query("INSERT OR REPLACE INTO partial_downloads (filename, hash_sum, temp_name, written_offsets) VALUES (?, ?, ?, ?)")
+                .bind(partial.filename.to_string_lossy())
+                .bind(hex::encode(partial.hash_sum))
+                .bind(&partial.temp_name)
+                .bind(&written_offsets)
+                .execute(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "save partial download failed"))?;
+
+        info!(
+            written_blocks = partial.written_offsets.len(),
+            "save partial download done"
+        );
+
+        Ok(())
+    }
+
+    #[instrument(skip(filename), fields(filename = %log_path(filename)))]
+    async fn remove_partial(&self, filename: &OsStr) -> io::Result<()> {
+        retry_busy!(
+            self.retry,
+            sqlx::query("DELETE FROM partial_downloads WHERE filename=?")
+                .bind(filename.to_string_lossy())
+                .execute(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "remove partial download failed"))?;
+
+        Ok(())
+    }
+}
+
+/// the addresses are kept in the peer_addresses table, so the peers are reconnected after
+/// restarting without reconfiguration
+#[async_trait]
+impl AddressStore for SqliteIndex {
+    #[instrument]
+    async fn load_addresses(&self) -> io::Result<Vec<PeerAddress>> {
+        let db_addresses: Vec<DbPeerAddress> = retry_busy!(
+            self.retry,
+            sqlx::query_as("SELECT * FROM peer_addresses").fetch_all(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "select peer addresses failed"))?;
+
+        db_addresses
+            .into_iter()
+            .map(|db_address| {
+                let peer_id = Uuid::parse_str(&db_address.peer_id)
+                    .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+                    .tap_err(
+                        |err| error!(%err, peer_id = %db_address.peer_id, "peer id invalid"),
+                    )?;
+
+                Ok(PeerAddress {
+                    peer_id,
+                    kind: db_address.kind.parse()?,
+                    addr: db_address.addr,
+                    last_success: db_address.last_success.map(|last_success| {
+                        SystemTime::UNIX_EPOCH + Duration::from_secs(last_success as _)
+                    }),
+                    failures: db_address.failures as _,
+                })
+            })
+            .collect()
+    }
+
+    #[instrument(skip(address), fields(peer_id = %address.peer_id, addr = %address.addr))]
+    async fn save_address(&self, address: &PeerAddress) -> io::Result<()> {
+        let last_success = address.last_success.map(|last_success| {
+            last_success
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64
+        });
+
+        retry_busy!(
+            self.retry,
+            sqlx::query("INSERT OR REPLACE INTO peer_addresses (peer_id, addr, kind, last_success, failures) VALUES (?, ?, ?, ?, ?)")
+                .bind(address.peer_id.as_hyphenated().to_string())
+                .bind(&address.addr)
+                .bind(address.kind.as_str())
+                .bind(last_success)
+                .bind(address.failures as i64)
+                .execute(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "save peer address failed"))?;
+
+        info!("save peer address done");
+
+        Ok(())
+    }
+
+    #[instrument]
+    async fn remove_address(&self, peer_id: Uuid, addr: &str) -> io::Result<()> {
+        retry_busy!(
+            self.retry,
+            sqlx::query("DELETE FROM peer_addresses WHERE peer_id=? AND addr=?")
+                .bind(peer_id.as_hyphenated().to_string())
+                .bind(addr)
+                .execute(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "remove peer address failed"))?;
+
+        Ok(())
+    }
+}
+
+/// the unresolved conflicts are kept in the conflicts table, like the partial downloads, they are
+/// not part of the index transaction
+#[async_trait]
+impl ConflictStore for SqliteIndex {
+    #[instrument]
+    async fn list_conflicts(&self) -> io::Result<Vec<ConflictEntry>> {
+        let db_conflicts: Vec<DbConflict> = retry_busy!(
+            self.retry,
+            sqlx::query_as("SELECT * FROM conflicts ORDER BY conflict_filename")
+                .fetch_all(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "select conflicts failed"))?;
+
+        db_conflicts.into_iter().map(TryInto::try_into).collect()
+    }
+
+    #[instrument(skip(conflict_filename), fields(conflict_filename = %log_path(conflict_filename)))]
+    async fn get_conflict(&self, conflict_filename: &OsStr) -> io::Result<Option<ConflictEntry>> {
+        let db_conflict: Option<DbConflict> = retry_busy!(
+            self.retry,
+            sqlx::query_as("SELECT * FROM conflicts WHERE conflict_filename=?")
+                .bind(conflict_filename.to_string_lossy())
+                .fetch_optional(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "select conflict failed"))?;
+
+        db_conflict.map(TryInto::try_into).transpose()
+    }
+
+    #[instrument(skip(conflict), fields(conflict_filename = %log_path(&conflict.conflict_filename)))]
+    async fn add_conflict(&self, conflict: &ConflictEntry) -> io::Result<()> {
+        let time = conflict
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+
+        retry_busy!(
+            self.retry,
+            sqlx::query("INSERT OR REPLACE INTO conflicts (conflict_filename, filename, hash_sum, time) VALUES (?, ?, ?, ?)")
+                .bind(conflict.conflict_filename.to_string_lossy())
+                .bind(conflict.filename.to_string_lossy())
+                .bind(hex::encode(conflict.hash_sum))
+                .bind(time)
+                .execute(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "add conflict failed"))?;
+
+        info!("add conflict done");
+
+        Ok(())
+    }
+
+    #[instrument(skip(conflict_filename), fields(conflict_filename = %log_path(conflict_filename)))]
+    async fn remove_conflict(&self, conflict_filename: &OsStr) -> io::Result<()> {
+        retry_busy!(
+            self.retry,
+            sqlx::query("DELETE FROM conflicts WHERE conflict_filename=?")
+                .bind(conflict_filename.to_string_lossy())
+                .execute(&self.db_poll)
+        )
+        .map_err(|err| io::Error::new(ErrorKind::Other, err))
+        .tap_err(|err| error!(%err, "remove conflict failed"))?;
+
+        Ok(())
+    }
+}
+
+/// the statements of the guard are retried when the database is busy, a commit is not retried,
+/// the failed commit rolls back the transaction
 #[derive(Debug)]
 pub struct SqliteIndexGuard {
     transaction: Transaction<'static, Sqlite>,
+    retry: BusyRetry,
 }
 
 impl SqliteIndexGuard {
+    /// the files after the filename, sorted by filename
+    async fn list_files_page(
+        &mut self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<IndexFile>, Error> {
+        let db_index_files: Vec<DbIndexFile> = retry_busy!(
+            self.retry,
+            sqlx::query_as(
+                "SELECT * FROM index_files WHERE ? IS NULL OR filename > ? ORDER BY filename LIMIT ?"
+            )
+            .bind(after)
+            .bind(after)
+            .bind(limit as i64)
+            .fetch_all(&mut self.transaction)
+        )
+        .tap_err(|err| error!(%err, "select index files page failed"))?;
+
+        let mut files = Vec::with_capacity(db_index_files.len());
+        for db_index_file in db_index_files {
+            files.push(self.construct_file(db_index_file).await?);
+        }
+
+        Ok(files)
+    }
+
     async fn construct_file(
         &mut self,
         db_index_file: DbIndexFile,
     ) -> Result<IndexFile, sqlx::Error> {
         let file_kind = db_index_file.kind.parse::<FileKind>().map_err(|err| {
-            error!(%err, filename = %db_index_file.filename, "parse file kind failed");
+            error!(%err, filename = %log_path(&db_index_file.filename), "parse file kind failed");
 
             sqlx::Error::Decode(Box::new(io::Error::new(ErrorKind::Other, err)))
         })?;
 
-        let db_file_details: Vec<DbFileDetail> = sqlx::query_as(
-            "SELECT * FROM file_details WHERE filename=? ORDER BY gen DESC",
+        // only the block chain of the current detail is selected, the previous block chains may
+        // be large and are rarely used
+        let db_file_details: Vec<DbFileDetail> = retry_busy!(
+            self.retry,
+            sqlx::query_as("SELECT filename, gen, hash_sum, CASE WHEN gen = ? THEN block_chain END AS block_chain, deleted, metadata, version, link_group FROM file_details WHERE filename=? ORDER BY gen DESC")
+                .bind(db_index_file.gen)
+                .bind(&db_index_file.filename)
+                .fetch_all(&mut self.transaction)
         )
-        .bind(&db_index_file.filename)
-        .fetch_all(&mut self.transaction)
-        .await
         .tap_err(
-            |err| error!(%err, filename = %db_index_file.filename, "select file details failed"),
+            |err| error!(%err, filename = %log_path(&db_index_file.filename), "select file details failed"),
         )?;
 
         if db_file_details.is_empty() {
-            error!(filename = %db_index_file.filename, "db file details is empty");
+            error!(filename = %log_path(&db_index_file.filename), "db file details is empty");
 
             return Err(sqlx::Error::Decode(Box::new(io::Error::new(
                 ErrorKind::Other,
@@ -137,7 +728,7 @@ impl SqliteIndexGuard {
             ))));
         }
 
-        info!(filename = %db_index_file.filename, "select all file details done");
+        info!(filename = %log_path(&db_index_file.filename), "select all file details done");
 
         let mut file_details = db_file_details
             .into_iter()
@@ -161,32 +752,39 @@ impl SqliteIndexGuard {
                     })?
                 };
 
-                let file_detail = match db_detail.block_chain {
-                    None => FileDetail {
-                        gen: db_detail.gen as _,
-                        hash_sum,
-                        block_chain: None,
-                        deleted: db_detail.deleted,
-                    },
-
-                    Some(block_chain) => {
-                        let block_chain = serde_json::from_str::<BlockChain>(&block_chain)
-                            .map_err(|err| {
-                                error!(%err, %block_chain, "parse block chain failed");
-
-                                sqlx::Error::Decode(Box::new(err))
-                            })?;
-
-                        FileDetail {
-                            gen: db_detail.gen as _,
-                            hash_sum,
-                            block_chain: Some(block_chain),
-                            deleted: db_detail.deleted,
-                        }
-                    }
-                };
+                let metadata = db_detail
+                    .metadata
+                    .as_deref()
+                    .map(serde_json::from_str::<FileMetadata>)
+                    .transpose()
+                    .map_err(|err| {
+                        error!(%err, metadata = ?db_detail.metadata, "parse file metadata failed");
 
-                Ok(file_detail)
+                        sqlx::Error::Decode(Box::new(err))
+                    })?;
+
+                let block_chain = db_detail
+                    .block_chain
+                    .as_deref()
+                    .map(parse_block_chain)
+                    .transpose()?;
+
+                let version =
+                    serde_json::from_str::<VersionVector>(&db_detail.version).map_err(|err| {
+                        error!(%err, version = %db_detail.version, "parse version vector failed");
+
+                        sqlx::Error::Decode(Box::new(err))
+                    })?;
+
+                Ok(FileDetail {
+                    gen: db_detail.gen as _,
+                    hash_sum,
+                    block_chain,
+                    deleted: db_detail.deleted,
+                    metadata,
+                    version,
+                    link_group: db_detail.link_group.map(Into::into),
+                })
             })
             .collect::<Result<Vec<FileDetail>, sqlx::Error>>()?;
 
@@ -205,7 +803,7 @@ impl SqliteIndexGuard {
         })
     }
 
-    #[instrument(err)]
+    #[instrument(err, skip(filename), fields(filename = %log_path(filename)))]
     async fn update_or_insert_file_detail(
         &mut self,
         filename: &str,
@@ -219,12 +817,21 @@ impl SqliteIndexGuard {
 
         let block_chain = file_detail.block_chain.as_ref().map(serde_json::to_string).transpose()
             .map_err(|err| {
-                error!(filename, %err, block_chain = ?file_detail.block_chain, "marshal block chain failed");
+                error!(filename = %log_path(filename), %err, block_chain = ?file_detail.block_chain, "marshal block chain failed");
 
                 Error::Custom(Box::new(err))
             })?;
 
-        info!(filename, ?block_chain, "marshal block chain done");
+        info!(filename = %log_path(filename), ?block_chain, "marshal block chain done");
+
+        let metadata = file_detail.metadata.as_ref().map(serde_json::to_string).transpose()
+            .map_err(|err| {
+                error!(filename = %log_path(filename), %err, metadata = ?file_detail.metadata, "marshal file metadata failed");
+
+                Error::Custom(Box::new(err))
+            })?;
+
+        let version = marshal_version(&file_detail.version)?;
 
         let new_db_file_detail = DbFileDetail {
             filename: filename.to_string(),
@@ -232,31 +839,39 @@ impl SqliteIndexGuard {
             hash_sum,
             block_chain,
             deleted: file_detail.deleted,
+            metadata,
+            version,
+            link_group: file_detail
+                .link_group
+                .as_ref()
+                .map(|link_group| link_group.to_string_lossy().to_string()),
         };
 
-        let db_file_detail: DbFileDetail = match sqlx::query_as(
-            "SELECT * FROM file_details WHERE filename = ? AND gen = ?",
-        )
-        .bind(filename)
-        .bind(file_detail.gen as i64)
-        .fetch_one(&mut self.transaction)
-        .await
-        {
+        let db_file_detail: DbFileDetail = match retry_busy!(
+            self.retry,
+            sqlx::query_as("SELECT * FROM file_details WHERE filename = ? AND gen = ?")
+                .bind(filename)
+                .bind(file_detail.gen as i64)
+                .fetch_one(&mut self.transaction)
+        ) {
             Err(sqlx::Error::RowNotFound) => {
-                let result = sqlx::query("INSERT INTO file_details (filename, gen, hash_sum, block_chain, deleted) VALUES (?, ?, ?, ?, ?)")
-                    .bind(new_db_file_detail.filename)
+                let result = retry_busy!(self.retry, sqlx::query("INSERT INTO file_details (filename, gen, hash_sum, block_chain, deleted, metadata, version, link_group) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+                    .bind(&new_db_file_detail.filename)
                     .bind(new_db_file_detail.gen)
-                    .bind(new_db_file_detail.hash_sum)
-                    .bind(new_db_file_detail.block_chain)
+                    .bind(&new_db_file_detail.hash_sum)
+                    .bind(&new_db_file_detail.block_chain)
                     .bind(new_db_file_detail.deleted)
-                    .execute(&mut self.transaction)
-                    .await.tap_err(|err| error!(%err, "insert db file detail failed"))?;
+                    .bind(&new_db_file_detail.metadata)
+                    .bind(&new_db_file_detail.version)
+                    .bind(&new_db_file_detail.link_group)
+                    .execute(&mut self.transaction))
+                    .tap_err(|err| error!(%err, "insert db file detail failed"))?;
 
                 let rows_affected = result.rows_affected();
                 if rows_affected != 1 {
                     error!(rows_affected, "rows affected invalid, should be 1");
 
-                    return Err(Error::SqlError(sqlx::Error::Io(io::Error::new(
+                    return Err(Error::Sql(sqlx::Error::Io(io::Error::new(
                         ErrorKind::Other,
                         format!("rows affected {rows_affected} invalid, should be 1"),
                     ))));
@@ -282,19 +897,22 @@ impl SqliteIndexGuard {
             return Ok(());
         }
 
-        let result = sqlx::query("UPDATE file_details SET hash_sum = ?, block_chain = ?, deleted = ? WHERE filename = ? AND gen = ?")
-            .bind(new_db_file_detail.hash_sum)
-            .bind(new_db_file_detail.block_chain)
+        let result = retry_busy!(self.retry, sqlx::query("UPDATE file_details SET hash_sum = ?, block_chain = ?, deleted = ?, metadata = ?, version = ?, link_group = ? WHERE filename = ? AND gen = ?")
+            .bind(&new_db_file_detail.hash_sum)
+            .bind(&new_db_file_detail.block_chain)
             .bind(new_db_file_detail.deleted)
-            .bind(new_db_file_detail.filename)
+            .bind(&new_db_file_detail.metadata)
+            .bind(&new_db_file_detail.version)
+            .bind(&new_db_file_detail.link_group)
+            .bind(&new_db_file_detail.filename)
             .bind(new_db_file_detail.gen)
-            .execute(&mut self.transaction).await.tap_err(|err| error!(%err, "update db file detail failed"))?;
+            .execute(&mut self.transaction)).tap_err(|err| error!(%err, "update db file detail failed"))?;
 
         let rows_affected = result.rows_affected();
         if rows_affected != 1 {
             error!(rows_affected, "rows affected invalid, should be 1");
 
-            return Err(Error::SqlError(sqlx::Error::Io(io::Error::new(
+            return Err(Error::Sql(sqlx::Error::Io(io::Error::new(
                 ErrorKind::Other,
                 format!("rows affected {rows_affected} invalid, should be 1"),
             ))));
@@ -304,6 +922,64 @@ impl SqliteIndexGuard {
     }
 }
 
+fn parse_block_chain(block_chain: &str) -> Result<BlockChain, sqlx::Error> {
+    serde_json::from_str(block_chain).map_err(|err| {
+        error!(%err, %block_chain, "parse block chain failed");
+
+        sqlx::Error::Decode(Box::new(err))
+    })
+}
+
+fn to_db_file_detail(file: &IndexFile, file_detail: &FileDetail) -> Result<DbFileDetail, Error> {
+    let block_chain = match &file_detail.block_chain {
+        None => None,
+        Some(block_chain) => Some(serde_json::to_string(&block_chain).map_err(|err| {
+            error!(%err, ?block_chain, "marshal block chain failed");
+
+            Error::Custom(Box::new(err))
+        })?),
+    };
+
+    let metadata = file_detail
+        .metadata
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|err| {
+            error!(%err, metadata = ?file_detail.metadata, "marshal file metadata failed");
+
+            Error::Custom(Box::new(err))
+        })?;
+
+    let hash_sum = if file_detail.hash_sum == [0; 32] {
+        String::new()
+    } else {
+        hex::encode(file_detail.hash_sum)
+    };
+
+    Ok(DbFileDetail {
+        filename: file.filename.to_string_lossy().to_string(),
+        gen: file_detail.gen as _,
+        hash_sum,
+        block_chain,
+        deleted: file_detail.deleted,
+        metadata,
+        version: marshal_version(&file_detail.version)?,
+        link_group: file_detail
+            .link_group
+            .as_ref()
+            .map(|link_group| link_group.to_string_lossy().to_string()),
+    })
+}
+
+fn marshal_version(version: &VersionVector) -> Result<String, Error> {
+    serde_json::to_string(version).map_err(|err| {
+        error!(%err, ?version, "marshal version vector failed");
+
+        Error::Custom(Box::new(err))
+    })
+}
+
 #[async_trait]
 impl IndexGuard for SqliteIndexGuard {
     type Error = Error;
@@ -311,10 +987,12 @@ impl IndexGuard for SqliteIndexGuard {
 
     #[instrument]
     async fn list_all_files(&mut self) -> Result<Self::IndexStream<'_>, Self::Error> {
-        let db_index_files: Vec<DbIndexFile> = sqlx::query_as("SELECT * FROM index_files")
-            .fetch_all(&mut self.transaction)
-            .await
-            .tap_err(|err| error!(%err, "select all index files failed"))?;
+        let db_index_files: Vec<DbIndexFile> = retry_busy!(
+            self.retry,
+            sqlx::query_as("SELECT * FROM index_files ORDER BY filename")
+                .fetch_all(&mut self.transaction)
+        )
+        .tap_err(|err| error!(%err, "select all index files failed"))?;
 
         info!("select all index files done");
 
@@ -329,110 +1007,110 @@ impl IndexGuard for SqliteIndexGuard {
         Ok(Box::pin(stream))
     }
 
-    #[instrument]
+    #[instrument(skip(file), fields(filename = %log_path(&file.filename)))]
     async fn create_file(&mut self, file: &IndexFile) -> Result<(), Self::Error> {
-        let db_index_file = DbIndexFile {
-            filename: file.filename.to_string_lossy().to_string(),
-            kind: file.kind.to_string(),
-            gen: file.detail.gen as _,
-            update_time: file
-                .update_time
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as _,
-            update_by: file.update_by.clone(),
-        };
-
-        let db_file_details = [&file.detail]
-            .into_iter()
-            .chain(file.previous_details.iter())
-            .map(|file_detail| {
-                let block_chain = match &file_detail.block_chain {
-                    None => None,
-                    Some(block_chain) => {
-                        Some(serde_json::to_string(&block_chain).map_err(|err| {
-                            error!(%err, ?block_chain, "marshal block chain failed");
-
-                            Error::Custom(Box::new(err))
-                        })?)
-                    }
-                };
+        self.create_files(slice::from_ref(file)).await
+    }
 
-                let hash_sum = if file_detail.hash_sum == [0; 32] {
-                    String::new()
-                } else {
-                    hex::encode(file_detail.hash_sum)
-                };
+    /// the rows are inserted by the multi rows statements, the statements are split to keep the
+    /// bound variables under the sqlite limit
+    #[instrument(skip(files), fields(files = files.len()))]
+    async fn create_files(&mut self, files: &[IndexFile]) -> Result<(), Self::Error> {
+        let mut db_index_files = Vec::with_capacity(files.len());
+        let mut db_file_details = Vec::with_capacity(files.len());
+        for file in files {
+            db_index_files.push(DbIndexFile {
+                filename: file.filename.to_string_lossy().to_string(),
+                kind: file.kind.to_string(),
+                gen: file.detail.gen as _,
+                update_time: file
+                    .update_time
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as _,
+                update_by: file.update_by.clone(),
+            });
+
+            for file_detail in [&file.detail]
+                .into_iter()
+                .chain(file.previous_details.iter())
+            {
+                db_file_details.push(to_db_file_detail(file, file_detail)?);
+            }
+        }
 
-                Ok(DbFileDetail {
-                    filename: file.filename.to_string_lossy().to_string(),
-                    gen: file_detail.gen as _,
-                    hash_sum,
-                    block_chain,
-                    deleted: file_detail.deleted,
-                })
+        for db_index_files in db_index_files.chunks(MAX_ROWS_PER_INSERT) {
+            retry_busy!(self.retry, async {
+                let mut query_builder = QueryBuilder::new(
+                    "INSERT INTO index_files (filename, kind, gen, update_time, update_by) ",
+                );
+                let query = query_builder
+                    .push_values(db_index_files, |mut b, db_index_file| {
+                        b.push_bind(&db_index_file.filename)
+                            .push_bind(&db_index_file.kind)
+                            .push_bind(db_index_file.gen)
+                            .push_bind(db_index_file.update_time)
+                            .push_bind(&db_index_file.update_by);
+                    })
+                    .build();
+
+                query.execute(&mut self.transaction).await
             })
-            .collect::<Result<Vec<_>, Error>>()?;
-
-        info!(?db_file_details, "collect db file details done");
-
-        sqlx::query("INSERT INTO index_files (filename, kind, gen, update_time, update_by) VALUES (?, ?, ?, ?, ?)")
-            .bind(&db_index_file.filename)
-            .bind(&db_index_file.kind)
-            .bind(db_index_file.gen)
-            .bind(db_index_file.update_time)
-            .bind(&db_index_file.update_by)
-            .execute(&mut self.transaction)
-            .await
-            .tap_err(|err| error!(%err, ?db_index_file, "insert db index file failed"))?;
-
-        info!(?db_index_file, "insert db index file done");
+            .tap_err(|err| error!(%err, "insert db index files failed"))?;
+        }
 
-        let mut query_builder = QueryBuilder::new(
-            "INSERT INTO file_details (filename, gen, hash_sum, block_chain, deleted) ",
-        );
-        let query = query_builder
-            .push_values(db_file_details, |mut b, db_file_detail| {
-                b.push_bind(db_file_detail.filename)
-                    .push_bind(db_file_detail.gen)
-                    .push_bind(db_file_detail.hash_sum)
-                    .push_bind(db_file_detail.block_chain)
-                    .push_bind(db_file_detail.deleted);
+        info!("insert db index files done");
+
+        for db_file_details in db_file_details.chunks(MAX_ROWS_PER_INSERT) {
+            retry_busy!(self.retry, async {
+                let mut query_builder = QueryBuilder::new(
+                    "INSERT INTO file_details (filename, gen, hash_sum, block_chain, deleted, metadata, version, link_group) ",
+                );
+                let query = query_builder
+                    .push_values(db_file_details, |mut b, db_file_detail| {
+                        b.push_bind(&db_file_detail.filename)
+                            .push_bind(db_file_detail.gen)
+                            .push_bind(&db_file_detail.hash_sum)
+                            .push_bind(&db_file_detail.block_chain)
+                            .push_bind(db_file_detail.deleted)
+                            .push_bind(&db_file_detail.metadata)
+                            .push_bind(&db_file_detail.version)
+                            .push_bind(&db_file_detail.link_group);
+                    })
+                    .build();
+
+                query.execute(&mut self.transaction).await
             })
-            .build();
-
-        query
-            .execute(&mut self.transaction)
-            .await
             .tap_err(|err| error!(%err, "insert db file details failed"))?;
+        }
 
         info!("insert db file details done");
 
         Ok(())
     }
 
-    #[instrument(err)]
+    #[instrument(err, skip(filename), fields(filename = %log_path(filename)))]
     async fn get_file(&mut self, filename: &OsStr) -> Result<Option<IndexFile>, Self::Error> {
-        let db_index_file: DbIndexFile =
-            match sqlx::query_as("SELECT * FROM index_files WHERE filename=?")
+        let db_index_file: DbIndexFile = match retry_busy!(
+            self.retry,
+            sqlx::query_as("SELECT * FROM index_files WHERE filename=?")
                 .bind(filename.to_string_lossy())
                 .fetch_one(&mut self.transaction)
-                .await
-            {
-                Err(sqlx::Error::RowNotFound) => {
-                    info!("index file not found");
+        ) {
+            Err(sqlx::Error::RowNotFound) => {
+                info!("index file not found");
 
-                    return Ok(None);
-                }
+                return Ok(None);
+            }
 
-                Err(err) => {
-                    error!(%err, "select index file failed");
+            Err(err) => {
+                error!(%err, "select index file failed");
 
-                    return Err(err.into());
-                }
+                return Err(err.into());
+            }
 
-                Ok(db_index_file) => db_index_file,
-            };
+            Ok(db_index_file) => db_index_file,
+        };
 
         info!("get db index file done");
 
@@ -443,27 +1121,105 @@ impl IndexGuard for SqliteIndexGuard {
         Ok(Some(index_file))
     }
 
-    #[instrument(err)]
+    #[instrument(skip(file), fields(filename = %log_path(&file.filename)))]
     async fn update_file(&mut self, file: &IndexFile) -> Result<(), Self::Error> {
-        let filename = file.filename.to_string_lossy();
+        self.update_files(slice::from_ref(file)).await
+    }
 
-        sqlx::query("DELETE FROM index_files WHERE filename = ?")
-            .bind(&filename)
-            .execute(&mut self.transaction)
-            .await
-            .tap_err(|err| error!(?filename, %err, "delete exists index file failed"))?;
+    #[instrument(err, skip(filename), fields(filename = %log_path(filename)))]
+    async fn get_block_chain(
+        &mut self,
+        filename: &OsStr,
+        gen: u32,
+    ) -> Result<Option<BlockChain>, Self::Error> {
+        let block_chain: Option<Option<String>> = retry_busy!(
+            self.retry,
+            sqlx::query_scalar(
+                "SELECT block_chain FROM file_details WHERE filename = ? AND gen = ?"
+            )
+            .bind(filename.to_string_lossy())
+            .bind(gen as i64)
+            .fetch_optional(&mut self.transaction)
+        )
+        .tap_err(|err| error!(%err, gen, "select block chain failed"))?;
 
-        info!(?filename, "delete exists index file done");
+        Ok(block_chain
+            .flatten()
+            .as_deref()
+            .map(parse_block_chain)
+            .transpose()?)
+    }
 
-        sqlx::query("DELETE FROM file_details WHERE filename = ?")
-            .bind(&filename)
-            .execute(&mut self.transaction)
-            .await
-            .tap_err(|err| error!(?filename, %err, "delete exists db file details failed"))?;
+    #[instrument(err, skip(files), fields(files = files.len()))]
+    async fn update_files(&mut self, files: &[IndexFile]) -> Result<(), Self::Error> {
+        let filenames = files
+            .iter()
+            .map(|file| file.filename.to_string_lossy())
+            .collect::<Vec<_>>();
+
+        // the previous details are got without block chains, keep their stored block chains.
+        // The block chain of the current detail is always got, so dropping it when the detail
+        // becomes a previous detail is kept
+        let mut kept_block_chains = vec![];
+        for filenames in filenames.chunks(MAX_ROWS_PER_INSERT) {
+            let block_chains: Vec<DbBlockChain> = retry_busy!(self.retry, async {
+                let mut query_builder = QueryBuilder::new(
+                    "SELECT filename, gen, hash_sum, block_chain FROM file_details WHERE block_chain IS NOT NULL AND gen < (SELECT gen FROM index_files WHERE index_files.filename = file_details.filename) AND filename IN (",
+                );
+                let mut separated = query_builder.separated(", ");
+                for filename in filenames {
+                    separated.push_bind(filename);
+                }
+                separated.push_unseparated(")");
+
+                query_builder
+                    .build_query_as()
+                    .fetch_all(&mut self.transaction)
+                    .await
+            })
+            .tap_err(|err| error!(%err, "select previous block chains failed"))?;
+
+            kept_block_chains.extend(block_chains);
 
-        info!(?filename, "delete exists db file details done");
+            for table in ["index_files", "file_details"] {
+                retry_busy!(self.retry, async {
+                    let mut query_builder =
+                        QueryBuilder::new(format!("DELETE FROM {table} WHERE filename IN ("));
+                    let mut separated = query_builder.separated(", ");
+                    for filename in filenames {
+                        separated.push_bind(filename);
+                    }
+                    separated.push_unseparated(")");
+
+                    query_builder.build().execute(&mut self.transaction).await
+                })
+                .tap_err(|err| error!(%err, table, "delete exists rows failed"))?;
+            }
+        }
+
+        info!("delete exists rows done");
+
+        self.create_files(files).await?;
 
-        self.create_file(file).await
+        for kept in &kept_block_chains {
+            retry_busy!(
+                self.retry,
+                sqlx::query("UPDATE file_details SET block_chain = ? WHERE filename = ? AND gen = ? AND hash_sum = ? AND block_chain IS NULL AND gen < (SELECT gen FROM index_files WHERE index_files.filename = file_details.filename)")
+                    .bind(&kept.block_chain)
+                    .bind(&kept.filename)
+                    .bind(kept.gen)
+                    .bind(&kept.hash_sum)
+                    .execute(&mut self.transaction)
+            )
+            .tap_err(|err| error!(%err, filename = %log_path(&kept.filename), gen = kept.gen, "keep previous block chain failed"))?;
+        }
+
+        info!(
+            count = kept_block_chains.len(),
+            "keep previous block chains done"
+        );
+
+        Ok(())
     }
 
     #[instrument]
@@ -476,3 +1232,584 @@ impl IndexGuard for SqliteIndexGuard {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, mem};
+
+    use futures_util::TryStreamExt;
+    use sqlx::Executor;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::index::address::AddressKind;
+    use crate::index::Block;
+
+    fn index_file() -> IndexFile {
+        IndexFile {
+            filename: OsString::from("test.txt"),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum: [1; 32],
+                block_chain: None,
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH + Duration::from_secs(100),
+            update_by: "test".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_busy_statement() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("index.db").display()
+        );
+
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(include_str!("../../sql/index_files.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_version.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_metadata.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_link_group.sql"))
+            .await
+            .unwrap();
+
+        let index = SqliteIndex::new_with_options(
+            &url,
+            SqliteIndexOptions {
+                busy_timeout: Duration::from_millis(10),
+                max_busy_retries: 1,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        // the other writer holds the write lock until it commits
+        let mut other_transaction = pool.begin().await.unwrap();
+        other_transaction
+            .execute("DELETE FROM index_files")
+            .await
+            .unwrap();
+
+        let mut index_guard = index.begin().await.unwrap();
+        let err = index_guard.create_file(&index_file()).await.unwrap_err();
+        assert!(err.is_busy());
+
+        assert_eq!(index.metrics().busy_retries(), 1);
+        assert_eq!(index.metrics().busy_timeouts(), 1);
+        assert_eq!(index.metrics().deadlocks(), 0);
+
+        drop(index_guard);
+        other_transaction.commit().await.unwrap();
+
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard.create_file(&index_file()).await.unwrap();
+        index_guard.commit().await.unwrap();
+
+        assert_eq!(
+            index.get_file(OsStr::new("test.txt")).await.unwrap(),
+            Some(index_file())
+        );
+        assert_eq!(index.metrics().busy_retries(), 1);
+    }
+
+    #[tokio::test]
+    async fn list_all_files_by_pages() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let index = SqliteIndex::open(dir.path().join("index.db"))
+            .await
+            .unwrap();
+
+        let files = (0..LIST_PAGE_SIZE + 1)
+            .map(|i| IndexFile {
+                filename: OsString::from(format!("{i:04}.txt")),
+                ..index_file()
+            })
+            .collect::<Vec<_>>();
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard.create_files(&files).await.unwrap();
+        index_guard.commit().await.unwrap();
+
+        let mut stream = index.list_all_files().await.unwrap();
+        let first = stream.try_next().await.unwrap().unwrap();
+        assert_eq!(first, files[0]);
+
+        // the listing doesn't hold a transaction, the writer isn't blocked, and the file created
+        // after the read pages is listed by the next page
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard.create_file(&index_file()).await.unwrap();
+        index_guard.commit().await.unwrap();
+
+        let rest = stream.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(rest[..LIST_PAGE_SIZE], files[1..]);
+        assert_eq!(rest[LIST_PAGE_SIZE..], [index_file()]);
+    }
+
+    #[tokio::test]
+    async fn metadata_round_trip() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("index.db").display()
+        );
+
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(include_str!("../../sql/index_files.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_version.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_metadata.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_link_group.sql"))
+            .await
+            .unwrap();
+
+        let index = SqliteIndex::new(&url).await.unwrap();
+        let mut index_file = index_file();
+        index_file.detail.metadata = Some(FileMetadata {
+            mode: 0o755,
+            mtime: SystemTime::UNIX_EPOCH + Duration::new(100, 123),
+            uid: Some(1000),
+            gid: None,
+        });
+        index_file.detail.version = VersionVector::initial("a");
+        index_file.detail.link_group = Some(OsString::from("link.txt"));
+
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard.create_file(&index_file).await.unwrap();
+        index_guard.commit().await.unwrap();
+
+        assert_eq!(
+            index.get_file(OsStr::new("test.txt")).await.unwrap(),
+            Some(index_file.clone())
+        );
+
+        // a chmod only changes the metadata and the version of the detail
+        index_file.detail.metadata.as_mut().unwrap().mode = 0o644;
+        index_file.detail.version = index_file.detail.version.incremented("b");
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard.update_file(&index_file).await.unwrap();
+        index_guard.commit().await.unwrap();
+
+        assert_eq!(
+            index.get_file(OsStr::new("test.txt")).await.unwrap(),
+            Some(index_file)
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_create_and_update() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("index.db").display()
+        );
+
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(include_str!("../../sql/index_files.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_version.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_metadata.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_link_group.sql"))
+            .await
+            .unwrap();
+
+        // more files than a single insert
+        let mut index_files = (0..MAX_ROWS_PER_INSERT * 2 + 1)
+            .map(|i| IndexFile {
+                filename: OsString::from(format!("{i:04}.txt")),
+                ..index_file()
+            })
+            .collect::<Vec<_>>();
+
+        let index = SqliteIndex::new(&url).await.unwrap();
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard.create_files(&index_files).await.unwrap();
+        index_guard.commit().await.unwrap();
+
+        for index_file in &mut index_files {
+            let old_detail = mem::replace(
+                &mut index_file.detail,
+                FileDetail {
+                    gen: 2,
+                    hash_sum: [2; 32],
+                    block_chain: None,
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+            );
+            index_file.previous_details.push(old_detail);
+        }
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard.update_files(&index_files).await.unwrap();
+        index_guard.commit().await.unwrap();
+
+        let files = index
+            .list_all_files()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(files, index_files);
+    }
+
+    #[tokio::test]
+    async fn lazy_previous_block_chains() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("index.db").display()
+        );
+
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(include_str!("../../sql/index_files.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_version.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_metadata.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_link_group.sql"))
+            .await
+            .unwrap();
+
+        let block_chain = |gen| BlockChain {
+            block_size: 4,
+            blocks: vec![Block {
+                offset: 0,
+                len: 4,
+                hash_sum: [gen; 32],
+                zero: false,
+            }],
+        };
+        let detail = |gen| FileDetail {
+            gen: gen as _,
+            hash_sum: [gen; 32],
+            block_chain: Some(block_chain(gen)),
+            deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        };
+        let file = IndexFile {
+            detail: detail(2),
+            previous_details: vec![detail(1)],
+            ..index_file()
+        };
+
+        let index = SqliteIndex::new(&url).await.unwrap();
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard.create_file(&file).await.unwrap();
+        index_guard.commit().await.unwrap();
+
+        let mut index_guard = index.begin().await.unwrap();
+        let mut got = index_guard.get_file(&file.filename).await.unwrap().unwrap();
+        assert_eq!(got.detail, file.detail);
+        assert_eq!(got.previous_details[0].block_chain, None);
+        assert_eq!(
+            index_guard
+                .get_block_chain(&file.filename, 1)
+                .await
+                .unwrap(),
+            Some(block_chain(1))
+        );
+
+        // the got file becomes a new gen, the block chain of the current detail is dropped
+        let mut old_detail = mem::replace(&mut got.detail, detail(3));
+        old_detail.block_chain.take();
+        got.previous_details.insert(0, old_detail);
+        index_guard.update_file(&got).await.unwrap();
+        index_guard.commit().await.unwrap();
+
+        let mut index_guard = index.begin().await.unwrap();
+        let mut block_chains = vec![];
+        for gen in 1..=3 {
+            block_chains.push(
+                index_guard
+                    .get_block_chain(&file.filename, gen)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(
+            block_chains,
+            [Some(block_chain(1)), None, Some(block_chain(3))]
+        );
+
+        assert_eq!(
+            index_guard
+                .get_block_chain(OsStr::new("missing"), 1)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_download_round_trip() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("index.db").display()
+        );
+
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(include_str!("../../sql/partial_downloads.sql"))
+            .await
+            .unwrap();
+
+        let index = SqliteIndex::new(&url).await.unwrap();
+        let filename = OsStr::new("test.txt");
+        assert_eq!(index.load_partial(filename).await.unwrap(), None);
+
+        let mut partial = PartialDownload {
+            filename: filename.to_owned(),
+            hash_sum: [1; 32],
+            temp_name: ".syncit-test.tmp".to_string(),
+            written_offsets: [0, 4].into(),
+        };
+        index.save_partial(&partial).await.unwrap();
+        assert_eq!(
+            index.load_partial(filename).await.unwrap(),
+            Some(partial.clone())
+        );
+
+        // the state is replaced
+        partial.written_offsets.insert(8);
+        index.save_partial(&partial).await.unwrap();
+        assert_eq!(index.load_partial(filename).await.unwrap(), Some(partial));
+
+        index.remove_partial(filename).await.unwrap();
+        assert_eq!(index.load_partial(filename).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn conflict_round_trip() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("index.db").display()
+        );
+
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(include_str!("../../sql/conflicts.sql"))
+            .await
+            .unwrap();
+
+        let index = SqliteIndex::new(&url).await.unwrap();
+        assert!(index.list_conflicts().await.unwrap().is_empty());
+
+        let conflicts = ["b.txt", "a.txt"].map(|filename| ConflictEntry {
+            conflict_filename: OsString::from(format!("{filename}.2024-01-01-00-00-00.conflict")),
+            filename: OsString::from(filename),
+            hash_sum: [1; 32],
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(100),
+        });
+        for conflict in &conflicts {
+            index.add_conflict(conflict).await.unwrap();
+        }
+        assert_eq!(
+            index.list_conflicts().await.unwrap(),
+            [conflicts[1].clone(), conflicts[0].clone()]
+        );
+        assert_eq!(
+            index
+                .get_conflict(&conflicts[0].conflict_filename)
+                .await
+                .unwrap(),
+            Some(conflicts[0].clone())
+        );
+
+        index
+            .remove_conflict(&conflicts[0].conflict_filename)
+            .await
+            .unwrap();
+        assert_eq!(
+            index.list_conflicts().await.unwrap(),
+            [conflicts[1].clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_address_round_trip() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("index.db").display()
+        );
+
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(include_str!("../../sql/peer_addresses.sql"))
+            .await
+            .unwrap();
+
+        let index = SqliteIndex::new(&url).await.unwrap();
+        assert_eq!(index.load_addresses().await.unwrap(), []);
+
+        let peer_id = Uuid::new_v4();
+        let mut address = PeerAddress::new(peer_id, "http://192.168.1.2:8080", AddressKind::Lan);
+        index.save_address(&address).await.unwrap();
+        assert_eq!(index.load_addresses().await.unwrap(), [address.clone()]);
+
+        // the state is replaced
+        address.last_success = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(100));
+        address.failures = 2;
+        index.save_address(&address).await.unwrap();
+        let relay = PeerAddress::new(peer_id, "http://relay.example.com", AddressKind::Relay);
+        index.save_address(&relay).await.unwrap();
+
+        let mut addresses = index.load_addresses().await.unwrap();
+        addresses.sort_by_key(|address| address.kind);
+        assert_eq!(addresses, [address.clone(), relay]);
+
+        index
+            .remove_address(peer_id, "http://relay.example.com")
+            .await
+            .unwrap();
+        assert_eq!(index.load_addresses().await.unwrap(), [address]);
+    }
+
+    async fn schema_version(path: &Path) -> i64 {
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(path))
+            .await
+            .unwrap();
+
+        sqlx::query_scalar("SELECT version FROM schema_version")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn open_and_migrate() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("index.db");
+
+        // the new database is created with all tables
+        let index = SqliteIndex::open(&path).await.unwrap();
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard.create_file(&index_file()).await.unwrap();
+        index_guard.commit().await.unwrap();
+        assert!(index.list_conflicts().await.unwrap().is_empty());
+        assert_eq!(index.load_addresses().await.unwrap(), []);
+        drop(index);
+        assert_eq!(schema_version(&path).await, SCHEMA_VERSION as i64);
+
+        // reopen keeps the data
+        let index = SqliteIndex::open(&path).await.unwrap();
+        assert_eq!(
+            index.get_file(OsStr::new("test.txt")).await.unwrap(),
+            Some(index_file())
+        );
+    }
+
+    /// the index tables before the versioning
+    const LEGACY_SCHEMA: &str = "
+        CREATE TABLE index_files
+        (
+            filename    TEXT    NOT NULL,
+            kind        TEXT    NOT NULL,
+            gen         INTEGER NOT NULL,
+            update_time INTEGER NOT NULL,
+            update_by   TEXT    NOT NULL
+        );
+        CREATE INDEX idx_filename ON index_files (filename);
+
+        CREATE TABLE file_details
+        (
+            filename    TEXT    NOT NULL,
+            gen         INTEGER NOT NULL,
+            hash_sum    TEXT    NOT NULL,
+            block_chain TEXT,
+            deleted     BLOB    NOT NULL
+        );
+        CREATE INDEX idx_filename_gen ON file_details (filename, gen);
+    ";
+
+    #[tokio::test]
+    async fn migrate_unversioned_database() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("index.db");
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+
+        // the database created before the versioning only has the index tables without the
+        // later columns
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(LEGACY_SCHEMA).await.unwrap();
+        pool.execute(
+            "INSERT INTO index_files (filename, kind, gen, update_time, update_by) \
+             VALUES ('test.txt', 'File', 1, 100, 'test')",
+        )
+        .await
+        .unwrap();
+        pool.execute(
+            "INSERT INTO file_details (filename, gen, hash_sum, block_chain, deleted) \
+             VALUES ('test.txt', 1, '0101010101010101010101010101010101010101010101010101010101010101', NULL, false)",
+        )
+        .await
+        .unwrap();
+        pool.close().await;
+
+        let index = SqliteIndex::open(&path).await.unwrap();
+        assert!(index.list_conflicts().await.unwrap().is_empty());
+        // the old rows are read with the added columns
+        let index_file = index
+            .get_file(OsStr::new("test.txt"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(index_file.detail.hash_sum, [1; 32]);
+        assert_eq!(index_file.detail.metadata, None);
+        assert_eq!(index_file.detail.version, Default::default());
+        drop(index);
+        assert_eq!(schema_version(&path).await, SCHEMA_VERSION as i64);
+
+        // the database of a newer version is never opened
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute("UPDATE schema_version SET version = version + 1")
+            .await
+            .unwrap();
+        pool.close().await;
+
+        let err = SqliteIndex::open(&path).await.unwrap_err();
+        assert!(matches!(err, Error::UnsupportedSchema(version) if version == SCHEMA_VERSION + 1));
+    }
+}
@@ -0,0 +1,52 @@
+use std::ffi::OsString;
+use std::time::{Duration, SystemTime};
+
+use super::{Block, BlockChain, FileDetail, FileKind, IndexFile};
+
+/// a file at the gen without the block chain, the hash sum is filled with the gen
+pub fn index_file(filename: &str, gen: u32) -> IndexFile {
+    IndexFile {
+        filename: OsString::from(filename),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen,
+            hash_sum: [gen as _; 32],
+            block_chain: None,
+            deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![],
+        update_time: SystemTime::UNIX_EPOCH + Duration::from_secs(100),
+        update_by: "test".to_string(),
+    }
+}
+
+/// a file at the gen with the previous gens, the newest first, every detail has a one block
+/// chain and the hash sums are filled with its gen
+pub fn index_file_with_history(filename: &str, gen: u32) -> IndexFile {
+    let detail = |gen| FileDetail {
+        gen,
+        hash_sum: [gen as _; 32],
+        block_chain: Some(BlockChain {
+            block_size: 4,
+            blocks: vec![Block {
+                offset: 0,
+                len: 4,
+                hash_sum: [gen as _; 32],
+                zero: false,
+            }],
+        }),
+        deleted: false,
+        metadata: None,
+        version: Default::default(),
+        link_group: None,
+    };
+
+    IndexFile {
+        detail: detail(gen),
+        previous_details: (1..gen).rev().map(detail).collect(),
+        ..index_file(filename, gen)
+    }
+}
@@ -0,0 +1,286 @@
+use std::cmp::Reverse;
+
+use futures_util::TryStreamExt;
+use tap::TapFallible;
+use tracing::{error, info};
+
+use super::{FileDetail, FileKind, Index, IndexFile, IndexGuard};
+
+/// the fixed columns of a file detail row, the gen, the deleted flag and the hex hash sum
+const DETAIL_ROW_BYTES: u64 = 8 + 1 + 64;
+
+/// the storage used by a dir
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct StorageUsage {
+    /// the files which are not deleted
+    pub files: u64,
+
+    /// the content size of the files which are not deleted
+    pub live_bytes: u64,
+
+    /// the previous details kept in the index, their content is not kept
+    pub versions: u64,
+
+    /// the bytes of the previous details kept in the index
+    pub version_bytes: u64,
+}
+
+impl StorageUsage {
+    pub fn total_bytes(&self) -> u64 {
+        self.live_bytes + self.version_bytes
+    }
+
+    fn add_file(&mut self, index_file: &IndexFile) {
+        if index_file.kind == FileKind::File && !index_file.detail.deleted {
+            self.files += 1;
+            self.live_bytes += content_size(&index_file.detail);
+        }
+
+        for previous_detail in &index_file.previous_details {
+            self.versions += 1;
+            self.version_bytes += detail_bytes(index_file, previous_detail);
+        }
+    }
+}
+
+/// calculate the storage used by the dir of the index
+pub async fn storage_usage<I: Index>(index: &I) -> Result<StorageUsage, I::Error> {
+    let mut index_guard = index
+        .begin()
+        .await
+        .tap_err(|err| error!(%err, "create index guard failed"))?;
+
+    let mut usage = StorageUsage::default();
+    for index_file in list_files_with_history(&mut index_guard).await? {
+        usage.add_file(&index_file);
+    }
+
+    info!(?usage, "calculate storage usage done");
+
+    Ok(usage)
+}
+
+/// trim the oldest previous details of the files until the dir fits the target size, the live
+/// content is never trimmed, so the returned usage may still exceed the target. The files with the
+/// most history are trimmed first, and the trimmed previous details can't be used to tell an old
+/// rumor from a conflict any more
+pub async fn reclaim_storage<I>(index: &I, target_bytes: u64) -> Result<StorageUsage, I::Error>
+where
+    I: Index,
+    I::Guard: Send,
+{
+    let mut index_guard = index
+        .begin()
+        .await
+        .tap_err(|err| error!(%err, "create index guard failed"))?;
+
+    let mut index_files = list_files_with_history(&mut index_guard).await?;
+
+    let mut usage = StorageUsage::default();
+    index_files
+        .iter()
+        .for_each(|index_file| usage.add_file(index_file));
+
+    if usage.total_bytes() <= target_bytes {
+        info!(
+            ?usage,
+            target_bytes, "storage usage fits the target, no need reclaim"
+        );
+
+        return Ok(usage);
+    }
+
+    index_files.sort_by_cached_key(|index_file| {
+        let history_bytes = index_file
+            .previous_details
+            .iter()
+            .map(|previous_detail| detail_bytes(index_file, previous_detail))
+            .sum::<u64>();
+
+        u64::MAX - history_bytes
+    });
+
+    for mut index_file in index_files {
+        if usage.total_bytes() <= target_bytes {
+            break;
+        }
+
+        if index_file.previous_details.is_empty() {
+            continue;
+        }
+
+        // trim the oldest details first
+        index_file
+            .previous_details
+            .sort_unstable_by_key(|detail| Reverse(detail.gen));
+        while usage.total_bytes() > target_bytes {
+            let previous_detail = match index_file.previous_details.pop() {
+                None => break,
+                Some(previous_detail) => previous_detail,
+            };
+
+            usage.versions -= 1;
+            usage.version_bytes -= detail_bytes(&index_file, &previous_detail);
+        }
+
+        index_guard
+            .update_file(&index_file)
+            .await
+            .tap_err(|err| error!(%err, "update trimmed index file failed"))?;
+    }
+
+    index_guard
+        .commit()
+        .await
+        .tap_err(|err| error!(%err, "commit index guard failed"))?;
+
+    info!(?usage, target_bytes, "reclaim storage done");
+
+    Ok(usage)
+}
+
+/// list all files with the block chains of their previous details, the block chains are counted
+/// in the version bytes
+async fn list_files_with_history<G: IndexGuard>(
+    index_guard: &mut G,
+) -> Result<Vec<IndexFile>, G::Error> {
+    let mut index_files = index_guard
+        .list_all_files()
+        .await
+        .tap_err(|err| error!(%err, "list all index files failed"))?
+        .try_collect::<Vec<_>>()
+        .await
+        .tap_err(|err| error!(%err, "collect all index files failed"))?;
+
+    for index_file in &mut index_files {
+        for previous_detail in &mut index_file.previous_details {
+            if previous_detail.block_chain.is_none() {
+                previous_detail.block_chain = index_guard
+                    .get_block_chain(&index_file.filename, previous_detail.gen)
+                    .await
+                    .tap_err(|err| error!(%err, gen = previous_detail.gen, "get previous block chain failed"))?;
+            }
+        }
+    }
+
+    Ok(index_files)
+}
+
+fn content_size(detail: &FileDetail) -> u64 {
+    detail
+        .block_chain
+        .as_ref()
+        .map(|block_chain| block_chain.blocks.iter().map(|block| block.len).sum())
+        .unwrap_or(0)
+}
+
+/// the bytes of the detail row in the index
+fn detail_bytes(index_file: &IndexFile, detail: &FileDetail) -> u64 {
+    let block_chain_bytes = detail
+        .block_chain
+        .as_ref()
+        .and_then(|block_chain| serde_json::to_vec(block_chain).ok())
+        .map(|block_chain| block_chain.len() as u64)
+        .unwrap_or(0);
+
+    index_file.filename.len() as u64 + DETAIL_ROW_BYTES + block_chain_bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsStr;
+
+    use sqlx::{Executor, SqlitePool};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::index::sqlite_index::SqliteIndex;
+    use crate::index::test_util::index_file_with_history;
+
+    #[tokio::test]
+    async fn usage_and_reclaim() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let url = format!(
+            "sqlite://{}?mode=rwc",
+            dir.path().join("index.db").display()
+        );
+        let pool = SqlitePool::connect(&url).await.unwrap();
+        pool.execute(include_str!("../../sql/index_files.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_version.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_metadata.sql"))
+            .await
+            .unwrap();
+        pool.execute(include_str!("../../sql/file_details_link_group.sql"))
+            .await
+            .unwrap();
+
+        let index = SqliteIndex::new(&url).await.unwrap();
+        let mut index_guard = index.begin().await.unwrap();
+        index_guard
+            .create_file(&index_file_with_history("a.txt", 3))
+            .await
+            .unwrap();
+        index_guard
+            .create_file(&index_file_with_history("b.txt", 2))
+            .await
+            .unwrap();
+        index_guard.commit().await.unwrap();
+
+        let version_bytes = detail_bytes(
+            &index_file_with_history("a.txt", 1),
+            &index_file_with_history("a.txt", 1).detail,
+        );
+        let usage = storage_usage(&index).await.unwrap();
+        assert_eq!(
+            usage,
+            StorageUsage {
+                files: 2,
+                live_bytes: 8,
+                versions: 3,
+                version_bytes: 3 * version_bytes,
+            }
+        );
+
+        // nothing to trim
+        assert_eq!(
+            reclaim_storage(&index, usage.total_bytes()).await.unwrap(),
+            usage
+        );
+
+        // a.txt has the most history, its oldest detail is trimmed first
+        let usage = reclaim_storage(&index, 8 + 2 * version_bytes)
+            .await
+            .unwrap();
+        assert_eq!(usage.versions, 2);
+        let a = index.get_file(OsStr::new("a.txt")).await.unwrap().unwrap();
+        assert_eq!(a.detail.gen, 3);
+        assert_eq!(
+            a.previous_details
+                .iter()
+                .map(|detail| detail.gen)
+                .collect::<Vec<_>>(),
+            [2]
+        );
+
+        // the live content is never trimmed
+        let usage = reclaim_storage(&index, 0).await.unwrap();
+        assert_eq!(
+            usage,
+            StorageUsage {
+                files: 2,
+                live_bytes: 8,
+                versions: 0,
+                version_bytes: 0,
+            }
+        );
+        assert_eq!(storage_usage(&index).await.unwrap(), usage);
+    }
+}
@@ -0,0 +1,420 @@
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use mockall::automock;
+use serde::Serialize;
+use tap::TapFallible;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::ext::log_path;
+use crate::index::IndexFile;
+use crate::sync_control::timing::{FileTiming, SyncStage};
+
+// 64MiB
+pub const DEFAULT_JOURNAL_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+pub const DEFAULT_JOURNAL_MAX_FILES: usize = 4;
+
+/// where the applied sync operation comes from
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationSource {
+    Watch,
+    Rumor,
+    SyncAll,
+}
+
+/// two peers created the same filename independently, the kept file stays at the filename
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConflictRecord {
+    pub time: SystemTime,
+    pub filename: OsString,
+    pub kept: IndexFile,
+    pub conflict: IndexFile,
+    /// where the content of the conflict file is preserved, it is None on the peer whose file is
+    /// kept, the other peer preserves it
+    pub conflict_filename: Option<OsString>,
+}
+
+/// the sink of the applied sync operations, a failed record must not fail the sync, so the
+/// implementation should log the error instead of returning it
+#[automock]
+pub trait Journal: Debug + Send + Sync {
+    fn record(&self, dir_id: Uuid, source: OperationSource, index_file: &IndexFile);
+
+    fn record_conflict(&self, _dir_id: Uuid, _conflict: &ConflictRecord) {}
+
+    /// record where the time of syncing the file is spent
+    fn record_timing(&self, _dir_id: Uuid, _timing: &FileTiming) {}
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoopJournal;
+
+impl Journal for NoopJournal {
+    fn record(&self, _dir_id: Uuid, _source: OperationSource, _index_file: &IndexFile) {}
+}
+
+#[derive(Debug, Serialize)]
+struct JournalEntry<'a> {
+    time: String,
+    dir_id: String,
+    source: OperationSource,
+    filename: String,
+    kind: String,
+    gen: u32,
+    deleted: bool,
+    hash_sum: String,
+    update_by: &'a str,
+}
+
+impl<'a> JournalEntry<'a> {
+    fn new(dir_id: Uuid, source: OperationSource, index_file: &'a IndexFile) -> Self {
+        Self {
+            time: DateTime::<Utc>::from(index_file.update_time)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            dir_id: dir_id.as_hyphenated().to_string(),
+            source,
+            filename: index_file.filename.to_string_lossy().into_owned(),
+            kind: index_file.kind.to_string(),
+            gen: index_file.detail.gen,
+            deleted: index_file.detail.deleted,
+            hash_sum: hex::encode(index_file.detail.hash_sum),
+            update_by: &index_file.update_by,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ConflictEntry<'a> {
+    time: String,
+    dir_id: String,
+    conflict: &'static str,
+    filename: String,
+    kept_update_by: &'a str,
+    kept_hash_sum: String,
+    conflict_update_by: &'a str,
+    conflict_hash_sum: String,
+    conflict_filename: Option<String>,
+}
+
+impl<'a> ConflictEntry<'a> {
+    fn new(dir_id: Uuid, conflict: &'a ConflictRecord) -> Self {
+        Self {
+            time: DateTime::<Utc>::from(conflict.time).to_rfc3339_opts(SecondsFormat::Millis, true),
+            dir_id: dir_id.as_hyphenated().to_string(),
+            conflict: "create_create",
+            filename: conflict.filename.to_string_lossy().into_owned(),
+            kept_update_by: &conflict.kept.update_by,
+            kept_hash_sum: hex::encode(conflict.kept.detail.hash_sum),
+            conflict_update_by: &conflict.conflict.update_by,
+            conflict_hash_sum: hex::encode(conflict.conflict.detail.hash_sum),
+            conflict_filename: conflict
+                .conflict_filename
+                .as_ref()
+                .map(|filename| filename.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TimingEntry {
+    time: String,
+    dir_id: String,
+    filename: String,
+    queue_wait_ms: u128,
+    download_ms: u128,
+    write_ms: u128,
+    verify_ms: u128,
+    rename_ms: u128,
+    index_commit_ms: u128,
+    total_ms: u128,
+    slowest_stage: Option<SyncStage>,
+}
+
+impl TimingEntry {
+    fn new(dir_id: Uuid, timing: &FileTiming) -> Self {
+        Self {
+            time: DateTime::<Utc>::from(timing.finished_at)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            dir_id: dir_id.as_hyphenated().to_string(),
+            filename: timing.filename.to_string_lossy().into_owned(),
+            queue_wait_ms: timing.queue_wait.as_millis(),
+            download_ms: timing.download.as_millis(),
+            write_ms: timing.write.as_millis(),
+            verify_ms: timing.verify.as_millis(),
+            rename_ms: timing.rename.as_millis(),
+            index_commit_ms: timing.index_commit.as_millis(),
+            total_ms: timing.total().as_millis(),
+            slowest_stage: timing.slowest_stage(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct JournalFile {
+    file: File,
+    size: u64,
+}
+
+/// append the operations as json lines to the journal file, external tools can tail it. When the
+/// file exceeds the max size, it is rotated to `{path}.1`, the older ones are shifted to
+/// `{path}.2` and so on, only the max files rotated files are kept
+#[derive(Debug)]
+pub struct JsonlJournal {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: Mutex<Option<JournalFile>>,
+}
+
+impl JsonlJournal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_size: DEFAULT_JOURNAL_MAX_SIZE,
+            max_files: DEFAULT_JOURNAL_MAX_FILES,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// 0 means never rotate
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+
+        self
+    }
+
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+
+        self
+    }
+
+    fn write_entry<T: Serialize>(&self, entry: &T) {
+        let mut line = match serde_json::to_vec(entry) {
+            Err(err) => {
+                error!(%err, "marshal journal entry failed");
+
+                return;
+            }
+
+            Ok(line) => line,
+        };
+        line.push(b'\n');
+
+        let _ = self.write_line(&line).tap_err(
+            |err| error!(%err, path = ?log_path(&self.path), "write journal entry failed"),
+        );
+    }
+
+    fn write_line(&self, line: &[u8]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+
+        if let Some(journal_file) = file.as_ref() {
+            if self.max_size > 0 && journal_file.size + line.len() as u64 > self.max_size {
+                file.take();
+                self.rotate()?;
+            }
+        }
+
+        let journal_file = match file.as_mut() {
+            Some(journal_file) => journal_file,
+            None => {
+                let new_file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?;
+                let size = new_file.metadata()?.len();
+
+                file.insert(JournalFile {
+                    file: new_file,
+                    size,
+                })
+            }
+        };
+
+        journal_file.file.write_all(line)?;
+        journal_file.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.max_files == 0 {
+            return remove_if_exists(&self.path);
+        }
+
+        remove_if_exists(&self.rotated_path(self.max_files))?;
+        for n in (1..self.max_files).rev() {
+            rename_if_exists(&self.rotated_path(n), &self.rotated_path(n + 1))?;
+        }
+
+        rename_if_exists(&self.path, &self.rotated_path(1))
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+
+        path.into()
+    }
+}
+
+impl Journal for JsonlJournal {
+    fn record(&self, dir_id: Uuid, source: OperationSource, index_file: &IndexFile) {
+        self.write_entry(&JournalEntry::new(dir_id, source, index_file));
+    }
+
+    fn record_conflict(&self, dir_id: Uuid, conflict: &ConflictRecord) {
+        self.write_entry(&ConflictEntry::new(dir_id, conflict));
+    }
+
+    fn record_timing(&self, dir_id: Uuid, timing: &FileTiming) {
+        self.write_entry(&TimingEntry::new(dir_id, timing));
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsString;
+    use std::time::{Duration, SystemTime};
+
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::index::test_util::index_file;
+
+    #[test]
+    fn record_and_rotate() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let dir_id = Uuid::new_v4();
+
+        let journal = JsonlJournal::new(&path).with_max_files(1);
+        journal.record(
+            dir_id,
+            OperationSource::Rumor,
+            &index_file("dir/test.txt", 1),
+        );
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let entry = serde_json::from_str::<Value>(content.trim_end()).unwrap();
+        assert_eq!(entry["time"], "1970-01-01T00:01:40.000Z");
+        assert_eq!(entry["dir_id"], dir_id.as_hyphenated().to_string());
+        assert_eq!(entry["source"], "rumor");
+        assert_eq!(entry["filename"], "dir/test.txt");
+        assert_eq!(entry["kind"], "File");
+        assert_eq!(entry["gen"], 1);
+        assert_eq!(entry["deleted"], false);
+        assert_eq!(entry["hash_sum"], hex::encode([1; 32]));
+        assert_eq!(entry["update_by"], "test");
+
+        // every record exceeds the max size, so every record rotates the journal
+        let journal = journal.with_max_size(1);
+        journal.record(
+            dir_id,
+            OperationSource::Watch,
+            &index_file("dir/test.txt", 2),
+        );
+        journal.record(
+            dir_id,
+            OperationSource::SyncAll,
+            &index_file("dir/test.txt", 3),
+        );
+
+        let rotated = std::fs::read_to_string(dir.path().join("journal.jsonl.1")).unwrap();
+        assert!(rotated.contains("\"gen\":2"));
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("\"gen\":3"));
+        assert!(!dir.path().join("journal.jsonl.2").exists());
+    }
+
+    #[test]
+    fn record_conflict() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let dir_id = Uuid::new_v4();
+        let mut conflict = index_file("dir/test.txt", 1);
+        conflict.detail.hash_sum = [2; 32];
+        conflict.update_by = "other".to_string();
+
+        let journal = JsonlJournal::new(&path);
+        journal.record_conflict(
+            dir_id,
+            &ConflictRecord {
+                time: SystemTime::UNIX_EPOCH + Duration::from_secs(200),
+                filename: OsString::from("dir/test.txt"),
+                kept: index_file("dir/test.txt", 1),
+                conflict,
+                conflict_filename: Some(OsString::from("dir/test.txt.conflict")),
+            },
+        );
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let entry = serde_json::from_str::<Value>(content.trim_end()).unwrap();
+        assert_eq!(entry["time"], "1970-01-01T00:03:20.000Z");
+        assert_eq!(entry["conflict"], "create_create");
+        assert_eq!(entry["filename"], "dir/test.txt");
+        assert_eq!(entry["kept_update_by"], "test");
+        assert_eq!(entry["kept_hash_sum"], hex::encode([1; 32]));
+        assert_eq!(entry["conflict_update_by"], "other");
+        assert_eq!(entry["conflict_hash_sum"], hex::encode([2; 32]));
+        assert_eq!(entry["conflict_filename"], "dir/test.txt.conflict");
+    }
+
+    #[test]
+    fn record_timing() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let dir_id = Uuid::new_v4();
+
+        let journal = JsonlJournal::new(&path);
+        journal.record_timing(
+            dir_id,
+            &FileTiming {
+                filename: OsString::from("dir/test.txt"),
+                finished_at: SystemTime::UNIX_EPOCH + Duration::from_secs(300),
+                queue_wait: Duration::from_millis(5),
+                download: Duration::from_millis(120),
+                write: Duration::from_millis(30),
+                verify: Duration::ZERO,
+                rename: Duration::from_millis(1),
+                index_commit: Duration::from_millis(4),
+            },
+        );
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let entry = serde_json::from_str::<Value>(content.trim_end()).unwrap();
+        assert_eq!(entry["time"], "1970-01-01T00:05:00.000Z");
+        assert_eq!(entry["filename"], "dir/test.txt");
+        assert_eq!(entry["download_ms"], 120);
+        assert_eq!(entry["verify_ms"], 0);
+        assert_eq!(entry["total_ms"], 160);
+        assert_eq!(entry["slowest_stage"], "download");
+    }
+}
@@ -2,8 +2,20 @@
 #![feature(pin_macro)]
 #![feature(type_alias_impl_trait)]
 
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod clock;
+pub mod events;
 mod ext;
+#[cfg(feature = "fault-injection")]
+mod fault;
 mod file_event_produce;
+mod id_source;
 mod index;
+mod journal;
+pub mod log_filter;
+#[cfg(feature = "grpc")]
+mod server;
 mod sync_control;
 mod transfer;
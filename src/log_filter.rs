@@ -0,0 +1,343 @@
+//! change the log verbosity of the subsystems at runtime, so the detailed logs of a misbehaving
+//! subsystem are captured without restarting the daemon or turning on the global debug logs. The
+//! subscriber of the daemon is wrapped by [`LogFilter::filter`], and the admin API keeps a clone
+//! of the filter to change the levels, such as by the `info,transfer=debug` directives
+
+use std::any::TypeId;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use thiserror::Error;
+use tracing::callsite;
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{info, Event, Level, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+/// the subsystems whose log levels can be changed alone
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Subsystem {
+    Transfer,
+    Index,
+    SyncControl,
+    Watcher,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 4] = [
+        Subsystem::Transfer,
+        Subsystem::Index,
+        Subsystem::SyncControl,
+        Subsystem::Watcher,
+    ];
+
+    /// the name used in the directives
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Transfer => "transfer",
+            Subsystem::Index => "index",
+            Subsystem::SyncControl => "sync_control",
+            Subsystem::Watcher => "watcher",
+        }
+    }
+
+    /// the log target prefix of the subsystem, it is the module path
+    fn target(self) -> &'static str {
+        match self {
+            Subsystem::Transfer => "syncit::transfer",
+            Subsystem::Index => "syncit::index",
+            Subsystem::SyncControl => "syncit::sync_control",
+            Subsystem::Watcher => "syncit::file_event_produce",
+        }
+    }
+
+    fn of_target(target: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|subsystem| {
+            target
+                .strip_prefix(subsystem.target())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+    }
+}
+
+impl Display for Subsystem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = ParseDirectiveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|subsystem| subsystem.name() == s)
+            .ok_or_else(|| ParseDirectiveError::UnknownSubsystem(s.to_string()))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseDirectiveError {
+    #[error("unknown subsystem {0}")]
+    UnknownSubsystem(String),
+    #[error("invalid log level {0}")]
+    InvalidLevel(String),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Levels {
+    default: LevelFilter,
+    subsystems: BTreeMap<Subsystem, LevelFilter>,
+}
+
+impl Levels {
+    fn level_of(&self, target: &str) -> LevelFilter {
+        Subsystem::of_target(target)
+            .and_then(|subsystem| self.subsystems.get(&subsystem).copied())
+            .unwrap_or(self.default)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.subsystems
+            .values()
+            .copied()
+            .fold(self.default, LevelFilter::max)
+    }
+}
+
+/// the runtime log levels of the subsystems, the other logs use the default level. The clones
+/// share the levels, and the changes take effect at once
+#[derive(Debug, Clone)]
+pub struct LogFilter {
+    levels: Arc<RwLock<Levels>>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self::new(LevelFilter::INFO)
+    }
+}
+
+impl LogFilter {
+    pub fn new(default: LevelFilter) -> Self {
+        Self {
+            levels: Arc::new(RwLock::new(Levels {
+                default,
+                subsystems: Default::default(),
+            })),
+        }
+    }
+
+    /// wrap the subscriber, the logs disabled by the filter never reach it
+    pub fn filter<S: Subscriber>(&self, subscriber: S) -> Filtered<S> {
+        Filtered {
+            filter: self.clone(),
+            inner: subscriber,
+        }
+    }
+
+    pub fn set_default_level(&self, level: LevelFilter) {
+        self.update(|levels| levels.default = level);
+    }
+
+    pub fn set_level(&self, subsystem: Subsystem, level: LevelFilter) {
+        self.update(|levels| {
+            levels.subsystems.insert(subsystem, level);
+        });
+    }
+
+    /// the subsystem uses the default level again
+    pub fn clear_level(&self, subsystem: Subsystem) {
+        self.update(|levels| {
+            levels.subsystems.remove(&subsystem);
+        });
+    }
+
+    /// replace the levels by the comma separated directives, such as `info,transfer=debug`, the
+    /// directive without subsystem sets the default level, otherwise the default level is kept.
+    /// When the directives are invalid, the levels are not changed
+    pub fn set_directives(&self, directives: &str) -> Result<(), ParseDirectiveError> {
+        let mut new_levels = Levels {
+            default: self.levels.read().unwrap().default,
+            subsystems: Default::default(),
+        };
+        for directive in directives
+            .split(',')
+            .map(str::trim)
+            .filter(|directive| !directive.is_empty())
+        {
+            match directive.split_once('=') {
+                None => new_levels.default = parse_level(directive)?,
+                Some((subsystem, level)) => {
+                    new_levels
+                        .subsystems
+                        .insert(subsystem.trim().parse()?, parse_level(level.trim())?);
+                }
+            }
+        }
+
+        self.update(|levels| *levels = new_levels);
+
+        Ok(())
+    }
+
+    /// the current levels in the directives form
+    pub fn directives(&self) -> String {
+        let levels = self.levels.read().unwrap();
+
+        [levels.default.to_string().to_lowercase()]
+            .into_iter()
+            .chain(levels.subsystems.iter().map(|(subsystem, level)| {
+                format!("{subsystem}={}", level.to_string().to_lowercase())
+            }))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn is_enabled(&self, target: &str, level: &Level) -> bool {
+        self.levels.read().unwrap().level_of(target) >= *level
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.levels.read().unwrap().max_level()
+    }
+
+    /// the interests of the callsites are cached by tracing, rebuild them after changing
+    fn update(&self, f: impl FnOnce(&mut Levels)) {
+        f(&mut self.levels.write().unwrap());
+        callsite::rebuild_interest_cache();
+
+        info!(directives = %self.directives(), "update log filter done");
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, ParseDirectiveError> {
+    level
+        .parse()
+        .map_err(|_| ParseDirectiveError::InvalidLevel(level.to_string()))
+}
+
+/// the subscriber filtered by the [`LogFilter`]
+#[derive(Debug)]
+pub struct Filtered<S> {
+    filter: LogFilter,
+    inner: S,
+}
+
+impl<S: Subscriber> Subscriber for Filtered<S> {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if !self.filter.is_enabled(metadata.target(), metadata.level()) {
+            return Interest::never();
+        }
+
+        self.inner.register_callsite(metadata)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let max_level = self.filter.max_level();
+
+        Some(
+            self.inner
+                .max_level_hint()
+                .map_or(max_level, |hint| hint.min(max_level)),
+        )
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.filter.is_enabled(metadata.target(), metadata.level()) && self.inner.enabled(metadata)
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        self.inner.new_span(span)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        self.inner.record(span, values)
+    }
+
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        self.inner.record_follows_from(span, follows)
+    }
+
+    fn event_enabled(&self, event: &Event<'_>) -> bool {
+        self.inner.event_enabled(event)
+    }
+
+    fn event(&self, event: &Event<'_>) {
+        self.inner.event(event)
+    }
+
+    fn enter(&self, span: &Id) {
+        self.inner.enter(span)
+    }
+
+    fn exit(&self, span: &Id) {
+        self.inner.exit(span)
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        self.inner.clone_span(id)
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        self.inner.try_close(id)
+    }
+
+    fn current_span(&self) -> Current {
+        self.inner.current_span()
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            return Some(self as *const Self as *const ());
+        }
+
+        self.inner.downcast_raw(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn change_subsystem_levels() {
+        let log_filter = LogFilter::default();
+        assert!(log_filter.is_enabled("syncit::transfer::grpc", &Level::INFO));
+        assert!(!log_filter.is_enabled("syncit::transfer::grpc", &Level::DEBUG));
+
+        log_filter.set_level(Subsystem::Transfer, LevelFilter::TRACE);
+        assert!(log_filter.is_enabled("syncit::transfer::grpc", &Level::TRACE));
+        assert!(!log_filter.is_enabled("syncit::index", &Level::DEBUG));
+        // the module which only shares the prefix is not in the subsystem
+        assert!(!log_filter.is_enabled("syncit::transfers", &Level::DEBUG));
+        assert_eq!(log_filter.max_level(), LevelFilter::TRACE);
+
+        log_filter
+            .set_directives("warn, index=debug,watcher=off")
+            .unwrap();
+        assert_eq!(log_filter.directives(), "warn,index=debug,watcher=off");
+        assert!(!log_filter.is_enabled("syncit::transfer::grpc", &Level::INFO));
+        assert!(log_filter.is_enabled("syncit::index::sqlite_index", &Level::DEBUG));
+        assert!(!log_filter.is_enabled("syncit::file_event_produce", &Level::ERROR));
+        assert!(log_filter.is_enabled("syncit::sync_control", &Level::WARN));
+
+        // the invalid directives change nothing
+        assert!(matches!(
+            log_filter.set_directives("debug,network=trace"),
+            Err(ParseDirectiveError::UnknownSubsystem(_))
+        ));
+        assert!(matches!(
+            log_filter.set_directives("index=loud"),
+            Err(ParseDirectiveError::InvalidLevel(_))
+        ));
+        assert_eq!(log_filter.directives(), "warn,index=debug,watcher=off");
+
+        log_filter.clear_level(Subsystem::Index);
+        assert!(!log_filter.is_enabled("syncit::index", &Level::INFO));
+    }
+}
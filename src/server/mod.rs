@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+
+use flume::Receiver;
+use futures_util::Stream;
+use tonic::transport::server::Router;
+use tonic::transport::Server;
+use uuid::Uuid;
+
+use crate::file_event_produce::NoWatch;
+use crate::sync_control::event::Event;
+use crate::sync_control::event_source::{EventPriority, EventSources};
+use crate::sync_control::options::{ConflictStrategy, SyncOptions};
+use crate::sync_control::SyncController;
+use crate::transfer::grpc::acl::ShareAcl;
+use crate::transfer::grpc::config::GrpcConfig;
+use crate::transfer::grpc::digest_exchange::DigestReceiverBuilder;
+use crate::transfer::grpc::rumor_transport::RumorReceiverBuilder;
+use crate::transfer::grpc::server::GrpcServerBuilder;
+use crate::transfer::rate_limit::BandwidthLimits;
+
+/// the server sends its whole index to the devices periodically, so a device which was offline
+/// when the rumors were sent still gets the changes
+pub const SERVER_ANTI_ENTROPY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// the event stream of the server sync controller
+pub type ServerEventStream = Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>;
+
+/// the serve only policies of the server role, the server never changes the files by itself, so
+/// it never wins a conflict
+pub fn server_options() -> SyncOptions {
+    SyncOptions {
+        conflict_strategy: ConflictStrategy::PreferRemote,
+        anti_entropy_interval: SERVER_ANTI_ENTROPY_INTERVAL,
+        ..Default::default()
+    }
+}
+
+/// the always on headless peer, such as a VPS, it keeps the devices in sync when they are never
+/// online at the same time. The server has no watcher, it applies the rumors of the devices,
+/// serves the blocks to them and gossips the index to them
+#[derive(Debug)]
+pub struct ServerRole {
+    user_id: Uuid,
+    dirs: HashMap<Uuid, PathBuf>,
+    config: GrpcConfig,
+    options: SyncOptions,
+    acl: Option<ShareAcl>,
+    bandwidth_limits: Option<BandwidthLimits>,
+}
+
+impl ServerRole {
+    pub fn new(user_id: Uuid) -> Self {
+        Self {
+            user_id,
+            dirs: Default::default(),
+            config: GrpcConfig::server(),
+            options: server_options(),
+            acl: None,
+            bandwidth_limits: None,
+        }
+    }
+
+    pub fn add_dir(mut self, dir_id: Uuid, sync_dir: PathBuf) -> Self {
+        self.dirs.insert(dir_id, sync_dir);
+
+        self
+    }
+
+    pub fn config(mut self, config: GrpcConfig) -> Self {
+        self.config = config;
+
+        self
+    }
+
+    pub fn with_options(mut self, options: SyncOptions) -> Self {
+        self.options = options;
+
+        self
+    }
+
+    /// only the authenticated devices which are granted the dir can download its blocks and send
+    /// its rumors, keep a clone of the acl to grant or revoke the shares when the server is
+    /// running. When the server hosts the dirs of several users, register the dir owners and the
+    /// devices of the users into the acl, so a user never reaches the dirs of the others
+    pub fn acl(mut self, acl: ShareAcl) -> Self {
+        self.acl = Some(acl);
+
+        self
+    }
+
+    /// the served blocks are limited by the upload limiter, the download limiter should wrap the
+    /// download transfers passed to [`ServerRole::controller`], the limits follow the options of
+    /// the controllers
+    pub fn bandwidth_limits(mut self, bandwidth_limits: BandwidthLimits) -> Self {
+        self.bandwidth_limits = Some(bandwidth_limits);
+
+        self
+    }
+
+    /// build the server which serves the blocks, receives the rumors and replies the index digests
+    /// of the dirs, the returned events of a dir should be passed to [`ServerRole::controller`]
+    pub fn build(&self) -> (Router, HashMap<Uuid, Receiver<Event>>) {
+        let mut block_server = GrpcServerBuilder::new().config(self.config.clone());
+        let mut rumor_receiver = RumorReceiverBuilder::new().config(self.config.clone());
+        let mut digest_receiver = DigestReceiverBuilder::new().config(self.config.clone());
+        let mut events = HashMap::with_capacity(self.dirs.len());
+
+        for (dir_id, sync_dir) in &self.dirs {
+            let (event_sender, event_receiver) = flume::unbounded();
+
+            block_server = block_server.add_dir(*dir_id, sync_dir.clone());
+            if self.options.no_atime {
+                block_server = block_server.no_atime(*dir_id);
+            }
+            rumor_receiver = rumor_receiver.add_dir(*dir_id, event_sender.clone());
+            digest_receiver = digest_receiver.add_dir(*dir_id, event_sender);
+            events.insert(*dir_id, event_receiver);
+        }
+
+        if let Some(acl) = &self.acl {
+            block_server = block_server.acl(acl.clone());
+            rumor_receiver = rumor_receiver.acl(acl.clone());
+            digest_receiver = digest_receiver.acl(acl.clone());
+        }
+
+        if let Some(bandwidth_limits) = &self.bandwidth_limits {
+            block_server = block_server.upload_limiter(bandwidth_limits.upload.clone());
+        }
+
+        let router = self
+            .config
+            .apply_server(Server::builder())
+            .add_service(block_server.build_service())
+            .add_service(rumor_receiver.build_service())
+            .add_service(digest_receiver.build_service());
+
+        (router, events)
+    }
+
+    /// the sync controller of the dir, when return None, the dir is not added. The dir is
+    /// scanned once when the controller starts by the sync all on start option, then it is only
+    /// changed by the rumors
+    pub fn controller<I, Si, Dl>(
+        &self,
+        dir_id: Uuid,
+        index: I,
+        events: Receiver<Event>,
+        rumor_sender: Si,
+        download_transfer: Dl,
+    ) -> Option<SyncController<I, ServerEventStream, Si, Dl, NoWatch>> {
+        let sync_dir = self.dirs.get(&dir_id)?;
+
+        let controller = SyncController::new(
+            self.user_id,
+            dir_id,
+            sync_dir.clone(),
+            index,
+            server_event_stream(events),
+            rumor_sender,
+            download_transfer,
+            NoWatch,
+        )
+        .with_options(self.options.clone());
+
+        Some(match &self.bandwidth_limits {
+            None => controller,
+            Some(bandwidth_limits) => controller.with_bandwidth_limits(bandwidth_limits.clone()),
+        })
+    }
+}
+
+/// the received rumors and digests of the dir are the only event source of the server
+fn server_event_stream(events: Receiver<Event>) -> ServerEventStream {
+    Box::pin(EventSources::new().add_receiver(EventPriority::Normal, events))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn forward_server_events() {
+        let dir_id = Uuid::new_v4();
+        let server_role = ServerRole::new(Uuid::new_v4()).add_dir(dir_id, PathBuf::from("/tmp"));
+
+        let (_router, mut events) = server_role.build();
+        let events = events.remove(&dir_id).unwrap();
+        assert!(events.is_empty());
+
+        let (event_sender, event_receiver) = flume::unbounded();
+        let mut event_stream = server_event_stream(event_receiver);
+        event_sender
+            .send(Event::Rumors {
+                sender_id: Uuid::new_v4(),
+                seq: Some(1),
+                remote_index: vec![],
+                dir_renames: vec![],
+            })
+            .unwrap();
+        drop(event_sender);
+
+        assert!(matches!(
+            event_stream.try_next().await.unwrap(),
+            Some(Event::Rumors { seq: Some(1), .. })
+        ));
+        assert!(event_stream.try_next().await.unwrap().is_none());
+    }
+}
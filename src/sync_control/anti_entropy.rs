@@ -0,0 +1,168 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io;
+
+use async_trait::async_trait;
+use mockall::automock;
+use uuid::Uuid;
+
+use crate::index::{IndexFile, Sha256sum, VersionVector};
+
+/// the summary of the index entry of a file, the peers exchange the digests instead of the whole
+/// index to find the entries which one of them missed
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileDigest {
+    pub filename: OsString,
+    pub gen: u32,
+    pub hash_sum: Sha256sum,
+    pub deleted: bool,
+    pub version: VersionVector,
+}
+
+impl From<&IndexFile> for FileDigest {
+    fn from(index_file: &IndexFile) -> Self {
+        Self {
+            filename: index_file.filename.clone(),
+            gen: index_file.detail.gen,
+            hash_sum: index_file.detail.hash_sum,
+            deleted: index_file.detail.deleted,
+            version: index_file.detail.version.clone(),
+        }
+    }
+}
+
+impl FileDigest {
+    /// like [`FileDetail::version_cmp`], when return None, the entries are changed concurrently
+    ///
+    /// [`FileDetail::version_cmp`]: crate::index::FileDetail::version_cmp
+    pub fn version_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.version.is_empty() || other.version.is_empty() {
+            return Some(self.gen.cmp(&other.gen));
+        }
+
+        self.version.partial_cmp(&other.version)
+    }
+}
+
+/// the files whose entries differ between the local and the remote digests, a file changed
+/// concurrently is in both lists, so each side gets the entry of the other and the conflict is
+/// handled by the rumors handler
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DigestDiff {
+    /// the local entry is newer or the remote doesn't have the file
+    pub local_newer: Vec<OsString>,
+    /// the remote entry is newer or the local doesn't have the file
+    pub remote_newer: Vec<OsString>,
+}
+
+/// compare the digests by the filenames, the filenames of the diff are sorted
+pub fn diff_digests(local: &[FileDigest], remote: &[FileDigest]) -> DigestDiff {
+    let mut digests = BTreeMap::<_, (Option<&FileDigest>, Option<&FileDigest>)>::new();
+    for digest in local {
+        digests.entry(&digest.filename).or_default().0 = Some(digest);
+    }
+    for digest in remote {
+        digests.entry(&digest.filename).or_default().1 = Some(digest);
+    }
+
+    let mut diff = DigestDiff::default();
+    for (filename, digests) in digests {
+        let (local_newer, remote_newer) = match digests {
+            (None, None) => continue,
+            (Some(_), None) => (true, false),
+            (None, Some(_)) => (false, true),
+            (Some(local), Some(remote)) if local == remote => continue,
+            (Some(local), Some(remote)) => match local.version_cmp(remote) {
+                Some(Ordering::Greater) => (true, false),
+                Some(Ordering::Less) => (false, true),
+                Some(Ordering::Equal) | None => (true, true),
+            },
+        };
+
+        if local_newer {
+            diff.local_newer.push(filename.clone());
+        }
+        if remote_newer {
+            diff.remote_newer.push(filename.clone());
+        }
+    }
+
+    diff
+}
+
+/// the answer of the peer to the digests
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DigestReply {
+    /// the entries which the peer has newer, they are applied as the rumors of the peer
+    pub newer: Vec<IndexFile>,
+    /// the files which the peer misses, they are announced to the peer
+    pub missing: Vec<OsString>,
+}
+
+/// exchange the index digests with the peers, the peer compares the digests with its index and
+/// replies the diff
+#[automock]
+#[async_trait]
+pub trait DigestExchange: Debug + Send + Sync {
+    /// the peers which the digests can be exchanged with
+    fn peer_ids(&self) -> Vec<Uuid>;
+
+    async fn exchange(
+        &self,
+        peer_id: Uuid,
+        dir_id: Uuid,
+        digests: Vec<FileDigest>,
+    ) -> io::Result<DigestReply>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(filename: &str, gen: u32, version: VersionVector) -> FileDigest {
+        FileDigest {
+            filename: OsString::from(filename),
+            gen,
+            hash_sum: [gen as _; 32],
+            deleted: false,
+            version,
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let created = VersionVector::initial("a");
+        let local = [
+            digest("concurrent.txt", 2, created.incremented("a")),
+            digest("local_newer.txt", 2, created.incremented("a")),
+            digest("local_only.txt", 1, created.clone()),
+            digest("old_peer.txt", 3, Default::default()),
+            digest("same.txt", 1, created.clone()),
+        ];
+        let remote = [
+            digest("concurrent.txt", 2, created.incremented("b")),
+            digest("local_newer.txt", 1, created.clone()),
+            digest("old_peer.txt", 4, created.clone()),
+            digest("remote_only.txt", 1, created.clone()),
+            digest("same.txt", 1, created.clone()),
+        ];
+
+        assert_eq!(
+            diff_digests(&local, &remote),
+            DigestDiff {
+                local_newer: vec![
+                    "concurrent.txt".into(),
+                    "local_newer.txt".into(),
+                    "local_only.txt".into(),
+                ],
+                remote_newer: vec![
+                    "concurrent.txt".into(),
+                    "old_peer.txt".into(),
+                    "remote_only.txt".into(),
+                ],
+            }
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+
+use async_trait::async_trait;
+use mockall::automock;
+use uuid::Uuid;
+
+use crate::index::IndexFile;
+use crate::sync_control::options::ConflictStrategy;
+
+/// the remote and local both changed the file since the last synced version, and the remote
+/// version is not older than the local version
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Conflict {
+    pub dir_id: Uuid,
+    pub local: IndexFile,
+    pub remote: IndexFile,
+}
+
+/// which file is kept after the conflict
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Resolution {
+    /// keep the local file, it becomes a newer version than the remote file, so the peers apply
+    /// it instead
+    Local,
+
+    /// replace the local file with the remote file, the local change is lost
+    Remote,
+
+    /// copy the local file as a `.conflict` file then apply the remote file
+    Both,
+}
+
+/// which side of a tracked conflict file is kept, see [`Event::ResolveConflict`]
+///
+/// [`Event::ResolveConflict`]: crate::sync_control::event::Event::ResolveConflict
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConflictSide {
+    /// keep the synced file, the conflict file is removed
+    Synced,
+
+    /// the conflict file replaces the synced file as a new local version, so it is synced to the
+    /// peers
+    ConflictCopy,
+}
+
+/// decide how the conflict is resolved, such as prompting the user
+#[automock]
+#[async_trait]
+pub trait ConflictResolver: Debug + Send + Sync {
+    async fn resolve(&self, conflict: &Conflict) -> Resolution;
+}
+
+#[async_trait]
+impl ConflictResolver for ConflictStrategy {
+    async fn resolve(&self, conflict: &Conflict) -> Resolution {
+        match self {
+            ConflictStrategy::KeepBoth => Resolution::Both,
+            ConflictStrategy::PreferRemote => Resolution::Remote,
+            ConflictStrategy::PreferLocal => Resolution::Local,
+            ConflictStrategy::KeepNewest => {
+                if (conflict.local.update_time, &conflict.local.update_by)
+                    > (conflict.remote.update_time, &conflict.remote.update_by)
+                {
+                    Resolution::Local
+                } else {
+                    Resolution::Remote
+                }
+            }
+        }
+    }
+}
+
+/// resolve the conflicts by the async callback, the applications can ask the user which file is
+/// kept, the rumor handling of the file waits for the callback
+pub struct CallbackResolver<F> {
+    callback: F,
+}
+
+impl<F> CallbackResolver<F> {
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F> Debug for CallbackResolver<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CallbackResolver").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<F, Fut> ConflictResolver for CallbackResolver<F>
+where
+    F: Fn(Conflict) -> Fut + Send + Sync,
+    Fut: Future<Output = Resolution> + Send,
+{
+    async fn resolve(&self, conflict: &Conflict) -> Resolution {
+        (self.callback)(conflict.clone()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::index::test_util::index_file;
+
+    #[tokio::test]
+    async fn resolve_by_strategy() {
+        let conflict = Conflict {
+            dir_id: Uuid::new_v4(),
+            local: IndexFile {
+                update_time: SystemTime::UNIX_EPOCH + Duration::from_secs(200),
+                ..index_file("test.txt", 2)
+            },
+            remote: index_file("test.txt", 3),
+        };
+
+        assert_eq!(
+            ConflictStrategy::KeepNewest.resolve(&conflict).await,
+            Resolution::Local
+        );
+        assert_eq!(
+            ConflictStrategy::PreferRemote.resolve(&conflict).await,
+            Resolution::Remote
+        );
+
+        let resolver = CallbackResolver::new(|conflict: Conflict| async move {
+            if conflict.remote.detail.gen > conflict.local.detail.gen {
+                Resolution::Both
+            } else {
+                Resolution::Local
+            }
+        });
+        assert_eq!(resolver.resolve(&conflict).await, Resolution::Both);
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::sync_control::options::{ConflictStrategy, EmptyDirPolicy, SyncOptions};
+
+/// the control message to reconfigure a running sync controller, it is applied between events
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Control {
+    /// replace all options
+    Options(Box<SyncOptions>),
+
+    ConflictStrategy(ConflictStrategy),
+
+    WriteConcurrency(usize),
+
+    EmptyDirPolicy(EmptyDirPolicy),
+
+    /// the upload and download bytes per second, 0 means no limit
+    BandwidthLimit {
+        upload_bytes_per_sec: u64,
+        download_bytes_per_sec: u64,
+    },
+
+    /// lift the bandwidth limits and concurrency caps for the duration, such as the initial
+    /// seeding of a new device, then restore them, a zero duration ends the turbo sync. The
+    /// handling event keeps the lifted caps until it is done
+    Turbo(Duration),
+
+    /// stop handling the watch events and rumors, such as on a metered connection, they are
+    /// queued and the periodic tasks are stopped until resuming
+    Pause,
+
+    /// handle the events queued when the sync is paused, then continue as usual
+    Resume,
+}
+
+impl Control {
+    pub fn apply(self, options: &mut SyncOptions) {
+        match self {
+            Control::Options(new_options) => *options = *new_options,
+            Control::ConflictStrategy(conflict_strategy) => {
+                options.conflict_strategy = conflict_strategy
+            }
+            Control::WriteConcurrency(write_concurrency) => {
+                options.write_concurrency = write_concurrency
+            }
+            Control::EmptyDirPolicy(empty_dir_policy) => {
+                options.empty_dir_policy = empty_dir_policy
+            }
+            Control::BandwidthLimit {
+                upload_bytes_per_sec,
+                download_bytes_per_sec,
+            } => {
+                options.upload_bytes_per_sec = upload_bytes_per_sec;
+                options.download_bytes_per_sec = download_bytes_per_sec;
+            }
+
+            // kept by the controller, the options are not changed
+            Control::Turbo(_) | Control::Pause | Control::Resume => {}
+        }
+
+        info!(?options, "apply control done");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let mut options = SyncOptions::default();
+
+        Control::ConflictStrategy(ConflictStrategy::PreferRemote).apply(&mut options);
+        Control::WriteConcurrency(1).apply(&mut options);
+        Control::EmptyDirPolicy(EmptyDirPolicy::Prune).apply(&mut options);
+        Control::BandwidthLimit {
+            upload_bytes_per_sec: 100,
+            download_bytes_per_sec: 200,
+        }
+        .apply(&mut options);
+        Control::Turbo(Duration::from_secs(60)).apply(&mut options);
+        assert_eq!(
+            options,
+            SyncOptions {
+                write_concurrency: 1,
+                conflict_strategy: ConflictStrategy::PreferRemote,
+                empty_dir_policy: EmptyDirPolicy::Prune,
+                upload_bytes_per_sec: 100,
+                download_bytes_per_sec: 200,
+                ..Default::default()
+            }
+        );
+
+        let new_options = SyncOptions {
+            debounce: Duration::from_millis(100),
+            ..Default::default()
+        };
+        Control::Options(Box::new(new_options.clone())).apply(&mut options);
+        assert_eq!(options, new_options);
+    }
+}
@@ -0,0 +1,63 @@
+use std::ffi::OsString;
+use std::sync::{Arc, Mutex};
+
+/// what syncing a rumor would do to the local file
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PlannedOp {
+    /// the local file doesn't exist
+    Create,
+
+    /// replace the local file with the remote one
+    Update,
+
+    Delete,
+
+    /// both sides changed the file, it is handled by the conflict strategy or the resolver
+    Conflict,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PlannedAction {
+    pub filename: OsString,
+    pub op: PlannedOp,
+    /// the bytes of the remote blocks which the local file doesn't have
+    pub transfer_bytes: u64,
+}
+
+/// collect the planned actions of the dry run, the rumors are planned against the local index
+/// instead of being applied, so no block is downloaded, no local file is changed and the index is
+/// not updated. The clones share the actions
+#[derive(Debug, Clone, Default)]
+pub struct SyncPlan {
+    actions: Arc<Mutex<Vec<PlannedAction>>>,
+}
+
+impl SyncPlan {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn plan(&self, action: PlannedAction) {
+        self.actions.lock().unwrap().push(action);
+    }
+
+    /// the planned actions in the handled order
+    pub fn actions(&self) -> Vec<PlannedAction> {
+        self.actions.lock().unwrap().clone()
+    }
+
+    /// the bytes which the planned actions would download
+    pub fn transfer_bytes(&self) -> u64 {
+        self.actions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|action| action.transfer_bytes)
+            .sum()
+    }
+
+    /// take the planned actions, such as after previewing a batch of rumors
+    pub fn take(&self) -> Vec<PlannedAction> {
+        std::mem::take(&mut *self.actions.lock().unwrap())
+    }
+}
@@ -0,0 +1,120 @@
+use std::error::Error;
+use std::io;
+
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::ext::hash::HashCanceled;
+use crate::transfer::integrity::PeerQuarantined;
+
+type BoxError = Box<dyn Error + Send + Sync + 'static>;
+
+/// the error of the sync, the caller can retry the transient transfer failures, and stop the
+/// sync when the index is broken
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error("index error: {0}")]
+    Index(BoxError),
+
+    /// the rumors or blocks can't be sent or received, or the received ones are invalid
+    #[error("transfer error: {0}")]
+    Transfer(BoxError),
+
+    #[error("filesystem error: {0}")]
+    Filesystem(BoxError),
+
+    /// the index is changed by others when handling the event, the event can be handled again
+    #[error("conflict: {0}")]
+    Conflict(String),
+
+    /// the sync is stopped by the shutdown
+    #[error("sync is cancelled")]
+    Cancelled,
+
+    /// the blocks can't be downloaded from the quarantined peer, the file is synced when a
+    /// healthy peer spreads its rumor or after the peer is released
+    #[error("peer {0} is quarantined")]
+    Quarantined(Uuid),
+}
+
+impl SyncError {
+    pub(crate) fn index(err: impl Into<BoxError>) -> Self {
+        Self::Index(err.into())
+    }
+
+    pub(crate) fn transfer(err: impl Into<BoxError>) -> Self {
+        Self::Transfer(err.into())
+    }
+
+    /// the download errors are passed as the io errors with the file writing errors, so they are
+    /// wrapped to be told apart
+    pub(crate) fn transfer_io(err: io::Error) -> io::Error {
+        match PeerQuarantined::quarantined_peer(&err) {
+            None => io::Error::new(err.kind(), Self::transfer(err)),
+            Some(peer_id) => io::Error::new(err.kind(), Self::Quarantined(peer_id)),
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, SyncError::Transfer(_) | SyncError::Conflict(_))
+    }
+}
+
+/// the handlers tag the index and transfer errors with [`SyncError`], the other errors are
+/// filesystem errors
+impl From<anyhow::Error> for SyncError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<SyncError>() {
+            Err(err) => err,
+            Ok(err) => return err,
+        };
+
+        if err.is::<HashCanceled>() {
+            return SyncError::Cancelled;
+        }
+
+        let transfer_err = err.chain().find_map(|cause| {
+            cause
+                .downcast_ref::<io::Error>()?
+                .get_ref()?
+                .downcast_ref::<SyncError>()
+        });
+        match transfer_err {
+            None => SyncError::Filesystem(err.into()),
+            Some(&SyncError::Quarantined(peer_id)) => SyncError::Quarantined(peer_id),
+            Some(_) => SyncError::Transfer(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let err = anyhow::Error::from(SyncError::index(io::Error::from(io::ErrorKind::Other)));
+        assert!(matches!(SyncError::from(err), SyncError::Index(_)));
+
+        let err = anyhow::Error::from(HashCanceled).context("hash file failed");
+        assert!(matches!(SyncError::from(err), SyncError::Cancelled));
+
+        let err = SyncError::transfer_io(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        let err = SyncError::from(anyhow::Error::from(err));
+        assert!(matches!(err, SyncError::Transfer(_)));
+        assert!(err.is_transient());
+
+        let peer_id = Uuid::new_v4();
+        let err = SyncError::transfer_io(PeerQuarantined(peer_id).into());
+        let err = SyncError::from(anyhow::Error::from(err).context("download file failed"));
+        assert!(matches!(err, SyncError::Quarantined(id) if id == peer_id));
+        assert!(!err.is_transient());
+
+        let err = SyncError::from(anyhow!("special file can't be synced"));
+        assert!(matches!(err, SyncError::Filesystem(_)));
+        assert!(!err.is_transient());
+    }
+}
@@ -1,7 +1,20 @@
+use std::ffi::OsString;
+
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
 use crate::file_event_produce::WatchEvent;
 use crate::index::IndexFile;
+use crate::sync_control::anti_entropy::{DigestReply, FileDigest};
+use crate::sync_control::conflict::ConflictSide;
+
+/// a dir renamed by the sender, the receiver renames its local dir once instead of downloading
+/// the moved files again
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirRename {
+    pub old_dir: OsString,
+    pub new_dir: OsString,
+}
 
 #[derive(Debug)]
 pub enum Event {
@@ -9,8 +22,48 @@ pub enum Event {
 
     Rumors {
         sender_id: Uuid,
+        /// the sender's monotonic sequence number of the message, used to drop the duplicated
+        /// messages of the sender, it is None when the rumors are pulled from the peer by the anti
+        /// entropy instead of sent by it, they are not checked for duplication
+        seq: Option<u64>,
         remote_index: Vec<IndexFile>,
+        dir_renames: Vec<DirRename>,
     },
 
     SyncAll,
+
+    /// the local files whose content doesn't match the index, such as found by the
+    /// [`Scrubber`](super::scrub::Scrubber), they are downloaded from the peers again
+    Repair(Vec<OsString>),
+
+    /// resolve the conflict file tracked by the [`ConflictStore`], the outstanding conflicts are
+    /// listed by the store
+    ///
+    /// [`ConflictStore`]: crate::index::conflicts::ConflictStore
+    ResolveConflict {
+        conflict_filename: OsString,
+        side: ConflictSide,
+    },
+
+    /// send the current index entries of the files to the peers again without changing them,
+    /// such as after a manual repair or when a peer reports it misses the files, the peers which
+    /// already have the entries ignore them
+    Announce {
+        filenames: Vec<OsString>,
+        /// only send to the peer, otherwise the peers are selected by the fanout
+        to: Option<Uuid>,
+    },
+
+    /// the index digests of the peer for the anti entropy, the entries which the peer misses
+    /// or has older and the files which the peer has newer are replied
+    Digests {
+        sender_id: Uuid,
+        digests: Vec<FileDigest>,
+        reply: oneshot::Sender<DigestReply>,
+    },
+
+    /// the peer is connected again, such as after the laptop sleeps, only the index of the peer
+    /// is exchanged, so the files changed during the downtime are synced without scanning the
+    /// whole dir
+    PeerConnected(Uuid),
 }
@@ -0,0 +1,259 @@
+use std::cmp::Reverse;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use flume::Receiver;
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::events::{SyncEvent, SyncEvents};
+use crate::sync_control::event::Event;
+
+pub type BoxEventStream<E> = Pin<Box<dyn Stream<Item = Result<Event, E>> + Send>>;
+
+/// the priority of an event source, the ready events of the higher priority sources are taken
+/// first
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum EventPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// merge the event sources, such as the file watcher, the rumor receiver and the manual
+/// triggers, into the event stream of the [`SyncController`]. The ready events of the higher
+/// priority sources are taken first, so a busy source of the lower priority never delays them,
+/// and the sources of the same priority take turns. The sources are only polled when the
+/// controller is ready for the next event, the producers of the bounded receivers wait when the
+/// controller is busy, and the stream ends when all sources end
+///
+/// [`SyncController`]: super::SyncController
+pub struct EventSources<E> {
+    /// sorted by the priority, the highest first, and the sources of the same priority keep the
+    /// adding order
+    sources: Vec<(EventPriority, BoxEventStream<E>)>,
+    /// the taken events count, the sources of the same priority are polled from it in turns
+    turn: usize,
+}
+
+impl<E> Default for EventSources<E> {
+    fn default() -> Self {
+        Self {
+            sources: vec![],
+            turn: 0,
+        }
+    }
+}
+
+impl<E> EventSources<E> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+}
+
+impl<E: 'static> EventSources<E> {
+    pub fn add_stream<S>(mut self, priority: EventPriority, stream: S) -> Self
+    where
+        S: Stream<Item = Result<Event, E>> + Send + 'static,
+    {
+        self.sources.push((priority, Box::pin(stream)));
+        self.sources.sort_by_key(|(priority, _)| Reverse(*priority));
+
+        self
+    }
+
+    /// the events sent to the receiver, use a bounded channel to make the producer wait when the
+    /// controller is busy
+    pub fn add_receiver(self, priority: EventPriority, receiver: Receiver<Event>) -> Self {
+        self.add_stream(priority, receiver.into_stream().map(Ok))
+    }
+
+    /// the peers connected by the address book which publishes to the events, they are sent as
+    /// [`Event::PeerConnected`], so the reconnected peers are caught up
+    pub fn add_peer_connections(self, priority: EventPriority, events: &SyncEvents) -> Self {
+        let peer_connections = stream::unfold(events.subscribe(), |mut subscriber| async move {
+            loop {
+                match subscriber.recv().await {
+                    Ok(SyncEvent::PeerConnected(connected)) => {
+                        match Uuid::parse_str(&connected.peer_id) {
+                            Err(err) => {
+                                warn!(%err, peer_id = %connected.peer_id, "invalid connected peer id, ignore");
+                            }
+
+                            Ok(peer_id) => {
+                                return Some((Ok(Event::PeerConnected(peer_id)), subscriber))
+                            }
+                        }
+                    }
+
+                    Ok(_) => {}
+
+                    // the peers of the skipped events are caught up by the anti entropy
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "peer connected events lag behind");
+                    }
+
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        self.add_stream(priority, peer_connections)
+    }
+}
+
+impl<E> Stream for EventSources<E> {
+    type Item = Result<Event, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut ended = vec![];
+        let mut polled = None;
+
+        let mut group_start = 0;
+        'groups: while group_start < this.sources.len() {
+            let priority = this.sources[group_start].0;
+            let group_len = this.sources[group_start..]
+                .iter()
+                .take_while(|(source_priority, _)| *source_priority == priority)
+                .count();
+
+            for i in 0..group_len {
+                let position = group_start + (this.turn + i) % group_len;
+                match this.sources[position].1.poll_next_unpin(cx) {
+                    Poll::Pending => {}
+                    Poll::Ready(None) => ended.push(position),
+                    Poll::Ready(Some(item)) => {
+                        polled = Some(item);
+
+                        break 'groups;
+                    }
+                }
+            }
+
+            group_start += group_len;
+        }
+
+        // the positions are pushed in turns, remove the later ones first
+        ended.sort_unstable_by_key(|position| Reverse(*position));
+        for position in ended {
+            let (priority, _) = this.sources.remove(position);
+
+            info!(?priority, "event source ends");
+        }
+
+        match polled {
+            Some(item) => {
+                this.turn = this.turn.wrapping_add(1);
+
+                Poll::Ready(Some(item))
+            }
+
+            None if this.sources.is_empty() => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::io;
+    use std::time::{Duration, SystemTime};
+
+    use futures_util::TryStreamExt;
+
+    use super::*;
+    use crate::index::address::{AddressKind, PeerAddress};
+
+    fn repair(filename: &str) -> Event {
+        Event::Repair(vec![OsString::from(filename)])
+    }
+
+    fn filename(event: Event) -> OsString {
+        match event {
+            Event::Repair(mut filenames) => filenames.remove(0),
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn merge_by_priority() {
+        let (high_sender, high_receiver) = flume::bounded(1);
+        high_sender.send(repair("high")).unwrap();
+        drop(high_sender);
+
+        let mut event_sources = EventSources::<io::Error>::new()
+            .add_stream(
+                EventPriority::Normal,
+                stream::iter([Ok(repair("a1")), Ok(repair("a2"))]),
+            )
+            .add_stream(EventPriority::Low, stream::iter([Ok(repair("low"))]))
+            .add_stream(
+                EventPriority::Normal,
+                stream::iter([
+                    Ok(repair("b1")),
+                    Err(io::Error::new(io::ErrorKind::Other, "watch failed")),
+                ]),
+            )
+            .add_receiver(EventPriority::High, high_receiver);
+        assert_eq!(event_sources.len(), 4);
+
+        let mut filenames = vec![];
+        let mut errors = 0;
+        while let Some(event) = event_sources.next().await {
+            match event {
+                Ok(event) => filenames.push(filename(event)),
+                Err(_) => errors += 1,
+            }
+        }
+
+        // the sources of the same priority take turns
+        assert_eq!(filenames, ["high", "b1", "a1", "a2", "low"]);
+        assert_eq!(errors, 1);
+        assert!(event_sources.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wait_pending_source() {
+        let (sender, receiver) = flume::bounded(1);
+        let mut event_sources = EventSources::<io::Error>::new()
+            .add_receiver(EventPriority::Normal, receiver)
+            .add_stream(EventPriority::High, stream::empty());
+
+        let task = tokio::spawn(async move { event_sources.try_next().await.unwrap() });
+        sender.send_async(repair("later")).await.unwrap();
+
+        assert_eq!(filename(task.await.unwrap().unwrap()), "later");
+    }
+
+    #[tokio::test]
+    async fn peer_connections() {
+        let events = SyncEvents::default();
+        let mut event_sources =
+            EventSources::<io::Error>::new().add_peer_connections(EventPriority::High, &events);
+
+        let peer_id = Uuid::new_v4();
+        events.scan_completed(Uuid::new_v4(), SystemTime::now(), Duration::ZERO);
+        events.peer_connected(
+            &PeerAddress::new(peer_id, "http://peer:8080", AddressKind::Lan),
+            SystemTime::now(),
+        );
+
+        match event_sources.try_next().await.unwrap().unwrap() {
+            Event::PeerConnected(connected) => assert_eq!(connected, peer_id),
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+}
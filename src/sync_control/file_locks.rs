@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+/// per filename async read write locks shared by the handlers, the handlers which touch the same
+/// file are serialized, and the handlers which touch different files don't block each other. A
+/// handler which changes the file or its index takes the write lock, the read lock is for the
+/// readers which only read the file content
+#[derive(Debug, Clone, Default)]
+pub struct FileLocks {
+    locks: Arc<Mutex<HashMap<OsString, Arc<RwLock<()>>>>>,
+}
+
+#[derive(Debug)]
+enum Guard {
+    Read(#[allow(dead_code)] OwnedRwLockReadGuard<()>),
+    Write(#[allow(dead_code)] OwnedRwLockWriteGuard<()>),
+}
+
+/// the file lock is released when the guard is dropped
+#[derive(Debug)]
+pub struct FileLockGuard {
+    filename: OsString,
+    locks: FileLocks,
+    guard: Option<Guard>,
+}
+
+impl FileLocks {
+    pub async fn read(&self, filename: &OsStr) -> FileLockGuard {
+        let guard = self.lock_of(filename).read_owned().await;
+
+        FileLockGuard {
+            filename: filename.to_os_string(),
+            locks: self.clone(),
+            guard: Some(Guard::Read(guard)),
+        }
+    }
+
+    pub async fn write(&self, filename: &OsStr) -> FileLockGuard {
+        let guard = self.lock_of(filename).write_owned().await;
+
+        FileLockGuard {
+            filename: filename.to_os_string(),
+            locks: self.clone(),
+            guard: Some(Guard::Write(guard)),
+        }
+    }
+
+    /// lock the files in the filename order, so two handlers which lock the same files can't
+    /// deadlock
+    pub async fn write_all(&self, filenames: &[&OsStr]) -> Vec<FileLockGuard> {
+        let mut filenames = filenames.to_vec();
+        filenames.sort_unstable();
+        filenames.dedup();
+
+        let mut guards = Vec::with_capacity(filenames.len());
+        for filename in filenames {
+            guards.push(self.write(filename).await);
+        }
+
+        guards
+    }
+
+    fn lock_of(&self, filename: &OsStr) -> Arc<RwLock<()>> {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(filename.to_os_string())
+            .or_default()
+            .clone()
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+}
+
+impl Drop for FileLockGuard {
+    fn drop(&mut self) {
+        self.guard.take();
+
+        // nobody holds or waits for the lock, remove it, so the map doesn't grow with every
+        // touched file
+        let mut locks = self.locks.locks.lock().unwrap();
+        if locks
+            .get(&self.filename)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.filename);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lock() {
+        let locks = FileLocks::default();
+
+        let read_guard1 = locks.read(OsStr::new("a")).await;
+        let read_guard2 = locks.read(OsStr::new("a")).await;
+        let write_guard = locks.write(OsStr::new("b")).await;
+
+        // the write lock waits for the read locks
+        assert!(
+            time::timeout(Duration::from_millis(10), locks.write(OsStr::new("a")))
+                .await
+                .is_err()
+        );
+
+        drop(read_guard1);
+        drop(read_guard2);
+        drop(write_guard);
+        assert_eq!(locks.len(), 0);
+
+        let guards = locks
+            .write_all(&[OsStr::new("b"), OsStr::new("a"), OsStr::new("b")])
+            .await;
+        assert_eq!(guards.len(), 2);
+        assert_eq!(locks.len(), 2);
+
+        drop(guards);
+        assert_eq!(locks.len(), 0);
+    }
+}
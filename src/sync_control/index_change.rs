@@ -0,0 +1,101 @@
+use std::ffi::OsStr;
+use std::mem;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use tracing::{error, info, warn};
+
+use crate::ext::log_path;
+use crate::index::{FileDetail, FileKind, IndexFile, IndexGuard, VersionVector};
+use crate::sync_control::error::SyncError;
+use crate::sync_control::options::SpecialFilePolicy;
+
+/// the index change of a file, the caller writes it into the index
+#[derive(Debug)]
+pub enum IndexChange {
+    Create(IndexFile),
+    Update(IndexFile),
+}
+
+/// the special file and the stored symlink can't be hashed, they are recorded in the index
+/// without content. The special file fails the event handling when the special file policy is
+/// error. When return None, means the file index is not changed
+pub async fn unsyncable_file_change<G>(
+    filename: &OsStr,
+    kind: FileKind,
+    special_file_policy: SpecialFilePolicy,
+    update_by: &str,
+    now: SystemTime,
+    index_guard: &mut G,
+) -> Result<Option<IndexChange>>
+where
+    G: IndexGuard,
+    G::Error: Send + Sync + 'static,
+{
+    if kind == FileKind::Special && special_file_policy == SpecialFilePolicy::Error {
+        error!(filename = ?log_path(&filename), "special file can't be synced");
+
+        return Err(anyhow!("special file {:?} can't be synced", filename));
+    }
+
+    warn!(filename = ?log_path(&filename), %kind, "skip unsyncable file content");
+
+    let detail = FileDetail {
+        gen: 1,
+        hash_sum: [0; 32],
+        block_chain: None,
+        deleted: false,
+        metadata: None,
+        version: VersionVector::initial(update_by),
+        link_group: None,
+    };
+
+    match index_guard
+        .get_file(filename)
+        .await
+        .map_err(SyncError::index)?
+    {
+        None => {
+            let index_file = IndexFile {
+                filename: filename.to_os_string(),
+                kind,
+                detail,
+                previous_details: vec![],
+                update_time: now,
+                update_by: update_by.to_string(),
+            };
+
+            info!(filename = ?log_path(&filename), %kind, "unsyncable file index created");
+
+            Ok(Some(IndexChange::Create(index_file)))
+        }
+
+        Some(index_file) if index_file.kind == kind && !index_file.detail.deleted => {
+            info!(filename = ?log_path(&filename), %kind, "unsyncable file index exists, ignore");
+
+            Ok(None)
+        }
+
+        Some(mut index_file) => {
+            let gen = index_file.detail.gen + 1;
+            let version = index_file.detail.version.incremented(update_by);
+            let mut old_detail = mem::replace(
+                &mut index_file.detail,
+                FileDetail {
+                    gen,
+                    version,
+                    ..detail
+                },
+            );
+            old_detail.block_chain.take();
+            index_file.kind = kind;
+            index_file.previous_details.push(old_detail);
+            index_file.update_time = now;
+            index_file.update_by = update_by.to_string();
+
+            info!(filename = ?log_path(&filename), %kind, "unsyncable file index changed");
+
+            Ok(Some(IndexChange::Update(index_file)))
+        }
+    }
+}
@@ -1,31 +1,114 @@
+use std::collections::VecDeque;
 use std::error::Error;
+use std::ffi::{OsStr, OsString};
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
-use event::Event;
-use futures_util::{Sink, Stream, TryStreamExt};
+use event::{DirRename, Event};
+use flume::{Receiver, Sender};
+use futures_util::{Sink, SinkExt, Stream, TryStreamExt};
+use rand::seq::IteratorRandom;
+use rand::Rng;
 use tap::TapFallible;
-use tracing::{error, info};
+use tokio::fs;
+use tokio::sync::{oneshot, watch};
+use tokio::time::Instant;
+use tokio::{select, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use crate::file_event_produce::WatchControl;
+use crate::clock::{Clock, SystemClock};
+use crate::events::{EventJournal, SyncEvents};
+use crate::ext::{log_path, set_log_privacy};
+use crate::file_event_produce::{coalesce_watch_events, WatchControl, WatchEvent};
+use crate::id_source::{IdSource, RandomIdSource};
+use crate::index::conflicts::{ConflictStore, NoopConflictStore};
+use crate::index::resume::{NoopResumeStore, ResumeStore};
 use crate::index::{Index, IndexFile, IndexGuard};
+use crate::journal::Journal;
+use crate::sync_control::anti_entropy::{diff_digests, DigestExchange, DigestReply, FileDigest};
+use crate::sync_control::conflict::{ConflictResolver, ConflictSide};
+use crate::sync_control::control::Control;
+use crate::sync_control::dry_run::SyncPlan;
+use crate::sync_control::error::SyncError;
+use crate::sync_control::file_locks::FileLocks;
+use crate::sync_control::options::{Fanout, SyncOptions};
+use crate::sync_control::oscillation::OscillationDetector;
+use crate::sync_control::power::PowerHook;
+use crate::sync_control::progress::{Progress, ProgressReporter};
+use crate::sync_control::replay::ReplayGuard;
 use crate::sync_control::rumors_event_handler::RumorsEventHandler;
+use crate::sync_control::status::{SyncPhase, SyncStatus};
 use crate::sync_control::sync_all_handler::SyncAllHandler;
+use crate::sync_control::timing::SyncTimings;
 use crate::sync_control::watch_event_handler::WatchEventHandler;
+use crate::transfer::integrity::{IntegrityTransfer, PeerHealths};
+use crate::transfer::rate_limit::BandwidthLimits;
+use crate::transfer::retry::RetryTransfer;
 use crate::transfer::DownloadTransfer;
 
+pub mod anti_entropy;
+pub mod conflict;
+pub mod control;
+pub mod dry_run;
+pub mod error;
 pub mod event;
-mod rumors_event_handler;
-mod sync_all_handler;
+pub mod event_source;
+mod file_locks;
+mod index_change;
+pub mod options;
+pub mod oscillation;
+pub mod power;
+pub mod progress;
+mod replay;
+pub mod rumors_event_handler;
+mod schedule;
+pub mod scrub;
+pub mod status;
+pub mod sync_all_handler;
+pub mod timing;
+pub mod versioning;
 mod watch_event_handler;
 
+/// max rumors in one anti entropy message
+const ANTI_ENTROPY_BATCH_SIZE: usize = 1024;
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct SendRumors {
     pub dir_id: Uuid,
     pub rumors: Vec<IndexFile>,
+    /// the dirs renamed by the sender, they are applied before the rumors
+    pub dir_renames: Vec<DirRename>,
+    /// don't send the rumors to the peer, usually it is the peer which sent the rumors
     pub except: Option<Uuid>,
+    /// only send the rumors to the peer, it is a reply which tells the peer its files are
+    /// outdated
+    pub to: Option<Uuid>,
+    /// how many peers the rumors are sent to, it is ignored when `to` is set
+    pub fanout: Fanout,
+}
+
+impl SendRumors {
+    /// select the peers which the rumors are sent to from the connected peers
+    pub fn select_peers<R: Rng>(&self, peers: &[Uuid], rng: &mut R) -> Vec<Uuid> {
+        if let Some(to) = self.to {
+            return peers.iter().copied().filter(|peer| *peer == to).collect();
+        }
+
+        let peers = peers
+            .iter()
+            .copied()
+            .filter(|peer| Some(*peer) != self.except);
+
+        match self.fanout {
+            Fanout::All => peers.collect(),
+            Fanout::Random(fanout) => peers.choose_multiple(rng, fanout),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -38,6 +121,38 @@ pub struct SyncController<I, St, Si, Dl, Wc> {
     rumor_sender: Si,
     download_transfer: Dl,
     watch_control: Wc,
+    replay_guard: ReplayGuard,
+    options: SyncOptions,
+    /// the non watch event received when merging watch events in the debounce window
+    pending_event: Option<Event>,
+    control_sender: Sender<Control>,
+    control_receiver: Receiver<Control>,
+    clock: Arc<dyn Clock>,
+    id_source: Arc<dyn IdSource>,
+    journal: EventJournal,
+    conflict_resolver: Option<Arc<dyn ConflictResolver>>,
+    digest_exchange: Option<Arc<dyn DigestExchange>>,
+    power_hook: Option<Arc<dyn PowerHook>>,
+    dry_run: Option<SyncPlan>,
+    resume_store: Arc<dyn ResumeStore>,
+    conflict_store: Arc<dyn ConflictStore>,
+    bandwidth_limits: Option<BandwidthLimits>,
+    peer_healths: PeerHealths,
+    progress: ProgressReporter,
+    status: watch::Sender<SyncStatus>,
+    shutdown: CancellationToken,
+    file_locks: FileLocks,
+    oscillation: OscillationDetector,
+    timings: SyncTimings,
+    next_anti_entropy: Option<Instant>,
+    next_sync_all: Option<Instant>,
+    /// the end of the turbo sync, the limits and caps are lifted before it
+    turbo_until: Option<Instant>,
+    next_low_power: Option<Instant>,
+    low_power: bool,
+    paused: bool,
+    /// the events received when the sync is paused, they are handled in order after resuming
+    paused_events: VecDeque<Event>,
 }
 
 impl<I, St, Si, Dl, Wc> SyncController<I, St, Si, Dl, Wc> {
@@ -51,6 +166,8 @@ impl<I, St, Si, Dl, Wc> SyncController<I, St, Si, Dl, Wc> {
         download_transfer: Dl,
         watch_control: Wc,
     ) -> Self {
+        let (control_sender, control_receiver) = flume::unbounded();
+
         Self {
             user_id,
             dir_id,
@@ -60,96 +177,1021 @@ impl<I, St, Si, Dl, Wc> SyncController<I, St, Si, Dl, Wc> {
             rumor_sender,
             download_transfer,
             watch_control,
+            replay_guard: Default::default(),
+            options: Default::default(),
+            pending_event: None,
+            control_sender,
+            control_receiver,
+            clock: Arc::new(SystemClock),
+            id_source: Arc::new(RandomIdSource),
+            journal: Default::default(),
+            conflict_resolver: None,
+            digest_exchange: None,
+            power_hook: None,
+            dry_run: None,
+            resume_store: Arc::new(NoopResumeStore),
+            conflict_store: Arc::new(NoopConflictStore),
+            bandwidth_limits: None,
+            peer_healths: Default::default(),
+            progress: Default::default(),
+            status: watch::channel(Default::default()).0,
+            shutdown: Default::default(),
+            file_locks: Default::default(),
+            oscillation: Default::default(),
+            timings: Default::default(),
+            next_anti_entropy: None,
+            next_sync_all: None,
+            turbo_until: None,
+            next_low_power: None,
+            low_power: false,
+            paused: false,
+            paused_events: Default::default(),
+        }
+    }
+
+    /// the sender to reconfigure the controller when it is running, the controls are applied
+    /// when waiting for the next event or before handling it
+    pub fn control_sender(&self) -> Sender<Control> {
+        self.control_sender.clone()
+    }
+
+    /// watch the status of the controller, the per file progress is reported by
+    /// [`SyncController::with_progress`]
+    pub fn status(&self) -> watch::Receiver<SyncStatus> {
+        self.status.subscribe()
+    }
+
+    /// cancel the token to stop the controller, the hashing file is abandoned, and the running
+    /// handler is stopped at the next file
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    fn apply_controls(&mut self) {
+        while let Ok(control) = self.control_receiver.try_recv() {
+            self.apply_control(control);
+        }
+
+        if self
+            .turbo_until
+            .is_some_and(|turbo_until| turbo_until <= Instant::now())
+        {
+            self.turbo_until = None;
+
+            info!("turbo sync ends");
+        }
+
+        if let Some(bandwidth_limits) = &self.bandwidth_limits {
+            let options = self.active_options();
+            bandwidth_limits
+                .upload
+                .set_limit(options.upload_bytes_per_sec);
+            bandwidth_limits
+                .download
+                .set_limit(options.download_bytes_per_sec);
         }
     }
+
+    fn apply_control(&mut self, control: Control) {
+        match control {
+            Control::Turbo(duration) => {
+                self.turbo_until = (!duration.is_zero()).then(|| Instant::now() + duration);
+
+                info!(?duration, "turbo sync starts");
+            }
+
+            Control::Pause => {
+                self.paused = true;
+                self.status.send_modify(|status| status.paused = true);
+
+                info!(dir = ?log_path(&self.sync_dir), "pause sync done");
+            }
+
+            Control::Resume => {
+                self.paused = false;
+                self.status.send_modify(|status| status.paused = false);
+
+                info!(
+                    dir = ?log_path(&self.sync_dir),
+                    queued_events = self.paused_events.len(),
+                    "resume sync done"
+                );
+            }
+
+            control => {
+                control.apply(&mut self.options);
+                set_log_privacy(self.options.log_privacy);
+            }
+        }
+    }
+
+    /// keep the event received when the sync is paused until it is resumed, the digests of the
+    /// peer are not replied, the peer exchanges them again at its next anti entropy
+    fn queue_paused_event(&mut self, event: Event) {
+        match event {
+            Event::Digests { sender_id, .. } => {
+                info!(%sender_id, "sync is paused, drop digests");
+            }
+
+            event => self.paused_events.push_back(event),
+        }
+    }
+
+    /// the options which the handlers use, the limits and caps are lifted during the turbo sync
+    fn active_options(&self) -> SyncOptions {
+        match self.turbo_until {
+            Some(turbo_until) if turbo_until > Instant::now() => self.options.turbo(),
+            _ => self.options.clone(),
+        }
+    }
+
+    pub fn with_options(mut self, options: SyncOptions) -> Self {
+        set_log_privacy(options.log_privacy);
+        self.options = options;
+
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+
+        self
+    }
+
+    pub fn with_id_source(mut self, id_source: Arc<dyn IdSource>) -> Self {
+        self.id_source = id_source;
+
+        self
+    }
+
+    /// record the applied sync operations into the journal
+    pub fn with_journal(mut self, journal: Arc<dyn Journal>) -> Self {
+        self.journal.journal = journal;
+
+        self
+    }
+
+    /// publish the synced files, the conflicts and the completed scans to the subscribers of the
+    /// events
+    pub fn with_events(mut self, events: SyncEvents) -> Self {
+        self.journal.events = events;
+
+        self
+    }
+
+    /// resolve the conflicts of the rumors by the resolver, otherwise by the conflict strategy of
+    /// the options
+    pub fn with_conflict_resolver(mut self, conflict_resolver: Arc<dyn ConflictResolver>) -> Self {
+        self.conflict_resolver = Some(conflict_resolver);
+
+        self
+    }
+
+    /// exchange the index digests with a random peer at the anti entropy interval instead of
+    /// sending the whole local index, the entries which the peer has newer are applied as its
+    /// rumors, and the files which the peer misses are announced to it
+    pub fn with_digest_exchange(mut self, digest_exchange: Arc<dyn DigestExchange>) -> Self {
+        self.digest_exchange = Some(digest_exchange);
+
+        self
+    }
+
+    /// notify the power hook when the controller enters and leaves the low power mode, so the
+    /// daemon releases the idle resources, such as the transfer connections to the idle peers
+    pub fn with_power_hook(mut self, power_hook: Arc<dyn PowerHook>) -> Self {
+        self.power_hook = Some(power_hook);
+
+        self
+    }
+
+    /// plan the rumors instead of applying them, so the changes which a sync would make to the
+    /// dir can be previewed, the planned actions are collected in the plan. The local changes are
+    /// still indexed and sent to the peers
+    pub fn with_dry_run(mut self, plan: SyncPlan) -> Self {
+        self.dry_run = Some(plan);
+
+        self
+    }
+
+    /// keep the interrupted downloads in the resume store, such as the [`SqliteIndex`] of the
+    /// dir, so they are resumed after a restart
+    ///
+    /// [`SqliteIndex`]: crate::index::sqlite_index::SqliteIndex
+    pub fn with_resume_store(mut self, resume_store: Arc<dyn ResumeStore>) -> Self {
+        self.resume_store = resume_store;
+
+        self
+    }
+
+    /// track the conflict files in the conflict store, such as the [`SqliteIndex`] of the dir, so
+    /// they can be listed and resolved by [`Event::ResolveConflict`]
+    ///
+    /// [`SqliteIndex`]: crate::index::sqlite_index::SqliteIndex
+    pub fn with_conflict_store(mut self, conflict_store: Arc<dyn ConflictStore>) -> Self {
+        self.conflict_store = conflict_store;
+
+        self
+    }
+
+    /// report the progress of the long running operations, such as hashing or downloading a large
+    /// file
+    pub fn with_progress(mut self, sender: Sender<Progress>) -> Self {
+        self.progress = ProgressReporter::new(sender);
+
+        self
+    }
+
+    /// detect the files oscillating between the peers, the detector can be shared with the caller
+    /// to watch the frozen files or unfreeze them. Resolving the conflict of a frozen file also
+    /// unfreezes it
+    pub fn with_oscillation_detector(mut self, oscillation: OscillationDetector) -> Self {
+        self.oscillation = oscillation;
+
+        self
+    }
+
+    /// time the stages of syncing the files, the recent timings are in the
+    /// [`SyncStatus::recent_timings`] and the journal
+    pub fn with_timings(mut self, timings: SyncTimings) -> Self {
+        self.timings = timings;
+
+        self
+    }
+
+    /// the limiters of the transfers, their limits follow the bandwidth limits of the options,
+    /// so the limits can be changed by [`Control::BandwidthLimit`] when the controller is running
+    pub fn with_bandwidth_limits(mut self, bandwidth_limits: BandwidthLimits) -> Self {
+        self.bandwidth_limits = Some(bandwidth_limits);
+
+        self
+    }
+
+    /// the blocks of a rumor are verified by the health of the rumor sender, keep a clone of the
+    /// healths to read the statistics and release the quarantined peers
+    pub fn with_peer_healths(mut self, peer_healths: PeerHealths) -> Self {
+        self.peer_healths = peer_healths;
+
+        self
+    }
 }
 
 impl<'a, I, St, Si, Dl, Wc, E1, E2> SyncController<I, St, Si, Dl, Wc>
 where
     I: Index,
+    I::Guard: Send,
     <I::Guard as IndexGuard>::Error: Send + Sync + 'static,
     E1: Error + Send + Sync + 'static,
     St: Stream<Item = Result<Event, E1>> + Unpin,
     Si: Sink<SendRumors> + Unpin,
     Si::Error: Error + Send + Sync + 'static,
-    Dl: DownloadTransfer + 'a,
+    Dl: DownloadTransfer + Sync + 'a,
     Dl::BlockStream<'a>: Unpin,
     Dl::Error: Into<io::Error>,
     Wc: WatchControl<Error = E2>,
     E2: Error + Send + Sync + 'static,
 {
-    pub async fn run(&mut self) -> Result<()> {
-        while let Some(event) = self
-            .event_stream
-            .try_next()
+    pub async fn run(&mut self) -> Result<(), SyncError> {
+        // the startup scan is handled as the first event, the watch is paused as usual
+        if self.options.sync_all_on_start {
+            info!(dir = ?log_path(&self.sync_dir), "sync all on start");
+
+            self.pending_event = Some(Event::SyncAll);
+        }
+
+        let shutdown = self.shutdown.clone();
+        loop {
+            let event = select! {
+                biased;
+
+                _ = shutdown.cancelled() => {
+                    info!(dir = ?log_path(&self.sync_dir), "controller is shut down, stop sync");
+
+                    return Ok(());
+                }
+
+                event = self.next_event() => match event? {
+                    None => break,
+                    Some(event) => event,
+                },
+            };
+
+            self.apply_controls();
+            if self.paused {
+                self.queue_paused_event(event);
+
+                continue;
+            }
+
+            let phase = SyncPhase::of(&event);
+            self.status.send_modify(|status| status.phase = phase);
+
+            let result = self.handle_event(event).await;
+            self.status.send_modify(|status| {
+                status.phase = SyncPhase::Idle;
+                match &result {
+                    Err(err) => status.last_error = Some(format!("{err:#}")),
+                    Ok(_) => status.handled_events += 1,
+                }
+                status.recent_timings = self.timings.recent();
+            });
+            result?;
+        }
+
+        info!(dir = ?log_path(&self.sync_dir),"no more dir file watch event, stop sync");
+
+        Ok(())
+    }
+
+    /// handle the event with the paused watch, so the changes made by the handler are not
+    /// watched
+    async fn handle_event(&mut self, event: Event) -> Result<()> {
+        self.pause_watch().await?;
+
+        info!("pause watch done");
+
+        let options = self.active_options();
+        match event {
+            Event::Watch(watch_events) => {
+                let handler = WatchEventHandler::new(
+                    &self.user_id,
+                    &self.dir_id,
+                    &self.sync_dir,
+                    &self.index,
+                    &mut self.rumor_sender,
+                )
+                .with_options(options.clone())
+                .with_clock(&*self.clock)
+                .with_journal(&self.journal)
+                .with_file_locks(self.file_locks.clone())
+                .with_progress(self.progress.clone())
+                .with_shutdown(self.shutdown.clone());
+
+                handler.handle_watch_events(watch_events).await?;
+
+                info!("handle watch events done");
+            }
+
+            Event::Rumors {
+                sender_id,
+                seq: Some(seq),
+                ..
+            } if !self.replay_guard.check_and_update(sender_id, seq) => {
+                warn!(%sender_id, seq, "drop duplicated or too old rumors message");
+            }
+
+            Event::Rumors {
+                sender_id,
+                remote_index: rumors,
+                dir_renames,
+                ..
+            } => {
+                // the retried failures aren't counted by the peer health, only the final one is
+                let download_transfer = IntegrityTransfer::new(
+                    RetryTransfer::new(&self.download_transfer, options.download_retry),
+                    self.peer_healths.health(sender_id),
+                );
+                let mut rumors_event_handler = RumorsEventHandler::new(
+                    self.user_id,
+                    self.dir_id,
+                    &self.sync_dir,
+                    &self.index,
+                    &download_transfer,
+                    &mut self.rumor_sender,
+                )
+                .with_options(options.clone())
+                .with_clock(&*self.clock)
+                .with_journal(&self.journal)
+                .with_resume_store(&*self.resume_store)
+                .with_conflict_store(&*self.conflict_store)
+                .with_file_locks(self.file_locks.clone())
+                .with_id_source(&*self.id_source)
+                .with_progress(self.progress.clone())
+                .with_timings(self.timings.clone())
+                .with_oscillation_detector(self.oscillation.clone())
+                .with_dir_renames(dir_renames);
+                if let Some(conflict_resolver) = &self.conflict_resolver {
+                    rumors_event_handler =
+                        rumors_event_handler.with_conflict_resolver(&**conflict_resolver);
+                }
+                if let Some(plan) = &self.dry_run {
+                    rumors_event_handler = rumors_event_handler.with_dry_run(plan.clone());
+                }
+
+                rumors_event_handler
+                    .handle_rumors_event(sender_id, rumors)
+                    .await?;
+
+                info!("handle rumors events done");
+            }
+
+            Event::SyncAll => {
+                // the periodic scan restarts after any scan
+                self.next_sync_all = None;
+
+                let sync_all_handler = SyncAllHandler::new(
+                    &self.user_id,
+                    &self.dir_id,
+                    &self.sync_dir,
+                    &self.index,
+                    &mut self.rumor_sender,
+                )
+                .with_options(options.clone())
+                .with_clock(&*self.clock)
+                .with_journal(&self.journal)
+                .with_file_locks(self.file_locks.clone())
+                .with_progress(self.progress.clone())
+                .with_shutdown(self.shutdown.clone());
+
+                let start = Instant::now();
+                match sync_all_handler.handle_sync_all_event().await {
+                    Err(SyncError::Cancelled) => {
+                        warn!("sync all is stopped by shutdown");
+                    }
+
+                    result => {
+                        result?;
+
+                        self.journal.events.scan_completed(
+                            self.dir_id,
+                            self.clock.now(),
+                            start.elapsed(),
+                        );
+                    }
+                }
+
+                info!("handle sync all event done");
+            }
+
+            Event::Repair(filenames) => {
+                let download_transfer =
+                    RetryTransfer::new(&self.download_transfer, options.download_retry);
+                let rumors_event_handler = RumorsEventHandler::new(
+                    self.user_id,
+                    self.dir_id,
+                    &self.sync_dir,
+                    &self.index,
+                    &download_transfer,
+                    &mut self.rumor_sender,
+                )
+                .with_options(options.clone())
+                .with_clock(&*self.clock)
+                .with_journal(&self.journal)
+                .with_resume_store(&*self.resume_store)
+                .with_file_locks(self.file_locks.clone())
+                .with_id_source(&*self.id_source)
+                .with_progress(self.progress.clone())
+                .with_timings(self.timings.clone());
+
+                rumors_event_handler.handle_repair_event(filenames).await?;
+
+                info!("handle repair event done");
+            }
+
+            Event::ResolveConflict {
+                conflict_filename,
+                side,
+            } => {
+                self.resolve_conflict_file(&conflict_filename, side).await?;
+
+                info!("handle resolve conflict event done");
+            }
+
+            Event::Announce { filenames, to } => {
+                self.announce_files(&filenames, to).await?;
+
+                info!("handle announce event done");
+            }
+
+            Event::Digests {
+                sender_id,
+                digests,
+                reply,
+            } => {
+                self.reply_digests(sender_id, &digests, reply).await?;
+
+                info!("handle digests event done");
+            }
+
+            Event::PeerConnected(peer_id) => {
+                // the entries pulled from the peer are handled as the next event
+                self.pending_event = self.catch_up_peer(peer_id).await?;
+
+                info!(%peer_id, "handle peer connected event done");
+            }
+        }
+
+        self.resume_watch().await?;
+
+        info!("resume watch done");
+
+        Ok(())
+    }
+
+    /// remove the conflict file, or move it over the synced file and handle it as a local
+    /// modification, so the new version gets the next gen and the replaced version is kept in the
+    /// previous details
+    async fn resolve_conflict_file(
+        &mut self,
+        conflict_filename: &OsStr,
+        side: ConflictSide,
+    ) -> Result<()> {
+        let conflict = match self
+            .conflict_store
+            .get_conflict(conflict_filename)
             .await
-            .tap_err(|err| error!(%err, "try next event failed"))?
+            .map_err(SyncError::index)?
         {
-            self.pause_watch().await?;
+            None => {
+                warn!(conflict_filename = ?log_path(conflict_filename), "conflict is not tracked, ignore");
+
+                return Ok(());
+            }
+
+            Some(conflict) => conflict,
+        };
+
+        let conflict_path = self.sync_dir.join(&conflict.conflict_filename);
+        let result = match side {
+            ConflictSide::Synced => fs::remove_file(&conflict_path).await,
+            ConflictSide::ConflictCopy => {
+                fs::rename(&conflict_path, self.sync_dir.join(&conflict.filename)).await
+            }
+        };
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                warn!(conflict_path = ?log_path(&conflict_path), "conflict file is removed, forget the conflict");
+            }
+
+            Err(err) => {
+                error!(%err, conflict_path = ?log_path(&conflict_path), "resolve conflict file failed");
+
+                return Err(err.into());
+            }
+
+            Ok(_) if side == ConflictSide::ConflictCopy => {
+                let handler = WatchEventHandler::new(
+                    &self.user_id,
+                    &self.dir_id,
+                    &self.sync_dir,
+                    &self.index,
+                    &mut self.rumor_sender,
+                )
+                .with_options(self.options.clone())
+                .with_clock(&*self.clock)
+                .with_journal(&self.journal)
+                .with_file_locks(self.file_locks.clone())
+                .with_progress(self.progress.clone())
+                .with_shutdown(self.shutdown.clone());
+
+                handler
+                    .handle_watch_events(vec![WatchEvent::Modify {
+                        name: conflict.filename.clone(),
+                    }])
+                    .await?;
+            }
+
+            Ok(_) => {}
+        }
+
+        self.conflict_store
+            .remove_conflict(&conflict.conflict_filename)
+            .await
+            .map_err(SyncError::index)?;
+        self.oscillation.unfreeze(&conflict.filename);
+
+        info!(conflict_filename = ?log_path(&conflict.conflict_filename), ?side, "resolve conflict done");
+
+        Ok(())
+    }
+
+    /// get the next event, when debounce is enabled, the watch events received in the debounce
+    /// window are merged into one event, and the events of the same path are coalesced
+    async fn next_event(&mut self) -> Result<Option<Event>> {
+        let event = match self.pending_event.take() {
+            Some(event) => Some(event),
+            None => self.next_stream_event().await?,
+        };
+
+        let mut watch_events = match event {
+            Some(Event::Watch(watch_events)) if !self.options.debounce.is_zero() => watch_events,
+            event => return Ok(event),
+        };
+
+        let deadline = Instant::now() + self.options.debounce;
+        while let Ok(event) = time::timeout_at(deadline, self.event_stream.try_next()).await {
+            match event
+                .tap_err(|err| error!(%err, "try next event failed"))
+                .map_err(SyncError::transfer)?
+            {
+                None => break,
+                Some(Event::Watch(more_watch_events)) => watch_events.extend(more_watch_events),
+                Some(event) => {
+                    self.pending_event = Some(event);
+
+                    break;
+                }
+            }
+        }
+
+        let watch_events = coalesce_watch_events(watch_events);
 
-            info!("pause watch done");
+        info!(
+            count = watch_events.len(),
+            "merge watch events in debounce window done"
+        );
+
+        Ok(Some(Event::Watch(watch_events)))
+    }
+
+    /// get the next event of the event stream, when the anti entropy interval elapses while
+    /// waiting, send the local index or exchange the digests before continuing waiting, the
+    /// entries pulled by the digests are returned as the rumors event, and when the sync all
+    /// interval elapses, return a sync all event, and when the turbo sync ends, restore the limits.
+    /// When no event comes in the low power after duration, enter the low power mode until the
+    /// next event. The controls are applied when they come, and the events queued when the sync
+    /// is paused are returned first after resuming
+    async fn next_stream_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            if !self.paused {
+                if let Some(event) = self.paused_events.pop_front() {
+                    return Ok(Some(event));
+                }
+            }
+
+            let anti_entropy_interval = self.periodic_interval(self.options.anti_entropy_interval);
+            let anti_entropy = next_deadline(&mut self.next_anti_entropy, anti_entropy_interval);
+            let sync_all_interval = self.periodic_interval(self.options.sync_all_interval);
+            let sync_all = next_deadline(&mut self.next_sync_all, sync_all_interval);
+            let low_power = match self.low_power {
+                true => None,
+                false => next_deadline(&mut self.next_low_power, self.options.low_power_after),
+            };
+
+            let deadline = anti_entropy
+                .into_iter()
+                .chain(sync_all)
+                .chain(self.turbo_until)
+                .chain(low_power)
+                .min();
+            let event_stream = &mut self.event_stream;
+            let control_receiver = &self.control_receiver;
+            let waited = select! {
+                // the controller keeps a control sender, the receiver is never disconnected
+                Ok(control) = control_receiver.recv_async() => Err(control),
+
+                event = async {
+                    match deadline {
+                        None => Ok(event_stream.try_next().await),
+                        Some(deadline) => time::timeout_at(deadline, event_stream.try_next()).await,
+                    }
+                } => Ok(event),
+            };
+            let event = match waited {
+                Ok(event) => event,
+                Err(control) => {
+                    self.apply_control(control);
+                    self.apply_controls();
+
+                    continue;
+                }
+            };
 
             match event {
-                Event::Watch(watch_events) => {
-                    let handler = WatchEventHandler::new(
-                        &self.user_id,
-                        &self.dir_id,
-                        &self.sync_dir,
-                        &self.index,
-                        &mut self.rumor_sender,
-                    );
-
-                    handler.handle_watch_events(watch_events).await?;
-
-                    info!("handle watch events done");
+                Ok(event) => {
+                    let event = event
+                        .tap_err(|err| error!(%err, "try next event failed"))
+                        .map_err(SyncError::transfer)?;
+                    if event.is_none() && !self.paused_events.is_empty() {
+                        warn!(
+                            queued_events = self.paused_events.len(),
+                            "event stream ends when the sync is paused, drop the queued events"
+                        );
+                    }
+                    self.wake().await;
+
+                    return Ok(event);
                 }
 
-                Event::Rumors {
-                    sender_id,
-                    remote_index: rumors,
-                } => {
-                    let rumors_event_handler = RumorsEventHandler::new(
-                        self.user_id,
-                        self.dir_id,
-                        &self.sync_dir,
-                        &self.index,
-                        &self.download_transfer,
-                        &mut self.rumor_sender,
-                    );
-
-                    rumors_event_handler
-                        .handle_rumors_event(sender_id, rumors)
-                        .await?;
-
-                    info!("handle rumors events done");
+                // restore the limits when the turbo sync ends, even if no event comes
+                Err(_) if self.turbo_until == deadline => self.apply_controls(),
+
+                Err(_) if low_power == deadline => self.enter_low_power().await,
+
+                Err(_) if sync_all == deadline => {
+                    info!(dir = ?log_path(&self.sync_dir), "sync all interval elapses");
+
+                    return Ok(Some(Event::SyncAll));
+                }
+
+                Err(_) => {
+                    self.next_anti_entropy = None;
+
+                    self.status
+                        .send_modify(|status| status.phase = SyncPhase::AntiEntropy);
+                    let result = match self.digest_exchange.clone() {
+                        None => self.send_anti_entropy(None).await.map(|_| None),
+                        Some(digest_exchange) => self.exchange_digests(&*digest_exchange).await,
+                    };
+                    self.status.send_modify(|status| {
+                        status.phase = SyncPhase::Idle;
+                        if let Err(err) = &result {
+                            status.last_error = Some(format!("{err:#}"));
+                        }
+                    });
+                    if let Some(event) = result? {
+                        return Ok(Some(event));
+                    }
                 }
+            }
+        }
+    }
+
+    /// the interval of the periodic task, it is lengthened in the low power mode, and the
+    /// periodic tasks are stopped when the sync is paused
+    fn periodic_interval(&self, interval: Duration) -> Duration {
+        if self.paused {
+            return Duration::ZERO;
+        }
+
+        match self.low_power {
+            true => interval.saturating_mul(self.options.low_power_interval_factor.max(1)),
+            false => interval,
+        }
+    }
+
+    /// no event comes in the low power after duration, release the idle resources by the power
+    /// hook and lengthen the periodic tasks
+    async fn enter_low_power(&mut self) {
+        self.low_power = true;
+        self.next_low_power = None;
+        self.next_anti_entropy = None;
+        self.next_sync_all = None;
+
+        if let Some(power_hook) = &self.power_hook {
+            power_hook.enter_low_power().await;
+        }
+        self.status.send_modify(|status| status.low_power = true);
+
+        info!(dir = ?log_path(&self.sync_dir), "enter low power mode done");
+    }
+
+    /// an event comes, restart the low power timer, and leave the low power mode
+    async fn wake(&mut self) {
+        self.next_low_power = None;
+        if !self.low_power {
+            return;
+        }
 
-                Event::SyncAll => {
-                    let sync_all_handler = SyncAllHandler::new(
-                        &self.user_id,
-                        &self.dir_id,
-                        &self.sync_dir,
-                        &self.index,
-                        &mut self.rumor_sender,
-                    );
+        self.low_power = false;
+        self.next_anti_entropy = None;
+        self.next_sync_all = None;
 
-                    sync_all_handler.handle_sync_all_event().await?;
+        if let Some(power_hook) = &self.power_hook {
+            power_hook.leave_low_power().await;
+        }
+        self.status.send_modify(|status| status.low_power = false);
+
+        info!(dir = ?log_path(&self.sync_dir), "leave low power mode done");
+    }
 
-                    info!("handle sync all event done");
+    /// send the current index entries of the files, the files which are not in the index are
+    /// ignored
+    async fn announce_files(&mut self, filenames: &[OsString], to: Option<Uuid>) -> Result<()> {
+        let mut rumors = Vec::with_capacity(filenames.len());
+        for filename in filenames {
+            match self
+                .index
+                .get_file(filename)
+                .await
+                .tap_err(
+                    |err| error!(%err, filename = ?log_path(filename), "get index file failed"),
+                )
+                .map_err(SyncError::index)?
+            {
+                None => {
+                    warn!(filename = ?log_path(filename), "file is not in the index, ignore announce");
                 }
+
+                Some(index_file) => rumors.push(index_file),
+            }
+        }
+
+        if rumors.is_empty() {
+            info!("no file to announce");
+
+            return Ok(());
+        }
+
+        let files = rumors.len();
+        let send_rumors = SendRumors {
+            dir_id: self.dir_id,
+            rumors,
+            dir_renames: vec![],
+            except: None,
+            to,
+            fanout: self.options.fanout,
+        };
+
+        self.rumor_sender
+            .send(send_rumors)
+            .await
+            .tap_err(|err| error!(%err, "send announce rumors failed"))
+            .map_err(SyncError::transfer)?;
+
+        info!(files, ?to, "announce files done");
+
+        Ok(())
+    }
+
+    async fn list_index_files(&self) -> Result<Vec<IndexFile>> {
+        let mut index_guard = self
+            .index
+            .begin()
+            .await
+            .tap_err(|err| error!(%err, "create index guard failed"))
+            .map_err(SyncError::index)?;
+        let index_files = index_guard
+            .list_all_files()
+            .await
+            .tap_err(|err| error!(%err, "list all index files failed"))
+            .map_err(SyncError::index)?
+            .try_collect::<Vec<_>>()
+            .await
+            .tap_err(|err| error!(%err, "collect all index files failed"))
+            .map_err(SyncError::index)?;
+
+        Ok(index_files)
+    }
+
+    /// send the local index to the peer, or to the peers selected by the fanout when it is None
+    async fn send_anti_entropy(&mut self, to: Option<Uuid>) -> Result<()> {
+        let index_files = self.list_index_files().await?;
+
+        for rumors in index_files.chunks(ANTI_ENTROPY_BATCH_SIZE) {
+            let send_rumors = SendRumors {
+                dir_id: self.dir_id,
+                rumors: rumors.to_vec(),
+                dir_renames: vec![],
+                except: None,
+                to,
+                fanout: self.options.fanout,
+            };
+
+            self.rumor_sender
+                .send(send_rumors)
+                .await
+                .tap_err(|err| error!(%err, "send anti entropy rumors failed"))
+                .map_err(SyncError::transfer)?;
+        }
+
+        info!(
+            files = index_files.len(),
+            ?to,
+            "send anti entropy rumors done"
+        );
+
+        Ok(())
+    }
+
+    /// exchange the digests with a random peer, the entries which the peer has newer are returned
+    /// as its pulled rumors, and the files which the peer misses are announced to it. A failed
+    /// peer is logged and skipped, another peer may be selected next time
+    async fn exchange_digests(
+        &mut self,
+        digest_exchange: &dyn DigestExchange,
+    ) -> Result<Option<Event>> {
+        let peer_id = match digest_exchange
+            .peer_ids()
+            .into_iter()
+            .choose(&mut rand::thread_rng())
+        {
+            None => {
+                info!("no peer to exchange digests");
+
+                return Ok(None);
+            }
+
+            Some(peer_id) => peer_id,
+        };
+
+        self.exchange_digests_with(digest_exchange, peer_id).await
+    }
+
+    /// catch up the reconnected peer, exchange the digests with it when the digest exchange is
+    /// set, otherwise send the local index to it only
+    async fn catch_up_peer(&mut self, peer_id: Uuid) -> Result<Option<Event>> {
+        match self.digest_exchange.clone() {
+            Some(digest_exchange) => self.exchange_digests_with(&*digest_exchange, peer_id).await,
+
+            None => {
+                self.send_anti_entropy(Some(peer_id)).await?;
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// exchange the digests with the peer, a failed peer is logged and skipped
+    async fn exchange_digests_with(
+        &mut self,
+        digest_exchange: &dyn DigestExchange,
+        peer_id: Uuid,
+    ) -> Result<Option<Event>> {
+        let digests = self
+            .list_index_files()
+            .await?
+            .iter()
+            .map(FileDigest::from)
+            .collect::<Vec<_>>();
+        let files = digests.len();
+
+        let reply = match digest_exchange
+            .exchange(peer_id, self.dir_id, digests)
+            .await
+        {
+            Err(err) => {
+                warn!(%err, %peer_id, "exchange digests with peer failed");
+
+                return Ok(None);
             }
 
-            self.resume_watch().await?;
+            Ok(reply) => reply,
+        };
+
+        info!(
+            %peer_id,
+            files,
+            newer = reply.newer.len(),
+            missing = reply.missing.len(),
+            "exchange digests done"
+        );
 
-            info!("resume watch done");
+        for filenames in reply.missing.chunks(ANTI_ENTROPY_BATCH_SIZE) {
+            self.announce_files(filenames, Some(peer_id)).await?;
         }
 
-        info!(dir = ?self.sync_dir,"no more dir file watch event, stop sync");
+        if reply.newer.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Event::Rumors {
+            sender_id: peer_id,
+            seq: None,
+            remote_index: reply.newer,
+            dir_renames: vec![],
+        }))
+    }
+
+    /// compare the digests of the peer with the local index, reply the local entries which the
+    /// peer misses or has older, and the files which the peer has newer. At most
+    /// [`ANTI_ENTROPY_BATCH_SIZE`] entries are replied, the rest are pulled by the next exchanges
+    async fn reply_digests(
+        &mut self,
+        sender_id: Uuid,
+        digests: &[FileDigest],
+        reply: oneshot::Sender<DigestReply>,
+    ) -> Result<()> {
+        let index_files = self.list_index_files().await?;
+        let local_digests = index_files.iter().map(FileDigest::from).collect::<Vec<_>>();
+        let diff = diff_digests(&local_digests, digests);
+
+        let newer = index_files
+            .into_iter()
+            .filter(|index_file| diff.local_newer.binary_search(&index_file.filename).is_ok())
+            .take(ANTI_ENTROPY_BATCH_SIZE)
+            .collect::<Vec<_>>();
+        let newer_files = newer.len();
+        let missing_files = diff.remote_newer.len();
+
+        if reply
+            .send(DigestReply {
+                newer,
+                missing: diff.remote_newer,
+            })
+            .is_err()
+        {
+            warn!(%sender_id, "digests sender is gone, drop the reply");
+
+            return Ok(());
+        }
+
+        info!(%sender_id, newer_files, missing_files, "reply digests done");
 
         Ok(())
     }
 }
 
+/// get the deadline of the periodic task, a zero interval disables the task
+fn next_deadline(next: &mut Option<Instant>, interval: Duration) -> Option<Instant> {
+    if interval.is_zero() {
+        *next = None;
+
+        return None;
+    }
+
+    Some(*next.get_or_insert_with(|| Instant::now() + interval))
+}
+
 impl<I, St, Si, Dl, Wc, E> SyncController<I, St, Si, Dl, Wc>
 where
     Wc: WatchControl<Error = E>,
@@ -169,3 +1211,532 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::env;
+    use std::time::SystemTime;
+
+    use futures_util::{stream, StreamExt};
+    use mockall::predicate::{always, eq};
+    use mockall::Sequence;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::file_event_produce::NoWatch;
+    use crate::index::conflicts::{ConflictEntry, MockConflictStore};
+    use crate::index::test_util::index_file;
+    use crate::index::{FileDetail, FileKind, MockIndex, MockIndexGuard};
+    use crate::sync_control::anti_entropy::MockDigestExchange;
+    use crate::sync_control::power::MockPowerHook;
+    use crate::transfer::MockDownloadTransfer;
+
+    fn controller<St>(
+        dir: &TempDir,
+        index: MockIndex,
+        event_stream: St,
+        options: SyncOptions,
+    ) -> SyncController<
+        MockIndex,
+        St,
+        impl Sink<SendRumors, Error = flume::SendError<SendRumors>> + Unpin,
+        MockDownloadTransfer,
+        NoWatch,
+    > {
+        let (sender, _) = flume::unbounded();
+
+        SyncController::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            dir.path().to_path_buf(),
+            index,
+            event_stream,
+            sender.into_sink(),
+            MockDownloadTransfer::new(),
+            NoWatch,
+        )
+        .with_options(options)
+    }
+
+    #[tokio::test]
+    async fn sync_all_on_start() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let mut index = MockIndex::new();
+        index
+            .expect_list_all_files()
+            .times(1)
+            .returning(|| Ok(Box::pin(stream::iter([]))));
+
+        let event_stream = stream::empty::<Result<Event, Infallible>>();
+        let mut started = controller(&dir, index, event_stream, Default::default());
+        let status = started.status();
+        started.run().await.unwrap();
+        assert_eq!(
+            *status.borrow(),
+            SyncStatus {
+                phase: SyncPhase::Idle,
+                handled_events: 1,
+                last_error: None,
+                recent_timings: vec![],
+                low_power: false,
+                paused: false,
+            }
+        );
+
+        // the scan is disabled, the index is never touched
+        let event_stream = stream::empty::<Result<Event, Infallible>>();
+        let options = SyncOptions {
+            sync_all_on_start: false,
+            ..Default::default()
+        };
+        controller(&dir, MockIndex::new(), event_stream, options)
+            .run()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_all_interval() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let mut index = MockIndex::new();
+        index
+            .expect_list_all_files()
+            .times(2..)
+            .returning(|| Ok(Box::pin(stream::iter([]))));
+
+        let event_stream = stream::pending::<Result<Event, Infallible>>();
+        let options = SyncOptions {
+            sync_all_on_start: false,
+            sync_all_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut controller = controller(&dir, index, event_stream, options);
+
+        time::timeout(Duration::from_millis(180), controller.run())
+            .await
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn turbo_sync() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let event_stream = stream::pending::<Result<Event, Infallible>>();
+        let options = SyncOptions {
+            sync_all_on_start: false,
+            upload_bytes_per_sec: 100,
+            download_bytes_per_sec: 200,
+            ..Default::default()
+        };
+        let bandwidth_limits = BandwidthLimits::new(100, 200);
+        let mut controller = controller(&dir, MockIndex::new(), event_stream, options.clone())
+            .with_bandwidth_limits(bandwidth_limits.clone());
+
+        controller
+            .control_sender()
+            .send(Control::Turbo(Duration::from_millis(50)))
+            .unwrap();
+        controller.apply_controls();
+        assert_eq!(bandwidth_limits.upload.limit(), 0);
+        assert_eq!(bandwidth_limits.download.limit(), 0);
+        assert_eq!(controller.active_options().rumor_concurrency, 0);
+        assert_eq!(controller.active_options().write_concurrency, 0);
+
+        // the limits are restored when the turbo sync ends without any event
+        time::timeout(Duration::from_millis(150), controller.run())
+            .await
+            .unwrap_err();
+        assert_eq!(bandwidth_limits.upload.limit(), 100);
+        assert_eq!(bandwidth_limits.download.limit(), 200);
+        assert_eq!(controller.active_options(), options);
+    }
+
+    #[tokio::test]
+    async fn low_power() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let (event_sender, event_receiver) = flume::unbounded();
+        let event_stream = Box::pin(event_receiver.into_stream().map(Ok::<_, Infallible>));
+        let options = SyncOptions {
+            sync_all_on_start: false,
+            low_power_after: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let mut seq = Sequence::new();
+        let mut power_hook = MockPowerHook::new();
+        power_hook
+            .expect_enter_low_power()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_const(());
+        power_hook
+            .expect_leave_low_power()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_const(());
+
+        let mut controller = controller(&dir, MockIndex::new(), event_stream, options)
+            .with_power_hook(Arc::new(power_hook));
+        let status = controller.status();
+
+        // the event wakes the controller, then the event stream ends
+        let sender = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(150)).await;
+            assert!(status.borrow().low_power);
+
+            event_sender
+                .send(Event::Announce {
+                    filenames: vec![],
+                    to: None,
+                })
+                .unwrap();
+        });
+
+        controller.run().await.unwrap();
+        sender.await.unwrap();
+        assert!(!controller.status().borrow().low_power);
+        assert_eq!(controller.status().borrow().handled_events, 1);
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let (event_sender, event_receiver) = flume::unbounded();
+        let event_stream = Box::pin(event_receiver.into_stream().map(Ok::<_, Infallible>));
+        let options = SyncOptions {
+            sync_all_on_start: false,
+            ..Default::default()
+        };
+
+        let mut controller = controller(&dir, MockIndex::new(), event_stream, options);
+        let control_sender = controller.control_sender();
+        let status = controller.status();
+        let announce = || Event::Announce {
+            filenames: vec![],
+            to: None,
+        };
+
+        let (result, _) = tokio::join!(controller.run(), async move {
+            control_sender.send(Control::Pause).unwrap();
+            event_sender.send(announce()).unwrap();
+            event_sender.send(announce()).unwrap();
+            time::sleep(Duration::from_millis(50)).await;
+            assert!(status.borrow().paused);
+            assert_eq!(status.borrow().handled_events, 0);
+
+            // the queued events are handled without a new event
+            control_sender.send(Control::Resume).unwrap();
+            time::sleep(Duration::from_millis(50)).await;
+            assert!(!status.borrow().paused);
+            assert_eq!(status.borrow().handled_events, 2);
+        });
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn resolve_conflict_keep_synced() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let conflict_filename = OsStr::new("test.txt.2024-01-01-00-00-00.conflict");
+        fs::write(dir.path().join(conflict_filename), b"local")
+            .await
+            .unwrap();
+
+        let mut conflict_store = MockConflictStore::new();
+        conflict_store
+            .expect_get_conflict()
+            .returning(|conflict_filename| {
+                Ok(Some(ConflictEntry {
+                    conflict_filename: conflict_filename.to_owned(),
+                    filename: "test.txt".into(),
+                    hash_sum: [0; 32],
+                    time: SystemTime::UNIX_EPOCH,
+                }))
+            });
+        conflict_store
+            .expect_remove_conflict()
+            .withf(move |filename| filename == conflict_filename)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let event_stream = stream::iter([Ok::<_, Infallible>(Event::ResolveConflict {
+            conflict_filename: conflict_filename.to_owned(),
+            side: ConflictSide::Synced,
+        })]);
+        let options = SyncOptions {
+            sync_all_on_start: false,
+            ..Default::default()
+        };
+        controller(&dir, MockIndex::new(), event_stream, options)
+            .with_conflict_store(Arc::new(conflict_store))
+            .run()
+            .await
+            .unwrap();
+
+        assert!(!dir.path().join(conflict_filename).exists());
+    }
+
+    #[tokio::test]
+    async fn announce_file() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let index_file = IndexFile {
+            filename: "test.txt".into(),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 2,
+                hash_sum: [1; 32],
+                block_chain: None,
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH,
+            update_by: "test".to_string(),
+        };
+        let mut index = MockIndex::new();
+        {
+            let index_file = index_file.clone();
+            index.expect_get_file().returning(move |filename| {
+                Ok((filename == "test.txt").then(|| index_file.clone()))
+            });
+        }
+
+        let peer_id = Uuid::new_v4();
+        let event_stream = stream::iter([Ok::<_, Infallible>(Event::Announce {
+            filenames: vec!["test.txt".into(), "not_exist.txt".into()],
+            to: Some(peer_id),
+        })]);
+        let (sender, receiver) = flume::unbounded();
+        SyncController::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            dir.path().to_path_buf(),
+            index,
+            event_stream,
+            sender.into_sink(),
+            MockDownloadTransfer::new(),
+            NoWatch,
+        )
+        .with_options(SyncOptions {
+            sync_all_on_start: false,
+            ..Default::default()
+        })
+        .run()
+        .await
+        .unwrap();
+
+        // the entry is sent as it is, the gen is not bumped
+        let send_rumors = receiver.try_recv().unwrap();
+        assert_eq!(send_rumors.rumors, [index_file]);
+        assert_eq!(send_rumors.to, Some(peer_id));
+        assert!(receiver.is_empty());
+    }
+
+    fn digest_index(index_files: Vec<IndexFile>) -> MockIndex {
+        let mut index = MockIndex::new();
+        {
+            let index_files = index_files.clone();
+            index.expect_begin().returning(move || {
+                let index_files = index_files.clone();
+                let mut index_guard = MockIndexGuard::new();
+                index_guard.expect_list_all_files().returning(move || {
+                    Ok(Box::pin(stream::iter(
+                        index_files.clone().into_iter().map(Ok),
+                    )))
+                });
+
+                Ok(index_guard)
+            });
+        }
+        index.expect_get_file().returning(move |filename| {
+            Ok(index_files
+                .iter()
+                .find(|index_file| index_file.filename == filename)
+                .cloned())
+        });
+
+        index
+    }
+
+    #[tokio::test]
+    async fn reply_digests() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let index = digest_index(vec![
+            index_file("local_newer.txt", 2),
+            index_file("same.txt", 1),
+        ]);
+
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let event_stream = stream::iter([Ok::<_, Infallible>(Event::Digests {
+            sender_id: Uuid::new_v4(),
+            digests: vec![
+                FileDigest::from(&index_file("local_newer.txt", 1)),
+                FileDigest::from(&index_file("remote_only.txt", 1)),
+                FileDigest::from(&index_file("same.txt", 1)),
+            ],
+            reply: reply_sender,
+        })]);
+        let options = SyncOptions {
+            sync_all_on_start: false,
+            ..Default::default()
+        };
+        controller(&dir, index, event_stream, options)
+            .run()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            reply_receiver.await.unwrap(),
+            DigestReply {
+                newer: vec![index_file("local_newer.txt", 2)],
+                missing: vec!["remote_only.txt".into()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn exchange_digests() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let dir_id = Uuid::new_v4();
+        let peer_id = Uuid::new_v4();
+        let local_files = vec![index_file("test.txt", 1)];
+        let index = digest_index(local_files.clone());
+
+        let mut digest_exchange = MockDigestExchange::new();
+        digest_exchange
+            .expect_peer_ids()
+            .returning(move || vec![peer_id]);
+        digest_exchange
+            .expect_exchange()
+            .with(
+                eq(peer_id),
+                eq(dir_id),
+                eq(vec![FileDigest::from(&local_files[0])]),
+            )
+            .returning(|_, _, _| {
+                Ok(DigestReply {
+                    newer: vec![index_file("remote.txt", 1)],
+                    missing: vec!["test.txt".into()],
+                })
+            });
+
+        let (sender, receiver) = flume::unbounded();
+        let mut controller = SyncController::new(
+            Uuid::new_v4(),
+            dir_id,
+            dir.path().to_path_buf(),
+            index,
+            stream::pending::<Result<Event, Infallible>>(),
+            sender.into_sink(),
+            MockDownloadTransfer::new(),
+            NoWatch,
+        );
+
+        // the newer entries of the peer are pulled as its rumors
+        let event = controller
+            .exchange_digests(&digest_exchange)
+            .await
+            .unwrap()
+            .unwrap();
+        let Event::Rumors {
+            sender_id,
+            seq,
+            remote_index,
+            dir_renames,
+        } = event
+        else {
+            panic!("not rumors event");
+        };
+        assert_eq!(sender_id, peer_id);
+        assert_eq!(seq, None);
+        assert_eq!(remote_index, [index_file("remote.txt", 1)]);
+        assert!(dir_renames.is_empty());
+
+        // the missing files are announced to the peer
+        let send_rumors = receiver.try_recv().unwrap();
+        assert_eq!(send_rumors.rumors, local_files);
+        assert_eq!(send_rumors.to, Some(peer_id));
+        assert!(receiver.is_empty());
+    }
+
+    #[tokio::test]
+    async fn catch_up_reconnected_peer() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let dir_id = Uuid::new_v4();
+        let peer_id = Uuid::new_v4();
+        let local_files = vec![index_file("test.txt", 1)];
+
+        // only the reconnected peer is exchanged with
+        let mut digest_exchange = MockDigestExchange::new();
+        digest_exchange
+            .expect_exchange()
+            .with(eq(peer_id), eq(dir_id), always())
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(DigestReply {
+                    newer: vec![],
+                    missing: vec!["test.txt".into()],
+                })
+            });
+
+        let options = SyncOptions {
+            sync_all_on_start: false,
+            ..Default::default()
+        };
+        for digest_exchange in [Some(Arc::new(digest_exchange)), None] {
+            let (sender, receiver) = flume::unbounded();
+            let mut controller = SyncController::new(
+                Uuid::new_v4(),
+                dir_id,
+                dir.path().to_path_buf(),
+                digest_index(local_files.clone()),
+                stream::iter([Ok::<_, Infallible>(Event::PeerConnected(peer_id))]),
+                sender.into_sink(),
+                MockDownloadTransfer::new(),
+                NoWatch,
+            )
+            .with_options(options.clone());
+            if let Some(digest_exchange) = digest_exchange {
+                controller = controller.with_digest_exchange(digest_exchange);
+            }
+
+            controller.run().await.unwrap();
+
+            // the missing files are announced, or the whole index is sent, to the peer only
+            let send_rumors = receiver.try_recv().unwrap();
+            assert_eq!(send_rumors.rumors, local_files);
+            assert_eq!(send_rumors.to, Some(peer_id));
+            assert!(receiver.is_empty());
+        }
+    }
+
+    #[test]
+    fn select_peers() {
+        let peers = (0..10).map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut send_rumors = SendRumors {
+            dir_id: Uuid::new_v4(),
+            rumors: vec![],
+            dir_renames: vec![],
+            except: Some(peers[0]),
+            to: None,
+            fanout: Fanout::All,
+        };
+
+        assert_eq!(send_rumors.select_peers(&peers, &mut rng), peers[1..]);
+
+        send_rumors.fanout = Fanout::Random(3);
+        let selected = send_rumors.select_peers(&peers, &mut rng);
+        assert_eq!(selected.len(), 3);
+        assert!(selected.iter().all(|peer| peers[1..].contains(peer)));
+
+        send_rumors.fanout = Fanout::Random(20);
+        assert_eq!(send_rumors.select_peers(&peers, &mut rng).len(), 9);
+
+        send_rumors.to = Some(peers[5]);
+        assert_eq!(send_rumors.select_peers(&peers, &mut rng), [peers[5]]);
+    }
+}
@@ -0,0 +1,292 @@
+use std::time::Duration;
+
+use crate::ext::{LogPrivacy, SymlinkPolicy, WalkLimits};
+use crate::index::BlockSizePolicy;
+use crate::sync_control::versioning::VersioningPolicy;
+use crate::transfer::retry::RetryPolicy;
+
+/// default max in flight block writes when syncing a file
+pub const DEFAULT_WRITE_CONCURRENCY: usize = 16;
+
+/// default max rumors handled concurrently
+pub const DEFAULT_RUMOR_CONCURRENCY: usize = 8;
+
+/// default queued files prefetched ahead
+pub const DEFAULT_PREFETCH_FILES: usize = 4;
+
+/// default max bytes of the prefetched blocks, 64MiB
+pub const DEFAULT_PREFETCH_BYTES: u64 = 64 * 1024 * 1024;
+
+/// default multiplier of the periodic task intervals in the low power mode
+pub const DEFAULT_LOW_POWER_INTERVAL_FACTOR: u32 = 4;
+
+/// how to handle the conflict when local and remote both change the file, it is the built-in
+/// [`ConflictResolver`](super::conflict::ConflictResolver) when no resolver is set
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum ConflictStrategy {
+    /// copy the local file as a `.conflict` file then apply the remote file
+    #[default]
+    KeepBoth,
+
+    /// apply the remote file directly, the local change is lost
+    PreferRemote,
+
+    /// keep the local file as a newer version, the remote change is lost
+    PreferLocal,
+
+    /// keep the file which is updated later, the other change is lost
+    KeepNewest,
+}
+
+/// how to handle the dirs which become empty after files are deleted by sync
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum EmptyDirPolicy {
+    /// keep the empty dirs
+    #[default]
+    Keep,
+
+    /// remove the parent dirs of the deleted file which become empty, the dirs which are not
+    /// emptied by sync, or still contain anything such as unsynced files, are never touched
+    Prune,
+}
+
+/// how to handle the special files, such as fifos, sockets and devices, they can't be hashed or
+/// synced
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum SpecialFilePolicy {
+    /// record the special file in the index without content, so peers know it is unsyncable
+    #[default]
+    Skip,
+
+    /// fail the event handling, the error is returned after the other watch events of the
+    /// batch are handled, and a scan stops at the special file
+    Error,
+}
+
+/// how carefully the synced file is verified before it replaces the target file
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum ParanoiaLevel {
+    /// only the downloaded blocks are verified by their hash sums
+    #[default]
+    Block,
+
+    /// also verify the whole assembled file by the file hash sum, it costs reading the file again
+    /// but catches the wrongly assembled file
+    File,
+}
+
+/// how many peers a new rumor is forwarded to
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Fanout {
+    /// forward to all peers except the sender of the rumor
+    #[default]
+    All,
+
+    /// forward to the number of randomly selected peers, the bandwidth stays sublinear in a large
+    /// mesh, the anti entropy covers the peers which are not selected
+    Random(usize),
+}
+
+/// the order of applying a batch of rumors, the rumors of the same file are still ordered by
+/// their versions. The watch events always keep their order, because a rename depends on the
+/// previous events
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum ApplyOrder {
+    /// the arrival order, grouped by the parent dir, so the files of a dir are applied and
+    /// prefetched together
+    #[default]
+    Arrival,
+
+    /// the smallest files first, so most files converge quickly
+    SmallestFirst,
+
+    Alphabetical,
+
+    /// the latest updated files first
+    NewestFirst,
+}
+
+/// how the synced files are created in the sync dir, it is applied after the synced file is moved
+/// to the target file
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct FileCreation {
+    /// the permission bits cleared from the mode of the synced files, like the umask of a process
+    pub umask: u32,
+
+    /// the mode of the synced files which have no metadata, none keeps the mode of the temp file
+    pub default_mode: Option<u32>,
+
+    /// set the immutable flag of the synced files, such as for the receive-only archival dirs,
+    /// the flag is cleared before the file is replaced or deleted by sync
+    pub immutable: bool,
+
+    /// set the no dump flag of the synced files, so the backups by `dump(8)` skip them
+    pub no_dump: bool,
+}
+
+/// the tunable knobs of the [`SyncController`](super::SyncController), new knobs should be added
+/// here with a default value, so the callers don't need to change
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SyncOptions {
+    /// max in flight block writes when syncing a file, 0 means no limit
+    pub write_concurrency: usize,
+
+    /// max rumors of different files handled concurrently, the rumors of the same file are still
+    /// handled in order, 0 means no limit
+    pub rumor_concurrency: usize,
+
+    pub apply_order: ApplyOrder,
+
+    /// how many queued new files are downloaded ahead of the handled rumors, 0 means disabled
+    pub prefetch_files: usize,
+
+    /// max bytes of the prefetched blocks kept in memory
+    pub prefetch_bytes: u64,
+
+    pub conflict_strategy: ConflictStrategy,
+
+    /// fsync the synced file before renaming it to the target file, so a crash can't leave a
+    /// half written file with a newer index
+    pub fsync: bool,
+
+    /// after receiving a watch event, wait the window and merge the following watch events, so
+    /// a burst of writes to one file is handled once
+    pub debounce: Duration,
+
+    pub empty_dir_policy: EmptyDirPolicy,
+
+    pub special_file_policy: SpecialFilePolicy,
+
+    /// follow the symlinks in the sync dir, or store the symlinks themselves without content
+    pub symlink_policy: SymlinkPolicy,
+
+    /// how the paths are written into the logs, the mode is process wide, it is set when the
+    /// options are given to the controller
+    pub log_privacy: LogPrivacy,
+
+    /// the depth and files limits of scanning the sync dir
+    pub walk_limits: WalkLimits,
+
+    pub paranoia_level: ParanoiaLevel,
+
+    /// the block size of the files hashed in the sync dir, the received files keep the block
+    /// size of the remote block chain
+    pub block_size_policy: BlockSizePolicy,
+
+    pub fanout: Fanout,
+
+    /// send the whole local index to the peers selected by the fanout periodically, or exchange
+    /// the index digests with a random peer when the controller has a digest exchange, so the
+    /// peers which missed the rumors catch up, zero means disabled
+    pub anti_entropy_interval: Duration,
+
+    /// scan the whole sync dir before handling the first event, so the changes made when the
+    /// process is stopped are synced
+    pub sync_all_on_start: bool,
+
+    /// scan the whole sync dir periodically, so the changes which the watcher missed are synced,
+    /// zero means disabled
+    pub sync_all_interval: Duration,
+
+    /// restore the owner and group of the synced files, changing the owner usually requires root
+    pub preserve_owner: bool,
+
+    /// don't update the access time of the local files when hashing or reusing them, the files
+    /// which the process doesn't own are still read with the access time updated
+    pub no_atime: bool,
+
+    /// the upload bytes per second of the bandwidth limits, 0 means no limit
+    pub upload_bytes_per_sec: u64,
+
+    /// the download bytes per second of the bandwidth limits, 0 means no limit
+    pub download_bytes_per_sec: u64,
+
+    /// keep the local files deleted or overwritten by the rumors in the versions dir, none means
+    /// they are removed
+    pub versioning: Option<VersioningPolicy>,
+
+    pub file_creation: FileCreation,
+
+    /// the bootstrap mode of attaching a dir which already has content on several machines, the
+    /// local files which are not synced yet are matched with the remote files by the hash sum.
+    /// The identical files adopt the remote index history without downloading, and the remote
+    /// deletions never remove them, it should be disabled after the first sync
+    pub adopt: bool,
+
+    /// enter the low power mode when no event comes for the duration, zero means disabled. The
+    /// power hook of the controller releases the idle resources, the periodic tasks are
+    /// lengthened, and the next watch event or rumor wakes the controller at once
+    pub low_power_after: Duration,
+
+    /// the anti entropy and sync all intervals are multiplied by it in the low power mode
+    pub low_power_interval_factor: u32,
+
+    /// reject the rumors whose update time is later than the local clock by more than it, so a
+    /// peer with a wildly wrong clock can't win every equal gen comparison, zero means no limit
+    pub max_rumor_clock_skew: Duration,
+
+    /// reject the rumors whose update time is earlier than the local clock by more than it, zero
+    /// means no limit and is the default. The anti entropy also sends the rumors of the long
+    /// unchanged files, so it must be longer than the age of the oldest synced file
+    pub max_rumor_age: Duration,
+
+    /// retry the failed block downloads of the rumors and repairs with the backoff, only the
+    /// blocks not received yet are requested again, 1 max attempt means never retry
+    pub download_retry: RetryPolicy,
+
+    /// recreate the hard link groups of the rumors as hard links to the local file of the group,
+    /// instead of downloading another copy of the same content
+    pub hard_links: bool,
+}
+
+impl SyncOptions {
+    /// the options of the turbo sync, the bandwidth limits and concurrency caps are lifted
+    pub fn turbo(&self) -> Self {
+        Self {
+            write_concurrency: 0,
+            rumor_concurrency: 0,
+            upload_bytes_per_sec: 0,
+            download_bytes_per_sec: 0,
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            write_concurrency: DEFAULT_WRITE_CONCURRENCY,
+            rumor_concurrency: DEFAULT_RUMOR_CONCURRENCY,
+            apply_order: Default::default(),
+            prefetch_files: DEFAULT_PREFETCH_FILES,
+            prefetch_bytes: DEFAULT_PREFETCH_BYTES,
+            conflict_strategy: Default::default(),
+            fsync: false,
+            debounce: Duration::ZERO,
+            empty_dir_policy: Default::default(),
+            special_file_policy: Default::default(),
+            symlink_policy: Default::default(),
+            log_privacy: Default::default(),
+            walk_limits: Default::default(),
+            paranoia_level: Default::default(),
+            block_size_policy: Default::default(),
+            fanout: Default::default(),
+            anti_entropy_interval: Duration::ZERO,
+            sync_all_on_start: true,
+            sync_all_interval: Duration::ZERO,
+            preserve_owner: false,
+            no_atime: false,
+            upload_bytes_per_sec: 0,
+            download_bytes_per_sec: 0,
+            versioning: None,
+            file_creation: Default::default(),
+            adopt: false,
+            low_power_after: Duration::ZERO,
+            low_power_interval_factor: DEFAULT_LOW_POWER_INTERVAL_FACTOR,
+            max_rumor_clock_skew: Duration::ZERO,
+            max_rumor_age: Duration::ZERO,
+            download_retry: RetryPolicy::default(),
+            hard_links: true,
+        }
+    }
+}
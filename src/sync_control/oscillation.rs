@@ -0,0 +1,246 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use flume::Sender;
+use tracing::{error, warn};
+
+use crate::ext::log_path;
+
+/// default alternating changes of a file in the window before it is frozen
+pub const DEFAULT_OSCILLATION_CHANGES: usize = 8;
+
+pub const DEFAULT_OSCILLATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// emitted once when a file is frozen
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OscillationAlert {
+    pub filename: OsString,
+    /// the alternating changes in the window
+    pub changes: usize,
+    /// the peers which changed the file in turn, sorted
+    pub update_by: Vec<String>,
+}
+
+/// detect the files whose generations ping-pong between the peers, such as the peers with
+/// oscillating clocks keep replacing each other's version. When a file is changed by different
+/// peers in turn too many times in the window, it is frozen: it is still applied locally, but
+/// its rumors are not forwarded until it is unfrozen. The clones share the state
+#[derive(Debug, Clone)]
+pub struct OscillationDetector {
+    max_changes: usize,
+    window: Duration,
+    state: Arc<Mutex<State>>,
+    alert_sender: Option<Sender<OscillationAlert>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    files: HashMap<OsString, FileChanges>,
+    /// the stale files are pruned at most once per window
+    pruned_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+struct FileChanges {
+    /// the alternating changes in the window, and who made them
+    changes: VecDeque<(SystemTime, String)>,
+    frozen: bool,
+}
+
+impl Default for OscillationDetector {
+    fn default() -> Self {
+        Self::new(DEFAULT_OSCILLATION_CHANGES, DEFAULT_OSCILLATION_WINDOW)
+    }
+}
+
+impl OscillationDetector {
+    /// 0 max changes means never freeze
+    pub fn new(max_changes: usize, window: Duration) -> Self {
+        Self {
+            max_changes,
+            window,
+            state: Default::default(),
+            alert_sender: None,
+        }
+    }
+
+    /// the alert is dropped when the receiver is full or dropped
+    pub fn with_alert(mut self, alert_sender: Sender<OscillationAlert>) -> Self {
+        self.alert_sender = Some(alert_sender);
+
+        self
+    }
+
+    /// record an applied change of the file, when return true, the file is frozen by this change
+    pub fn record(&self, filename: &OsStr, update_by: &str, now: SystemTime) -> bool {
+        if self.max_changes == 0 {
+            return false;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        self.prune_stale_files(&mut state, now);
+
+        let file = state.files.entry(filename.to_os_string()).or_default();
+        if file.frozen {
+            return false;
+        }
+
+        while let Some((time, _)) = file.changes.front() {
+            if now.duration_since(*time).unwrap_or_default() <= self.window {
+                break;
+            }
+
+            file.changes.pop_front();
+        }
+
+        // the successive changes of a peer are not an oscillation, the count restarts from it
+        if file
+            .changes
+            .back()
+            .is_some_and(|(_, last_update_by)| last_update_by == update_by)
+        {
+            file.changes.clear();
+        }
+        file.changes.push_back((now, update_by.to_string()));
+
+        if file.changes.len() < self.max_changes {
+            return false;
+        }
+
+        file.frozen = true;
+
+        let mut update_by = file
+            .changes
+            .iter()
+            .map(|(_, update_by)| update_by.clone())
+            .collect::<Vec<_>>();
+        update_by.sort_unstable();
+        update_by.dedup();
+
+        error!(
+            filename = ?log_path(filename),
+            changes = file.changes.len(),
+            ?update_by,
+            "file generations oscillate between peers, freeze it"
+        );
+
+        if let Some(alert_sender) = &self.alert_sender {
+            let _ = alert_sender.try_send(OscillationAlert {
+                filename: filename.to_os_string(),
+                changes: file.changes.len(),
+                update_by,
+            });
+        }
+
+        true
+    }
+
+    pub fn is_frozen(&self, filename: &OsStr) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .get(filename)
+            .is_some_and(|file| file.frozen)
+    }
+
+    /// the frozen files, sorted
+    pub fn frozen_files(&self) -> Vec<OsString> {
+        let mut frozen_files = self
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .iter()
+            .filter(|(_, file)| file.frozen)
+            .map(|(filename, _)| filename.clone())
+            .collect::<Vec<_>>();
+        frozen_files.sort_unstable();
+
+        frozen_files
+    }
+
+    /// unfreeze the file, such as its conflict is resolved, the changes are counted again
+    pub fn unfreeze(&self, filename: &OsStr) {
+        if self.state.lock().unwrap().files.remove(filename).is_some() {
+            warn!(filename = ?log_path(filename), "unfreeze file done");
+        }
+    }
+
+    /// forget the files which are not frozen and not changed in the window
+    fn prune_stale_files(&self, state: &mut State, now: SystemTime) {
+        if state.pruned_at.is_some_and(|pruned_at| {
+            now.duration_since(pruned_at).unwrap_or_default() <= self.window
+        }) {
+            return;
+        }
+
+        state.files.retain(|_, file| {
+            file.frozen
+                || file.changes.back().is_some_and(|(time, _)| {
+                    now.duration_since(*time).unwrap_or_default() <= self.window
+                })
+        });
+        state.pruned_at = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freeze_oscillating_file() {
+        let (alert_sender, alert_receiver) = flume::unbounded();
+        let detector =
+            OscillationDetector::new(4, Duration::from_secs(10)).with_alert(alert_sender);
+        let filename = OsStr::new("test.txt");
+        let start = SystemTime::UNIX_EPOCH;
+
+        // the successive changes of a peer are never frozen
+        for secs in 0..5 {
+            assert!(!detector.record(filename, "a", start + Duration::from_secs(secs)));
+        }
+
+        // the changes out of the window are not counted
+        for (secs, update_by) in [
+            (20, "b"),
+            (21, "a"),
+            (22, "b"),
+            (40, "a"),
+            (41, "b"),
+            (42, "a"),
+        ] {
+            assert!(!detector.record(filename, update_by, start + Duration::from_secs(secs)));
+        }
+        assert!(!detector.is_frozen(filename));
+
+        assert!(detector.record(filename, "b", start + Duration::from_secs(43)));
+        assert!(detector.is_frozen(filename));
+        assert!(!detector.record(filename, "a", start + Duration::from_secs(44)));
+        assert_eq!(detector.frozen_files(), [filename]);
+        assert_eq!(
+            alert_receiver.try_iter().collect::<Vec<_>>(),
+            [OscillationAlert {
+                filename: filename.to_os_string(),
+                changes: 4,
+                update_by: vec!["a".to_string(), "b".to_string()],
+            }]
+        );
+
+        detector.unfreeze(filename);
+        assert!(!detector.is_frozen(filename));
+        assert!(detector.frozen_files().is_empty());
+
+        // the stale files are forgotten
+        detector.record(
+            OsStr::new("other.txt"),
+            "a",
+            start + Duration::from_secs(50),
+        );
+        detector.record(filename, "a", start + Duration::from_secs(70));
+        assert_eq!(detector.state.lock().unwrap().files.len(), 1);
+    }
+}
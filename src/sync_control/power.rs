@@ -0,0 +1,17 @@
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use mockall::automock;
+
+/// release and restore the resources of the daemon when the controller enters and leaves the
+/// low power mode, such as the transfer connections to the idle peers, for the daemon running on
+/// the NAS and the single board computers
+#[automock]
+#[async_trait]
+pub trait PowerHook: Debug + Send + Sync {
+    /// no event comes for the low power after duration
+    async fn enter_low_power(&self);
+
+    /// a watch event or rumor comes, it is called before the event is handled
+    async fn leave_low_power(&self);
+}
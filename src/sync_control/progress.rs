@@ -0,0 +1,249 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::sync::{Arc, Mutex};
+
+use flume::{Receiver, Sender, TrySendError};
+use tokio::fs::File;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::ext::hash::hash_sparse_file_with_progress;
+use crate::index::{BlockChain, Sha256sum};
+
+/// the progress of the long running operations of the controller
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Progress {
+    Hashing {
+        filename: OsString,
+        hashed_bytes: u64,
+        total_bytes: u64,
+    },
+
+    /// the total bytes are the bytes of the downloaded blocks, the local blocks which are copied
+    /// are not counted
+    Downloading {
+        filename: OsString,
+        downloaded_bytes: u64,
+        total_bytes: u64,
+    },
+}
+
+impl Progress {
+    fn filename(&self) -> &OsStr {
+        match self {
+            Progress::Hashing { filename, .. } | Progress::Downloading { filename, .. } => filename,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        match self {
+            Progress::Hashing {
+                hashed_bytes,
+                total_bytes,
+                ..
+            } => hashed_bytes >= total_bytes,
+            Progress::Downloading {
+                downloaded_bytes,
+                total_bytes,
+                ..
+            } => downloaded_bytes >= total_bytes,
+        }
+    }
+}
+
+/// report the progress to the receiver, the reports are dropped when the receiver is full or
+/// dropped, so a slow receiver never blocks the sync
+#[derive(Debug, Clone, Default)]
+pub struct ProgressReporter {
+    sender: Option<Sender<Progress>>,
+}
+
+impl ProgressReporter {
+    pub fn new(sender: Sender<Progress>) -> Self {
+        Self {
+            sender: Some(sender),
+        }
+    }
+
+    /// the progress is only built when there is a receiver
+    pub fn report(&self, progress: impl FnOnce() -> Progress) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(progress());
+        }
+    }
+}
+
+/// fan out the progress of the controller to the observers, such as the GUIs attached to a long
+/// running daemon. A new observer receives the recently finished operations and the latest
+/// progress of the in flight operations first, then the following progress
+#[derive(Debug, Clone)]
+pub struct ProgressHub {
+    state: Arc<Mutex<HubState>>,
+}
+
+#[derive(Debug)]
+struct HubState {
+    /// the latest progress of the unfinished operations by the filename
+    in_flight: BTreeMap<OsString, Progress>,
+    /// the recently finished operations, the oldest is dropped when it is full
+    history: VecDeque<Progress>,
+    history_len: usize,
+    observers: Vec<Sender<Progress>>,
+}
+
+impl ProgressHub {
+    /// keep the history_len recently finished operations for the new observers
+    pub fn new(history_len: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(HubState {
+                in_flight: Default::default(),
+                history: VecDeque::with_capacity(history_len),
+                history_len,
+                observers: vec![],
+            })),
+        }
+    }
+
+    /// publish the progress received from the [`ProgressReporter`] until all its senders are
+    /// dropped
+    pub async fn run(&self, receiver: Receiver<Progress>) {
+        while let Ok(progress) = receiver.recv_async().await {
+            self.publish(progress);
+        }
+
+        info!("all progress reporters are dropped, stop publishing");
+    }
+
+    pub fn publish(&self, progress: Progress) {
+        let mut state = self.state.lock().unwrap();
+        if progress.is_finished() {
+            state.in_flight.remove(progress.filename());
+            if state.history_len > 0 {
+                if state.history.len() == state.history_len {
+                    state.history.pop_front();
+                }
+                state.history.push_back(progress.clone());
+            }
+        } else {
+            state
+                .in_flight
+                .insert(progress.filename().to_os_string(), progress.clone());
+        }
+
+        // like the reporter, the progress is dropped for a full observer, and the dropped
+        // observers are removed
+        state.observers.retain(|observer| {
+            !matches!(
+                observer.try_send(progress.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    /// attach an observer, the snapshot is sent before any following progress, the observer
+    /// receives at most cap progress which are not received
+    pub fn subscribe(&self, cap: usize) -> Receiver<Progress> {
+        let mut state = self.state.lock().unwrap();
+        let snapshot_len = state.history.len() + state.in_flight.len();
+        let (sender, receiver) = flume::bounded(cap.max(snapshot_len));
+        for progress in state.history.iter().chain(state.in_flight.values()) {
+            let _ = sender.try_send(progress.clone());
+        }
+        state.observers.push(sender);
+
+        receiver
+    }
+}
+
+/// hash the file of the sync dir, report the hashing progress, and stop hashing when the
+/// controller is shut down. The holes of the sparse file are not read
+pub async fn hash_file_with_report(
+    file: File,
+    filename: &OsStr,
+    total_bytes: u64,
+    block_size: usize,
+    reporter: &ProgressReporter,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<(Sha256sum, BlockChain)> {
+    hash_sparse_file_with_progress(file, block_size, shutdown, |hashed_bytes| {
+        reporter.report(|| Progress::Hashing {
+            filename: filename.to_os_string(),
+            hashed_bytes,
+            total_bytes,
+        })
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tempfile::TempDir;
+    use tokio::fs;
+
+    use super::*;
+    use crate::ext::hash::HashCanceled;
+
+    #[tokio::test]
+    async fn report_hashing_progress() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let path = dir.path().join("test.txt");
+        fs::write(&path, b"test").await.unwrap();
+
+        let (sender, receiver) = flume::unbounded();
+        let reporter = ProgressReporter::new(sender);
+        let shutdown = CancellationToken::new();
+
+        let file = File::open(&path).await.unwrap();
+        hash_file_with_report(file, OsStr::new("test.txt"), 4, 4, &reporter, &shutdown)
+            .await
+            .unwrap();
+        assert_eq!(
+            receiver.drain().collect::<Vec<_>>(),
+            [Progress::Hashing {
+                filename: OsString::from("test.txt"),
+                hashed_bytes: 4,
+                total_bytes: 4,
+            }]
+        );
+
+        shutdown.cancel();
+        let file = File::open(&path).await.unwrap();
+        let err = hash_file_with_report(file, OsStr::new("test.txt"), 4, 4, &reporter, &shutdown)
+            .await
+            .unwrap_err();
+        assert!(err.is::<HashCanceled>());
+        assert!(receiver.is_empty());
+    }
+
+    fn downloading(filename: &str, downloaded_bytes: u64) -> Progress {
+        Progress::Downloading {
+            filename: OsString::from(filename),
+            downloaded_bytes,
+            total_bytes: 4,
+        }
+    }
+
+    #[test]
+    fn replay_to_new_observer() {
+        let hub = ProgressHub::new(1);
+        hub.publish(downloading("a.txt", 4));
+        hub.publish(downloading("b.txt", 4));
+        hub.publish(downloading("c.txt", 2));
+
+        // the oldest finished operation is dropped from the history
+        let receiver = hub.subscribe(1);
+        assert_eq!(
+            receiver.drain().collect::<Vec<_>>(),
+            [downloading("b.txt", 4), downloading("c.txt", 2)]
+        );
+
+        hub.publish(downloading("c.txt", 4));
+        assert_eq!(receiver.try_recv().unwrap(), downloading("c.txt", 4));
+
+        drop(receiver);
+        hub.publish(downloading("d.txt", 2));
+        assert!(hub.state.lock().unwrap().observers.is_empty());
+    }
+}
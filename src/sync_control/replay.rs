@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+/// how many sequence numbers before the highest one are still accepted
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// sliding window anti-replay check, like the ipsec one, a sequence number is accepted only once,
+/// and too old sequence numbers are always rejected
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+struct ReplayWindow {
+    highest: u64,
+    /// bit n means `highest - n` has been seen
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    fn check_and_update(&mut self, seq: u64) -> bool {
+        if self.bitmap == 0 {
+            self.highest = seq;
+            self.bitmap = 1;
+
+            return true;
+        }
+
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.bitmap = if shift >= REPLAY_WINDOW_SIZE {
+                1
+            } else {
+                (self.bitmap << shift) | 1
+            };
+            self.highest = seq;
+
+            return true;
+        }
+
+        let offset = self.highest - seq;
+        if offset >= REPLAY_WINDOW_SIZE {
+            return false;
+        }
+
+        let mask = 1 << offset;
+        if self.bitmap & mask != 0 {
+            return false;
+        }
+
+        self.bitmap |= mask;
+
+        true
+    }
+}
+
+/// per sender windows of the received rumors seqs, it only suppresses the duplicated messages at
+/// the transport level, such as the multicast and reliable copies of the same send. The window is
+/// keyed by the transport sender and the seq isn't bound to the update_by or the content of the
+/// rumors, so it doesn't authenticate the rumors, and an old rumor forwarded again in a new
+/// message isn't dropped by it
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    windows: HashMap<Uuid, ReplayWindow>,
+}
+
+impl ReplayGuard {
+    /// when return false, the message of the sender with the seq has been received or is too old,
+    /// and should be dropped
+    pub fn check_and_update(&mut self, sender_id: Uuid, seq: u64) -> bool {
+        self.windows
+            .entry(sender_id)
+            .or_default()
+            .check_and_update(seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay() {
+        let mut guard = ReplayGuard::default();
+        let peer = Uuid::new_v4();
+
+        assert!(guard.check_and_update(peer, 10));
+        assert!(!guard.check_and_update(peer, 10));
+        assert!(guard.check_and_update(peer, 12));
+        // out of order but inside the window
+        assert!(guard.check_and_update(peer, 11));
+        assert!(!guard.check_and_update(peer, 11));
+        assert!(guard.check_and_update(peer, 9));
+
+        // other peer has its own window
+        assert!(guard.check_and_update(Uuid::new_v4(), 10));
+    }
+
+    #[test]
+    fn too_old() {
+        let mut guard = ReplayGuard::default();
+        let peer = Uuid::new_v4();
+
+        assert!(guard.check_and_update(peer, 1));
+        assert!(guard.check_and_update(peer, 1 + REPLAY_WINDOW_SIZE));
+        assert!(!guard.check_and_update(peer, 1));
+        assert!(guard.check_and_update(peer, 2));
+        assert!(guard.check_and_update(peer, 1000));
+        assert!(!guard.check_and_update(peer, 1000 - REPLAY_WINDOW_SIZE));
+    }
+}
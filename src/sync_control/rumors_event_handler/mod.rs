@@ -1,24 +1,63 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error::Error;
-use std::ffi::OsStr;
-use std::io::ErrorKind;
-use std::path::Path;
-use std::pin::pin;
-use std::{io, u64};
-
-use anyhow::{anyhow, Result};
-use chrono::{FixedOffset, Utc};
+use std::ffi::{OsStr, OsString};
+use std::fs::Permissions;
+use std::io::{ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path};
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+use std::{io, iter, mem};
+
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset, Utc};
+use futures_util::future::Either;
 use futures_util::stream::FuturesUnordered;
-use futures_util::{Sink, SinkExt, Stream, TryStreamExt};
-use itertools::{EitherOrBoth, Itertools};
+use futures_util::{future, stream, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use prefetch::PrefetchStore;
+use sha2::{Digest, Sha256};
 use tap::TapFallible;
 use tokio::fs;
 use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncSeekExt;
+use tokio::sync::{Notify, RwLock};
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use crate::ext::{AsyncFileCopy, AsyncFileExt, AsyncTempFile};
-use crate::index::{Block, Index, IndexFile, IndexGuard};
+use crate::clock::{Clock, SystemClock};
+use crate::ext::hash::hash_file_with_block_size;
+use crate::ext::{
+    file_hash_sum, is_dir, log_path, open_read, set_inode_flags, sparse, AsyncFileCopy,
+    AsyncFileExt, AsyncTempFile, InodeFlags,
+};
+use crate::id_source::{IdSource, RandomIdSource};
+use crate::index::conflicts::{
+    ConflictEntry, ConflictStore, NoopConflictStore, CONFLICT_EXTENSION,
+};
+use crate::index::resume::{NoopResumeStore, PartialDownload, ResumeStore};
+use crate::index::sqlite_index;
+use crate::index::{
+    Block, BlockChain, FileDetail, FileKind, FileMetadata, Index, IndexFile, IndexGuard, Sha256sum,
+};
+use crate::journal::{ConflictRecord, Journal, NoopJournal, OperationSource};
+use crate::sync_control::conflict::{Conflict, ConflictResolver, Resolution};
+use crate::sync_control::dry_run::{PlannedAction, PlannedOp, SyncPlan};
+use crate::sync_control::error::SyncError;
+use crate::sync_control::event::DirRename;
+use crate::sync_control::file_locks::FileLocks;
+use crate::sync_control::options::{EmptyDirPolicy, ParanoiaLevel, SyncOptions};
+use crate::sync_control::oscillation::OscillationDetector;
+use crate::sync_control::progress::{Progress, ProgressReporter};
+use crate::sync_control::schedule::apply_queue;
+use crate::sync_control::scrub::is_modified;
+use crate::sync_control::timing::{SyncStage, SyncTimings};
+use crate::sync_control::versioning;
 use crate::sync_control::SendRumors;
 use crate::transfer::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
 
@@ -29,6 +68,31 @@ pub struct RumorsEventHandler<'a, I, Dl, Si> {
     index: &'a I,
     download_transfer: &'a Dl,
     rumor_sender: Si,
+    options: SyncOptions,
+    clock: &'a dyn Clock,
+    journal: &'a dyn Journal,
+    resume_store: &'a dyn ResumeStore,
+    conflict_store: &'a dyn ConflictStore,
+    file_locks: FileLocks,
+    id_source: &'a dyn IdSource,
+    /// the newer local files of the conflict but old rumors, they are replied to the sender
+    outdated_replies: Mutex<Vec<IndexFile>>,
+    /// the rumors which create the parent dirs take the read lock, pruning the empty dirs takes
+    /// the write lock, so a concurrent rumor never loses its parent dir before the rename
+    dir_lock: RwLock<()>,
+    prefetch_store: PrefetchStore,
+    dir_renames: Vec<DirRename>,
+    /// the files moved by the applied dir renames and their content hash sums, their rumors only
+    /// update the index
+    moved_files: Mutex<HashMap<OsString, Sha256sum>>,
+    progress: ProgressReporter,
+    oscillation: OscillationDetector,
+    timings: SyncTimings,
+    /// when the rumors are received, the queue wait of a rumor starts from it
+    received_at: Instant,
+    conflict_resolver: Option<&'a dyn ConflictResolver>,
+    /// plan the rumors instead of applying them
+    dry_run: Option<SyncPlan>,
 }
 
 impl<'a, I, Dl, Si> RumorsEventHandler<'a, I, Dl, Si> {
@@ -47,8 +111,113 @@ impl<'a, I, Dl, Si> RumorsEventHandler<'a, I, Dl, Si> {
             index,
             download_transfer,
             rumor_sender,
+            options: Default::default(),
+            clock: &SystemClock,
+            journal: &NoopJournal,
+            resume_store: &NoopResumeStore,
+            conflict_store: &NoopConflictStore,
+            file_locks: Default::default(),
+            id_source: &RandomIdSource,
+            outdated_replies: Default::default(),
+            dir_lock: Default::default(),
+            prefetch_store: Default::default(),
+            dir_renames: vec![],
+            moved_files: Default::default(),
+            progress: Default::default(),
+            oscillation: Default::default(),
+            timings: Default::default(),
+            received_at: Instant::now(),
+            conflict_resolver: None,
+            dry_run: None,
         }
     }
+
+    pub fn with_options(mut self, options: SyncOptions) -> Self {
+        self.options = options;
+
+        self
+    }
+
+    pub fn with_clock(mut self, clock: &'a dyn Clock) -> Self {
+        self.clock = clock;
+
+        self
+    }
+
+    pub fn with_journal(mut self, journal: &'a dyn Journal) -> Self {
+        self.journal = journal;
+
+        self
+    }
+
+    /// resume the interrupted downloads of the new files
+    pub fn with_resume_store(mut self, resume_store: &'a dyn ResumeStore) -> Self {
+        self.resume_store = resume_store;
+
+        self
+    }
+
+    /// track the created conflict files in the conflict store, so they can be resolved later
+    pub fn with_conflict_store(mut self, conflict_store: &'a dyn ConflictStore) -> Self {
+        self.conflict_store = conflict_store;
+
+        self
+    }
+
+    pub fn with_file_locks(mut self, file_locks: FileLocks) -> Self {
+        self.file_locks = file_locks;
+
+        self
+    }
+
+    pub fn with_id_source(mut self, id_source: &'a dyn IdSource) -> Self {
+        self.id_source = id_source;
+
+        self
+    }
+
+    /// report the downloading progress of the files
+    pub fn with_progress(mut self, progress: ProgressReporter) -> Self {
+        self.progress = progress;
+
+        self
+    }
+
+    /// time the stages of syncing the files
+    pub fn with_timings(mut self, timings: SyncTimings) -> Self {
+        self.timings = timings;
+
+        self
+    }
+
+    /// the detector shared by the handlers, the rumors of the frozen files are not forwarded
+    pub fn with_oscillation_detector(mut self, oscillation: OscillationDetector) -> Self {
+        self.oscillation = oscillation;
+
+        self
+    }
+
+    /// resolve the conflicts by the resolver instead of the conflict strategy of the options
+    pub fn with_conflict_resolver(mut self, conflict_resolver: &'a dyn ConflictResolver) -> Self {
+        self.conflict_resolver = Some(conflict_resolver);
+
+        self
+    }
+
+    /// don't download the blocks, change the local files or update the index, the actions which
+    /// the rumors would take are recorded in the plan
+    pub fn with_dry_run(mut self, plan: SyncPlan) -> Self {
+        self.dry_run = Some(plan);
+
+        self
+    }
+
+    /// the dirs renamed by the sender of the rumors
+    pub fn with_dir_renames(mut self, dir_renames: Vec<DirRename>) -> Self {
+        self.dir_renames = dir_renames;
+
+        self
+    }
 }
 
 impl<'a, 'b, I, Dl, Si> RumorsEventHandler<'a, I, Dl, Si>
@@ -65,15 +234,88 @@ where
         mut self,
         sender_id: Uuid,
         rumors: Vec<IndexFile>,
-    ) -> Result<()> {
+    ) -> Result<(), SyncError> {
+        let concurrency = match self.options.rumor_concurrency {
+            0 => rumors.len().max(1),
+            concurrency => concurrency,
+        };
+        let concurrent = concurrency > 1 && rumors.len() > 1;
+        self.received_at = Instant::now();
+        self.prefetch_store = PrefetchStore::new(self.options.prefetch_bytes);
+
+        if self.dry_run.is_none() {
+            if let Err(err) = self.apply_dir_renames(&rumors).await {
+                warn!(%err, "apply dir renames failed, handle the rumors file by file");
+            }
+        }
+
+        let queue = apply_queue(&rumors, self.options.apply_order);
+        let handled = AtomicUsize::new(0);
+        let handled_notify = Notify::new();
+        let handling = stream::iter(&queue)
+            .map(|&position| self.handle_locked_rumor(&rumors[position]))
+            .buffered(concurrency)
+            .inspect(|_| {
+                handled.fetch_add(1, AtomicOrdering::Relaxed);
+                handled_notify.notify_waiters();
+            })
+            .collect::<Vec<_>>();
+        let prefetching = self.prefetch(&rumors, &queue, concurrency, &handled, &handled_notify);
+
+        let queue_results = match future::select(pin!(handling), pin!(prefetching)).await {
+            Either::Left((queue_results, _)) => queue_results,
+            Either::Right(((), handling)) => handling.await,
+        };
+
+        // restore the arrival order
+        let mut results = queue.into_iter().zip(queue_results).collect::<Vec<_>>();
+        results.sort_unstable_by_key(|(position, _)| *position);
+
         let mut new_rumors = Vec::with_capacity(rumors.len());
-        for rumor in rumors {
-            let new = self.handle_rumor(&rumor).await?;
+        for (rumor, (_, result)) in rumors.into_iter().zip(results) {
+            let new = match result.map_err(SyncError::from) {
+                // the rumor isn't applied, so it is synced again when a healthy peer spreads it
+                Err(SyncError::Quarantined(peer_id)) => {
+                    warn!(%peer_id, filename = ?log_path(&rumor.filename), "peer is quarantined, wait the rumor from another peer");
+
+                    false
+                }
+
+                // the concurrent index transactions may conflict, such as two sqlite transactions
+                // upgrading to write at the same time, so the failed rumor is retried alone
+                Err(err) if concurrent && is_index_contention(&err) => {
+                    warn!(%err, filename = ?log_path(&rumor.filename), "handle rumor concurrently failed, retry it alone");
+
+                    self.handle_locked_rumor(&rumor).await?
+                }
+
+                result => result?,
+            };
 
-            info!(new, filename = ?rumor.filename, "handle rumor done");
+            info!(new, filename = ?log_path(&rumor.filename), "handle rumor done");
 
             if new {
+                if let Some(timing) = self.timings.finish(&rumor.filename, self.clock.now()) {
+                    self.journal.record_timing(self.dir_id, &timing);
+                }
+                self.journal
+                    .record(self.dir_id, OperationSource::Rumor, &rumor);
+
+                if self
+                    .oscillation
+                    .record(&rumor.filename, &rumor.update_by, self.clock.now())
+                {
+                    self.record_oscillation_conflict(&rumor).await;
+                }
+                if self.oscillation.is_frozen(&rumor.filename) {
+                    warn!(filename = ?log_path(&rumor.filename), "file is frozen, don't forward its rumor");
+
+                    continue;
+                }
+
                 new_rumors.push(rumor);
+            } else {
+                self.timings.discard(&rumor.filename);
             }
         }
 
@@ -83,360 +325,1758 @@ where
             info!("send new rumors to others done");
         }
 
+        let oscillation = &self.oscillation;
+        self.outdated_replies
+            .get_mut()
+            .unwrap()
+            .retain(|reply| !oscillation.is_frozen(&reply.filename));
+        if !self.outdated_replies.get_mut().unwrap().is_empty() {
+            let replies = mem::take(self.outdated_replies.get_mut().unwrap());
+            self.reply_outdated_rumors(sender_id, replies).await?;
+
+            info!(%sender_id, "reply outdated rumors done");
+        }
+
         Ok(())
     }
 
-    /// when return false, means the rumor is old and should be ignore
-    async fn handle_rumor(&mut self, remote_index_file: &IndexFile) -> Result<bool> {
-        let mut index_guard = self.index.begin().await?;
+    /// download the corrupted blocks of the local files from the peers again, the index is the
+    /// source of truth, it is not changed, so the corruption never becomes a new gen and no rumor
+    /// is sent. A file changed or repaired since it was found corrupted is skipped
+    pub async fn handle_repair_event(self, filenames: Vec<OsString>) -> Result<(), SyncError> {
+        for filename in filenames {
+            let _file_lock_guard = self.file_locks.write(&filename).await;
+
+            let repaired = self.repair_file(&filename).await?;
+            if !repaired {
+                self.timings.discard(&filename);
+            } else if let Some(timing) = self.timings.finish(&filename, self.clock.now()) {
+                self.journal.record_timing(self.dir_id, &timing);
+            }
+
+            info!(repaired, filename = ?log_path(&filename), "repair file done");
+        }
+
+        Ok(())
+    }
+
+    async fn repair_file(&self, filename: &OsStr) -> Result<bool> {
+        let index_file = match self
+            .index
+            .get_file(filename)
+            .await
+            .map_err(SyncError::index)?
+        {
+            Some(index_file) if index_file.kind == FileKind::File && !index_file.detail.deleted => {
+                index_file
+            }
+
+            _ => return Ok(false),
+        };
+        let block_chain = match &index_file.detail.block_chain {
+            None => return Ok(false),
+            Some(block_chain) => block_chain,
+        };
+
+        let path = self.sync_dir.join(filename);
+        let local = match fs::symlink_metadata(&path).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                error!(%err, path = ?log_path(&path), "get file metadata failed");
+
+                return Err(err.into());
+            }
+
+            Ok(metadata) => {
+                // the local change is synced by the watch, it is never replaced
+                if is_modified(&metadata, &index_file) {
+                    info!(path = ?log_path(&path), "file is changed locally, skip repair");
+
+                    return Ok(false);
+                }
+
+                let file = open_read(&path, self.options.no_atime)
+                    .await
+                    .tap_err(|err| error!(%err, path = ?log_path(&path), "open file failed"))?;
+                let reader = file
+                    .try_clone()
+                    .await
+                    .tap_err(|err| error!(%err, "clone file failed"))?;
+                let (hash_sum, local_block_chain) =
+                    hash_file_with_block_size(reader, block_chain.block_size as _).await?;
+                if hash_sum == index_file.detail.hash_sum {
+                    info!(path = ?log_path(&path), "file is not corrupted, skip repair");
+
+                    return Ok(false);
+                }
+
+                Some((file, local_block_chain))
+            }
+        };
+
+        let mut temp_file = AsyncTempFile::create(self.sync_dir, &self.id_source.temp_name())
+            .await
+            .tap_err(|err| error!(%err, "create temp file failed"))?;
+
+        info!("create temp file done");
+
+        // the index is the source of truth, only the local blocks which don't match it are
+        // downloaded
+        let blocks_diff = diff_with_local_file(
+            local.as_ref().map(|(file, _)| file),
+            &temp_file,
+            self.dir_id,
+            Path::new(filename),
+            block_chain,
+            local
+                .as_ref()
+                .map(|(_, local_block_chain)| local_block_chain),
+        )
+        .await?;
+
+        let file_size = block_chain.blocks.iter().map(|block| block.len).sum();
+        temp_file
+            .set_len(file_size)
+            .await
+            .tap_err(|err| error!(%err, "set temp file size failed"))?;
+
+        let download_block_requests = blocks_diff.download_block_requests;
+        let block_stream = self
+            .download_blocks(filename, &download_block_requests, vec![])
+            .await?;
+
+        info!(
+            filename = ?log_path(filename),
+            corrupted_blocks = download_block_requests.len(),
+            "get block stream done"
+        );
+
+        // the repaired file is always verified, a peer may have the same corruption
+        let options = SyncOptions {
+            paranoia_level: ParanoiaLevel::File,
+            ..self.options.clone()
+        };
+        if !sync_file(
+            filename,
+            &index_file.detail.hash_sum,
+            &temp_file,
+            local.as_ref().map(|(file, _)| LocalBlocks {
+                file,
+                copy_blocks: &blocks_diff.copy_blocks,
+            }),
+            block_stream,
+            &options,
+            &self.timings,
+        )
+        .await?
+        {
+            warn!(filename = ?log_path(filename), "repair file canceled");
+
+            return Ok(false);
+        }
+
+        temp_file.close();
+        let temp_file_path = temp_file.path();
+
+        let _dir_guard = self.dir_lock.read().await;
+        create_parent_dirs(self.sync_dir, Path::new(filename)).await?;
+
+        self.clear_immutable_flag(&path).await?;
+        self.timings
+            .time(
+                filename,
+                SyncStage::Rename,
+                fs::rename(temp_file_path, &path),
+            )
+            .await
+            .tap_err(
+                |err| error!(%err, path = ?log_path(&path), "move temp file to target file failed"),
+            )?;
+
+        apply_file_metadata(&path, index_file.detail.metadata.as_ref(), &self.options).await;
+
+        Ok(true)
+    }
+
+    /// rename the local dirs renamed by the sender, so the moved files are not downloaded again.
+    /// A dir is renamed only when every local file in it is confirmed by a rumor of the moved file
+    /// with the same content, otherwise the rumors are handled file by file
+    async fn apply_dir_renames(&self, rumors: &[IndexFile]) -> Result<()> {
+        if self.dir_renames.is_empty() {
+            return Ok(());
+        }
+
+        let remote_files = rumors
+            .iter()
+            .filter(|rumor| !rumor.detail.deleted)
+            .map(|rumor| (rumor.filename.as_os_str(), rumor.detail.hash_sum))
+            .collect::<HashMap<_, _>>();
+        let local_files = self
+            .index
+            .list_all_files()
+            .await
+            .map_err(SyncError::index)?
+            .try_filter(|index_file| future::ready(!index_file.detail.deleted))
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(SyncError::index)?;
+
+        for dir_rename in &self.dir_renames {
+            let old_dir = Path::new(&dir_rename.old_dir);
+            let new_dir = Path::new(&dir_rename.new_dir);
+            if !is_valid_filename(old_dir)
+                || !is_valid_filename(new_dir)
+                || new_dir.starts_with(old_dir)
+            {
+                warn!(old_dir = ?log_path(old_dir), new_dir = ?log_path(new_dir), "invalid dir rename, ignore");
+
+                continue;
+            }
+
+            let mut moved_files = HashMap::new();
+            let confirmed = local_files
+                .iter()
+                .filter_map(|index_file| {
+                    let relative = Path::new(&index_file.filename).strip_prefix(old_dir).ok()?;
+
+                    Some((
+                        new_dir.join(relative).into_os_string(),
+                        index_file.detail.hash_sum,
+                    ))
+                })
+                .all(|(new_filename, hash_sum)| {
+                    let confirmed = remote_files.get(new_filename.as_os_str()) == Some(&hash_sum);
+                    moved_files.insert(new_filename, hash_sum);
+
+                    confirmed
+                });
+            if !confirmed || moved_files.is_empty() {
+                info!(old_dir = ?log_path(old_dir), new_dir = ?log_path(new_dir), "dir rename is not confirmed by the rumors, ignore");
+
+                continue;
+            }
+
+            let old_path = self.sync_dir.join(old_dir);
+            let new_path = self.sync_dir.join(new_dir);
+            if !is_dir(&old_path).await? || fs::symlink_metadata(&new_path).await.is_ok() {
+                info!(old_dir = ?log_path(old_dir), new_dir = ?log_path(new_dir), "local dirs don't match the dir rename, ignore");
+
+                continue;
+            }
+
+            {
+                let _dir_guard = self.dir_lock.write().await;
+                create_parent_dirs(self.sync_dir, new_dir).await?;
+
+                fs::rename(&old_path, &new_path).await.tap_err(|err| {
+                    error!(%err, old_path = ?log_path(&old_path), new_path = ?log_path(&new_path), "rename dir failed")
+                })?;
+            }
+
+            info!(
+                old_path = ?log_path(&old_path),
+                new_path = ?log_path(&new_path),
+                files = moved_files.len(),
+                "rename dir done"
+            );
+
+            self.moved_files.lock().unwrap().extend(moved_files);
+        }
+
+        Ok(())
+    }
+
+    /// when return true, the file of the rumor has been moved to its filename by a dir rename
+    async fn is_moved_file(&self, remote_index_file: &IndexFile) -> bool {
+        let hash_sum = self
+            .moved_files
+            .lock()
+            .unwrap()
+            .remove(&remote_index_file.filename);
+        if hash_sum != Some(remote_index_file.detail.hash_sum) {
+            return false;
+        }
+
+        fs::symlink_metadata(self.sync_dir.join(&remote_index_file.filename))
+            .await
+            .is_ok()
+    }
+
+    /// the file has been moved by the dir rename or linked to its link group, only the index is
+    /// updated
+    async fn handle_moved_file(
+        &self,
+        remote_index_file: &IndexFile,
+        local_exists: bool,
+        mut index_guard: I::Guard,
+    ) -> Result<bool> {
+        if local_exists {
+            index_guard
+                .update_file(remote_index_file)
+                .await
+                .map_err(SyncError::index)?;
+        } else {
+            index_guard
+                .create_file(remote_index_file)
+                .await
+                .map_err(SyncError::index)?;
+        }
+
+        apply_file_metadata(
+            &self.sync_dir.join(&remote_index_file.filename),
+            remote_index_file.detail.metadata.as_ref(),
+            &self.options,
+        )
+        .await;
+
+        index_guard.commit().await.map_err(SyncError::index)?;
+
+        info!(filename = ?log_path(&remote_index_file.filename), "handle moved file done");
+
+        Ok(true)
+    }
+
+    /// when return some, the rumor is in the link group of a local file which has the same content,
+    /// the file is recreated as a hard link to it
+    async fn hard_link_leader(
+        &self,
+        remote_index_file: &IndexFile,
+        index_guard: &mut I::Guard,
+    ) -> Result<Option<OsString>> {
+        let leader = match &remote_index_file.detail.link_group {
+            Some(leader) if *leader != remote_index_file.filename => leader,
+            _ => return Ok(None),
+        };
+
+        let leader_index_file = match index_guard
+            .get_file(leader)
+            .await
+            .map_err(SyncError::index)?
+        {
+            Some(leader_index_file)
+                if leader_index_file.kind == FileKind::File
+                    && !leader_index_file.detail.deleted
+                    && leader_index_file.detail.hash_sum == remote_index_file.detail.hash_sum =>
+            {
+                leader_index_file
+            }
+
+            _ => return Ok(None),
+        };
+
+        // the leader changed locally and not scanned yet can't share the content
+        match fs::symlink_metadata(self.sync_dir.join(leader)).await {
+            Ok(metadata) if !is_modified(&metadata, &leader_index_file) => Ok(Some(leader.clone())),
+
+            _ => Ok(None),
+        }
+    }
+
+    /// link the file to the local leader of its link group, when return false, the file isn't
+    /// linked, such as the file system doesn't support hard links, and it is downloaded as usual
+    async fn link_file(&self, remote_index_file: &IndexFile, leader: &OsStr) -> Result<bool> {
+        let path = self.sync_dir.join(&remote_index_file.filename);
+        let temp_path = self.sync_dir.join(self.id_source.temp_name());
+        if let Err(err) = fs::hard_link(self.sync_dir.join(leader), &temp_path).await {
+            warn!(%err, path = ?log_path(&path), "link file to link group failed, download it");
+
+            return Ok(false);
+        }
+
+        let _dir_guard = self.dir_lock.read().await;
+        let result = async {
+            create_parent_dirs(self.sync_dir, Path::new(&remote_index_file.filename)).await?;
+            if !remove_replaced_dir(&path).await? {
+                return Ok(false);
+            }
+
+            self.release_local_file(&remote_index_file.filename).await?;
+            fs::rename(&temp_path, &path).await?;
+
+            Ok::<_, io::Error>(true)
+        }
+        .await;
+        if !matches!(result, Ok(true)) {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        let linked = result.tap_err(
+            |err| error!(%err, path = ?log_path(&path), "move linked file to target file failed"),
+        )?;
+        if linked {
+            info!(path = ?log_path(&path), leader = ?log_path(leader), "link file to link group done");
+        }
+
+        Ok(linked)
+    }
+
+    /// download the blocks of the queued new files ahead while the rumors before them are handled,
+    /// so the network is kept busy across the file boundaries
+    async fn prefetch(
+        &self,
+        rumors: &[IndexFile],
+        queue: &[usize],
+        concurrency: usize,
+        handled: &AtomicUsize,
+        handled_notify: &Notify,
+    ) {
+        let window = self.options.prefetch_files;
+        if window == 0 || self.options.prefetch_bytes == 0 || self.dry_run.is_some() {
+            return;
+        }
+
+        'queue: for (queue_position, &position) in queue.iter().enumerate().skip(concurrency) {
+            // wait until the rumor enters the prefetch window, the started rumor downloads the
+            // blocks by itself
+            loop {
+                let notified = handled_notify.notified();
+                let started = handled.load(AtomicOrdering::Relaxed) + concurrency;
+                if queue_position < started {
+                    continue 'queue;
+                }
+
+                if queue_position < started + window {
+                    break;
+                }
+
+                notified.await;
+            }
+
+            self.prefetch_file(&rumors[position]).await;
+        }
+    }
+
+    /// only the file which doesn't exist locally is prefetched, all of its blocks are probably
+    /// downloaded
+    async fn prefetch_file(&self, remote_index_file: &IndexFile) {
+        let filename = &remote_index_file.filename;
+        let block_chain = match &remote_index_file.detail.block_chain {
+            Some(block_chain)
+                if remote_index_file.kind == FileKind::File
+                    && !remote_index_file.detail.deleted
+                    && is_valid_filename(Path::new(filename)) =>
+            {
+                block_chain
+            }
+
+            _ => return,
+        };
+
+        match fs::symlink_metadata(self.sync_dir.join(filename)).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            _ => return,
+        }
+
+        if !self
+            .prefetch_store
+            .begin(filename, remote_index_file.detail.hash_sum)
+        {
+            return;
+        }
+
+        let download_block_requests = blocks_to_download_block_requests(
+            self.dir_id,
+            Path::new(filename),
+            &block_chain.blocks,
+        );
+        let block_stream = match self
+            .download_transfer
+            .download(&download_block_requests)
+            .await
+        {
+            Err(err) => {
+                let err: io::Error = err.into();
+                warn!(%err, filename = ?log_path(filename), "prefetch file failed");
+
+                return;
+            }
+
+            Ok(block_stream) => block_stream,
+        };
+
+        let mut block_stream = pin!(block_stream);
+        let mut prefetched_blocks = 0;
+        while let Some(Ok(Some(download_block))) = block_stream.next().await {
+            if !self.prefetch_store.insert(filename, download_block) {
+                break;
+            }
+
+            prefetched_blocks += 1;
+        }
+
+        info!(filename = ?log_path(filename), prefetched_blocks, "prefetch file done");
+    }
+
+    /// download the requested blocks, the prefetched blocks are served first, the received
+    /// bytes are reported as the downloading progress of the file
+    async fn download_blocks<'s>(
+        &'s self,
+        filename: &'s OsStr,
+        download_block_requests: &'s [DownloadBlockRequest],
+        prefetched_blocks: Vec<DownloadBlock>,
+    ) -> io::Result<Pin<Box<dyn Stream<Item = io::Result<Option<DownloadBlock>>> + 's>>> {
+        let total_bytes = download_block_requests
+            .iter()
+            .map(|request| request.len)
+            .chain(
+                prefetched_blocks
+                    .iter()
+                    .map(|block| block.data.len() as u64),
+            )
+            .sum::<u64>();
+        let mut downloaded_bytes = 0;
+        let report = move |download_block: &Option<DownloadBlock>| {
+            if let Some(download_block) = download_block {
+                downloaded_bytes += download_block.data.len() as u64;
+                self.progress.report(|| Progress::Downloading {
+                    filename: filename.to_os_string(),
+                    downloaded_bytes,
+                    total_bytes,
+                });
+            }
+        };
+
+        let all_prefetched = download_block_requests.is_empty() && !prefetched_blocks.is_empty();
+        let prefetched_blocks =
+            stream::iter(prefetched_blocks.into_iter().map(|block| Ok(Some(block))));
+        if all_prefetched {
+            return Ok(Box::pin(prefetched_blocks.inspect_ok(report)));
+        }
+
+        let block_stream = self
+            .download_transfer
+            .download(download_block_requests)
+            .await
+            .map_err(|err| SyncError::transfer_io(err.into()))?
+            .map_err(|err| SyncError::transfer_io(err.into()));
+
+        Ok(Box::pin(
+            prefetched_blocks.chain(block_stream).inspect_ok(report),
+        ))
+    }
+
+    /// the rumors of the same file are serialized by the file lock, the earlier rumor takes the
+    /// lock first
+    async fn handle_locked_rumor(&self, remote_index_file: &IndexFile) -> Result<bool> {
+        let _file_lock_guard = self.file_locks.write(&remote_index_file.filename).await;
+        self.timings.record(
+            &remote_index_file.filename,
+            SyncStage::QueueWait,
+            self.received_at.elapsed(),
+        );
+
+        self.handle_rumor(remote_index_file).await
+    }
+
+    /// when return false, means the rumor is old and should be ignore
+    async fn handle_rumor(&self, remote_index_file: &IndexFile) -> Result<bool> {
+        if !is_valid_filename(Path::new(&remote_index_file.filename)) {
+            warn!(filename = ?log_path(&remote_index_file.filename), "filename is not a normal relative path, ignore");
+
+            return Ok(false);
+        }
+
+        if self.is_stale_rumor(remote_index_file) {
+            self.reply_stale_rumor(remote_index_file).await?;
+
+            return Ok(false);
+        }
+
+        if let Some(plan) = &self.dry_run {
+            self.plan_rumor(plan, remote_index_file).await?;
+
+            return Ok(false);
+        }
+
+        if remote_index_file.kind != FileKind::File {
+            return self.handle_remote_without_content(remote_index_file).await;
+        }
+
+        let mut index_guard = self.index.begin().await.map_err(SyncError::index)?;
+        let local_index_file = index_guard
+            .get_file(&remote_index_file.filename)
+            .await
+            .map_err(SyncError::index)?;
+
+        if self.options.adopt {
+            if let Some(adoption) = self
+                .adoption(remote_index_file, local_index_file.as_ref())
+                .await?
+            {
+                return self
+                    .adopt(
+                        remote_index_file,
+                        local_index_file.as_ref(),
+                        adoption,
+                        index_guard,
+                    )
+                    .await;
+            }
+        }
+
+        let replaceable = local_index_file.as_ref().is_none_or(|local_index_file| {
+            local_index_file.detail.deleted
+                && local_index_file
+                    .detail
+                    .version_cmp(&remote_index_file.detail)
+                    == Some(Ordering::Less)
+        });
+        if replaceable
+            && !remote_index_file.detail.deleted
+            && self.is_moved_file(remote_index_file).await
+        {
+            return self
+                .handle_moved_file(remote_index_file, local_index_file.is_some(), index_guard)
+                .await;
+        }
+
+        if replaceable && !remote_index_file.detail.deleted && self.options.hard_links {
+            if let Some(leader) = self
+                .hard_link_leader(remote_index_file, &mut index_guard)
+                .await?
+            {
+                if self.link_file(remote_index_file, &leader).await? {
+                    return self
+                        .handle_moved_file(
+                            remote_index_file,
+                            local_index_file.is_some(),
+                            index_guard,
+                        )
+                        .await;
+                }
+            }
+        }
+
+        match local_index_file {
+            None => {
+                index_guard
+                    .create_file(remote_index_file)
+                    .await
+                    .map_err(SyncError::index)?;
+
+                info!(filename = ?log_path(&remote_index_file.filename), "create file index done");
+
+                let path = self.sync_dir.join(&remote_index_file.filename);
+
+                // file has been deleted
+                if remote_index_file.detail.deleted {
+                    self.release_local_file(&remote_index_file.filename).await?;
+                    remove_local_file(&path).await?;
+
+                    self.remove_empty_dirs(&remote_index_file.filename).await;
+
+                    index_guard.commit().await.map_err(SyncError::index)?;
+
+                    info!("index guard commit done");
+
+                    return Ok(true);
+                }
+
+                let block_chain = match &remote_index_file.detail.block_chain {
+                    None => {
+                        error!(filename = ?log_path(&remote_index_file.filename), "index file doesn't have block chain");
+
+                        return Err(SyncError::Transfer(
+                            format!(
+                                "{:?} index file doesn't have block chain",
+                                remote_index_file.filename
+                            )
+                            .into(),
+                        )
+                        .into());
+                    }
+
+                    Some(block_chain) => block_chain,
+                };
+
+                let mut file = match self
+                    .download_resumable(remote_index_file, block_chain)
+                    .await?
+                {
+                    None => {
+                        warn!(filename = ?log_path(&remote_index_file.filename), "sync file canceled");
+
+                        return Ok(false);
+                    }
+
+                    Some(file) => file,
+                };
+
+                info!(path = ?log_path(&path), "sync file data done");
+
+                file.close();
+                let temp_file_path = file.path();
+
+                let _dir_guard = self.dir_lock.read().await;
+                create_parent_dirs(self.sync_dir, Path::new(&remote_index_file.filename)).await?;
+
+                if !remove_replaced_dir(&path).await? {
+                    return Ok(false);
+                }
+
+                self.release_local_file(&remote_index_file.filename).await?;
+                self.timings
+                    .time(
+                        &remote_index_file.filename,
+                        SyncStage::Rename,
+                        fs::rename(temp_file_path, &path),
+                    )
+                    .await
+                    .tap_err(|err| error!(%err, path = ?log_path(&path), "move temp file to target file failed"))?;
+
+                info!(path = ?log_path(&path), "move temp file to target file done");
+
+                apply_file_metadata(
+                    &path,
+                    remote_index_file.detail.metadata.as_ref(),
+                    &self.options,
+                )
+                .await;
+
+                self.timings
+                    .time(
+                        &remote_index_file.filename,
+                        SyncStage::IndexCommit,
+                        index_guard.commit(),
+                    )
+                    .await
+                    .map_err(SyncError::index)?;
+
+                info!("index guard commit done");
+
+                Ok(true)
+            }
+
+            Some(local_index_file) => {
+                if is_create_create_conflict(remote_index_file, &local_index_file) {
+                    return self
+                        .handle_create_create_conflict(
+                            remote_index_file,
+                            &local_index_file,
+                            index_guard,
+                        )
+                        .await;
+                }
+
+                match local_index_file
+                    .detail
+                    .version_cmp(&remote_index_file.detail)
+                {
+                    Some(Ordering::Less) => {
+                        self.handle_remote_is_latest(
+                            remote_index_file,
+                            &local_index_file,
+                            index_guard,
+                        )
+                        .await
+                    }
+
+                    Some(Ordering::Equal) | None => {
+                        self.handle_concurrent_change(
+                            remote_index_file,
+                            &local_index_file,
+                            index_guard,
+                        )
+                        .await
+                    }
+
+                    Some(Ordering::Greater) => {
+                        self.handle_local_is_latest(remote_index_file, &local_index_file);
+
+                        Ok(false)
+                    }
+                }
+            }
+        }
+    }
+
+    /// plan the rumor against the local index entry and the local file without changing them
+    async fn plan_rumor(&self, plan: &SyncPlan, remote_index_file: &IndexFile) -> Result<()> {
+        let filename = &remote_index_file.filename;
+        let local_index_file = self
+            .index
+            .get_file(filename)
+            .await
+            .map_err(SyncError::index)?;
+        let local_exists = fs::symlink_metadata(self.sync_dir.join(filename))
+            .await
+            .is_ok();
+
+        let op = match &local_index_file {
+            None if remote_index_file.detail.deleted => local_exists.then_some(PlannedOp::Delete),
+            None if local_exists => Some(PlannedOp::Update),
+            None => Some(PlannedOp::Create),
+
+            Some(local_index_file)
+                if is_create_create_conflict(remote_index_file, local_index_file) =>
+            {
+                Some(PlannedOp::Conflict)
+            }
+            Some(local_index_file) if is_same_content(remote_index_file, local_index_file) => None,
+            Some(local_index_file) => match local_index_file
+                .detail
+                .version_cmp(&remote_index_file.detail)
+            {
+                Some(Ordering::Less) if remote_index_file.detail.deleted => {
+                    (!local_index_file.detail.deleted).then_some(PlannedOp::Delete)
+                }
+                Some(Ordering::Less) if local_index_file.detail.deleted => Some(PlannedOp::Create),
+                Some(Ordering::Less) => Some(PlannedOp::Update),
+                Some(Ordering::Equal) | None => Some(PlannedOp::Conflict),
+                Some(Ordering::Greater) => None,
+            },
+        };
+
+        let op = match op {
+            None => {
+                info!(filename = ?log_path(filename), "rumor changes nothing, skip planning");
+
+                return Ok(());
+            }
+
+            Some(op) => op,
+        };
+        let transfer_bytes = match op {
+            PlannedOp::Delete => 0,
+            _ => transfer_bytes(remote_index_file, local_index_file.as_ref()),
+        };
+
+        plan.plan(PlannedAction {
+            filename: filename.clone(),
+            op,
+            transfer_bytes,
+        });
+
+        info!(filename = ?log_path(filename), ?op, transfer_bytes, "plan rumor done");
+
+        Ok(())
+    }
+
+    /// decide how the local file which is not synced yet adopts the remote file, when return None,
+    /// the rumor is handled as usual
+    async fn adoption(
+        &self,
+        remote_index_file: &IndexFile,
+        local_index_file: Option<&IndexFile>,
+    ) -> Result<Option<Adoption>> {
+        match local_index_file {
+            // the local file is only created by the local scan, it is never synced
+            Some(local_index_file)
+                if local_index_file.kind == FileKind::File
+                    && !local_index_file.detail.deleted
+                    && local_index_file.previous_details.is_empty()
+                    && local_index_file.update_by == self.user_id.as_hyphenated().to_string() =>
+            {
+                // the remote deletion of the synced local file is applied as usual
+                let deleted_local = remote_index_file.previous_details.iter().any(|detail| {
+                    detail.gen == local_index_file.detail.gen
+                        && detail.hash_sum == local_index_file.detail.hash_sum
+                });
+
+                Ok((remote_index_file.detail.deleted && !deleted_local)
+                    .then_some(Adoption::KeepLocal))
+            }
+
+            Some(_) => Ok(None),
+
+            None => {
+                let path = self.sync_dir.join(&remote_index_file.filename);
+                match fs::symlink_metadata(&path).await {
+                    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                    Err(err) => {
+                        error!(%err, path = ?log_path(&path), "get file metadata failed");
+
+                        return Err(err.into());
+                    }
+
+                    Ok(metadata) if !metadata.is_file() => return Ok(None),
+                    Ok(_) => {}
+                }
+
+                if remote_index_file.detail.deleted {
+                    return Ok(Some(Adoption::KeepUntracked));
+                }
+
+                let block_size = match &remote_index_file.detail.block_chain {
+                    None => return Ok(None),
+                    Some(block_chain) => block_chain.block_size,
+                };
+                let file = open_read(&path, self.options.no_atime)
+                    .await
+                    .tap_err(|err| error!(%err, path = ?log_path(&path), "open file failed"))?;
+                let (hash_sum, _) = hash_file_with_block_size(file, block_size as _).await?;
+
+                Ok((hash_sum == remote_index_file.detail.hash_sum).then_some(Adoption::Identical))
+            }
+        }
+    }
+
+    async fn adopt(
+        &self,
+        remote_index_file: &IndexFile,
+        local_index_file: Option<&IndexFile>,
+        adoption: Adoption,
+        mut index_guard: I::Guard,
+    ) -> Result<bool> {
+        let filename = &remote_index_file.filename;
+        match (adoption, local_index_file) {
+            (Adoption::KeepLocal, Some(local_index_file)) => {
+                info!(filename = ?log_path(filename), "remote deleted file is created locally, keep local file");
+
+                self.keep_local_version(remote_index_file, local_index_file, index_guard)
+                    .await
+            }
+
+            (Adoption::Identical | Adoption::KeepUntracked, _) => {
+                index_guard
+                    .create_file(remote_index_file)
+                    .await
+                    .map_err(SyncError::index)?;
+
+                // the untracked file is kept, the next scan adds it after the remote deletion
+                if adoption == Adoption::Identical {
+                    apply_file_metadata(
+                        &self.sync_dir.join(filename),
+                        remote_index_file.detail.metadata.as_ref(),
+                        &self.options,
+                    )
+                    .await;
+                }
+
+                index_guard.commit().await.map_err(SyncError::index)?;
+
+                info!(?adoption, filename = ?log_path(filename), "adopt remote file done");
+
+                Ok(true)
+            }
+
+            (Adoption::KeepLocal, None) => Ok(false),
+        }
+    }
+
+    /// download the remote file which has no local blocks into a temp file. When the download
+    /// is interrupted, the temp file is kept and its written blocks are saved into the resume
+    /// store, so the next download of the same content only requests the missing blocks. When
+    /// return None, the sync is canceled
+    async fn download_resumable(
+        &self,
+        remote_index_file: &IndexFile,
+        block_chain: &BlockChain,
+    ) -> Result<Option<AsyncTempFile>> {
+        let filename = &remote_index_file.filename;
+        let partial = self
+            .resume_store
+            .load_partial(filename)
+            .await
+            .tap_err(
+                |err| warn!(%err, filename = ?log_path(filename), "load partial download failed"),
+            )
+            .ok()
+            .flatten();
+        let has_partial = partial.is_some();
+
+        let (mut file, temp_name, mut written_offsets) = match self
+            .open_partial(remote_index_file, block_chain, partial)
+            .await
+        {
+            Some(resumed) => resumed,
+            None => {
+                let temp_name = self.id_source.temp_name();
+                let file = AsyncTempFile::create(self.sync_dir, &temp_name)
+                    .await
+                    .tap_err(|err| error!(%err, "create temp file failed"))?;
+
+                info!("create temp file done");
+
+                (file, temp_name, BTreeSet::new())
+            }
+        };
+
+        let file_size = block_chain.blocks.iter().map(|block| block.len).sum();
+        let missing_blocks = block_chain
+            .blocks
+            .iter()
+            .filter(|block| !written_offsets.contains(&block.offset))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // the received blocks may not be written when the download is interrupted, they are
+        // verified before resuming anyway
+        let received_offsets = RefCell::new(BTreeSet::new());
+        let result = async {
+            file.set_len(file_size)
+                .await
+                .tap_err(|err| error!(%err, "set temp file size failed"))?;
+
+            let mut download_block_requests = blocks_to_download_block_requests(
+                self.dir_id,
+                Path::new(filename),
+                &missing_blocks,
+            );
+            let prefetched_blocks = self
+                .prefetch_store
+                .take(remote_index_file, &mut download_block_requests);
+
+            let block_stream = self
+                .download_blocks(
+                    &remote_index_file.filename,
+                    &download_block_requests,
+                    prefetched_blocks,
+                )
+                .await?
+                .inspect_ok(|download_block: &Option<DownloadBlock>| {
+                    if let Some(download_block) = download_block {
+                        received_offsets.borrow_mut().insert(download_block.offset);
+                    }
+                });
+
+            info!(?download_block_requests, "get block stream done");
+
+            sync_file(
+                filename,
+                &remote_index_file.detail.hash_sum,
+                &file,
+                None,
+                block_stream,
+                &self.options,
+                &self.timings,
+            )
+            .await
+        }
+        .await;
+
+        match result {
+            Err(err) => {
+                written_offsets.extend(received_offsets.into_inner());
+                let partial = PartialDownload {
+                    filename: filename.clone(),
+                    hash_sum: remote_index_file.detail.hash_sum,
+                    temp_name,
+                    written_offsets,
+                };
+
+                match self.resume_store.save_partial(&partial).await {
+                    Err(err) => {
+                        warn!(%err, filename = ?log_path(filename), "save partial download failed")
+                    }
+                    Ok(()) => file.keep(),
+                }
+
+                Err(err.into())
+            }
+
+            Ok(synced) => {
+                if has_partial {
+                    let _ = self
+                        .resume_store
+                        .remove_partial(filename)
+                        .await
+                        .tap_err(|err| warn!(%err, filename = ?log_path(filename), "remove partial download failed"));
+                }
+
+                Ok(synced.then_some(file))
+            }
+        }
+    }
+
+    /// reopen the temp file of the interrupted download of the same content, only the written
+    /// blocks which match the block hash sums are kept, when return None, the download starts
+    /// from scratch
+    async fn open_partial(
+        &self,
+        remote_index_file: &IndexFile,
+        block_chain: &BlockChain,
+        partial: Option<PartialDownload>,
+    ) -> Option<(AsyncTempFile, String, BTreeSet<u64>)> {
+        let partial = partial?;
+        let temp_path = self.sync_dir.join(&partial.temp_name);
+
+        if partial.hash_sum != remote_index_file.detail.hash_sum {
+            info!(filename = ?log_path(&partial.filename), "partial download is stale, drop it");
+
+            let _ = remove_local_file(&temp_path).await;
+
+            return None;
+        }
+
+        let file = AsyncTempFile::open(self.sync_dir, &partial.temp_name)
+            .await
+            .tap_err(|err| warn!(%err, temp_path = ?log_path(&temp_path), "open partial temp file failed"))
+            .ok()?;
+
+        let mut written_offsets = BTreeSet::new();
+        let mut buf = vec![];
+        for block in &block_chain.blocks {
+            if !partial.written_offsets.contains(&block.offset) {
+                continue;
+            }
+
+            buf.resize(block.len as _, 0);
+            match file.read_at(&mut buf, block.offset).await {
+                Ok(n) if n == block.len => {}
+                _ => continue,
+            }
+
+            if <Sha256sum>::from(Sha256::digest(&buf)) == block.hash_sum {
+                written_offsets.insert(block.offset);
+            }
+        }
+
+        info!(
+            filename = ?log_path(&partial.filename),
+            recorded_blocks = partial.written_offsets.len(),
+            verified_blocks = written_offsets.len(),
+            "resume partial download done"
+        );
+
+        Some((file, partial.temp_name, written_offsets))
+    }
+
+    /// the remote symlink or special file can't be created without content, but when it replaces
+    /// the local file, the local file is removed so the peers still agree the file is gone
+    async fn handle_remote_without_content(&self, remote_index_file: &IndexFile) -> Result<bool> {
+        let mut index_guard = self.index.begin().await.map_err(SyncError::index)?;
+
+        let local_index_file = match index_guard
+            .get_file(&remote_index_file.filename)
+            .await
+            .map_err(SyncError::index)?
+        {
+            Some(local_index_file)
+                if local_index_file.kind == FileKind::File
+                    && !local_index_file.detail.deleted
+                    && remote_index_file
+                        .previous_details
+                        .iter()
+                        .any(|previous_detail| {
+                            previous_detail.gen == local_index_file.detail.gen
+                                && previous_detail.hash_sum == local_index_file.detail.hash_sum
+                        }) =>
+            {
+                local_index_file
+            }
+
+            _ => {
+                warn!(filename = ?log_path(&remote_index_file.filename), kind = %remote_index_file.kind, "remote file without content can't be synced, ignore");
+
+                return Ok(false);
+            }
+        };
+
+        info!(
+            filename = ?log_path(&remote_index_file.filename),
+            local_kind = %local_index_file.kind,
+            remote_kind = %remote_index_file.kind,
+            "remote replaces the local file with another kind"
+        );
+
+        index_guard
+            .update_file(remote_index_file)
+            .await
+            .map_err(SyncError::index)?;
+
+        info!(filename = ?log_path(&remote_index_file.filename), "update file index done");
+
+        let path = self.sync_dir.join(&remote_index_file.filename);
+        // only the regular file is removed, the path may have been replaced locally
+        if fs::symlink_metadata(&path)
+            .await
+            .is_ok_and(|metadata| metadata.is_file())
+        {
+            self.release_local_file(&remote_index_file.filename).await?;
+            remove_local_file(&path).await?;
+        }
+
+        index_guard.commit().await.map_err(SyncError::index)?;
+
+        info!("index guard commit done");
+
+        Ok(true)
+    }
+
+    /// the remote and local have the same gen, or their versions are changed concurrently, so
+    /// neither one descends from the other
+    async fn handle_concurrent_change(
+        &self,
+        remote_index_file: &IndexFile,
+        local_index_file: &IndexFile,
+        mut index_guard: I::Guard,
+    ) -> Result<bool> {
+        if remote_index_file == local_index_file {
+            info!("nothing changed");
+
+            return Ok(false);
+        }
+
+        // remote and local made the same change, only the metadata is different, pick one of them
+        // by the update time and update by, so all nodes converge without a conflict file
+        if is_same_content(remote_index_file, local_index_file) {
+            if (remote_index_file.update_time, &remote_index_file.update_by)
+                > (local_index_file.update_time, &local_index_file.update_by)
+            {
+                return self
+                    .handle_same_content(remote_index_file, index_guard)
+                    .await;
+            }
+
+            info!("file content is same, ignore remote");
+
+            return Ok(false);
+        }
+
+        // remote and local change together, however, local is newer, so ignore remote
+        if remote_index_file.update_time < local_index_file.update_time {
+            info!("ignore remote");
+
+            return Ok(false);
+        }
+
+        if remote_index_file.update_time > local_index_file.update_time {
+            index_guard
+                .update_file(remote_index_file)
+                .await
+                .map_err(SyncError::index)?;
+
+            info!(filename = ?log_path(&remote_index_file.filename), "update file index done");
+
+            if remote_index_file.detail.deleted {
+                let path = self.sync_dir.join(&remote_index_file.filename);
+
+                self.release_local_file(&remote_index_file.filename).await?;
+                remove_local_file(&path).await?;
+
+                self.remove_empty_dirs(&remote_index_file.filename).await;
+
+                index_guard.commit().await.map_err(SyncError::index)?;
+
+                info!("index guard commit done");
 
-        match index_guard.get_file(&remote_index_file.filename).await? {
-            None => {
-                index_guard.create_file(remote_index_file).await?;
+                return Ok(true);
+            }
 
-                info!(filename = ?remote_index_file.filename, "create file index done");
+            let keep_local = match self
+                .resolve_conflict(remote_index_file, local_index_file)
+                .await
+            {
+                Resolution::Local => {
+                    return self
+                        .keep_local_version(remote_index_file, local_index_file, index_guard)
+                        .await;
+                }
 
-                let path = self.sync_dir.join(&remote_index_file.filename);
+                Resolution::Remote => false,
+                Resolution::Both => true,
+            };
 
-                // file has been deleted
-                if remote_index_file.detail.deleted {
-                    match fs::remove_file(&path).await {
-                        Err(err) if err.kind() == ErrorKind::NotFound => {
-                            info!(?path, "file may have been deleted");
-                        }
+            return self
+                .replace_with_remote(remote_index_file, local_index_file, index_guard, keep_local)
+                .await;
+        }
 
-                        Err(err) => {
-                            error!(%err, ?path, "delete file failed");
+        warn!(
+            filename = ?log_path(&remote_index_file.filename),
+            "remote and local change file at the same time, that's so hard to sync, we only can warn and ingore it"
+        );
 
-                            return Err(err.into());
-                        }
+        Ok(false)
+    }
 
-                        Ok(_) => {}
-                    }
+    /// the remote and local created the file independently, the gen can't tell which one is newer.
+    /// Every node picks the same winner by the update time and update by, the loser content is
+    /// preserved as a conflict file by the node which holds it
+    async fn handle_create_create_conflict(
+        &self,
+        remote_index_file: &IndexFile,
+        local_index_file: &IndexFile,
+        mut index_guard: I::Guard,
+    ) -> Result<bool> {
+        warn!(filename = ?log_path(&remote_index_file.filename), "remote and local create file independently");
 
-                    index_guard.commit().await?;
+        if (local_index_file.update_time, &local_index_file.update_by)
+            >= (remote_index_file.update_time, &remote_index_file.update_by)
+        {
+            info!("local file wins the create conflict, reply the local file index");
 
-                    info!("index guard commit done");
+            self.outdated_replies
+                .lock()
+                .unwrap()
+                .push(local_index_file.clone());
+            self.journal.record_conflict(
+                self.dir_id,
+                &ConflictRecord {
+                    time: self.clock.now(),
+                    filename: local_index_file.filename.clone(),
+                    kept: local_index_file.clone(),
+                    conflict: remote_index_file.clone(),
+                    conflict_filename: None,
+                },
+            );
 
-                    return Ok(true);
-                }
+            return Ok(false);
+        }
 
-                let mut file = AsyncTempFile::create(self.sync_dir)
-                    .await
-                    .tap_err(|err| error!(%err, "create temp file failed"))?;
+        let path = self.sync_dir.join(&local_index_file.filename);
+        let conflict_filename =
+            match open_origin_file(&path, local_index_file, self.options.no_atime).await? {
+                None => None,
+                Some(local_file) => Some(
+                    self.create_conflict_file(&local_file, local_index_file)
+                        .await?,
+                ),
+            };
 
-                info!(?path, "open file done");
+        index_guard
+            .update_file(remote_index_file)
+            .await
+            .map_err(SyncError::index)?;
 
-                let block_chain = match &remote_index_file.detail.block_chain {
-                    None => {
-                        error!(filename = ?remote_index_file.filename, "index file doesn't have block chain");
+        info!(filename = ?log_path(&remote_index_file.filename), "update file index done");
 
-                        return Err(anyhow!(
-                            "{:?} index file doesn't have block chain",
-                            remote_index_file.filename
-                        ));
-                    }
+        let new = self
+            .replace_with_remote(remote_index_file, local_index_file, index_guard, false)
+            .await?;
 
-                    Some(block_chain) => block_chain,
-                };
+        self.journal.record_conflict(
+            self.dir_id,
+            &ConflictRecord {
+                time: self.clock.now(),
+                filename: remote_index_file.filename.clone(),
+                kept: remote_index_file.clone(),
+                conflict: local_index_file.clone(),
+                conflict_filename,
+            },
+        );
 
-                let file_size = block_chain.blocks.iter().map(|block| block.len).sum();
+        Ok(new)
+    }
 
-                file.set_len(file_size)
-                    .await
-                    .tap_err(|err| error!(%err, ?path, "set file size failed"))?;
+    async fn resolve_conflict(
+        &self,
+        remote_index_file: &IndexFile,
+        local_index_file: &IndexFile,
+    ) -> Resolution {
+        let conflict = Conflict {
+            dir_id: self.dir_id,
+            local: local_index_file.clone(),
+            remote: remote_index_file.clone(),
+        };
+        let resolution = match self.conflict_resolver {
+            None => self.options.conflict_strategy.resolve(&conflict).await,
+            Some(conflict_resolver) => conflict_resolver.resolve(&conflict).await,
+        };
 
-                let download_block_requests = blocks_to_download_block_requests(
-                    self.dir_id,
-                    Path::new(&remote_index_file.filename),
-                    &block_chain.blocks,
-                );
+        info!(?resolution, filename = ?log_path(&remote_index_file.filename), "resolve conflict done");
 
-                let block_stream = self
-                    .download_transfer
-                    .download(&download_block_requests)
-                    .await
-                    .map_err(Into::into)?
-                    .map_err(Into::into);
+        resolution
+    }
 
-                info!(?download_block_requests, "get block stream done");
+    /// release the local file before it is deleted or replaced, the immutable flag set by sync is
+    /// cleared, then the file is moved into the versions dir when the versioning is enabled
+    async fn release_local_file(&self, filename: &OsStr) -> io::Result<()> {
+        self.clear_immutable_flag(&self.sync_dir.join(filename))
+            .await?;
 
-                if !sync_file(&remote_index_file.filename, &file, block_stream).await? {
-                    warn!(filename = ?remote_index_file.filename, "sync file canceled");
+        if let Some(policy) = &self.options.versioning {
+            versioning::archive(self.sync_dir, filename, self.clock.now(), policy).await?;
+        }
 
-                    return Ok(false);
-                }
+        Ok(())
+    }
 
-                info!(?path, "sync file data done");
+    /// the immutable local file can't be replaced or deleted, the flag is only cleared when sync
+    /// sets it
+    async fn clear_immutable_flag(&self, path: &Path) -> io::Result<()> {
+        if !self.options.file_creation.immutable || fs::symlink_metadata(path).await.is_err() {
+            return Ok(());
+        }
 
-                file.close();
-                let temp_file_path = file.path();
+        set_inode_flags(path, InodeFlags::default())
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(path), "clear inode flags failed"))
+    }
 
-                fs::rename(temp_file_path, &path)
-                    .await
-                    .tap_err(|err| error!(%err, ?path, "move temp file to target file failed"))?;
+    /// copy the local file as a conflict file, the conflict file is tracked by the conflict store
+    /// until it is resolved, a failed tracking doesn't fail the rumor
+    async fn create_conflict_file(
+        &self,
+        local_file: &File,
+        local_index_file: &IndexFile,
+    ) -> io::Result<OsString> {
+        let conflict_filename = create_conflict_file_from(
+            local_file,
+            self.sync_dir,
+            &local_index_file.filename,
+            self.clock.now(),
+        )
+        .await?;
+
+        let conflict = ConflictEntry {
+            conflict_filename: conflict_filename.clone(),
+            filename: local_index_file.filename.clone(),
+            hash_sum: local_index_file.detail.hash_sum,
+            time: self.clock.now(),
+        };
+        if let Err(err) = self.conflict_store.add_conflict(&conflict).await {
+            warn!(%err, conflict_filename = ?log_path(&conflict_filename), "add conflict failed, the conflict file is not tracked");
+        }
 
-                info!(?path, "move temp file to target file done");
+        Ok(conflict_filename)
+    }
 
-                index_guard.commit().await?;
+    /// keep a conflict copy of the frozen file, so the user can choose the version by resolving
+    /// the conflict, a failure is only warned, the file is frozen anyway
+    async fn record_oscillation_conflict(&self, index_file: &IndexFile) {
+        if index_file.kind != FileKind::File || index_file.detail.deleted {
+            return;
+        }
 
-                info!("index guard commit done");
+        let path = self.sync_dir.join(&index_file.filename);
+        let file = match open_read(&path, self.options.no_atime).await {
+            Err(err) => {
+                warn!(%err, path = ?log_path(&path), "open frozen file failed, no conflict file is created");
 
-                Ok(true)
+                return;
             }
 
-            Some(local_index_file) => {
-                match local_index_file
-                    .detail
-                    .gen
-                    .cmp(&remote_index_file.detail.gen)
-                {
-                    Ordering::Less => {
-                        self.handle_remote_is_latest(
-                            remote_index_file,
-                            &local_index_file,
-                            index_guard,
-                        )
-                        .await
-                    }
-
-                    Ordering::Equal => {
-                        self.handle_gen_eq(remote_index_file, &local_index_file, index_guard)
-                            .await
-                    }
+            Ok(file) => file,
+        };
 
-                    Ordering::Greater => {
-                        self.handle_local_is_latest(remote_index_file, &local_index_file);
+        match self.create_conflict_file(&file, index_file).await {
+            Err(err) => {
+                warn!(%err, path = ?log_path(&path), "create conflict file of frozen file failed");
+            }
 
-                        Ok(false)
-                    }
-                }
+            Ok(conflict_filename) => {
+                info!(conflict_filename = ?log_path(&conflict_filename), "create conflict file of frozen file done");
             }
         }
     }
 
-    async fn handle_gen_eq(
-        &mut self,
+    /// keep the local file as a new version after the remote version, both versions are in its
+    /// previous details, so the peers replace the remote file with it. The new version is replied
+    /// to the sender, the sender spreads it as a new rumor
+    async fn keep_local_version(
+        &self,
         remote_index_file: &IndexFile,
         local_index_file: &IndexFile,
         mut index_guard: I::Guard,
     ) -> Result<bool> {
-        if remote_index_file == local_index_file {
-            info!("nothing changed");
+        let mut kept = local_index_file.clone();
+        let gen = remote_index_file
+            .detail
+            .gen
+            .max(local_index_file.detail.gen)
+            + 1;
+        // the kept version descends from both versions
+        let version = local_index_file
+            .detail
+            .version
+            .merged(&remote_index_file.detail.version)
+            .incremented(&self.user_id.as_hyphenated().to_string());
+        let mut local_detail = mem::replace(
+            &mut kept.detail,
+            FileDetail {
+                gen,
+                version,
+                ..local_index_file.detail.clone()
+            },
+        );
+        local_detail.block_chain.take();
+        let mut remote_detail = remote_index_file.detail.clone();
+        remote_detail.block_chain.take();
+        kept.previous_details.extend([local_detail, remote_detail]);
+        kept.update_time = self.clock.now();
+        kept.update_by = self.user_id.as_hyphenated().to_string();
+
+        index_guard
+            .update_file(&kept)
+            .await
+            .map_err(SyncError::index)?;
+        index_guard.commit().await.map_err(SyncError::index)?;
 
-            return Ok(false);
-        }
+        info!(filename = ?log_path(&kept.filename), gen, "keep local file as new version done");
 
-        // remote and local change together so they have same gen but different update time,
-        // however, local is newer, so ignore remote
-        if remote_index_file.update_time < local_index_file.update_time {
-            info!("ignore remote");
+        self.journal.record_conflict(
+            self.dir_id,
+            &ConflictRecord {
+                time: self.clock.now(),
+                filename: kept.filename.clone(),
+                kept: kept.clone(),
+                conflict: remote_index_file.clone(),
+                conflict_filename: None,
+            },
+        );
+        self.outdated_replies.lock().unwrap().push(kept);
 
-            return Ok(false);
-        }
+        Ok(false)
+    }
 
-        if remote_index_file.update_time > local_index_file.update_time {
-            index_guard.update_file(remote_index_file).await?;
+    /// replace the local file with the remote file, the index guard has been updated to the
+    /// remote file, when keep_local is true, the local file is copied as a conflict file first
+    async fn replace_with_remote(
+        &self,
+        remote_index_file: &IndexFile,
+        local_index_file: &IndexFile,
+        index_guard: I::Guard,
+        keep_local: bool,
+    ) -> Result<bool> {
+        let path = self.sync_dir.join(&remote_index_file.filename);
+        let origin_file = open_origin_file(&path, local_index_file, self.options.no_atime).await?;
 
-            info!(filename = ?remote_index_file.filename, "update file index done");
+        if let Some(origin_file) = &origin_file {
+            if keep_local {
+                self.create_conflict_file(origin_file, local_index_file)
+                    .await?;
 
-            if remote_index_file.detail.deleted {
-                let path = self.sync_dir.join(&remote_index_file.filename);
+                info!(filename = ?log_path(&remote_index_file.filename), "create conflict file done");
+            }
+        }
 
-                fs::remove_file(&path)
-                    .await
-                    .tap_err(|err| error!(%err, ?path, "delete file failed"))?;
+        let remote_block_chain = match &remote_index_file.detail.block_chain {
+            None => {
+                error!(filename = ?log_path(&remote_index_file.filename), "index file doesn't have block chain");
 
-                info!(?path, "delete file done");
+                return Err(SyncError::Transfer(
+                    format!(
+                        "{:?} index file doesn't have block chain",
+                        remote_index_file.filename
+                    )
+                    .into(),
+                )
+                .into());
+            }
 
-                index_guard.commit().await?;
+            Some(block_chain) => block_chain,
+        };
 
-                info!("index guard commit done");
+        let file_size = remote_block_chain
+            .blocks
+            .iter()
+            .map(|block| block.len)
+            .sum::<u64>();
+        let mut temp_file = AsyncTempFile::create(self.sync_dir, &self.id_source.temp_name())
+            .await
+            .tap_err(|err| error!(%err, "create temp file failed"))?;
 
-                return Ok(true);
-            }
+        info!("create temp file done");
 
-            let path = self.sync_dir.join(&remote_index_file.filename);
-            if !local_index_file.detail.deleted {
-                let origin_file = File::open(&path)
-                    .await
-                    .tap_err(|err| error!(%err, ?path, "open origin target file failed"))?;
+        let blocks_diff = diff_with_local_file(
+            origin_file.as_ref(),
+            &temp_file,
+            self.dir_id,
+            Path::new(&remote_index_file.filename),
+            remote_block_chain,
+            local_index_file.detail.block_chain.as_ref(),
+        )
+        .await?;
 
-                create_conflict_file_from(&origin_file, self.sync_dir, &remote_index_file.filename)
-                    .await?;
+        temp_file
+            .set_len(file_size)
+            .await
+            .tap_err(|err| error!(%err, "set temp file size failed"))?;
 
-                info!(filename = ?remote_index_file.filename, "create conflict file done");
-            }
+        let mut download_block_requests = blocks_diff.download_block_requests;
+        let prefetched_blocks = self
+            .prefetch_store
+            .take(remote_index_file, &mut download_block_requests);
 
-            let remote_block_chain = match &remote_index_file.detail.block_chain {
-                None => {
-                    error!(filename = ?remote_index_file.filename, "index file doesn't have block chain");
+        let block_stream = self
+            .download_blocks(
+                &remote_index_file.filename,
+                &download_block_requests,
+                prefetched_blocks,
+            )
+            .await?;
 
-                    return Err(anyhow!(
-                        "{:?} index file doesn't have block chain",
-                        remote_index_file.filename
-                    ));
-                }
+        info!(?download_block_requests, "get block stream done");
 
-                Some(block_chain) => block_chain,
-            };
+        if !sync_file(
+            &remote_index_file.filename,
+            &remote_index_file.detail.hash_sum,
+            &temp_file,
+            origin_file.as_ref().map(|origin_file| LocalBlocks {
+                file: origin_file,
+                copy_blocks: &blocks_diff.copy_blocks,
+            }),
+            block_stream,
+            &self.options,
+            &self.timings,
+        )
+        .await?
+        {
+            warn!(filename = ?log_path(&remote_index_file.filename), "sync file canceled");
 
-            let file_size = remote_block_chain
-                .blocks
-                .iter()
-                .map(|block| block.len)
-                .sum::<u64>();
-            let mut temp_file = AsyncTempFile::create(self.sync_dir)
-                .await
-                .tap_err(|err| error!(%err, "create temp file failed"))?;
+            return Ok(false);
+        }
 
-            info!("create temp file done");
+        info!("sync file data done");
 
-            temp_file
-                .set_len(file_size)
-                .await
-                .tap_err(|err| error!(%err, "set temp file size failed"))?;
+        temp_file.close();
+        let temp_path = temp_file.path();
 
-            let download_block_requests = blocks_to_download_block_requests(
-                self.dir_id,
-                Path::new(&remote_index_file.filename),
-                &remote_block_chain.blocks,
-            );
+        let _dir_guard = self.dir_lock.read().await;
+        create_parent_dirs(self.sync_dir, Path::new(&remote_index_file.filename)).await?;
 
-            let block_stream = self
-                .download_transfer
-                .download(&download_block_requests)
-                .await
-                .map_err(Into::into)?
-                .map_err(Into::into);
+        if !remove_replaced_dir(&path).await? {
+            return Ok(false);
+        }
 
-            info!(?download_block_requests, "get block stream done");
+        self.release_local_file(&remote_index_file.filename).await?;
+        self.timings
+            .time(
+                &remote_index_file.filename,
+                SyncStage::Rename,
+                fs::rename(temp_path, &path),
+            )
+            .await
+            .tap_err(
+                |err| error!(%err, temp_path = ?log_path(&temp_path), path = ?log_path(&path), "rename temp file to target file failed"),
+            )?;
 
-            sync_file(&remote_index_file.filename, &temp_file, block_stream).await?;
+        info!(temp_path = ?log_path(&temp_path), path = ?log_path(&path), "rename temp file to target file done");
+
+        apply_file_metadata(
+            &path,
+            remote_index_file.detail.metadata.as_ref(),
+            &self.options,
+        )
+        .await;
+
+        self.timings
+            .time(
+                &remote_index_file.filename,
+                SyncStage::IndexCommit,
+                index_guard.commit(),
+            )
+            .await
+            .map_err(SyncError::index)?;
 
-            info!("sync file data done");
+        info!("index guard commit done");
 
-            temp_file.close();
-            let temp_path = temp_file.path();
+        Ok(true)
+    }
 
-            fs::rename(temp_path, &path).await.tap_err(
-                |err| error!(%err, ?temp_path, ?path, "rename temp file to target file failed"),
-            )?;
+    /// the update time of the rumor is later than the local clock by more than the allowed clock
+    /// skew, or earlier by more than the allowed age. The age isn't limited by default, the anti
+    /// entropy also sends the rumors of the long unchanged files
+    fn is_stale_rumor(&self, remote_index_file: &IndexFile) -> bool {
+        let now = self.clock.now();
+        let max_skew = self.options.max_rumor_clock_skew;
+        let max_age = self.options.max_rumor_age;
+
+        let stale = match remote_index_file.update_time.duration_since(now) {
+            Ok(ahead) => !max_skew.is_zero() && ahead > max_skew,
+            Err(err) => !max_age.is_zero() && err.duration() > max_age,
+        };
+        if stale {
+            warn!(
+                filename = ?log_path(&remote_index_file.filename),
+                update_by = %remote_index_file.update_by,
+                update_time = ?remote_index_file.update_time,
+                ?now,
+                "rumor update time is out of the allowed clock skew or age, reject it"
+            );
+        }
 
-            info!(?temp_path, ?path, "rename temp file to target file done");
+        stale
+    }
 
-            index_guard.commit().await?;
+    /// tell the sender the local file index, so the sender stops spreading the rejected gen
+    /// and its user can notice the wrong clock
+    async fn reply_stale_rumor(&self, remote_index_file: &IndexFile) -> Result<()> {
+        if self.dry_run.is_some() {
+            return Ok(());
+        }
 
-            info!("index guard commit done");
+        if let Some(local_index_file) = self
+            .index
+            .get_file(&remote_index_file.filename)
+            .await
+            .map_err(SyncError::index)?
+        {
+            info!(filename = ?log_path(&remote_index_file.filename), "reply the local file index of the stale rumor");
 
-            return Ok(true);
+            self.outdated_replies.lock().unwrap().push(local_index_file);
         }
 
-        warn!(
-            filename = ?remote_index_file.filename,
-            "remote and local change file at the same time, that's so hard to sync, we only can warn and ingore it"
-        );
-
-        Ok(false)
+        Ok(())
     }
 
-    fn handle_local_is_latest(
-        &mut self,
-        remote_index_file: &IndexFile,
-        local_index_file: &IndexFile,
-    ) {
+    fn handle_local_is_latest(&self, remote_index_file: &IndexFile, local_index_file: &IndexFile) {
         // rumor is old
-        if local_index_file
-            .previous_details
-            .iter()
-            .any(|previous_detail| {
-                previous_detail.gen == remote_index_file.detail.gen
-                    && previous_detail.hash_sum == remote_index_file.detail.hash_sum
-            })
-        {
+        if descends_from(local_index_file, remote_index_file) {
             info!("rumor is old, ignore");
         } else {
-            info!("rumor is conflict but old, still ignore");
+            // the sender never sees the local gen, tell it the file is outdated, so it can pull the
+            // newer gen instead of waiting for the rumor spreading
+            info!("rumor is conflict but old, reply the local file index");
+
+            self.outdated_replies
+                .lock()
+                .unwrap()
+                .push(local_index_file.clone());
         }
     }
 
     async fn handle_remote_is_latest(
-        &mut self,
+        &self,
         remote_index_file: &IndexFile,
         local_index_file: &IndexFile,
         mut index_guard: I::Guard,
     ) -> Result<bool> {
+        // both sides have made the same change, no need to touch the file
+        if is_same_content(remote_index_file, local_index_file) {
+            return self
+                .handle_same_content(remote_index_file, index_guard)
+                .await;
+        }
+
         // remote is latest and no conflict, can apply directly
         let path = self.sync_dir.join(&remote_index_file.filename);
 
-        if remote_index_file
-            .previous_details
-            .iter()
-            .any(|previous_detail| {
-                previous_detail.gen == local_index_file.detail.gen
-                    && previous_detail.hash_sum == local_index_file.detail.hash_sum
-            })
-        {
-            index_guard.update_file(remote_index_file).await?;
+        if descends_from(remote_index_file, local_index_file) {
+            index_guard
+                .update_file(remote_index_file)
+                .await
+                .map_err(SyncError::index)?;
 
-            info!(filename = ?remote_index_file.filename, "update file index done");
+            info!(filename = ?log_path(&remote_index_file.filename), "update file index done");
 
             // file has been deleted
             if remote_index_file.detail.deleted {
-                match fs::remove_file(&path).await {
-                    Err(err) if err.kind() == ErrorKind::NotFound => {
-                        info!(?path, "file may have been deleted");
-                    }
-
-                    Err(err) => {
-                        error!(%err, ?path, "delete file failed");
-
-                        return Err(err.into());
-                    }
+                self.release_local_file(&remote_index_file.filename).await?;
+                remove_local_file(&path).await?;
 
-                    Ok(_) => {}
-                }
+                self.remove_empty_dirs(&remote_index_file.filename).await;
 
-                index_guard.commit().await?;
+                index_guard.commit().await.map_err(SyncError::index)?;
 
                 info!("index guard commit done");
 
                 return Ok(true);
             }
 
-            let mut temp_file = AsyncTempFile::create(self.sync_dir)
-                .await
-                .tap_err(|err| error!(%err, ?path, "open temp file failed"))?;
-
-            info!(?path, "open temp file done");
-
-            let file = File::open(&path)
-                .await
-                .tap_err(|err| error!(%err, ?path, "open target file failed"))?;
-
-            info!(?path, "open target file done");
-
-            let metadata = file
-                .metadata()
+            let mut temp_file = AsyncTempFile::create(self.sync_dir, &self.id_source.temp_name())
                 .await
-                .tap_err(|err| error!(%err, ?path, "get target origin file metadata failed"))?;
+                .tap_err(|err| error!(%err, path = ?log_path(&path), "open temp file failed"))?;
 
-            info!("get target origin file metadata done");
-
-            file.copy(&temp_file, 0, 0, metadata.len())
-                .await
-                .tap_err(|err| error!(%err, "copy origin file data to temp file failed"))?;
+            info!(path = ?log_path(&path), "open temp file done");
 
-            drop(file);
+            let origin_file =
+                open_origin_file(&path, local_index_file, self.options.no_atime).await?;
 
             let remote_block_chain = match &remote_index_file.detail.block_chain {
                 None => {
-                    error!(filename = ?remote_index_file.filename, "index file doesn't have block chain");
+                    error!(filename = ?log_path(&remote_index_file.filename), "index file doesn't have block chain");
 
-                    return Err(anyhow!(
-                        "{:?} index file doesn't have block chain",
-                        remote_index_file.filename
-                    ));
+                    return Err(SyncError::Transfer(
+                        format!(
+                            "{:?} index file doesn't have block chain",
+                            remote_index_file.filename
+                        )
+                        .into(),
+                    )
+                    .into());
                 }
 
                 Some(block_chain) => block_chain,
@@ -448,74 +2088,136 @@ where
                 .map(|block| block.len)
                 .sum();
 
+            let blocks_diff = diff_with_local_file(
+                origin_file.as_ref(),
+                &temp_file,
+                self.dir_id,
+                Path::new(&remote_index_file.filename),
+                remote_block_chain,
+                local_index_file.detail.block_chain.as_ref(),
+            )
+            .await?;
+
             temp_file
                 .set_len(file_size)
                 .await
                 .tap_err(|err| error!(%err, "set temp file size failed"))?;
 
-            let download_block_requests = match &local_index_file.detail.block_chain {
-                None => blocks_to_download_block_requests(
-                    self.dir_id,
-                    Path::new(&remote_index_file.filename),
-                    &remote_block_chain.blocks,
-                ),
-
-                Some(local_block_chain) => compare_blocks(
-                    self.dir_id,
-                    Path::new(&remote_index_file.filename),
-                    &remote_block_chain.blocks,
-                    &local_block_chain.blocks,
-                ),
-            };
+            let mut download_block_requests = blocks_diff.download_block_requests;
+            let prefetched_blocks = self
+                .prefetch_store
+                .take(remote_index_file, &mut download_block_requests);
 
             let block_stream = self
-                .download_transfer
-                .download(&download_block_requests)
-                .await
-                .map_err(Into::into)?
-                .map_err(Into::into);
+                .download_blocks(
+                    &remote_index_file.filename,
+                    &download_block_requests,
+                    prefetched_blocks,
+                )
+                .await?;
 
             info!(?download_block_requests, "get block stream done");
 
-            sync_file(&remote_index_file.filename, &temp_file, block_stream).await?;
+            if !sync_file(
+                &remote_index_file.filename,
+                &remote_index_file.detail.hash_sum,
+                &temp_file,
+                origin_file.as_ref().map(|origin_file| LocalBlocks {
+                    file: origin_file,
+                    copy_blocks: &blocks_diff.copy_blocks,
+                }),
+                block_stream,
+                &self.options,
+                &self.timings,
+            )
+            .await?
+            {
+                warn!(filename = ?log_path(&remote_index_file.filename), "sync file canceled");
+
+                return Ok(false);
+            }
 
-            info!(?path, "sync file data done");
+            info!(path = ?log_path(&path), "sync file data done");
 
             temp_file.close();
             let temp_file_path = temp_file.path();
 
-            fs::rename(temp_file_path, &path)
-                .await
-                .tap_err(|err| error!(%err, ?path, "move temp file to target file failed"))?;
+            let _dir_guard = self.dir_lock.read().await;
+            create_parent_dirs(self.sync_dir, Path::new(&remote_index_file.filename)).await?;
 
-            info!(?path, "move temp file to target file done");
+            if !remove_replaced_dir(&path).await? {
+                return Ok(false);
+            }
 
-            index_guard.commit().await?;
+            self.release_local_file(&remote_index_file.filename).await?;
+            self.timings
+                .time(
+                    &remote_index_file.filename,
+                    SyncStage::Rename,
+                    fs::rename(temp_file_path, &path),
+                )
+                .await
+                .tap_err(
+                    |err| error!(%err, path = ?log_path(&path), "move temp file to target file failed"),
+                )?;
+
+            info!(path = ?log_path(&path), "move temp file to target file done");
+
+            apply_file_metadata(
+                &path,
+                remote_index_file.detail.metadata.as_ref(),
+                &self.options,
+            )
+            .await;
+
+            self.timings
+                .time(
+                    &remote_index_file.filename,
+                    SyncStage::IndexCommit,
+                    index_guard.commit(),
+                )
+                .await
+                .map_err(SyncError::index)?;
 
             info!("index guard commit done");
 
             return Ok(true);
         }
 
-        // remote file and local file is conflict, need copy the local file as conflict file then
-        // apply the remote file
-        let origin_file = File::open(&path)
-            .await
-            .tap_err(|err| error!(%err, "open target origin file failed"))?;
+        // remote file and local file is conflict, the resolver decides whether the local file is
+        // kept, copied as a conflict file or replaced by the remote file
+        let resolution = self
+            .resolve_conflict(remote_index_file, local_index_file)
+            .await;
+        if resolution == Resolution::Local {
+            return self
+                .keep_local_version(remote_index_file, local_index_file, index_guard)
+                .await;
+        }
 
-        info!(?path, "open target origin file done");
-        create_conflict_file_from(&origin_file, self.sync_dir, &remote_index_file.filename).await?;
+        let origin_file = open_origin_file(&path, local_index_file, self.options.no_atime).await?;
 
-        info!(origin_filename = ?remote_index_file.filename, "create conflict file done");
+        if let Some(origin_file) = &origin_file {
+            if resolution == Resolution::Both {
+                self.create_conflict_file(origin_file, local_index_file)
+                    .await?;
+
+                info!(origin_filename = ?log_path(&remote_index_file.filename), "create conflict file done");
+            }
+        }
 
         let remote_block_chain = match &remote_index_file.detail.block_chain {
             None => {
-                error!(filename = ?remote_index_file.filename, "index file doesn't have block chain");
+                error!(filename = ?log_path(&remote_index_file.filename), "index file doesn't have block chain");
 
-                return Err(anyhow!(
-                    "{:?} index file doesn't have block chain",
-                    remote_index_file.filename
-                ));
+                return Err(SyncError::Transfer(
+                    format!(
+                        "{:?} index file doesn't have block chain",
+                        remote_index_file.filename
+                    )
+                    .into(),
+                )
+                .into());
             }
 
             Some(remote_block_chain) => remote_block_chain,
@@ -527,50 +2229,150 @@ where
             .map(|block| block.len)
             .sum::<u64>();
 
-        let mut temp_file = AsyncTempFile::create(self.sync_dir)
+        let mut temp_file = AsyncTempFile::create(self.sync_dir, &self.id_source.temp_name())
             .await
             .tap_err(|err| error!(%err, "create temp file failed"))?;
 
         info!("create temp file done");
 
+        let blocks_diff = diff_with_local_file(
+            origin_file.as_ref(),
+            &temp_file,
+            self.dir_id,
+            Path::new(&remote_index_file.filename),
+            remote_block_chain,
+            local_index_file.detail.block_chain.as_ref(),
+        )
+        .await?;
+
         temp_file
             .set_len(file_size)
             .await
             .tap_err(|err| error!(%err, "set temp file size failed"))?;
 
-        let download_block_requests = blocks_to_download_block_requests(
-            self.dir_id,
-            Path::new(&remote_index_file.filename),
-            &remote_block_chain.blocks,
-        );
+        let mut download_block_requests = blocks_diff.download_block_requests;
+        let prefetched_blocks = self
+            .prefetch_store
+            .take(remote_index_file, &mut download_block_requests);
 
         let block_stream = self
-            .download_transfer
-            .download(&download_block_requests)
-            .await
-            .map_err(Into::into)?
-            .map_err(Into::into);
+            .download_blocks(
+                &remote_index_file.filename,
+                &download_block_requests,
+                prefetched_blocks,
+            )
+            .await?;
 
         info!(?download_block_requests, "get block stream done");
 
-        sync_file(&remote_index_file.filename, &temp_file, block_stream).await?;
+        if !sync_file(
+            &remote_index_file.filename,
+            &remote_index_file.detail.hash_sum,
+            &temp_file,
+            origin_file.as_ref().map(|origin_file| LocalBlocks {
+                file: origin_file,
+                copy_blocks: &blocks_diff.copy_blocks,
+            }),
+            block_stream,
+            &self.options,
+            &self.timings,
+        )
+        .await?
+        {
+            warn!(filename = ?log_path(&remote_index_file.filename), "sync file canceled");
+
+            return Ok(false);
+        }
 
-        info!(?path, "sync file data done");
+        info!(path = ?log_path(&path), "sync file data done");
 
         temp_file.close();
         let temp_path = temp_file.path();
 
-        fs::rename(temp_path, &path)
+        let _dir_guard = self.dir_lock.read().await;
+        create_parent_dirs(self.sync_dir, Path::new(&remote_index_file.filename)).await?;
+
+        if !remove_replaced_dir(&path).await? {
+            return Ok(false);
+        }
+
+        self.release_local_file(&remote_index_file.filename).await?;
+        self.timings
+            .time(
+                &remote_index_file.filename,
+                SyncStage::Rename,
+                fs::rename(temp_path, &path),
+            )
             .await
             .tap_err(|err| error!(%err, "move temp file to target file failed"))?;
 
-        index_guard.commit().await?;
+        apply_file_metadata(
+            &path,
+            remote_index_file.detail.metadata.as_ref(),
+            &self.options,
+        )
+        .await;
+
+        self.timings
+            .time(
+                &remote_index_file.filename,
+                SyncStage::IndexCommit,
+                index_guard.commit(),
+            )
+            .await
+            .map_err(SyncError::index)?;
+
+        info!("index guard commit done");
+
+        Ok(true)
+    }
+
+    /// the local file content is same as the remote one, only update the file index
+    async fn handle_same_content(
+        &self,
+        remote_index_file: &IndexFile,
+        mut index_guard: I::Guard,
+    ) -> Result<bool> {
+        index_guard
+            .update_file(remote_index_file)
+            .await
+            .map_err(SyncError::index)?;
+
+        info!(filename = ?log_path(&remote_index_file.filename), "file content is same, update file index done");
+
+        index_guard.commit().await.map_err(SyncError::index)?;
 
         info!("index guard commit done");
 
         Ok(true)
     }
 
+    /// remove the parent dirs of the deleted file which become empty when the empty dir policy is
+    /// prune, the sync dir itself is never removed
+    async fn remove_empty_dirs(&self, filename: &OsStr) {
+        if self.options.empty_dir_policy != EmptyDirPolicy::Prune {
+            return;
+        }
+
+        let _dir_guard = self.dir_lock.write().await;
+
+        let mut parent = Path::new(filename).parent();
+        while let Some(dir) = parent.filter(|dir| !dir.as_os_str().is_empty()) {
+            let path = self.sync_dir.join(dir);
+
+            // remove_dir fails when the dir is not empty, so only the empty dirs are removed
+            if let Err(err) = fs::remove_dir(&path).await {
+                info!(%err, path = ?log_path(&path), "dir is not removed, stop removing empty dirs");
+
+                return;
+            }
+
+            info!(path = ?log_path(&path), "remove empty dir done");
+
+            parent = dir.parent();
+        }
+    }
+
     async fn send_rumors_to_others(
         &mut self,
         sender_id: Uuid,
@@ -579,22 +2381,51 @@ where
         let send_rumors = SendRumors {
             dir_id: self.dir_id,
             rumors,
+            dir_renames: mem::take(&mut self.dir_renames),
             except: Some(sender_id),
+            to: None,
+            fanout: self.options.fanout,
+        };
+
+        self.rumor_sender
+            .send(send_rumors)
+            .await
+            .map_err(SyncError::transfer)?;
+
+        Ok(())
+    }
+
+    async fn reply_outdated_rumors(
+        &mut self,
+        sender_id: Uuid,
+        rumors: Vec<IndexFile>,
+    ) -> Result<()> {
+        let send_rumors = SendRumors {
+            dir_id: self.dir_id,
+            rumors,
+            dir_renames: vec![],
+            except: None,
+            to: Some(sender_id),
+            fanout: self.options.fanout,
         };
 
-        self.rumor_sender.send(send_rumors).await?;
+        self.rumor_sender
+            .send(send_rumors)
+            .await
+            .map_err(SyncError::transfer)?;
 
         Ok(())
     }
 }
 
-fn blocks_to_download_block_requests<'a>(
+pub fn blocks_to_download_block_requests<'a>(
     dir_id: Uuid,
     filename: &'a Path,
     blocks: &'a [Block],
 ) -> Vec<DownloadBlockRequest> {
     blocks
         .iter()
+        .filter(|block| !block.zero)
         .map(|block| DownloadBlockRequest {
             dir_id,
             filename: filename.to_string_lossy().to_string(),
@@ -605,17 +2436,403 @@ fn blocks_to_download_block_requests<'a>(
         .collect()
 }
 
+/// the index errors which are caused by the other concurrent transactions, the other errors fail
+/// again when the rumor is handled alone
+fn is_index_contention(err: &SyncError) -> bool {
+    match err {
+        SyncError::Conflict(_) => true,
+        // the index errors may be wrapped by the io errors, such as the resume store errors
+        SyncError::Index(err) => err
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+            .unwrap_or(&**err)
+            .downcast_ref::<sqlite_index::Error>()
+            .is_some_and(sqlite_index::Error::is_busy),
+        _ => false,
+    }
+}
+
+/// the filename of a rumor must be a relative path inside the sync dir
+fn is_valid_filename(filename: &Path) -> bool {
+    filename.components().next().is_some()
+        && filename
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// create the missing parent dirs of the file inside the sync dir, the existing parents must be
+/// real dirs rather than symlinks, so the file can't be written outside the sync dir
+async fn create_parent_dirs(sync_dir: &Path, filename: &Path) -> io::Result<()> {
+    let parent = match filename.parent() {
+        None => return Ok(()),
+        Some(parent) => parent,
+    };
+
+    let mut dir = sync_dir.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+
+        match fs::symlink_metadata(&dir).await {
+            Ok(metadata) if metadata.is_dir() => continue,
+
+            Ok(_) => {
+                error!(dir = ?log_path(&dir), "parent is not a dir");
+
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    format!("parent {dir:?} is not a dir"),
+                ));
+            }
+
+            Err(err) if err.kind() == ErrorKind::NotFound => match fs::create_dir(&dir).await {
+                Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+                    error!(%err, dir = ?log_path(&dir), "create parent dir failed");
+
+                    return Err(err);
+                }
+
+                _ => {
+                    info!(dir = ?log_path(&dir), "create parent dir done");
+                }
+            },
+
+            Err(err) => {
+                error!(%err, dir = ?log_path(&dir), "get parent dir metadata failed");
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// both files exist with different content and they share no detail, so neither of them is
+/// derived from the other, the trimmed history may make an old file look like a created one
+fn is_create_create_conflict(remote_index_file: &IndexFile, local_index_file: &IndexFile) -> bool {
+    if remote_index_file.kind != FileKind::File
+        || local_index_file.kind != FileKind::File
+        || remote_index_file.detail.deleted
+        || local_index_file.detail.deleted
+        || is_same_content(remote_index_file, local_index_file)
+    {
+        return false;
+    }
+
+    let local_details = iter::once(&local_index_file.detail)
+        .chain(&local_index_file.previous_details)
+        .map(|detail| (detail.gen, detail.hash_sum))
+        .collect::<HashSet<_>>();
+
+    !iter::once(&remote_index_file.detail)
+        .chain(&remote_index_file.previous_details)
+        .any(|detail| local_details.contains(&(detail.gen, detail.hash_sum)))
+}
+
+/// when return true, the later file is changed from the earlier file. The versions tell it even
+/// when the history is pruned, the details recorded by the old peers are looked up in the previous
+/// details
+fn descends_from(later: &IndexFile, earlier: &IndexFile) -> bool {
+    if !later.detail.version.is_empty() && !earlier.detail.version.is_empty() {
+        return later.detail.version > earlier.detail.version;
+    }
+
+    later.previous_details.iter().any(|previous_detail| {
+        previous_detail.gen == earlier.detail.gen
+            && previous_detail.hash_sum == earlier.detail.hash_sum
+    })
+}
+
+/// the bytes of the remote blocks which the local file doesn't have
+fn transfer_bytes(remote_index_file: &IndexFile, local_index_file: Option<&IndexFile>) -> u64 {
+    let block_chain = match &remote_index_file.detail.block_chain {
+        None => return 0,
+        Some(block_chain) => block_chain,
+    };
+    let local_blocks = local_index_file
+        .and_then(|local_index_file| local_index_file.detail.block_chain.as_ref())
+        .map(|local_block_chain| {
+            local_block_chain
+                .blocks
+                .iter()
+                .map(|block| block.hash_sum)
+                .collect::<HashSet<_>>()
+        })
+        .unwrap_or_default();
+
+    block_chain
+        .blocks
+        .iter()
+        .filter(|block| !block.zero && !local_blocks.contains(&block.hash_sum))
+        .map(|block| block.len)
+        .sum()
+}
+
+fn is_same_content(remote_index_file: &IndexFile, local_index_file: &IndexFile) -> bool {
+    remote_index_file.kind == local_index_file.kind
+        && remote_index_file
+            .detail
+            .same_content(&local_index_file.detail)
+}
+
+/// open the local file whose content can be reused or kept as a conflict file, when return None,
+/// the local file is deleted, has no content or its path is replaced by another kind, such as a dir
+async fn open_origin_file(
+    path: &Path,
+    local_index_file: &IndexFile,
+    no_atime: bool,
+) -> io::Result<Option<File>> {
+    if local_index_file.detail.deleted || local_index_file.kind != FileKind::File {
+        return Ok(None);
+    }
+
+    match fs::metadata(path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            warn!(path = ?log_path(&path), "origin file not found");
+
+            return Ok(None);
+        }
+
+        Err(err) => {
+            error!(%err, path = ?log_path(&path), "get origin file metadata failed");
+
+            return Err(err);
+        }
+
+        Ok(metadata) if !metadata.is_file() => {
+            warn!(path = ?log_path(&path), "origin file is replaced by another kind");
+
+            return Ok(None);
+        }
+
+        Ok(_) => {}
+    }
+
+    let origin_file = open_read(path, no_atime)
+        .await
+        .tap_err(|err| error!(%err, path = ?log_path(&path), "open origin file failed"))?;
+
+    info!(path = ?log_path(&path), "open origin file done");
+
+    Ok(Some(origin_file))
+}
+
+/// remove the deleted file, the path may have been deleted or replaced by a dir locally, then
+/// there is nothing to remove
+async fn remove_local_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            info!(path = ?log_path(&path), "file may have been deleted");
+        }
+
+        Err(err) if err.kind() == ErrorKind::IsADirectory => {
+            info!(path = ?log_path(&path), "file has been replaced by a dir, keep the dir");
+        }
+
+        Err(err) => {
+            error!(%err, path = ?log_path(&path), "delete file failed");
+
+            return Err(err);
+        }
+
+        Ok(_) => {
+            info!(path = ?log_path(&path), "delete file done");
+        }
+    }
+
+    Ok(())
+}
+
+/// moving the synced file onto a dir fails, the rename replaces a file, a symlink or a special
+/// file, so only a dir needs to be removed, when return false, the dir still has files and the
+/// synced file can't be placed
+async fn remove_replaced_dir(path: &Path) -> io::Result<bool> {
+    match fs::symlink_metadata(path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(err) => {
+            error!(%err, path = ?log_path(&path), "get target file metadata failed");
+
+            return Err(err);
+        }
+        Ok(metadata) if !metadata.is_dir() => return Ok(true),
+        Ok(_) => {}
+    }
+
+    match fs::remove_dir(path).await {
+        Err(err) if err.kind() == ErrorKind::DirectoryNotEmpty => {
+            warn!(path = ?log_path(&path), "target is a dir which is not empty, can't replace it with the file");
+
+            Ok(false)
+        }
+
+        Err(err) => {
+            error!(%err, path = ?log_path(&path), "remove target dir failed");
+
+            Err(err)
+        }
+
+        Ok(_) => {
+            info!(path = ?log_path(&path), "remove target empty dir done");
+
+            Ok(true)
+        }
+    }
+}
+
+/// restore the permissions, the mtime and the ownership of the synced file, then apply the file
+/// creation options, the content is already in place, so a failure is only warned, such as the
+/// peer is not allowed to change the owner
+async fn apply_file_metadata(path: &Path, metadata: Option<&FileMetadata>, options: &SyncOptions) {
+    let file_creation = &options.file_creation;
+    let mode = metadata
+        .map(|metadata| metadata.mode)
+        .or(file_creation.default_mode);
+    if let Some(mode) = mode {
+        let mode = mode & !file_creation.umask;
+        let _ = fs::set_permissions(path, Permissions::from_mode(mode))
+            .await
+            .tap_err(
+                |err| warn!(%err, path = ?log_path(&path), mode, "set file permissions failed"),
+            );
+    }
+
+    if let Some(metadata) = metadata {
+        match File::open(path).await {
+            Err(err) => {
+                warn!(%err, path = ?log_path(&path), "open file for setting mtime failed");
+            }
+
+            Ok(file) => {
+                let _ =
+                    file.into_std().await.set_modified(metadata.mtime).tap_err(
+                        |err| warn!(%err, path = ?log_path(&path), "set file mtime failed"),
+                    );
+            }
+        }
+
+        if options.preserve_owner {
+            let _ = std::os::unix::fs::chown(path, metadata.uid, metadata.gid).tap_err(
+                |err| warn!(%err, path = ?log_path(&path), uid = ?metadata.uid, gid = ?metadata.gid, "set file owner failed"),
+            );
+        }
+
+        info!(path = ?log_path(&path), ?metadata, "apply file metadata done");
+    }
+
+    // the immutable flag is set at last, the file can't be changed after it
+    if file_creation.immutable || file_creation.no_dump {
+        let flags = InodeFlags {
+            immutable: file_creation.immutable,
+            no_dump: file_creation.no_dump,
+        };
+        let _ = set_inode_flags(path, flags)
+            .await
+            .tap_err(|err| warn!(%err, path = ?log_path(&path), ?flags, "set inode flags failed"));
+    }
+}
+
+/// when the local file exists, copy it into the temp file and diff the remote blocks with the
+/// local blocks, the unchanged blocks are kept and the moved blocks can be copied, otherwise all
+/// the remote blocks need to be downloaded
+async fn diff_with_local_file(
+    origin_file: Option<&File>,
+    temp_file: &File,
+    dir_id: Uuid,
+    filename: &Path,
+    remote_block_chain: &BlockChain,
+    local_block_chain: Option<&BlockChain>,
+) -> io::Result<BlocksDiff> {
+    match (origin_file, local_block_chain) {
+        (Some(origin_file), Some(local_block_chain)) => {
+            let metadata = origin_file
+                .metadata()
+                .await
+                .tap_err(|err| error!(%err, "get target origin file metadata failed"))?;
+
+            origin_file
+                .copy(temp_file, 0, 0, metadata.len())
+                .await
+                .tap_err(|err| error!(%err, "copy origin file data to temp file failed"))?;
+
+            // the blocks split by different block sizes never match, so the local file is split
+            // again by the remote block size
+            let local_blocks = if local_block_chain.block_size == remote_block_chain.block_size {
+                Cow::Borrowed(&local_block_chain.blocks)
+            } else {
+                let mut reader = origin_file
+                    .try_clone()
+                    .await
+                    .tap_err(|err| error!(%err, "clone origin file failed"))?;
+                reader
+                    .seek(SeekFrom::Start(0))
+                    .await
+                    .tap_err(|err| error!(%err, "seek origin file failed"))?;
+
+                let (_, block_chain) =
+                    hash_file_with_block_size(reader, remote_block_chain.block_size as _)
+                        .await
+                        .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+
+                info!(
+                    filename = ?log_path(filename),
+                    local_block_size = local_block_chain.block_size,
+                    remote_block_size = remote_block_chain.block_size,
+                    "rehash origin file by remote block size done"
+                );
+
+                Cow::Owned(block_chain.blocks)
+            };
+
+            // the copied local data may be inside the remote zero blocks
+            for range in zero_ranges(&remote_block_chain.blocks) {
+                sparse::punch_hole(temp_file, range.start, range.end - range.start)
+                    .await
+                    .tap_err(|err| error!(%err, ?range, "punch zero blocks hole failed"))?;
+            }
+
+            Ok(compare_blocks(
+                dir_id,
+                filename,
+                &remote_block_chain.blocks,
+                &local_blocks,
+            ))
+        }
+
+        _ => Ok(BlocksDiff {
+            copy_blocks: vec![],
+            download_block_requests: blocks_to_download_block_requests(
+                dir_id,
+                filename,
+                &remote_block_chain.blocks,
+            ),
+        }),
+    }
+}
+
+/// the ranges of the zero blocks, the adjacent zero blocks are merged
+fn zero_ranges(blocks: &[Block]) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = vec![];
+    for block in blocks.iter().filter(|block| block.zero) {
+        match ranges.last_mut() {
+            Some(range) if range.end == block.offset => range.end += block.len,
+            _ => ranges.push(block.offset..block.offset + block.len),
+        }
+    }
+
+    ranges
+}
+
 async fn create_conflict_file_from(
     origin_file: &File,
     sync_dir: &Path,
     filename: &OsStr,
-) -> io::Result<()> {
-    let now_str = Utc::now()
+    now: SystemTime,
+) -> io::Result<OsString> {
+    let now_str = DateTime::<Utc>::from(now)
         .with_timezone(&FixedOffset::east_opt(8 * 3600).expect("create fixed offset failed"))
         .format("%Y-%m-%d-%H-%M-%S");
     let mut filename = filename.to_os_string();
-    filename.push(format!(".{now_str}"));
-    filename.push(".conflict");
+    filename.push(format!(".{now_str}.{CONFLICT_EXTENSION}"));
 
     let conflict_file = OpenOptions::new()
         .read(true)
@@ -625,7 +2842,7 @@ async fn create_conflict_file_from(
         .await
         .tap_err(|err| error!(%err, "create conflict file failed"))?;
 
-    info!(?filename, "create conflict file done");
+    info!(filename = ?log_path(&filename), "create conflict file done");
 
     let metadata = origin_file
         .metadata()
@@ -637,25 +2854,97 @@ async fn create_conflict_file_from(
         .await
         .tap_err(|err| error!(%err, "copy target origin file data to conflict file failed"))?;
 
-    Ok(())
+    Ok(filename)
+}
+
+/// how the local file which is not synced yet adopts the remote file
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Adoption {
+    /// the untracked local file has the same content, only the index is created
+    Identical,
+
+    /// the untracked local file is not removed by the remote deletion
+    KeepUntracked,
+
+    /// the local created file becomes a newer version than the remote deletion
+    KeepLocal,
 }
 
-async fn sync_file<S: Stream<Item = io::Result<Option<DownloadBlock>>>>(
+/// the local file and the blocks which can be copied from it
+#[derive(Debug, Copy, Clone)]
+pub struct LocalBlocks<'a> {
+    pub file: &'a File,
+    pub copy_blocks: &'a [CopyBlock],
+}
+
+/// copy the local blocks and write the downloaded blocks into the file, when return false, a
+/// block can't be found or the assembled file doesn't match the hash sum, the file should be
+/// dropped
+pub async fn sync_file<S: Stream<Item = io::Result<Option<DownloadBlock>>>>(
     filename: &OsStr,
+    hash_sum: &Sha256sum,
     file: &File,
+    local_blocks: Option<LocalBlocks<'_>>,
     block_stream: S,
+    options: &SyncOptions,
+    timings: &SyncTimings,
 ) -> io::Result<bool> {
-    let futures_unordered = FuturesUnordered::new();
+    if let Some(local_blocks) = local_blocks {
+        for copy_block in local_blocks.copy_blocks {
+            local_blocks
+                .file
+                .copy(
+                    file,
+                    copy_block.src_offset,
+                    copy_block.dst_offset,
+                    copy_block.len,
+                )
+                .await
+                .tap_err(|err| error!(%err, ?copy_block, "copy local block failed"))?;
+        }
+
+        info!(
+            filename = ?log_path(&filename),
+            copy_blocks = local_blocks.copy_blocks.len(),
+            "copy local blocks done"
+        );
+    }
+
+    let mut futures_unordered = FuturesUnordered::new();
     let mut block_stream = pin!(block_stream.map_err(io::Error::from));
-    while let Some(download_block) = block_stream.try_next().await? {
+    loop {
+        let download_block = match timings
+            .time(filename, SyncStage::Download, block_stream.try_next())
+            .await
+        {
+            Err(err) => {
+                // finish the pending writes, so the received blocks can be resumed
+                let _ = futures_unordered.try_collect::<()>().await;
+
+                return Err(err);
+            }
+
+            Ok(None) => break,
+            Ok(Some(download_block)) => download_block,
+        };
+
         match download_block {
             None => {
-                warn!(?filename, "can't find block, maybe file is outdated");
+                warn!(filename = ?log_path(&filename), "can't find block, maybe file is outdated");
 
                 return Ok(false);
             }
 
             Some(download_block) => {
+                if options.write_concurrency > 0
+                    && futures_unordered.len() >= options.write_concurrency
+                {
+                    timings
+                        .time(filename, SyncStage::Write, futures_unordered.try_next())
+                        .await
+                        .tap_err(|err| error!(%err, "write at failed"))?;
+                }
+
                 futures_unordered.push(async move {
                     file.write_at(&download_block.data, download_block.offset)
                         .await
@@ -669,52 +2958,105 @@ async fn sync_file<S: Stream<Item = io::Result<Option<DownloadBlock>>>>(
         }
     }
 
-    futures_unordered
-        .try_collect()
+    timings
+        .time(filename, SyncStage::Write, futures_unordered.try_collect())
         .await
         .tap_err(|err| error!(%err, "write at failed"))?;
 
+    if options.paranoia_level == ParanoiaLevel::File {
+        let start = Instant::now();
+        let mut reader = file
+            .try_clone()
+            .await
+            .tap_err(|err| error!(%err, "clone file failed"))?;
+        reader
+            .seek(SeekFrom::Start(0))
+            .await
+            .tap_err(|err| error!(%err, "seek file failed"))?;
+
+        if file_hash_sum(reader).await? != *hash_sum {
+            error!(filename = ?log_path(&filename), "assembled file hash sum mismatch");
+
+            return Ok(false);
+        }
+
+        timings.record(filename, SyncStage::Verify, start.elapsed());
+
+        info!(filename = ?log_path(&filename), "verify assembled file done");
+    }
+
+    if options.fsync {
+        timings
+            .time(filename, SyncStage::Write, file.sync_all())
+            .await
+            .tap_err(|err| error!(%err, "fsync file failed"))?;
+    }
+
     Ok(true)
 }
 
-fn compare_blocks(
+/// a remote block which is found in the local file, so it can be copied instead of downloaded
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CopyBlock {
+    pub src_offset: u64,
+    pub dst_offset: u64,
+    pub len: u64,
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct BlocksDiff {
+    /// blocks exist in the local file but at different offsets
+    pub copy_blocks: Vec<CopyBlock>,
+    /// blocks don't exist in the local file
+    pub download_block_requests: Vec<DownloadBlockRequest>,
+}
+
+/// diff the remote blocks with the local blocks by the block hash sum, the blocks at the same
+/// offset are skipped, the blocks moved to other offsets are copied from the local file, so
+/// inserting data into the file won't make all the following blocks downloaded
+pub fn compare_blocks(
     dir_id: Uuid,
     filename: &Path,
-    left_blocks: &[Block],
-    right_blocks: &[Block],
-) -> Vec<DownloadBlockRequest> {
+    remote_blocks: &[Block],
+    local_blocks: &[Block],
+) -> BlocksDiff {
     let filename = filename.to_string_lossy().to_string();
+    let mut local_block_offsets = HashMap::with_capacity(local_blocks.len());
+    for local_block in local_blocks {
+        local_block_offsets
+            .entry((local_block.hash_sum, local_block.len))
+            .or_insert(local_block.offset);
+    }
 
-    left_blocks
-        .iter()
-        .zip_longest(right_blocks.iter())
-        .filter_map(|zip_result| match zip_result {
-            EitherOrBoth::Both(remote_block, local_block) => {
-                if *remote_block == *local_block {
-                    None
-                } else {
-                    Some(DownloadBlockRequest {
-                        dir_id,
-                        filename: filename.clone(),
-                        offset: remote_block.offset,
-                        len: remote_block.len,
-                        hash_sum: remote_block.hash_sum,
-                    })
-                }
-            }
-            EitherOrBoth::Left(remote_block) => Some(DownloadBlockRequest {
-                dir_id,
-                filename: filename.clone(),
-                offset: remote_block.offset,
+    let mut blocks_diff = BlocksDiff::default();
+    for (index, remote_block) in remote_blocks.iter().enumerate() {
+        // the zero blocks are punched as the holes
+        if remote_block.zero || local_blocks.get(index) == Some(remote_block) {
+            continue;
+        }
+
+        match local_block_offsets.get(&(remote_block.hash_sum, remote_block.len)) {
+            Some(&src_offset) => blocks_diff.copy_blocks.push(CopyBlock {
+                src_offset,
+                dst_offset: remote_block.offset,
                 len: remote_block.len,
-                hash_sum: remote_block.hash_sum,
             }),
 
-            // when this branch hit, all remaining blocks are right blocks when will be ignore
-            EitherOrBoth::Right(_) => None,
-        })
-        .collect::<Vec<_>>()
+            None => blocks_diff
+                .download_block_requests
+                .push(DownloadBlockRequest {
+                    dir_id,
+                    filename: filename.clone(),
+                    offset: remote_block.offset,
+                    len: remote_block.len,
+                    hash_sum: remote_block.hash_sum,
+                }),
+        }
+    }
+
+    blocks_diff
 }
 
+mod prefetch;
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::sync::Mutex;
+
+use crate::index::{IndexFile, Sha256sum};
+use crate::transfer::{DownloadBlock, DownloadBlockRequest};
+
+/// the blocks downloaded ahead for the queued rumors, the stored blocks never exceed the budget.
+/// A file is taken once, the prefetching of a taken file stops
+#[derive(Debug, Default)]
+pub struct PrefetchStore {
+    budget: u64,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    used: u64,
+    files: HashMap<OsString, PrefetchedFile>,
+    taken: HashSet<OsString>,
+}
+
+#[derive(Debug)]
+struct PrefetchedFile {
+    hash_sum: Sha256sum,
+    blocks: HashMap<u64, DownloadBlock>,
+}
+
+impl PrefetchStore {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            inner: Default::default(),
+        }
+    }
+
+    /// when return false, the file is prefetched or taken, it should not be prefetched again
+    pub fn begin(&self, filename: &OsStr, hash_sum: Sha256sum) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.taken.contains(filename) || inner.files.contains_key(filename) {
+            return false;
+        }
+
+        inner.files.insert(
+            filename.to_os_string(),
+            PrefetchedFile {
+                hash_sum,
+                blocks: Default::default(),
+            },
+        );
+
+        true
+    }
+
+    /// when return false, the file is taken or the budget is used up, the prefetching of the
+    /// file should stop
+    pub fn insert(&self, filename: &OsStr, block: DownloadBlock) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { used, files, .. } = &mut *inner;
+        let len = block.data.len() as u64;
+        if *used + len > self.budget {
+            return false;
+        }
+
+        match files.get_mut(filename) {
+            None => false,
+            Some(file) => {
+                if let Some(old_block) = file.blocks.insert(block.offset, block) {
+                    *used -= old_block.data.len() as u64;
+                }
+                *used += len;
+
+                true
+            }
+        }
+    }
+
+    /// take the prefetched blocks of the requests, the served requests are removed
+    pub fn take(
+        &self,
+        remote_index_file: &IndexFile,
+        download_block_requests: &mut Vec<DownloadBlockRequest>,
+    ) -> Vec<DownloadBlock> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.taken.insert(remote_index_file.filename.clone());
+
+        let mut file = match inner.files.remove(&remote_index_file.filename) {
+            None => return vec![],
+            Some(file) => file,
+        };
+        inner.used -= file
+            .blocks
+            .values()
+            .map(|block| block.data.len() as u64)
+            .sum::<u64>();
+
+        // the blocks of another content are useless
+        if file.hash_sum != remote_index_file.detail.hash_sum {
+            return vec![];
+        }
+
+        let mut blocks = Vec::with_capacity(file.blocks.len());
+        download_block_requests.retain(|request| match file.blocks.remove(&request.offset) {
+            Some(block) if block.data.len() as u64 == request.len => {
+                blocks.push(block);
+
+                false
+            }
+
+            _ => true,
+        });
+
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::SystemTime;
+
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::index::{Block, BlockChain, FileDetail, FileKind};
+    use crate::sync_control::rumors_event_handler::blocks_to_download_block_requests;
+
+    fn block(offset: u64, data: &'static [u8]) -> DownloadBlock {
+        DownloadBlock {
+            offset,
+            data: Bytes::from_static(data),
+        }
+    }
+
+    #[test]
+    fn take_prefetched_blocks() {
+        let blocks = [0, 4, 8].map(|offset| Block {
+            offset,
+            len: 4,
+            hash_sum: [offset as _; 32],
+            zero: false,
+        });
+        let index_file = IndexFile {
+            filename: OsString::from("test.txt"),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum: [1; 32],
+                block_chain: Some(BlockChain {
+                    block_size: 4,
+                    blocks: blocks.to_vec(),
+                }),
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::now(),
+            update_by: "test".to_string(),
+        };
+
+        let store = PrefetchStore::new(8);
+        let filename = OsStr::new("test.txt");
+        assert!(store.begin(filename, [1; 32]));
+        assert!(!store.begin(filename, [1; 32]));
+        assert!(store.insert(filename, block(0, b"test")));
+        assert!(store.insert(filename, block(4, b"data")));
+        // the budget is used up
+        assert!(!store.insert(filename, block(8, b"more")));
+
+        let mut requests =
+            blocks_to_download_block_requests(Uuid::new_v4(), Path::new("test.txt"), &blocks);
+        let prefetched = store.take(&index_file, &mut requests);
+        assert_eq!(prefetched, [block(0, b"test"), block(4, b"data")]);
+        assert_eq!(
+            requests
+                .iter()
+                .map(|request| request.offset)
+                .collect::<Vec<_>>(),
+            [8]
+        );
+
+        // the taken file is not prefetched again, and the budget is released
+        assert!(!store.begin(filename, [1; 32]));
+        assert!(!store.insert(filename, block(8, b"more")));
+        assert!(store.begin(OsStr::new("other.txt"), [2; 32]));
+        assert!(store.insert(OsStr::new("other.txt"), block(0, b"testdata")));
+    }
+}
@@ -1,6 +1,9 @@
 use std::ffi::OsString;
 use std::io::Cursor;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 use std::{env, future};
 
@@ -8,11 +11,20 @@ use bytes::Bytes;
 use futures_util::stream;
 use mockall::predicate::*;
 use tempfile::TempDir;
+use tokio::time;
 use tokio_stream::wrappers::ReadDirStream;
 
 use super::*;
+use crate::ext::hash::hash_file_with_block_size;
 use crate::ext::hash_file;
-use crate::index::{FileDetail, FileKind, MockIndex, MockIndexGuard};
+use crate::index::memory_index::MemoryIndex;
+use crate::index::resume::MockResumeStore;
+use crate::index::sqlite_index::{SqliteIndex, SqliteIndexOptions};
+use crate::index::{FileDetail, FileKind, FileMetadata, MockIndex, MockIndexGuard, VersionVector};
+use crate::journal::MockJournal;
+use crate::sync_control::conflict::MockConflictResolver;
+use crate::sync_control::options::{ConflictStrategy, Fanout, FileCreation};
+use crate::transfer::integrity::{IntegrityTransfer, PeerHealth};
 use crate::transfer::MockDownloadTransfer;
 
 #[tokio::test]
@@ -46,6 +58,9 @@ async fn local_not_exist() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }
                         && arg.previous_details.is_empty()
                 }))
@@ -80,6 +95,8 @@ async fn local_not_exist() {
     }
 
     let (sender, receiver) = flume::bounded(1);
+    let (progress_sender, progress_receiver) = flume::unbounded();
+    let timings = SyncTimings::default();
 
     let handler = RumorsEventHandler::new(
         user_id,
@@ -88,7 +105,9 @@ async fn local_not_exist() {
         &index,
         &download_transfer,
         sender.into_sink(),
-    );
+    )
+    .with_progress(ProgressReporter::new(progress_sender))
+    .with_timings(timings.clone());
 
     handler
         .handle_rumors_event(
@@ -101,6 +120,9 @@ async fn local_not_exist() {
                     hash_sum,
                     block_chain: Some(block_chain.clone()),
                     deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
                 },
                 previous_details: vec![],
                 update_time: SystemTime::now(),
@@ -123,6 +145,9 @@ async fn local_not_exist() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         }
     );
     assert!(rumor.previous_details.is_empty());
@@ -130,6 +155,20 @@ async fn local_not_exist() {
 
     let path = dir.path().join("test.txt");
     assert_eq!(fs::read(path).await.unwrap(), b"test");
+
+    let recent_timings = timings.recent();
+    assert_eq!(recent_timings.len(), 1);
+    assert_eq!(recent_timings[0].filename, "test.txt");
+    assert!(recent_timings[0].total() > Duration::ZERO);
+
+    assert_eq!(
+        progress_receiver.drain().collect::<Vec<_>>(),
+        [Progress::Downloading {
+            filename: OsString::from("test.txt"),
+            downloaded_bytes: 4,
+            total_bytes: 4,
+        }]
+    );
 }
 
 #[tokio::test]
@@ -164,12 +203,18 @@ async fn local_is_latest() {
                             hash_sum,
                             block_chain: Some(block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![FileDetail {
                             gen: 1,
                             hash_sum,
                             block_chain: None,
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         }],
                         update_time: SystemTime::now(),
                         update_by: local_user_id.as_hyphenated().to_string(),
@@ -204,6 +249,9 @@ async fn local_is_latest() {
                     hash_sum,
                     block_chain: Some(block_chain.clone()),
                     deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
                 },
                 previous_details: vec![],
                 update_time: SystemTime::now(),
@@ -216,6 +264,129 @@ async fn local_is_latest() {
     receiver.recv_async().await.unwrap_err();
 }
 
+#[tokio::test]
+async fn local_is_latest_reply_outdated() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+    let (other_hash_sum, other_block_chain) = hash_file(Cursor::new(b"other")).await.unwrap();
+    let (root_hash_sum, _) = hash_file(Cursor::new(b"root")).await.unwrap();
+
+    let local_index_file = IndexFile {
+        filename: OsString::from("test.txt"),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen: 3,
+            hash_sum,
+            block_chain: Some(block_chain),
+            deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![
+            FileDetail {
+                gen: 2,
+                hash_sum,
+                block_chain: None,
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            FileDetail {
+                gen: 1,
+                hash_sum: root_hash_sum,
+                block_chain: None,
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+        ],
+        update_time: SystemTime::now(),
+        update_by: local_user_id.as_hyphenated().to_string(),
+    };
+
+    {
+        let local_index_file = local_index_file.clone();
+
+        index.expect_begin().returning(move || {
+            let mut index_guard = MockIndexGuard::new();
+            let local_index_file = local_index_file.clone();
+
+            index_guard
+                .expect_get_file()
+                .with(eq(OsStr::new("test.txt")))
+                .returning(move |_| Ok(Some(local_index_file.clone())));
+
+            Ok(index_guard)
+        });
+    }
+
+    let download_transfer = MockDownloadTransfer::new();
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        local_user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    // the remote gen 2 is never seen by local
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 2,
+                    hash_sum: other_hash_sum,
+                    block_chain: Some(other_block_chain),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![FileDetail {
+                    gen: 1,
+                    hash_sum: root_hash_sum,
+                    block_chain: None,
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                }],
+                update_time: SystemTime::now(),
+                update_by: user_id.as_hyphenated().to_string(),
+            }],
+        )
+        .await
+        .unwrap();
+
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(
+        send_rumors,
+        SendRumors {
+            dir_id,
+            rumors: vec![local_index_file],
+            dir_renames: vec![],
+            except: None,
+            to: Some(user_id),
+            fanout: Fanout::All,
+        }
+    );
+}
+
 #[tokio::test]
 async fn remote_is_latest() {
     let dir = TempDir::new_in(env::temp_dir()).unwrap();
@@ -252,6 +423,9 @@ async fn remote_is_latest() {
                             hash_sum: old_hash_sum,
                             block_chain: Some(old_block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![],
                         update_time: SystemTime::UNIX_EPOCH,
@@ -269,6 +443,9 @@ async fn remote_is_latest() {
                                 hash_sum: new_hash_sum,
                                 block_chain: Some(new_block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![FileDetail {
@@ -276,6 +453,9 @@ async fn remote_is_latest() {
                                 hash_sum: old_hash_sum,
                                 block_chain: None,
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }]
                 }))
                 .returning(|_| Ok(()));
@@ -330,12 +510,18 @@ async fn remote_is_latest() {
                     hash_sum: new_hash_sum,
                     block_chain: Some(new_block_chain.clone()),
                     deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
                 },
                 previous_details: vec![FileDetail {
                     gen: 1,
                     hash_sum: old_hash_sum,
                     block_chain: None,
                     deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
                 }],
                 update_time: SystemTime::now(),
                 update_by: user_id.as_hyphenated().to_string(),
@@ -357,6 +543,9 @@ async fn remote_is_latest() {
             hash_sum: new_hash_sum,
             block_chain: Some(new_block_chain),
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -366,6 +555,9 @@ async fn remote_is_latest() {
             hash_sum: old_hash_sum,
             block_chain: None,
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         }]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
@@ -406,6 +598,9 @@ async fn local_remote_same() {
                             hash_sum,
                             block_chain: Some(block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![],
                         update_time,
@@ -441,6 +636,9 @@ async fn local_remote_same() {
                     hash_sum,
                     block_chain: Some(block_chain.clone()),
                     deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
                 },
                 previous_details: vec![],
                 update_time,
@@ -487,6 +685,9 @@ async fn eq_gen_local_latest() {
                             hash_sum,
                             block_chain: Some(block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![],
                         update_time: new_update_time,
@@ -522,6 +723,9 @@ async fn eq_gen_local_latest() {
                     hash_sum,
                     block_chain: Some(block_chain.clone()),
                     deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
                 },
                 previous_details: vec![],
                 update_time,
@@ -547,17 +751,30 @@ async fn eq_gen_remote_latest() {
     fs::write(dir.path().join("test.txt"), b"old")
         .await
         .unwrap();
-    let (old_hash_sum, old_block_chain) = hash_file(Cursor::new(b"new")).await.unwrap();
+    let (old_hash_sum, old_block_chain) = hash_file(Cursor::new(b"old")).await.unwrap();
     let (new_hash_sum, new_block_chain) = hash_file(Cursor::new(b"new")).await.unwrap();
+    let (root_hash_sum, _) = hash_file(Cursor::new(b"root")).await.unwrap();
+    let root_details = vec![FileDetail {
+        gen: 1,
+        hash_sum: root_hash_sum,
+        block_chain: None,
+        deleted: false,
+        metadata: None,
+        version: Default::default(),
+        link_group: None,
+    }];
 
     {
         let old_block_chain = old_block_chain.clone();
         let new_block_chain = new_block_chain.clone();
+        let root_details = root_details.clone();
 
         index.expect_begin().returning(move || {
             let mut index_guard = MockIndexGuard::new();
             let old_block_chain = old_block_chain.clone();
             let new_block_chain = new_block_chain.clone();
+            let local_root_details = root_details.clone();
+            let root_details = root_details.clone();
 
             index_guard
                 .expect_get_file()
@@ -567,12 +784,15 @@ async fn eq_gen_remote_latest() {
                         filename: OsString::from("test.txt"),
                         kind: FileKind::File,
                         detail: FileDetail {
-                            gen: 1,
+                            gen: 2,
                             hash_sum: old_hash_sum,
                             block_chain: Some(old_block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
-                        previous_details: vec![],
+                        previous_details: local_root_details.clone(),
                         update_time,
                         update_by: local_user_id.as_hyphenated().to_string(),
                     }))
@@ -585,12 +805,15 @@ async fn eq_gen_remote_latest() {
                         && arg.kind == FileKind::File
                         && arg.detail
                             == FileDetail {
-                                gen: 1,
+                                gen: 2,
                                 hash_sum: new_hash_sum,
                                 block_chain: Some(new_block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }
-                        && arg.previous_details.is_empty()
+                        && arg.previous_details == root_details
                         && arg.update_time == new_update_time
                         && arg.update_by == user_id.as_hyphenated().to_string()
                 }))
@@ -642,12 +865,15 @@ async fn eq_gen_remote_latest() {
                 filename: OsString::from("test.txt"),
                 kind: FileKind::File,
                 detail: FileDetail {
-                    gen: 1,
+                    gen: 2,
                     hash_sum: new_hash_sum,
                     block_chain: Some(new_block_chain.clone()),
                     deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
                 },
-                previous_details: vec![],
+                previous_details: root_details.clone(),
                 update_time: new_update_time,
                 update_by: user_id.as_hyphenated().to_string(),
             }],
@@ -664,13 +890,16 @@ async fn eq_gen_remote_latest() {
     assert_eq!(
         rumor.detail,
         FileDetail {
-            gen: 1,
+            gen: 2,
             hash_sum: new_hash_sum,
             block_chain: Some(new_block_chain),
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         }
     );
-    assert!(rumor.previous_details.is_empty());
+    assert_eq!(rumor.previous_details, root_details);
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
 
     let path = dir.path().join("test.txt");
@@ -692,6 +921,241 @@ async fn eq_gen_remote_latest() {
     dbg!(entry.file_name());
 }
 
+#[tokio::test]
+async fn eq_gen_remote_latest_prefer_remote() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let update_time = SystemTime::now();
+    let new_update_time = update_time + Duration::from_secs(1);
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"old")
+        .await
+        .unwrap();
+    let (old_hash_sum, old_block_chain) = hash_file(Cursor::new(b"old")).await.unwrap();
+    let (new_hash_sum, new_block_chain) = hash_file(Cursor::new(b"new")).await.unwrap();
+    let (root_hash_sum, _) = hash_file(Cursor::new(b"root")).await.unwrap();
+    let root_details = vec![FileDetail {
+        gen: 1,
+        hash_sum: root_hash_sum,
+        block_chain: None,
+        deleted: false,
+        metadata: None,
+        version: Default::default(),
+        link_group: None,
+    }];
+
+    let local_root_details = root_details.clone();
+    index.expect_begin().returning(move || {
+        let mut index_guard = MockIndexGuard::new();
+        let old_block_chain = old_block_chain.clone();
+        let local_root_details = local_root_details.clone();
+
+        index_guard
+            .expect_get_file()
+            .with(eq(OsStr::new("test.txt")))
+            .returning(move |_| {
+                Ok(Some(IndexFile {
+                    filename: OsString::from("test.txt"),
+                    kind: FileKind::File,
+                    detail: FileDetail {
+                        gen: 2,
+                        hash_sum: old_hash_sum,
+                        block_chain: Some(old_block_chain.clone()),
+                        deleted: false,
+                        metadata: None,
+                        version: Default::default(),
+                        link_group: None,
+                    },
+                    previous_details: local_root_details.clone(),
+                    update_time,
+                    update_by: Uuid::new_v4().as_hyphenated().to_string(),
+                }))
+            });
+        index_guard.expect_update_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer.expect_download().returning(|_| {
+        Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+            offset: 0,
+            data: Bytes::from_static(b"new"),
+        }))])))
+    });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_options(SyncOptions {
+        write_concurrency: 1,
+        conflict_strategy: ConflictStrategy::PreferRemote,
+        fsync: true,
+        ..Default::default()
+    });
+
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 2,
+                    hash_sum: new_hash_sum,
+                    block_chain: Some(new_block_chain),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: root_details,
+                update_time: new_update_time,
+                update_by: user_id.as_hyphenated().to_string(),
+            }],
+        )
+        .await
+        .unwrap();
+
+    receiver.recv_async().await.unwrap();
+
+    let path = dir.path().join("test.txt");
+    assert_eq!(fs::read(path).await.unwrap(), b"new");
+
+    // the local change is dropped without a conflict file
+    let read_dir = ReadDirStream::new(fs::read_dir(dir.path()).await.unwrap());
+    let filenames = read_dir
+        .map_ok(|entry| entry.file_name())
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(filenames, vec![OsString::from("test.txt")]);
+}
+
+#[tokio::test]
+async fn remote_latest_resolver_keeps_local() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let sender_id = Uuid::new_v4();
+    let update_time = SystemTime::now();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"local")
+        .await
+        .unwrap();
+    let (local_hash_sum, local_block_chain) = hash_file(Cursor::new(b"local")).await.unwrap();
+    let (remote_hash_sum, remote_block_chain) = hash_file(Cursor::new(b"remote")).await.unwrap();
+    let (root_hash_sum, _) = hash_file(Cursor::new(b"root")).await.unwrap();
+    let detail = |gen, hash_sum| FileDetail {
+        gen,
+        hash_sum,
+        block_chain: None,
+        deleted: false,
+        metadata: None,
+        version: Default::default(),
+        link_group: None,
+    };
+
+    let local_index_file = IndexFile {
+        filename: OsString::from("test.txt"),
+        kind: FileKind::File,
+        detail: FileDetail {
+            block_chain: Some(local_block_chain),
+            ..detail(2, local_hash_sum)
+        },
+        previous_details: vec![detail(1, root_hash_sum)],
+        update_time,
+        update_by: user_id.as_hyphenated().to_string(),
+    };
+    // the remote changed the file twice without seeing the local change
+    let remote_index_file = IndexFile {
+        filename: OsString::from("test.txt"),
+        kind: FileKind::File,
+        detail: FileDetail {
+            block_chain: Some(remote_block_chain),
+            ..detail(3, remote_hash_sum)
+        },
+        previous_details: vec![detail(1, root_hash_sum), detail(2, [0; 32])],
+        update_time: update_time + Duration::from_secs(1),
+        update_by: sender_id.as_hyphenated().to_string(),
+    };
+
+    {
+        let local_index_file = local_index_file.clone();
+        index.expect_begin().times(1).returning(move || {
+            let mut index_guard = MockIndexGuard::new();
+            let local_index_file = local_index_file.clone();
+
+            index_guard
+                .expect_get_file()
+                .returning(move |_| Ok(Some(local_index_file.clone())));
+            index_guard
+                .expect_update_file()
+                .times(1)
+                .withf(move |kept: &IndexFile| {
+                    kept.detail.gen == 4
+                        && kept.detail.hash_sum == local_hash_sum
+                        && kept
+                            .previous_details
+                            .iter()
+                            .map(|detail| (detail.gen, detail.hash_sum))
+                            .eq([
+                                (1, root_hash_sum),
+                                (2, local_hash_sum),
+                                (3, remote_hash_sum),
+                            ])
+                })
+                .returning(|_| Ok(()));
+            index_guard.expect_commit().times(1).returning(|| Ok(()));
+
+            Ok(index_guard)
+        });
+    }
+
+    let mut conflict_resolver = MockConflictResolver::new();
+    conflict_resolver
+        .expect_resolve()
+        .times(1)
+        .returning(|_| Resolution::Local);
+
+    // the remote file is never downloaded
+    let download_transfer = MockDownloadTransfer::new();
+    let (sender, receiver) = flume::bounded(1);
+
+    RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_conflict_resolver(&conflict_resolver)
+    .handle_rumors_event(sender_id, vec![remote_index_file])
+    .await
+    .unwrap();
+
+    // the kept local version is replied to the sender
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.to, Some(sender_id));
+    assert_eq!(send_rumors.rumors[0].detail.gen, 4);
+    receiver.recv_async().await.unwrap_err();
+
+    let path = dir.path().join("test.txt");
+    assert_eq!(fs::read(path).await.unwrap(), b"local");
+}
+
 #[tokio::test]
 async fn no_require_block() {
     let dir = TempDir::new_in(env::temp_dir()).unwrap();
@@ -723,6 +1187,9 @@ async fn no_require_block() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }
                         && arg.previous_details.is_empty()
                 }))
@@ -771,6 +1238,9 @@ async fn no_require_block() {
                     hash_sum,
                     block_chain: Some(block_chain.clone()),
                     deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
                 },
                 previous_details: vec![],
                 update_time: SystemTime::now(),
@@ -782,3 +1252,2580 @@ async fn no_require_block() {
 
     receiver.recv_async().await.unwrap_err();
 }
+
+#[test]
+fn compare_blocks_moved() {
+    let dir_id = Uuid::new_v4();
+    let block = |offset, hash| Block {
+        offset,
+        len: 4,
+        hash_sum: [hash; 32],
+        zero: false,
+    };
+
+    let local_blocks = [block(0, 1), block(4, 2), block(8, 3)];
+    // a block is inserted at the start and the last block is changed
+    let remote_blocks = [block(0, 4), block(4, 1), block(8, 2), block(12, 5)];
+
+    let blocks_diff = compare_blocks(dir_id, Path::new("test.txt"), &remote_blocks, &local_blocks);
+
+    assert_eq!(
+        blocks_diff.copy_blocks,
+        vec![
+            CopyBlock {
+                src_offset: 0,
+                dst_offset: 4,
+                len: 4,
+            },
+            CopyBlock {
+                src_offset: 4,
+                dst_offset: 8,
+                len: 4,
+            },
+        ]
+    );
+    assert_eq!(
+        blocks_diff.download_block_requests,
+        blocks_to_download_block_requests(
+            dir_id,
+            Path::new("test.txt"),
+            &[block(0, 4), block(12, 5)]
+        )
+    );
+
+    let blocks_diff = compare_blocks(dir_id, Path::new("test.txt"), &local_blocks, &local_blocks);
+    assert_eq!(blocks_diff, BlocksDiff::default());
+}
+
+#[tokio::test]
+async fn diff_zero_blocks() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let dir_id = Uuid::new_v4();
+    let local_content = b"aaaabbbbcccc";
+    let remote_content = b"aaaa\0\0\0\0\0\0\0\0dddd";
+
+    fs::write(dir.path().join("test.txt"), local_content)
+        .await
+        .unwrap();
+    let origin_file = File::open(dir.path().join("test.txt")).await.unwrap();
+    let temp_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(dir.path().join("temp"))
+        .await
+        .unwrap();
+
+    let (_, local_block_chain) = hash_file_with_block_size(Cursor::new(local_content), 4)
+        .await
+        .unwrap();
+    let (_, remote_block_chain) = hash_file_with_block_size(Cursor::new(remote_content), 4)
+        .await
+        .unwrap();
+    assert_eq!(
+        remote_block_chain
+            .blocks
+            .iter()
+            .map(|block| block.zero)
+            .collect::<Vec<_>>(),
+        [false, true, true, false, false]
+    );
+
+    let blocks_diff = diff_with_local_file(
+        Some(&origin_file),
+        &temp_file,
+        dir_id,
+        Path::new("test.txt"),
+        &remote_block_chain,
+        Some(&local_block_chain),
+    )
+    .await
+    .unwrap();
+
+    // the zero blocks are neither copied nor downloaded, only the trailing empty block is copied
+    assert_eq!(
+        blocks_diff.copy_blocks,
+        [CopyBlock {
+            src_offset: 12,
+            dst_offset: 16,
+            len: 0,
+        }]
+    );
+    assert_eq!(
+        blocks_diff.download_block_requests,
+        blocks_to_download_block_requests(
+            dir_id,
+            Path::new("test.txt"),
+            &remote_block_chain.blocks[3..4]
+        )
+    );
+    assert_eq!(
+        zero_ranges(&remote_block_chain.blocks),
+        [Range { start: 4, end: 12 }]
+    );
+
+    temp_file.set_len(16).await.unwrap();
+    assert_eq!(
+        fs::read(dir.path().join("temp")).await.unwrap(),
+        b"aaaa\0\0\0\0\0\0\0\0\0\0\0\0"
+    );
+}
+
+#[tokio::test]
+async fn remote_is_latest_relocate_blocks() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"aaaabbbb")
+        .await
+        .unwrap();
+
+    let (old_hash_sum, old_block_chain) = hash_file_with_block_size(Cursor::new(b"aaaabbbb"), 4)
+        .await
+        .unwrap();
+    // a block is inserted at the start
+    let (new_hash_sum, new_block_chain) =
+        hash_file_with_block_size(Cursor::new(b"ccccaaaabbbb"), 4)
+            .await
+            .unwrap();
+
+    index.expect_begin().returning(move || {
+        let mut index_guard = MockIndexGuard::new();
+        let old_block_chain = old_block_chain.clone();
+
+        index_guard.expect_get_file().returning(move |_| {
+            Ok(Some(IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 1,
+                    hash_sum: old_hash_sum,
+                    block_chain: Some(old_block_chain.clone()),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time: SystemTime::UNIX_EPOCH,
+                update_by: local_user_id.as_hyphenated().to_string(),
+            }))
+        });
+        index_guard.expect_update_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let mut download_transfer = MockDownloadTransfer::new();
+
+    {
+        let new_block_chain = new_block_chain.clone();
+
+        download_transfer
+            .expect_download()
+            .with(function(move |arg: &[DownloadBlockRequest]| {
+                blocks_to_download_block_requests(
+                    dir_id,
+                    Path::new("test.txt"),
+                    &new_block_chain.blocks[..1],
+                ) == arg
+            }))
+            .returning(|_| {
+                Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+                    offset: 0,
+                    data: Bytes::from_static(b"cccc"),
+                }))])))
+            });
+    }
+
+    let (sender, _receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 2,
+                    hash_sum: new_hash_sum,
+                    block_chain: Some(new_block_chain),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![FileDetail {
+                    gen: 1,
+                    hash_sum: old_hash_sum,
+                    block_chain: None,
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                }],
+                update_time: SystemTime::now(),
+                update_by: user_id.as_hyphenated().to_string(),
+            }],
+        )
+        .await
+        .unwrap();
+
+    let path = dir.path().join("test.txt");
+    assert_eq!(fs::read(path).await.unwrap(), b"ccccaaaabbbb");
+}
+
+#[tokio::test]
+async fn remote_block_size_differs() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"aaaabbbb")
+        .await
+        .unwrap();
+
+    // the local file is split by a larger block size
+    let (old_hash_sum, old_block_chain) = hash_file_with_block_size(Cursor::new(b"aaaabbbb"), 8)
+        .await
+        .unwrap();
+    // a block is inserted at the start, the local blocks are matched by the remote block size
+    let (new_hash_sum, new_block_chain) =
+        hash_file_with_block_size(Cursor::new(b"ccccaaaabbbb"), 4)
+            .await
+            .unwrap();
+
+    index.expect_begin().returning(move || {
+        let mut index_guard = MockIndexGuard::new();
+        let old_block_chain = old_block_chain.clone();
+
+        index_guard.expect_get_file().returning(move |_| {
+            Ok(Some(IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 1,
+                    hash_sum: old_hash_sum,
+                    block_chain: Some(old_block_chain.clone()),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time: SystemTime::UNIX_EPOCH,
+                update_by: local_user_id.as_hyphenated().to_string(),
+            }))
+        });
+        index_guard.expect_update_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let mut download_transfer = MockDownloadTransfer::new();
+
+    {
+        let new_block_chain = new_block_chain.clone();
+
+        download_transfer
+            .expect_download()
+            .with(function(move |arg: &[DownloadBlockRequest]| {
+                blocks_to_download_block_requests(
+                    dir_id,
+                    Path::new("test.txt"),
+                    &new_block_chain.blocks[..1],
+                ) == arg
+            }))
+            .returning(|_| {
+                Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+                    offset: 0,
+                    data: Bytes::from_static(b"cccc"),
+                }))])))
+            });
+    }
+
+    let (sender, _receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 2,
+                    hash_sum: new_hash_sum,
+                    block_chain: Some(new_block_chain),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![FileDetail {
+                    gen: 1,
+                    hash_sum: old_hash_sum,
+                    block_chain: None,
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                }],
+                update_time: SystemTime::now(),
+                update_by: user_id.as_hyphenated().to_string(),
+            }],
+        )
+        .await
+        .unwrap();
+
+    let path = dir.path().join("test.txt");
+    assert_eq!(fs::read(path).await.unwrap(), b"ccccaaaabbbb");
+}
+
+#[tokio::test]
+async fn remote_is_latest_same_content() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"test")
+        .await
+        .unwrap();
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+
+    {
+        let block_chain = block_chain.clone();
+
+        index.expect_begin().returning(move || {
+            let mut index_guard = MockIndexGuard::new();
+            let block_chain = block_chain.clone();
+
+            index_guard.expect_get_file().returning(move |_| {
+                Ok(Some(IndexFile {
+                    filename: OsString::from("test.txt"),
+                    kind: FileKind::File,
+                    detail: FileDetail {
+                        gen: 1,
+                        hash_sum,
+                        block_chain: Some(block_chain.clone()),
+                        deleted: false,
+                        metadata: None,
+                        version: Default::default(),
+                        link_group: None,
+                    },
+                    previous_details: vec![],
+                    update_time: SystemTime::UNIX_EPOCH,
+                    update_by: local_user_id.as_hyphenated().to_string(),
+                }))
+            });
+            index_guard
+                .expect_update_file()
+                .with(function(|arg: &IndexFile| arg.detail.gen == 2))
+                .returning(|_| Ok(()));
+            index_guard.expect_commit().returning(|| Ok(()));
+
+            Ok(index_guard)
+        });
+    }
+
+    // no block should be downloaded
+    let download_transfer = MockDownloadTransfer::new();
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    // both sides made the same change, so the remote previous details don't contain the local one
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 2,
+                    hash_sum,
+                    block_chain: Some(block_chain),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time: SystemTime::now(),
+                update_by: user_id.as_hyphenated().to_string(),
+            }],
+        )
+        .await
+        .unwrap();
+
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.rumors[0].detail.gen, 2);
+
+    let mut read_dir = fs::read_dir(dir.path()).await.unwrap();
+    let mut filenames = vec![];
+    while let Some(entry) = read_dir.next_entry().await.unwrap() {
+        filenames.push(entry.file_name());
+    }
+    assert_eq!(filenames, vec![OsString::from("test.txt")]);
+}
+
+#[tokio::test]
+async fn eq_gen_same_content() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let update_time = SystemTime::now();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"test")
+        .await
+        .unwrap();
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+
+    {
+        let block_chain = block_chain.clone();
+
+        index.expect_begin().returning(move || {
+            let mut index_guard = MockIndexGuard::new();
+            let block_chain = block_chain.clone();
+
+            index_guard.expect_get_file().returning(move |_| {
+                Ok(Some(IndexFile {
+                    filename: OsString::from("test.txt"),
+                    kind: FileKind::File,
+                    detail: FileDetail {
+                        gen: 1,
+                        hash_sum,
+                        block_chain: Some(block_chain.clone()),
+                        deleted: false,
+                        metadata: None,
+                        version: Default::default(),
+                        link_group: None,
+                    },
+                    previous_details: vec![],
+                    update_time,
+                    update_by: "a".to_string(),
+                }))
+            });
+            index_guard
+                .expect_update_file()
+                .with(function(|arg: &IndexFile| arg.update_by == "b"))
+                .returning(|_| Ok(()));
+            index_guard.expect_commit().returning(|| Ok(()));
+
+            Ok(index_guard)
+        });
+    }
+
+    // no block should be downloaded
+    let download_transfer = MockDownloadTransfer::new();
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    // remote and local made the same change at the same time
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 1,
+                    hash_sum,
+                    block_chain: Some(block_chain),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time,
+                update_by: "b".to_string(),
+            }],
+        )
+        .await
+        .unwrap();
+
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.rumors[0].update_by, "b");
+
+    let mut read_dir = fs::read_dir(dir.path()).await.unwrap();
+    let mut filenames = vec![];
+    while let Some(entry) = read_dir.next_entry().await.unwrap() {
+        filenames.push(entry.file_name());
+    }
+    assert_eq!(filenames, vec![OsString::from("test.txt")]);
+}
+
+#[tokio::test]
+async fn concurrent_versions_remote_latest() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let creator_id = Uuid::new_v4().as_hyphenated().to_string();
+    let dir_id = Uuid::new_v4();
+    let update_time = SystemTime::now();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"test")
+        .await
+        .unwrap();
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+
+    // the local changes the file twice, the remote deletes it once at the same time, the local
+    // gen is bigger but the versions are concurrent
+    let created = VersionVector::initial(&creator_id);
+    let local_version = created
+        .incremented(&local_user_id.as_hyphenated().to_string())
+        .incremented(&local_user_id.as_hyphenated().to_string());
+    let remote_index_file = IndexFile {
+        filename: OsString::from("test.txt"),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen: 2,
+            hash_sum: [0; 32],
+            block_chain: None,
+            deleted: true,
+            metadata: None,
+            version: created.incremented(&user_id.as_hyphenated().to_string()),
+            link_group: None,
+        },
+        previous_details: vec![],
+        update_time: update_time + Duration::from_secs(1),
+        update_by: user_id.as_hyphenated().to_string(),
+    };
+
+    {
+        let remote_index_file = remote_index_file.clone();
+
+        index.expect_begin().returning(move || {
+            let mut index_guard = MockIndexGuard::new();
+            let block_chain = block_chain.clone();
+            let local_version = local_version.clone();
+
+            index_guard
+                .expect_get_file()
+                .with(eq(OsStr::new("test.txt")))
+                .returning(move |_| {
+                    Ok(Some(IndexFile {
+                        filename: OsString::from("test.txt"),
+                        kind: FileKind::File,
+                        detail: FileDetail {
+                            gen: 3,
+                            hash_sum,
+                            block_chain: Some(block_chain.clone()),
+                            deleted: false,
+                            metadata: None,
+                            version: local_version.clone(),
+                            link_group: None,
+                        },
+                        previous_details: vec![],
+                        update_time,
+                        update_by: local_user_id.as_hyphenated().to_string(),
+                    }))
+                });
+            index_guard
+                .expect_update_file()
+                .with(eq(remote_index_file.clone()))
+                .times(1)
+                .returning(|_| Ok(()));
+            index_guard.expect_commit().returning(|| Ok(()));
+
+            Ok(index_guard)
+        });
+    }
+
+    let download_transfer = MockDownloadTransfer::new();
+
+    let (sender, _receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    handler
+        .handle_rumors_event(user_id, vec![remote_index_file])
+        .await
+        .unwrap();
+
+    assert!(!dir.path().join("test.txt").exists());
+}
+
+#[tokio::test]
+async fn create_parent_dirs() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+
+    index.expect_begin().returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer.expect_download().returning(|_| {
+        Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+            offset: 0,
+            data: Bytes::from_static(b"test"),
+        }))])))
+    });
+
+    let (sender, _receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![IndexFile {
+                filename: OsString::from("a/b/test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 1,
+                    hash_sum,
+                    block_chain: Some(block_chain),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time: SystemTime::now(),
+                update_by: user_id.as_hyphenated().to_string(),
+            }],
+        )
+        .await
+        .unwrap();
+
+    let path = dir.path().join("a/b/test.txt");
+    assert_eq!(fs::read(path).await.unwrap(), b"test");
+}
+
+#[tokio::test]
+async fn remove_empty_dirs() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::create_dir_all(dir.path().join("a/b")).await.unwrap();
+    fs::write(dir.path().join("a/other.txt"), b"other")
+        .await
+        .unwrap();
+    fs::write(dir.path().join("a/b/test.txt"), b"test")
+        .await
+        .unwrap();
+
+    index.expect_begin().returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let download_transfer = MockDownloadTransfer::new();
+
+    let (sender, _receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_options(SyncOptions {
+        empty_dir_policy: EmptyDirPolicy::Prune,
+        ..Default::default()
+    });
+
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![
+                IndexFile {
+                    filename: OsString::from("a/b/test.txt"),
+                    kind: FileKind::File,
+                    detail: FileDetail {
+                        gen: 1,
+                        hash_sum: [0; 32],
+                        block_chain: None,
+                        deleted: true,
+                        metadata: None,
+                        version: Default::default(),
+                        link_group: None,
+                    },
+                    previous_details: vec![],
+                    update_time: SystemTime::now(),
+                    update_by: user_id.as_hyphenated().to_string(),
+                },
+                // invalid filename is ignored
+                IndexFile {
+                    filename: OsString::from("../test.txt"),
+                    kind: FileKind::File,
+                    detail: FileDetail {
+                        gen: 1,
+                        hash_sum: [0; 32],
+                        block_chain: None,
+                        deleted: true,
+                        metadata: None,
+                        version: Default::default(),
+                        link_group: None,
+                    },
+                    previous_details: vec![],
+                    update_time: SystemTime::now(),
+                    update_by: user_id.as_hyphenated().to_string(),
+                },
+            ],
+        )
+        .await
+        .unwrap();
+
+    assert!(!dir.path().join("a/b").exists());
+    // a is not empty
+    assert!(dir.path().join("a/other.txt").exists());
+}
+
+#[tokio::test]
+async fn verify_assembled_file() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+
+    index.expect_begin().returning(move || {
+        let mut index_guard = MockIndexGuard::new();
+        index_guard
+            .expect_get_file()
+            .with(eq(OsStr::new("test.txt")))
+            .returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    // a wrong block which passes the transfer, like a bug in the offset math
+    download_transfer.expect_download().returning(|_| {
+        Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+            offset: 0,
+            data: Bytes::from_static(b"tesx"),
+        }))])))
+    });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_options(SyncOptions {
+        paranoia_level: ParanoiaLevel::File,
+        ..Default::default()
+    });
+
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 1,
+                    hash_sum,
+                    block_chain: Some(block_chain),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time: SystemTime::now(),
+                update_by: user_id.as_hyphenated().to_string(),
+            }],
+        )
+        .await
+        .unwrap();
+
+    receiver.recv_async().await.unwrap_err();
+    assert!(!dir.path().join("test.txt").exists());
+}
+
+async fn created_index_file(
+    content: &'static [u8],
+    update_time: SystemTime,
+    update_by: Uuid,
+) -> IndexFile {
+    let (hash_sum, block_chain) = hash_file(Cursor::new(content)).await.unwrap();
+
+    IndexFile {
+        filename: OsString::from("test.txt"),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen: 1,
+            hash_sum,
+            block_chain: Some(block_chain),
+            deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![],
+        update_time,
+        update_by: update_by.as_hyphenated().to_string(),
+    }
+}
+
+#[tokio::test]
+async fn create_create_conflict_remote_wins() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let update_time = SystemTime::now();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"local")
+        .await
+        .unwrap();
+    let local_index_file = created_index_file(b"local", update_time, local_user_id).await;
+    // the remote has a higher gen, but it never sees the local file
+    let mut remote_index_file =
+        created_index_file(b"remote", update_time + Duration::from_secs(1), user_id).await;
+    remote_index_file.detail.gen = 2;
+    remote_index_file.previous_details = vec![FileDetail {
+        gen: 1,
+        hash_sum: [1; 32],
+        block_chain: None,
+        deleted: false,
+        metadata: None,
+        version: Default::default(),
+        link_group: None,
+    }];
+
+    {
+        let local_index_file = local_index_file.clone();
+        let remote_index_file = remote_index_file.clone();
+
+        index.expect_begin().returning(move || {
+            let mut index_guard = MockIndexGuard::new();
+            let local_index_file = local_index_file.clone();
+
+            index_guard
+                .expect_get_file()
+                .with(eq(OsStr::new("test.txt")))
+                .returning(move |_| Ok(Some(local_index_file.clone())));
+            index_guard
+                .expect_update_file()
+                .with(eq(remote_index_file.clone()))
+                .returning(|_| Ok(()));
+            index_guard.expect_commit().returning(|| Ok(()));
+
+            Ok(index_guard)
+        });
+    }
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer.expect_download().returning(|_| {
+        Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+            offset: 0,
+            data: Bytes::from_static(b"remote"),
+        }))])))
+    });
+
+    let mut journal = MockJournal::new();
+    journal.expect_record().returning(|_, _, _| ());
+    journal.expect_record_timing().returning(|_, _| ());
+    {
+        let local_index_file = local_index_file.clone();
+        let remote_index_file = remote_index_file.clone();
+
+        journal
+            .expect_record_conflict()
+            .withf(move |conflict_dir_id, conflict| {
+                *conflict_dir_id == dir_id
+                    && conflict.kept == remote_index_file
+                    && conflict.conflict == local_index_file
+                    && conflict.conflict_filename.is_some()
+            })
+            .times(1)
+            .returning(|_, _| ());
+    }
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        local_user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_journal(&journal);
+
+    handler
+        .handle_rumors_event(user_id, vec![remote_index_file.clone()])
+        .await
+        .unwrap();
+
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.rumors, vec![remote_index_file]);
+    assert_eq!(send_rumors.except, Some(user_id));
+
+    assert_eq!(
+        fs::read(dir.path().join("test.txt")).await.unwrap(),
+        b"remote"
+    );
+
+    // the local content is preserved as a conflict file
+    let read_dir = ReadDirStream::new(fs::read_dir(dir.path()).await.unwrap());
+    let conflict_files = read_dir
+        .try_filter(|entry| future::ready(entry.file_name().as_bytes().ends_with(b".conflict")))
+        .map_ok(|entry| entry.path())
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(conflict_files.len(), 1);
+    assert_eq!(fs::read(&conflict_files[0]).await.unwrap(), b"local");
+}
+
+#[tokio::test]
+async fn create_create_conflict_local_wins() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let update_time = SystemTime::now();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"local")
+        .await
+        .unwrap();
+    let local_index_file = created_index_file(
+        b"local",
+        update_time + Duration::from_secs(1),
+        local_user_id,
+    )
+    .await;
+    let remote_index_file = created_index_file(b"remote", update_time, user_id).await;
+
+    {
+        let local_index_file = local_index_file.clone();
+
+        index.expect_begin().returning(move || {
+            let mut index_guard = MockIndexGuard::new();
+            let local_index_file = local_index_file.clone();
+
+            index_guard
+                .expect_get_file()
+                .with(eq(OsStr::new("test.txt")))
+                .returning(move |_| Ok(Some(local_index_file.clone())));
+
+            Ok(index_guard)
+        });
+    }
+
+    let download_transfer = MockDownloadTransfer::new();
+
+    let mut journal = MockJournal::new();
+    journal
+        .expect_record_conflict()
+        .withf(|_, conflict| conflict.conflict_filename.is_none())
+        .times(1)
+        .returning(|_, _| ());
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        local_user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_journal(&journal);
+
+    handler
+        .handle_rumors_event(user_id, vec![remote_index_file])
+        .await
+        .unwrap();
+
+    // the remote preserves its own content when it receives the reply
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.rumors, vec![local_index_file]);
+    assert_eq!(send_rumors.to, Some(user_id));
+
+    assert_eq!(
+        fs::read(dir.path().join("test.txt")).await.unwrap(),
+        b"local"
+    );
+}
+
+#[tokio::test]
+async fn apply_remote_metadata() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mtime = SystemTime::UNIX_EPOCH + Duration::new(1_000_000, 500);
+    let mut index = MockIndex::new();
+
+    let mut remote_index_file = created_index_file(b"test", SystemTime::now(), user_id).await;
+    remote_index_file.detail.metadata = Some(FileMetadata {
+        mode: 0o751,
+        mtime,
+        uid: None,
+        gid: None,
+    });
+
+    index.expect_begin().returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer.expect_download().returning(|_| {
+        Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+            offset: 0,
+            data: Bytes::from_static(b"test"),
+        }))])))
+    });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    handler
+        .handle_rumors_event(user_id, vec![remote_index_file])
+        .await
+        .unwrap();
+
+    receiver.recv_async().await.unwrap();
+
+    let metadata = fs::metadata(dir.path().join("test.txt")).await.unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o7777, 0o751);
+    assert_eq!(metadata.modified().unwrap(), mtime);
+}
+
+#[tokio::test]
+async fn remote_file_replaces_empty_dir() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::create_dir(dir.path().join("test.txt")).await.unwrap();
+    let remote_index_file = created_index_file(b"test", SystemTime::now(), user_id).await;
+
+    index.expect_begin().returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer.expect_download().returning(|_| {
+        Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+            offset: 0,
+            data: Bytes::from_static(b"test"),
+        }))])))
+    });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    handler
+        .handle_rumors_event(user_id, vec![remote_index_file.clone()])
+        .await
+        .unwrap();
+
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.rumors, vec![remote_index_file]);
+    assert_eq!(
+        fs::read(dir.path().join("test.txt")).await.unwrap(),
+        b"test"
+    );
+}
+
+#[tokio::test]
+async fn remote_file_not_replaces_non_empty_dir() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::create_dir(dir.path().join("test.txt")).await.unwrap();
+    fs::write(dir.path().join("test.txt/inner.txt"), b"inner")
+        .await
+        .unwrap();
+    let remote_index_file = created_index_file(b"test", SystemTime::now(), user_id).await;
+
+    index.expect_begin().returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().never();
+
+        Ok(index_guard)
+    });
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer.expect_download().returning(|_| {
+        Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+            offset: 0,
+            data: Bytes::from_static(b"test"),
+        }))])))
+    });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    handler
+        .handle_rumors_event(user_id, vec![remote_index_file])
+        .await
+        .unwrap();
+
+    receiver.recv_async().await.unwrap_err();
+    assert_eq!(
+        fs::read(dir.path().join("test.txt/inner.txt"))
+            .await
+            .unwrap(),
+        b"inner"
+    );
+}
+
+#[tokio::test]
+async fn remote_symlink_replaces_file() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"test")
+        .await
+        .unwrap();
+    let local_index_file = created_index_file(b"test", SystemTime::now(), user_id).await;
+    let remote_index_file = IndexFile {
+        filename: OsString::from("test.txt"),
+        kind: FileKind::Symlink,
+        detail: FileDetail {
+            gen: 2,
+            hash_sum: [0; 32],
+            block_chain: None,
+            deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![FileDetail {
+            block_chain: None,
+            ..local_index_file.detail.clone()
+        }],
+        update_time: SystemTime::now(),
+        update_by: user_id.as_hyphenated().to_string(),
+    };
+
+    {
+        let remote_index_file = remote_index_file.clone();
+
+        index.expect_begin().returning(move || {
+            let mut index_guard = MockIndexGuard::new();
+            let local_index_file = local_index_file.clone();
+
+            index_guard
+                .expect_get_file()
+                .returning(move |_| Ok(Some(local_index_file.clone())));
+            index_guard
+                .expect_update_file()
+                .with(eq(remote_index_file.clone()))
+                .returning(|_| Ok(()));
+            index_guard.expect_commit().returning(|| Ok(()));
+
+            Ok(index_guard)
+        });
+    }
+
+    let download_transfer = MockDownloadTransfer::new();
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    handler
+        .handle_rumors_event(user_id, vec![remote_index_file.clone()])
+        .await
+        .unwrap();
+
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.rumors, vec![remote_index_file]);
+    assert!(!dir.path().join("test.txt").exists());
+}
+
+#[tokio::test]
+async fn resume_interrupted_download() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    let (hash_sum, block_chain) = hash_file_with_block_size(Cursor::new(b"testdata"), 4)
+        .await
+        .unwrap();
+
+    index.expect_begin().returning(move || {
+        let mut index_guard = MockIndexGuard::new();
+        index_guard
+            .expect_get_file()
+            .with(eq(OsStr::new("test.txt")))
+            .returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let saved_partial = Arc::new(Mutex::new(None::<PartialDownload>));
+    let mut resume_store = MockResumeStore::new();
+    {
+        let saved_partial = saved_partial.clone();
+        resume_store
+            .expect_load_partial()
+            .returning(move |_| Ok(saved_partial.lock().unwrap().clone()));
+    }
+    {
+        let saved_partial = saved_partial.clone();
+        resume_store
+            .expect_save_partial()
+            .times(1)
+            .returning(move |partial| {
+                saved_partial.lock().unwrap().replace(partial.clone());
+
+                Ok(())
+            });
+    }
+    resume_store
+        .expect_remove_partial()
+        .with(eq(OsStr::new("test.txt")))
+        .times(1)
+        .returning(|_| Ok(()));
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    // the connection is broken after the first block
+    download_transfer
+        .expect_download()
+        .withf(|requests: &[DownloadBlockRequest]| requests.len() == 3)
+        .times(1)
+        .returning(|_| {
+            Ok(Box::pin(stream::iter([
+                Ok(Some(DownloadBlock {
+                    offset: 0,
+                    data: Bytes::from_static(b"test"),
+                })),
+                Err(io::Error::new(
+                    ErrorKind::ConnectionReset,
+                    "connection reset",
+                )),
+            ])))
+        });
+    // only the missing blocks are requested when resuming
+    download_transfer
+        .expect_download()
+        .withf(|requests: &[DownloadBlockRequest]| requests.len() == 2 && requests[0].offset == 4)
+        .times(1)
+        .returning(|_| {
+            Ok(Box::pin(stream::iter([
+                Ok(Some(DownloadBlock {
+                    offset: 4,
+                    data: Bytes::from_static(b"data"),
+                })),
+                Ok(Some(DownloadBlock {
+                    offset: 8,
+                    data: Bytes::new(),
+                })),
+            ])))
+        });
+
+    let rumor = IndexFile {
+        filename: OsString::from("test.txt"),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen: 1,
+            hash_sum,
+            block_chain: Some(block_chain),
+            deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![],
+        update_time: SystemTime::now(),
+        update_by: user_id.as_hyphenated().to_string(),
+    };
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let err = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.clone().into_sink(),
+    )
+    .with_resume_store(&resume_store)
+    .handle_rumors_event(user_id, vec![rumor.clone()])
+    .await
+    .unwrap_err();
+    // the broken connection is a transient transfer error
+    assert!(matches!(err, SyncError::Transfer(_)));
+
+    let temp_name = {
+        let mut saved_partial = saved_partial.lock().unwrap();
+        let partial = saved_partial.as_mut().unwrap();
+        assert_eq!(partial.hash_sum, hash_sum);
+        assert_eq!(partial.written_offsets, BTreeSet::from([0]));
+
+        // the unwritten block doesn't pass the verification
+        partial.written_offsets.insert(4);
+
+        partial.temp_name.clone()
+    };
+    assert!(dir.path().join(&temp_name).exists());
+    assert!(!dir.path().join("test.txt").exists());
+
+    RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_resume_store(&resume_store)
+    .handle_rumors_event(user_id, vec![rumor])
+    .await
+    .unwrap();
+
+    receiver.recv_async().await.unwrap();
+    assert_eq!(
+        fs::read(dir.path().join("test.txt")).await.unwrap(),
+        b"testdata"
+    );
+    assert!(!dir.path().join(&temp_name).exists());
+}
+
+fn new_file_index() -> MockIndex {
+    let mut index = MockIndex::new();
+    index.expect_begin().returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    index
+}
+
+#[tokio::test]
+async fn concurrent_rumors() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let index = new_file_index();
+
+    let a = created_index_file(b"aaaa", SystemTime::now(), user_id).await;
+    let b = created_index_file(b"bbbb", SystemTime::now(), user_id).await;
+
+    // the download of a.txt waits the download of b.txt, it never finishes when the rumors are
+    // handled one by one
+    let (started_sender, started_receiver) = flume::bounded::<()>(1);
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer
+        .expect_download()
+        .withf(|requests: &[DownloadBlockRequest]| requests[0].filename == "a.txt")
+        .returning(move |_| {
+            let started_receiver = started_receiver.clone();
+
+            Ok(Box::pin(stream::once(async move {
+                started_receiver.recv_async().await.unwrap();
+
+                Ok(Some(DownloadBlock {
+                    offset: 0,
+                    data: Bytes::from_static(b"aaaa"),
+                }))
+            })))
+        });
+    download_transfer
+        .expect_download()
+        .withf(|requests: &[DownloadBlockRequest]| requests[0].filename == "b.txt")
+        .returning(move |_| {
+            started_sender.try_send(()).unwrap();
+
+            Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+                offset: 0,
+                data: Bytes::from_static(b"bbbb"),
+            }))])))
+        });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    time::timeout(
+        Duration::from_secs(5),
+        handler.handle_rumors_event(
+            user_id,
+            vec![
+                IndexFile {
+                    filename: OsString::from("a.txt"),
+                    ..a
+                },
+                IndexFile {
+                    filename: OsString::from("b.txt"),
+                    ..b
+                },
+            ],
+        ),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    // the new rumors keep the arrival order
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(
+        send_rumors
+            .rumors
+            .iter()
+            .map(|rumor| rumor.filename.as_os_str())
+            .collect::<Vec<_>>(),
+        ["a.txt", "b.txt"]
+    );
+    assert_eq!(fs::read(dir.path().join("a.txt")).await.unwrap(), b"aaaa");
+    assert_eq!(fs::read(dir.path().join("b.txt")).await.unwrap(), b"bbbb");
+}
+
+#[tokio::test]
+async fn retry_concurrent_failed_rumor() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+
+    // the first transaction conflicts with the concurrent one
+    let busy_error = sqlite_busy_error().await;
+    let mut index = MockIndex::new();
+    index
+        .expect_begin()
+        .times(1)
+        .return_once(|| Err(io::Error::new(ErrorKind::Other, busy_error)));
+    index.expect_begin().returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let a = created_index_file(b"aaaa", SystemTime::now(), user_id).await;
+    let b = created_index_file(b"bbbb", SystemTime::now(), user_id).await;
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer
+        .expect_download()
+        .returning(|requests: &[DownloadBlockRequest]| {
+            let data = if requests[0].filename == "a.txt" {
+                Bytes::from_static(b"aaaa")
+            } else {
+                Bytes::from_static(b"bbbb")
+            };
+
+            Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+                offset: 0,
+                data,
+            }))])))
+        });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    );
+
+    handler
+        .handle_rumors_event(
+            user_id,
+            vec![
+                IndexFile {
+                    filename: OsString::from("a.txt"),
+                    ..a
+                },
+                IndexFile {
+                    filename: OsString::from("b.txt"),
+                    ..b
+                },
+            ],
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(receiver.recv_async().await.unwrap().rumors.len(), 2);
+    assert_eq!(fs::read(dir.path().join("a.txt")).await.unwrap(), b"aaaa");
+    assert_eq!(fs::read(dir.path().join("b.txt")).await.unwrap(), b"bbbb");
+}
+
+/// the error of a write when the other index transaction holds the write lock
+async fn sqlite_busy_error() -> sqlite_index::Error {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let index = SqliteIndex::open_with_options(
+        dir.path().join("index.db"),
+        SqliteIndexOptions {
+            busy_timeout: Duration::from_millis(10),
+            max_busy_retries: 0,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let index_file = created_index_file(b"test", SystemTime::now(), Uuid::new_v4()).await;
+
+    let mut other_guard = index.begin().await.unwrap();
+    other_guard.create_file(&index_file).await.unwrap();
+
+    let mut index_guard = index.begin().await.unwrap();
+    let err = index_guard.create_file(&index_file).await.unwrap_err();
+    assert!(err.is_busy());
+
+    err
+}
+
+#[tokio::test]
+async fn fail_concurrent_rumor_without_retry() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+
+    // the failure isn't caused by the concurrent transaction, so it isn't retried
+    let mut index = MockIndex::new();
+    index
+        .expect_begin()
+        .times(1)
+        .returning(|| Err(io::Error::new(ErrorKind::Other, "disk I/O error")));
+    index.expect_begin().times(1).returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let a = created_index_file(b"aaaa", SystemTime::now(), user_id).await;
+    let b = created_index_file(b"bbbb", SystemTime::now(), user_id).await;
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer
+        .expect_download()
+        .returning(|requests: &[DownloadBlockRequest]| {
+            let data = if requests[0].filename == "a.txt" {
+                Bytes::from_static(b"aaaa")
+            } else {
+                Bytes::from_static(b"bbbb")
+            };
+
+            Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+                offset: 0,
+                data,
+            }))])))
+        });
+
+    let (sender, _receiver) = flume::bounded(1);
+
+    let err = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .handle_rumors_event(
+        user_id,
+        vec![
+            IndexFile {
+                filename: OsString::from("a.txt"),
+                ..a
+            },
+            IndexFile {
+                filename: OsString::from("b.txt"),
+                ..b
+            },
+        ],
+    )
+    .await
+    .unwrap_err();
+
+    assert!(matches!(err, SyncError::Index(_)));
+}
+
+#[tokio::test]
+async fn prefetch_queued_new_file() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let index = new_file_index();
+
+    let a = created_index_file(b"aaaa", SystemTime::now(), user_id).await;
+    let b = created_index_file(b"bbbb", SystemTime::now(), user_id).await;
+
+    // the rumors are handled one by one, the download of a.txt waits the prefetching of b.txt
+    let (prefetched_sender, prefetched_receiver) = flume::bounded::<()>(1);
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer
+        .expect_download()
+        .withf(|requests: &[DownloadBlockRequest]| requests[0].filename == "a.txt")
+        .returning(move |_| {
+            let prefetched_receiver = prefetched_receiver.clone();
+
+            Ok(Box::pin(stream::once(async move {
+                prefetched_receiver.recv_async().await.unwrap();
+
+                Ok(Some(DownloadBlock {
+                    offset: 0,
+                    data: Bytes::from_static(b"aaaa"),
+                }))
+            })))
+        });
+    // b.txt is downloaded only by the prefetching
+    download_transfer
+        .expect_download()
+        .withf(|requests: &[DownloadBlockRequest]| requests[0].filename == "b.txt")
+        .times(1)
+        .returning(move |_| {
+            prefetched_sender.try_send(()).unwrap();
+
+            Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+                offset: 0,
+                data: Bytes::from_static(b"bbbb"),
+            }))])))
+        });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_options(SyncOptions {
+        rumor_concurrency: 1,
+        prefetch_files: 1,
+        ..Default::default()
+    });
+
+    time::timeout(
+        Duration::from_secs(5),
+        handler.handle_rumors_event(
+            user_id,
+            vec![
+                IndexFile {
+                    filename: OsString::from("a.txt"),
+                    ..a
+                },
+                IndexFile {
+                    filename: OsString::from("b.txt"),
+                    ..b
+                },
+            ],
+        ),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+
+    assert_eq!(receiver.recv_async().await.unwrap().rumors.len(), 2);
+    assert_eq!(fs::read(dir.path().join("a.txt")).await.unwrap(), b"aaaa");
+    assert_eq!(fs::read(dir.path().join("b.txt")).await.unwrap(), b"bbbb");
+}
+
+#[tokio::test]
+async fn apply_dir_rename() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+
+    fs::create_dir(dir.path().join("old")).await.unwrap();
+    fs::write(dir.path().join("old/test.txt"), b"test")
+        .await
+        .unwrap();
+    let local_index_file = created_index_file(b"test", SystemTime::UNIX_EPOCH, user_id).await;
+    let local_index_file = IndexFile {
+        filename: OsString::from("old/test.txt"),
+        ..local_index_file
+    };
+
+    let deleted_index_file = IndexFile {
+        detail: FileDetail {
+            gen: 2,
+            hash_sum: [0; 32],
+            block_chain: None,
+            deleted: true,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![local_index_file.detail.clone()],
+        update_time: SystemTime::now(),
+        ..local_index_file.clone()
+    };
+    let moved_index_file = IndexFile {
+        filename: OsString::from("new/test.txt"),
+        update_time: SystemTime::now(),
+        ..local_index_file.clone()
+    };
+
+    let mut index = MockIndex::new();
+    {
+        let local_index_file = local_index_file.clone();
+
+        index
+            .expect_list_all_files()
+            .returning(move || Ok(Box::pin(stream::iter([Ok(local_index_file.clone())]))));
+    }
+    index.expect_begin().returning(move || {
+        let local_index_file = local_index_file.clone();
+
+        let mut index_guard = MockIndexGuard::new();
+        index_guard
+            .expect_get_file()
+            .with(eq(OsStr::new("old/test.txt")))
+            .returning(move |_| Ok(Some(local_index_file.clone())));
+        index_guard
+            .expect_get_file()
+            .with(eq(OsStr::new("new/test.txt")))
+            .returning(|_| Ok(None));
+        index_guard.expect_update_file().returning(|_| Ok(()));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    // the moved file is never downloaded
+    let download_transfer = MockDownloadTransfer::new();
+    let (sender, receiver) = flume::bounded(1);
+
+    let dir_renames = vec![DirRename {
+        old_dir: OsString::from("old"),
+        new_dir: OsString::from("new"),
+    }];
+    let handler = RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_dir_renames(dir_renames.clone());
+
+    handler
+        .handle_rumors_event(user_id, vec![deleted_index_file, moved_index_file])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        fs::read(dir.path().join("new/test.txt")).await.unwrap(),
+        b"test"
+    );
+    assert!(!dir.path().join("old").exists());
+
+    // the dir rename is forwarded with the rumors
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.rumors.len(), 2);
+    assert_eq!(send_rumors.dir_renames, dir_renames);
+}
+
+#[tokio::test]
+async fn repair_corrupted_file() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let path = dir.path().join("test.txt");
+    fs::write(&path, b"abcdefgx").await.unwrap();
+
+    let (hash_sum, block_chain) = hash_file_with_block_size(Cursor::new(b"abcdefgh"), 4)
+        .await
+        .unwrap();
+    let index_file = IndexFile {
+        filename: OsString::from("test.txt"),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen: 1,
+            hash_sum,
+            block_chain: Some(block_chain),
+            deleted: false,
+            metadata: Some(FileMetadata::from_path(&path).await.unwrap()),
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![],
+        update_time: SystemTime::now(),
+        update_by: user_id.as_hyphenated().to_string(),
+    };
+
+    let mut index = MockIndex::new();
+    index
+        .expect_get_file()
+        .with(eq(OsStr::new("test.txt")))
+        .returning(move |_| Ok(Some(index_file.clone())));
+
+    // only the corrupted block is downloaded
+    let mut download_transfer = MockDownloadTransfer::new();
+    download_transfer
+        .expect_download()
+        .withf(|requests: &[DownloadBlockRequest]| {
+            requests.iter().map(|request| request.offset).eq([4])
+        })
+        .times(1)
+        .returning(|_| {
+            Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+                offset: 4,
+                data: Bytes::from_static(b"efgh"),
+            }))])))
+        });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.clone().into_sink(),
+    )
+    .handle_repair_event(vec![OsString::from("test.txt")])
+    .await
+    .unwrap();
+
+    assert_eq!(fs::read(&path).await.unwrap(), b"abcdefgh");
+    receiver.try_recv().unwrap_err();
+
+    // the repaired file is not downloaded again
+    RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .handle_repair_event(vec![OsString::from("test.txt")])
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn archive_deleted_file() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    fs::write(dir.path().join("test.txt"), b"test")
+        .await
+        .unwrap();
+
+    let mut index = MockIndex::new();
+    index.expect_begin().returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_create_file().returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let (sender, _receiver) = flume::unbounded();
+
+    RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &MockDownloadTransfer::new(),
+        sender.into_sink(),
+    )
+    .with_options(SyncOptions {
+        versioning: Some(Default::default()),
+        ..Default::default()
+    })
+    .handle_rumors_event(
+        user_id,
+        vec![IndexFile {
+            filename: OsString::from("test.txt"),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 2,
+                hash_sum: [0; 32],
+                block_chain: None,
+                deleted: true,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::now(),
+            update_by: user_id.as_hyphenated().to_string(),
+        }],
+    )
+    .await
+    .unwrap();
+
+    assert!(!dir.path().join("test.txt").exists());
+    let versions = ReadDirStream::new(
+        fs::read_dir(dir.path().join(versioning::VERSIONS_DIR))
+            .await
+            .unwrap(),
+    )
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap();
+    assert_eq!(versions.len(), 1);
+    assert_eq!(fs::read(versions[0].path()).await.unwrap(), b"test");
+}
+
+#[tokio::test]
+async fn apply_file_creation() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let path = dir.path().join("test.txt");
+    fs::write(&path, b"test").await.unwrap();
+
+    let options = SyncOptions {
+        file_creation: FileCreation {
+            umask: 0o027,
+            default_mode: Some(0o666),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    // the file without metadata uses the default mode
+    apply_file_metadata(&path, None, &options).await;
+    let mode = fs::metadata(&path).await.unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+
+    let mut metadata = FileMetadata::from_path(&path).await.unwrap();
+    metadata.mode = 0o100777;
+    apply_file_metadata(&path, Some(&metadata), &options).await;
+    let mode = fs::metadata(&path).await.unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o750);
+}
+
+#[tokio::test]
+async fn adopt_untracked_files() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    fs::write(dir.path().join("same.txt"), b"test")
+        .await
+        .unwrap();
+    fs::write(dir.path().join("deleted.txt"), b"local")
+        .await
+        .unwrap();
+
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+    let rumor = |filename: &str, deleted: bool| IndexFile {
+        filename: OsString::from(filename),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen: 3,
+            hash_sum,
+            block_chain: (!deleted).then(|| block_chain.clone()),
+            deleted,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![],
+        update_time: SystemTime::now(),
+        update_by: "peer".to_string(),
+    };
+    let rumors = vec![rumor("same.txt", false), rumor("deleted.txt", true)];
+
+    let created = Arc::new(Mutex::new(vec![]));
+    let mut index = MockIndex::new();
+    {
+        let created = created.clone();
+        index.expect_begin().returning(move || {
+            let created = created.clone();
+            let mut index_guard = MockIndexGuard::new();
+            index_guard.expect_get_file().returning(|_| Ok(None));
+            index_guard
+                .expect_create_file()
+                .returning(move |index_file| {
+                    created.lock().unwrap().push(index_file.clone());
+
+                    Ok(())
+                });
+            index_guard.expect_commit().returning(|| Ok(()));
+
+            Ok(index_guard)
+        });
+    }
+
+    // the identical file is never downloaded
+    let download_transfer = MockDownloadTransfer::new();
+    let (sender, receiver) = flume::unbounded();
+    RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_options(SyncOptions {
+        adopt: true,
+        ..Default::default()
+    })
+    .handle_rumors_event(Uuid::new_v4(), rumors.clone())
+    .await
+    .unwrap();
+
+    let mut created = mem::take(&mut *created.lock().unwrap());
+    created.sort_by(|a, b| b.filename.cmp(&a.filename));
+    assert_eq!(created, rumors);
+    assert_eq!(
+        fs::read(dir.path().join("same.txt")).await.unwrap(),
+        b"test"
+    );
+    assert_eq!(
+        fs::read(dir.path().join("deleted.txt")).await.unwrap(),
+        b"local"
+    );
+    assert_eq!(receiver.try_recv().unwrap().rumors, rumors);
+}
+
+#[tokio::test]
+async fn recreate_hard_link() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let leader_path = dir.path().join("a.txt");
+    fs::write(&leader_path, b"test").await.unwrap();
+
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+    let index_file = |filename: &str, metadata: Option<FileMetadata>| IndexFile {
+        filename: OsString::from(filename),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen: 1,
+            hash_sum,
+            block_chain: Some(block_chain.clone()),
+            deleted: false,
+            metadata,
+            version: Default::default(),
+            link_group: Some(OsString::from("a.txt")),
+        },
+        previous_details: vec![],
+        update_time: SystemTime::now(),
+        update_by: "peer".to_string(),
+    };
+    let leader = index_file(
+        "a.txt",
+        Some(FileMetadata::from_path(&leader_path).await.unwrap()),
+    );
+    let rumor = index_file("b.txt", None);
+
+    let created = Arc::new(Mutex::new(vec![]));
+    let mut index = MockIndex::new();
+    {
+        let created = created.clone();
+        index.expect_begin().returning(move || {
+            let created = created.clone();
+            let leader = leader.clone();
+            let mut index_guard = MockIndexGuard::new();
+            index_guard.expect_get_file().returning(move |filename| {
+                Ok((filename == leader.filename).then(|| leader.clone()))
+            });
+            index_guard
+                .expect_create_file()
+                .returning(move |index_file| {
+                    created.lock().unwrap().push(index_file.clone());
+
+                    Ok(())
+                });
+            index_guard.expect_commit().returning(|| Ok(()));
+
+            Ok(index_guard)
+        });
+    }
+
+    // the file is linked to the local leader of its group, nothing is downloaded
+    let download_transfer = MockDownloadTransfer::new();
+    let (sender, _receiver) = flume::unbounded();
+    RumorsEventHandler::new(
+        user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .handle_rumors_event(Uuid::new_v4(), vec![rumor.clone()])
+    .await
+    .unwrap();
+
+    assert_eq!(*created.lock().unwrap(), [rumor]);
+    let leader_metadata = fs::metadata(&leader_path).await.unwrap();
+    let metadata = fs::metadata(dir.path().join("b.txt")).await.unwrap();
+    assert_eq!(metadata.ino(), leader_metadata.ino());
+    assert_eq!(metadata.nlink(), 2);
+}
+
+#[tokio::test]
+async fn dry_run() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let local_version = VersionVector::initial(&local_user_id.as_hyphenated().to_string());
+    let remote_version = local_version.incremented(&user_id.as_hyphenated().to_string());
+
+    let index = MemoryIndex::new();
+    let mut index_guard = index.begin().await.unwrap();
+    let mut local_files = vec![];
+    for (filename, content) in [
+        ("conflict.txt", b"mine" as &'static [u8]),
+        ("deleted.txt", b"gone"),
+        ("update.txt", b"old"),
+    ] {
+        fs::write(dir.path().join(filename), content).await.unwrap();
+
+        let mut index_file = created_index_file(content, SystemTime::now(), local_user_id).await;
+        index_file.filename = OsString::from(filename);
+        index_file.detail.version = local_version.clone();
+        index_guard.create_file(&index_file).await.unwrap();
+        local_files.push(index_file);
+    }
+    index_guard.commit().await.unwrap();
+
+    let mut conflict = created_index_file(b"theirs", SystemTime::now(), user_id).await;
+    conflict.filename = OsString::from("conflict.txt");
+    conflict.detail.version = VersionVector::initial(&user_id.as_hyphenated().to_string());
+
+    let mut deleted = local_files[1].clone();
+    deleted.previous_details = vec![deleted.detail.clone()];
+    deleted.detail = FileDetail {
+        gen: 2,
+        hash_sum: [0; 32],
+        block_chain: None,
+        deleted: true,
+        metadata: None,
+        version: remote_version.clone(),
+        link_group: None,
+    };
+
+    let mut update = created_index_file(b"new", SystemTime::now(), user_id).await;
+    update.filename = OsString::from("update.txt");
+    update.detail.gen = 2;
+    update.detail.version = remote_version;
+    update.previous_details = vec![local_files[2].detail.clone()];
+
+    let mut new = created_index_file(b"new", SystemTime::now(), user_id).await;
+    new.filename = OsString::from("new.txt");
+
+    let plan = SyncPlan::new();
+    let download_transfer = MockDownloadTransfer::new();
+    let (sender, receiver) = flume::unbounded();
+    RumorsEventHandler::new(
+        local_user_id,
+        Uuid::new_v4(),
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_dry_run(plan.clone())
+    .handle_rumors_event(user_id, vec![conflict, deleted, update, new])
+    .await
+    .unwrap();
+
+    let mut actions = plan.actions();
+    actions.sort_by(|a, b| a.filename.cmp(&b.filename));
+    assert_eq!(
+        actions,
+        [
+            PlannedAction {
+                filename: OsString::from("conflict.txt"),
+                op: PlannedOp::Conflict,
+                transfer_bytes: 6,
+            },
+            PlannedAction {
+                filename: OsString::from("deleted.txt"),
+                op: PlannedOp::Delete,
+                transfer_bytes: 0,
+            },
+            PlannedAction {
+                filename: OsString::from("new.txt"),
+                op: PlannedOp::Create,
+                transfer_bytes: 3,
+            },
+            PlannedAction {
+                filename: OsString::from("update.txt"),
+                op: PlannedOp::Update,
+                transfer_bytes: 3,
+            },
+        ]
+    );
+    assert_eq!(plan.transfer_bytes(), 12);
+
+    // nothing is changed or forwarded
+    assert_eq!(
+        fs::read(dir.path().join("deleted.txt")).await.unwrap(),
+        b"gone"
+    );
+    assert_eq!(
+        fs::read(dir.path().join("update.txt")).await.unwrap(),
+        b"old"
+    );
+    assert!(fs::metadata(dir.path().join("new.txt")).await.is_err());
+    assert_eq!(index.len(), 3);
+    assert!(receiver.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn reject_stale_rumors() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let now = SystemTime::now();
+
+    let index = MemoryIndex::new();
+    let mut local = created_index_file(b"mine", now, local_user_id).await;
+    local.filename = OsString::from("test.txt");
+    let mut index_guard = index.begin().await.unwrap();
+    index_guard.create_file(&local).await.unwrap();
+    index_guard.commit().await.unwrap();
+
+    // the clock of the sender is days ahead, so the rumor would win the equal gen comparison
+    let mut future =
+        created_index_file(b"theirs", now + Duration::from_secs(3 * 86400), user_id).await;
+    future.filename = OsString::from("test.txt");
+    // a long unchanged file is still accepted, the anti entropy sends its rumor too
+    let mut old = created_index_file(b"old", now - Duration::from_secs(400 * 86400), user_id).await;
+    old.filename = OsString::from("old.txt");
+
+    let mut download_transfer = MockDownloadTransfer::new();
+    {
+        let blocks = old.detail.block_chain.as_ref().unwrap().blocks.clone();
+
+        download_transfer
+            .expect_download()
+            .with(function(move |arg: &[DownloadBlockRequest]| {
+                blocks_to_download_block_requests(dir_id, Path::new("old.txt"), &blocks) == arg
+            }))
+            .times(1)
+            .returning(|_| {
+                Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+                    offset: 0,
+                    data: Bytes::from_static(b"old"),
+                }))])))
+            });
+    }
+    let (sender, receiver) = flume::unbounded();
+    RumorsEventHandler::new(
+        local_user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_options(SyncOptions {
+        max_rumor_clock_skew: Duration::from_secs(3600),
+        ..Default::default()
+    })
+    .handle_rumors_event(user_id, vec![future, old.clone()])
+    .await
+    .unwrap();
+
+    assert_eq!(
+        index.get_file(OsStr::new("test.txt")).await.unwrap(),
+        Some(local.clone())
+    );
+    assert_eq!(
+        index
+            .get_file(OsStr::new("old.txt"))
+            .await
+            .unwrap()
+            .unwrap()
+            .detail,
+        old.detail
+    );
+    assert_eq!(fs::read(dir.path().join("old.txt")).await.unwrap(), b"old");
+
+    // the accepted rumor is spread, the sender is told the local file index
+    let spread = receiver.try_recv().unwrap();
+    assert_eq!(spread.to, None);
+    assert_eq!(spread.rumors.len(), 1);
+    assert_eq!(spread.rumors[0].filename, OsStr::new("old.txt"));
+
+    let reply = receiver.try_recv().unwrap();
+    assert_eq!(reply.to, Some(user_id));
+    assert_eq!(reply.rumors, [local]);
+    assert!(receiver.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn reject_too_old_rumors() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let now = SystemTime::now();
+
+    let index = MemoryIndex::new();
+    let mut old = created_index_file(b"old", now - Duration::from_secs(400 * 86400), user_id).await;
+    old.filename = OsString::from("old.txt");
+
+    let download_transfer = MockDownloadTransfer::new();
+    let (sender, receiver) = flume::unbounded();
+    RumorsEventHandler::new(
+        local_user_id,
+        dir_id,
+        dir.path(),
+        &index,
+        &download_transfer,
+        sender.into_sink(),
+    )
+    .with_options(SyncOptions {
+        max_rumor_age: Duration::from_secs(365 * 86400),
+        ..Default::default()
+    })
+    .handle_rumors_event(user_id, vec![old])
+    .await
+    .unwrap();
+
+    assert!(index
+        .get_file(OsStr::new("old.txt"))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!dir.path().join("old.txt").exists());
+
+    // the local file doesn't exist, so the sender is told nothing
+    assert!(receiver.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn skip_quarantined_peer() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let local_user_id = Uuid::new_v4();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let index = MemoryIndex::new();
+    let rumor = created_index_file(b"test", SystemTime::now(), user_id).await;
+
+    // the first download receives a corrupted block, so the peer is quarantined
+    let mut inner = MockDownloadTransfer::new();
+    let corrupted = AtomicBool::new(true);
+    inner.expect_download().times(2).returning(move |_| {
+        let data = match corrupted.swap(false, AtomicOrdering::Relaxed) {
+            true => Bytes::from_static(b"tset"),
+            false => Bytes::from_static(b"test"),
+        };
+
+        Ok(Box::pin(stream::iter([Ok(Some(DownloadBlock {
+            offset: 0,
+            data,
+        }))])))
+    });
+    let health = PeerHealth::new(user_id, 1);
+    let download_transfer = IntegrityTransfer::new(inner, health.clone());
+    let requests = blocks_to_download_block_requests(
+        dir_id,
+        Path::new("test.txt"),
+        &rumor.detail.block_chain.as_ref().unwrap().blocks,
+    );
+    download_transfer
+        .download(&requests)
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(health.is_quarantined());
+
+    let (sender, receiver) = flume::unbounded();
+    let handle = |rumor: IndexFile| {
+        RumorsEventHandler::new(
+            local_user_id,
+            dir_id,
+            dir.path(),
+            &index,
+            &download_transfer,
+            sender.clone().into_sink(),
+        )
+        .handle_rumors_event(user_id, vec![rumor])
+    };
+
+    // the rumor is neither applied nor replied as outdated
+    handle(rumor.clone()).await.unwrap();
+
+    assert!(index
+        .get_file(OsStr::new("test.txt"))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(receiver.try_recv().is_err());
+
+    // the rumor is synced after the release
+    health.release();
+    handle(rumor.clone()).await.unwrap();
+
+    assert_eq!(
+        index
+            .get_file(OsStr::new("test.txt"))
+            .await
+            .unwrap()
+            .unwrap()
+            .detail,
+        rumor.detail
+    );
+    assert_eq!(
+        fs::read(dir.path().join("test.txt")).await.unwrap(),
+        b"test"
+    );
+}
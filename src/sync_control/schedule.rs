@@ -0,0 +1,99 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::index::IndexFile;
+use crate::sync_control::options::ApplyOrder;
+
+/// the positions of the rumors in the applying order, the sort is stable, so the rumors with the
+/// same key keep the arrival order
+pub fn apply_queue(rumors: &[IndexFile], order: ApplyOrder) -> Vec<usize> {
+    let mut queue = (0..rumors.len()).collect::<Vec<_>>();
+
+    match order {
+        ApplyOrder::Arrival => {
+            let mut dir_positions = HashMap::new();
+            let dir_positions = rumors
+                .iter()
+                .enumerate()
+                .map(|(position, rumor)| {
+                    *dir_positions
+                        .entry(Path::new(&rumor.filename).parent())
+                        .or_insert(position)
+                })
+                .collect::<Vec<_>>();
+
+            queue.sort_by_key(|&position| dir_positions[position]);
+        }
+
+        ApplyOrder::SmallestFirst => queue.sort_by_key(|&position| file_size(&rumors[position])),
+        ApplyOrder::Alphabetical => queue.sort_by_key(|&position| &rumors[position].filename),
+        ApplyOrder::NewestFirst => {
+            queue.sort_by_key(|&position| Reverse(rumors[position].update_time))
+        }
+    }
+
+    queue
+}
+
+/// the deleted file, dir and symlink have no content, they are applied first by the smallest
+/// first order
+fn file_size(rumor: &IndexFile) -> u64 {
+    match &rumor.detail.block_chain {
+        Some(block_chain) if !rumor.detail.deleted => {
+            block_chain.blocks.iter().map(|block| block.len).sum()
+        }
+
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::index::{Block, BlockChain, FileDetail, FileKind};
+
+    fn rumor(filename: &str, len: u64, update_secs: u64) -> IndexFile {
+        IndexFile {
+            filename: OsString::from(filename),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum: [0; 32],
+                block_chain: Some(BlockChain {
+                    block_size: len,
+                    blocks: vec![Block {
+                        offset: 0,
+                        len,
+                        hash_sum: [0; 32],
+                        zero: false,
+                    }],
+                }),
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH + Duration::from_secs(update_secs),
+            update_by: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn apply_queue_order() {
+        let rumors = [
+            rumor("b/1.txt", 30, 1),
+            rumor("a/2.txt", 10, 3),
+            rumor("b/3.txt", 20, 2),
+        ];
+
+        assert_eq!(apply_queue(&rumors, ApplyOrder::Arrival), [0, 2, 1]);
+        assert_eq!(apply_queue(&rumors, ApplyOrder::SmallestFirst), [1, 2, 0]);
+        assert_eq!(apply_queue(&rumors, ApplyOrder::Alphabetical), [1, 0, 2]);
+        assert_eq!(apply_queue(&rumors, ApplyOrder::NewestFirst), [1, 2, 0]);
+    }
+}
@@ -0,0 +1,430 @@
+use std::error::Error;
+use std::fs::Metadata;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use futures_util::{Sink, SinkExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tap::TapFallible;
+use tokio::io::AsyncReadExt;
+use tokio::{fs, select, time};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::ext::{log_path, open_read};
+use crate::file_event_produce::WatchEvent;
+use crate::index::{FileKind, FileMetadata, Index, IndexFile, Sha256sum};
+use crate::sync_control::event::Event;
+use crate::transfer::rate_limit::RateLimiter;
+
+/// the default hashing speed of the scrub, it is slow enough to not disturb the syncing
+pub const DEFAULT_SCRUB_BYTES_PER_SEC: u64 = 4 * 1024 * 1024;
+
+/// the default pause between two scrub rounds
+pub const DEFAULT_SCRUB_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// the bytes read and hashed at a time, the rate limiter is acquired for every chunk
+const SCRUB_CHUNK_SIZE: usize = 64 * 1024;
+
+/// the result of a scrub round
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ScrubReport {
+    /// the files whose content is hashed
+    pub scrubbed_files: u64,
+
+    pub scrubbed_bytes: u64,
+
+    /// the files changed or deleted without the watch events, they are sent as
+    /// [`Event::Watch`], so the local changes are synced
+    pub modified_files: u64,
+
+    /// the files whose content doesn't match the index but their metadata is not changed, such
+    /// as bit rot, they are sent as [`Event::Repair`], so they are downloaded from the peers again
+    pub corrupted_files: u64,
+}
+
+/// what the scrub found about a local file
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Finding {
+    Intact,
+    Modified,
+    Deleted,
+    Corrupted,
+}
+
+/// re-hash the local files slowly in the background and compare them with the index, the
+/// changes missed by the watcher and the corrupted files are sent to the sync controller as
+/// events. The scrub only reads the index and the files, it never changes them, so the index can
+/// be another connection of the index used by the controller
+pub struct Scrubber<I, Si> {
+    sync_dir: PathBuf,
+    index: I,
+    event_sender: Si,
+    limiter: RateLimiter,
+    interval: Duration,
+    no_atime: bool,
+}
+
+impl<I, Si> Scrubber<I, Si> {
+    pub fn new(sync_dir: PathBuf, index: I, event_sender: Si) -> Self {
+        Self {
+            sync_dir,
+            index,
+            event_sender,
+            limiter: RateLimiter::new(DEFAULT_SCRUB_BYTES_PER_SEC),
+            interval: DEFAULT_SCRUB_INTERVAL,
+            no_atime: false,
+        }
+    }
+
+    /// the hashing speed, 0 means no limit
+    pub fn with_bytes_per_sec(self, bytes_per_sec: u64) -> Self {
+        self.limiter.set_limit(bytes_per_sec);
+
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+
+        self
+    }
+
+    /// read the files without updating their access time, like [`SyncOptions::no_atime`]
+    ///
+    /// [`SyncOptions::no_atime`]: crate::sync_control::options::SyncOptions::no_atime
+    pub fn with_no_atime(mut self, no_atime: bool) -> Self {
+        self.no_atime = no_atime;
+
+        self
+    }
+
+    /// the limiter of the hashing speed, its limit can be changed when the scrub is running
+    pub fn limiter(&self) -> RateLimiter {
+        self.limiter.clone()
+    }
+}
+
+impl<I, Si> Scrubber<I, Si>
+where
+    I: Index,
+    I::Error: Send + Sync + 'static,
+    Si: Sink<Event> + Unpin,
+    Si::Error: Error + Send + Sync + 'static,
+{
+    /// scrub the dir every interval until the token is cancelled
+    pub async fn run(&mut self, cancel: CancellationToken) -> Result<()> {
+        loop {
+            select! {
+                _ = cancel.cancelled() => {
+                    info!(dir = ?log_path(&self.sync_dir), "scrub is cancelled");
+
+                    return Ok(());
+                }
+
+                report = self.scrub() => {
+                    report?;
+                }
+            }
+
+            select! {
+                _ = cancel.cancelled() => {
+                    info!(dir = ?log_path(&self.sync_dir), "scrub is cancelled");
+
+                    return Ok(());
+                }
+
+                _ = time::sleep(self.interval) => {}
+            }
+        }
+    }
+
+    /// scrub all files of the index once, the findings are sent when they are found, so a
+    /// corrupted file doesn't wait for the whole round
+    pub async fn scrub(&mut self) -> Result<ScrubReport> {
+        // the listing transaction is not held when hashing
+        let filenames = self
+            .index
+            .list_all_files()
+            .await
+            .tap_err(|err| error!(%err, "list all index files failed"))?
+            .try_filter_map(|index_file| async move {
+                Ok(is_scrubbable(&index_file).then_some(index_file.filename))
+            })
+            .try_collect::<Vec<_>>()
+            .await
+            .tap_err(|err| error!(%err, "list all index files failed"))?;
+
+        let mut report = ScrubReport::default();
+        for filename in filenames {
+            let index_file = match self.index.get_file(&filename).await? {
+                Some(index_file) if is_scrubbable(&index_file) => index_file,
+                _ => continue,
+            };
+
+            let finding = self.scrub_file(&index_file, &mut report).await?;
+            let event = match finding {
+                Finding::Intact => continue,
+
+                Finding::Modified => {
+                    report.modified_files += 1;
+
+                    Event::Watch(vec![WatchEvent::Modify { name: filename }])
+                }
+
+                Finding::Deleted => {
+                    report.modified_files += 1;
+
+                    Event::Watch(vec![WatchEvent::Delete { name: filename }])
+                }
+
+                Finding::Corrupted => {
+                    // the file may be changed by a rumor when hashing
+                    match self.index.get_file(&filename).await? {
+                        Some(current) if current.detail.gen == index_file.detail.gen => {}
+                        _ => continue,
+                    }
+
+                    report.corrupted_files += 1;
+
+                    warn!(filename = ?log_path(&filename), "file content doesn't match the index, repair it");
+
+                    Event::Repair(vec![filename])
+                }
+            };
+
+            self.event_sender
+                .send(event)
+                .await
+                .tap_err(|err| error!(%err, "send scrub event failed"))?;
+        }
+
+        info!(?report, dir = ?log_path(&self.sync_dir), "scrub done");
+
+        Ok(report)
+    }
+
+    async fn scrub_file(
+        &self,
+        index_file: &IndexFile,
+        report: &mut ScrubReport,
+    ) -> Result<Finding> {
+        let path = self.sync_dir.join(&index_file.filename);
+        let metadata = match fs::symlink_metadata(&path).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Finding::Deleted),
+            Err(err) => {
+                error!(%err, path = ?log_path(&path), "get file metadata failed");
+
+                return Err(err.into());
+            }
+
+            Ok(metadata) => metadata,
+        };
+        if is_modified(&metadata, index_file) {
+            return Ok(Finding::Modified);
+        }
+
+        let file = match open_read(&path, self.no_atime).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Finding::Deleted),
+            Err(err) => {
+                error!(%err, path = ?log_path(&path), "open file failed");
+
+                return Err(err.into());
+            }
+
+            Ok(file) => file,
+        };
+
+        let hash_sum = self
+            .hash_limited(file)
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(&path), "hash file failed"))?;
+
+        report.scrubbed_files += 1;
+        report.scrubbed_bytes += metadata.len();
+
+        if hash_sum == index_file.detail.hash_sum {
+            return Ok(Finding::Intact);
+        }
+
+        // the file may be written when hashing
+        let metadata = fs::symlink_metadata(&path).await?;
+        if is_modified(&metadata, index_file) {
+            return Ok(Finding::Modified);
+        }
+
+        Ok(Finding::Corrupted)
+    }
+
+    async fn hash_limited(&self, mut file: fs::File) -> std::io::Result<Sha256sum> {
+        let mut hasher = Sha256::new();
+        let mut buf = BytesMut::zeroed(SCRUB_CHUNK_SIZE);
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+
+            self.limiter.acquire(n as _).await;
+            hasher.update(&buf[..n]);
+        }
+
+        Ok(hasher.finalize().into())
+    }
+}
+
+/// only the regular files have the content to scrub
+fn is_scrubbable(index_file: &IndexFile) -> bool {
+    index_file.kind == FileKind::File
+        && !index_file.detail.deleted
+        && index_file.detail.block_chain.is_some()
+}
+
+/// when the size or the modified time of the local file is not the indexed one, the file is
+/// changed by the user, not corrupted. The file indexed without the metadata is always treated as
+/// changed, the local content is never replaced by a guess
+pub(crate) fn is_modified(metadata: &Metadata, index_file: &IndexFile) -> bool {
+    if !metadata.is_file() {
+        return true;
+    }
+
+    let indexed_size = index_file.detail.block_chain.as_ref().map(|block_chain| {
+        block_chain
+            .blocks
+            .iter()
+            .map(|block| block.len)
+            .sum::<u64>()
+    });
+    if indexed_size != Some(metadata.len()) {
+        return true;
+    }
+
+    index_file
+        .detail
+        .metadata
+        .as_ref()
+        .is_none_or(|indexed| indexed.mtime != FileMetadata::from(metadata).mtime)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsString;
+    use std::time::{Duration, SystemTime};
+
+    use futures_util::stream;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::ext::hash::hash_file;
+    use crate::index::{FileDetail, MockIndex};
+
+    async fn index_file(dir: &TempDir, filename: &str) -> IndexFile {
+        let path = dir.path().join(filename);
+        let (hash_sum, block_chain) = hash_file(fs::File::open(&path).await.unwrap())
+            .await
+            .unwrap();
+
+        IndexFile {
+            filename: OsString::from(filename),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum,
+                block_chain: Some(block_chain),
+                deleted: false,
+                metadata: Some(FileMetadata::from_path(&path).await.unwrap()),
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH + Duration::from_secs(100),
+            update_by: "test".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn scrub_finds_changes() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        for filename in ["intact.txt", "corrupted.txt", "modified.txt", "deleted.txt"] {
+            fs::write(dir.path().join(filename), b"test").await.unwrap();
+        }
+
+        let mut index_files = vec![];
+        for filename in ["corrupted.txt", "deleted.txt", "intact.txt", "modified.txt"] {
+            index_files.push(index_file(&dir, filename).await);
+        }
+
+        // flip the content but keep the size and the modified time, like bit rot
+        let corrupted = dir.path().join("corrupted.txt");
+        let mtime = fs::metadata(&corrupted).await.unwrap().modified().unwrap();
+        fs::write(&corrupted, b"tesT").await.unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&corrupted)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        fs::write(dir.path().join("modified.txt"), b"modified")
+            .await
+            .unwrap();
+        fs::remove_file(dir.path().join("deleted.txt"))
+            .await
+            .unwrap();
+
+        let mut index = MockIndex::new();
+        let listed_files = index_files.clone();
+        index.expect_list_all_files().returning(move || {
+            Ok(Box::pin(stream::iter(
+                listed_files.clone().into_iter().map(Ok),
+            )))
+        });
+        index.expect_get_file().returning(move |filename| {
+            Ok(index_files
+                .iter()
+                .find(|index_file| index_file.filename == filename)
+                .cloned())
+        });
+
+        let (sender, receiver) = flume::unbounded();
+        let mut scrubber = Scrubber::new(dir.path().to_path_buf(), index, sender.into_sink())
+            .with_bytes_per_sec(0);
+        let report = scrubber.scrub().await.unwrap();
+        assert_eq!(
+            report,
+            ScrubReport {
+                scrubbed_files: 2,
+                scrubbed_bytes: 8,
+                modified_files: 2,
+                corrupted_files: 1,
+            }
+        );
+
+        let events = receiver
+            .drain()
+            .map(|event| format!("{event:?}"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                format!("{:?}", Event::Repair(vec!["corrupted.txt".into()])),
+                format!(
+                    "{:?}",
+                    Event::Watch(vec![WatchEvent::Delete {
+                        name: "deleted.txt".into()
+                    }])
+                ),
+                format!(
+                    "{:?}",
+                    Event::Watch(vec![WatchEvent::Modify {
+                        name: "modified.txt".into()
+                    }])
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use crate::sync_control::event::Event;
+use crate::sync_control::timing::FileTiming;
+
+/// what the controller is doing
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum SyncPhase {
+    /// waiting for the next event
+    #[default]
+    Idle,
+
+    /// handling the changes of the sync dir
+    Watch,
+
+    /// applying the rumors of the peers, the downloads are reported by the
+    /// [`Progress`](super::progress::Progress)
+    Rumors,
+
+    /// scanning the whole sync dir
+    SyncAll,
+
+    /// downloading the corrupted local files again
+    Repair,
+
+    /// sending the local index or exchanging the index digests with the peers, including
+    /// announcing the files on demand
+    AntiEntropy,
+}
+
+impl SyncPhase {
+    pub fn of(event: &Event) -> Self {
+        match event {
+            Event::Watch(_) | Event::ResolveConflict { .. } => SyncPhase::Watch,
+            Event::Rumors { .. } => SyncPhase::Rumors,
+            Event::SyncAll => SyncPhase::SyncAll,
+            Event::Repair(_) => SyncPhase::Repair,
+            Event::Announce { .. } | Event::Digests { .. } | Event::PeerConnected(_) => {
+                SyncPhase::AntiEntropy
+            }
+        }
+    }
+}
+
+/// the status of the controller, it is watched by [`SyncController::status`], so a UI can render
+/// it without polling the controller
+///
+/// [`SyncController::status`]: super::SyncController::status
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SyncStatus {
+    pub phase: SyncPhase,
+
+    /// the events handled since the controller started
+    pub handled_events: u64,
+
+    /// the error of the last failed event, the controller stops after it
+    pub last_error: Option<String>,
+
+    /// the stage timings of the recently synced files, the oldest first
+    pub recent_timings: Vec<FileTiming>,
+
+    /// no event comes for a while, the periodic tasks are lengthened until the next event
+    pub low_power: bool,
+
+    /// the sync is paused by the control, the received events are queued until it is resumed
+    pub paused: bool,
+}
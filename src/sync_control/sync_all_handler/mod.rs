@@ -1,31 +1,56 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::ffi::{OsStr, OsString};
-use std::io::ErrorKind;
+use std::io;
+use std::mem;
 use std::path::Path;
 use std::pin::pin;
-use std::time::SystemTime;
-use std::{io, mem};
 
-use anyhow::Result;
-use futures_util::{Sink, SinkExt, TryStreamExt};
+use anyhow::{anyhow, Result};
+use futures_util::stream::TryChunksError;
+use futures_util::{future, Sink, SinkExt, Stream, TryStreamExt};
 use tap::TapFallible;
-use tokio::fs;
-use tokio::fs::{DirEntry, File};
-use tokio_stream::wrappers::ReadDirStream;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 use uuid::Uuid;
 
-use crate::ext::hash_file;
-use crate::index::{FileDetail, FileKind, Index, IndexFile, IndexGuard};
+use crate::clock::{Clock, SystemClock};
+use crate::ext::{
+    hard_link_id, log_path, open_read, unsyncable_kind, walk_dir_sorted, WalkLimitError,
+};
+use crate::index::conflicts::is_conflict_file;
+use crate::index::{
+    BlockChain, FileDetail, FileKind, FileMetadata, Index, IndexFile, IndexGuard, Sha256sum,
+    VersionVector,
+};
+use crate::journal::{Journal, NoopJournal, OperationSource};
+use crate::sync_control::error::SyncError;
+use crate::sync_control::file_locks::FileLocks;
+use crate::sync_control::index_change::{unsyncable_file_change, IndexChange};
+use crate::sync_control::options::SyncOptions;
+use crate::sync_control::progress::{hash_file_with_report, ProgressReporter};
+use crate::sync_control::versioning::is_version_file;
 use crate::sync_control::SendRumors;
 
+/// how many files are handled in one index transaction, the rumors of a batch are sent after it
+/// is committed
+pub const SYNC_ALL_BATCH_SIZE: usize = 128;
+
 pub struct SyncAllHandler<'a, I, Si> {
     user_id: &'a Uuid,
     dir_id: &'a Uuid,
     sync_dir: &'a Path,
     index: &'a I,
     rumor_sender: Si,
+    options: SyncOptions,
+    clock: &'a dyn Clock,
+    journal: &'a dyn Journal,
+    file_locks: FileLocks,
+    progress: ProgressReporter,
+    shutdown: CancellationToken,
+    /// the hash results of the hard linked files, keyed by dev and inode, so the names of one
+    /// inode are hashed once in a scan
+    hard_links: HashMap<(u64, u64), HashedFile>,
 }
 
 impl<'a, I, Si> SyncAllHandler<'a, I, Si> {
@@ -42,253 +67,461 @@ impl<'a, I, Si> SyncAllHandler<'a, I, Si> {
             sync_dir,
             index,
             rumor_sender,
+            options: Default::default(),
+            clock: &SystemClock,
+            journal: &NoopJournal,
+            file_locks: Default::default(),
+            progress: Default::default(),
+            shutdown: Default::default(),
+            hard_links: Default::default(),
         }
     }
+
+    pub fn with_options(mut self, options: SyncOptions) -> Self {
+        self.options = options;
+
+        self
+    }
+
+    pub fn with_clock(mut self, clock: &'a dyn Clock) -> Self {
+        self.clock = clock;
+
+        self
+    }
+
+    pub fn with_journal(mut self, journal: &'a dyn Journal) -> Self {
+        self.journal = journal;
+
+        self
+    }
+
+    pub fn with_file_locks(mut self, file_locks: FileLocks) -> Self {
+        self.file_locks = file_locks;
+
+        self
+    }
+
+    pub fn with_progress(mut self, progress: ProgressReporter) -> Self {
+        self.progress = progress;
+
+        self
+    }
+
+    /// stop hashing the files when the token is canceled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+
+        self
+    }
 }
 
 impl<'a, I, Si> SyncAllHandler<'a, I, Si>
 where
     I: Index,
+    I::Guard: Send,
     <I::Guard as IndexGuard>::Error: Send + Sync + 'static,
     Si: Sink<SendRumors> + Unpin,
     Si::Error: Error + Send + Sync + 'static,
 {
-    pub async fn handle_sync_all_event(mut self) -> Result<()> {
-        let dir = self.sync_dir;
+    pub async fn handle_sync_all_event(mut self) -> Result<(), SyncError> {
+        let index = self.index;
+
+        // the index lists the files without blocking the writers, such as the sqlite index reads
+        // every page by its own transaction, so the batches are committed while listing
+        let index_files = index.list_all_files().await.map_err(SyncError::index)?;
+
+        info!("get all file index stream done");
+
+        let filenames = walk_dir_sorted(
+            self.sync_dir,
+            self.options.symlink_policy,
+            self.options.walk_limits,
+        )
+        .try_filter(|filename| {
+            future::ready(!is_conflict_file(filename) && !is_version_file(filename))
+        });
+
+        // every batch is committed then sent as soon as the walk fills it, so peers can start
+        // syncing before the whole scan is done
+        let mut batches = pin!(diff_dir(filenames, index_files).try_chunks(SYNC_ALL_BATCH_SIZE));
+        loop {
+            let batch = match batches.try_next().await {
+                // a pathological sync dir shouldn't stop the sync, the watch events and rumors
+                // are still handled, the committed batches are kept
+                Err(TryChunksError(_, err)) if is_walk_limit_error(&err) => {
+                    error!(%err, sync_dir = ?log_path(self.sync_dir), "sync dir exceeds the walk limits, stop sync all");
+
+                    return Ok(());
+                }
 
-        let read_dir = fs::read_dir(dir)
-            .await
-            .tap_err(|err| error!(%err, sync_dir = ?self.sync_dir, "read dir failed"))?;
-
-        let entries = ReadDirStream::new(read_dir)
-            .try_filter_map(|entry| async move {
-                let file_type = entry
-                    .file_type()
-                    .await
-                    .tap_err(|err| error!(%err, "get entry file type failed"))?;
-                let path = entry.path();
-                let path = path.strip_prefix(dir).map_err(|err| {
-                    error!(%err, "trim dir prefix failed");
-
-                    io::Error::new(ErrorKind::Other, err)
-                })?;
-
-                if file_type.is_dir() {
-                    Ok(None)
-                } else {
-                    Ok(Some((path.as_os_str().to_os_string(), entry)))
+                Err(TryChunksError(_, err)) => {
+                    error!(%err, "diff dir with index files failed");
+
+                    return Err(err.into());
                 }
-            })
-            .try_collect::<HashMap<_, _>>()
-            .await?;
 
-        let mut index_guard = self.index.begin().await?;
-        let index_files = {
-            info!("get index guard done");
+                Ok(None) => break,
+
+                Ok(Some(batch)) => batch,
+            };
 
-            let all_file_index_stream = index_guard.list_all_files().await?;
+            info!(changes = batch.len(), "diff dir batch done");
 
-            info!("get all file index stream done");
+            self.sync_batch(&batch).await?;
+        }
+
+        Ok(())
+    }
 
-            let all_file_index_stream = pin!(all_file_index_stream);
+    async fn sync_batch(&mut self, batch: &[(OsString, FileChange)]) -> Result<(), SyncError> {
+        // hold the locks until the batch is committed, so a rumor can't apply to the files
+        // between the update and the commit
+        let filenames = batch
+            .iter()
+            .map(|(filename, _)| filename.as_os_str())
+            .collect::<Vec<_>>();
+        let _file_lock_guards = self.file_locks.write_all(&filenames).await;
+
+        let mut index_guard = self.index.begin().await.map_err(SyncError::index)?;
+
+        info!("get index guard done");
+
+        let mut created = vec![];
+        let mut updated = vec![];
+        for (filename, change) in batch {
+            let index_change = match change {
+                FileChange::Delete => self.update_delete_file(filename, &mut index_guard).await?,
+                FileChange::New => self.update_new_file(filename, &mut index_guard).await?,
+                FileChange::Exists => self.update_exists_file(filename, &mut index_guard).await?,
+            };
+
+            match index_change {
+                None => {}
+                Some(IndexChange::Create(index_file)) => created.push(index_file),
+                Some(IndexChange::Update(index_file)) => updated.push(index_file),
+            }
+        }
 
-            all_file_index_stream
-                .map_ok(|index_file: IndexFile| (index_file.filename.clone(), index_file))
-                .try_collect::<HashMap<_, _>>()
+        // the first scan of a large dir creates many files, write them at once
+        if !created.is_empty() {
+            index_guard
+                .create_files(&created)
                 .await
-                .tap_err(|err| error!(%err, "collect all index files failed"))?
-        };
+                .map_err(SyncError::index)?;
+        }
+        if !updated.is_empty() {
+            index_guard
+                .update_files(&updated)
+                .await
+                .map_err(SyncError::index)?;
+        }
 
-        let new_files = get_new_files(&entries, &index_files);
-        let delete_files = get_delete_files(&entries, &index_files);
-        let exists_files = get_exists_files(&entries, &index_files);
+        index_guard.commit().await.map_err(SyncError::index)?;
 
-        let latest_file_index = self
-            .update_index(&new_files, &delete_files, &exists_files, index_guard)
-            .await?;
+        let rumors = created.into_iter().chain(updated).collect::<Vec<_>>();
 
-        info!("update index done");
+        info!(files = batch.len(), "commit index guard done");
 
-        self.send_rumors_to_all(latest_file_index).await?;
+        for rumor in &rumors {
+            self.journal
+                .record(*self.dir_id, OperationSource::SyncAll, rumor);
+        }
 
-        info!("send rumors to all done");
+        // only the changed files are sent, peers already know the unchanged ones
+        if !rumors.is_empty() {
+            self.send_rumors_to_all(rumors).await?;
+
+            info!("send rumors to all done");
+        }
 
         Ok(())
     }
 
-    async fn update_index(
+    /// when return None, means the file index is not changed, the caller writes the change
+    async fn update_delete_file(
         &mut self,
-        new_files: &[&OsStr],
-        delete_files: &[&OsStr],
-        exists_files: &[&OsStr],
-        mut index_guard: I::Guard,
-    ) -> Result<Vec<IndexFile>> {
-        for filename in delete_files {
-            match index_guard.get_file(filename).await? {
-                None => {
-                    error!(delete_file = ?filename, "delete file not found in index guard");
-
-                    return Err(anyhow::anyhow!(
-                        "delete file {:?} not found in index guard",
-                        filename
-                    ));
-                }
+        filename: &OsStr,
+        index_guard: &mut I::Guard,
+    ) -> Result<Option<IndexChange>> {
+        match index_guard
+            .get_file(filename)
+            .await
+            .map_err(SyncError::index)?
+        {
+            None => {
+                error!(delete_file = ?log_path(&filename), "delete file not found in index guard");
+
+                Err(SyncError::Conflict(format!(
+                    "delete file {:?} not found in index guard",
+                    filename
+                ))
+                .into())
+            }
 
-                Some(mut index_file) => {
-                    if index_file.detail.deleted {
-                        continue;
-                    }
-
-                    info!(delete_file = ?filename, "get delete file index done");
-
-                    let gen = index_file.detail.gen + 1;
-                    let mut old_detail = mem::replace(
-                        &mut index_file.detail,
-                        FileDetail {
-                            gen,
-                            hash_sum: [0; 32],
-                            block_chain: None,
-                            deleted: true,
-                        },
-                    );
-                    old_detail.block_chain.take();
-                    index_file.previous_details.push(old_detail);
-                    index_file.update_time = SystemTime::now();
-                    index_file.update_by = self.user_id.as_hyphenated().to_string();
-
-                    index_guard.update_file(&index_file).await?;
-
-                    info!(delete_file = ?filename, "update delete file index done");
+            Some(mut index_file) => {
+                if index_file.detail.deleted {
+                    return Ok(None);
                 }
+
+                info!(delete_file = ?log_path(&filename), "get delete file index done");
+
+                let gen = index_file.detail.gen + 1;
+                let version = index_file
+                    .detail
+                    .version
+                    .incremented(&self.user_id.as_hyphenated().to_string());
+                let mut old_detail = mem::replace(
+                    &mut index_file.detail,
+                    FileDetail {
+                        gen,
+                        hash_sum: [0; 32],
+                        block_chain: None,
+                        deleted: true,
+                        metadata: None,
+                        version,
+                        link_group: None,
+                    },
+                );
+                old_detail.block_chain.take();
+                index_file.previous_details.push(old_detail);
+                index_file.update_time = self.clock.now();
+                index_file.update_by = self.user_id.as_hyphenated().to_string();
+
+                info!(delete_file = ?log_path(&filename), "delete file index changed");
+
+                Ok(Some(IndexChange::Update(index_file)))
             }
         }
+    }
 
-        for filename in new_files {
-            let path = self.sync_dir.join(filename);
-            let file = File::open(&path)
-                .await
-                .tap_err(|err| error!(%err, ?path, "open file failed"))?;
-
-            info!(new_filename = ?filename, "open file done");
-
-            let (hash_sum, block_chain) = hash_file(file).await?;
-
-            info!(new_filename = ?filename, "hash file done");
-
-            match index_guard.get_file(filename).await? {
-                Some(mut index_file) => {
-                    if !index_file.detail.deleted && index_file.detail.hash_sum == hash_sum {
-                        continue;
-                    }
-
-                    let gen = index_file.detail.gen + 1;
-                    let mut old_detail = mem::replace(
-                        &mut index_file.detail,
-                        FileDetail {
-                            gen,
-                            hash_sum,
-                            block_chain: Some(block_chain),
-                            deleted: false,
-                        },
-                    );
-                    old_detail.block_chain.take();
-                    index_file.previous_details.push(old_detail);
-                    index_file.update_time = SystemTime::now();
-                    index_file.update_by = self.user_id.as_hyphenated().to_string();
-
-                    index_guard.update_file(&index_file).await?;
-
-                    info!(new_filename = ?filename, "update file index done");
-                }
+    /// when return None, means the file index is not changed, the caller writes the change
+    async fn update_new_file(
+        &mut self,
+        filename: &OsStr,
+        index_guard: &mut I::Guard,
+    ) -> Result<Option<IndexChange>> {
+        let path = self.sync_dir.join(filename);
+        if let Some(kind) = unsyncable_kind(&path, self.options.symlink_policy).await? {
+            return self
+                .update_unsyncable_file(filename, kind, index_guard)
+                .await;
+        }
+
+        let HashedFile {
+            hash_sum,
+            block_chain,
+            link_group,
+        } = self.hash_path(&path).await?;
+        let metadata = FileMetadata::from_path(&path)
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(&path), "get file metadata failed"))?;
+
+        info!(new_filename = ?log_path(&filename), "hash file done");
 
-                None => {
-                    let index_file = IndexFile {
-                        filename: filename.to_os_string(),
-                        kind: FileKind::File,
-                        detail: FileDetail {
-                            gen: 1,
-                            hash_sum,
-                            block_chain: Some(block_chain),
-                            deleted: false,
-                        },
-                        previous_details: vec![],
-                        update_time: SystemTime::now(),
-                        update_by: self.user_id.as_hyphenated().to_string(),
-                    };
-
-                    index_guard.create_file(&index_file).await?;
-
-                    info!(new_filename = ?filename, "create file index done");
+        match index_guard
+            .get_file(filename)
+            .await
+            .map_err(SyncError::index)?
+        {
+            Some(mut index_file) => {
+                if !index_file.detail.deleted
+                    && index_file.detail.hash_sum == hash_sum
+                    && index_file.detail.link_group == link_group
+                {
+                    return Ok(None);
                 }
+
+                let gen = index_file.detail.gen + 1;
+                let version = index_file
+                    .detail
+                    .version
+                    .incremented(&self.user_id.as_hyphenated().to_string());
+                let mut old_detail = mem::replace(
+                    &mut index_file.detail,
+                    FileDetail {
+                        gen,
+                        hash_sum,
+                        block_chain: Some(block_chain),
+                        deleted: false,
+                        metadata: Some(metadata),
+                        version,
+                        link_group,
+                    },
+                );
+                old_detail.block_chain.take();
+                index_file.kind = FileKind::File;
+                index_file.previous_details.push(old_detail);
+                index_file.update_time = self.clock.now();
+                index_file.update_by = self.user_id.as_hyphenated().to_string();
+
+                info!(new_filename = ?log_path(&filename), "file index changed");
+
+                Ok(Some(IndexChange::Update(index_file)))
+            }
+
+            None => {
+                let index_file = IndexFile {
+                    filename: filename.to_os_string(),
+                    kind: FileKind::File,
+                    detail: FileDetail {
+                        gen: 1,
+                        hash_sum,
+                        block_chain: Some(block_chain),
+                        deleted: false,
+                        metadata: Some(metadata),
+                        version: VersionVector::initial(&self.user_id.as_hyphenated().to_string()),
+                        link_group,
+                    },
+                    previous_details: vec![],
+                    update_time: self.clock.now(),
+                    update_by: self.user_id.as_hyphenated().to_string(),
+                };
+
+                info!(new_filename = ?log_path(&filename), "file index created");
+
+                Ok(Some(IndexChange::Create(index_file)))
             }
         }
+    }
 
-        for filename in exists_files {
-            let path = self.sync_dir.join(filename);
-            let file = File::open(&path)
-                .await
-                .tap_err(|err| error!(%err, ?path, "open file failed"))?;
-            let (hash_sum, block_chain) = hash_file(file).await?;
+    /// when return None, means the file index is not changed, the caller writes the change
+    async fn update_exists_file(
+        &mut self,
+        filename: &OsStr,
+        index_guard: &mut I::Guard,
+    ) -> Result<Option<IndexChange>> {
+        let path = self.sync_dir.join(filename);
+        if let Some(kind) = unsyncable_kind(&path, self.options.symlink_policy).await? {
+            return self
+                .update_unsyncable_file(filename, kind, index_guard)
+                .await;
+        }
 
-            match index_guard.get_file(filename).await? {
-                None => {
-                    error!(exists_filename = ?filename, "exists file not found in index guard");
+        let HashedFile {
+            hash_sum,
+            block_chain,
+            link_group,
+        } = self.hash_path(&path).await?;
+        let metadata = FileMetadata::from_path(&path)
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(&path), "get file metadata failed"))?;
 
-                    return Err(anyhow::anyhow!(
-                        "exists file {:?} not found in index guard",
-                        filename
-                    ));
-                }
+        match index_guard
+            .get_file(filename)
+            .await
+            .map_err(SyncError::index)?
+        {
+            None => {
+                error!(exists_filename = ?log_path(&filename), "exists file not found in index guard");
+
+                Err(SyncError::Conflict(format!(
+                    "exists file {:?} not found in index guard",
+                    filename
+                ))
+                .into())
+            }
 
-                Some(mut index_file) => {
-                    if index_file.detail.hash_sum == hash_sum {
-                        continue;
-                    }
-
-                    info!(exists_filename = ?filename, "get exists file index done");
-
-                    let gen = index_file.detail.gen + 1;
-                    let mut old_detail = mem::replace(
-                        &mut index_file.detail,
-                        FileDetail {
-                            gen,
-                            hash_sum,
-                            block_chain: Some(block_chain),
-                            deleted: false,
-                        },
-                    );
-                    old_detail.block_chain.take();
-                    index_file.previous_details.push(old_detail);
-                    index_file.update_time = SystemTime::now();
-                    index_file.update_by = self.user_id.as_hyphenated().to_string();
-
-                    index_guard.update_file(&index_file).await?;
-
-                    info!(exists_filename = ?filename, "update exists file index done");
+            Some(mut index_file) => {
+                if index_file.detail.hash_sum == hash_sum
+                    && index_file.detail.link_group == link_group
+                {
+                    return Ok(None);
                 }
+
+                info!(exists_filename = ?log_path(&filename), "get exists file index done");
+
+                let gen = index_file.detail.gen + 1;
+                let version = index_file
+                    .detail
+                    .version
+                    .incremented(&self.user_id.as_hyphenated().to_string());
+                let mut old_detail = mem::replace(
+                    &mut index_file.detail,
+                    FileDetail {
+                        gen,
+                        hash_sum,
+                        block_chain: Some(block_chain),
+                        deleted: false,
+                        metadata: Some(metadata),
+                        version,
+                        link_group,
+                    },
+                );
+                old_detail.block_chain.take();
+                index_file.kind = FileKind::File;
+                index_file.previous_details.push(old_detail);
+                index_file.update_time = self.clock.now();
+                index_file.update_by = self.user_id.as_hyphenated().to_string();
+
+                info!(exists_filename = ?log_path(&filename), "exists file index changed");
+
+                Ok(Some(IndexChange::Update(index_file)))
             }
         }
+    }
 
-        let index_files = {
-            let all_file_index_stream = index_guard.list_all_files().await?;
-
-            info!("get all file index stream done");
+    /// hash the file, the hard linked file reuses the hash result of its other names, and all
+    /// names of the inode are in the link group of the first scanned name
+    async fn hash_path(&mut self, path: &Path) -> Result<HashedFile> {
+        let link_id = hard_link_id(path)
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(&path), "get hard link id failed"))?;
+        if let Some(hashed) = link_id.and_then(|link_id| self.hard_links.get(&link_id)) {
+            info!(path = ?log_path(&path), "reuse hard link hash done");
 
-            let all_file_index_stream = pin!(all_file_index_stream);
+            return Ok(hashed.clone());
+        }
 
-            all_file_index_stream
-                .try_collect::<Vec<_>>()
-                .await
-                .tap_err(|err| error!(%err, "collect all index files failed"))?
+        let file = open_read(path, self.options.no_atime)
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(&path), "open file failed"))?;
+        let total_bytes = file
+            .metadata()
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(&path), "get file metadata failed"))?
+            .len();
+        let filename = path.strip_prefix(self.sync_dir).unwrap_or(path);
+        let (hash_sum, block_chain) = hash_file_with_report(
+            file,
+            filename.as_os_str(),
+            total_bytes,
+            self.options.block_size_policy.block_size(total_bytes),
+            &self.progress,
+            &self.shutdown,
+        )
+        .await?;
+
+        info!(path = ?log_path(&path), "hash file done");
+
+        let hashed = HashedFile {
+            hash_sum,
+            block_chain,
+            link_group: link_id.map(|_| filename.as_os_str().to_os_string()),
         };
+        if let Some(link_id) = link_id {
+            self.hard_links.insert(link_id, hashed.clone());
+        }
 
-        info!("collect all index file done");
-
-        index_guard.commit().await?;
-
-        info!("commit index guard done");
+        Ok(hashed)
+    }
 
-        Ok(index_files)
+    /// the changes of the scanned unsyncable files are written with the batch, the special file
+    /// fails the scan when the special file policy is error
+    async fn update_unsyncable_file(
+        &mut self,
+        filename: &OsStr,
+        kind: FileKind,
+        index_guard: &mut I::Guard,
+    ) -> Result<Option<IndexChange>> {
+        unsyncable_file_change(
+            filename,
+            kind,
+            self.options.special_file_policy,
+            &self.user_id.as_hyphenated().to_string(),
+            self.clock.now(),
+            index_guard,
+        )
+        .await
     }
 
     async fn send_rumors_to_all<Iter: IntoIterator<Item = IndexFile>>(
@@ -299,57 +532,104 @@ where
         let send_rumors = SendRumors {
             dir_id: *self.dir_id,
             rumors,
+            dir_renames: vec![],
             except: None,
+            to: None,
+            fanout: self.options.fanout,
         };
 
-        self.rumor_sender.send(send_rumors).await?;
+        self.rumor_sender
+            .send(send_rumors)
+            .await
+            .map_err(SyncError::transfer)?;
 
         Ok(())
     }
 }
 
-fn get_new_files<'a>(
-    entries: &'a HashMap<OsString, DirEntry>,
-    index_files: &'a HashMap<OsString, IndexFile>,
-) -> Vec<&'a OsStr> {
-    entries
-        .keys()
-        .filter_map(|filename| match index_files.get(filename) {
-            None => Some(filename.as_os_str()),
-            Some(index_file) => index_file.detail.deleted.then_some(filename.as_os_str()),
-        })
-        .collect::<Vec<_>>()
+/// the hash result of a scanned file
+#[derive(Debug, Clone)]
+struct HashedFile {
+    hash_sum: Sha256sum,
+    block_chain: BlockChain,
+    /// the first scanned name of the hard linked file
+    link_group: Option<OsString>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum FileChange {
+    New,
+    Delete,
+    Exists,
 }
 
-fn get_delete_files<'a>(
-    entries: &'a HashMap<OsString, DirEntry>,
-    index_files: &'a HashMap<OsString, IndexFile>,
-) -> Vec<&'a OsStr> {
-    index_files
-        .values()
-        .filter_map(|index_file| {
-            if index_file.detail.deleted {
-                None
-            } else {
-                (!entries.contains_key(&index_file.filename))
-                    .then_some(index_file.filename.as_os_str())
+/// sorted merge the filenames stream with the index files stream, both are sorted by filename,
+/// so only one filename and one index file are held at a time. The changes are streamed in the
+/// filename order, the unchanged deleted index files are skipped
+fn diff_dir<'s, F, S, E>(
+    filenames: F,
+    index_files: S,
+) -> impl Stream<Item = Result<(OsString, FileChange)>> + 's
+where
+    F: Stream<Item = io::Result<OsString>> + 's,
+    S: Stream<Item = Result<IndexFile, E>> + 's,
+    E: Error + Send + Sync + 'static,
+{
+    async_stream::try_stream! {
+        let mut filenames = pin!(filenames);
+        let mut index_files = pin!(index_files);
+        let mut filename = filenames.try_next().await?;
+        let mut last_filename: Option<OsString> = None;
+
+        while let Some(index_file) = index_files.try_next().await.map_err(SyncError::index)? {
+            if let Some(last_filename) = &last_filename {
+                if *last_filename >= index_file.filename {
+                    Err(SyncError::index(anyhow!(
+                        "index files are not sorted by filename, {:?} is after {:?}",
+                        index_file.filename,
+                        last_filename
+                    )))?;
+                }
             }
-        })
-        .collect()
+
+            // the files before the index file are not in the index
+            while filename
+                .as_ref()
+                .is_some_and(|filename| *filename < index_file.filename)
+            {
+                let new_filename = mem::replace(&mut filename, filenames.try_next().await?)
+                    .expect("filename is checked");
+
+                yield (new_filename, FileChange::New);
+            }
+
+            if filename.as_ref() == Some(&index_file.filename) {
+                filename = filenames.try_next().await?;
+
+                match index_file.detail.deleted {
+                    true => yield (index_file.filename.clone(), FileChange::New),
+                    false => yield (index_file.filename.clone(), FileChange::Exists),
+                }
+            } else if !index_file.detail.deleted {
+                yield (index_file.filename.clone(), FileChange::Delete);
+            }
+
+            last_filename = Some(index_file.filename);
+        }
+
+        // the rest files are not in the index
+        while let Some(new_filename) = filename {
+            yield (new_filename, FileChange::New);
+
+            filename = filenames.try_next().await?;
+        }
+    }
 }
 
-fn get_exists_files<'a>(
-    entries: &'a HashMap<OsString, DirEntry>,
-    index_files: &'a HashMap<OsString, IndexFile>,
-) -> Vec<&'a OsStr> {
-    entries
-        .keys()
-        .filter(|filename| match index_files.get(*filename) {
-            None => false,
-            Some(index_file) => !index_file.detail.deleted,
-        })
-        .map(|filename| filename.as_os_str())
-        .collect()
+/// the walk failed because the sync dir exceeds the walk limits
+fn is_walk_limit_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(WalkLimitError::is_walk_limit_error)
 }
 
 #[cfg(test)]
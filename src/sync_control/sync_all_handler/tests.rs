@@ -1,13 +1,17 @@
 use std::env;
+use std::ffi::OsStr;
+use std::io;
 use std::io::Cursor;
+use std::time::SystemTime;
 
 use futures_util::stream;
 use mockall::predicate::*;
 use tempfile::TempDir;
+use tokio::fs;
 
 use super::*;
-use crate::ext::hash_file;
-use crate::index::{MockIndex, MockIndexGuard};
+use crate::ext::{hash_file, WalkLimits};
+use crate::index::{FileMetadata, MockIndex, MockIndexGuard, VersionVector};
 
 #[tokio::test]
 async fn all_empty() {
@@ -16,37 +20,106 @@ async fn all_empty() {
     let dir_id = Uuid::new_v4();
     let mut index = MockIndex::new();
 
-    index.expect_begin().returning(move || {
+    index
+        .expect_list_all_files()
+        .times(1)
+        .returning(|| Ok(Box::pin(stream::iter([]))));
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = SyncAllHandler::new(&user_id, &dir_id, dir.path(), &index, sender.into_sink());
+
+    handler.handle_sync_all_event().await.unwrap();
+
+    // nothing to sync, no index transaction and no rumors
+    receiver.recv_async().await.unwrap_err();
+}
+
+#[tokio::test]
+async fn exceed_walk_limits() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("a.txt"), b"a").await.unwrap();
+    fs::write(dir.path().join("b.txt"), b"b").await.unwrap();
+
+    index
+        .expect_list_all_files()
+        .times(1)
+        .returning(|| Ok(Box::pin(stream::iter([]))));
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = SyncAllHandler::new(&user_id, &dir_id, dir.path(), &index, sender.into_sink())
+        .with_options(SyncOptions {
+            walk_limits: WalkLimits {
+                max_entries: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+    // no index transaction is begun
+    handler.handle_sync_all_event().await.unwrap();
+
+    receiver.recv_async().await.unwrap_err();
+}
+
+#[tokio::test]
+async fn exceed_walk_limits_after_batch() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    for i in 0..SYNC_ALL_BATCH_SIZE + 2 {
+        fs::write(dir.path().join(format!("{i:04}.txt")), b"test")
+            .await
+            .unwrap();
+    }
+
+    index
+        .expect_list_all_files()
+        .times(1)
+        .returning(|| Ok(Box::pin(stream::iter([]))));
+
+    // only the first batch is filled before the walk exceeds the limits
+    index.expect_begin().times(1).returning(|| {
         let mut index_guard = MockIndexGuard::new();
-        index_guard
-            .expect_list_all_files()
-            .times(1)
-            .returning(|| Ok(Box::pin(stream::iter([]))));
 
+        index_guard.expect_get_file().returning(|_| Ok(None));
         index_guard
-            .expect_list_all_files()
+            .expect_create_files()
+            .with(function(|args: &[IndexFile]| {
+                args.len() == SYNC_ALL_BATCH_SIZE
+            }))
             .times(1)
-            .returning(|| Ok(Box::pin(stream::iter([]))));
-
-        index_guard.expect_commit().returning(|| Ok(()));
+            .returning(|_| Ok(()));
+        index_guard.expect_commit().times(1).returning(|| Ok(()));
 
         Ok(index_guard)
     });
 
     let (sender, receiver) = flume::bounded(1);
 
-    let handler = SyncAllHandler::new(&user_id, &dir_id, dir.path(), &index, sender.into_sink());
+    let handler = SyncAllHandler::new(&user_id, &dir_id, dir.path(), &index, sender.into_sink())
+        .with_options(SyncOptions {
+            walk_limits: WalkLimits {
+                max_entries: SYNC_ALL_BATCH_SIZE + 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
 
     handler.handle_sync_all_event().await.unwrap();
 
-    assert_eq!(
-        receiver.recv_async().await.unwrap(),
-        SendRumors {
-            dir_id,
-            rumors: vec![],
-            except: None,
-        }
-    );
+    let rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(rumors.rumors.len(), SYNC_ALL_BATCH_SIZE);
+    assert_eq!(rumors.rumors[0].filename, OsStr::new("0000.txt"));
+
+    receiver.recv_async().await.unwrap_err();
 }
 
 #[tokio::test]
@@ -54,7 +127,6 @@ async fn empty_index() {
     let dir = TempDir::new_in(env::temp_dir()).unwrap();
     let user_id = Uuid::new_v4();
     let dir_id = Uuid::new_v4();
-    let update_time = SystemTime::now();
     let mut index = MockIndex::new();
 
     fs::write(dir.path().join("test.txt"), b"test")
@@ -63,6 +135,11 @@ async fn empty_index() {
 
     let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
 
+    index
+        .expect_list_all_files()
+        .times(1)
+        .returning(|| Ok(Box::pin(stream::iter([]))));
+
     {
         let block_chain = block_chain.clone();
 
@@ -70,10 +147,6 @@ async fn empty_index() {
             let block_chain = block_chain.clone();
 
             let mut index_guard = MockIndexGuard::new();
-            index_guard
-                .expect_list_all_files()
-                .times(1)
-                .returning(|| Ok(Box::pin(stream::iter([]))));
 
             index_guard
                 .expect_get_file()
@@ -84,8 +157,10 @@ async fn empty_index() {
                 let block_chain = block_chain.clone();
 
                 index_guard
-                    .expect_create_file()
-                    .with(function(move |arg: &IndexFile| {
+                    .expect_create_files()
+                    .with(function(move |args: &[IndexFile]| {
+                        let [arg] = args else { return false };
+
                         arg.filename == OsStr::new("test.txt")
                             && arg.kind == FileKind::File
                             && arg.detail
@@ -94,6 +169,11 @@ async fn empty_index() {
                                     hash_sum,
                                     block_chain: Some(block_chain.clone()),
                                     deleted: false,
+                                    metadata: arg.detail.metadata,
+                                    version: VersionVector::initial(
+                                        &user_id.as_hyphenated().to_string(),
+                                    ),
+                                    link_group: None,
                                 }
                             && arg.previous_details.is_empty()
                             && arg.update_by == user_id.as_hyphenated().to_string()
@@ -101,25 +181,6 @@ async fn empty_index() {
                     .returning(|_| Ok(()));
             }
 
-            index_guard
-                .expect_list_all_files()
-                .times(1)
-                .returning(move || {
-                    Ok(Box::pin(stream::iter([Ok(IndexFile {
-                        filename: OsString::from("test.txt"),
-                        kind: FileKind::File,
-                        detail: FileDetail {
-                            gen: 1,
-                            hash_sum,
-                            block_chain: Some(block_chain.clone()),
-                            deleted: false,
-                        },
-                        previous_details: vec![],
-                        update_time,
-                        update_by: user_id.as_hyphenated().to_string(),
-                    })])))
-                });
-
             index_guard.expect_commit().returning(|| Ok(()));
 
             Ok(index_guard)
@@ -147,6 +208,13 @@ async fn empty_index() {
             hash_sum,
             block_chain: Some(block_chain.clone()),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert!(rumor.previous_details.is_empty());
@@ -163,34 +231,35 @@ async fn empty_dir_with_index() {
 
     let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
 
+    {
+        let block_chain = block_chain.clone();
+
+        index.expect_list_all_files().times(1).returning(move || {
+            Ok(Box::pin(stream::iter([Ok(IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 1,
+                    hash_sum,
+                    block_chain: Some(block_chain.clone()),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time,
+                update_by: user_id.as_hyphenated().to_string(),
+            })])))
+        });
+    }
+
     {
         let block_chain = block_chain.clone();
 
         index.expect_begin().returning(move || {
             let mut index_guard = MockIndexGuard::new();
 
-            {
-                let block_chain = block_chain.clone();
-                index_guard
-                    .expect_list_all_files()
-                    .times(1)
-                    .returning(move || {
-                        Ok(Box::pin(stream::iter([Ok(IndexFile {
-                            filename: OsString::from("test.txt"),
-                            kind: FileKind::File,
-                            detail: FileDetail {
-                                gen: 1,
-                                hash_sum,
-                                block_chain: Some(block_chain.clone()),
-                                deleted: false,
-                            },
-                            previous_details: vec![],
-                            update_time,
-                            update_by: user_id.as_hyphenated().to_string(),
-                        })])))
-                    });
-            }
-
             let block_chain = block_chain.clone();
 
             index_guard
@@ -205,6 +274,9 @@ async fn empty_dir_with_index() {
                             hash_sum,
                             block_chain: Some(block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![],
                         update_time,
@@ -213,8 +285,10 @@ async fn empty_dir_with_index() {
                 });
 
             index_guard
-                .expect_update_file()
-                .with(function(move |arg: &IndexFile| {
+                .expect_update_files()
+                .with(function(move |args: &[IndexFile]| {
+                    let [arg] = args else { return false };
+
                     arg.filename == OsStr::new("test.txt")
                         && arg.kind == FileKind::File
                         && arg.detail
@@ -223,6 +297,11 @@ async fn empty_dir_with_index() {
                                 hash_sum: [0; 32],
                                 block_chain: None,
                                 deleted: true,
+                                metadata: None,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![FileDetail {
@@ -230,35 +309,14 @@ async fn empty_dir_with_index() {
                                 hash_sum,
                                 block_chain: None,
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }]
                         && arg.update_by == user_id.as_hyphenated().to_string()
                 }))
                 .returning(|_| Ok(()));
 
-            index_guard
-                .expect_list_all_files()
-                .times(1)
-                .returning(move || {
-                    Ok(Box::pin(stream::iter([Ok(IndexFile {
-                        filename: OsString::from("test.txt"),
-                        kind: FileKind::File,
-                        detail: FileDetail {
-                            gen: 2,
-                            hash_sum: [0; 32],
-                            block_chain: None,
-                            deleted: true,
-                        },
-                        previous_details: vec![FileDetail {
-                            gen: 1,
-                            hash_sum,
-                            block_chain: None,
-                            deleted: false,
-                        }],
-                        update_time,
-                        update_by: user_id.as_hyphenated().to_string(),
-                    })])))
-                });
-
             index_guard.expect_commit().returning(|| Ok(()));
 
             Ok(index_guard)
@@ -286,6 +344,9 @@ async fn empty_dir_with_index() {
             hash_sum: [0; 32],
             block_chain: None,
             deleted: true,
+            metadata: None,
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -295,6 +356,9 @@ async fn empty_dir_with_index() {
             hash_sum,
             block_chain: None,
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         }]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
@@ -315,6 +379,29 @@ async fn index_old() {
     let (old_hash_sum, old_block_chain) = hash_file(Cursor::new(b"old")).await.unwrap();
     let (new_hash_sum, new_block_chain) = hash_file(Cursor::new(b"new")).await.unwrap();
 
+    {
+        let old_block_chain = old_block_chain.clone();
+
+        index.expect_list_all_files().times(1).returning(move || {
+            Ok(Box::pin(stream::iter([Ok(IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 1,
+                    hash_sum: old_hash_sum,
+                    block_chain: Some(old_block_chain.clone()),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time,
+                update_by: user_id.as_hyphenated().to_string(),
+            })])))
+        });
+    }
+
     {
         let old_block_chain = old_block_chain.clone();
         let new_block_chain = new_block_chain.clone();
@@ -322,29 +409,6 @@ async fn index_old() {
         index.expect_begin().returning(move || {
             let mut index_guard = MockIndexGuard::new();
 
-            {
-                let old_block_chain = old_block_chain.clone();
-
-                index_guard
-                    .expect_list_all_files()
-                    .times(1)
-                    .returning(move || {
-                        Ok(Box::pin(stream::iter([Ok(IndexFile {
-                            filename: OsString::from("test.txt"),
-                            kind: FileKind::File,
-                            detail: FileDetail {
-                                gen: 1,
-                                hash_sum: old_hash_sum,
-                                block_chain: Some(old_block_chain.clone()),
-                                deleted: false,
-                            },
-                            previous_details: vec![],
-                            update_time,
-                            update_by: user_id.as_hyphenated().to_string(),
-                        })])))
-                    });
-            }
-
             let old_block_chain = old_block_chain.clone();
 
             index_guard
@@ -359,6 +423,9 @@ async fn index_old() {
                             hash_sum: old_hash_sum,
                             block_chain: Some(old_block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![],
                         update_time,
@@ -370,8 +437,10 @@ async fn index_old() {
                 let new_block_chain = new_block_chain.clone();
 
                 index_guard
-                    .expect_update_file()
-                    .with(function(move |arg: &IndexFile| {
+                    .expect_update_files()
+                    .with(function(move |args: &[IndexFile]| {
+                        let [arg] = args else { return false };
+
                         arg.filename == OsStr::new("test.txt")
                             && arg.kind == FileKind::File
                             && arg.detail
@@ -380,6 +449,11 @@ async fn index_old() {
                                     hash_sum: new_hash_sum,
                                     block_chain: Some(new_block_chain.clone()),
                                     deleted: false,
+                                    metadata: arg.detail.metadata,
+                                    version: VersionVector::initial(
+                                        &user_id.as_hyphenated().to_string(),
+                                    ),
+                                    link_group: None,
                                 }
                             && arg.previous_details
                                 == vec![FileDetail {
@@ -387,38 +461,15 @@ async fn index_old() {
                                     hash_sum: old_hash_sum,
                                     block_chain: None,
                                     deleted: false,
+                                    metadata: None,
+                                    version: Default::default(),
+                                    link_group: None,
                                 }]
                             && arg.update_by == user_id.as_hyphenated().to_string()
                     }))
                     .returning(|_| Ok(()));
             }
 
-            let new_block_chain = new_block_chain.clone();
-
-            index_guard
-                .expect_list_all_files()
-                .times(1)
-                .returning(move || {
-                    Ok(Box::pin(stream::iter([Ok(IndexFile {
-                        filename: OsString::from("test.txt"),
-                        kind: FileKind::File,
-                        detail: FileDetail {
-                            gen: 2,
-                            hash_sum: new_hash_sum,
-                            block_chain: Some(new_block_chain.clone()),
-                            deleted: false,
-                        },
-                        previous_details: vec![FileDetail {
-                            gen: 1,
-                            hash_sum: old_hash_sum,
-                            block_chain: None,
-                            deleted: false,
-                        }],
-                        update_time,
-                        update_by: user_id.as_hyphenated().to_string(),
-                    })])))
-                });
-
             index_guard.expect_commit().returning(|| Ok(()));
 
             Ok(index_guard)
@@ -446,6 +497,13 @@ async fn index_old() {
             hash_sum: new_hash_sum,
             block_chain: Some(new_block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -455,6 +513,9 @@ async fn index_old() {
             hash_sum: old_hash_sum,
             block_chain: None,
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         }]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
@@ -477,31 +538,31 @@ async fn no_changed() {
     {
         let block_chain = block_chain.clone();
 
-        index.expect_begin().returning(move || {
-            let mut index_guard = MockIndexGuard::new();
+        index.expect_list_all_files().times(1).returning(move || {
+            Ok(Box::pin(stream::iter([Ok(IndexFile {
+                filename: OsString::from("test.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 1,
+                    hash_sum,
+                    block_chain: Some(block_chain.clone()),
+                    deleted: false,
+                    metadata: None,
+                    version: Default::default(),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time,
+                update_by: user_id.as_hyphenated().to_string(),
+            })])))
+        });
+    }
 
-            {
-                let block_chain = block_chain.clone();
+    {
+        let block_chain = block_chain.clone();
 
-                index_guard
-                    .expect_list_all_files()
-                    .times(1)
-                    .returning(move || {
-                        Ok(Box::pin(stream::iter([Ok(IndexFile {
-                            filename: OsString::from("test.txt"),
-                            kind: FileKind::File,
-                            detail: FileDetail {
-                                gen: 1,
-                                hash_sum,
-                                block_chain: Some(block_chain.clone()),
-                                deleted: false,
-                            },
-                            previous_details: vec![],
-                            update_time,
-                            update_by: user_id.as_hyphenated().to_string(),
-                        })])))
-                    });
-            }
+        index.expect_begin().returning(move || {
+            let mut index_guard = MockIndexGuard::new();
 
             {
                 let block_chain = block_chain.clone();
@@ -518,6 +579,9 @@ async fn no_changed() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             },
                             previous_details: vec![],
                             update_time,
@@ -526,27 +590,6 @@ async fn no_changed() {
                     });
             }
 
-            let block_chain = block_chain.clone();
-
-            index_guard
-                .expect_list_all_files()
-                .times(1)
-                .returning(move || {
-                    Ok(Box::pin(stream::iter([Ok(IndexFile {
-                        filename: OsString::from("test.txt"),
-                        kind: FileKind::File,
-                        detail: FileDetail {
-                            gen: 1,
-                            hash_sum,
-                            block_chain: Some(block_chain.clone()),
-                            deleted: false,
-                        },
-                        previous_details: vec![],
-                        update_time,
-                        update_by: user_id.as_hyphenated().to_string(),
-                    })])))
-                });
-
             index_guard.expect_commit().returning(|| Ok(()));
 
             Ok(index_guard)
@@ -559,23 +602,129 @@ async fn no_changed() {
 
     handler.handle_sync_all_event().await.unwrap();
 
-    let mut rumors = receiver.recv_async().await.unwrap();
-    assert!(rumors.except.is_none());
-    assert_eq!(rumors.rumors.len(), 1);
+    // the unchanged file is not sent again
+    receiver.recv_async().await.unwrap_err();
+}
 
-    let rumor = rumors.rumors.remove(0);
+#[tokio::test]
+async fn diff_dir_sorted_merge() {
+    let index_file = |filename: &str, deleted| IndexFile {
+        filename: OsString::from(filename),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen: 1,
+            hash_sum: [0; 32],
+            block_chain: None,
+            deleted,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![],
+        update_time: SystemTime::UNIX_EPOCH,
+        update_by: String::new(),
+    };
+
+    let filenames = ["a", "c", "d", "f"].map(OsString::from).to_vec();
+    let index_files = [
+        index_file("b", false),
+        index_file("c", false),
+        index_file("d", true),
+        index_file("e", true),
+        index_file("g", false),
+    ];
+
+    let filenames_stream = |filenames: &[OsString]| {
+        stream::iter(filenames.to_vec().into_iter().map(Ok::<_, io::Error>))
+    };
+
+    let changes = diff_dir(
+        filenames_stream(&filenames),
+        stream::iter(index_files.clone().map(Ok::<_, io::Error>)),
+    )
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap();
 
-    assert_eq!(rumor.filename, OsStr::new("test.txt"));
-    assert_eq!(rumor.kind, FileKind::File);
     assert_eq!(
-        rumor.detail,
-        FileDetail {
-            gen: 1,
-            hash_sum,
-            block_chain: Some(block_chain.clone()),
-            deleted: false,
-        }
+        changes,
+        [
+            ("a", FileChange::New),
+            ("b", FileChange::Delete),
+            ("c", FileChange::Exists),
+            ("d", FileChange::New),
+            ("f", FileChange::New),
+            ("g", FileChange::Delete),
+        ]
+        .map(|(filename, change)| (OsString::from(filename), change))
+    );
+
+    let mut index_files = index_files;
+    index_files.reverse();
+    diff_dir(
+        filenames_stream(&filenames),
+        stream::iter(index_files.map(Ok::<_, io::Error>)),
+    )
+    .try_collect::<Vec<_>>()
+    .await
+    .unwrap_err();
+}
+
+#[tokio::test]
+async fn hard_linked_files() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("a.txt"), b"test").await.unwrap();
+    fs::hard_link(dir.path().join("a.txt"), dir.path().join("b.txt"))
+        .await
+        .unwrap();
+    fs::write(dir.path().join("c.txt"), b"test").await.unwrap();
+
+    index
+        .expect_list_all_files()
+        .times(1)
+        .returning(|| Ok(Box::pin(stream::iter([]))));
+
+    index.expect_begin().times(1).returning(|| {
+        let mut index_guard = MockIndexGuard::new();
+
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard
+            .expect_create_files()
+            .times(1)
+            .returning(|_| Ok(()));
+        index_guard.expect_commit().times(1).returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let (sender, receiver) = flume::bounded(1);
+
+    let handler = SyncAllHandler::new(&user_id, &dir_id, dir.path(), &index, sender.into_sink());
+
+    handler.handle_sync_all_event().await.unwrap();
+
+    // the names of one inode are in the group of the first scanned name
+    let rumors = receiver.recv_async().await.unwrap();
+    let link_groups = rumors
+        .rumors
+        .iter()
+        .map(|rumor| (rumor.filename.clone(), rumor.detail.link_group.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        link_groups,
+        [
+            ("a.txt", Some(OsString::from("a.txt"))),
+            ("b.txt", Some(OsString::from("a.txt"))),
+            ("c.txt", None),
+        ]
+        .map(|(filename, link_group)| (OsString::from(filename), link_group))
+    );
+    assert_eq!(
+        rumors.rumors[0].detail.hash_sum,
+        rumors.rumors[1].detail.hash_sum
     );
-    assert!(rumor.previous_details.is_empty());
-    assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
 }
@@ -0,0 +1,231 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::ext::log_path;
+
+/// the finished file timings kept for the status
+pub const DEFAULT_RECENT_TIMINGS: usize = 16;
+
+/// a stage of syncing a remote file
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncStage {
+    /// waiting for the concurrency slot and the file lock
+    QueueWait,
+    /// waiting for the blocks from the network
+    Download,
+    /// writing the blocks and the fsync
+    Write,
+    /// hashing the assembled file
+    Verify,
+    /// moving the temp file to the target file
+    Rename,
+    IndexCommit,
+}
+
+/// where the time of syncing a file is spent, so the slowness can be told apart between the
+/// network, the disk and the index
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileTiming {
+    pub filename: OsString,
+    /// when the file is synced
+    pub finished_at: SystemTime,
+    pub queue_wait: Duration,
+    pub download: Duration,
+    pub write: Duration,
+    pub verify: Duration,
+    pub rename: Duration,
+    pub index_commit: Duration,
+}
+
+impl FileTiming {
+    fn new(filename: OsString) -> Self {
+        Self {
+            filename,
+            finished_at: SystemTime::UNIX_EPOCH,
+            queue_wait: Duration::ZERO,
+            download: Duration::ZERO,
+            write: Duration::ZERO,
+            verify: Duration::ZERO,
+            rename: Duration::ZERO,
+            index_commit: Duration::ZERO,
+        }
+    }
+
+    pub fn stages(&self) -> [(SyncStage, Duration); 6] {
+        [
+            (SyncStage::QueueWait, self.queue_wait),
+            (SyncStage::Download, self.download),
+            (SyncStage::Write, self.write),
+            (SyncStage::Verify, self.verify),
+            (SyncStage::Rename, self.rename),
+            (SyncStage::IndexCommit, self.index_commit),
+        ]
+    }
+
+    pub fn total(&self) -> Duration {
+        self.stages().iter().map(|(_, elapsed)| *elapsed).sum()
+    }
+
+    /// the stage which takes the most time, None when nothing is timed
+    pub fn slowest_stage(&self) -> Option<SyncStage> {
+        self.stages()
+            .into_iter()
+            .filter(|(_, elapsed)| !elapsed.is_zero())
+            .max_by_key(|(_, elapsed)| *elapsed)
+            .map(|(stage, _)| stage)
+    }
+
+    fn stage_mut(&mut self, stage: SyncStage) -> &mut Duration {
+        match stage {
+            SyncStage::QueueWait => &mut self.queue_wait,
+            SyncStage::Download => &mut self.download,
+            SyncStage::Write => &mut self.write,
+            SyncStage::Verify => &mut self.verify,
+            SyncStage::Rename => &mut self.rename,
+            SyncStage::IndexCommit => &mut self.index_commit,
+        }
+    }
+}
+
+/// collect the stage timings of the syncing files, the timing of a file is finished after its
+/// rumor is handled, and the recent finished timings are kept. The clones share the timings
+#[derive(Debug, Clone)]
+pub struct SyncTimings {
+    recent_len: usize,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_flight: HashMap<OsString, FileTiming>,
+    recent: VecDeque<FileTiming>,
+}
+
+impl Default for SyncTimings {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECENT_TIMINGS)
+    }
+}
+
+impl SyncTimings {
+    pub fn new(recent_len: usize) -> Self {
+        Self {
+            recent_len,
+            state: Default::default(),
+        }
+    }
+
+    /// add the elapsed time to the stage of the file, a stage may be timed many times, such as
+    /// the downloads of the blocks
+    pub fn record(&self, filename: &OsStr, stage: SyncStage, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let timing = state
+            .in_flight
+            .entry(filename.to_os_string())
+            .or_insert_with(|| FileTiming::new(filename.to_os_string()));
+
+        *timing.stage_mut(stage) += elapsed;
+    }
+
+    /// time the future as the stage of the file
+    pub async fn time<F: Future>(&self, filename: &OsStr, stage: SyncStage, fut: F) -> F::Output {
+        let start = Instant::now();
+        let output = fut.await;
+        self.record(filename, stage, start.elapsed());
+
+        output
+    }
+
+    /// finish the timing of the file and keep it as a recent timing, return None when the file
+    /// is not timed
+    pub fn finish(&self, filename: &OsStr, now: SystemTime) -> Option<FileTiming> {
+        let mut state = self.state.lock().unwrap();
+        let mut timing = state.in_flight.remove(filename)?;
+        timing.finished_at = now;
+
+        info!(
+            filename = ?log_path(filename),
+            total = ?timing.total(),
+            slowest_stage = ?timing.slowest_stage(),
+            "sync file timing done"
+        );
+
+        if self.recent_len > 0 {
+            if state.recent.len() >= self.recent_len {
+                state.recent.pop_front();
+            }
+            state.recent.push_back(timing.clone());
+        }
+
+        Some(timing)
+    }
+
+    /// drop the timing of the file which is not synced, such as the old rumor
+    pub fn discard(&self, filename: &OsStr) {
+        self.state.lock().unwrap().in_flight.remove(filename);
+    }
+
+    /// the recent finished timings, the oldest first
+    pub fn recent(&self) -> Vec<FileTiming> {
+        self.state.lock().unwrap().recent.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn record_and_finish() {
+        let timings = SyncTimings::new(2);
+        let filename = OsStr::new("test.txt");
+
+        timings.record(filename, SyncStage::Download, Duration::from_millis(30));
+        timings.record(filename, SyncStage::Download, Duration::from_millis(30));
+        timings.record(filename, SyncStage::Write, Duration::from_millis(20));
+        let output = timings
+            .time(filename, SyncStage::IndexCommit, async { 1 })
+            .await;
+        assert_eq!(output, 1);
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let timing = timings.finish(filename, now).unwrap();
+        assert_eq!(timing.finished_at, now);
+        assert_eq!(timing.download, Duration::from_millis(60));
+        assert_eq!(timing.slowest_stage(), Some(SyncStage::Download));
+        assert!(timing.total() >= Duration::from_millis(80));
+        assert!(timings.finish(filename, now).is_none());
+
+        // the discarded timing is not kept
+        timings.record(
+            OsStr::new("old.txt"),
+            SyncStage::QueueWait,
+            Duration::from_millis(1),
+        );
+        timings.discard(OsStr::new("old.txt"));
+        assert!(timings.finish(OsStr::new("old.txt"), now).is_none());
+
+        // only the recent timings are kept
+        for filename in ["a.txt", "b.txt"] {
+            timings.record(
+                OsStr::new(filename),
+                SyncStage::Rename,
+                Duration::from_millis(1),
+            );
+            timings.finish(OsStr::new(filename), now);
+        }
+        let recent = timings
+            .recent()
+            .into_iter()
+            .map(|timing| timing.filename)
+            .collect::<Vec<_>>();
+        assert_eq!(recent, ["a.txt", "b.txt"]);
+    }
+}
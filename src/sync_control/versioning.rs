@@ -0,0 +1,291 @@
+use std::cmp::Reverse;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tap::TapFallible;
+use tokio::fs;
+use tracing::{error, info};
+
+use crate::ext::log_path;
+
+/// the dir in the sync dir which keeps the old versions, it is never synced
+pub const VERSIONS_DIR: &str = ".syncit-versions";
+
+/// the version time suffix of the archived file, `<filename>~<time>`
+const VERSION_TIME_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+
+/// how long the old versions of the deleted and overwritten files are kept, like the
+/// `.stversions` of syncthing. The limits are checked when a version is archived
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct VersioningPolicy {
+    /// the versions kept for a file, 0 means no limit
+    pub keep_versions: usize,
+
+    /// the versions older than it are removed, zero means no limit
+    pub max_age: Duration,
+
+    /// the max bytes of all versions, the oldest versions are removed first, 0 means no limit
+    pub max_bytes: u64,
+}
+
+/// the archived versions are in the versions dir
+pub fn is_version_file(filename: impl AsRef<Path>) -> bool {
+    filename.as_ref().starts_with(VERSIONS_DIR)
+}
+
+/// move the local file into the versions dir before it is deleted or replaced, when return false,
+/// the path is not a regular file and nothing is archived
+pub async fn archive(
+    sync_dir: &Path,
+    filename: &OsStr,
+    now: SystemTime,
+    policy: &VersioningPolicy,
+) -> io::Result<bool> {
+    let path = sync_dir.join(filename);
+    match fs::symlink_metadata(&path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => {
+            error!(%err, path = ?log_path(&path), "get file metadata failed");
+
+            return Err(err);
+        }
+
+        Ok(metadata) if !metadata.is_file() => return Ok(false),
+        Ok(_) => {}
+    }
+
+    let mut version_name = OsString::from(filename);
+    version_name.push(format!(
+        "~{}",
+        DateTime::<Utc>::from(now).format(VERSION_TIME_FORMAT)
+    ));
+    let version_path = sync_dir.join(VERSIONS_DIR).join(&version_name);
+    if let Some(parent) = version_path.parent() {
+        fs::create_dir_all(parent).await.tap_err(
+            |err| error!(%err, parent = ?log_path(parent), "create versions dir failed"),
+        )?;
+    }
+
+    fs::rename(&path, &version_path).await.tap_err(
+        |err| error!(%err, path = ?log_path(&path), version_path = ?log_path(&version_path), "archive file failed"),
+    )?;
+
+    info!(path = ?log_path(&path), version_path = ?log_path(&version_path), "archive file done");
+
+    prune_file_versions(&version_path, filename, now, policy).await?;
+    if policy.max_bytes > 0 {
+        prune_versions_size(&sync_dir.join(VERSIONS_DIR), policy.max_bytes).await?;
+    }
+
+    Ok(true)
+}
+
+/// remove the versions of the file which exceed the keep versions or the max age
+async fn prune_file_versions(
+    version_path: &Path,
+    filename: &OsStr,
+    now: SystemTime,
+    policy: &VersioningPolicy,
+) -> io::Result<()> {
+    if policy.keep_versions == 0 && policy.max_age.is_zero() {
+        return Ok(());
+    }
+
+    let (Some(dir), Some(name)) = (version_path.parent(), Path::new(filename).file_name()) else {
+        return Ok(());
+    };
+
+    let mut versions = vec![];
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(time) = version_time(&entry.file_name(), Some(name)) {
+            versions.push((time, entry.path()));
+        }
+    }
+    versions.sort_unstable_by_key(|(time, _)| Reverse(*time));
+
+    for (i, (time, path)) in versions.into_iter().enumerate() {
+        let too_many = policy.keep_versions > 0 && i >= policy.keep_versions;
+        let too_old = !policy.max_age.is_zero()
+            && now.duration_since(time).unwrap_or_default() > policy.max_age;
+        if too_many || too_old {
+            remove_version(&path).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// remove the oldest versions until all versions fit the max bytes
+async fn prune_versions_size(versions_dir: &Path, max_bytes: u64) -> io::Result<()> {
+    let mut versions = vec![];
+    let mut dirs = vec![versions_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if let Some(time) = version_time(&entry.file_name(), None) {
+                versions.push((time, metadata.len(), entry.path()));
+            }
+        }
+    }
+
+    let mut total_bytes = versions.iter().map(|(_, len, _)| len).sum::<u64>();
+    versions.sort_unstable_by_key(|(time, _, _)| *time);
+    for (_, len, path) in versions {
+        if total_bytes <= max_bytes {
+            break;
+        }
+
+        remove_version(&path).await?;
+        total_bytes -= len;
+    }
+
+    Ok(())
+}
+
+async fn remove_version(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => {
+            error!(%err, path = ?log_path(path), "remove version failed");
+
+            Err(err)
+        }
+
+        Ok(_) => {
+            info!(path = ?log_path(path), "remove version done");
+
+            Ok(())
+        }
+    }
+}
+
+/// parse the version time of the archived file name, when the name is given, only the versions
+/// of the name are parsed
+fn version_time(version_name: &OsStr, name: Option<&OsStr>) -> Option<SystemTime> {
+    let version_name = version_name.as_bytes();
+    let split = version_name.iter().rposition(|&b| b == b'~')?;
+    if name.is_some_and(|name| name.as_bytes() != &version_name[..split]) {
+        return None;
+    }
+
+    let time = std::str::from_utf8(&version_name[split + 1..]).ok()?;
+    let time = NaiveDateTime::parse_from_str(time, VERSION_TIME_FORMAT).ok()?;
+
+    Some(DateTime::<Utc>::from_naive_utc_and_offset(time, Utc).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    async fn versions(dir: &TempDir) -> Vec<OsString> {
+        let mut versions = vec![];
+        let mut entries = fs::read_dir(dir.path().join(VERSIONS_DIR).join("sub"))
+            .await
+            .unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            versions.push(entry.file_name());
+        }
+        versions.sort();
+
+        versions
+    }
+
+    #[tokio::test]
+    async fn archive_with_retention() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        fs::create_dir(dir.path().join("sub")).await.unwrap();
+        let filename = OsStr::new("sub/test.txt");
+        let policy = VersioningPolicy {
+            keep_versions: 2,
+            max_age: Duration::from_secs(3600),
+            max_bytes: 0,
+        };
+
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for secs in [0, 10, 20] {
+            fs::write(dir.path().join(filename), b"test").await.unwrap();
+            let archived = archive(
+                dir.path(),
+                filename,
+                start + Duration::from_secs(secs),
+                &policy,
+            )
+            .await
+            .unwrap();
+            assert!(archived);
+        }
+
+        assert!(!dir.path().join(filename).exists());
+        assert_eq!(
+            versions(&dir).await,
+            [
+                "test.txt~20231114-221330.000",
+                "test.txt~20231114-221340.000"
+            ]
+        );
+
+        // the old versions are removed by the max age
+        fs::write(dir.path().join(filename), b"test").await.unwrap();
+        archive(
+            dir.path(),
+            filename,
+            start + Duration::from_secs(7200),
+            &policy,
+        )
+        .await
+        .unwrap();
+        assert_eq!(versions(&dir).await, ["test.txt~20231115-001320.000"]);
+
+        // nothing to archive
+        assert!(!archive(dir.path(), filename, start, &policy).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn prune_by_size() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        fs::create_dir(dir.path().join("sub")).await.unwrap();
+        let policy = VersioningPolicy {
+            max_bytes: 8,
+            ..Default::default()
+        };
+
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for (secs, filename) in [(0, "sub/a.txt"), (10, "sub/b.txt"), (20, "sub/c.txt")] {
+            fs::write(dir.path().join(filename), b"test").await.unwrap();
+            archive(
+                dir.path(),
+                OsStr::new(filename),
+                start + Duration::from_secs(secs),
+                &policy,
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(
+            versions(&dir).await,
+            ["b.txt~20231114-221330.000", "c.txt~20231114-221340.000"]
+        );
+    }
+
+    #[test]
+    fn version_file() {
+        assert!(is_version_file(
+            ".syncit-versions/test.txt~20231114-221320.000"
+        ));
+        assert!(!is_version_file("test.txt"));
+    }
+}
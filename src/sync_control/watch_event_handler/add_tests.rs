@@ -1,6 +1,7 @@
 use std::env;
 use std::ffi::OsString;
 use std::io::Cursor;
+use std::time::{Duration, SystemTime};
 
 use mockall::predicate::*;
 use tempfile::TempDir;
@@ -8,8 +9,10 @@ use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 
 use super::*;
+use crate::clock::MockClock;
 use crate::ext::hash_file;
-use crate::index::{MockIndex, MockIndexGuard};
+use crate::index::{FileMetadata, MockIndex, MockIndexGuard, VersionVector};
+use crate::sync_control::options::SpecialFilePolicy;
 
 #[tokio::test]
 async fn add_event() {
@@ -43,6 +46,11 @@ async fn add_event() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details.is_empty()
                 }))
@@ -67,7 +75,12 @@ async fn add_event() {
 
     file.write_all(b"test").await.unwrap();
 
-    let watch_event_handler = WatchEventHandler::new(&user_id, &dir_id, dir.path(), &index, sender);
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+    let mut clock = MockClock::new();
+    clock.expect_now().return_const(now);
+
+    let watch_event_handler =
+        WatchEventHandler::new(&user_id, &dir_id, dir.path(), &index, sender).with_clock(&clock);
     watch_event_handler
         .handle_watch_events(vec![WatchEvent::Add {
             name: OsString::from("test.txt"),
@@ -91,9 +104,17 @@ async fn add_event() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert!(rumor.previous_details.is_empty());
+    assert_eq!(rumor.update_time, now);
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
 }
 
@@ -125,12 +146,18 @@ async fn add_event_with_deleted_index() {
                             hash_sum: [0; 32],
                             block_chain: None,
                             deleted: true,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![FileDetail {
                             gen: 1,
                             hash_sum,
                             block_chain: None,
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         }],
                         update_time: SystemTime::now(),
                         update_by: user_id.as_hyphenated().to_string(),
@@ -148,6 +175,11 @@ async fn add_event_with_deleted_index() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![
@@ -156,12 +188,18 @@ async fn add_event_with_deleted_index() {
                                     hash_sum,
                                     block_chain: None,
                                     deleted: false,
+                                    metadata: None,
+                                    version: Default::default(),
+                                    link_group: None,
                                 },
                                 FileDetail {
                                     gen: 2,
                                     hash_sum: [0; 32],
                                     block_chain: None,
                                     deleted: true,
+                                    metadata: None,
+                                    version: Default::default(),
+                                    link_group: None,
                                 },
                             ]
                 }))
@@ -210,6 +248,13 @@ async fn add_event_with_deleted_index() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -220,12 +265,18 @@ async fn add_event_with_deleted_index() {
                 hash_sum,
                 block_chain: None,
                 deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
             },
             FileDetail {
                 gen: 2,
                 hash_sum: [0; 32],
                 block_chain: None,
                 deleted: true,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
             },
         ]
     );
@@ -264,6 +315,9 @@ async fn add_event_with_exists_index() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             },
                             previous_details: vec![],
                             update_time: SystemTime::now(),
@@ -283,6 +337,11 @@ async fn add_event_with_exists_index() {
                                 hash_sum: new_hash_sum,
                                 block_chain: Some(new_block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![FileDetail {
@@ -290,6 +349,9 @@ async fn add_event_with_exists_index() {
                                 hash_sum,
                                 block_chain: None,
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }]
                 }))
                 .returning(|_| Ok(()));
@@ -337,6 +399,13 @@ async fn add_event_with_exists_index() {
             hash_sum: new_hash_sum,
             block_chain: Some(new_block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -346,6 +415,9 @@ async fn add_event_with_exists_index() {
             hash_sum,
             block_chain: None,
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         },]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
@@ -382,6 +454,9 @@ async fn add_event_with_same_index() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             },
                             previous_details: vec![],
                             update_time: SystemTime::now(),
@@ -401,6 +476,11 @@ async fn add_event_with_same_index() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![FileDetail {
@@ -408,6 +488,9 @@ async fn add_event_with_same_index() {
                                 hash_sum,
                                 block_chain: None,
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }]
                 }))
                 .returning(|_| Ok(()));
@@ -441,3 +524,215 @@ async fn add_event_with_same_index() {
 
     receiver.recv_async().await.unwrap_err();
 }
+
+#[tokio::test]
+async fn add_special_file_event() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    index.expect_begin().returning(move || {
+        let mut index_guard = MockIndexGuard::new();
+        index_guard
+            .expect_get_file()
+            .with(eq(OsStr::new("test.fifo")))
+            .returning(|_| Ok(None));
+
+        index_guard
+            .expect_create_file()
+            .with(function(move |arg: &IndexFile| {
+                arg.filename == OsStr::new("test.fifo")
+                    && arg.kind == FileKind::Special
+                    && arg.detail
+                        == FileDetail {
+                            gen: 1,
+                            hash_sum: [0; 32],
+                            block_chain: None,
+                            deleted: false,
+                            metadata: None,
+                            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+                            link_group: None,
+                        }
+            }))
+            .returning(|_| Ok(()));
+
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let (sender, receiver) = flume::bounded::<SendRumors>(1);
+    let sender = sender.into_sink();
+
+    nix::unistd::mkfifo(&dir.path().join("test.fifo"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+
+    let watch_event_handler = WatchEventHandler::new(&user_id, &dir_id, dir.path(), &index, sender);
+    watch_event_handler
+        .handle_watch_events(vec![WatchEvent::Add {
+            name: OsString::from("test.fifo"),
+        }])
+        .await
+        .unwrap();
+
+    let send_rumors = receiver.recv_async().await.unwrap();
+
+    assert_eq!(send_rumors.rumors.len(), 1);
+    assert_eq!(send_rumors.rumors[0].kind, FileKind::Special);
+    assert!(send_rumors.rumors[0].detail.block_chain.is_none());
+
+    let (sender, receiver) = flume::bounded::<SendRumors>(1);
+    let sender = sender.into_sink();
+
+    let watch_event_handler = WatchEventHandler::new(&user_id, &dir_id, dir.path(), &index, sender)
+        .with_options(SyncOptions {
+            special_file_policy: SpecialFilePolicy::Error,
+            ..Default::default()
+        });
+    let err = watch_event_handler
+        .handle_watch_events(vec![WatchEvent::Add {
+            name: OsString::from("test.fifo"),
+        }])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SyncError::Filesystem(_)));
+
+    // the error policy fails the event handling, nothing is recorded or sent
+    assert!(receiver.recv_async().await.is_err());
+}
+
+#[tokio::test]
+async fn special_file_error_keeps_following_events() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    fs::write(dir.path().join("test.txt"), b"test")
+        .await
+        .unwrap();
+    nix::unistd::mkfifo(&dir.path().join("test.fifo"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+
+    // the group of the fifo is rolled back, only the group of the following file is committed
+    let mut seq = mockall::Sequence::new();
+    index
+        .expect_begin()
+        .times(1)
+        .in_sequence(&mut seq)
+        .returning(|| Ok(MockIndexGuard::new()));
+    index
+        .expect_begin()
+        .times(1)
+        .in_sequence(&mut seq)
+        .returning(|| {
+            let mut index_guard = MockIndexGuard::new();
+            index_guard
+                .expect_get_file()
+                .with(eq(OsStr::new("test.txt")))
+                .returning(|_| Ok(None));
+            index_guard
+                .expect_create_file()
+                .with(function(|arg: &IndexFile| {
+                    arg.filename == OsStr::new("test.txt")
+                }))
+                .returning(|_| Ok(()));
+            index_guard.expect_commit().returning(|| Ok(()));
+
+            Ok(index_guard)
+        });
+
+    let (sender, receiver) = flume::bounded::<SendRumors>(1);
+    let sender = sender.into_sink();
+
+    let watch_event_handler = WatchEventHandler::new(&user_id, &dir_id, dir.path(), &index, sender)
+        .with_options(SyncOptions {
+            special_file_policy: SpecialFilePolicy::Error,
+            ..Default::default()
+        });
+    let err = watch_event_handler
+        .handle_watch_events(vec![
+            WatchEvent::Add {
+                name: OsString::from("test.fifo"),
+            },
+            WatchEvent::Add {
+                name: OsString::from("test.txt"),
+            },
+        ])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SyncError::Filesystem(_)));
+
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.rumors.len(), 1);
+    assert_eq!(send_rumors.rumors[0].filename, OsStr::new("test.txt"));
+}
+
+#[tokio::test]
+async fn add_event_replaced_by_dir() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+
+    index.expect_begin().returning(move || {
+        let mut index_guard = MockIndexGuard::new();
+        let block_chain = block_chain.clone();
+
+        index_guard
+            .expect_get_file()
+            .with(eq(OsStr::new("test")))
+            .returning(move |_| {
+                Ok(Some(IndexFile {
+                    filename: "test".into(),
+                    kind: FileKind::File,
+                    detail: FileDetail {
+                        gen: 1,
+                        hash_sum,
+                        block_chain: Some(block_chain.clone()),
+                        deleted: false,
+                        metadata: None,
+                        version: Default::default(),
+                        link_group: None,
+                    },
+                    previous_details: vec![],
+                    update_time: SystemTime::now(),
+                    update_by: user_id.as_hyphenated().to_string(),
+                }))
+            });
+        index_guard
+            .expect_update_file()
+            .with(function(|arg: &IndexFile| {
+                arg.filename == OsStr::new("test") && arg.detail.gen == 2 && arg.detail.deleted
+            }))
+            .returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    // the file is replaced by a dir which has its own files
+    tokio::fs::create_dir(dir.path().join("test"))
+        .await
+        .unwrap();
+    tokio::fs::write(dir.path().join("test/inner.txt"), b"inner")
+        .await
+        .unwrap();
+
+    let (sender, receiver) = flume::bounded::<SendRumors>(1);
+    let sender = sender.into_sink();
+
+    let watch_event_handler = WatchEventHandler::new(&user_id, &dir_id, dir.path(), &index, sender);
+    watch_event_handler
+        .handle_watch_events(vec![WatchEvent::Add {
+            name: OsString::from("test"),
+        }])
+        .await
+        .unwrap();
+
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(send_rumors.rumors.len(), 1);
+    assert!(send_rumors.rumors[0].detail.deleted);
+    assert_eq!(send_rumors.rumors[0].previous_details[0].hash_sum, hash_sum);
+}
@@ -1,13 +1,14 @@
 use std::env;
 use std::ffi::OsString;
 use std::io::Cursor;
+use std::time::{Duration, Instant, SystemTime};
 
 use mockall::predicate::*;
 use tempfile::TempDir;
 
 use super::*;
 use crate::ext::hash_file;
-use crate::index::{MockIndex, MockIndexGuard};
+use crate::index::{MockIndex, MockIndexGuard, VersionVector};
 
 #[tokio::test]
 async fn delete_event() {
@@ -69,6 +70,9 @@ async fn delete_event_with_exist_file() {
                             hash_sum,
                             block_chain: Some(block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![],
                         update_time: SystemTime::now(),
@@ -87,6 +91,11 @@ async fn delete_event_with_exist_file() {
                                 hash_sum: [0; 32],
                                 block_chain: None,
                                 deleted: true,
+                                metadata: None,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![FileDetail {
@@ -94,6 +103,9 @@ async fn delete_event_with_exist_file() {
                                 hash_sum,
                                 block_chain: None,
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }]
                         && arg.update_by == user_id.as_hyphenated().to_string()
                 }))
@@ -132,6 +144,9 @@ async fn delete_event_with_exist_file() {
             hash_sum: [0; 32],
             block_chain: None,
             deleted: true,
+            metadata: None,
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -141,6 +156,9 @@ async fn delete_event_with_exist_file() {
             hash_sum,
             block_chain: None,
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         }]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
@@ -173,12 +191,18 @@ async fn delete_event_with_deleted_file() {
                             hash_sum,
                             block_chain: None,
                             deleted: true,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![FileDetail {
                             gen: 1,
                             hash_sum,
                             block_chain: Some(block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         }],
                         update_time: SystemTime::now(),
                         update_by: user_id.as_hyphenated().to_string(),
@@ -204,3 +228,47 @@ async fn delete_event_with_deleted_file() {
 
     receiver.recv_async().await.unwrap_err();
 }
+
+#[tokio::test]
+async fn delete_events_wait_debounce_once() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    index.expect_begin().times(10).returning(move || {
+        let mut index_guard = MockIndexGuard::new();
+        index_guard.expect_get_file().returning(|_| Ok(None));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let (sender, receiver) = flume::bounded::<SendRumors>(1);
+    let sender = sender.into_sink();
+
+    let watch_event_handler = WatchEventHandler::new(&user_id, &dir_id, dir.path(), &index, sender)
+        .with_options(SyncOptions {
+            debounce: Duration::from_millis(200),
+            ..Default::default()
+        });
+
+    let start = Instant::now();
+    watch_event_handler
+        .handle_watch_events(
+            (0..10)
+                .map(|i| WatchEvent::Delete {
+                    name: OsString::from(format!("{i}.txt")),
+                })
+                .collect(),
+        )
+        .await
+        .unwrap();
+
+    // the missing files of the batch share one debounce window
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(200));
+    assert!(elapsed < Duration::from_millis(1000), "{elapsed:?}");
+
+    receiver.recv_async().await.unwrap_err();
+}
@@ -1,19 +1,34 @@
 use std::error::Error;
 use std::ffi::OsStr;
+use std::io;
 use std::io::ErrorKind;
 use std::mem;
 use std::path::Path;
-use std::time::SystemTime;
 
 use anyhow::Result;
-use futures_util::{Sink, SinkExt};
-use tokio::fs::File;
+use futures_util::{future, Sink, SinkExt, TryStreamExt};
+use tap::TapFallible;
+use tokio::fs::{self, File};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 use uuid::Uuid;
 
-use crate::ext::hash_file;
+use crate::clock::{Clock, SystemClock};
+use crate::ext::{is_dir, log_path, open_read, unsyncable_kind};
 use crate::file_event_produce::WatchEvent;
-use crate::index::{FileDetail, FileKind, Index, IndexFile, IndexGuard};
+use crate::index::conflicts::is_conflict_file;
+use crate::index::{
+    FileDetail, FileKind, FileMetadata, Index, IndexFile, IndexGuard, VersionVector,
+};
+use crate::journal::{Journal, NoopJournal, OperationSource};
+use crate::sync_control::error::SyncError;
+use crate::sync_control::event::DirRename;
+use crate::sync_control::file_locks::FileLocks;
+use crate::sync_control::index_change::{unsyncable_file_change, IndexChange};
+use crate::sync_control::options::SyncOptions;
+use crate::sync_control::progress::{hash_file_with_report, ProgressReporter};
+use crate::sync_control::versioning::is_version_file;
 use crate::sync_control::SendRumors;
 
 pub struct WatchEventHandler<'a, I, Si> {
@@ -22,6 +37,14 @@ pub struct WatchEventHandler<'a, I, Si> {
     sync_dir: &'a Path,
     index: &'a I,
     rumor_sender: Si,
+    options: SyncOptions,
+    clock: &'a dyn Clock,
+    journal: &'a dyn Journal,
+    file_locks: FileLocks,
+    progress: ProgressReporter,
+    shutdown: CancellationToken,
+    /// the dirs renamed by the watch events, they are sent with the rumors of the moved files
+    dir_renames: Vec<DirRename>,
 }
 
 impl<'a, I, Si> WatchEventHandler<'a, I, Si> {
@@ -38,8 +61,52 @@ impl<'a, I, Si> WatchEventHandler<'a, I, Si> {
             sync_dir,
             index,
             rumor_sender,
+            options: Default::default(),
+            clock: &SystemClock,
+            journal: &NoopJournal,
+            file_locks: Default::default(),
+            progress: Default::default(),
+            shutdown: Default::default(),
+            dir_renames: vec![],
         }
     }
+
+    pub fn with_options(mut self, options: SyncOptions) -> Self {
+        self.options = options;
+
+        self
+    }
+
+    pub fn with_clock(mut self, clock: &'a dyn Clock) -> Self {
+        self.clock = clock;
+
+        self
+    }
+
+    pub fn with_journal(mut self, journal: &'a dyn Journal) -> Self {
+        self.journal = journal;
+
+        self
+    }
+
+    pub fn with_file_locks(mut self, file_locks: FileLocks) -> Self {
+        self.file_locks = file_locks;
+
+        self
+    }
+
+    pub fn with_progress(mut self, progress: ProgressReporter) -> Self {
+        self.progress = progress;
+
+        self
+    }
+
+    /// stop hashing the files when the token is canceled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+
+        self
+    }
 }
 
 impl<'a, I, Si> WatchEventHandler<'a, I, Si>
@@ -49,98 +116,109 @@ where
     Si: Sink<SendRumors> + Unpin,
     Si::Error: Error + Send + Sync + 'static,
 {
-    pub async fn handle_watch_events(mut self, watch_events: Vec<WatchEvent>) -> Result<()> {
+    pub async fn handle_watch_events(
+        mut self,
+        watch_events: Vec<WatchEvent>,
+    ) -> Result<(), SyncError> {
+        let watch_events = exclude_unsynced_files(watch_events);
         let mut rumors = Vec::with_capacity(watch_events.len());
+        let mut first_error = None;
 
-        for event in watch_events {
-            let mut index_guard = self.index.begin().await?;
+        // wait before any index transaction is begun, so the transaction isn't held while waiting
+        self.wait_missing_files(&watch_events).await;
 
-            match event {
-                WatchEvent::Add { name } => {
-                    match self.handle_add_watch_event(&name, &mut index_guard).await {
-                        Err(err) => {
-                            error!(%err, ?name, "handle add watch event failed");
+        for group in group_related_events(watch_events) {
+            let names = group.iter().flat_map(event_names).collect::<Vec<_>>();
+            let _file_lock_guards = self.file_locks.write_all(&names).await;
 
-                            break;
-                        }
+            // the related events are committed in one transaction, so a rename is never half
+            // committed, when any event fails, the whole group is rolled back
+            let mut index_guard = self.index.begin().await.map_err(SyncError::index)?;
+            let dir_renames_len = self.dir_renames.len();
+            let mut group_rumors = vec![];
+            let mut group_error = None;
 
-                        Ok(rumor) => {
-                            if let Some(rumor) = rumor {
-                                rumors.push(rumor);
-                            }
-                        }
-                    }
-                }
-                WatchEvent::Modify { name } => {
-                    match self
-                        .handle_modify_watch_event(&name, &mut index_guard)
-                        .await
-                    {
-                        Err(err) => {
-                            error!(%err, ?name, "handle modify watch event failed");
-
-                            break;
-                        }
-
-                        Ok(rumor) => {
-                            if let Some(rumor) = rumor {
-                                rumors.push(rumor);
-                            }
-                        }
-                    }
-                }
-                WatchEvent::Rename { old_name, new_name } => {
-                    match self
-                        .handle_rename_watch_event(&old_name, &new_name, &mut index_guard)
-                        .await
-                    {
-                        Err(err) => {
-                            error!(%err, ?old_name, ?new_name, "handle rename watch event failed");
-
-                            break;
-                        }
-
-                        Ok(rename_rumors) => {
-                            if let Some(rename_rumors) = rename_rumors {
-                                rumors.extend(rename_rumors);
-                            }
-                        }
+            for event in &group {
+                match self.handle_watch_event(event, &mut index_guard).await {
+                    Err(err) => {
+                        error!(%err, ?event, "handle watch event failed");
+
+                        group_error = Some(err);
+
+                        break;
                     }
+
+                    Ok(event_rumors) => group_rumors.extend(event_rumors),
                 }
-                WatchEvent::Delete { name } => {
-                    match self
-                        .handle_delete_watch_event(&name, &mut index_guard)
-                        .await
-                    {
-                        Err(err) => {
-                            error!(%err, ?name, "handle delete watch event failed");
-
-                            break;
-                        }
-
-                        Ok(rumor) => {
-                            if let Some(rumor) = rumor {
-                                rumors.push(rumor);
-                            }
-                        }
-                    }
+
+                info!("handle watch event done");
+            }
+
+            // the groups are independent, the following groups are still handled, so their
+            // events aren't lost, and the first error is returned after sending the rumors
+            if let Some(err) = group_error {
+                self.dir_renames.truncate(dir_renames_len);
+                first_error.get_or_insert(err);
+
+                if self.shutdown.is_cancelled() {
+                    break;
                 }
+
+                continue;
             }
 
-            info!("handle watch event done");
+            index_guard.commit().await.map_err(SyncError::index)?;
 
-            index_guard.commit().await?;
+            info!(events = group.len(), "commit index guard done");
 
-            info!("commit index guard done");
+            rumors.extend(group_rumors);
         }
 
         info!("handle all watch events done");
 
+        for rumor in &rumors {
+            self.journal
+                .record(*self.dir_id, OperationSource::Watch, rumor);
+        }
+
         self.send_rumors_to_all(rumors).await?;
 
         info!("send rumors to all done");
 
-        Ok(())
+        match first_error {
+            None => Ok(()),
+            Some(err) => Err(err.into()),
+        }
+    }
+
+    async fn handle_watch_event(
+        &mut self,
+        event: &WatchEvent,
+        index_guard: &mut I::Guard,
+    ) -> Result<Vec<IndexFile>> {
+        let rumors = match event {
+            WatchEvent::Add { name } => self
+                .handle_add_watch_event(name, index_guard)
+                .await?
+                .into_iter()
+                .collect(),
+            WatchEvent::Modify { name } => self
+                .handle_modify_watch_event(name, index_guard)
+                .await?
+                .into_iter()
+                .collect(),
+            WatchEvent::Rename { old_name, new_name } => self
+                .handle_rename_watch_event(old_name, new_name, index_guard)
+                .await?
+                .unwrap_or_default(),
+            WatchEvent::Delete { name } => self
+                .handle_delete_watch_event(name, index_guard)
+                .await?
+                .into_iter()
+                .collect(),
+        };
+
+        Ok(rumors)
     }
 
     async fn handle_add_watch_event(
@@ -149,15 +227,25 @@ where
         index_guard: &mut I::Guard,
     ) -> Result<Option<IndexFile>> {
         let path = self.sync_dir.join(name);
-        let file = match File::open(&path).await {
+        if let Some(kind) = unsyncable_kind(&path, self.options.symlink_policy).await? {
+            return self.handle_unsyncable_file(name, kind, index_guard).await;
+        }
+
+        if is_dir(&path).await? {
+            info!(path = ?log_path(&path), "file is replaced by a dir, handle as modify");
+
+            return self.handle_modify_watch_event(name, index_guard).await;
+        }
+
+        let file = match open_read(&path, self.options.no_atime).await {
             Err(err) if err.kind() == ErrorKind::NotFound => {
-                info!(?path, "ignore not exists file");
+                info!(path = ?log_path(&path), "ignore not exists file");
 
                 return Ok(None);
             }
 
             Err(err) => {
-                error!(%err, ?path, "open file failed");
+                error!(%err, path = ?log_path(&path), "open file failed");
 
                 return Err(err.into());
             }
@@ -165,13 +253,28 @@ where
             Ok(file) => file,
         };
 
-        info!(?path, "open file done");
-
-        let (hash_sum, block_chain) = hash_file(file).await?;
-
-        info!(?path, "hash file done");
-
-        let mut index_file = match index_guard.get_file(name).await? {
+        info!(path = ?log_path(&path), "open file done");
+
+        let file_metadata = file
+            .metadata()
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(&path), "get file metadata failed"))?;
+        let metadata = FileMetadata::from(&file_metadata);
+        let (hash_sum, block_chain) = hash_file_with_report(
+            file,
+            name,
+            file_metadata.len(),
+            self.options
+                .block_size_policy
+                .block_size(file_metadata.len()),
+            &self.progress,
+            &self.shutdown,
+        )
+        .await?;
+
+        info!(path = ?log_path(&path), "hash file done");
+
+        let mut index_file = match index_guard.get_file(name).await.map_err(SyncError::index)? {
             None => {
                 let index_file = IndexFile {
                     filename: name.to_os_string(),
@@ -181,15 +284,21 @@ where
                         hash_sum,
                         block_chain: Some(block_chain),
                         deleted: false,
+                        metadata: Some(metadata),
+                        version: VersionVector::initial(&self.user_id.as_hyphenated().to_string()),
+                        link_group: None,
                     },
                     previous_details: vec![],
-                    update_time: SystemTime::now(),
+                    update_time: self.clock.now(),
                     update_by: self.user_id.as_hyphenated().to_string(),
                 };
 
-                index_guard.create_file(&index_file).await?;
+                index_guard
+                    .create_file(&index_file)
+                    .await
+                    .map_err(SyncError::index)?;
 
-                info!(?path, "create file index done");
+                info!(path = ?log_path(&path), "create file index done");
 
                 return Ok(Some(index_file));
             }
@@ -198,12 +307,16 @@ where
         };
 
         if !index_file.detail.deleted && index_file.detail.hash_sum == hash_sum {
-            info!(?path, "file hash no changed, ignore add watch event");
+            info!(path = ?log_path(&path), "file hash no changed, ignore add watch event");
 
             return Ok(None);
         }
 
         let gen = index_file.detail.gen + 1;
+        let version = index_file
+            .detail
+            .version
+            .incremented(&self.user_id.as_hyphenated().to_string());
         let mut old_info = mem::replace(
             &mut index_file.detail,
             FileDetail {
@@ -211,15 +324,22 @@ where
                 hash_sum,
                 block_chain: Some(block_chain),
                 deleted: false,
+                metadata: Some(metadata),
+                version,
+                link_group: None,
             },
         );
         old_info.block_chain.take();
 
+        index_file.kind = FileKind::File;
         index_file.previous_details.push(old_info);
 
-        index_guard.update_file(&index_file).await?;
+        index_guard
+            .update_file(&index_file)
+            .await
+            .map_err(SyncError::index)?;
 
-        info!(?path, "update file index done");
+        info!(path = ?log_path(&path), "update file index done");
 
         Ok(Some(index_file))
     }
@@ -230,12 +350,16 @@ where
         index_guard: &mut I::Guard,
     ) -> Result<Option<IndexFile>> {
         let path = self.sync_dir.join(name);
-        let file = match File::open(&path).await {
-            Err(err) if err.kind() == ErrorKind::NotFound => {
-                return match index_guard.get_file(name).await? {
+        if let Some(kind) = unsyncable_kind(&path, self.options.symlink_policy).await? {
+            return self.handle_unsyncable_file(name, kind, index_guard).await;
+        }
+
+        let file = match self.open_file(&path).await {
+            Ok(None) => {
+                return match index_guard.get_file(name).await.map_err(SyncError::index)? {
                     None => {
                         info!(
-                            ?path,
+                            path = ?log_path(&path),
                             "file not exists and index doesn't contain it, ignore"
                         );
 
@@ -243,13 +367,17 @@ where
                     }
 
                     Some(index_file) if index_file.detail.deleted => {
-                        info!(?path, "file not exists and index file is deleted, ignore");
+                        info!(path = ?log_path(&path), "file not exists and index file is deleted, ignore");
 
                         Ok(None)
                     }
 
                     Some(mut index_file) => {
                         let gen = index_file.detail.gen + 1;
+                        let version = index_file
+                            .detail
+                            .version
+                            .incremented(&self.user_id.as_hyphenated().to_string());
                         let mut old_info = mem::replace(
                             &mut index_file.detail,
                             FileDetail {
@@ -257,14 +385,20 @@ where
                                 hash_sum: [0; 32],
                                 block_chain: None,
                                 deleted: true,
+                                metadata: None,
+                                version,
+                                link_group: None,
                             },
                         );
                         old_info.block_chain.take();
                         index_file.previous_details.push(old_info);
 
-                        index_guard.update_file(&index_file).await?;
+                        index_guard
+                            .update_file(&index_file)
+                            .await
+                            .map_err(SyncError::index)?;
 
-                        info!(?path, "update file index done");
+                        info!(path = ?log_path(&path), "update file index done");
 
                         Ok(Some(index_file))
                     }
@@ -272,21 +406,36 @@ where
             }
 
             Err(err) => {
-                error!(%err, ?path, "open file failed");
+                error!(%err, path = ?log_path(&path), "open file failed");
 
                 return Err(err.into());
             }
 
-            Ok(file) => file,
+            Ok(Some(file)) => file,
         };
 
-        info!(?path, "open file done");
-
-        let (hash_sum, block_chain) = hash_file(file).await?;
-
-        info!(?path, "hash file done");
-
-        let mut index_file = match index_guard.get_file(name).await? {
+        info!(path = ?log_path(&path), "open file done");
+
+        let file_metadata = file
+            .metadata()
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(&path), "get file metadata failed"))?;
+        let metadata = FileMetadata::from(&file_metadata);
+        let (hash_sum, block_chain) = hash_file_with_report(
+            file,
+            name,
+            file_metadata.len(),
+            self.options
+                .block_size_policy
+                .block_size(file_metadata.len()),
+            &self.progress,
+            &self.shutdown,
+        )
+        .await?;
+
+        info!(path = ?log_path(&path), "hash file done");
+
+        let mut index_file = match index_guard.get_file(name).await.map_err(SyncError::index)? {
             None => {
                 let index_file = IndexFile {
                     filename: name.to_os_string(),
@@ -296,15 +445,21 @@ where
                         hash_sum,
                         block_chain: Some(block_chain),
                         deleted: false,
+                        metadata: Some(metadata),
+                        version: VersionVector::initial(&self.user_id.as_hyphenated().to_string()),
+                        link_group: None,
                     },
                     previous_details: vec![],
-                    update_time: SystemTime::now(),
+                    update_time: self.clock.now(),
                     update_by: self.user_id.as_hyphenated().to_string(),
                 };
 
-                index_guard.create_file(&index_file).await?;
+                index_guard
+                    .create_file(&index_file)
+                    .await
+                    .map_err(SyncError::index)?;
 
-                info!(?path, "create file index done");
+                info!(path = ?log_path(&path), "create file index done");
 
                 return Ok(Some(index_file));
             }
@@ -313,12 +468,22 @@ where
         };
 
         if !index_file.detail.deleted && index_file.detail.hash_sum == hash_sum {
-            info!(?path, "file hash no changed, ignore modify watch event");
+            info!(path = ?log_path(&path), "file hash no changed, ignore modify watch event");
 
             return Ok(None);
         }
 
         let gen = index_file.detail.gen + 1;
+        let version = index_file
+            .detail
+            .version
+            .incremented(&self.user_id.as_hyphenated().to_string());
+        // the content written through one name is shared by the other names of its link group
+        let link_group = if index_file.detail.deleted {
+            None
+        } else {
+            index_file.detail.link_group.clone()
+        };
         let mut old_info = mem::replace(
             &mut index_file.detail,
             FileDetail {
@@ -326,15 +491,22 @@ where
                 hash_sum,
                 block_chain: Some(block_chain),
                 deleted: false,
+                metadata: Some(metadata),
+                version,
+                link_group,
             },
         );
         old_info.block_chain.take();
 
+        index_file.kind = FileKind::File;
         index_file.previous_details.push(old_info);
 
-        index_guard.update_file(&index_file).await?;
+        index_guard
+            .update_file(&index_file)
+            .await
+            .map_err(SyncError::index)?;
 
-        info!(?path, "update file index done");
+        info!(path = ?log_path(&path), "update file index done");
 
         Ok(Some(index_file))
     }
@@ -346,17 +518,41 @@ where
         index_guard: &mut I::Guard,
     ) -> Result<Option<Vec<IndexFile>>> {
         let new_path = self.sync_dir.join(new_name);
-        let new_file = match File::open(&new_path).await {
-            Err(err) if err.kind() == ErrorKind::NotFound => {
-                let mut old_index_file = match index_guard.get_file(old_name).await? {
+        if is_dir(&new_path).await? {
+            return self
+                .handle_dir_rename_watch_event(old_name, new_name, index_guard)
+                .await;
+        }
+
+        if let Some(kind) = unsyncable_kind(&new_path, self.options.symlink_policy).await? {
+            let mut rumors = Vec::with_capacity(2);
+            rumors.extend(
+                self.handle_delete_watch_event(old_name, index_guard)
+                    .await?,
+            );
+            rumors.extend(
+                self.handle_unsyncable_file(new_name, kind, index_guard)
+                    .await?,
+            );
+
+            return Ok(Some(rumors));
+        }
+
+        let new_file = match self.open_file(&new_path).await {
+            Ok(None) => {
+                let mut old_index_file = match index_guard
+                    .get_file(old_name)
+                    .await
+                    .map_err(SyncError::index)?
+                {
                     None => {
-                        info!(?old_name, "old file index not exists, ignore");
+                        info!(old_name = ?log_path(&old_name), "old file index not exists, ignore");
 
                         return Ok(None);
                     }
 
                     Some(index_file) if index_file.detail.deleted => {
-                        info!(?old_name, "old file index deleted has been set, ignore");
+                        info!(old_name = ?log_path(&old_name), "old file index deleted has been set, ignore");
 
                         return Ok(None);
                     }
@@ -365,6 +561,10 @@ where
                 };
 
                 let gen = old_index_file.detail.gen + 1;
+                let version = old_index_file
+                    .detail
+                    .version
+                    .incremented(&self.user_id.as_hyphenated().to_string());
                 let mut old_old_file_info = mem::replace(
                     &mut old_index_file.detail,
                     FileDetail {
@@ -372,25 +572,35 @@ where
                         hash_sum: [0; 32],
                         block_chain: None,
                         deleted: true,
+                        metadata: None,
+                        version,
+                        link_group: None,
                     },
                 );
                 old_old_file_info.block_chain.take();
                 old_index_file.previous_details.push(old_old_file_info);
 
-                index_guard.update_file(&old_index_file).await?;
+                index_guard
+                    .update_file(&old_index_file)
+                    .await
+                    .map_err(SyncError::index)?;
 
-                info!(?old_name, "update old file index done");
+                info!(old_name = ?log_path(&old_name), "update old file index done");
 
-                let mut new_index_file = match index_guard.get_file(new_name).await? {
+                let mut new_index_file = match index_guard
+                    .get_file(new_name)
+                    .await
+                    .map_err(SyncError::index)?
+                {
                     None => {
-                        info!(?new_path, "new file not exists and file index too");
+                        info!(new_path = ?log_path(&new_path), "new file not exists and file index too");
 
                         return Ok(Some(vec![old_index_file]));
                     }
 
                     Some(new_index_file) if new_index_file.detail.deleted => {
                         info!(
-                            ?new_path,
+                            new_path = ?log_path(&new_path),
                             "new file not exists and file index deleted has been set"
                         );
 
@@ -401,6 +611,10 @@ where
                 };
 
                 let gen = new_index_file.detail.gen + 1;
+                let version = new_index_file
+                    .detail
+                    .version
+                    .incremented(&self.user_id.as_hyphenated().to_string());
                 let mut old_new_file_info = mem::replace(
                     &mut new_index_file.detail,
                     FileDetail {
@@ -408,43 +622,71 @@ where
                         hash_sum: [0; 32],
                         block_chain: None,
                         deleted: true,
+                        metadata: None,
+                        version,
+                        link_group: None,
                     },
                 );
                 old_new_file_info.block_chain.take();
                 new_index_file.previous_details.push(old_new_file_info);
 
-                index_guard.update_file(&new_index_file).await?;
+                index_guard
+                    .update_file(&new_index_file)
+                    .await
+                    .map_err(SyncError::index)?;
 
-                info!(?new_name, "update new file index done");
+                info!(new_name = ?log_path(&new_name), "update new file index done");
 
                 return Ok(Some(vec![old_index_file, new_index_file]));
             }
 
             Err(err) => {
-                error!(%err, ?new_path, "open file failed");
+                error!(%err, new_path = ?log_path(&new_path), "open file failed");
 
                 return Err(err.into());
             }
 
-            Ok(file) => file,
+            Ok(Some(file)) => file,
         };
 
-        let (hash_sum, block_chain) = hash_file(new_file).await?;
+        let file_metadata = new_file.metadata().await.tap_err(
+            |err| error!(%err, new_path = ?log_path(&new_path), "get file metadata failed"),
+        )?;
+        let metadata = FileMetadata::from(&file_metadata);
+        let (hash_sum, block_chain) = hash_file_with_report(
+            new_file,
+            new_name,
+            file_metadata.len(),
+            self.options
+                .block_size_policy
+                .block_size(file_metadata.len()),
+            &self.progress,
+            &self.shutdown,
+        )
+        .await?;
 
         let mut rumors = Vec::with_capacity(2);
 
         // update old file index at first
-        match index_guard.get_file(old_name).await? {
+        match index_guard
+            .get_file(old_name)
+            .await
+            .map_err(SyncError::index)?
+        {
             None => {
-                info!(?old_name, "old file index not exists, ignore");
+                info!(old_name = ?log_path(&old_name), "old file index not exists, ignore");
             }
 
             Some(index_file) if index_file.detail.deleted => {
-                info!(?old_name, "old file index deleted has been set, ignore");
+                info!(old_name = ?log_path(&old_name), "old file index deleted has been set, ignore");
             }
 
             Some(mut old_index_file) => {
                 let gen = old_index_file.detail.gen + 1;
+                let version = old_index_file
+                    .detail
+                    .version
+                    .incremented(&self.user_id.as_hyphenated().to_string());
                 let mut old_old_file_info = mem::replace(
                     &mut old_index_file.detail,
                     FileDetail {
@@ -452,18 +694,28 @@ where
                         hash_sum: [0; 32],
                         block_chain: None,
                         deleted: true,
+                        metadata: None,
+                        version,
+                        link_group: None,
                     },
                 );
                 old_old_file_info.block_chain.take();
                 old_index_file.previous_details.push(old_old_file_info);
 
-                index_guard.update_file(&old_index_file).await?;
+                index_guard
+                    .update_file(&old_index_file)
+                    .await
+                    .map_err(SyncError::index)?;
 
                 rumors.push(old_index_file);
             }
         };
 
-        let index_file = match index_guard.get_file(new_name).await? {
+        let index_file = match index_guard
+            .get_file(new_name)
+            .await
+            .map_err(SyncError::index)?
+        {
             None => {
                 let index_file = IndexFile {
                     filename: new_name.to_os_string(),
@@ -473,21 +725,31 @@ where
                         hash_sum,
                         block_chain: Some(block_chain),
                         deleted: false,
+                        metadata: Some(metadata),
+                        version: VersionVector::initial(&self.user_id.as_hyphenated().to_string()),
+                        link_group: None,
                     },
                     previous_details: vec![],
-                    update_time: SystemTime::now(),
+                    update_time: self.clock.now(),
                     update_by: self.user_id.as_hyphenated().to_string(),
                 };
 
-                index_guard.create_file(&index_file).await?;
+                index_guard
+                    .create_file(&index_file)
+                    .await
+                    .map_err(SyncError::index)?;
 
-                info!(?new_name, "create new file index done");
+                info!(new_name = ?log_path(&new_name), "create new file index done");
 
                 index_file
             }
 
             Some(mut index_file) => {
                 let gen = index_file.detail.gen + 1;
+                let version = index_file
+                    .detail
+                    .version
+                    .incremented(&self.user_id.as_hyphenated().to_string());
                 let mut old_info = mem::replace(
                     &mut index_file.detail,
                     FileDetail {
@@ -495,14 +757,21 @@ where
                         hash_sum,
                         block_chain: Some(block_chain),
                         deleted: false,
+                        metadata: Some(metadata),
+                        version,
+                        link_group: None,
                     },
                 );
                 old_info.block_chain.take();
+                index_file.kind = FileKind::File;
                 index_file.previous_details.push(old_info);
 
-                index_guard.update_file(&index_file).await?;
+                index_guard
+                    .update_file(&index_file)
+                    .await
+                    .map_err(SyncError::index)?;
 
-                info!(?new_name, "update new file index done");
+                info!(new_name = ?log_path(&new_name), "update new file index done");
 
                 index_file
             }
@@ -513,20 +782,164 @@ where
         Ok(Some(rumors))
     }
 
+    /// the files of the renamed dir keep their content, so they are moved in the index without
+    /// hashing, the peers rename their local dir once by the dir rename
+    async fn handle_dir_rename_watch_event(
+        &mut self,
+        old_name: &OsStr,
+        new_name: &OsStr,
+        index_guard: &mut I::Guard,
+    ) -> Result<Option<Vec<IndexFile>>> {
+        let old_dir = Path::new(old_name);
+        let moved_files = index_guard
+            .list_all_files()
+            .await
+            .map_err(SyncError::index)?
+            .try_filter(|index_file| {
+                future::ready(
+                    !index_file.detail.deleted
+                        && Path::new(&index_file.filename).starts_with(old_dir),
+                )
+            })
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(SyncError::index)?;
+        if moved_files.is_empty() {
+            info!(old_name = ?log_path(&old_name), "renamed dir has no index files, ignore");
+
+            return Ok(None);
+        }
+
+        let now = self.clock.now();
+        let mut rumors = Vec::with_capacity(moved_files.len() * 2);
+        for mut old_index_file in moved_files {
+            let relative = Path::new(&old_index_file.filename)
+                .strip_prefix(old_dir)
+                .expect("moved file must be in the old dir");
+            let new_filename = Path::new(new_name).join(relative).into_os_string();
+
+            let gen = old_index_file.detail.gen + 1;
+            let version = old_index_file
+                .detail
+                .version
+                .incremented(&self.user_id.as_hyphenated().to_string());
+            let mut moved_detail = mem::replace(
+                &mut old_index_file.detail,
+                FileDetail {
+                    gen,
+                    hash_sum: [0; 32],
+                    block_chain: None,
+                    deleted: true,
+                    metadata: None,
+                    version,
+                    link_group: None,
+                },
+            );
+            let mut old_old_file_info = moved_detail.clone();
+            old_old_file_info.block_chain.take();
+            old_index_file.previous_details.push(old_old_file_info);
+            old_index_file.update_time = now;
+            old_index_file.update_by = self.user_id.as_hyphenated().to_string();
+
+            index_guard
+                .update_file(&old_index_file)
+                .await
+                .map_err(SyncError::index)?;
+
+            let new_index_file = match index_guard
+                .get_file(&new_filename)
+                .await
+                .map_err(SyncError::index)?
+            {
+                None => {
+                    moved_detail.gen = 1;
+                    moved_detail.version =
+                        VersionVector::initial(&self.user_id.as_hyphenated().to_string());
+                    let index_file = IndexFile {
+                        filename: new_filename,
+                        kind: old_index_file.kind,
+                        detail: moved_detail,
+                        previous_details: vec![],
+                        update_time: now,
+                        update_by: self.user_id.as_hyphenated().to_string(),
+                    };
+
+                    index_guard
+                        .create_file(&index_file)
+                        .await
+                        .map_err(SyncError::index)?;
+
+                    index_file
+                }
+
+                Some(mut index_file) => {
+                    moved_detail.gen = index_file.detail.gen + 1;
+                    moved_detail.version = index_file
+                        .detail
+                        .version
+                        .incremented(&self.user_id.as_hyphenated().to_string());
+                    let mut old_info = mem::replace(&mut index_file.detail, moved_detail);
+                    old_info.block_chain.take();
+                    index_file.kind = old_index_file.kind;
+                    index_file.previous_details.push(old_info);
+                    index_file.update_time = now;
+                    index_file.update_by = self.user_id.as_hyphenated().to_string();
+
+                    index_guard
+                        .update_file(&index_file)
+                        .await
+                        .map_err(SyncError::index)?;
+
+                    index_file
+                }
+            };
+
+            rumors.push(old_index_file);
+            rumors.push(new_index_file);
+        }
+
+        info!(
+            old_name = ?log_path(&old_name),
+            new_name = ?log_path(&new_name),
+            files = rumors.len() / 2,
+            "move dir files index done"
+        );
+
+        self.dir_renames.push(DirRename {
+            old_dir: old_name.to_os_string(),
+            new_dir: new_name.to_os_string(),
+        });
+
+        Ok(Some(rumors))
+    }
+
     async fn handle_delete_watch_event(
         &mut self,
         name: &OsStr,
         index_guard: &mut I::Guard,
     ) -> Result<Option<IndexFile>> {
-        let mut index_file = match index_guard.get_file(name).await? {
+        // the file may be recreated, such as an editor saves the file atomically
+        let path = self.sync_dir.join(name);
+        if self
+            .open_file(&path)
+            .await
+            .tap_err(|err| error!(%err, path = ?log_path(&path), "open file failed"))?
+            .is_some()
+        {
+            info!(path = ?log_path(&path), "deleted file exists again, handle as modify");
+
+            return self.handle_modify_watch_event(name, index_guard).await;
+        }
+
+        let mut index_file = match index_guard.get_file(name).await.map_err(SyncError::index)? {
             None => {
-                info!(?name, "file has no index, ignore");
+                info!(name = ?log_path(&name), "file has no index, ignore");
 
                 return Ok(None);
             }
 
             Some(index_file) if index_file.detail.deleted => {
-                info!(?name, "file index deleted has been set, ignore");
+                info!(name = ?log_path(&name), "file index deleted has been set, ignore");
 
                 return Ok(None);
             }
@@ -535,6 +948,10 @@ where
         };
 
         let gen = index_file.detail.gen + 1;
+        let version = index_file
+            .detail
+            .version
+            .incremented(&self.user_id.as_hyphenated().to_string());
         let mut old_info = mem::replace(
             &mut index_file.detail,
             FileDetail {
@@ -542,18 +959,114 @@ where
                 hash_sum: [0; 32],
                 block_chain: None,
                 deleted: true,
+                metadata: None,
+                version,
+                link_group: None,
             },
         );
         old_info.block_chain.take();
         index_file.previous_details.push(old_info);
 
-        index_guard.update_file(&index_file).await?;
+        index_guard
+            .update_file(&index_file)
+            .await
+            .map_err(SyncError::index)?;
 
-        info!(?name, "update file index done");
+        info!(name = ?log_path(&name), "update file index done");
 
         Ok(Some(index_file))
     }
 
+    /// the special file can't be hashed, it is recorded in the index without content when the
+    /// special file policy is skip
+    async fn handle_unsyncable_file(
+        &mut self,
+        name: &OsStr,
+        kind: FileKind,
+        index_guard: &mut I::Guard,
+    ) -> Result<Option<IndexFile>> {
+        let index_file = match unsyncable_file_change(
+            name,
+            kind,
+            self.options.special_file_policy,
+            &self.user_id.as_hyphenated().to_string(),
+            self.clock.now(),
+            index_guard,
+        )
+        .await?
+        {
+            None => return Ok(None),
+
+            Some(IndexChange::Create(index_file)) => {
+                index_guard
+                    .create_file(&index_file)
+                    .await
+                    .map_err(SyncError::index)?;
+
+                index_file
+            }
+
+            Some(IndexChange::Update(index_file)) => {
+                index_guard
+                    .update_file(&index_file)
+                    .await
+                    .map_err(SyncError::index)?;
+
+                index_file
+            }
+        };
+
+        info!(name = ?log_path(&name), %kind, "write unsyncable file index done");
+
+        Ok(Some(index_file))
+    }
+
+    /// when some files of the events are not found, wait the debounce window once for the whole
+    /// batch, so a transient absence, such as an editor saves the file atomically, is seen as the
+    /// recreated file by the handlers instead of generating a delete generation
+    async fn wait_missing_files(&self, watch_events: &[WatchEvent]) {
+        if self.options.debounce.is_zero() {
+            return;
+        }
+
+        let mut missing_files = 0;
+        for name in watch_events.iter().filter_map(opened_name) {
+            if let Err(err) = fs::symlink_metadata(self.sync_dir.join(name)).await {
+                if err.kind() == ErrorKind::NotFound {
+                    missing_files += 1;
+                }
+            }
+        }
+        if missing_files == 0 {
+            return;
+        }
+
+        info!(
+            missing_files,
+            debounce = ?self.options.debounce,
+            "some files are not found, wait the debounce window"
+        );
+
+        time::sleep(self.options.debounce).await;
+    }
+
+    /// when return None, means the file is not found or is replaced by a dir
+    async fn open_file(&self, path: &Path) -> io::Result<Option<File>> {
+        // the file is replaced by a dir, the files in the dir have their own index files, so the
+        // file itself is gone
+        if is_dir(path).await? {
+            info!(path = ?log_path(path), "file is replaced by a dir");
+
+            return Ok(None);
+        }
+
+        match open_read(path, self.options.no_atime).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+            Ok(file) => Ok(Some(file)),
+        }
+    }
+
     async fn send_rumors_to_all<Iter: IntoIterator<Item = IndexFile>>(
         &mut self,
         rumors: Iter,
@@ -568,15 +1081,93 @@ where
         let send_rumors = SendRumors {
             dir_id: *self.dir_id,
             rumors,
+            dir_renames: mem::take(&mut self.dir_renames),
             except: None,
+            to: None,
+            fanout: self.options.fanout,
         };
 
-        self.rumor_sender.send(send_rumors).await?;
+        self.rumor_sender
+            .send(send_rumors)
+            .await
+            .map_err(SyncError::transfer)?;
 
         Ok(())
     }
 }
 
+/// the conflict copies and the archived versions are never synced, a rename from them adds the new
+/// file, and a rename to them deletes the old file
+fn exclude_unsynced_files(watch_events: Vec<WatchEvent>) -> Vec<WatchEvent> {
+    watch_events
+        .into_iter()
+        .filter_map(|event| match event {
+            WatchEvent::Add { name }
+            | WatchEvent::Modify { name }
+            | WatchEvent::Delete { name }
+                if is_unsynced_file(&name) =>
+            {
+                None
+            }
+
+            WatchEvent::Rename { old_name, new_name } => {
+                match (is_unsynced_file(&old_name), is_unsynced_file(&new_name)) {
+                    (true, true) => None,
+                    (true, false) => Some(WatchEvent::Add { name: new_name }),
+                    (false, true) => Some(WatchEvent::Delete { name: old_name }),
+                    (false, false) => Some(WatchEvent::Rename { old_name, new_name }),
+                }
+            }
+
+            event => Some(event),
+        })
+        .collect()
+}
+
+/// the name of the file which is opened when handling the event, a not found one may be recreated
+fn opened_name(event: &WatchEvent) -> Option<&OsStr> {
+    match event {
+        WatchEvent::Modify { name } | WatchEvent::Delete { name } => Some(name),
+        WatchEvent::Rename { new_name, .. } => Some(new_name),
+        WatchEvent::Add { .. } => None,
+    }
+}
+
+fn event_names(event: &WatchEvent) -> Vec<&OsStr> {
+    match event {
+        WatchEvent::Add { name } | WatchEvent::Modify { name } | WatchEvent::Delete { name } => {
+            vec![name]
+        }
+        WatchEvent::Rename { old_name, new_name } => vec![old_name, new_name],
+    }
+}
+
+/// group the adjacent events which touch the same file, or the file and its parent dir, such as
+/// a rename and the following modify of the new file, the order of the events is kept
+fn group_related_events(watch_events: Vec<WatchEvent>) -> Vec<Vec<WatchEvent>> {
+    let mut groups: Vec<Vec<WatchEvent>> = vec![];
+    for event in watch_events {
+        let related = groups.last().is_some_and(|group| {
+            group.iter().flat_map(event_names).any(|grouped| {
+                event_names(&event).into_iter().any(|name| {
+                    Path::new(name).starts_with(grouped) || Path::new(grouped).starts_with(name)
+                })
+            })
+        });
+
+        match groups.last_mut() {
+            Some(group) if related => group.push(event),
+            _ => groups.push(vec![event]),
+        }
+    }
+
+    groups
+}
+
+fn is_unsynced_file(filename: &OsStr) -> bool {
+    is_conflict_file(filename) || is_version_file(filename)
+}
+
 #[cfg(test)]
 mod add_tests;
 #[cfg(test)]
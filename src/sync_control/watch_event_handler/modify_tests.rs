@@ -1,15 +1,17 @@
 use std::env;
 use std::ffi::OsString;
 use std::io::Cursor;
+use std::time::{Duration, SystemTime};
 
 use mockall::predicate::*;
 use tempfile::TempDir;
+use tokio::fs;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 
 use super::*;
 use crate::ext::hash_file;
-use crate::index::{MockIndex, MockIndexGuard};
+use crate::index::{FileMetadata, MockIndex, MockIndexGuard, VersionVector};
 
 #[tokio::test]
 async fn modify_event() {
@@ -43,6 +45,11 @@ async fn modify_event() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details.is_empty()
                 }))
@@ -91,6 +98,13 @@ async fn modify_event() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert!(rumor.previous_details.is_empty());
@@ -125,12 +139,18 @@ async fn modify_event_with_deleted_index() {
                             hash_sum: [0; 32],
                             block_chain: None,
                             deleted: true,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![FileDetail {
                             gen: 1,
                             hash_sum,
                             block_chain: None,
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         }],
                         update_time: SystemTime::now(),
                         update_by: user_id.as_hyphenated().to_string(),
@@ -148,6 +168,11 @@ async fn modify_event_with_deleted_index() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![
@@ -156,12 +181,18 @@ async fn modify_event_with_deleted_index() {
                                     hash_sum,
                                     block_chain: None,
                                     deleted: false,
+                                    metadata: None,
+                                    version: Default::default(),
+                                    link_group: None,
                                 },
                                 FileDetail {
                                     gen: 2,
                                     hash_sum: [0; 32],
                                     block_chain: None,
                                     deleted: true,
+                                    metadata: None,
+                                    version: Default::default(),
+                                    link_group: None,
                                 },
                             ]
                 }))
@@ -210,6 +241,13 @@ async fn modify_event_with_deleted_index() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -220,12 +258,18 @@ async fn modify_event_with_deleted_index() {
                 hash_sum,
                 block_chain: None,
                 deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
             },
             FileDetail {
                 gen: 2,
                 hash_sum: [0; 32],
                 block_chain: None,
                 deleted: true,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
             },
         ]
     );
@@ -264,6 +308,9 @@ async fn modify_event_with_exists_index() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             },
                             previous_details: vec![],
                             update_time: SystemTime::now(),
@@ -283,6 +330,11 @@ async fn modify_event_with_exists_index() {
                                 hash_sum: new_hash_sum,
                                 block_chain: Some(new_block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![FileDetail {
@@ -290,6 +342,9 @@ async fn modify_event_with_exists_index() {
                                 hash_sum,
                                 block_chain: None,
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }]
                 }))
                 .returning(|_| Ok(()));
@@ -337,6 +392,13 @@ async fn modify_event_with_exists_index() {
             hash_sum: new_hash_sum,
             block_chain: Some(new_block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -346,6 +408,9 @@ async fn modify_event_with_exists_index() {
             hash_sum,
             block_chain: None,
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         },]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
@@ -382,6 +447,9 @@ async fn modify_event_with_same_index() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             },
                             previous_details: vec![],
                             update_time: SystemTime::now(),
@@ -401,6 +469,11 @@ async fn modify_event_with_same_index() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![FileDetail {
@@ -408,6 +481,9 @@ async fn modify_event_with_same_index() {
                                 hash_sum,
                                 block_chain: None,
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }]
                 }))
                 .returning(|_| Ok(()));
@@ -498,12 +574,18 @@ async fn modify_event_with_no_file_deleted_index() {
                         hash_sum: [0; 32],
                         block_chain: None,
                         deleted: true,
+                        metadata: None,
+                        version: Default::default(),
+                        link_group: None,
                     },
                     previous_details: vec![FileDetail {
                         gen: 1,
                         hash_sum,
                         block_chain: None,
                         deleted: false,
+                        metadata: None,
+                        version: Default::default(),
+                        link_group: None,
                     }],
                     update_time: SystemTime::now(),
                     update_by: user_id.as_hyphenated().to_string(),
@@ -555,6 +637,9 @@ async fn modify_event_with_no_file_exists_index() {
                             hash_sum,
                             block_chain: Some(block_chain.clone()),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![],
                         update_time: SystemTime::now(),
@@ -574,6 +659,9 @@ async fn modify_event_with_no_file_exists_index() {
                             hash_sum: [0; 32],
                             block_chain: None,
                             deleted: true,
+                            metadata: None,
+                            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+                            link_group: None,
                         }
                     && arg.previous_details
                         == vec![FileDetail {
@@ -581,6 +669,9 @@ async fn modify_event_with_no_file_exists_index() {
                             hash_sum,
                             block_chain: None,
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         }]
             }))
             .returning(|_| Ok(()));
@@ -617,6 +708,9 @@ async fn modify_event_with_no_file_exists_index() {
             hash_sum: [0; 32],
             block_chain: None,
             deleted: true,
+            metadata: None,
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -626,7 +720,77 @@ async fn modify_event_with_no_file_exists_index() {
             hash_sum,
             block_chain: None,
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         },]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
 }
+
+#[tokio::test]
+async fn modify_event_with_reappeared_file() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+
+    // no update_file expectation, the transient absence must not generate a delete generation
+    index.expect_begin().returning(move || {
+        let mut index_guard = MockIndexGuard::new();
+        let block_chain = block_chain.clone();
+
+        index_guard
+            .expect_get_file()
+            .with(eq(OsStr::new("test.txt")))
+            .returning(move |_| {
+                Ok(Some(IndexFile {
+                    filename: "test.txt".into(),
+                    kind: FileKind::File,
+                    detail: FileDetail {
+                        gen: 1,
+                        hash_sum,
+                        block_chain: Some(block_chain.clone()),
+                        deleted: false,
+                        metadata: None,
+                        version: Default::default(),
+                        link_group: None,
+                    },
+                    previous_details: vec![],
+                    update_time: SystemTime::now(),
+                    update_by: user_id.as_hyphenated().to_string(),
+                }))
+            });
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    // the editor recreates the file during the debounce window
+    let path = dir.path().join("test.txt");
+    let recreate = tokio::spawn(async move {
+        time::sleep(Duration::from_millis(50)).await;
+        fs::write(path, b"test").await.unwrap();
+    });
+
+    let (sender, receiver) = flume::bounded::<SendRumors>(1);
+    let sender = sender.into_sink();
+
+    let watch_event_handler = WatchEventHandler::new(&user_id, &dir_id, dir.path(), &index, sender)
+        .with_options(SyncOptions {
+            debounce: Duration::from_millis(500),
+            ..Default::default()
+        });
+    watch_event_handler
+        .handle_watch_events(vec![WatchEvent::Modify {
+            name: OsString::from("test.txt"),
+        }])
+        .await
+        .unwrap();
+
+    recreate.await.unwrap();
+
+    receiver.recv_async().await.unwrap_err();
+}
@@ -1,15 +1,18 @@
 use std::env;
 use std::ffi::OsString;
 use std::io::Cursor;
+use std::time::SystemTime;
 
+use futures_util::stream;
 use mockall::predicate::*;
 use tempfile::TempDir;
+use tokio::fs;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 
 use super::*;
 use crate::ext::hash_file;
-use crate::index::{Block, BlockChain, MockIndex, MockIndexGuard};
+use crate::index::{Block, BlockChain, FileMetadata, MockIndex, MockIndexGuard, VersionVector};
 
 #[tokio::test]
 async fn rename_event() {
@@ -48,6 +51,11 @@ async fn rename_event() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details.is_empty()
                 }))
@@ -97,6 +105,13 @@ async fn rename_event() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert!(rumor.previous_details.is_empty());
@@ -134,6 +149,9 @@ async fn rename_event_with_old_file() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             },
                             previous_details: vec![],
                             update_time: SystemTime::now(),
@@ -153,6 +171,11 @@ async fn rename_event_with_old_file() {
                                 hash_sum: [0; 32],
                                 block_chain: None,
                                 deleted: true,
+                                metadata: None,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![FileDetail {
@@ -160,6 +183,9 @@ async fn rename_event_with_old_file() {
                                 hash_sum,
                                 block_chain: None,
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }]
                         && arg.update_by == user_id.as_hyphenated().to_string()
                 }))
@@ -181,6 +207,11 @@ async fn rename_event_with_old_file() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details.is_empty()
                 }))
@@ -230,6 +261,9 @@ async fn rename_event_with_old_file() {
             hash_sum: [0; 32],
             block_chain: None,
             deleted: true,
+            metadata: None,
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -239,6 +273,9 @@ async fn rename_event_with_old_file() {
             hash_sum,
             block_chain: None,
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         }]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
@@ -254,6 +291,13 @@ async fn rename_event_with_old_file() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert!(rumor.previous_details.is_empty());
@@ -289,12 +333,18 @@ async fn rename_event_with_old_deleted_file() {
                             hash_sum: [0; 32],
                             block_chain: None,
                             deleted: true,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![FileDetail {
                             gen: 1,
                             hash_sum,
                             block_chain: None,
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         }],
                         update_time: SystemTime::now(),
                         update_by: user_id.as_hyphenated().to_string(),
@@ -317,6 +367,11 @@ async fn rename_event_with_old_deleted_file() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details.is_empty()
                 }))
@@ -367,6 +422,13 @@ async fn rename_event_with_old_deleted_file() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert!(rumor.previous_details.is_empty());
@@ -410,9 +472,13 @@ async fn rename_event_with_exist_new_file() {
                                     offset: 0,
                                     len: 1,
                                     hash_sum,
+                                    zero: false,
                                 }],
                             }),
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![],
                         update_time: SystemTime::now(),
@@ -431,6 +497,11 @@ async fn rename_event_with_exist_new_file() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![FileDetail {
@@ -438,6 +509,9 @@ async fn rename_event_with_exist_new_file() {
                                 hash_sum,
                                 block_chain: None,
                                 deleted: false,
+                                metadata: None,
+                                version: Default::default(),
+                                link_group: None,
                             }]
                         && arg.update_by == user_id.as_hyphenated().to_string()
                 }))
@@ -487,6 +561,13 @@ async fn rename_event_with_exist_new_file() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -496,6 +577,9 @@ async fn rename_event_with_exist_new_file() {
             hash_sum,
             block_chain: None,
             deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
         }]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
@@ -534,12 +618,18 @@ async fn rename_event_with_deleted_new_file() {
                             hash_sum,
                             block_chain: None,
                             deleted: true,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         },
                         previous_details: vec![FileDetail {
                             gen: 1,
                             hash_sum,
                             block_chain: None,
                             deleted: false,
+                            metadata: None,
+                            version: Default::default(),
+                            link_group: None,
                         }],
                         update_time: SystemTime::now(),
                         update_by: user_id.as_hyphenated().to_string(),
@@ -557,6 +647,11 @@ async fn rename_event_with_deleted_new_file() {
                                 hash_sum,
                                 block_chain: Some(block_chain.clone()),
                                 deleted: false,
+                                metadata: arg.detail.metadata,
+                                version: VersionVector::initial(
+                                    &user_id.as_hyphenated().to_string(),
+                                ),
+                                link_group: None,
                             }
                         && arg.previous_details
                             == vec![
@@ -565,12 +660,18 @@ async fn rename_event_with_deleted_new_file() {
                                     hash_sum,
                                     block_chain: None,
                                     deleted: false,
+                                    metadata: None,
+                                    version: Default::default(),
+                                    link_group: None,
                                 },
                                 FileDetail {
                                     gen: 2,
                                     hash_sum,
                                     block_chain: None,
                                     deleted: true,
+                                    metadata: None,
+                                    version: Default::default(),
+                                    link_group: None,
                                 },
                             ]
                         && arg.update_by == user_id.as_hyphenated().to_string()
@@ -621,6 +722,13 @@ async fn rename_event_with_deleted_new_file() {
             hash_sum,
             block_chain: Some(block_chain),
             deleted: false,
+            metadata: Some(
+                FileMetadata::from_path(dir.path().join(&rumor.filename))
+                    .await
+                    .unwrap(),
+            ),
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+            link_group: None,
         }
     );
     assert_eq!(
@@ -631,14 +739,190 @@ async fn rename_event_with_deleted_new_file() {
                 hash_sum,
                 block_chain: None,
                 deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
             },
             FileDetail {
                 gen: 2,
                 hash_sum,
                 block_chain: None,
                 deleted: true,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
             },
         ]
     );
     assert_eq!(rumor.update_by, user_id.as_hyphenated().to_string());
 }
+
+#[tokio::test]
+async fn rename_dir_event() {
+    let dir = TempDir::new_in(env::temp_dir()).unwrap();
+    let user_id = Uuid::new_v4();
+    let dir_id = Uuid::new_v4();
+    let mut index = MockIndex::new();
+
+    let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+    let old_index_file = |filename: &str| IndexFile {
+        filename: OsString::from(filename),
+        kind: FileKind::File,
+        detail: FileDetail {
+            gen: 1,
+            hash_sum,
+            block_chain: Some(block_chain.clone()),
+            deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        },
+        previous_details: vec![],
+        update_time: SystemTime::UNIX_EPOCH,
+        update_by: user_id.as_hyphenated().to_string(),
+    };
+    let index_files = vec![old_index_file("old/test.txt"), old_index_file("other.txt")];
+
+    index.expect_begin().returning(move || {
+        let index_files = index_files.clone();
+
+        let mut index_guard = MockIndexGuard::new();
+        index_guard.expect_list_all_files().returning(move || {
+            Ok(Box::pin(stream::iter(
+                index_files.clone().into_iter().map(Ok),
+            )))
+        });
+        index_guard
+            .expect_update_file()
+            .with(function(|arg: &IndexFile| {
+                arg.filename == OsStr::new("old/test.txt")
+                    && arg.detail.deleted
+                    && arg.detail.gen == 2
+                    && arg.previous_details.len() == 1
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+        index_guard
+            .expect_get_file()
+            .with(eq(OsStr::new("new/test.txt")))
+            .returning(|_| Ok(None));
+        index_guard
+            .expect_create_file()
+            .with(function(move |arg: &IndexFile| {
+                arg.filename == OsStr::new("new/test.txt")
+                    && arg.detail.gen == 1
+                    && arg.detail.hash_sum == hash_sum
+                    && !arg.detail.deleted
+            }))
+            .times(1)
+            .returning(|_| Ok(()));
+        index_guard.expect_commit().returning(|| Ok(()));
+
+        Ok(index_guard)
+    });
+
+    let (sender, receiver) = flume::bounded::<SendRumors>(1);
+    let sender = sender.into_sink();
+
+    fs::create_dir(dir.path().join("new")).await.unwrap();
+    fs::write(dir.path().join("new/test.txt"), b"test")
+        .await
+        .unwrap();
+
+    let watch_event_handler = WatchEventHandler::new(&user_id, &dir_id, dir.path(), &index, sender);
+    watch_event_handler
+        .handle_watch_events(vec![WatchEvent::Rename {
+            old_name: OsString::from("old"),
+            new_name: OsString::from("new"),
+        }])
+        .await
+        .unwrap();
+
+    // the moved files are not hashed again, the peers rename the dir once
+    let send_rumors = receiver.recv_async().await.unwrap();
+    assert_eq!(
+        send_rumors
+            .rumors
+            .iter()
+            .map(|rumor| (rumor.filename.as_os_str(), rumor.detail.deleted))
+            .collect::<Vec<_>>(),
+        [
+            (OsStr::new("old/test.txt"), true),
+            (OsStr::new("new/test.txt"), false)
+        ]
+    );
+    assert_eq!(
+        send_rumors.dir_renames,
+        [DirRename {
+            old_dir: OsString::from("old"),
+            new_dir: OsString::from("new"),
+        }]
+    );
+}
+
+#[test]
+fn exclude_unsynced_file_events() {
+    let watch_events = vec![
+        WatchEvent::Add {
+            name: "a.txt.2024-01-01-00-00-00.conflict".into(),
+        },
+        WatchEvent::Rename {
+            old_name: "b.txt.2024-01-01-00-00-00.conflict".into(),
+            new_name: "b.txt".into(),
+        },
+        WatchEvent::Rename {
+            old_name: "c.txt".into(),
+            new_name: "c.txt.2024-01-01-00-00-00.conflict".into(),
+        },
+        WatchEvent::Modify {
+            name: "d.txt".into(),
+        },
+        WatchEvent::Add {
+            name: ".syncit-versions/d.txt~20231114-221320.000".into(),
+        },
+    ];
+
+    assert_eq!(
+        exclude_unsynced_files(watch_events),
+        [
+            WatchEvent::Add {
+                name: "b.txt".into()
+            },
+            WatchEvent::Delete {
+                name: "c.txt".into()
+            },
+            WatchEvent::Modify {
+                name: "d.txt".into()
+            },
+        ]
+    );
+}
+
+#[test]
+fn group_rename_events() {
+    let rename = |old_name: &str, new_name: &str| WatchEvent::Rename {
+        old_name: OsString::from(old_name),
+        new_name: OsString::from(new_name),
+    };
+    let modify = |name: &str| WatchEvent::Modify {
+        name: OsString::from(name),
+    };
+
+    let groups = group_related_events(vec![
+        rename("a.txt", "b.txt"),
+        modify("b.txt"),
+        modify("c.txt"),
+        rename("dir", "new_dir"),
+        modify("new_dir/d.txt"),
+        modify("a.txt"),
+    ]);
+    assert_eq!(
+        groups,
+        [
+            vec![rename("a.txt", "b.txt"), modify("b.txt")],
+            vec![modify("c.txt")],
+            vec![rename("dir", "new_dir"), modify("new_dir/d.txt")],
+            vec![modify("a.txt")],
+        ]
+    );
+}
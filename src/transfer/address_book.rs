@@ -0,0 +1,300 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tap::TapFallible;
+use thiserror::Error;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::events::SyncEvents;
+use crate::index::address::{AddressKind, AddressStore, PeerAddress};
+
+#[derive(Debug, Error)]
+pub enum ConnectError<E> {
+    #[error("peer {0} has no address")]
+    NoAddress(Uuid),
+    /// the error of the last tried address
+    #[error("connect all addresses failed: {0}")]
+    Connect(E),
+}
+
+/// the candidate addresses of the peers, such as the LAN, WAN and relay addresses. The address
+/// states are persisted by the store, so a peer is reconnected after the network changes or
+/// restarting without reconfiguration. The connected channel is usually passed to
+/// `RumorSender::add_peer` and the block transfer of the peer
+#[derive(Debug)]
+pub struct AddressBook {
+    store: Arc<dyn AddressStore>,
+    peers: Mutex<HashMap<Uuid, Vec<PeerAddress>>>,
+    events: SyncEvents,
+}
+
+impl AddressBook {
+    /// load the persisted addresses from the store
+    #[instrument]
+    pub async fn load(store: Arc<dyn AddressStore>) -> io::Result<Self> {
+        let mut peers = HashMap::<_, Vec<_>>::new();
+        for address in store.load_addresses().await? {
+            peers.entry(address.peer_id).or_default().push(address);
+        }
+
+        info!(peers = peers.len(), "load address book done");
+
+        Ok(Self {
+            store,
+            peers: Mutex::new(peers),
+            events: Default::default(),
+        })
+    }
+
+    /// publish the connected peers to the subscribers of the events
+    pub fn with_events(mut self, events: SyncEvents) -> Self {
+        self.events = events;
+
+        self
+    }
+
+    /// add the address of the peer, an existing address only changes the kind
+    #[instrument(skip(self))]
+    pub async fn add_address(
+        &self,
+        peer_id: Uuid,
+        addr: &str,
+        kind: AddressKind,
+    ) -> io::Result<()> {
+        let address = {
+            let mut peers = self.peers.lock().unwrap();
+            let addresses = peers.entry(peer_id).or_default();
+            match addresses.iter_mut().find(|address| address.addr == addr) {
+                None => {
+                    let address = PeerAddress::new(peer_id, addr, kind);
+                    addresses.push(address.clone());
+
+                    address
+                }
+
+                Some(address) => {
+                    address.kind = kind;
+
+                    address.clone()
+                }
+            }
+        };
+
+        self.store.save_address(&address).await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn remove_address(&self, peer_id: Uuid, addr: &str) -> io::Result<()> {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            if let Some(addresses) = peers.get_mut(&peer_id) {
+                addresses.retain(|address| address.addr != addr);
+                if addresses.is_empty() {
+                    peers.remove(&peer_id);
+                }
+            }
+        }
+
+        self.store.remove_address(peer_id, addr).await
+    }
+
+    pub fn peer_ids(&self) -> Vec<Uuid> {
+        self.peers.lock().unwrap().keys().copied().collect()
+    }
+
+    /// the addresses of the peer in the connection attempt order: the fewer consecutive
+    /// failures first, then LAN, WAN and relay, then the recent success first
+    pub fn candidates(&self, peer_id: Uuid) -> Vec<PeerAddress> {
+        let mut addresses = self
+            .peers
+            .lock()
+            .unwrap()
+            .get(&peer_id)
+            .cloned()
+            .unwrap_or_default();
+
+        addresses.sort_by_key(|address| {
+            (
+                address.failures,
+                address.kind,
+                Reverse(address.last_success),
+            )
+        });
+
+        addresses
+    }
+
+    /// try the candidate addresses in order until one is connected, the results are recorded,
+    /// so the failed address is tried later next time
+    #[instrument(skip(self, connect))]
+    pub async fn connect<T, E, F, Fut>(
+        &self,
+        peer_id: Uuid,
+        mut connect: F,
+    ) -> Result<(PeerAddress, T), ConnectError<E>>
+    where
+        E: std::error::Error,
+        F: FnMut(PeerAddress) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut last_err = None;
+        for address in self.candidates(peer_id) {
+            match connect(address.clone()).await {
+                Err(err) => {
+                    warn!(%err, addr = %address.addr, "connect peer address failed");
+
+                    self.record(&address, false).await;
+                    last_err = Some(err);
+                }
+
+                Ok(conn) => {
+                    info!(addr = %address.addr, "connect peer address done");
+
+                    let address = self.record(&address, true).await;
+                    self.events.peer_connected(
+                        &address,
+                        address.last_success.unwrap_or_else(SystemTime::now),
+                    );
+
+                    return Ok((address, conn));
+                }
+            }
+        }
+
+        Err(match last_err {
+            None => ConnectError::NoAddress(peer_id),
+            Some(err) => ConnectError::Connect(err),
+        })
+    }
+
+    /// a failed save is logged only, the connection should not fail because of the store
+    async fn record(&self, address: &PeerAddress, success: bool) -> PeerAddress {
+        let address = {
+            let mut peers = self.peers.lock().unwrap();
+            let recorded = peers.get_mut(&address.peer_id).and_then(|addresses| {
+                addresses
+                    .iter_mut()
+                    .find(|recorded| recorded.addr == address.addr)
+            });
+
+            match recorded {
+                // removed when connecting
+                None => return address.clone(),
+                Some(recorded) => {
+                    if success {
+                        recorded.failures = 0;
+                        recorded.last_success = Some(SystemTime::now());
+                    } else {
+                        recorded.failures = recorded.failures.saturating_add(1);
+                    }
+
+                    recorded.clone()
+                }
+            }
+        };
+
+        let _ = self
+            .store
+            .save_address(&address)
+            .await
+            .tap_err(|err| error!(%err, addr = %address.addr, "save peer address failed"));
+
+        address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::SyncEvent;
+    use crate::index::address::{MockAddressStore, NoopAddressStore};
+
+    #[tokio::test]
+    async fn connect_in_order() {
+        let peer_id = Uuid::new_v4();
+        let mut store = MockAddressStore::new();
+        store.expect_load_addresses().times(1).returning(move || {
+            Ok(vec![
+                PeerAddress::new(peer_id, "relay", AddressKind::Relay),
+                PeerAddress::new(peer_id, "wan", AddressKind::Wan),
+                PeerAddress::new(peer_id, "lan", AddressKind::Lan),
+            ])
+        });
+        store.expect_save_address().returning(|_| Ok(()));
+
+        let events = SyncEvents::default();
+        let mut subscriber = events.subscribe();
+        let address_book = AddressBook::load(Arc::new(store))
+            .await
+            .unwrap()
+            .with_events(events);
+        assert_eq!(address_book.peer_ids(), [peer_id]);
+
+        // the LAN address is unreachable after the network changes
+        let mut tried = vec![];
+        let (address, _) = address_book
+            .connect(peer_id, |address| {
+                tried.push(address.addr.clone());
+
+                async move {
+                    if address.kind == AddressKind::Lan {
+                        Err(io::Error::new(io::ErrorKind::Other, "unreachable"))
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(tried, ["lan", "wan"]);
+        assert_eq!(address.addr, "wan");
+        assert!(address.last_success.is_some());
+        match subscriber.try_recv().unwrap() {
+            SyncEvent::PeerConnected(connected) => assert_eq!(connected.addr, "wan"),
+            event => panic!("unexpected event {event:?}"),
+        }
+
+        // the failed address is tried last
+        assert_eq!(
+            address_book
+                .candidates(peer_id)
+                .into_iter()
+                .map(|address| address.addr)
+                .collect::<Vec<_>>(),
+            ["wan", "relay", "lan"]
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_no_address() {
+        let address_book = AddressBook::load(Arc::new(NoopAddressStore)).await.unwrap();
+        let peer_id = Uuid::new_v4();
+
+        let err = address_book
+            .connect(peer_id, |_| async { Ok::<_, io::Error>(()) })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ConnectError::NoAddress(id) if id == peer_id));
+
+        address_book
+            .add_address(peer_id, "lan", AddressKind::Lan)
+            .await
+            .unwrap();
+        let err = address_book
+            .connect(peer_id, |_| async {
+                Err::<(), _>(io::Error::new(io::ErrorKind::Other, "unreachable"))
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ConnectError::Connect(_)));
+        assert_eq!(address_book.candidates(peer_id)[0].failures, 1);
+
+        address_book.remove_address(peer_id, "lan").await.unwrap();
+        assert!(address_book.peer_ids().is_empty());
+    }
+}
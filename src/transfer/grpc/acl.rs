@@ -0,0 +1,242 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use thiserror::Error;
+use tonic::Status;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// the authenticated device id of the request, the authentication layer, such as an interceptor
+/// which verifies the device token or the client certificate, inserts it into the request
+/// extensions
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct DeviceId(pub Uuid);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Error)]
+pub enum AclError {
+    #[error("device is not authenticated")]
+    Unauthenticated,
+
+    #[error("device {device_id} is not allowed to access dir {dir_id}")]
+    PermissionDenied { device_id: Uuid, dir_id: Uuid },
+}
+
+impl From<AclError> for Status {
+    fn from(err: AclError) -> Self {
+        match err {
+            AclError::Unauthenticated => Status::unauthenticated(err.to_string()),
+            AclError::PermissionDenied { .. } => Status::permission_denied(err.to_string()),
+        }
+    }
+}
+
+/// the dirs which the devices may join, the clones share the same shares, so the admin can grant
+/// or revoke the shares when the server is running. When a server hosts the dirs of several
+/// users, the dirs and the devices are registered with their users, a device may access the dirs
+/// of its user without the shares, and never the dirs of the other users
+#[derive(Debug, Clone, Default)]
+pub struct ShareAcl {
+    shares: Arc<RwLock<HashMap<Uuid, HashSet<Uuid>>>>,
+    tenants: Arc<RwLock<Tenants>>,
+}
+
+#[derive(Debug, Default)]
+struct Tenants {
+    /// the user of the device
+    device_users: HashMap<Uuid, Uuid>,
+    /// the user who owns the dir
+    dir_owners: HashMap<Uuid, Uuid>,
+}
+
+impl ShareAcl {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// when return false, the device has joined the dir
+    pub fn grant(&self, device_id: Uuid, dir_id: Uuid) -> bool {
+        let granted = self
+            .shares
+            .write()
+            .unwrap()
+            .entry(device_id)
+            .or_default()
+            .insert(dir_id);
+
+        info!(%device_id, %dir_id, granted, "grant share done");
+
+        granted
+    }
+
+    /// when return false, the device hasn't joined the dir
+    pub fn revoke(&self, device_id: Uuid, dir_id: Uuid) -> bool {
+        let mut shares = self.shares.write().unwrap();
+        let revoked = match shares.get_mut(&device_id) {
+            None => false,
+            Some(dirs) => {
+                let revoked = dirs.remove(&dir_id);
+                if dirs.is_empty() {
+                    shares.remove(&device_id);
+                }
+
+                revoked
+            }
+        };
+
+        info!(%device_id, %dir_id, revoked, "revoke share done");
+
+        revoked
+    }
+
+    /// revoke all shares of the device, such as a lost device, the device is unregistered from
+    /// its user too
+    pub fn revoke_device(&self, device_id: Uuid) {
+        self.shares.write().unwrap().remove(&device_id);
+        self.tenants
+            .write()
+            .unwrap()
+            .device_users
+            .remove(&device_id);
+
+        info!(%device_id, "revoke device shares done");
+    }
+
+    /// the device belongs to the user, a registered device is moved to the user
+    pub fn register_device(&self, device_id: Uuid, user_id: Uuid) {
+        self.tenants
+            .write()
+            .unwrap()
+            .device_users
+            .insert(device_id, user_id);
+
+        info!(%device_id, %user_id, "register device done");
+    }
+
+    /// the dir belongs to the user, only the devices of the user may access it
+    pub fn set_dir_owner(&self, dir_id: Uuid, user_id: Uuid) {
+        self.tenants
+            .write()
+            .unwrap()
+            .dir_owners
+            .insert(dir_id, user_id);
+
+        info!(%dir_id, %user_id, "set dir owner done");
+    }
+
+    /// the dirs which the device may join
+    pub fn shares(&self, device_id: Uuid) -> Vec<Uuid> {
+        self.shares
+            .read()
+            .unwrap()
+            .get(&device_id)
+            .map(|dirs| dirs.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// the dir which has an owner is only allowed for the devices of the owner, the other dirs
+    /// are allowed by the shares
+    pub fn is_allowed(&self, device_id: Uuid, dir_id: Uuid) -> bool {
+        {
+            let tenants = self.tenants.read().unwrap();
+            if let Some(owner) = tenants.dir_owners.get(&dir_id) {
+                return tenants.device_users.get(&device_id) == Some(owner);
+            }
+        }
+
+        self.shares
+            .read()
+            .unwrap()
+            .get(&device_id)
+            .is_some_and(|dirs| dirs.contains(&dir_id))
+    }
+
+    /// check the device of the request may access the dir
+    pub fn check(&self, device_id: Option<&DeviceId>, dir_id: Uuid) -> Result<(), AclError> {
+        let device_id = match device_id {
+            None => {
+                warn!(%dir_id, "request is not authenticated");
+
+                return Err(AclError::Unauthenticated);
+            }
+
+            Some(device_id) => device_id.0,
+        };
+
+        if !self.is_allowed(device_id, dir_id) {
+            warn!(%device_id, %dir_id, "device is not allowed to access the dir");
+
+            return Err(AclError::PermissionDenied { device_id, dir_id });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grant_and_revoke() {
+        let acl = ShareAcl::new();
+        let device_id = Uuid::new_v4();
+        let dir_id = Uuid::new_v4();
+
+        assert_eq!(
+            acl.check(None, dir_id).unwrap_err(),
+            AclError::Unauthenticated
+        );
+        assert_eq!(
+            acl.check(Some(&DeviceId(device_id)), dir_id).unwrap_err(),
+            AclError::PermissionDenied { device_id, dir_id }
+        );
+
+        assert!(acl.grant(device_id, dir_id));
+        assert!(!acl.grant(device_id, dir_id));
+        acl.clone()
+            .check(Some(&DeviceId(device_id)), dir_id)
+            .unwrap();
+        assert!(!acl.is_allowed(Uuid::new_v4(), dir_id));
+        assert_eq!(acl.shares(device_id), [dir_id]);
+
+        assert!(acl.revoke(device_id, dir_id));
+        assert!(!acl.revoke(device_id, dir_id));
+        assert!(!acl.is_allowed(device_id, dir_id));
+
+        acl.grant(device_id, dir_id);
+        acl.revoke_device(device_id);
+        assert!(acl.shares(device_id).is_empty());
+    }
+
+    #[test]
+    fn tenant_isolation() {
+        let acl = ShareAcl::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let (alice_device, bob_device) = (Uuid::new_v4(), Uuid::new_v4());
+        let (alice_dir, bob_dir) = (Uuid::new_v4(), Uuid::new_v4());
+
+        acl.register_device(alice_device, alice);
+        acl.register_device(bob_device, bob);
+        acl.set_dir_owner(alice_dir, alice);
+        acl.set_dir_owner(bob_dir, bob);
+
+        // the devices of the owner need no shares
+        acl.check(Some(&DeviceId(alice_device)), alice_dir).unwrap();
+        acl.check(Some(&DeviceId(bob_device)), bob_dir).unwrap();
+
+        // a share never crosses the users
+        acl.grant(bob_device, alice_dir);
+        assert_eq!(
+            acl.check(Some(&DeviceId(bob_device)), alice_dir)
+                .unwrap_err(),
+            AclError::PermissionDenied {
+                device_id: bob_device,
+                dir_id: alice_dir,
+            }
+        );
+        assert!(!acl.is_allowed(Uuid::new_v4(), alice_dir));
+
+        acl.revoke_device(alice_device);
+        assert!(!acl.is_allowed(alice_device, alice_dir));
+    }
+}
@@ -3,17 +3,20 @@ use bytes::Bytes;
 use futures_util::{stream, Stream, TryStreamExt};
 use tap::TapFallible;
 use tonic::body::BoxBody;
+use tonic::codec::CompressionEncoding;
 use tonic::codegen::{Body, StdError};
 use tonic::Status;
 use tower::Service;
 use tracing::{error, info, instrument};
 
 use super::super::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+use super::config::{GrpcConfig, DEFAULT_MAX_MESSAGE_SIZE};
 use super::pb::{self, download_transfer_service_client::DownloadTransferServiceClient};
 
 #[derive(Debug)]
 pub struct GrpcClient<T> {
     client: DownloadTransferServiceClient<T>,
+    max_message_size: usize,
 }
 
 impl<T, RespBody> GrpcClient<T>
@@ -27,6 +30,20 @@ where
     pub fn new(grpc_channel: T) -> Self {
         Self {
             client: DownloadTransferServiceClient::new(grpc_channel),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+
+    pub fn with_config(grpc_channel: T, config: &GrpcConfig) -> Self {
+        let mut client = DownloadTransferServiceClient::new(grpc_channel)
+            .accept_compressed(CompressionEncoding::Gzip);
+        if let Some(compression) = config.compression {
+            client = client.send_compressed(compression.into());
+        }
+
+        Self {
+            client,
+            max_message_size: config.max_message_size,
         }
     }
 }
@@ -49,6 +66,22 @@ where
         &'a self,
         block_offset: &'a [DownloadBlockRequest],
     ) -> Result<Self::BlockStream<'a>, Self::Error> {
+        if let Some(req) = block_offset
+            .iter()
+            .find(|req| req.len > self.max_message_size as u64)
+        {
+            error!(
+                len = req.len,
+                max_message_size = self.max_message_size,
+                "block request len is larger than max message size"
+            );
+
+            return Err(Status::invalid_argument(format!(
+                "block request len {} is larger than max message size {}",
+                req.len, self.max_message_size
+            )));
+        }
+
         let reqs = block_offset
             .iter()
             .map(|req| pb::DownloadBlockRequest {
@@ -71,12 +104,29 @@ where
         info!("send download request done");
 
         let resp = resp.into_inner();
+        let max_message_size = self.max_message_size;
+
+        Ok(resp.and_then(move |block| async move {
+            match block.inner {
+                None => Ok(None),
+
+                Some(block) if block.data.len() > max_message_size => {
+                    error!(
+                        len = block.data.len(),
+                        max_message_size, "block data len is larger than max message size"
+                    );
+
+                    Err(Status::resource_exhausted(format!(
+                        "block data len {} is larger than max message size {max_message_size}",
+                        block.data.len()
+                    )))
+                }
 
-        Ok(resp.map_ok(|block| {
-            block.inner.map(|block| DownloadBlock {
-                offset: block.offset,
-                data: block.data,
-            })
+                Some(block) => Ok(Some(DownloadBlock {
+                    offset: block.offset,
+                    data: block.data,
+                })),
+            }
         }))
     }
 }
@@ -157,16 +207,14 @@ mod tests {
 
         let grpc_client = GrpcClient::new(build_channel(client).await);
 
-        let resp = grpc_client
-            .download(&[DownloadBlockRequest {
-                dir_id,
-                filename: "test.txt".to_string(),
-                offset: 0,
-                len: 4,
-                hash_sum,
-            }])
-            .await
-            .unwrap();
+        let reqs = [DownloadBlockRequest {
+            dir_id,
+            filename: "test.txt".to_string(),
+            offset: 0,
+            len: 4,
+            hash_sum,
+        }];
+        let resp = grpc_client.download(&reqs).await.unwrap();
 
         let resp = resp.try_collect::<Vec<_>>().await.unwrap();
         assert_eq!(
@@ -206,16 +254,14 @@ mod tests {
 
         let (hash_sum, _) = hash_file(Cursor::new(b"wrong")).await.unwrap();
 
-        let resp = grpc_client
-            .download(&[DownloadBlockRequest {
-                dir_id,
-                filename: "test.txt".to_string(),
-                offset: 0,
-                len: 4,
-                hash_sum,
-            }])
-            .await
-            .unwrap();
+        let reqs = [DownloadBlockRequest {
+            dir_id,
+            filename: "test.txt".to_string(),
+            offset: 0,
+            len: 4,
+            hash_sum,
+        }];
+        let resp = grpc_client.download(&reqs).await.unwrap();
 
         let resp = resp.try_collect::<Vec<_>>().await.unwrap();
         assert_eq!(resp, vec![None]);
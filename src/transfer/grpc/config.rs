@@ -0,0 +1,90 @@
+use tonic::codec::CompressionEncoding;
+use tonic::transport::{Endpoint, Server};
+
+// 8MiB, leave enough room for a 4MiB block and the message overhead
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+// 8MiB, the h2 default 64KiB window stalls every 4MiB block many times
+pub const DEFAULT_STREAM_WINDOW_SIZE: u32 = 8 * 1024 * 1024;
+
+// 32MiB
+pub const DEFAULT_CONNECTION_WINDOW_SIZE: u32 = 32 * 1024 * 1024;
+
+/// the max concurrent streams of a connection of the server role, many devices download from it
+/// at the same time
+pub const SERVER_MAX_CONCURRENT_STREAMS: u32 = 1024;
+
+/// the max concurrent requests of a connection of the server role
+pub const SERVER_CONCURRENCY_LIMIT_PER_CONNECTION: usize = 256;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Compression {
+    Gzip,
+}
+
+impl From<Compression> for CompressionEncoding {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::Gzip => CompressionEncoding::Gzip,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GrpcConfig {
+    /// the max size of a single block data, block request or response which len is larger than it
+    /// will be rejected
+    pub max_message_size: usize,
+    /// compression of the sent messages, the received messages compression is always accepted
+    pub compression: Option<Compression>,
+    pub initial_stream_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
+    /// None means the h2 default
+    pub max_concurrent_streams: Option<u32>,
+    /// None means no limit
+    pub concurrency_limit_per_connection: Option<usize>,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            compression: None,
+            initial_stream_window_size: Some(DEFAULT_STREAM_WINDOW_SIZE),
+            initial_connection_window_size: Some(DEFAULT_CONNECTION_WINDOW_SIZE),
+            max_concurrent_streams: None,
+            concurrency_limit_per_connection: None,
+        }
+    }
+}
+
+impl GrpcConfig {
+    /// the config of the always on server role, which serves many devices
+    pub fn server() -> Self {
+        Self {
+            max_concurrent_streams: Some(SERVER_MAX_CONCURRENT_STREAMS),
+            concurrency_limit_per_connection: Some(SERVER_CONCURRENCY_LIMIT_PER_CONNECTION),
+            ..Default::default()
+        }
+    }
+
+    /// apply the transport level config to the client endpoint
+    pub fn apply_endpoint(&self, endpoint: Endpoint) -> Endpoint {
+        endpoint
+            .initial_stream_window_size(self.initial_stream_window_size)
+            .initial_connection_window_size(self.initial_connection_window_size)
+    }
+
+    /// apply the transport level config to the server
+    pub fn apply_server(&self, server: Server) -> Server {
+        let server = server
+            .initial_stream_window_size(self.initial_stream_window_size)
+            .initial_connection_window_size(self.initial_connection_window_size)
+            .max_concurrent_streams(self.max_concurrent_streams);
+
+        match self.concurrency_limit_per_connection {
+            None => server,
+            Some(limit) => server.concurrency_limit_per_connection(limit),
+        }
+    }
+}
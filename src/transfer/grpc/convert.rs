@@ -0,0 +1,352 @@
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use super::pb;
+use crate::index::{Block, BlockChain, FileDetail, FileKind, FileMetadata, IndexFile, Sha256sum};
+use crate::sync_control::anti_entropy::{DigestReply, FileDigest};
+use crate::sync_control::event::DirRename;
+
+#[derive(Debug, Error)]
+pub enum ConvertError {
+    #[error("invalid hash sum length {0}, should be 32")]
+    InvalidHashSum(usize),
+
+    #[error("invalid file kind {0}")]
+    InvalidFileKind(i32),
+
+    #[error("missing field {0}")]
+    MissingField(&'static str),
+
+    #[error("invalid uuid: {0}")]
+    InvalidUuid(#[from] uuid::Error),
+
+    #[error("decode protobuf failed: {0}")]
+    Decode(#[from] prost::DecodeError),
+}
+
+impl From<&Block> for pb::Block {
+    fn from(block: &Block) -> Self {
+        Self {
+            offset: block.offset,
+            len: block.len,
+            hash_sum: Bytes::copy_from_slice(&block.hash_sum),
+            zero: block.zero,
+        }
+    }
+}
+
+impl TryFrom<pb::Block> for Block {
+    type Error = ConvertError;
+
+    fn try_from(block: pb::Block) -> Result<Self, Self::Error> {
+        Ok(Self {
+            offset: block.offset,
+            len: block.len,
+            hash_sum: to_hash_sum(&block.hash_sum)?,
+            zero: block.zero,
+        })
+    }
+}
+
+impl From<&BlockChain> for pb::BlockChain {
+    fn from(block_chain: &BlockChain) -> Self {
+        Self {
+            block_size: block_chain.block_size,
+            blocks: block_chain.blocks.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl TryFrom<pb::BlockChain> for BlockChain {
+    type Error = ConvertError;
+
+    fn try_from(block_chain: pb::BlockChain) -> Result<Self, Self::Error> {
+        Ok(Self {
+            block_size: block_chain.block_size,
+            blocks: block_chain
+                .blocks
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl From<&FileMetadata> for pb::FileMetadata {
+    fn from(metadata: &FileMetadata) -> Self {
+        Self {
+            mode: metadata.mode,
+            mtime: metadata
+                .mtime
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            uid: metadata.uid,
+            gid: metadata.gid,
+        }
+    }
+}
+
+impl From<pb::FileMetadata> for FileMetadata {
+    fn from(metadata: pb::FileMetadata) -> Self {
+        Self {
+            mode: metadata.mode,
+            mtime: SystemTime::UNIX_EPOCH + Duration::from_nanos(metadata.mtime),
+            uid: metadata.uid,
+            gid: metadata.gid,
+        }
+    }
+}
+
+impl From<&FileDetail> for pb::FileDetail {
+    fn from(detail: &FileDetail) -> Self {
+        Self {
+            gen: detail.gen,
+            hash_sum: Bytes::copy_from_slice(&detail.hash_sum),
+            block_chain: detail.block_chain.as_ref().map(Into::into),
+            deleted: detail.deleted,
+            metadata: detail.metadata.as_ref().map(Into::into),
+            version: detail
+                .version
+                .iter()
+                .map(|(user_id, count)| (user_id.to_string(), count))
+                .collect(),
+            link_group: detail
+                .link_group
+                .as_ref()
+                .map(|link_group| Bytes::copy_from_slice(link_group.as_bytes())),
+        }
+    }
+}
+
+impl TryFrom<pb::FileDetail> for FileDetail {
+    type Error = ConvertError;
+
+    fn try_from(detail: pb::FileDetail) -> Result<Self, Self::Error> {
+        Ok(Self {
+            gen: detail.gen,
+            hash_sum: to_hash_sum(&detail.hash_sum)?,
+            block_chain: detail.block_chain.map(TryInto::try_into).transpose()?,
+            deleted: detail.deleted,
+            metadata: detail.metadata.map(Into::into),
+            version: detail.version.into_iter().collect(),
+            link_group: detail
+                .link_group
+                .map(|link_group| OsString::from_vec(link_group.to_vec())),
+        })
+    }
+}
+
+impl From<FileKind> for pb::FileKind {
+    fn from(kind: FileKind) -> Self {
+        match kind {
+            FileKind::File => pb::FileKind::File,
+            FileKind::Symlink => pb::FileKind::Symlink,
+            FileKind::Special => pb::FileKind::Special,
+        }
+    }
+}
+
+impl From<&IndexFile> for pb::IndexFile {
+    fn from(index_file: &IndexFile) -> Self {
+        let update_time = index_file
+            .update_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        Self {
+            filename: Bytes::copy_from_slice(index_file.filename.as_bytes()),
+            kind: pb::FileKind::from(index_file.kind) as _,
+            detail: Some((&index_file.detail).into()),
+            previous_details: index_file.previous_details.iter().map(Into::into).collect(),
+            update_time,
+            update_by: index_file.update_by.clone(),
+        }
+    }
+}
+
+impl TryFrom<pb::IndexFile> for IndexFile {
+    type Error = ConvertError;
+
+    fn try_from(index_file: pb::IndexFile) -> Result<Self, Self::Error> {
+        let kind = match pb::FileKind::from_i32(index_file.kind) {
+            None => return Err(ConvertError::InvalidFileKind(index_file.kind)),
+            Some(pb::FileKind::File) => FileKind::File,
+            Some(pb::FileKind::Symlink) => FileKind::Symlink,
+            Some(pb::FileKind::Special) => FileKind::Special,
+        };
+
+        let detail = index_file
+            .detail
+            .ok_or(ConvertError::MissingField("detail"))?
+            .try_into()?;
+
+        Ok(Self {
+            filename: OsString::from_vec(index_file.filename.to_vec()),
+            kind,
+            detail,
+            previous_details: index_file
+                .previous_details
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()?,
+            update_time: SystemTime::UNIX_EPOCH + Duration::from_nanos(index_file.update_time),
+            update_by: index_file.update_by,
+        })
+    }
+}
+
+impl From<&DirRename> for pb::DirRename {
+    fn from(dir_rename: &DirRename) -> Self {
+        Self {
+            old_dir: Bytes::copy_from_slice(dir_rename.old_dir.as_bytes()),
+            new_dir: Bytes::copy_from_slice(dir_rename.new_dir.as_bytes()),
+        }
+    }
+}
+
+impl From<pb::DirRename> for DirRename {
+    fn from(dir_rename: pb::DirRename) -> Self {
+        Self {
+            old_dir: OsString::from_vec(dir_rename.old_dir.to_vec()),
+            new_dir: OsString::from_vec(dir_rename.new_dir.to_vec()),
+        }
+    }
+}
+
+impl From<&FileDigest> for pb::FileDigest {
+    fn from(digest: &FileDigest) -> Self {
+        Self {
+            filename: Bytes::copy_from_slice(digest.filename.as_bytes()),
+            gen: digest.gen,
+            hash_sum: Bytes::copy_from_slice(&digest.hash_sum),
+            deleted: digest.deleted,
+            version: digest
+                .version
+                .iter()
+                .map(|(user_id, count)| (user_id.to_string(), count))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<pb::FileDigest> for FileDigest {
+    type Error = ConvertError;
+
+    fn try_from(digest: pb::FileDigest) -> Result<Self, Self::Error> {
+        Ok(Self {
+            filename: OsString::from_vec(digest.filename.to_vec()),
+            gen: digest.gen,
+            hash_sum: to_hash_sum(&digest.hash_sum)?,
+            deleted: digest.deleted,
+            version: digest.version.into_iter().collect(),
+        })
+    }
+}
+
+impl From<&DigestReply> for pb::DigestReply {
+    fn from(reply: &DigestReply) -> Self {
+        Self {
+            newer: reply.newer.iter().map(Into::into).collect(),
+            missing: reply
+                .missing
+                .iter()
+                .map(|filename| Bytes::copy_from_slice(filename.as_bytes()))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<pb::DigestReply> for DigestReply {
+    type Error = ConvertError;
+
+    fn try_from(reply: pb::DigestReply) -> Result<Self, Self::Error> {
+        Ok(Self {
+            newer: reply
+                .newer
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()?,
+            missing: reply
+                .missing
+                .into_iter()
+                .map(|filename| OsString::from_vec(filename.to_vec()))
+                .collect(),
+        })
+    }
+}
+
+fn to_hash_sum(hash_sum: &[u8]) -> Result<Sha256sum, ConvertError> {
+    hash_sum
+        .try_into()
+        .map_err(|_| ConvertError::InvalidHashSum(hash_sum.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::ext::hash_file;
+
+    #[tokio::test]
+    async fn index_file_round_trip() {
+        let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+        let index_file = IndexFile {
+            filename: OsString::from_vec(b"dir/\xfftest.txt".to_vec()),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 2,
+                hash_sum,
+                block_chain: Some(block_chain),
+                deleted: false,
+                metadata: Some(FileMetadata {
+                    mode: 0o755,
+                    mtime: SystemTime::now(),
+                    uid: Some(1000),
+                    gid: None,
+                }),
+                version: Default::default(),
+                link_group: Some(OsString::from("dir/link.txt")),
+            },
+            previous_details: vec![FileDetail {
+                gen: 1,
+                hash_sum: [0; 32],
+                block_chain: None,
+                deleted: true,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            }],
+            update_time: SystemTime::now(),
+            update_by: "test".to_string(),
+        };
+
+        let pb_index_file = pb::IndexFile::from(&index_file);
+
+        assert_eq!(IndexFile::try_from(pb_index_file).unwrap(), index_file);
+    }
+
+    #[test]
+    fn invalid_hash_sum() {
+        let detail = pb::FileDetail {
+            gen: 1,
+            hash_sum: Bytes::from_static(b"short"),
+            block_chain: None,
+            deleted: false,
+            metadata: None,
+            version: Default::default(),
+            link_group: None,
+        };
+
+        assert!(matches!(
+            FileDetail::try_from(detail),
+            Err(ConvertError::InvalidHashSum(5))
+        ));
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use http::Uri;
+use tap::TapFallible;
+use tokio::net::{self, TcpStream};
+use tokio::{select, time};
+use tonic::transport::{Channel, Endpoint};
+use tracing::{info, instrument, warn};
+
+use super::proxy::Proxy;
+
+/// the RFC 8305 recommended delay before starting the next connection attempt
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// the connect results of a resolved address
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct DialStats {
+    pub successes: u64,
+    /// the consecutive failures, reset by a success
+    pub failures: u64,
+}
+
+/// the per address connect results, the clones share the results. The failed addresses are tried
+/// later in their address family, and a dial error is recorded as a failure of the peer address
+/// by the address book
+#[derive(Debug, Clone, Default)]
+pub struct DialMetrics {
+    stats: Arc<Mutex<HashMap<SocketAddr, DialStats>>>,
+}
+
+impl DialMetrics {
+    pub fn stats(&self, addr: SocketAddr) -> DialStats {
+        self.stats
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn record_success(&self, addr: SocketAddr) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(addr).or_default();
+        stats.successes += 1;
+        stats.failures = 0;
+    }
+
+    fn record_failure(&self, addr: SocketAddr) {
+        self.stats.lock().unwrap().entry(addr).or_default().failures += 1;
+    }
+}
+
+/// dial the dual stack peers with the RFC 8305 happy eyeballs, the resolved addresses are
+/// interleaved by the family, and the next attempt starts when the previous one fails or doesn't
+/// finish in the attempt delay, so a broken IPv6 or IPv4 network only delays the connection a bit
+#[derive(Debug, Clone)]
+pub struct Dialer {
+    attempt_delay: Duration,
+    prefer_ipv6: bool,
+    metrics: DialMetrics,
+    proxy: Option<Proxy>,
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Self {
+            attempt_delay: DEFAULT_ATTEMPT_DELAY,
+            prefer_ipv6: true,
+            metrics: Default::default(),
+            proxy: None,
+        }
+    }
+}
+
+impl Dialer {
+    pub fn with_attempt_delay(mut self, attempt_delay: Duration) -> Self {
+        self.attempt_delay = attempt_delay;
+
+        self
+    }
+
+    /// try the IPv4 addresses first, such as the IPv6 network is known broken
+    pub fn with_prefer_ipv4(mut self) -> Self {
+        self.prefer_ipv6 = false;
+
+        self
+    }
+
+    /// share the metrics with other dialers
+    pub fn with_metrics(mut self, metrics: DialMetrics) -> Self {
+        self.metrics = metrics;
+
+        self
+    }
+
+    /// connect the peers through the proxy, the proxy is dialed with the happy eyeballs, the
+    /// LAN peers usually use a dialer without the proxy
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+
+        self
+    }
+
+    pub fn metrics(&self) -> &DialMetrics {
+        &self.metrics
+    }
+
+    /// connect the endpoint with the dialer, the endpoint config is still applied
+    pub async fn connect(&self, endpoint: Endpoint) -> Result<Channel, tonic::transport::Error> {
+        let dialer = self.clone();
+
+        endpoint
+            .connect_with_connector(tower::service_fn(move |uri: Uri| {
+                let dialer = dialer.clone();
+
+                async move { dialer.dial_uri(&uri).await }
+            }))
+            .await
+    }
+
+    /// the lazy version of [`Dialer::connect`], the peer is dialed when the channel is used
+    pub fn connect_lazy(&self, endpoint: Endpoint) -> Channel {
+        let dialer = self.clone();
+
+        endpoint.connect_with_connector_lazy(tower::service_fn(move |uri: Uri| {
+            let dialer = dialer.clone();
+
+            async move { dialer.dial_uri(&uri).await }
+        }))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn dial_uri(&self, uri: &Uri) -> io::Result<TcpStream> {
+        let (host, port) = uri_host_port(uri)?;
+
+        let proxy = match &self.proxy {
+            None => {
+                let addrs = net::lookup_host((host, port))
+                    .await
+                    .tap_err(|err| warn!(%err, host, "resolve host failed"))?
+                    .collect::<Vec<_>>();
+
+                return self.dial(addrs).await;
+            }
+
+            Some(proxy) => proxy,
+        };
+
+        // the target host is resolved by the proxy
+        let addrs = net::lookup_host(proxy.addr())
+            .await
+            .tap_err(|err| warn!(%err, proxy = proxy.addr(), "resolve proxy failed"))?
+            .collect::<Vec<_>>();
+
+        let mut stream = self.dial(addrs).await?;
+        proxy
+            .handshake(&mut stream, host, port)
+            .await
+            .tap_err(|err| warn!(%err, proxy = proxy.addr(), "proxy handshake failed"))?;
+
+        info!(proxy = proxy.addr(), "connect through proxy done");
+
+        Ok(stream)
+    }
+
+    /// race the addresses, the first connected stream is returned, the other attempts are
+    /// dropped
+    #[instrument(skip(self))]
+    pub async fn dial(&self, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+        let mut pending = self.sort_addrs(addrs).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+
+        loop {
+            if attempts.is_empty() {
+                match pending.next() {
+                    None => {
+                        return Err(last_err.unwrap_or_else(|| {
+                            io::Error::new(ErrorKind::AddrNotAvailable, "no address to dial")
+                        }))
+                    }
+
+                    Some(addr) => attempts.push(attempt(addr)),
+                }
+            }
+
+            select! {
+                Some((addr, result)) = attempts.next() => match result {
+                    Err(err) => {
+                        warn!(%err, %addr, "connect address failed");
+
+                        self.metrics.record_failure(addr);
+                        last_err = Some(err);
+
+                        // a failed attempt starts the next one without waiting
+                        if let Some(addr) = pending.next() {
+                            attempts.push(attempt(addr));
+                        }
+                    }
+
+                    Ok(stream) => {
+                        info!(%addr, "connect address done");
+
+                        self.metrics.record_success(addr);
+
+                        return Ok(stream);
+                    }
+                },
+
+                _ = time::sleep(self.attempt_delay), if !pending.as_slice().is_empty() => {
+                    if let Some(addr) = pending.next() {
+                        attempts.push(attempt(addr));
+                    }
+                }
+            }
+        }
+    }
+
+    /// interleave the addresses by the family, starting with the preferred family. The
+    /// addresses of a family keep the resolver order, except the failed ones are moved back
+    fn sort_addrs(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+            .into_iter()
+            .partition(|addr| addr.is_ipv6() == self.prefer_ipv6);
+        preferred.sort_by_key(|addr| self.metrics.stats(*addr).failures);
+        other.sort_by_key(|addr| self.metrics.stats(*addr).failures);
+
+        let mut sorted = Vec::with_capacity(preferred.len() + other.len());
+        let mut preferred = preferred.into_iter();
+        let mut other = other.into_iter();
+        loop {
+            match (preferred.next(), other.next()) {
+                (None, None) => return sorted,
+                (first, second) => sorted.extend(first.into_iter().chain(second)),
+            }
+        }
+    }
+}
+
+/// the host and the port of the uri, the port defaults to the one of the scheme
+pub(super) fn uri_host_port(uri: &Uri) -> io::Result<(&str, u16)> {
+    let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("uri {uri} has no host")))?;
+    // the IPv6 host of the uri is bracketed
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        });
+
+    Ok((host, port))
+}
+
+async fn attempt(addr: SocketAddr) -> (SocketAddr, io::Result<TcpStream>) {
+    (addr, TcpStream::connect(addr).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn interleave_families() {
+        let addrs = [
+            "127.0.0.1:80",
+            "127.0.0.2:80",
+            "127.0.0.3:80",
+            "[::1]:80",
+            "[::2]:80",
+        ]
+        .map(|addr| addr.parse::<SocketAddr>().unwrap());
+
+        let dialer = Dialer::default();
+        dialer.metrics.record_failure(addrs[3]);
+        assert_eq!(
+            dialer.sort_addrs(addrs.to_vec()),
+            [addrs[4], addrs[0], addrs[3], addrs[1], addrs[2]]
+        );
+
+        let dialer = Dialer::default().with_prefer_ipv4();
+        assert_eq!(
+            dialer.sort_addrs(addrs.to_vec()),
+            [addrs[0], addrs[3], addrs[1], addrs[4], addrs[2]]
+        );
+    }
+
+    #[tokio::test]
+    async fn dial_next_when_failed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the port is closed after the listener is dropped
+        let closed_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let dialer = Dialer::default().with_attempt_delay(Duration::from_secs(60));
+        let stream = dialer.dial(vec![closed_addr, addr]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+
+        assert_eq!(
+            dialer.metrics().stats(closed_addr),
+            DialStats {
+                successes: 0,
+                failures: 1
+            }
+        );
+        assert_eq!(
+            dialer.metrics().stats(addr),
+            DialStats {
+                successes: 1,
+                failures: 0
+            }
+        );
+
+        let err = dialer.dial(vec![closed_addr]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(dialer.metrics().stats(closed_addr).failures, 2);
+    }
+
+    #[tokio::test]
+    async fn dial_through_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Proxy::HttpConnect {
+            addr: listener.local_addr().unwrap().to_string(),
+            auth: None,
+        };
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // the peer host is not resolved locally
+            let req = b"CONNECT peer.invalid:8080 HTTP/1.1\r\nHost: peer.invalid:8080\r\n\r\n";
+            let mut buf = vec![0; req.len()];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, req);
+
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let dialer = Dialer::default().with_proxy(proxy);
+        dialer
+            .dial_uri(&Uri::from_static("http://peer.invalid:8080"))
+            .await
+            .unwrap();
+
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use flume::Sender;
+use tap::TapFallible;
+use tokio::sync::oneshot;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::Channel;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use super::acl::{DeviceId, ShareAcl};
+use super::config::GrpcConfig;
+use super::convert::ConvertError;
+use super::pb;
+use super::pb::digest_exchange_service_client::DigestExchangeServiceClient;
+use super::pb::digest_exchange_service_server::{
+    DigestExchangeService, DigestExchangeServiceServer,
+};
+use crate::sync_control::anti_entropy::{DigestExchange, DigestReply, FileDigest};
+use crate::sync_control::event::Event;
+
+/// exchange the index digests with the digest receivers of the peers
+#[derive(Debug)]
+pub struct GrpcDigestExchange {
+    user_id: Uuid,
+    peers: HashMap<Uuid, DigestExchangeServiceClient<Channel>>,
+    config: GrpcConfig,
+}
+
+impl GrpcDigestExchange {
+    pub fn new(user_id: Uuid) -> Self {
+        Self {
+            user_id,
+            peers: Default::default(),
+            config: Default::default(),
+        }
+    }
+
+    /// the config only applies to the peers added after it
+    pub fn config(mut self, config: GrpcConfig) -> Self {
+        self.config = config;
+
+        self
+    }
+
+    /// the channel connects to the digest receiver of the peer
+    pub fn add_peer(mut self, peer_id: Uuid, channel: Channel) -> Self {
+        let mut client =
+            DigestExchangeServiceClient::new(channel).accept_compressed(CompressionEncoding::Gzip);
+        if let Some(compression) = self.config.compression {
+            client = client.send_compressed(compression.into());
+        }
+
+        self.peers.insert(peer_id, client);
+
+        self
+    }
+}
+
+#[async_trait]
+impl DigestExchange for GrpcDigestExchange {
+    fn peer_ids(&self) -> Vec<Uuid> {
+        self.peers.keys().copied().collect()
+    }
+
+    #[instrument(err, skip(self, digests))]
+    async fn exchange(
+        &self,
+        peer_id: Uuid,
+        dir_id: Uuid,
+        digests: Vec<FileDigest>,
+    ) -> io::Result<DigestReply> {
+        let mut client = self.peers.get(&peer_id).cloned().ok_or_else(|| {
+            error!(%peer_id, "peer not found");
+
+            io::Error::new(io::ErrorKind::NotFound, format!("peer {peer_id} not found"))
+        })?;
+
+        let reply = client
+            .exchange_digests(pb::Digests {
+                dir_id: dir_id.as_hyphenated().to_string(),
+                sender_id: self.user_id.as_hyphenated().to_string(),
+                digests: digests.iter().map(Into::into).collect(),
+            })
+            .await
+            .tap_err(|err| warn!(%err, %peer_id, "exchange digests with peer failed"))
+            .map_err(io::Error::other)?
+            .into_inner();
+
+        let reply = DigestReply::try_from(reply)
+            .tap_err(|err| error!(%err, %peer_id, "decode digest reply failed"))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        info!(
+            %peer_id,
+            newer = reply.newer.len(),
+            missing = reply.missing.len(),
+            "exchange digests done"
+        );
+
+        Ok(reply)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DigestReceiverBuilder {
+    dirs: HashMap<Uuid, Sender<Event>>,
+    config: GrpcConfig,
+    acl: Option<ShareAcl>,
+}
+
+impl DigestReceiverBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// the received digests of the dir are sent to the event sender as [`Event::Digests`], the
+    /// dir sync controller replies them, its receiver should be merged into the event stream of
+    /// the controller
+    pub fn add_dir(mut self, dir_id: Uuid, event_sender: Sender<Event>) -> Self {
+        self.dirs.insert(dir_id, event_sender);
+
+        self
+    }
+
+    pub fn config(mut self, config: GrpcConfig) -> Self {
+        self.config = config;
+
+        self
+    }
+
+    /// only accept the digests of the authenticated devices which are allowed to join the dir,
+    /// and the device must be the sender of the digests
+    pub fn acl(mut self, acl: ShareAcl) -> Self {
+        self.acl = Some(acl);
+
+        self
+    }
+
+    pub fn build_service(self) -> DigestExchangeServiceServer<DigestReceiver> {
+        let mut service = DigestExchangeServiceServer::new(DigestReceiver {
+            dirs: Arc::new(self.dirs),
+            acl: self.acl,
+        })
+        .accept_compressed(CompressionEncoding::Gzip);
+        if let Some(compression) = self.config.compression {
+            service = service.send_compressed(compression.into());
+        }
+
+        service
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DigestReceiver {
+    dirs: Arc<HashMap<Uuid, Sender<Event>>>,
+    acl: Option<ShareAcl>,
+}
+
+#[async_trait]
+impl DigestExchangeService for DigestReceiver {
+    #[instrument(err, skip(self, request))]
+    async fn exchange_digests(
+        &self,
+        request: Request<pb::Digests>,
+    ) -> Result<Response<pb::DigestReply>, Status> {
+        let device_id = request.extensions().get::<DeviceId>().copied();
+        let (dir_id, sender_id, digests) = decode_digests(request.into_inner()).map_err(|err| {
+            error!(%err, "decode digests failed");
+
+            Status::invalid_argument(err.to_string())
+        })?;
+
+        if let Some(acl) = &self.acl {
+            acl.check(device_id.as_ref(), dir_id)?;
+
+            if device_id != Some(DeviceId(sender_id)) {
+                warn!(%sender_id, ?device_id, "digests sender is not the authenticated device");
+
+                return Err(Status::permission_denied(format!(
+                    "sender {sender_id} is not the authenticated device"
+                )));
+            }
+        }
+
+        let event_sender = self.dirs.get(&dir_id).ok_or_else(|| {
+            error!(%dir_id, "dir not found");
+
+            Status::not_found(format!("dir {dir_id} not found"))
+        })?;
+
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let event = Event::Digests {
+            sender_id,
+            digests,
+            reply: reply_sender,
+        };
+
+        event_sender.send_async(event).await.map_err(|_| {
+            error!(%dir_id, "dir event receiver is closed");
+
+            Status::unavailable(format!("dir {dir_id} is not syncing"))
+        })?;
+
+        let reply = reply_receiver.await.map_err(|_| {
+            error!(%dir_id, "dir controller doesn't reply the digests");
+
+            Status::unavailable(format!("dir {dir_id} doesn't reply the digests"))
+        })?;
+
+        info!(%dir_id, %sender_id, "reply digests done");
+
+        Ok(Response::new((&reply).into()))
+    }
+}
+
+/// return the dir id, the sender id and the digests
+fn decode_digests(digests: pb::Digests) -> Result<(Uuid, Uuid, Vec<FileDigest>), ConvertError> {
+    let dir_id = Uuid::parse_str(&digests.dir_id)?;
+    let sender_id = Uuid::parse_str(&digests.sender_id)?;
+    let digests = digests
+        .digests
+        .into_iter()
+        .map(TryInto::try_into)
+        .collect::<Result<_, _>>()?;
+
+    Ok((dir_id, sender_id, digests))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::time::{Duration, SystemTime};
+
+    use futures_util::stream;
+    use http::Uri;
+    use tonic::transport::{Endpoint, Server};
+
+    use super::*;
+    use crate::index::{FileDetail, FileKind, IndexFile, VersionVector};
+
+    #[tokio::test]
+    async fn exchange_digests() {
+        let user_id = Uuid::new_v4();
+        let dir_id = Uuid::new_v4();
+        let peer_id = Uuid::new_v4();
+
+        let (event_sender, event_receiver) = flume::unbounded();
+        let exchange = GrpcDigestExchange::new(user_id).add_peer(
+            peer_id,
+            serve(DigestReceiverBuilder::new().add_dir(dir_id, event_sender)).await,
+        );
+        assert_eq!(exchange.peer_ids(), [peer_id]);
+
+        let digests = vec![FileDigest {
+            filename: OsString::from("local.txt"),
+            gen: 1,
+            hash_sum: [1; 32],
+            deleted: false,
+            version: VersionVector::initial(&user_id.as_hyphenated().to_string()),
+        }];
+        let reply = DigestReply {
+            newer: vec![IndexFile {
+                filename: OsString::from("remote.txt"),
+                kind: FileKind::File,
+                detail: FileDetail {
+                    gen: 1,
+                    hash_sum: [2; 32],
+                    block_chain: None,
+                    deleted: true,
+                    metadata: None,
+                    version: VersionVector::initial(&peer_id.as_hyphenated().to_string()),
+                    link_group: None,
+                },
+                previous_details: vec![],
+                update_time: SystemTime::UNIX_EPOCH + Duration::from_secs(100),
+                update_by: peer_id.as_hyphenated().to_string(),
+            }],
+            missing: vec![OsString::from("local.txt")],
+        };
+
+        // the controller of the peer replies the digests
+        let controller = {
+            let expect_digests = digests.clone();
+            let reply = reply.clone();
+
+            tokio::spawn(async move {
+                let Event::Digests {
+                    sender_id,
+                    digests,
+                    reply: reply_sender,
+                } = event_receiver.recv_async().await.unwrap()
+                else {
+                    panic!("not digests event");
+                };
+                assert_eq!(sender_id, user_id);
+                assert_eq!(digests, expect_digests);
+
+                reply_sender.send(reply).unwrap();
+            })
+        };
+
+        let got_reply = exchange.exchange(peer_id, dir_id, digests).await.unwrap();
+        controller.await.unwrap();
+        assert_eq!(got_reply, reply);
+
+        // the unknown dir is rejected
+        exchange
+            .exchange(peer_id, Uuid::new_v4(), vec![])
+            .await
+            .unwrap_err();
+    }
+
+    async fn serve(builder: DigestReceiverBuilder) -> Channel {
+        let (client, server) = tokio::io::duplex(4096);
+
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(builder.build_service())
+                .serve_with_incoming(stream::iter(vec![Ok::<_, std::io::Error>(server)]))
+                .await
+        });
+
+        let mut client = Some(client);
+        Endpoint::try_from("http://127.0.0.1:80")
+            .unwrap()
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let client = client.take();
+
+                async move {
+                    client.ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::Other, "Client already taken")
+                    })
+                }
+            }))
+            .await
+            .unwrap()
+    }
+}
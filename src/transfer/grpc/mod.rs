@@ -1,4 +1,17 @@
+pub mod acl;
 pub mod client;
+pub mod config;
+pub mod convert;
+pub mod dial;
+pub mod digest_exchange;
+#[cfg(feature = "multicast")]
+pub mod multicast;
+pub mod proxy;
+pub mod relay;
+pub mod resolve;
+pub mod rumor_transport;
+pub mod server;
+pub mod snapshot;
 
 mod pb {
     tonic::include_proto!("syncit");
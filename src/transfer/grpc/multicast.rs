@@ -0,0 +1,297 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use prost::Message;
+use sha2::Sha256;
+use tap::TapFallible;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::convert::ConvertError;
+use super::pb;
+use crate::index::IndexFile;
+use crate::sync_control::event::Event;
+
+/// the max datagram size, the larger rumors are only delivered by the reliable path
+pub const MAX_DATAGRAM_SIZE: usize = 1400;
+
+const TAG_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Error)]
+pub enum MulticastError {
+    #[error("datagram length {0} is too short")]
+    TooShort(usize),
+
+    #[error("invalid datagram signature")]
+    InvalidSignature,
+
+    #[error("rumors seq is missing")]
+    MissingSeq,
+
+    #[error("convert rumors failed: {0}")]
+    Convert(#[from] ConvertError),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MulticastConfig {
+    pub group: SocketAddrV4,
+    /// the local interface which joins the group
+    pub interface: Ipv4Addr,
+    /// the shared key of the cluster, every datagram is signed by hmac-sha256 with it
+    pub key: Vec<u8>,
+}
+
+/// the rumors received from the multicast group
+#[derive(Debug, Eq, PartialEq)]
+pub struct MulticastRumors {
+    pub dir_id: Uuid,
+    pub sender_id: Uuid,
+    pub seq: u64,
+    pub rumors: Vec<IndexFile>,
+}
+
+impl MulticastRumors {
+    /// the rumors event of the dir sync controller, the [`RumorSender`] uses the same seq on the
+    /// multicast and the reliable path, so the later copy is dropped by the replay check. The dir
+    /// renames are only delivered by the reliable path, the multicast rumors are applied file by
+    /// file
+    ///
+    /// [`RumorSender`]: super::rumor_transport::RumorSender
+    pub fn into_event(self) -> Event {
+        Event::Rumors {
+            sender_id: self.sender_id,
+            seq: Some(self.seq),
+            remote_index: self.rumors,
+            dir_renames: vec![],
+        }
+    }
+}
+
+/// a best effort rumors channel for the LAN only clusters, the small rumors reach the peers with
+/// one datagram before the reliable path delivers them
+#[derive(Debug)]
+pub struct MulticastRumorChannel {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    key: Vec<u8>,
+    user_id: Uuid,
+}
+
+impl MulticastRumorChannel {
+    pub async fn bind(config: MulticastConfig, user_id: Uuid) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(
+            Ipv4Addr::UNSPECIFIED,
+            config.group.port(),
+        ))
+        .await
+        .tap_err(|err| error!(%err, group = %config.group, "bind multicast socket failed"))?;
+
+        socket
+            .join_multicast_v4(*config.group.ip(), config.interface)
+            .tap_err(|err| error!(%err, group = %config.group, "join multicast group failed"))?;
+
+        info!(group = %config.group, "join multicast group done");
+
+        Ok(Self {
+            socket,
+            group: config.group,
+            key: config.key,
+            user_id,
+        })
+    }
+
+    /// send to the group with a plain socket, such as a unicast one in the tests
+    #[cfg(test)]
+    pub(crate) fn from_socket(
+        socket: UdpSocket,
+        group: SocketAddrV4,
+        key: Vec<u8>,
+        user_id: Uuid,
+    ) -> Self {
+        Self {
+            socket,
+            group,
+            key,
+            user_id,
+        }
+    }
+
+    /// when return false, the rumors are too large for a datagram and are not sent
+    pub async fn send(&self, dir_id: Uuid, seq: u64, rumors: &[IndexFile]) -> io::Result<bool> {
+        let datagram = encode_datagram(&self.key, dir_id, self.user_id, seq, rumors);
+        if datagram.len() > MAX_DATAGRAM_SIZE {
+            info!(%dir_id, seq, len = datagram.len(), "rumors are too large for multicast, skip");
+
+            return Ok(false);
+        }
+
+        self.socket
+            .send_to(&datagram, self.group)
+            .await
+            .tap_err(|err| error!(%err, group = %self.group, "send multicast rumors failed"))?;
+
+        info!(%dir_id, seq, "send multicast rumors done");
+
+        Ok(true)
+    }
+
+    /// receive the next valid rumors, the invalid datagrams and the datagrams sent by self are
+    /// dropped
+    pub async fn recv(&self) -> io::Result<MulticastRumors> {
+        let mut buf = vec![0; u16::MAX as usize];
+        loop {
+            let (n, addr) = self
+                .socket
+                .recv_from(&mut buf)
+                .await
+                .tap_err(|err| error!(%err, "receive multicast datagram failed"))?;
+
+            match decode_datagram(&self.key, &buf[..n]) {
+                Err(err) => {
+                    warn!(%err, %addr, "drop invalid multicast datagram");
+                }
+
+                Ok(rumors) if rumors.sender_id == self.user_id => {}
+
+                Ok(rumors) => {
+                    info!(%addr, dir_id = %rumors.dir_id, seq = rumors.seq, "receive multicast rumors done");
+
+                    return Ok(rumors);
+                }
+            }
+        }
+    }
+}
+
+/// the datagram is the hmac-sha256 tag followed by the protobuf encoded Rumors
+fn encode_datagram(
+    key: &[u8],
+    dir_id: Uuid,
+    sender_id: Uuid,
+    seq: u64,
+    rumors: &[IndexFile],
+) -> Bytes {
+    let rumors = pb::Rumors {
+        dir_id: dir_id.as_hyphenated().to_string(),
+        sender_id: sender_id.as_hyphenated().to_string(),
+        rumors: rumors.iter().map(Into::into).collect(),
+        seq,
+        dir_renames: vec![],
+    };
+    let payload = rumors.encode_to_vec();
+
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(&payload);
+
+    let mut datagram = BytesMut::with_capacity(TAG_SIZE + payload.len());
+    datagram.put_slice(&mac.finalize().into_bytes());
+    datagram.put_slice(&payload);
+
+    datagram.freeze()
+}
+
+fn decode_datagram(key: &[u8], datagram: &[u8]) -> Result<MulticastRumors, MulticastError> {
+    if datagram.len() < TAG_SIZE {
+        return Err(MulticastError::TooShort(datagram.len()));
+    }
+
+    let (tag, payload) = datagram.split_at(TAG_SIZE);
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(payload);
+    mac.verify_slice(tag)
+        .map_err(|_| MulticastError::InvalidSignature)?;
+
+    let rumors = pb::Rumors::decode(payload).map_err(ConvertError::from)?;
+    // 0 is the protobuf default, it must not skip the replay check
+    if rumors.seq == 0 {
+        return Err(MulticastError::MissingSeq);
+    }
+
+    Ok(MulticastRumors {
+        dir_id: Uuid::parse_str(&rumors.dir_id).map_err(ConvertError::from)?,
+        sender_id: Uuid::parse_str(&rumors.sender_id).map_err(ConvertError::from)?,
+        seq: rumors.seq,
+        rumors: rumors
+            .rumors
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::index::{FileDetail, FileKind};
+
+    #[test]
+    fn datagram_round_trip() {
+        let dir_id = Uuid::new_v4();
+        let sender_id = Uuid::new_v4();
+        let rumors = vec![IndexFile {
+            filename: OsString::from("test.txt"),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum: [1; 32],
+                block_chain: None,
+                deleted: true,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH + Duration::from_secs(100),
+            update_by: sender_id.as_hyphenated().to_string(),
+        }];
+
+        let datagram = encode_datagram(b"key", dir_id, sender_id, 10, &rumors);
+
+        assert_eq!(
+            decode_datagram(b"key", &datagram).unwrap(),
+            MulticastRumors {
+                dir_id,
+                sender_id,
+                seq: 10,
+                rumors,
+            }
+        );
+
+        assert!(matches!(
+            decode_datagram(b"other key", &datagram),
+            Err(MulticastError::InvalidSignature)
+        ));
+
+        let mut forged = datagram.to_vec();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decode_datagram(b"key", &forged),
+            Err(MulticastError::InvalidSignature)
+        ));
+
+        assert!(matches!(
+            decode_datagram(b"key", &datagram[..10]),
+            Err(MulticastError::TooShort(10))
+        ));
+    }
+
+    #[test]
+    fn reject_missing_seq() {
+        // the seq 0 can't skip the replay check as the pulled rumors
+        let datagram = encode_datagram(b"key", Uuid::new_v4(), Uuid::new_v4(), 0, &[]);
+
+        assert!(matches!(
+            decode_datagram(b"key", &datagram),
+            Err(MulticastError::MissingSeq)
+        ));
+    }
+}
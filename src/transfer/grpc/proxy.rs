@@ -0,0 +1,305 @@
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// the max size of the CONNECT response head
+const MAX_RESPONSE_HEAD_SIZE: usize = 8 * 1024;
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_NO_AUTH: u8 = 0;
+const SOCKS5_USERNAME_PASSWORD: u8 = 2;
+const SOCKS5_CONNECT: u8 = 1;
+const SOCKS5_IPV4: u8 = 1;
+const SOCKS5_DOMAIN: u8 = 3;
+const SOCKS5_IPV6: u8 = 4;
+
+/// the proxy of the outbound connections, the target host is resolved by the proxy, so the
+/// relay or cloud peers are reachable when the local network only allows the proxy
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Proxy {
+    Socks5 {
+        /// the `host:port` of the proxy
+        addr: String,
+        auth: Option<ProxyAuth>,
+    },
+    /// the HTTP CONNECT tunnel
+    HttpConnect {
+        /// the `host:port` of the proxy
+        addr: String,
+        auth: Option<ProxyAuth>,
+    },
+}
+
+impl Proxy {
+    pub fn addr(&self) -> &str {
+        match self {
+            Proxy::Socks5 { addr, .. } | Proxy::HttpConnect { addr, .. } => addr,
+        }
+    }
+
+    /// open the tunnel to the target through the connected proxy stream
+    pub async fn handshake<S>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            Proxy::Socks5 { auth, .. } => socks5_handshake(stream, host, port, auth.as_ref()).await,
+            Proxy::HttpConnect { auth, .. } => {
+                http_connect_handshake(stream, host, port, auth.as_ref()).await
+            }
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// the password is never logged
+impl Debug for ProxyAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyAuth")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+async fn socks5_handshake<S>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    auth: Option<&ProxyAuth>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = match auth {
+        None => SOCKS5_NO_AUTH,
+        Some(_) => SOCKS5_USERNAME_PASSWORD,
+    };
+    stream.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(proxy_error(format!("invalid socks version {}", reply[0])));
+    }
+    // the proxy replies 0xff when it rejects the method
+    if reply[1] != method {
+        return Err(proxy_error("socks proxy rejects the auth method"));
+    }
+
+    if let Some(auth) = auth {
+        // RFC 1929
+        let mut req = vec![1, short_len(&auth.username)?];
+        req.extend_from_slice(auth.username.as_bytes());
+        req.push(short_len(&auth.password)?);
+        req.extend_from_slice(auth.password.as_bytes());
+        stream.write_all(&req).await?;
+
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(proxy_error("socks proxy rejects the username or password"));
+        }
+    }
+
+    let mut req = vec![SOCKS5_VERSION, SOCKS5_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(SOCKS5_IPV4);
+            req.extend_from_slice(&ip.octets());
+        }
+
+        Ok(IpAddr::V6(ip)) => {
+            req.push(SOCKS5_IPV6);
+            req.extend_from_slice(&ip.octets());
+        }
+
+        Err(_) => {
+            req.extend_from_slice(&[SOCKS5_DOMAIN, short_len(host)?]);
+            req.extend_from_slice(host.as_bytes());
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&req).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(proxy_error(format!(
+            "socks proxy connect {host}:{port} failed, reply {}",
+            reply[1]
+        )));
+    }
+
+    // skip the bound address and port
+    let addr_len = match reply[3] {
+        SOCKS5_IPV4 => 4,
+        SOCKS5_IPV6 => 16,
+        SOCKS5_DOMAIN => stream.read_u8().await? as usize,
+        atyp => return Err(proxy_error(format!("invalid socks address type {atyp}"))),
+    };
+    let mut bound = vec![0; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+async fn http_connect_handshake<S>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    auth: Option<&ProxyAuth>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{host}]:{port}"),
+        _ => format!("{host}:{port}"),
+    };
+
+    let mut req = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(auth) = auth {
+        let credentials = base64::encode(format!("{}:{}", auth.username, auth.password));
+        req.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // read byte by byte, the bytes after the head belong to the tunnel
+    let mut head = Vec::with_capacity(128);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD_SIZE {
+            return Err(proxy_error("http proxy response head is too large"));
+        }
+
+        head.push(stream.read_u8().await?);
+    }
+
+    let status_line = head
+        .split(|b| *b == b'\n')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1);
+    if status != Some("200") {
+        return Err(proxy_error(format!(
+            "http proxy connect {authority} failed: {}",
+            status_line.trim()
+        )));
+    }
+
+    Ok(())
+}
+
+/// the error doesn't contain the field, it may be the password
+fn short_len(s: &str) -> io::Result<u8> {
+    s.len().try_into().map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("socks field of {} bytes is too long", s.len()),
+        )
+    })
+}
+
+fn proxy_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::ConnectionRefused, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    async fn read_n(stream: &mut DuplexStream, n: usize) -> Vec<u8> {
+        let mut buf = vec![0; n];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        buf
+    }
+
+    #[tokio::test]
+    async fn socks5_with_auth() {
+        let (mut client, mut server) = duplex(1024);
+        let proxy = Proxy::Socks5 {
+            addr: "127.0.0.1:1080".to_string(),
+            auth: Some(ProxyAuth {
+                username: "user".to_string(),
+                password: "pass".to_string(),
+            }),
+        };
+
+        let server = tokio::spawn(async move {
+            assert_eq!(read_n(&mut server, 3).await, [5, 1, 2]);
+            server.write_all(&[5, 2]).await.unwrap();
+            assert_eq!(read_n(&mut server, 11).await, b"\x01\x04user\x04pass");
+            server.write_all(&[1, 0]).await.unwrap();
+
+            // the domain is resolved by the proxy
+            assert_eq!(
+                read_n(&mut server, 18).await,
+                b"\x05\x01\x00\x03\x0bexample.com\x00\x50"
+            );
+            server
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            server.write_all(b"tunnel").await.unwrap();
+
+            server
+        });
+
+        proxy
+            .handshake(&mut client, "example.com", 80)
+            .await
+            .unwrap();
+        assert_eq!(read_n(&mut client, 6).await, b"tunnel");
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let (mut client, mut server) = duplex(1024);
+        let proxy = Proxy::HttpConnect {
+            addr: "127.0.0.1:3128".to_string(),
+            auth: None,
+        };
+
+        let server = tokio::spawn(async move {
+            let req = b"CONNECT [::1]:80 HTTP/1.1\r\nHost: [::1]:80\r\n\r\n";
+            assert_eq!(read_n(&mut server, req.len()).await, req);
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\ntunnel")
+                .await
+                .unwrap();
+
+            let req = b"CONNECT 10.0.0.1:80 HTTP/1.1\r\nHost: 10.0.0.1:80\r\n\r\n";
+            assert_eq!(read_n(&mut server, req.len()).await, req);
+            server
+                .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                .await
+                .unwrap();
+
+            server
+        });
+
+        proxy.handshake(&mut client, "::1", 80).await.unwrap();
+        assert_eq!(read_n(&mut client, 6).await, b"tunnel");
+
+        let err = proxy
+            .handshake(&mut client, "10.0.0.1", 80)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,560 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use flume::Sender;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use tap::TapFallible;
+use tonic::body::BoxBody;
+use tonic::codec::CompressionEncoding;
+use tonic::codegen::{Body, StdError};
+use tonic::transport::server::Router;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+use tower::Service;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use super::acl::DeviceId;
+use super::config::GrpcConfig;
+use super::pb;
+use super::pb::relay_serve::Kind;
+use super::pb::relay_service_client::RelayServiceClient;
+use super::pb::relay_service_server::{RelayService, RelayServiceServer};
+use crate::transfer::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+
+/// the buffered blocks of a relayed download, a slow downloader slows down the serving peer
+const RELAY_BLOCK_BUFFER: usize = 16;
+
+type RelayedBlockSender = Sender<Result<pb::DownloadBlock, Status>>;
+
+/// the relay of the peers behind NAT, the serving peer keeps a stream to the relay, and the
+/// relay forwards the download requests and the blocks between the peers. The relay never reads
+/// the blocks, the downloader verifies them by the hash sums.
+///
+/// Both peers must be authenticated by the authentication layer of the relay, which inserts the
+/// [`DeviceId`] into the requests. A peer only registers itself, so another device can't take
+/// over its session, and the downloader is forwarded to the serving peer, which checks its acl
+/// as if the downloader connected directly. The blocks are only protected by the transport of
+/// each hop, the serving peer must trust the relay with the content of the served dirs
+#[derive(Debug, Clone, Default)]
+pub struct RelayServer {
+    sessions: Arc<Mutex<HashMap<Uuid, Arc<Session>>>>,
+    next_request_id: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+struct Session {
+    request_sender: Sender<pb::RelayRequest>,
+    pending: Mutex<HashMap<u64, RelayedBlockSender>>,
+}
+
+impl RelayServer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// the registered serving peers
+    pub fn peer_ids(&self) -> Vec<Uuid> {
+        self.sessions.lock().unwrap().keys().copied().collect()
+    }
+
+    /// build the service only, when user wants to add it into their own server
+    pub fn build_service(&self, config: &GrpcConfig) -> RelayServiceServer<RelayServer> {
+        let mut service =
+            RelayServiceServer::new(self.clone()).accept_compressed(CompressionEncoding::Gzip);
+        if let Some(compression) = config.compression {
+            service = service.send_compressed(compression.into());
+        }
+
+        service
+    }
+
+    /// build the server with the transport level config
+    pub fn build(&self, config: &GrpcConfig) -> Router {
+        let mut server = config.apply_server(Server::builder());
+
+        server.add_service(self.build_service(config))
+    }
+
+    async fn route_blocks(
+        &self,
+        peer_id: Uuid,
+        session: Arc<Session>,
+        mut incoming: Streaming<pb::RelayServe>,
+    ) {
+        loop {
+            let block = match incoming.message().await {
+                Err(err) => {
+                    warn!(%err, %peer_id, "receive relayed block failed");
+
+                    break;
+                }
+
+                Ok(None) => break,
+
+                Ok(Some(pb::RelayServe {
+                    kind: Some(Kind::Block(block)),
+                })) => block,
+
+                Ok(Some(_)) => {
+                    warn!(%peer_id, "serving peer registers again, ignore it");
+
+                    continue;
+                }
+            };
+
+            let block_sender = session
+                .pending
+                .lock()
+                .unwrap()
+                .get(&block.request_id)
+                .cloned();
+            let block_sender = match block_sender {
+                // the downloader is gone
+                None => continue,
+                Some(block_sender) => block_sender,
+            };
+
+            let result = match (block.block, block.error) {
+                (Some(block), _) => Ok(block),
+                (None, None) => {
+                    session.pending.lock().unwrap().remove(&block.request_id);
+
+                    continue;
+                }
+
+                (None, Some(err)) => {
+                    session.pending.lock().unwrap().remove(&block.request_id);
+
+                    Err(Status::unavailable(format!("serving peer failed: {err}")))
+                }
+            };
+
+            if block_sender.send_async(result).await.is_err() {
+                session.pending.lock().unwrap().remove(&block.request_id);
+            }
+        }
+
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            if sessions
+                .get(&peer_id)
+                .is_some_and(|registered| Arc::ptr_eq(registered, &session))
+            {
+                sessions.remove(&peer_id);
+            }
+        }
+
+        for (_, block_sender) in session.pending.lock().unwrap().drain() {
+            let _ = block_sender.try_send(Err(Status::unavailable(format!(
+                "serving peer {peer_id} is disconnected"
+            ))));
+        }
+
+        info!(%peer_id, "serving peer is disconnected");
+    }
+}
+
+#[async_trait]
+impl RelayService for RelayServer {
+    type ServeStream = Pin<Box<dyn Stream<Item = Result<pb::RelayRequest, Status>> + Send>>;
+    type DownloadStream = Pin<Box<dyn Stream<Item = Result<pb::DownloadBlock, Status>> + Send>>;
+
+    #[instrument(err, skip(self, request))]
+    async fn serve(
+        &self,
+        request: Request<Streaming<pb::RelayServe>>,
+    ) -> Result<Response<Self::ServeStream>, Status> {
+        let device_id = request.extensions().get::<DeviceId>().copied();
+        let mut incoming = request.into_inner();
+        let peer_id = match incoming.message().await? {
+            Some(pb::RelayServe {
+                kind: Some(Kind::RegisterPeerId(peer_id)),
+            }) => parse_peer_id(&peer_id)
+                .map_err(|_| Status::invalid_argument(format!("invalid peer id {peer_id}")))?,
+
+            _ => {
+                error!("the first message of the serving peer is not register");
+
+                return Err(Status::invalid_argument(
+                    "the first message should register the peer",
+                ));
+            }
+        };
+
+        match device_id {
+            None => {
+                warn!(%peer_id, "serving peer is not authenticated");
+
+                return Err(Status::unauthenticated("serving peer is not authenticated"));
+            }
+
+            Some(DeviceId(device_id)) if device_id != peer_id => {
+                warn!(%peer_id, %device_id, "device registers as another peer");
+
+                return Err(Status::permission_denied(format!(
+                    "device {device_id} can't register as peer {peer_id}"
+                )));
+            }
+
+            Some(_) => {}
+        }
+
+        let (request_sender, request_receiver) = flume::unbounded();
+        let session = Arc::new(Session {
+            request_sender,
+            pending: Default::default(),
+        });
+
+        // the new registration of the same device replaces the stale one, such as the peer
+        // reconnects
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(peer_id, session.clone());
+
+        info!(%peer_id, "register serving peer done");
+
+        let this = self.clone();
+        tokio::spawn(async move { this.route_blocks(peer_id, session, incoming).await });
+
+        Ok(Response::new(Box::pin(
+            request_receiver.into_stream().map(Ok),
+        )))
+    }
+
+    #[instrument(err, skip(self, request))]
+    async fn download(
+        &self,
+        request: Request<pb::RelayDownloadRequest>,
+    ) -> Result<Response<Self::DownloadStream>, Status> {
+        let device_id = match request.extensions().get::<DeviceId>() {
+            None => {
+                warn!("downloader is not authenticated");
+
+                return Err(Status::unauthenticated("downloader is not authenticated"));
+            }
+
+            Some(DeviceId(device_id)) => *device_id,
+        };
+        let request = request.into_inner();
+        let peer_id = parse_peer_id(&request.peer_id).map_err(|_| {
+            Status::invalid_argument(format!("invalid peer id {}", request.peer_id))
+        })?;
+
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&peer_id)
+            .cloned()
+            .ok_or_else(|| {
+                warn!(%peer_id, "serving peer is not registered");
+
+                Status::unavailable(format!("peer {peer_id} is not registered"))
+            })?;
+
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (block_sender, block_receiver) = flume::bounded(RELAY_BLOCK_BUFFER);
+        session
+            .pending
+            .lock()
+            .unwrap()
+            .insert(request_id, block_sender);
+
+        session
+            .request_sender
+            .send(pb::RelayRequest {
+                request_id,
+                requests: request.requests,
+                device_id: device_id.as_hyphenated().to_string(),
+            })
+            .map_err(|_| Status::unavailable(format!("peer {peer_id} is disconnected")))?;
+
+        info!(%peer_id, %device_id, request_id, "forward download request done");
+
+        Ok(Response::new(Box::pin(block_receiver.into_stream())))
+    }
+}
+
+/// download from the peer directly, when the direct download fails, such as both peers are
+/// behind NAT, download through the relay
+#[derive(Debug)]
+pub struct RelayDownloadTransfer<D, T> {
+    direct: D,
+    relay: RelayServiceClient<T>,
+    peer_id: Uuid,
+}
+
+impl<D, T, RespBody> RelayDownloadTransfer<D, T>
+where
+    T: Service<http::Request<BoxBody>, Response = http::Response<RespBody>> + Send + Sync,
+    T::Error: Into<StdError>,
+    T::Future: Send,
+    RespBody: Body<Data = Bytes> + Send + 'static,
+    RespBody::Error: Into<StdError> + Send,
+{
+    /// the relay channel connects to the relay which the peer registers to
+    pub fn new(direct: D, relay_channel: T, peer_id: Uuid) -> Self {
+        Self {
+            direct,
+            relay: RelayServiceClient::new(relay_channel)
+                .accept_compressed(CompressionEncoding::Gzip),
+            peer_id,
+        }
+    }
+}
+
+#[async_trait]
+impl<D, T, RespBody> DownloadTransfer for RelayDownloadTransfer<D, T>
+where
+    D: DownloadTransfer + Send + Sync,
+    Status: From<D::Error>,
+    T: Service<http::Request<BoxBody>, Response = http::Response<RespBody>> + Send + Sync,
+    T::Error: Into<StdError>,
+    T::Future: Send,
+    T: Clone,
+    RespBody: Body<Data = Bytes> + Send + 'static,
+    RespBody::Error: Into<StdError> + Send,
+{
+    type Error = Status;
+    type BlockStream<'a> = Pin<Box<dyn Stream<Item = Result<Option<DownloadBlock>, Self::Error>> + 'a>> where Self: 'a;
+
+    #[instrument(err, skip(self), fields(peer_id = %self.peer_id))]
+    async fn download<'a>(
+        &'a self,
+        block_offset: &'a [DownloadBlockRequest],
+    ) -> Result<Self::BlockStream<'a>, Self::Error> {
+        let err = match self.direct.download(block_offset).await {
+            Ok(stream) => return Ok(Box::pin(stream.map_err(Status::from))),
+            Err(err) => Status::from(err),
+        };
+
+        warn!(%err, "direct download failed, download through relay");
+
+        let requests = block_offset
+            .iter()
+            .map(|req| pb::DownloadBlockRequest {
+                dir_id: req.dir_id.as_hyphenated().to_string(),
+                filename: req.filename.clone(),
+                offset: req.offset,
+                len: req.len,
+                hash_sum: hex::encode(req.hash_sum),
+            })
+            .collect();
+
+        let resp = self
+            .relay
+            .clone()
+            .download(pb::RelayDownloadRequest {
+                peer_id: self.peer_id.as_hyphenated().to_string(),
+                requests,
+            })
+            .await
+            .tap_err(|err| error!(%err, "download through relay failed"))?;
+
+        Ok(Box::pin(resp.into_inner().map_ok(|block| {
+            block.inner.map(|block| DownloadBlock {
+                offset: block.offset,
+                data: block.data,
+            })
+        })))
+    }
+}
+
+fn parse_peer_id(peer_id: &str) -> Result<Uuid, uuid::Error> {
+    Uuid::parse_str(peer_id).tap_err(|err| error!(%err, peer_id, "parse peer id failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io;
+    use std::time::Duration;
+
+    use futures_util::stream;
+    use http::Uri;
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+    use tokio::fs;
+    use tokio::time;
+    use tonic::transport::{Channel, Endpoint};
+
+    use super::*;
+    use crate::transfer::grpc::acl::ShareAcl;
+    use crate::transfer::grpc::server::GrpcServerBuilder;
+    use crate::transfer::MockDownloadTransfer;
+
+    #[tokio::test]
+    async fn download_through_relay() {
+        let relay = RelayServer::new();
+
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let dir_id = Uuid::new_v4();
+        fs::write(dir.path().join("test.txt"), b"test")
+            .await
+            .unwrap();
+
+        // the serving peer is behind NAT, it registers to the relay, the relayed downloader is
+        // checked by its acl
+        let peer_id = Uuid::new_v4();
+        let downloader_id = Uuid::new_v4();
+        let acl = ShareAcl::new();
+        acl.grant(downloader_id, dir_id);
+        tokio::spawn(
+            GrpcServerBuilder::new()
+                .add_dir(dir_id, dir.path().to_path_buf())
+                .acl(acl.clone())
+                .serve_through_relay(connect_relay(&relay, peer_id).await, peer_id),
+        );
+        while relay.peer_ids().is_empty() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let direct = || {
+            let mut direct = MockDownloadTransfer::new();
+            direct.expect_download().times(1).returning(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "behind NAT",
+                ))
+            });
+
+            direct
+        };
+        let transfer = RelayDownloadTransfer::new(
+            direct(),
+            connect_relay(&relay, downloader_id).await,
+            peer_id,
+        );
+
+        let reqs = [
+            DownloadBlockRequest {
+                dir_id,
+                filename: "test.txt".to_string(),
+                offset: 0,
+                len: 4,
+                hash_sum: Sha256::digest(b"test").into(),
+            },
+            DownloadBlockRequest {
+                dir_id,
+                filename: "missing.txt".to_string(),
+                offset: 0,
+                len: 4,
+                hash_sum: Sha256::digest(b"test").into(),
+            },
+        ];
+        let blocks = transfer
+            .download(&reqs)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            blocks,
+            [
+                Some(DownloadBlock {
+                    offset: 0,
+                    data: Bytes::from_static(b"test"),
+                }),
+                None
+            ]
+        );
+
+        // the device which isn't granted the dir is refused by the serving peer
+        let transfer = RelayDownloadTransfer::new(
+            direct(),
+            connect_relay(&relay, Uuid::new_v4()).await,
+            peer_id,
+        );
+        let err = transfer
+            .download(&reqs)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        assert!(err.message().contains("not allowed"));
+    }
+
+    #[tokio::test]
+    async fn register_as_another_peer() {
+        let relay = RelayServer::new();
+        let peer_id = Uuid::new_v4();
+
+        let err = GrpcServerBuilder::new()
+            .serve_through_relay(connect_relay(&relay, Uuid::new_v4()).await, peer_id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+        assert!(relay.peer_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn peer_not_registered() {
+        let relay = RelayServer::new();
+        let mut request = Request::new(pb::RelayDownloadRequest {
+            peer_id: Uuid::new_v4().as_hyphenated().to_string(),
+            requests: vec![],
+        });
+        request.extensions_mut().insert(DeviceId(Uuid::new_v4()));
+
+        let err = relay.download(request).await.err().unwrap();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+
+        // the downloader must be authenticated
+        let err = relay
+            .download(Request::new(pb::RelayDownloadRequest {
+                peer_id: Uuid::new_v4().as_hyphenated().to_string(),
+                requests: vec![],
+            }))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    }
+
+    /// serve the relay to a connection, the connection is authenticated as the device
+    async fn connect_relay(relay: &RelayServer, device_id: Uuid) -> Channel {
+        let (client, server) = tokio::io::duplex(4096);
+        let router = Server::builder()
+            .layer(tonic::service::interceptor(
+                move |mut request: Request<()>| {
+                    request.extensions_mut().insert(DeviceId(device_id));
+
+                    Ok(request)
+                },
+            ))
+            .add_service(relay.build_service(&GrpcConfig::default()));
+        tokio::spawn(async move {
+            router
+                .serve_with_incoming(stream::iter(vec![Ok::<_, io::Error>(server)]))
+                .await
+        });
+
+        build_channel(client).await
+    }
+
+    async fn build_channel(client: tokio::io::DuplexStream) -> Channel {
+        let mut client = Some(client);
+
+        Endpoint::try_from("http://127.0.0.1:80")
+            .unwrap()
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let client = client.take();
+
+                async move {
+                    client
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "client already taken"))
+                }
+            }))
+            .await
+            .unwrap()
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::Uri;
+use mockall::automock;
+use tokio::sync::mpsc;
+use tokio::{net, select, time};
+use tokio_util::sync::CancellationToken;
+use tonic::transport::{Channel, Endpoint};
+use tower::discover::Change;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+use super::config::GrpcConfig;
+use super::dial::uri_host_port;
+use crate::transfer::address_book::{AddressBook, ConnectError};
+
+/// the default pause between two resolutions of the peer address
+pub const DEFAULT_RESOLVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// the pending address changes of the balanced channel
+const CHANGES_CAPACITY: usize = 16;
+
+/// resolve the host of a peer address to the socket addresses
+#[automock]
+#[async_trait]
+pub trait Resolver: Debug + Send + Sync {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// the system resolver, the lookup runs in the blocking pool of the runtime, so a slow DNS server
+/// never blocks the syncing
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(net::lookup_host((host, port)).await?.collect())
+    }
+}
+
+/// keep the channel of the peer following the address changes of its hostname, such as a home
+/// server behind the dynamic DNS, tonic only resolves the hostname when connecting. The address
+/// is picked by the address book, and its host is resolved again every interval, the connections
+/// to the new socket addresses are added to the channel, and the ones to the gone addresses are
+/// dropped, so the clones of the channel passed to `RumorSender::add_peer` and the block
+/// transfer reconnect by themselves. The connections are dialed by tonic, not the [`Dialer`]
+///
+/// [`Dialer`]: super::dial::Dialer
+#[derive(Debug)]
+pub struct PeerResolver {
+    peer_id: Uuid,
+    address_book: Arc<AddressBook>,
+    resolver: Arc<dyn Resolver>,
+    config: GrpcConfig,
+    interval: Duration,
+    changes: mpsc::Sender<Change<SocketAddr, Endpoint>>,
+    /// the picked peer address and its socket addresses
+    resolved: Option<(String, BTreeSet<SocketAddr>)>,
+}
+
+impl PeerResolver {
+    /// return the resolver and the channel of the peer, the channel has no connection until the
+    /// first resolution
+    pub fn new(peer_id: Uuid, address_book: Arc<AddressBook>) -> (Self, Channel) {
+        let (channel, changes) = Channel::balance_channel(CHANGES_CAPACITY);
+
+        let peer_resolver = Self {
+            peer_id,
+            address_book,
+            resolver: Arc::new(SystemResolver),
+            config: Default::default(),
+            interval: DEFAULT_RESOLVE_INTERVAL,
+            changes,
+            resolved: None,
+        };
+
+        (peer_resolver, channel)
+    }
+
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+
+        self
+    }
+
+    /// the config applies to the connections added after it
+    pub fn with_config(mut self, config: GrpcConfig) -> Self {
+        self.config = config;
+
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+
+        self
+    }
+
+    /// the socket addresses which the channel connects to
+    pub fn resolved_addrs(&self) -> Vec<SocketAddr> {
+        self.resolved
+            .as_ref()
+            .map(|(_, addrs)| addrs.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// resolve the peer addresses again until the token is cancelled, a failed resolution keeps
+    /// the current connections and is retried next interval
+    pub async fn run(&mut self, cancel: CancellationToken) {
+        loop {
+            select! {
+                _ = cancel.cancelled() => {
+                    info!(peer_id = %self.peer_id, "peer resolver is cancelled");
+
+                    return;
+                }
+
+                // the failure is logged by the resolution
+                _ = self.resolve() => {}
+            }
+
+            select! {
+                _ = cancel.cancelled() => {
+                    info!(peer_id = %self.peer_id, "peer resolver is cancelled");
+
+                    return;
+                }
+
+                _ = time::sleep(self.interval) => {}
+            }
+        }
+    }
+
+    /// resolve the candidate addresses of the peer in the address book order until one is
+    /// resolved, the results are recorded by the address book. When return true, the socket
+    /// addresses are changed and the channel is updated
+    #[instrument(err, skip(self), fields(peer_id = %self.peer_id))]
+    pub async fn resolve(&mut self) -> io::Result<bool> {
+        let resolver = self.resolver.clone();
+        let (address, (uri, addrs)) = self
+            .address_book
+            .connect(self.peer_id, |address| {
+                let resolver = resolver.clone();
+
+                async move {
+                    let uri = address
+                        .addr
+                        .parse::<Uri>()
+                        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+                    let (host, port) = uri_host_port(&uri)?;
+                    let addrs = resolver
+                        .resolve(host, port)
+                        .await?
+                        .into_iter()
+                        .collect::<BTreeSet<_>>();
+                    if addrs.is_empty() {
+                        return Err(io::Error::new(
+                            ErrorKind::NotFound,
+                            format!("host {host} has no address"),
+                        ));
+                    }
+
+                    Ok((uri, addrs))
+                }
+            })
+            .await
+            .map_err(|err| match err {
+                ConnectError::NoAddress(peer_id) => io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("peer {peer_id} has no address"),
+                ),
+                ConnectError::Connect(err) => err,
+            })?;
+
+        let (removed, added) = match &self.resolved {
+            // the hostname of the same address may be resolved to the same socket addresses
+            Some((resolved_address, resolved)) if *resolved_address == address.addr => (
+                resolved.difference(&addrs).copied().collect::<Vec<_>>(),
+                addrs.difference(resolved).copied().collect::<Vec<_>>(),
+            ),
+
+            resolved => (
+                resolved
+                    .iter()
+                    .flat_map(|(_, resolved)| resolved.iter().copied())
+                    .collect(),
+                addrs.iter().copied().collect(),
+            ),
+        };
+
+        if removed.is_empty() && added.is_empty() {
+            info!(addr = %address.addr, "peer address is not changed");
+
+            return Ok(false);
+        }
+
+        for addr in &removed {
+            self.send_change(Change::Remove(*addr)).await?;
+        }
+        for addr in &added {
+            let endpoint = self.endpoint(&uri, *addr)?;
+            self.send_change(Change::Insert(*addr, endpoint)).await?;
+        }
+
+        info!(addr = %address.addr, ?added, ?removed, "resolve peer address done");
+
+        self.resolved = Some((address.addr, addrs));
+
+        Ok(true)
+    }
+
+    /// the endpoint connects to the socket address, the requests still carry the hostname
+    fn endpoint(&self, uri: &Uri, addr: SocketAddr) -> io::Result<Endpoint> {
+        let scheme = uri.scheme_str().unwrap_or("http");
+        let endpoint = Endpoint::from_shared(format!("{scheme}://{addr}"))
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?
+            .origin(uri.clone());
+
+        Ok(self.config.apply_endpoint(endpoint))
+    }
+
+    async fn send_change(&self, change: Change<SocketAddr, Endpoint>) -> io::Result<()> {
+        self.changes.send(change).await.map_err(|_| {
+            error!(peer_id = %self.peer_id, "peer channel is dropped");
+
+            io::Error::new(ErrorKind::BrokenPipe, "peer channel is dropped")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::index::address::{AddressKind, NoopAddressStore};
+
+    #[tokio::test]
+    async fn follow_address_changes() {
+        let peer_id = Uuid::new_v4();
+        let address_book = Arc::new(AddressBook::load(Arc::new(NoopAddressStore)).await.unwrap());
+        address_book
+            .add_address(peer_id, "http://home.example:8080", AddressKind::Wan)
+            .await
+            .unwrap();
+
+        // the dynamic DNS record changes, then the DNS server is down
+        let answers = Arc::new(Mutex::new(vec![
+            Err(io::Error::new(ErrorKind::TimedOut, "dns timeout")),
+            Ok(vec!["127.0.0.2:8080".parse().unwrap()]),
+            Ok(vec!["127.0.0.1:8080".parse().unwrap()]),
+            Ok(vec!["127.0.0.1:8080".parse().unwrap()]),
+        ]));
+        let mut resolver = MockResolver::new();
+        resolver
+            .expect_resolve()
+            .withf(|host, port| host == "home.example" && *port == 8080)
+            .returning(move |_, _| answers.lock().unwrap().pop().unwrap());
+
+        let (peer_resolver, _channel) = PeerResolver::new(peer_id, address_book.clone());
+        let mut peer_resolver = peer_resolver.with_resolver(Arc::new(resolver));
+
+        assert!(peer_resolver.resolve().await.unwrap());
+        assert_eq!(
+            peer_resolver.resolved_addrs(),
+            ["127.0.0.1:8080".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(!peer_resolver.resolve().await.unwrap());
+
+        assert!(peer_resolver.resolve().await.unwrap());
+        assert_eq!(
+            peer_resolver.resolved_addrs(),
+            ["127.0.0.2:8080".parse::<SocketAddr>().unwrap()]
+        );
+
+        // the current connections are kept, the failure is recorded by the address book
+        let err = peer_resolver.resolve().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(
+            peer_resolver.resolved_addrs(),
+            ["127.0.0.2:8080".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(address_book.candidates(peer_id)[0].failures, 1);
+    }
+}
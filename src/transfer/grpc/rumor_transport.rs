@@ -0,0 +1,559 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use flume::Sender;
+use futures_util::future::join_all;
+use futures_util::{Stream, StreamExt};
+use tap::TapFallible;
+use tonic::codec::CompressionEncoding;
+use tonic::transport::Channel;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use super::acl::{DeviceId, ShareAcl};
+use super::config::GrpcConfig;
+use super::convert::ConvertError;
+#[cfg(feature = "multicast")]
+use super::multicast::MulticastRumorChannel;
+use super::pb;
+use super::pb::rumor_transfer_service_client::RumorTransferServiceClient;
+use super::pb::rumor_transfer_service_server::{RumorTransferService, RumorTransferServiceServer};
+use crate::index::IndexFile;
+use crate::sync_control::event::{DirRename, Event};
+use crate::sync_control::SendRumors;
+
+/// deliver the rumors produced by the sync controllers to the peers, the peers are selected by
+/// the `except`, `to` and `fanout` of the rumors
+#[derive(Debug)]
+pub struct RumorSender {
+    user_id: Uuid,
+    peers: HashMap<Uuid, RumorTransferServiceClient<Channel>>,
+    config: GrpcConfig,
+    seq: u64,
+    #[cfg(feature = "multicast")]
+    multicast: Option<MulticastRumorChannel>,
+}
+
+impl RumorSender {
+    pub fn new(user_id: Uuid) -> Self {
+        Self {
+            user_id,
+            peers: Default::default(),
+            config: Default::default(),
+            // start from the current time, so the seq keeps increasing after restarting, the
+            // peers won't drop the new rumors as replayed
+            seq: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as _,
+            #[cfg(feature = "multicast")]
+            multicast: None,
+        }
+    }
+
+    /// the config only applies to the peers added after it
+    pub fn config(mut self, config: GrpcConfig) -> Self {
+        self.config = config;
+
+        self
+    }
+
+    /// the channel connects to the rumor receiver of the peer
+    pub fn add_peer(mut self, peer_id: Uuid, channel: Channel) -> Self {
+        let mut client =
+            RumorTransferServiceClient::new(channel).accept_compressed(CompressionEncoding::Gzip);
+        if let Some(compression) = self.config.compression {
+            client = client.send_compressed(compression.into());
+        }
+
+        self.peers.insert(peer_id, client);
+
+        self
+    }
+
+    /// also send the small rumors to the multicast group before the reliable path, both copies
+    /// have the same seq, so the peers drop the later one by the replay check
+    #[cfg(feature = "multicast")]
+    pub fn multicast(mut self, channel: MulticastRumorChannel) -> Self {
+        self.multicast = Some(channel);
+
+        self
+    }
+
+    /// send the rumors to the selected peers, return the peers which received the rumors, a
+    /// failed peer is logged and skipped, the anti entropy will cover it later
+    #[instrument(skip(self, send_rumors), fields(dir_id = %send_rumors.dir_id))]
+    pub async fn send(&mut self, send_rumors: &SendRumors) -> Vec<Uuid> {
+        let peer_ids = self.peers.keys().copied().collect::<Vec<_>>();
+        let selected = send_rumors.select_peers(&peer_ids, &mut rand::thread_rng());
+
+        self.seq += 1;
+
+        // the dir renames are only delivered by the reliable path, and the multicast can't select
+        // the peers, otherwise the later reliable copy is dropped as replayed
+        #[cfg(feature = "multicast")]
+        if let Some(multicast) = &self.multicast {
+            if send_rumors.dir_renames.is_empty() && send_rumors.to.is_none() {
+                // best effort, the error is logged and the reliable path still delivers them
+                let _ = multicast
+                    .send(send_rumors.dir_id, self.seq, &send_rumors.rumors)
+                    .await;
+            }
+        }
+
+        let rumors = pb::Rumors {
+            dir_id: send_rumors.dir_id.as_hyphenated().to_string(),
+            sender_id: self.user_id.as_hyphenated().to_string(),
+            rumors: send_rumors.rumors.iter().map(Into::into).collect(),
+            seq: self.seq,
+            dir_renames: send_rumors.dir_renames.iter().map(Into::into).collect(),
+        };
+
+        let results = join_all(selected.into_iter().map(|peer_id| {
+            let mut client = self.peers[&peer_id].clone();
+            let rumors = rumors.clone();
+
+            async move {
+                client
+                    .send_rumors(rumors)
+                    .await
+                    .tap_err(|err| warn!(%err, %peer_id, "send rumors to peer failed"))
+                    .ok()
+                    .map(|_| peer_id)
+            }
+        }))
+        .await;
+
+        let delivered = results.into_iter().flatten().collect::<Vec<_>>();
+
+        info!(seq = self.seq, peers = delivered.len(), "send rumors done");
+
+        delivered
+    }
+
+    /// consume the rumors until the stream ends, usually the stream is the receiver of the rumor
+    /// sink of the sync controllers
+    pub async fn run<S: Stream<Item = SendRumors> + Unpin>(mut self, mut rumors: S) {
+        while let Some(send_rumors) = rumors.next().await {
+            self.send(&send_rumors).await;
+        }
+
+        info!("rumors stream is end, stop sending rumors");
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RumorReceiverBuilder {
+    dirs: HashMap<Uuid, Sender<Event>>,
+    config: GrpcConfig,
+    acl: Option<ShareAcl>,
+}
+
+impl RumorReceiverBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// the received rumors of the dir are sent to the event sender as [`Event::Rumors`], its
+    /// receiver should be merged into the event stream of the dir sync controller
+    pub fn add_dir(mut self, dir_id: Uuid, event_sender: Sender<Event>) -> Self {
+        self.dirs.insert(dir_id, event_sender);
+
+        self
+    }
+
+    pub fn config(mut self, config: GrpcConfig) -> Self {
+        self.config = config;
+
+        self
+    }
+
+    /// only accept the rumors of the authenticated devices which are allowed to join the dir, and
+    /// the device must be the sender of the rumors
+    pub fn acl(mut self, acl: ShareAcl) -> Self {
+        self.acl = Some(acl);
+
+        self
+    }
+
+    pub fn build_service(self) -> RumorTransferServiceServer<RumorReceiver> {
+        let mut service = RumorTransferServiceServer::new(RumorReceiver {
+            dirs: Arc::new(self.dirs),
+            acl: self.acl,
+        })
+        .accept_compressed(CompressionEncoding::Gzip);
+        if let Some(compression) = self.config.compression {
+            service = service.send_compressed(compression.into());
+        }
+
+        service
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RumorReceiver {
+    dirs: Arc<HashMap<Uuid, Sender<Event>>>,
+    acl: Option<ShareAcl>,
+}
+
+#[async_trait]
+impl RumorTransferService for RumorReceiver {
+    #[instrument(err, skip(self, request))]
+    async fn send_rumors(
+        &self,
+        request: Request<pb::Rumors>,
+    ) -> Result<Response<pb::SendRumorsResponse>, Status> {
+        let device_id = request.extensions().get::<DeviceId>().copied();
+        let rumors = request.into_inner();
+        let seq = rumors.seq;
+        // 0 is the protobuf default, the seq of the sent rumors starts from the current time
+        if seq == 0 {
+            warn!("rumors seq is missing");
+
+            return Err(Status::invalid_argument("rumors seq is missing"));
+        }
+
+        let (dir_id, sender_id, remote_index, dir_renames) =
+            decode_rumors(rumors).map_err(|err| {
+                error!(%err, "decode rumors failed");
+
+                Status::invalid_argument(err.to_string())
+            })?;
+
+        if let Some(acl) = &self.acl {
+            acl.check(device_id.as_ref(), dir_id)?;
+
+            if device_id != Some(DeviceId(sender_id)) {
+                warn!(%sender_id, ?device_id, "rumors sender is not the authenticated device");
+
+                return Err(Status::permission_denied(format!(
+                    "sender {sender_id} is not the authenticated device"
+                )));
+            }
+        }
+
+        let event = Event::Rumors {
+            sender_id,
+            seq: Some(seq),
+            remote_index,
+            dir_renames,
+        };
+
+        let event_sender = self.dirs.get(&dir_id).ok_or_else(|| {
+            error!(%dir_id, "dir not found");
+
+            Status::not_found(format!("dir {dir_id} not found"))
+        })?;
+
+        event_sender.send_async(event).await.map_err(|_| {
+            error!(%dir_id, "dir event receiver is closed");
+
+            Status::unavailable(format!("dir {dir_id} is not syncing"))
+        })?;
+
+        info!(%dir_id, "receive rumors done");
+
+        Ok(Response::new(pb::SendRumorsResponse {}))
+    }
+}
+
+/// return the dir id, the sender id, the rumors and the dir renames
+fn decode_rumors(
+    rumors: pb::Rumors,
+) -> Result<(Uuid, Uuid, Vec<IndexFile>, Vec<DirRename>), ConvertError> {
+    let dir_id = Uuid::parse_str(&rumors.dir_id)?;
+    let sender_id = Uuid::parse_str(&rumors.sender_id)?;
+    let remote_index = rumors
+        .rumors
+        .into_iter()
+        .map(TryInto::try_into)
+        .collect::<Result<_, _>>()?;
+    let dir_renames = rumors.dir_renames.into_iter().map(Into::into).collect();
+
+    Ok((dir_id, sender_id, remote_index, dir_renames))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::time::Duration;
+
+    use futures_util::stream;
+    use http::Uri;
+    use tonic::transport::{Endpoint, Server};
+
+    use super::*;
+    use crate::index::{FileDetail, FileKind};
+    use crate::sync_control::options::Fanout;
+
+    #[tokio::test]
+    async fn send_and_receive() {
+        let user_id = Uuid::new_v4();
+        let dir_id = Uuid::new_v4();
+        let peer_a = Uuid::new_v4();
+        let peer_b = Uuid::new_v4();
+
+        let (event_sender_a, event_receiver_a) = flume::unbounded();
+        let (event_sender_b, event_receiver_b) = flume::unbounded();
+        let mut rumor_sender = RumorSender::new(user_id)
+            .add_peer(
+                peer_a,
+                serve(RumorReceiverBuilder::new().add_dir(dir_id, event_sender_a)).await,
+            )
+            .add_peer(
+                peer_b,
+                serve(RumorReceiverBuilder::new().add_dir(dir_id, event_sender_b)).await,
+            );
+
+        let rumors = vec![IndexFile {
+            filename: OsString::from("test.txt"),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum: [1; 32],
+                block_chain: None,
+                deleted: true,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH + Duration::from_secs(100),
+            update_by: user_id.as_hyphenated().to_string(),
+        }];
+
+        let dir_renames = vec![DirRename {
+            old_dir: OsString::from("old"),
+            new_dir: OsString::from("new"),
+        }];
+        let delivered = rumor_sender
+            .send(&SendRumors {
+                dir_id,
+                rumors: rumors.clone(),
+                dir_renames: dir_renames.clone(),
+                except: Some(peer_a),
+                to: None,
+                fanout: Fanout::All,
+            })
+            .await;
+        assert_eq!(delivered, [peer_b]);
+        assert!(event_receiver_a.is_empty());
+
+        let Event::Rumors {
+            sender_id,
+            seq,
+            remote_index,
+            dir_renames: received_dir_renames,
+        } = event_receiver_b.recv_async().await.unwrap()
+        else {
+            panic!("not rumors event");
+        };
+        assert_eq!(sender_id, user_id);
+        assert_eq!(seq, Some(rumor_sender.seq));
+        assert_eq!(remote_index, rumors);
+        assert_eq!(received_dir_renames, dir_renames);
+
+        // the unknown dir is rejected
+        let delivered = rumor_sender
+            .send(&SendRumors {
+                dir_id: Uuid::new_v4(),
+                rumors,
+                dir_renames: vec![],
+                except: None,
+                to: Some(peer_a),
+                fanout: Fanout::All,
+            })
+            .await;
+        assert!(delivered.is_empty());
+    }
+
+    #[tokio::test]
+    async fn acl() {
+        let device_id = Uuid::new_v4();
+        let dir_id = Uuid::new_v4();
+        let acl = ShareAcl::new();
+        let (event_sender, event_receiver) = flume::unbounded();
+        let receiver = RumorReceiver {
+            dirs: Arc::new(HashMap::from([(dir_id, event_sender)])),
+            acl: Some(acl.clone()),
+        };
+
+        let request = |sender_id: Uuid, device_id: Option<Uuid>| {
+            let mut request = Request::new(pb::Rumors {
+                dir_id: dir_id.as_hyphenated().to_string(),
+                sender_id: sender_id.as_hyphenated().to_string(),
+                rumors: vec![],
+                seq: 1,
+                dir_renames: vec![],
+            });
+            if let Some(device_id) = device_id {
+                request.extensions_mut().insert(DeviceId(device_id));
+            }
+
+            request
+        };
+
+        let err = receiver
+            .send_rumors(request(device_id, None))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+
+        let err = receiver
+            .send_rumors(request(device_id, Some(device_id)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+
+        acl.grant(device_id, dir_id);
+
+        // the device can't send the rumors as another peer
+        let err = receiver
+            .send_rumors(request(Uuid::new_v4(), Some(device_id)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+
+        receiver
+            .send_rumors(request(device_id, Some(device_id)))
+            .await
+            .unwrap();
+        assert_eq!(event_receiver.len(), 1);
+
+        acl.revoke(device_id, dir_id);
+        let err = receiver
+            .send_rumors(request(device_id, Some(device_id)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn reject_missing_seq() {
+        let dir_id = Uuid::new_v4();
+        let (event_sender, event_receiver) = flume::unbounded();
+        let receiver = RumorReceiver {
+            dirs: Arc::new(HashMap::from([(dir_id, event_sender)])),
+            acl: None,
+        };
+
+        // the seq 0 can't skip the replay check as the pulled rumors
+        let err = receiver
+            .send_rumors(Request::new(pb::Rumors {
+                dir_id: dir_id.as_hyphenated().to_string(),
+                sender_id: Uuid::new_v4().as_hyphenated().to_string(),
+                rumors: vec![],
+                seq: 0,
+                dir_renames: vec![],
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(event_receiver.is_empty());
+    }
+
+    #[cfg(feature = "multicast")]
+    #[tokio::test]
+    async fn multicast_and_reliable_share_seq() {
+        use std::net::SocketAddr;
+
+        use tokio::net::UdpSocket;
+
+        let user_id = Uuid::new_v4();
+        let dir_id = Uuid::new_v4();
+        let peer_id = Uuid::new_v4();
+
+        let receive_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(receive_addr) = receive_socket.local_addr().unwrap() else {
+            panic!("not ipv4 addr");
+        };
+        let multicast_receiver = MulticastRumorChannel::from_socket(
+            receive_socket,
+            receive_addr,
+            b"key".to_vec(),
+            peer_id,
+        );
+        let multicast_sender = MulticastRumorChannel::from_socket(
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            receive_addr,
+            b"key".to_vec(),
+            user_id,
+        );
+
+        let (event_sender, event_receiver) = flume::unbounded();
+        let mut rumor_sender = RumorSender::new(user_id)
+            .add_peer(
+                peer_id,
+                serve(RumorReceiverBuilder::new().add_dir(dir_id, event_sender)).await,
+            )
+            .multicast(multicast_sender);
+
+        let send_rumors = |dir_renames: Vec<DirRename>| SendRumors {
+            dir_id,
+            rumors: vec![],
+            dir_renames,
+            except: None,
+            to: None,
+            fanout: Fanout::All,
+        };
+        let seq_of = |event: Event| match event {
+            Event::Rumors { seq, .. } => seq,
+            _ => panic!("not rumors event"),
+        };
+
+        // the multicast copy arrives first, the reliable one has the same seq
+        for _ in 0..2 {
+            rumor_sender.send(&send_rumors(vec![])).await;
+
+            let multicast_seq = seq_of(multicast_receiver.recv().await.unwrap().into_event());
+            let reliable_seq = seq_of(event_receiver.recv_async().await.unwrap());
+            assert_eq!(multicast_seq, Some(rumor_sender.seq));
+            assert_eq!(reliable_seq, multicast_seq);
+        }
+
+        // the dir renames are only sent by the reliable path
+        rumor_sender
+            .send(&send_rumors(vec![DirRename {
+                old_dir: OsString::from("old"),
+                new_dir: OsString::from("new"),
+            }]))
+            .await;
+        let rename_seq = seq_of(event_receiver.recv_async().await.unwrap());
+        assert_eq!(rename_seq, Some(rumor_sender.seq));
+
+        rumor_sender.send(&send_rumors(vec![])).await;
+        let multicast_seq = seq_of(multicast_receiver.recv().await.unwrap().into_event());
+        let reliable_seq = seq_of(event_receiver.recv_async().await.unwrap());
+        assert_eq!(multicast_seq, Some(rumor_sender.seq));
+        assert_eq!(reliable_seq, multicast_seq);
+    }
+
+    async fn serve(builder: RumorReceiverBuilder) -> Channel {
+        let (client, server) = tokio::io::duplex(4096);
+
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(builder.build_service())
+                .serve_with_incoming(stream::iter(vec![Ok::<_, std::io::Error>(server)]))
+                .await
+        });
+
+        let mut client = Some(client);
+        Endpoint::try_from("http://127.0.0.1:80")
+            .unwrap()
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let client = client.take();
+
+                async move {
+                    client.ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::Other, "Client already taken")
+                    })
+                }
+            }))
+            .await
+            .unwrap()
+    }
+}
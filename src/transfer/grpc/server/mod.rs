@@ -0,0 +1,521 @@
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use flume::Sender;
+use futures_util::Stream;
+use sha2::{Digest, Sha256};
+use tap::TapFallible;
+use tonic::body::BoxBody;
+use tonic::codec::CompressionEncoding;
+use tonic::codegen::{Body, StdError};
+use tonic::transport::server::Router;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+use tower::Service;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use super::acl::{DeviceId, ShareAcl};
+use super::config::GrpcConfig;
+use super::pb;
+use super::pb::download_transfer_service_server::{
+    DownloadTransferService, DownloadTransferServiceServer,
+};
+use super::pb::relay_serve::Kind;
+use super::pb::relay_service_client::RelayServiceClient;
+use crate::ext::{log_path, open_read, AsyncFileExt};
+use crate::transfer::rate_limit::RateLimiter;
+
+/// the buffered blocks sent to the relay
+const RELAY_SERVE_BUFFER: usize = 16;
+
+#[derive(Debug, Default)]
+pub struct GrpcServerBuilder {
+    dirs: HashMap<Uuid, PathBuf>,
+    no_atime_dirs: HashSet<Uuid>,
+    config: GrpcConfig,
+    acl: Option<ShareAcl>,
+    upload_limiter: Option<RateLimiter>,
+}
+
+impl GrpcServerBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// serve the blocks of the sync dir
+    pub fn add_dir(mut self, dir_id: Uuid, sync_dir: PathBuf) -> Self {
+        self.dirs.insert(dir_id, sync_dir);
+
+        self
+    }
+
+    /// don't update the access time of the served files of the dir, the files which the process
+    /// doesn't own are still read with the access time updated
+    pub fn no_atime(mut self, dir_id: Uuid) -> Self {
+        self.no_atime_dirs.insert(dir_id);
+
+        self
+    }
+
+    pub fn config(mut self, config: GrpcConfig) -> Self {
+        self.config = config;
+
+        self
+    }
+
+    /// only serve the blocks to the authenticated devices which are allowed to join the dir
+    pub fn acl(mut self, acl: ShareAcl) -> Self {
+        self.acl = Some(acl);
+
+        self
+    }
+
+    /// limit the rate of the served blocks, the limiter may be shared with the other upload paths
+    pub fn upload_limiter(mut self, upload_limiter: RateLimiter) -> Self {
+        self.upload_limiter = Some(upload_limiter);
+
+        self
+    }
+
+    /// build the service only, when user wants to add it into their own server
+    pub fn build_service(self) -> DownloadTransferServiceServer<GrpcServer> {
+        let compression = self.config.compression;
+        let mut service = DownloadTransferServiceServer::new(self.build_server())
+            .accept_compressed(CompressionEncoding::Gzip);
+        if let Some(compression) = compression {
+            service = service.send_compressed(compression.into());
+        }
+
+        service
+    }
+
+    /// serve the blocks through the relay, such as the device is behind NAT. The relay
+    /// authenticates the downloaders, their acl is checked as if they connected directly, so
+    /// only the trusted relay should be used. It returns when the relay closes the stream, the
+    /// caller should register again
+    #[instrument(err, skip(self, relay_channel))]
+    pub async fn serve_through_relay<T, RespBody>(
+        self,
+        relay_channel: T,
+        peer_id: Uuid,
+    ) -> Result<(), Status>
+    where
+        T: Service<http::Request<BoxBody>, Response = http::Response<RespBody>> + Send + Sync,
+        T::Error: Into<StdError>,
+        T::Future: Send,
+        RespBody: Body<Data = Bytes> + Send + 'static,
+        RespBody::Error: Into<StdError> + Send,
+    {
+        let mut client =
+            RelayServiceClient::new(relay_channel).accept_compressed(CompressionEncoding::Gzip);
+        if let Some(compression) = self.config.compression {
+            client = client.send_compressed(compression.into());
+        }
+        let server = self.build_server();
+
+        let (serve_sender, serve_receiver) = flume::bounded(RELAY_SERVE_BUFFER);
+        let _ = serve_sender.try_send(pb::RelayServe {
+            kind: Some(Kind::RegisterPeerId(peer_id.as_hyphenated().to_string())),
+        });
+
+        let outbound = async_stream::stream! {
+            while let Ok(serve) = serve_receiver.recv_async().await {
+                yield serve
+            }
+        };
+        let mut requests = client
+            .serve(outbound)
+            .await
+            .tap_err(|err| error!(%err, "register to relay failed"))?
+            .into_inner();
+
+        info!("register to relay done");
+
+        while let Some(request) = requests.message().await? {
+            let server = server.clone();
+            let serve_sender = serve_sender.clone();
+
+            tokio::spawn(async move { server.serve_relay_request(request, serve_sender).await });
+        }
+
+        info!("relay closes the stream");
+
+        Ok(())
+    }
+
+    fn build_server(self) -> GrpcServer {
+        GrpcServer {
+            dirs: Arc::new(self.dirs),
+            no_atime_dirs: Arc::new(self.no_atime_dirs),
+            max_message_size: self.config.max_message_size,
+            acl: self.acl,
+            upload_limiter: self.upload_limiter,
+        }
+    }
+
+    /// build the server with the transport level config
+    pub fn build(self) -> Router {
+        let mut server = self.config.apply_server(Server::builder());
+
+        server.add_service(self.build_service())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GrpcServer {
+    dirs: Arc<HashMap<Uuid, PathBuf>>,
+    no_atime_dirs: Arc<HashSet<Uuid>>,
+    max_message_size: usize,
+    acl: Option<ShareAcl>,
+    upload_limiter: Option<RateLimiter>,
+}
+
+#[async_trait]
+impl DownloadTransferService for GrpcServer {
+    type DownloadStream = Pin<Box<dyn Stream<Item = Result<pb::DownloadBlock, Status>> + Send>>;
+
+    #[instrument(err, skip(self, request))]
+    async fn download(
+        &self,
+        request: Request<Streaming<pb::DownloadBlockRequest>>,
+    ) -> Result<Response<Self::DownloadStream>, Status> {
+        let device_id = request.extensions().get::<DeviceId>().copied();
+        let mut reqs = request.into_inner();
+        let this = self.clone();
+
+        let stream = async_stream::try_stream! {
+            while let Some(req) = reqs.message().await? {
+                let inner = this.read_block(device_id, &req).await?;
+
+                info!(filename = %log_path(&req.filename), offset = req.offset, found = inner.is_some(), "read block done");
+
+                if let (Some(upload_limiter), Some(inner)) = (&this.upload_limiter, &inner) {
+                    upload_limiter.acquire(inner.data.len() as _).await;
+                }
+
+                yield pb::DownloadBlock { inner }
+            }
+        };
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+impl GrpcServer {
+    /// send the blocks of the relayed request, and end the request with the error if any
+    async fn serve_relay_request(
+        &self,
+        request: pb::RelayRequest,
+        serve_sender: Sender<pb::RelayServe>,
+    ) {
+        let request_id = request.request_id;
+        let device_id = Uuid::parse_str(&request.device_id)
+            .tap_err(|err| error!(%err, device_id = %request.device_id, "parse relayed device id failed"))
+            .ok()
+            .map(DeviceId);
+        let mut error = None;
+        for req in &request.requests {
+            let inner = match self.read_block(device_id, req).await {
+                Err(err) => {
+                    error = Some(err.message().to_string());
+
+                    break;
+                }
+
+                Ok(inner) => inner,
+            };
+
+            if let (Some(upload_limiter), Some(inner)) = (&self.upload_limiter, &inner) {
+                upload_limiter.acquire(inner.data.len() as _).await;
+            }
+
+            let block = pb::RelayBlock {
+                request_id,
+                block: Some(pb::DownloadBlock { inner }),
+                error: None,
+            };
+            if serve_sender
+                .send_async(pb::RelayServe {
+                    kind: Some(Kind::Block(block)),
+                })
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        info!(
+            request_id,
+            blocks = request.requests.len(),
+            failed = error.is_some(),
+            "serve relayed request done"
+        );
+
+        let end = pb::RelayBlock {
+            request_id,
+            block: None,
+            error,
+        };
+        let _ = serve_sender
+            .send_async(pb::RelayServe {
+                kind: Some(Kind::Block(end)),
+            })
+            .await;
+    }
+
+    /// when return None, means the block is not found or the block hash is changed
+    async fn read_block(
+        &self,
+        device_id: Option<DeviceId>,
+        req: &pb::DownloadBlockRequest,
+    ) -> Result<Option<pb::DownloadBlockInner>, Status> {
+        if req.len > self.max_message_size as u64 {
+            error!(
+                len = req.len,
+                max_message_size = self.max_message_size,
+                "block request len is larger than max message size"
+            );
+
+            return Err(Status::invalid_argument(format!(
+                "block request len {} is larger than max message size {}",
+                req.len, self.max_message_size
+            )));
+        }
+
+        let dir_id = Uuid::parse_str(&req.dir_id).map_err(|err| {
+            error!(%err, dir_id = %req.dir_id, "parse dir id failed");
+
+            Status::invalid_argument(format!("invalid dir id {}", req.dir_id))
+        })?;
+
+        if let Some(acl) = &self.acl {
+            acl.check(device_id.as_ref(), dir_id)?;
+        }
+
+        let sync_dir = self.dirs.get(&dir_id).ok_or_else(|| {
+            error!(%dir_id, "dir not found");
+
+            Status::not_found(format!("dir {dir_id} not found"))
+        })?;
+
+        let filename = Path::new(&req.filename);
+        if !filename
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            error!(filename = %log_path(&req.filename), "filename is not a normal relative path");
+
+            return Err(Status::invalid_argument(format!(
+                "invalid filename {}",
+                req.filename
+            )));
+        }
+
+        let path = sync_dir.join(filename);
+        let no_atime = self.no_atime_dirs.contains(&dir_id);
+        let file = match open_read(&path, no_atime).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                warn!(path = ?log_path(&path), "file not found");
+
+                return Ok(None);
+            }
+
+            Err(err) => {
+                error!(%err, path = ?log_path(&path), "open file failed");
+
+                return Err(Status::internal(err.to_string()));
+            }
+
+            Ok(file) => file,
+        };
+
+        let mut buf = BytesMut::zeroed(req.len as _);
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = file
+                .read_at(&mut buf[filled..], req.offset + filled as u64)
+                .await
+                .map_err(|err| {
+                    error!(%err, path = ?log_path(&path), offset = req.offset, "read block failed");
+
+                    Status::internal(err.to_string())
+                })? as usize;
+            if n == 0 {
+                warn!(
+                    path = ?log_path(&path),
+                    offset = req.offset,
+                    len = req.len,
+                    "file is shorter than block"
+                );
+
+                return Ok(None);
+            }
+
+            filled += n;
+        }
+
+        let hash_sum = hex::encode(Sha256::digest(&buf));
+        if hash_sum != req.hash_sum {
+            warn!(
+                path = ?log_path(&path),
+                offset = req.offset,
+                "block hash sum mismatch, maybe file is changed"
+            );
+
+            return Ok(None);
+        }
+
+        Ok(Some(pb::DownloadBlockInner {
+            offset: req.offset,
+            data: buf.freeze(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+    use futures_util::{stream, TryStreamExt};
+    use http::Uri;
+    use tempfile::TempDir;
+    use tokio::fs;
+    use tokio::io::DuplexStream;
+    use tonic::transport::{Channel, Endpoint};
+
+    use super::*;
+    use crate::ext::hash_file;
+    use crate::transfer::grpc::client::GrpcClient;
+    use crate::transfer::grpc::config::Compression;
+    use crate::transfer::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+
+    #[tokio::test]
+    async fn download_block() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let dir_id = Uuid::new_v4();
+        fs::write(dir.path().join("test.txt"), b"test")
+            .await
+            .unwrap();
+        let (_, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+
+        let config = GrpcConfig {
+            compression: Some(Compression::Gzip),
+            ..Default::default()
+        };
+        let client = serve(
+            GrpcServerBuilder::new()
+                .add_dir(dir_id, dir.path().to_path_buf())
+                .config(config.clone()),
+        )
+        .await;
+        let grpc_client = GrpcClient::with_config(client, &config);
+
+        let reqs = [DownloadBlockRequest {
+            dir_id,
+            filename: "test.txt".to_string(),
+            offset: 0,
+            len: 4,
+            hash_sum: block_chain.blocks[0].hash_sum,
+        }];
+        let resp = grpc_client.download(&reqs).await.unwrap();
+
+        let resp = resp.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(
+            resp,
+            vec![Some(DownloadBlock {
+                offset: 0,
+                data: Bytes::from_static(b"test"),
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn hash_mismatch() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let dir_id = Uuid::new_v4();
+        fs::write(dir.path().join("test.txt"), b"test")
+            .await
+            .unwrap();
+        let (_, block_chain) = hash_file(Cursor::new(b"tset")).await.unwrap();
+
+        let client =
+            serve(GrpcServerBuilder::new().add_dir(dir_id, dir.path().to_path_buf())).await;
+        let grpc_client = GrpcClient::new(client);
+
+        let reqs = [DownloadBlockRequest {
+            dir_id,
+            filename: "test.txt".to_string(),
+            offset: 0,
+            len: 4,
+            hash_sum: block_chain.blocks[0].hash_sum,
+        }];
+        let resp = grpc_client.download(&reqs).await.unwrap();
+
+        let resp = resp.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(resp, vec![None]);
+    }
+
+    #[tokio::test]
+    async fn escape_sync_dir() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let dir_id = Uuid::new_v4();
+
+        let client =
+            serve(GrpcServerBuilder::new().add_dir(dir_id, dir.path().to_path_buf())).await;
+        let grpc_client = GrpcClient::new(client);
+
+        let reqs = [DownloadBlockRequest {
+            dir_id,
+            filename: "../test.txt".to_string(),
+            offset: 0,
+            len: 4,
+            hash_sum: [0; 32],
+        }];
+        let resp = grpc_client.download(&reqs).await.unwrap();
+
+        let err = resp.try_collect::<Vec<_>>().await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    async fn serve(builder: GrpcServerBuilder) -> Channel {
+        let (client, server) = tokio::io::duplex(4096);
+
+        tokio::spawn(async move {
+            builder
+                .build()
+                .serve_with_incoming(stream::iter(vec![Ok::<_, std::io::Error>(server)]))
+                .await
+        });
+
+        build_channel(Some(client)).await
+    }
+
+    async fn build_channel(mut client: Option<DuplexStream>) -> Channel {
+        GrpcConfig::default()
+            .apply_endpoint(Endpoint::try_from("http://127.0.0.1:80").unwrap())
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let client = client.take();
+
+                async move {
+                    match client {
+                        None => Err(std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            "Client already taken",
+                        )),
+                        Some(client) => Ok(client),
+                    }
+                }
+            }))
+            .await
+            .unwrap()
+    }
+}
@@ -0,0 +1,108 @@
+use std::pin::pin;
+
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use prost::Message;
+use tap::TapFallible;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::convert::ConvertError;
+use super::pb;
+use crate::index::{Index, IndexFile};
+
+/// export all index files of the dir as a protobuf encoded IndexSnapshot
+pub async fn export_snapshot<I: Index>(index: &I, dir_id: Uuid) -> Result<Bytes, I::Error> {
+    let index_stream = index.list_all_files().await?;
+    let files = pin!(index_stream)
+        .map_ok(|index_file| pb::IndexFile::from(&index_file))
+        .try_collect::<Vec<_>>()
+        .await
+        .tap_err(|err| error!(%err, "collect all index files failed"))?;
+
+    info!(%dir_id, count = files.len(), "collect all index files done");
+
+    Ok(encode_pb_snapshot(dir_id, files))
+}
+
+/// encode the index files as a protobuf encoded IndexSnapshot
+pub fn encode_snapshot(dir_id: Uuid, files: &[IndexFile]) -> Bytes {
+    encode_pb_snapshot(dir_id, files.iter().map(Into::into).collect())
+}
+
+fn encode_pb_snapshot(dir_id: Uuid, files: Vec<pb::IndexFile>) -> Bytes {
+    let snapshot = pb::IndexSnapshot {
+        dir_id: dir_id.as_hyphenated().to_string(),
+        files,
+    };
+
+    snapshot.encode_to_vec().into()
+}
+
+/// decode a protobuf encoded IndexSnapshot, return the dir id and the index files
+pub fn decode_snapshot(data: &[u8]) -> Result<(Uuid, Vec<IndexFile>), ConvertError> {
+    let snapshot = pb::IndexSnapshot::decode(data)
+        .tap_err(|err| error!(%err, "decode index snapshot failed"))?;
+
+    let dir_id = Uuid::parse_str(&snapshot.dir_id)
+        .tap_err(|err| error!(%err, dir_id = %snapshot.dir_id, "parse dir id failed"))?;
+
+    let files = snapshot
+        .files
+        .into_iter()
+        .map(TryInto::try_into)
+        .collect::<Result<Vec<_>, _>>()
+        .tap_err(|err| error!(%err, "convert index files failed"))?;
+
+    Ok((dir_id, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::io;
+    use std::time::{Duration, SystemTime};
+
+    use futures_util::stream;
+
+    use super::*;
+    use crate::index::{FileDetail, FileKind, MockIndex};
+
+    #[tokio::test]
+    async fn snapshot_round_trip() {
+        let dir_id = Uuid::new_v4();
+        let index_file = IndexFile {
+            filename: OsString::from("test.txt"),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum: [1; 32],
+                block_chain: None,
+                deleted: true,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH + Duration::from_secs(100),
+            update_by: "test".to_string(),
+        };
+
+        let mut index = MockIndex::new();
+        {
+            let index_file = index_file.clone();
+
+            index.expect_list_all_files().returning(move || {
+                Ok(Box::pin(stream::iter([Ok::<_, io::Error>(
+                    index_file.clone(),
+                )])))
+            });
+        }
+
+        let data = export_snapshot(&index, dir_id).await.unwrap();
+        let (got_dir_id, files) = decode_snapshot(&data).unwrap();
+
+        assert_eq!(got_dir_id, dir_id);
+        assert_eq!(files, vec![index_file]);
+    }
+}
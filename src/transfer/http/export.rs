@@ -0,0 +1,304 @@
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::pin::pin;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use futures_util::TryStreamExt;
+use sha2::{Digest, Sha256};
+use tap::TapFallible;
+use tokio::fs;
+use tokio::fs::File;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::ext::{log_path, AsyncFileExt};
+use crate::index::{Block, Index, IndexFile};
+use crate::transfer::grpc::snapshot::encode_snapshot;
+
+pub const MANIFEST_NAME: &str = "manifest";
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ExportSummary {
+    pub files: usize,
+    /// the new written blocks, the blocks which already exist are not counted
+    pub blocks: usize,
+    /// the files whose content doesn't match the index, they are not in the manifest
+    pub skipped_files: Vec<OsString>,
+}
+
+/// export the verified blocks of the sync dir into `{out_dir}/{dir_id}/blocks/{hex hash sum}`,
+/// and the exported index files into the protobuf encoded `{out_dir}/{dir_id}/manifest`, the
+/// out dir can be uploaded to a static file server and consumed by the
+/// [`Layout::ContentAddressed`](super::Layout::ContentAddressed) http transfer
+pub async fn export_blocks<I>(
+    index: &I,
+    dir_id: Uuid,
+    sync_dir: &Path,
+    out_dir: &Path,
+) -> Result<ExportSummary>
+where
+    I: Index,
+    I::Error: Send + Sync + 'static,
+{
+    let dir_out = out_dir.join(dir_id.as_hyphenated().to_string());
+    let blocks_dir = dir_out.join("blocks");
+    fs::create_dir_all(&blocks_dir).await.tap_err(
+        |err| error!(%err, blocks_dir = ?log_path(&blocks_dir), "create blocks dir failed"),
+    )?;
+
+    let mut summary = ExportSummary::default();
+    let mut exported_files = vec![];
+
+    let index_stream = index.list_all_files().await?;
+    let mut index_stream = pin!(index_stream);
+    while let Some(index_file) = index_stream.try_next().await? {
+        if index_file.detail.deleted {
+            exported_files.push(index_file);
+
+            continue;
+        }
+
+        let blocks = match &index_file.detail.block_chain {
+            None => {
+                warn!(filename = ?log_path(&index_file.filename), "index file doesn't have block chain, skip");
+
+                summary.skipped_files.push(index_file.filename);
+
+                continue;
+            }
+
+            Some(block_chain) => &block_chain.blocks,
+        };
+
+        match export_file_blocks(sync_dir, &index_file, blocks, &blocks_dir).await? {
+            None => {
+                warn!(filename = ?log_path(&index_file.filename), "file content doesn't match index, skip");
+
+                summary.skipped_files.push(index_file.filename);
+            }
+
+            Some(n) => {
+                info!(filename = ?log_path(&index_file.filename), blocks = n, "export file blocks done");
+
+                summary.blocks += n;
+                summary.files += 1;
+                exported_files.push(index_file);
+            }
+        }
+    }
+
+    let manifest_path = dir_out.join(MANIFEST_NAME);
+    fs::write(&manifest_path, encode_snapshot(dir_id, &exported_files))
+        .await
+        .tap_err(
+            |err| error!(%err, manifest_path = ?log_path(&manifest_path), "write manifest failed"),
+        )?;
+
+    info!(?summary, "export blocks done");
+
+    Ok(summary)
+}
+
+/// when return None, means the file content doesn't match the block chain
+async fn export_file_blocks(
+    sync_dir: &Path,
+    index_file: &IndexFile,
+    blocks: &[Block],
+    blocks_dir: &Path,
+) -> Result<Option<usize>> {
+    let path = sync_dir.join(&index_file.filename);
+    let file = match File::open(&path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            error!(%err, path = ?log_path(&path), "open file failed");
+
+            return Err(err.into());
+        }
+        Ok(file) => file,
+    };
+
+    let mut new_blocks = 0;
+    for block in blocks {
+        let block_path = blocks_dir.join(hex::encode(block.hash_sum));
+        match fs::metadata(&block_path).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                error!(%err, block_path = ?log_path(&block_path), "get block metadata failed");
+
+                return Err(err.into());
+            }
+            Ok(_) => continue,
+        }
+
+        let mut buf = BytesMut::zeroed(block.len as _);
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = file
+                .read_at(&mut buf[filled..], block.offset + filled as u64)
+                .await
+                .tap_err(|err| error!(%err, path = ?log_path(&path), offset = block.offset, "read block failed"))?
+                as usize;
+            if n == 0 {
+                return Ok(None);
+            }
+
+            filled += n;
+        }
+
+        if <[u8; 32]>::from(Sha256::digest(&buf)) != block.hash_sum {
+            return Ok(None);
+        }
+
+        // write then rename, a half written block must not be served
+        let mut temp_path = block_path.clone().into_os_string();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, &buf)
+            .await
+            .tap_err(|err| error!(%err, temp_path = ?log_path(&temp_path), "write block failed"))?;
+        fs::rename(&temp_path, &block_path).await.tap_err(
+            |err| error!(%err, block_path = ?log_path(&block_path), "rename block failed"),
+        )?;
+
+        new_blocks += 1;
+    }
+
+    Ok(Some(new_blocks))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::env;
+    use std::io;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    use bytes::Bytes;
+    use futures_util::stream;
+    use http::{Request, Response, StatusCode};
+    use hyper::Body;
+    use tempfile::TempDir;
+    use tower::service_fn;
+
+    use super::*;
+    use crate::ext::hash_file;
+    use crate::index::{BlockChain, FileDetail, FileKind, MockIndex};
+    use crate::transfer::grpc::snapshot::decode_snapshot;
+    use crate::transfer::http::{HttpTransfer, Layout};
+    use crate::transfer::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+
+    #[tokio::test]
+    async fn export_and_download() {
+        let sync_dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let out_dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let dir_id = Uuid::new_v4();
+
+        fs::write(sync_dir.path().join("test.txt"), b"test")
+            .await
+            .unwrap();
+        fs::write(sync_dir.path().join("changed.txt"), b"changed")
+            .await
+            .unwrap();
+
+        let (hash_sum, block_chain) = hash_file(Cursor::new(b"test")).await.unwrap();
+        let (changed_hash_sum, changed_block_chain) =
+            hash_file(Cursor::new(b"origin")).await.unwrap();
+        let index_files = vec![
+            new_index_file("test.txt", hash_sum, block_chain.clone()),
+            new_index_file("changed.txt", changed_hash_sum, changed_block_chain),
+        ];
+
+        let mut index = MockIndex::new();
+        {
+            let index_files = index_files.clone();
+
+            index.expect_list_all_files().returning(move || {
+                Ok(Box::pin(stream::iter(
+                    index_files.clone().into_iter().map(Ok::<_, io::Error>),
+                )))
+            });
+        }
+
+        let summary = export_blocks(&index, dir_id, sync_dir.path(), out_dir.path())
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            ExportSummary {
+                files: 1,
+                blocks: 1,
+                skipped_files: vec![OsString::from("changed.txt")],
+            }
+        );
+
+        let dir_out = out_dir.path().join(dir_id.as_hyphenated().to_string());
+        let manifest = fs::read(dir_out.join(MANIFEST_NAME)).await.unwrap();
+        let (got_dir_id, files) = decode_snapshot(&manifest).unwrap();
+        assert_eq!(got_dir_id, dir_id);
+        assert_eq!(files, vec![index_files[0].clone()]);
+
+        let out_path = out_dir.path().to_path_buf();
+        let transfer = HttpTransfer::new(
+            service_fn(move |req: Request<Body>| serve(out_path.clone(), req)),
+            "http://127.0.0.1",
+        )
+        .with_layout(Layout::ContentAddressed);
+
+        let reqs = [DownloadBlockRequest {
+            dir_id,
+            filename: "test.txt".to_string(),
+            offset: 0,
+            len: 4,
+            hash_sum: block_chain.blocks[0].hash_sum,
+        }];
+        let blocks = transfer
+            .download(&reqs)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            blocks,
+            vec![Some(DownloadBlock {
+                offset: 0,
+                data: Bytes::from_static(b"test"),
+            })]
+        );
+    }
+
+    async fn serve(root: PathBuf, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let path = root.join(req.uri().path().trim_start_matches('/'));
+
+        Ok(match fs::read(path).await {
+            Err(_) => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap(),
+            Ok(data) => Response::new(Body::from(data)),
+        })
+    }
+
+    fn new_index_file(filename: &str, hash_sum: [u8; 32], block_chain: BlockChain) -> IndexFile {
+        IndexFile {
+            filename: OsString::from(filename),
+            kind: FileKind::File,
+            detail: FileDetail {
+                gen: 1,
+                hash_sum,
+                block_chain: Some(block_chain),
+                deleted: false,
+                metadata: None,
+                version: Default::default(),
+                link_group: None,
+            },
+            previous_details: vec![],
+            update_time: SystemTime::UNIX_EPOCH,
+            update_by: "test".to_string(),
+        }
+    }
+}
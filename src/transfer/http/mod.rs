@@ -0,0 +1,424 @@
+use std::fmt::Write;
+use std::io;
+use std::io::ErrorKind;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures_util::Stream;
+use http::header::RANGE;
+use http::{Request, Response, StatusCode, Uri};
+use hyper::body::HttpBody;
+use hyper::Body;
+use sha2::{Digest, Sha256};
+use tap::TapFallible;
+use thiserror::Error;
+use tower::{Service, ServiceExt};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use super::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+use crate::ext::log_path;
+
+#[cfg(feature = "grpc")]
+pub mod export;
+
+type StdError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid url: {0}")]
+    InvalidUrl(#[from] http::Error),
+
+    #[error("http service error: {0}")]
+    Service(StdError),
+
+    #[error("read http body failed: {0}")]
+    Body(#[from] hyper::Error),
+
+    #[error("unexpected http status {0}")]
+    Status(StatusCode),
+
+    #[error("http server doesn't support range request")]
+    RangeNotSupported,
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        io::Error::new(ErrorKind::Other, err)
+    }
+}
+
+/// how the files are laid out on the static file server
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Layout {
+    /// the sync dir is served as is, blocks are fetched with range requests from
+    /// `{base_url}/{dir_id}/{filename}`
+    #[default]
+    Files,
+
+    /// the blocks are exported by [`export::export_blocks`], every block is fetched from
+    /// `{base_url}/{dir_id}/blocks/{hex hash sum}`
+    ContentAddressed,
+}
+
+/// download blocks from a static file server, downloaded blocks are verified by the block hash
+/// sum, so the server doesn't need to be trusted
+#[derive(Debug, Clone)]
+pub struct HttpTransfer<T> {
+    client: T,
+    base_url: String,
+    layout: Layout,
+}
+
+impl<T> HttpTransfer<T> {
+    /// the client can be a hyper client with any connector, such as a https connector
+    pub fn new(client: T, base_url: impl Into<String>) -> Self {
+        let mut base_url = base_url.into();
+        while base_url.ends_with('/') {
+            base_url.pop();
+        }
+
+        Self {
+            client,
+            base_url,
+            layout: Layout::Files,
+        }
+    }
+
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+
+        self
+    }
+
+    fn block_url(&self, req: &DownloadBlockRequest) -> String {
+        match self.layout {
+            Layout::Files => self.file_url(&req.dir_id, &req.filename),
+            Layout::ContentAddressed => format!(
+                "{}/{}/blocks/{}",
+                self.base_url,
+                req.dir_id.as_hyphenated(),
+                hex::encode(req.hash_sum)
+            ),
+        }
+    }
+
+    fn file_url(&self, dir_id: &Uuid, filename: &str) -> String {
+        let mut url = format!("{}/{}", self.base_url, dir_id.as_hyphenated());
+        for segment in filename.split('/').filter(|segment| !segment.is_empty()) {
+            url.push('/');
+            percent_encode(segment, &mut url);
+        }
+
+        url
+    }
+}
+
+#[async_trait]
+impl<T> DownloadTransfer for HttpTransfer<T>
+where
+    T: Service<Request<Body>, Response = Response<Body>> + Clone + Send + Sync,
+    T::Error: Into<StdError>,
+    T::Future: Send,
+{
+    type Error = Error;
+    type BlockStream<'a> = impl Stream<Item = Result<Option<DownloadBlock>, Self::Error>> where Self: 'a;
+
+    #[instrument(err, skip(self))]
+    async fn download<'a>(
+        &'a self,
+        block_offset: &'a [DownloadBlockRequest],
+    ) -> Result<Self::BlockStream<'a>, Self::Error> {
+        Ok(async_stream::try_stream! {
+            for req in block_offset {
+                yield self.download_block(req).await?;
+            }
+        })
+    }
+}
+
+impl<T> HttpTransfer<T>
+where
+    T: Service<Request<Body>, Response = Response<Body>> + Clone + Send + Sync,
+    T::Error: Into<StdError>,
+    T::Future: Send,
+{
+    /// when return None, means the block is not found or the block hash is changed
+    async fn download_block(
+        &self,
+        req: &DownloadBlockRequest,
+    ) -> Result<Option<DownloadBlock>, Error> {
+        let url = self.block_url(req);
+        let mut builder = Request::get(url.parse::<Uri>().map_err(http::Error::from)?);
+        if req.len > 0 && self.layout == Layout::Files {
+            builder = builder.header(
+                RANGE,
+                format!("bytes={}-{}", req.offset, req.offset + req.len - 1),
+            );
+        }
+        let request = builder
+            .body(Body::empty())
+            .tap_err(|err| error!(%err, url = %log_path(&url), "build http request failed"))?;
+
+        let response = self
+            .client
+            .clone()
+            .oneshot(request)
+            .await
+            .map_err(|err| Error::Service(err.into()))
+            .tap_err(|err| error!(%err, url = %log_path(&url), "send http request failed"))?;
+
+        let status = response.status();
+        let data = match status {
+            StatusCode::NOT_FOUND | StatusCode::RANGE_NOT_SATISFIABLE => {
+                warn!(url = %log_path(&url), %status, "block not found");
+
+                return Ok(None);
+            }
+
+            StatusCode::PARTIAL_CONTENT => read_body(response.into_body(), req.len)
+                .await
+                .tap_err(|err| error!(%err, url = %log_path(&url), "read http body failed"))?,
+
+            // the content addressed blocks and the empty blocks are requested without a range
+            StatusCode::OK if self.layout == Layout::ContentAddressed || req.len == 0 => {
+                read_body(response.into_body(), req.len)
+                    .await
+                    .tap_err(|err| error!(%err, url = %log_path(&url), "read http body failed"))?
+            }
+
+            // server ignores the range header and returns the whole file, don't download the
+            // whole file for every block
+            StatusCode::OK => {
+                error!(url = %log_path(&url), "http server doesn't support range request");
+
+                return Err(Error::RangeNotSupported);
+            }
+
+            status => {
+                error!(url = %log_path(&url), %status, "unexpected http status");
+
+                return Err(Error::Status(status));
+            }
+        };
+
+        let data = match data {
+            None => {
+                warn!(
+                    url = %log_path(&url),
+                    offset = req.offset,
+                    len = req.len,
+                    "http body is longer than the block, maybe file is changed"
+                );
+
+                return Ok(None);
+            }
+
+            Some(data) => data,
+        };
+
+        let hash_sum: [u8; 32] = Sha256::digest(&data).into();
+        if data.len() as u64 != req.len || hash_sum != req.hash_sum {
+            warn!(
+                url = %log_path(&url),
+                offset = req.offset,
+                "block hash sum mismatch, maybe file is changed"
+            );
+
+            return Ok(None);
+        }
+
+        info!(url = %log_path(&url), offset = req.offset, len = req.len, "download block done");
+
+        Ok(Some(DownloadBlock {
+            offset: req.offset,
+            data,
+        }))
+    }
+}
+
+/// read the body without buffering more than the limit, when return None, the body is longer
+/// than the limit
+async fn read_body(mut body: Body, limit: u64) -> Result<Option<Bytes>, hyper::Error> {
+    let mut data = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (data.len() + chunk.len()) as u64 > limit {
+            return Ok(None);
+        }
+
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(data.freeze()))
+}
+
+/// percent encode everything except the rfc 3986 unreserved characters
+fn percent_encode(segment: &str, url: &mut String) {
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            url.push(byte as char);
+        } else {
+            let _ = write!(url, "%{byte:02X}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::io::Cursor;
+
+    use futures_util::TryStreamExt;
+    use tower::service_fn;
+
+    use super::*;
+    use crate::ext::hash_file;
+
+    const DATA: &[u8] = b"test data";
+
+    async fn serve(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        if !req.uri().path().ends_with("/sub%20dir/test.txt") {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap());
+        }
+
+        let range = req.headers()[RANGE].to_str().unwrap();
+        let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+        let (start, end) = (
+            start.parse::<usize>().unwrap(),
+            end.parse::<usize>().unwrap(),
+        );
+
+        Ok(Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .body(Body::from(&DATA[start..=end]))
+            .unwrap())
+    }
+
+    #[tokio::test]
+    async fn download_block() {
+        let dir_id = Uuid::new_v4();
+        let (_, block_chain) = hash_file(Cursor::new(&DATA[5..])).await.unwrap();
+        let transfer = HttpTransfer::new(service_fn(serve), "http://127.0.0.1/");
+
+        let reqs = [DownloadBlockRequest {
+            dir_id,
+            filename: "sub dir/test.txt".to_string(),
+            offset: 5,
+            len: 4,
+            hash_sum: block_chain.blocks[0].hash_sum,
+        }];
+        let blocks = transfer
+            .download(&reqs)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            blocks,
+            vec![Some(DownloadBlock {
+                offset: 5,
+                data: Bytes::from_static(b"data"),
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn block_changed_or_not_found() {
+        let dir_id = Uuid::new_v4();
+        let (_, block_chain) = hash_file(Cursor::new(b"wrong")).await.unwrap();
+        let transfer = HttpTransfer::new(service_fn(serve), "http://127.0.0.1");
+
+        let reqs = [
+            DownloadBlockRequest {
+                dir_id,
+                filename: "sub dir/test.txt".to_string(),
+                offset: 0,
+                len: 5,
+                hash_sum: block_chain.blocks[0].hash_sum,
+            },
+            DownloadBlockRequest {
+                dir_id,
+                filename: "not-exist.txt".to_string(),
+                offset: 0,
+                len: 5,
+                hash_sum: block_chain.blocks[0].hash_sum,
+            },
+        ];
+        let blocks = transfer
+            .download(&reqs)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(blocks, vec![None, None]);
+    }
+
+    #[tokio::test]
+    async fn body_longer_than_block() {
+        let dir_id = Uuid::new_v4();
+        let (_, block_chain) = hash_file(Cursor::new(&DATA[5..])).await.unwrap();
+        let transfer = HttpTransfer::new(
+            service_fn(|_| async {
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .body(Body::from(DATA))
+                        .unwrap(),
+                )
+            }),
+            "http://127.0.0.1",
+        );
+
+        let reqs = [DownloadBlockRequest {
+            dir_id,
+            filename: "sub dir/test.txt".to_string(),
+            offset: 5,
+            len: 4,
+            hash_sum: block_chain.blocks[0].hash_sum,
+        }];
+        let blocks = transfer
+            .download(&reqs)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(blocks, vec![None]);
+    }
+
+    #[tokio::test]
+    async fn range_not_supported() {
+        let dir_id = Uuid::new_v4();
+        let (_, block_chain) = hash_file(Cursor::new(&DATA[5..])).await.unwrap();
+        let transfer = HttpTransfer::new(
+            service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::from(DATA))) }),
+            "http://127.0.0.1",
+        );
+
+        let reqs = [DownloadBlockRequest {
+            dir_id,
+            filename: "sub dir/test.txt".to_string(),
+            offset: 5,
+            len: 4,
+            hash_sum: block_chain.blocks[0].hash_sum,
+        }];
+        let err = transfer
+            .download(&reqs)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::RangeNotSupported));
+    }
+}
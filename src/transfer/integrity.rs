@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use flume::Sender;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use super::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+use crate::index::Sha256sum;
+
+/// default failures of a peer before it is quarantined
+pub const DEFAULT_QUARANTINE_THRESHOLD: u64 = 8;
+
+/// the transfer integrity statistics of a peer
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct IntegrityStats {
+    pub verified_blocks: u64,
+    /// the received blocks whose hash sum or len doesn't match the request
+    pub hash_failures: u64,
+    /// the failed downloads and the blocks which are not requested
+    pub protocol_errors: u64,
+    pub quarantined: bool,
+}
+
+/// emitted once when a peer is quarantined
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QuarantineAlert {
+    pub peer_id: Uuid,
+    pub stats: IntegrityStats,
+}
+
+/// the download is refused because the peer is quarantined
+#[derive(Debug, Error)]
+#[error("peer {0} is quarantined")]
+pub struct PeerQuarantined(pub Uuid);
+
+impl PeerQuarantined {
+    /// when return the peer id, the download failed because the peer is quarantined, the blocks
+    /// should be downloaded from another peer
+    pub fn quarantined_peer(err: &io::Error) -> Option<Uuid> {
+        err.get_ref()?
+            .downcast_ref::<PeerQuarantined>()
+            .map(|err| err.0)
+    }
+}
+
+impl From<PeerQuarantined> for io::Error {
+    fn from(err: PeerQuarantined) -> Self {
+        io::Error::new(ErrorKind::Other, err)
+    }
+}
+
+/// the shared integrity statistics of a peer, the clones share the statistics. When the failures
+/// reach the threshold, the peer is quarantined until it is released
+#[derive(Debug, Clone)]
+pub struct PeerHealth {
+    peer_id: Uuid,
+    threshold: u64,
+    stats: Arc<Mutex<IntegrityStats>>,
+    alert_sender: Option<Sender<QuarantineAlert>>,
+}
+
+impl PeerHealth {
+    /// 0 threshold means never quarantine
+    pub fn new(peer_id: Uuid, threshold: u64) -> Self {
+        Self {
+            peer_id,
+            threshold,
+            stats: Default::default(),
+            alert_sender: None,
+        }
+    }
+
+    /// the alert is dropped when the receiver is full or dropped
+    pub fn with_alert(mut self, alert_sender: Sender<QuarantineAlert>) -> Self {
+        self.alert_sender = Some(alert_sender);
+
+        self
+    }
+
+    pub fn peer_id(&self) -> Uuid {
+        self.peer_id
+    }
+
+    pub fn stats(&self) -> IntegrityStats {
+        *self.stats.lock().unwrap()
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.stats.lock().unwrap().quarantined
+    }
+
+    /// release the quarantined peer, such as its RAM or disk is replaced, the failures are reset
+    pub fn release(&self) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.hash_failures = 0;
+        stats.protocol_errors = 0;
+        stats.quarantined = false;
+
+        warn!(peer_id = %self.peer_id, "release quarantined peer done");
+    }
+
+    fn record_verified(&self) {
+        self.stats.lock().unwrap().verified_blocks += 1;
+    }
+
+    fn record_hash_failure(&self) {
+        self.record_failure(|stats| stats.hash_failures += 1);
+    }
+
+    fn record_protocol_error(&self) {
+        self.record_failure(|stats| stats.protocol_errors += 1);
+    }
+
+    fn record_failure(&self, record: impl FnOnce(&mut IntegrityStats)) {
+        let mut stats = self.stats.lock().unwrap();
+        record(&mut stats);
+
+        if stats.quarantined
+            || self.threshold == 0
+            || stats.hash_failures + stats.protocol_errors < self.threshold
+        {
+            return;
+        }
+
+        stats.quarantined = true;
+
+        error!(
+            peer_id = %self.peer_id,
+            hash_failures = stats.hash_failures,
+            protocol_errors = stats.protocol_errors,
+            "peer has too many transfer failures, quarantine it"
+        );
+
+        if let Some(alert_sender) = &self.alert_sender {
+            let _ = alert_sender.try_send(QuarantineAlert {
+                peer_id: self.peer_id,
+                stats: *stats,
+            });
+        }
+    }
+}
+
+/// the healths of the peers keyed by the peer id, the clones share the healths, so the
+/// statistics can be read and the quarantined peers can be released when the sync is running
+#[derive(Debug, Clone)]
+pub struct PeerHealths {
+    threshold: u64,
+    alert_sender: Option<Sender<QuarantineAlert>>,
+    healths: Arc<Mutex<HashMap<Uuid, PeerHealth>>>,
+}
+
+impl Default for PeerHealths {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUARANTINE_THRESHOLD)
+    }
+}
+
+impl PeerHealths {
+    /// 0 threshold means never quarantine
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            alert_sender: None,
+            healths: Default::default(),
+        }
+    }
+
+    /// the alerts of all peers are sent to the sender
+    pub fn with_alert(mut self, alert_sender: Sender<QuarantineAlert>) -> Self {
+        self.alert_sender = Some(alert_sender);
+
+        self
+    }
+
+    /// the health of the peer, it is created when the peer is first seen
+    pub fn health(&self, peer_id: Uuid) -> PeerHealth {
+        self.healths
+            .lock()
+            .unwrap()
+            .entry(peer_id)
+            .or_insert_with(|| {
+                let health = PeerHealth::new(peer_id, self.threshold);
+                match &self.alert_sender {
+                    None => health,
+                    Some(alert_sender) => health.with_alert(alert_sender.clone()),
+                }
+            })
+            .clone()
+    }
+}
+
+/// verify the received blocks by the requests and count the failures into the peer health.
+/// The mismatched block is reported as not found, so it is never written into the synced file.
+/// The quarantined peer is not requested, the download fails with [`PeerQuarantined`], the
+/// rumors of the peer are still received, the files are synced by the healthy peers or after
+/// the release
+#[derive(Debug)]
+pub struct IntegrityTransfer<D> {
+    inner: D,
+    health: PeerHealth,
+}
+
+impl<D> IntegrityTransfer<D> {
+    pub fn new(inner: D, health: PeerHealth) -> Self {
+        Self { inner, health }
+    }
+
+    pub fn health(&self) -> &PeerHealth {
+        &self.health
+    }
+}
+
+#[async_trait]
+impl<D> DownloadTransfer for IntegrityTransfer<D>
+where
+    D: DownloadTransfer + Send + Sync,
+    D::Error: Into<io::Error>,
+{
+    type Error = io::Error;
+    type BlockStream<'a> = Pin<Box<dyn Stream<Item = Result<Option<DownloadBlock>, Self::Error>> + 'a>> where Self: 'a;
+
+    async fn download<'a>(
+        &'a self,
+        block_offset: &'a [DownloadBlockRequest],
+    ) -> Result<Self::BlockStream<'a>, Self::Error> {
+        if self.health.is_quarantined() {
+            warn!(peer_id = %self.health.peer_id, "peer is quarantined, skip download");
+
+            return Err(PeerQuarantined(self.health.peer_id).into());
+        }
+
+        let stream = match self.inner.download(block_offset).await {
+            Err(err) => {
+                self.health.record_protocol_error();
+
+                return Err(err.into());
+            }
+
+            Ok(stream) => stream,
+        };
+
+        let requests = block_offset
+            .iter()
+            .map(|req| (req.offset, (req.len, req.hash_sum)))
+            .collect::<HashMap<_, _>>();
+        let health = &self.health;
+
+        Ok(Box::pin(stream.map_err(Into::into).map(move |block| {
+            let block = match block {
+                Err(err) => {
+                    health.record_protocol_error();
+
+                    return Err(err);
+                }
+
+                Ok(None) => return Ok(None),
+                Ok(Some(block)) => block,
+            };
+
+            match requests.get(&block.offset) {
+                None => {
+                    warn!(peer_id = %health.peer_id, offset = block.offset, "receive not requested block");
+
+                    health.record_protocol_error();
+
+                    Ok(None)
+                }
+
+                Some((len, hash_sum)) if !verify_block(&block, *len, hash_sum) => {
+                    warn!(peer_id = %health.peer_id, offset = block.offset, "received block hash sum mismatch");
+
+                    health.record_hash_failure();
+
+                    Ok(None)
+                }
+
+                Some(_) => {
+                    health.record_verified();
+
+                    Ok(Some(block))
+                }
+            }
+        })))
+    }
+}
+
+fn verify_block(block: &DownloadBlock, len: u64, hash_sum: &Sha256sum) -> bool {
+    block.data.len() as u64 == len && <Sha256sum>::from(Sha256::digest(&block.data)) == *hash_sum
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use bytes::Bytes;
+    use futures_util::stream;
+
+    use super::*;
+    use crate::transfer::MockDownloadTransfer;
+
+    fn request(offset: u64, data: &[u8]) -> DownloadBlockRequest {
+        DownloadBlockRequest {
+            dir_id: Uuid::nil(),
+            filename: "test.txt".to_string(),
+            offset,
+            len: data.len() as _,
+            hash_sum: Sha256::digest(data).into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn quarantine_corrupting_peer() {
+        let mut inner = MockDownloadTransfer::new();
+        inner.expect_download().times(1).returning(|_| {
+            Ok(Box::pin(stream::iter([
+                Ok(Some(DownloadBlock {
+                    offset: 0,
+                    data: Bytes::from_static(b"test"),
+                })),
+                // corrupted
+                Ok(Some(DownloadBlock {
+                    offset: 4,
+                    data: Bytes::from_static(b"dbta"),
+                })),
+                // not requested
+                Ok(Some(DownloadBlock {
+                    offset: 8,
+                    data: Bytes::from_static(b"more"),
+                })),
+            ])))
+        });
+
+        let (alert_sender, alert_receiver) = flume::unbounded();
+        let peer_id = Uuid::new_v4();
+        let health = PeerHealth::new(peer_id, 2).with_alert(alert_sender);
+        let transfer = IntegrityTransfer::new(inner, health.clone());
+
+        let requests = [request(0, b"test"), request(4, b"data")];
+        let blocks = transfer
+            .download(&requests)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            blocks,
+            [
+                Some(DownloadBlock {
+                    offset: 0,
+                    data: Bytes::from_static(b"test"),
+                }),
+                None,
+                None
+            ]
+        );
+
+        let stats = IntegrityStats {
+            verified_blocks: 1,
+            hash_failures: 1,
+            protocol_errors: 1,
+            quarantined: true,
+        };
+        assert_eq!(health.stats(), stats);
+        assert_eq!(
+            alert_receiver.drain().collect::<Vec<_>>(),
+            [QuarantineAlert { peer_id, stats }]
+        );
+
+        // the quarantined peer is not requested
+        let err = match transfer.download(&requests).await {
+            Err(err) => err,
+            Ok(_) => panic!("quarantined peer is requested"),
+        };
+        assert_eq!(PeerQuarantined::quarantined_peer(&err), Some(peer_id));
+
+        health.release();
+        assert!(!health.is_quarantined());
+    }
+
+    #[tokio::test]
+    async fn count_download_error() {
+        let mut inner = MockDownloadTransfer::new();
+        inner
+            .expect_download()
+            .returning(|_| Err(io::Error::new(io::ErrorKind::Other, "broken pipe")));
+
+        let health = PeerHealth::new(Uuid::new_v4(), 0);
+        let transfer = IntegrityTransfer::new(inner, health.clone());
+
+        for _ in 0..3 {
+            assert!(transfer.download(&[request(0, b"test")]).await.is_err());
+        }
+
+        // 0 threshold never quarantines
+        assert_eq!(health.stats().protocol_errors, 3);
+        assert!(!health.is_quarantined());
+    }
+
+    #[test]
+    fn share_peer_healths() {
+        let (alert_sender, alert_receiver) = flume::unbounded();
+        let peer_healths = PeerHealths::new(1).with_alert(alert_sender);
+        let peer_id = Uuid::new_v4();
+
+        peer_healths.health(peer_id).record_hash_failure();
+
+        assert!(peer_healths.health(peer_id).is_quarantined());
+        assert!(!peer_healths.health(Uuid::new_v4()).is_quarantined());
+        assert_eq!(alert_receiver.drain().count(), 1);
+
+        peer_healths.clone().health(peer_id).release();
+        assert!(!peer_healths.health(peer_id).is_quarantined());
+    }
+}
@@ -1,4 +1,5 @@
 use std::error::Error;
+use std::fmt::{Debug, Formatter};
 use std::io;
 use std::pin::Pin;
 
@@ -8,9 +9,16 @@ use futures_util::Stream;
 use mockall::automock;
 use uuid::Uuid;
 
+use crate::ext::log_path;
 use crate::index::Sha256sum;
 
+pub mod address_book;
+#[cfg(feature = "grpc")]
 pub mod grpc;
+pub mod http;
+pub mod integrity;
+pub mod rate_limit;
+pub mod retry;
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct DownloadBlock {
@@ -18,7 +26,7 @@ pub struct DownloadBlock {
     pub data: Bytes,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 pub struct DownloadBlockRequest {
     pub dir_id: Uuid,
     pub filename: String,
@@ -27,6 +35,20 @@ pub struct DownloadBlockRequest {
     pub hash_sum: Sha256sum,
 }
 
+/// the download requests are logged by the transfer instrument, so the filename must respect
+/// the log privacy mode
+impl Debug for DownloadBlockRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DownloadBlockRequest")
+            .field("dir_id", &self.dir_id)
+            .field("filename", &log_path(&self.filename))
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .field("hash_sum", &self.hash_sum)
+            .finish()
+    }
+}
+
 #[automock(type Error = io::Error; type BlockStream = Pin < Box < dyn Stream < Item = Result < Option < DownloadBlock >, io::Error >> >>;)]
 #[async_trait]
 pub trait DownloadTransfer {
@@ -40,3 +62,21 @@ pub trait DownloadTransfer {
         block_offset: &'a [DownloadBlockRequest],
     ) -> Result<Self::BlockStream<'a>, Self::Error>;
 }
+
+/// the controller wraps its transfer by the borrowed transfer, such as checking the blocks of a
+/// rumor by the health of its sender
+#[async_trait]
+impl<D> DownloadTransfer for &D
+where
+    D: DownloadTransfer + Sync + ?Sized,
+{
+    type Error = D::Error;
+    type BlockStream<'a> = D::BlockStream<'a> where Self: 'a;
+
+    async fn download<'a>(
+        &'a self,
+        block_offset: &'a [DownloadBlockRequest],
+    ) -> Result<Self::BlockStream<'a>, Self::Error> {
+        (**self).download(block_offset).await
+    }
+}
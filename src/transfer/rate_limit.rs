@@ -0,0 +1,202 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt};
+use tokio::time;
+use tracing::info;
+
+use super::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+
+/// the token bucket limiter of the transferred bytes, the bucket holds at most one second of
+/// tokens. The clones share the same bucket, so a limit can be changed when the transfer is
+/// running
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    /// 0 means no limit
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket::new(bytes_per_sec, Instant::now()))),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_sec
+    }
+
+    /// 0 means no limit
+    pub fn set_limit(&self, bytes_per_sec: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.bytes_per_sec == bytes_per_sec {
+            return;
+        }
+
+        bucket.set_limit(bytes_per_sec, Instant::now());
+
+        info!(bytes_per_sec, "set rate limit done");
+    }
+
+    /// wait until the bytes can be transferred
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = self.bucket.lock().unwrap().take(bytes, Instant::now());
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_sec: u64,
+    /// negative when a block larger than the bucket is taken, the following takers wait until
+    /// the debt is paid
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self::new(0, Instant::now())
+    }
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        Self {
+            bytes_per_sec,
+            tokens: bytes_per_sec as _,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let rate = self.bytes_per_sec as f64;
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last_refill = now;
+    }
+
+    fn set_limit(&mut self, bytes_per_sec: u64, now: Instant) {
+        self.refill(now);
+
+        if self.bytes_per_sec == 0 {
+            // the unlimited bucket starts full
+            self.tokens = bytes_per_sec as _;
+        } else {
+            self.tokens = self.tokens.min(bytes_per_sec as _);
+        }
+        self.bytes_per_sec = bytes_per_sec;
+    }
+
+    /// take the tokens of the bytes, return how long the taker should wait
+    fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        if self.bytes_per_sec == 0 {
+            return Duration::ZERO;
+        }
+
+        self.refill(now);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec as f64)
+        }
+    }
+}
+
+/// the upload and download limiters of a device, the download limiter is used by
+/// [`RateLimitedTransfer`], the upload limiter is used by the block server
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimits {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+impl BandwidthLimits {
+    /// 0 means no limit
+    pub fn new(upload_bytes_per_sec: u64, download_bytes_per_sec: u64) -> Self {
+        Self {
+            upload: RateLimiter::new(upload_bytes_per_sec),
+            download: RateLimiter::new(download_bytes_per_sec),
+        }
+    }
+}
+
+/// limit the download rate of the inner transfer, the next block is not polled until the
+/// received block is paid, so the transport flow control slows down the sender
+#[derive(Debug)]
+pub struct RateLimitedTransfer<D> {
+    inner: D,
+    limiter: RateLimiter,
+}
+
+impl<D> RateLimitedTransfer<D> {
+    pub fn new(inner: D, limiter: RateLimiter) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+#[async_trait]
+impl<D> DownloadTransfer for RateLimitedTransfer<D>
+where
+    D: DownloadTransfer + Send + Sync,
+{
+    type Error = D::Error;
+    type BlockStream<'a> = Pin<Box<dyn Stream<Item = Result<Option<DownloadBlock>, Self::Error>> + 'a>> where Self: 'a;
+
+    async fn download<'a>(
+        &'a self,
+        block_offset: &'a [DownloadBlockRequest],
+    ) -> Result<Self::BlockStream<'a>, Self::Error> {
+        let stream = self.inner.download(block_offset).await?;
+        let limiter = &self.limiter;
+
+        Ok(Box::pin(stream.then(move |block| async move {
+            if let Ok(Some(block)) = &block {
+                limiter.acquire(block.data.len() as _).await;
+            }
+
+            block
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_take() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(100, now);
+
+        // the full bucket allows a burst of one second
+        assert_eq!(bucket.take(100, now), Duration::ZERO);
+        assert_eq!(bucket.take(50, now), Duration::from_millis(500));
+
+        // the debt is paid after half a second
+        let now = now + Duration::from_millis(500);
+        assert_eq!(bucket.take(0, now), Duration::ZERO);
+
+        // the refill never exceeds one second of tokens
+        let now = now + Duration::from_secs(10);
+        assert_eq!(bucket.take(100, now), Duration::ZERO);
+        assert_eq!(bucket.take(100, now), Duration::from_secs(1));
+
+        // no limit
+        bucket.set_limit(0, now);
+        assert_eq!(bucket.take(u64::MAX, now), Duration::ZERO);
+
+        bucket.set_limit(10, now);
+        assert_eq!(bucket.take(20, now), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,296 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::{future, stream, Stream, StreamExt};
+use rand::Rng;
+use tokio::time;
+use tracing::{info, warn};
+
+use super::{DownloadBlock, DownloadBlockRequest, DownloadTransfer};
+
+/// default attempts of a download, including the first one
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 4;
+
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+type BoxStream<'a, E> = Pin<Box<dyn Stream<Item = Result<Option<DownloadBlock>, E>> + 'a>>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// the attempts of a download, including the first one, 1 means never retry
+    pub max_attempts: u32,
+
+    /// the backoff before the first retry, it is doubled after every retry
+    pub initial_backoff: Duration,
+
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// the exponential backoff before the retry, with the jitter in the upper half, so the
+    /// downloaders of a failed peer don't retry at the same time
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        if backoff.is_zero() {
+            return backoff;
+        }
+
+        rand::thread_rng().gen_range(backoff / 2..=backoff)
+    }
+}
+
+/// retry the failed downloads of the inner transfer with the exponential backoff. When the block
+/// stream fails, only the blocks which are not received are requested again, the received
+/// blocks are not sent twice. The error is returned after the max attempts
+#[derive(Debug)]
+pub struct RetryTransfer<D> {
+    inner: D,
+    policy: RetryPolicy,
+}
+
+impl<D> RetryTransfer<D> {
+    pub fn new(inner: D, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait]
+impl<D> DownloadTransfer for RetryTransfer<D>
+where
+    D: DownloadTransfer + Send + Sync,
+{
+    type Error = D::Error;
+    type BlockStream<'a> = Pin<Box<dyn Stream<Item = Result<Option<DownloadBlock>, Self::Error>> + 'a>> where Self: 'a;
+
+    async fn download<'a>(
+        &'a self,
+        block_offset: &'a [DownloadBlockRequest],
+    ) -> Result<Self::BlockStream<'a>, Self::Error> {
+        let mut attempt = 1;
+        let first_stream = loop {
+            // the error is not held across the backoff, it may be not Send
+            match self.inner.download(block_offset).await {
+                Err(err) if attempt < self.policy.max_attempts => {
+                    warn!(%err, attempt, "download failed, retry it");
+                }
+
+                result => break result?,
+            }
+
+            time::sleep(self.policy.backoff(attempt)).await;
+            attempt += 1;
+        };
+
+        let policy = self.policy;
+        let stream = async_stream::stream! {
+            let mut first_stream = Some(first_stream);
+            let mut requests = Cow::Borrowed(block_offset);
+            let mut received = HashSet::new();
+
+            loop {
+                let err = {
+                    // the failed download is handled as a failed stream
+                    let mut stream: BoxStream<'_, D::Error> = match first_stream.take() {
+                        Some(stream) => Box::pin(stream),
+                        None => match self.inner.download(&requests).await {
+                            Err(err) => Box::pin(stream::once(future::ready(Err(err)))),
+                            Ok(stream) => Box::pin(stream),
+                        },
+                    };
+
+                    let mut failed = None;
+                    while let Some(block) = stream.next().await {
+                        match block {
+                            Err(err) => {
+                                failed = Some(err);
+
+                                break;
+                            }
+
+                            Ok(block) => {
+                                if let Some(block) = &block {
+                                    received.insert(block.offset);
+                                }
+
+                                yield Ok(block);
+                            }
+                        }
+                    }
+
+                    match failed {
+                        None => return,
+                        Some(err) => err,
+                    }
+                };
+
+                if attempt >= policy.max_attempts {
+                    warn!(%err, attempt, "download failed after the max attempts");
+
+                    yield Err(err);
+
+                    return;
+                }
+
+                let remaining = requests
+                    .iter()
+                    .filter(|request| !received.contains(&request.offset))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                warn!(%err, attempt, remaining = remaining.len(), "download blocks failed, retry the remaining blocks");
+
+                time::sleep(policy.backoff(attempt)).await;
+                attempt += 1;
+                requests = Cow::Owned(remaining);
+
+                info!(attempt, "retry download blocks");
+            }
+        };
+
+        Ok(Box::pin(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use bytes::Bytes;
+    use futures_util::TryStreamExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::transfer::MockDownloadTransfer;
+
+    fn request(offset: u64) -> DownloadBlockRequest {
+        DownloadBlockRequest {
+            dir_id: Uuid::nil(),
+            filename: "test.txt".to_string(),
+            offset,
+            len: 4,
+            hash_sum: [0; 32],
+        }
+    }
+
+    fn block(offset: u64) -> DownloadBlock {
+        DownloadBlock {
+            offset,
+            data: Bytes::from_static(b"test"),
+        }
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy::default();
+        for retry in 1..10 {
+            let backoff = policy.backoff(retry);
+            let max = (DEFAULT_INITIAL_BACKOFF * 2u32.pow(retry - 1)).min(DEFAULT_MAX_BACKOFF);
+            assert!(backoff >= max / 2 && backoff <= max, "{backoff:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_remaining_blocks() {
+        let mut inner = MockDownloadTransfer::new();
+        let mut seq = mockall::Sequence::new();
+        inner
+            .expect_download()
+            .withf(|requests: &[DownloadBlockRequest]| requests.len() == 3)
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")));
+        // the connection is broken after the first block
+        inner
+            .expect_download()
+            .withf(|requests: &[DownloadBlockRequest]| requests.len() == 3)
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| {
+                Ok(Box::pin(stream::iter([
+                    Ok(Some(block(0))),
+                    Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset")),
+                ])))
+            });
+        inner
+            .expect_download()
+            .withf(|requests: &[DownloadBlockRequest]| {
+                requests.iter().map(|request| request.offset).eq([4, 8])
+            })
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| {
+                Ok(Box::pin(stream::iter([
+                    Ok(Some(block(4))),
+                    Ok(Some(block(8))),
+                ])))
+            });
+
+        let transfer = RetryTransfer::new(inner, policy(3));
+        let requests = [request(0), request(4), request(8)];
+        let blocks = transfer
+            .download(&requests)
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(blocks, [Some(block(0)), Some(block(4)), Some(block(8))]);
+    }
+
+    #[tokio::test]
+    async fn give_up_after_max_attempts() {
+        let mut inner = MockDownloadTransfer::new();
+        inner.expect_download().times(2).returning(|_| {
+            Ok(Box::pin(stream::iter([Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "reset",
+            ))])))
+        });
+
+        let transfer = RetryTransfer::new(inner, policy(2));
+        let err = transfer
+            .download(&[request(0)])
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        let mut inner = MockDownloadTransfer::new();
+        inner
+            .expect_download()
+            .times(1)
+            .returning(|_| Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")));
+
+        // 1 attempt never retries
+        let transfer = RetryTransfer::new(inner, policy(1));
+        assert!(transfer.download(&[request(0)]).await.is_err());
+    }
+}